@@ -0,0 +1,117 @@
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::{
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+};
+
+use crate::{DvConstSize, DvDeErr, DvDeserialize, DvSerErr, DvSerialize, Endianness};
+
+/// the integer types a LenPrefixed can store its element count in
+pub trait LenPrefix: DvSerialize + DvDeserialize + Copy {
+    const MAX: usize;
+
+    fn from_len(len: usize) -> Self;
+    fn to_len(self) -> usize;
+}
+
+macro_rules! impl_len_prefix {
+    ($($t:ty),*) => {
+        $(
+            impl LenPrefix for $t {
+                const MAX: usize = <$t>::MAX as usize;
+
+                fn from_len(len: usize) -> Self {
+                    len as $t
+                }
+
+                fn to_len(self) -> usize {
+                    self as usize
+                }
+            }
+        )*
+    };
+}
+
+impl_len_prefix!(u8, u16, u32);
+
+/// a Vec written as its element count in an L followed by every element
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct LenPrefixed<L, T> {
+    pub items: Vec<T>,
+    _prefix: PhantomData<L>,
+}
+
+impl<L, T> LenPrefixed<L, T> {
+    pub fn new(items: Vec<T>) -> Self {
+        Self {
+            items,
+            _prefix: PhantomData,
+        }
+    }
+
+    pub fn into_inner(self) -> Vec<T> {
+        self.items
+    }
+}
+
+impl<L, T> From<Vec<T>> for LenPrefixed<L, T> {
+    fn from(items: Vec<T>) -> Self {
+        Self::new(items)
+    }
+}
+
+impl<L, T> Deref for LenPrefixed<L, T> {
+    type Target = Vec<T>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.items
+    }
+}
+
+impl<L, T> DerefMut for LenPrefixed<L, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.items
+    }
+}
+
+impl<L: LenPrefix, T: DvSerialize> DvSerialize for LenPrefixed<L, T> {
+    fn serialize(&self, endianness: Endianness, target: &mut [u8]) -> Result<usize, DvSerErr> {
+        if self.items.len() > L::MAX {
+            return Err(DvSerErr::BadElementCount(0, L::MAX));
+        }
+
+        let mut acc = L::from_len(self.items.len()).serialize(endianness, target)?;
+
+        for item in self.items.iter() {
+            acc += item.serialize(endianness, &mut target[acc..])?;
+        }
+
+        Ok(acc)
+    }
+}
+
+impl<L: LenPrefix, T: DvDeserialize> DvDeserialize for LenPrefixed<L, T> {
+    fn deserialize(endianness: Endianness, input: &[u8]) -> Result<(Self, usize), DvDeErr>
+    where
+        Self: Sized,
+    {
+        let (count, mut acc) = L::deserialize(endianness, input)?;
+        let count = count.to_len();
+
+        // the count comes from the input, so a corrupted one can't make it reserve gigabytes
+        let mut items = Vec::with_capacity(count.min(input.len() - acc));
+        for _ in 0..count {
+            let (item, read) = T::deserialize(endianness, &input[acc..])?;
+            acc += read;
+            items.push(item);
+        }
+
+        Ok((Self::new(items), acc))
+    }
+}
+
+impl<L, T> DvConstSize for LenPrefixed<L, T> {
+    const SERIALIZED_SIZE: Option<usize> = None;
+}
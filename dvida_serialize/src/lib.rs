@@ -1,7 +1,12 @@
 #![no_std]
 
+mod len_prefixed;
+mod null_terminated;
 mod numbers;
 
+pub use len_prefixed::{LenPrefix, LenPrefixed};
+pub use null_terminated::NullTerminated;
+
 pub use dvida_serialize_macros::DvDeSer;
 use thiserror::Error;
 
@@ -18,12 +23,24 @@ pub enum DvSerErr {
     BufferTooSmall,
     #[error("Inappropriate string length, expected range: {0}, ={1}")]
     BadStringLength(usize, usize),
+    #[error("Inappropriate element count, expected range: {0}, ={1}")]
+    BadElementCount(usize, usize),
 }
 
 #[derive(Debug, Clone, Copy, Error)]
 pub enum DvDeErr {
     #[error("The buffer's size is wrong")]
     WrongBufferSize,
+    #[error("The enum tag doesn't match any variant")]
+    UnknownVariant,
+    #[error("The string isn't valid utf-8")]
+    InvalidString,
+    #[error("Expected 0 or 1 for a bool, got {0}")]
+    InvalidBool(u8),
+    #[error("Not a valid unicode scalar value: {0:#x}")]
+    InvalidChar(u32),
+    #[error("Expected 0 or 1 for an option's presence byte, got {0}")]
+    InvalidPresenceByte(u8),
 }
 
 pub trait DvSerialize {
@@ -33,6 +50,18 @@ pub trait DvSerialize {
     fn serialize(&self, endianness: Endianness, target: &mut [u8]) -> Result<usize, DvSerErr>;
 }
 
+/// types whose serialized size can be known at compile time, the derive implements it too
+pub trait DvConstSize {
+    /// None if the size depends on the value
+    const SERIALIZED_SIZE: Option<usize>;
+
+    /// fails to compile when used on a type without a fixed size
+    const SERIALIZED_SIZE_UNWRAP: usize = match Self::SERIALIZED_SIZE {
+        Some(size) => size,
+        None => panic!("The type doesn't have a fixed serialized size"),
+    };
+}
+
 pub trait DvDeserialize {
     /// the deserialize function takes in endianness, a slice of data, and returns the parsed self
     /// and number of bytes read
@@ -41,3 +70,391 @@ pub trait DvDeserialize {
     where
         Self: Sized;
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate alloc;
+
+    use alloc::{boxed::Box, string::String, vec, vec::Vec};
+
+    use crate::{
+        DvConstSize, DvDeErr, DvDeSer, DvDeserialize, DvSerErr, DvSerialize, Endianness,
+        LenPrefixed, NullTerminated,
+    };
+
+    #[derive(DvDeSer, Debug, PartialEq)]
+    #[repr(u8)]
+    enum Fis {
+        Empty,
+        Pair(u16, u32),
+        Header { kind: u8, lba: u64 },
+        Explicit = 7,
+        AfterExplicit,
+    }
+
+    #[derive(DvDeSer, Debug, PartialEq)]
+    #[dv(tag = u16)]
+    enum Wide {
+        First(u8),
+        Second,
+    }
+
+    #[derive(DvDeSer, Debug, PartialEq)]
+    struct Lba(u64);
+
+    #[derive(DvDeSer, Debug, PartialEq)]
+    struct Range(u32, u16);
+
+    #[derive(DvDeSer, Debug, PartialEq)]
+    struct Marker;
+
+    fn round_trip<T: DvSerialize + DvDeserialize + PartialEq + core::fmt::Debug>(
+        value: T,
+        endianness: Endianness,
+        expected_len: usize,
+    ) -> [u8; 32] {
+        let mut buf = [0u8; 32];
+        let written = value.serialize(endianness, &mut buf).unwrap();
+        assert_eq!(written, expected_len);
+
+        let (parsed, read) = T::deserialize(endianness, &buf[..written]).unwrap();
+        assert_eq!(read, written);
+        assert_eq!(parsed, value);
+
+        buf
+    }
+
+    #[test]
+    fn enum_round_trips() {
+        for endianness in [Endianness::Little, Endianness::Big] {
+            assert_eq!(round_trip(Fis::Empty, endianness, 1)[0], 0);
+
+            let buf = round_trip(Fis::Pair(0x1234, 0xDEADBEEF), endianness, 7);
+            assert_eq!(buf[0], 1);
+
+            let buf = round_trip(
+                Fis::Header {
+                    kind: 0x27,
+                    lba: 0x0102_0304_0506,
+                },
+                endianness,
+                10,
+            );
+            assert_eq!(buf[..2], [2, 0x27]);
+
+            assert_eq!(round_trip(Fis::Explicit, endianness, 1)[0], 7);
+            assert_eq!(round_trip(Fis::AfterExplicit, endianness, 1)[0], 8);
+
+            round_trip(Wide::First(9), endianness, 3);
+            round_trip(Wide::Second, endianness, 2);
+        }
+
+        // the tag follows the endianness like any other number
+        let buf = round_trip(Wide::Second, Endianness::Big, 2);
+        assert_eq!(buf[..2], [0, 1]);
+        let buf = round_trip(Wide::Second, Endianness::Little, 2);
+        assert_eq!(buf[..2], [1, 0]);
+    }
+
+    #[test]
+    fn enum_errors() {
+        assert!(matches!(
+            Fis::deserialize(Endianness::Little, &[3]),
+            Err(DvDeErr::UnknownVariant)
+        ));
+        assert!(matches!(
+            Fis::deserialize(Endianness::Little, &[1, 0]),
+            Err(DvDeErr::WrongBufferSize)
+        ));
+        assert!(matches!(
+            Fis::Pair(1, 2).serialize(Endianness::Little, &mut [0u8; 4]),
+            Err(DvSerErr::BufferTooSmall)
+        ));
+    }
+
+    #[test]
+    fn tuple_struct_round_trips() {
+        for endianness in [Endianness::Little, Endianness::Big] {
+            round_trip(Lba(0x0102_0304_0506_0708), endianness, 8);
+            round_trip(Range(0xCAFEBABE, 0x1234), endianness, 6);
+            round_trip(Marker, endianness, 0);
+        }
+
+        let buf = round_trip(Range(1, 2), Endianness::Little, 6);
+        assert_eq!(buf[..6], [1, 0, 0, 0, 2, 0]);
+        let buf = round_trip(Lba(1), Endianness::Big, 8);
+        assert_eq!(buf[..8], [0, 0, 0, 0, 0, 0, 0, 1]);
+    }
+
+    #[derive(DvDeSer)]
+    struct Fixed {
+        magic: u32,
+        name: [u8; 12],
+        blocks: [[u16; 2]; 3],
+    }
+
+    // just enough for the derive to accept a String field
+    impl DvSerialize for String {
+        fn serialize(&self, _: Endianness, target: &mut [u8]) -> Result<usize, DvSerErr> {
+            let bytes = self.as_bytes();
+            target
+                .get_mut(..bytes.len())
+                .ok_or(DvSerErr::BufferTooSmall)?
+                .copy_from_slice(bytes);
+            Ok(bytes.len())
+        }
+    }
+
+    impl DvDeserialize for String {
+        fn deserialize(_: Endianness, input: &[u8]) -> Result<(Self, usize), DvDeErr> {
+            Ok((String::from_utf8_lossy(input).into(), input.len()))
+        }
+    }
+
+    #[derive(DvDeSer)]
+    struct Named {
+        inode: u32,
+        name: String,
+    }
+
+    #[test]
+    fn serialized_size() {
+        assert_eq!(Fixed::SERIALIZED_SIZE, Some(4 + 12 + 12));
+        let buf = [0u8; Fixed::SERIALIZED_SIZE_UNWRAP];
+        assert_eq!(buf.len(), 28);
+
+        assert_eq!(Named::SERIALIZED_SIZE, None);
+
+        assert_eq!(Lba::SERIALIZED_SIZE, Some(8));
+        assert_eq!(Marker::SERIALIZED_SIZE, Some(0));
+        assert_eq!(Wide::SERIALIZED_SIZE, None);
+        assert_eq!(Fis::SERIALIZED_SIZE, None);
+    }
+
+    #[derive(DvDeSer)]
+    #[dv(tag = u16)]
+    enum SameSize {
+        A(u32),
+        B(i16, i16),
+        C(char),
+        D(bool, bool, [bool; 2]),
+    }
+
+    #[test]
+    fn enum_serialized_size() {
+        assert_eq!(SameSize::SERIALIZED_SIZE, Some(6));
+    }
+
+    #[test]
+    fn array_round_trips() {
+        let words: [u32; 4] = [0x01020304, 0x05060708, 0x090A0B0C, 0x0D0E0F10];
+        let buf = round_trip(words, Endianness::Big, 16);
+        assert_eq!(
+            buf[..16],
+            core::array::from_fn::<u8, 16, _>(|i| i as u8 + 1)
+        );
+        let buf = round_trip(words, Endianness::Little, 16);
+        assert_eq!(buf[..4], [4, 3, 2, 1]);
+
+        let uuid: [u8; 16] = [
+            0x12, 0x3e, 0x45, 0x67, 0xe8, 0x9b, 0x12, 0xd3, 0xa4, 0x56, 0x42, 0x66, 0x14, 0x17,
+            0x40, 0x00,
+        ];
+        let buf = round_trip(uuid, Endianness::Big, 16);
+        assert_eq!(buf[..16], uuid);
+
+        round_trip([[1u16, 2], [3, 4], [5, 6]], Endianness::Big, 12);
+        round_trip([Lba(7), Lba(8)], Endianness::Little, 16);
+
+        assert!(matches!(
+            <[u32; 4]>::deserialize(Endianness::Big, &buf[..15]),
+            Err(DvDeErr::WrongBufferSize)
+        ));
+        assert!(matches!(
+            words.serialize(Endianness::Big, &mut [0u8; 15]),
+            Err(DvSerErr::BufferTooSmall)
+        ));
+    }
+
+    #[test]
+    fn len_prefixed_round_trips() {
+        let empty = LenPrefixed::<u16, u32>::new(vec![]);
+        let buf = round_trip(empty, Endianness::Little, 2);
+        assert_eq!(buf[..2], [0, 0]);
+
+        let one = LenPrefixed::<u16, u32>::new(vec![0xDEADBEEF]);
+        let buf = round_trip(one, Endianness::Big, 6);
+        assert_eq!(buf[..6], [0, 1, 0xDE, 0xAD, 0xBE, 0xEF]);
+
+        let many: LenPrefixed<u16, u32> = (0..1000).collect::<Vec<u32>>().into();
+        let mut buf = vec![0u8; 4002];
+        let written = many.serialize(Endianness::Big, &mut buf).unwrap();
+        assert_eq!(written, 4002);
+        assert_eq!(buf[..2], [0x03, 0xE8]);
+        let (parsed, read) = LenPrefixed::<u16, u32>::deserialize(Endianness::Big, &buf).unwrap();
+        assert_eq!(read, 4002);
+        assert_eq!(parsed, many);
+
+        // the count claims more elements than the input holds
+        assert!(matches!(
+            LenPrefixed::<u16, u32>::deserialize(Endianness::Big, &buf[..4001]),
+            Err(DvDeErr::WrongBufferSize)
+        ));
+        assert!(matches!(
+            many.serialize(Endianness::Big, &mut [0u8; 4001]),
+            Err(DvSerErr::BufferTooSmall)
+        ));
+
+        let too_many = LenPrefixed::<u8, u8>::new(vec![0; 256]);
+        assert!(matches!(
+            too_many.serialize(Endianness::Little, &mut [0u8; 512]),
+            Err(DvSerErr::BadElementCount(0, 255))
+        ));
+        assert_eq!(LenPrefixed::<u8, u8>::SERIALIZED_SIZE, None);
+    }
+
+    #[test]
+    fn null_terminated_strings() {
+        // 15 bytes of text and the terminator exactly fill the field
+        let exact = NullTerminated::<16>::from("fifteen chars!!");
+        let buf = round_trip(exact, Endianness::NA, 16);
+        assert_eq!(&buf[..16], b"fifteen chars!!\0");
+
+        let short = NullTerminated::<16>::from("root");
+        let buf = round_trip(short, Endianness::NA, 16);
+        assert_eq!(&buf[..16], b"root\0\0\0\0\0\0\0\0\0\0\0\0");
+
+        round_trip(NullTerminated::<4>::from(""), Endianness::NA, 4);
+
+        // the padding is rewritten even if the buffer had something in it
+        let mut buf = [0xFFu8; 8];
+        NullTerminated::<6>::from("ab")
+            .serialize(Endianness::NA, &mut buf)
+            .unwrap();
+        assert_eq!(buf, [b'a', b'b', 0, 0, 0, 0, 0xFF, 0xFF]);
+
+        let overflow = NullTerminated::<16>::from("sixteen chars!!!");
+        assert!(matches!(
+            overflow.serialize(Endianness::NA, &mut [0u8; 32]),
+            Err(DvSerErr::BadStringLength(0, 15))
+        ));
+
+        // a full field without a terminator is still read, and only up to the first zero
+        let (parsed, read) = NullTerminated::<4>::deserialize(Endianness::NA, b"abcdef").unwrap();
+        assert_eq!((&*parsed, read), ("abcd", 4));
+        let (parsed, _) = NullTerminated::<4>::deserialize(Endianness::NA, b"a\0cd").unwrap();
+        assert_eq!(&*parsed, "a");
+
+        assert!(matches!(
+            NullTerminated::<4>::deserialize(Endianness::NA, b"abc"),
+            Err(DvDeErr::WrongBufferSize)
+        ));
+        assert!(matches!(
+            NullTerminated::<4>::deserialize(Endianness::NA, &[0xFF, 0, 0, 0]),
+            Err(DvDeErr::InvalidString)
+        ));
+        assert_eq!(NullTerminated::<16>::SERIALIZED_SIZE, Some(16));
+    }
+
+    #[test]
+    fn bool_and_char_round_trips() {
+        for endianness in [Endianness::Little, Endianness::Big] {
+            assert_eq!(round_trip(true, endianness, 1)[0], 1);
+            assert_eq!(round_trip(false, endianness, 1)[0], 0);
+            round_trip('a', endianness, 4);
+            round_trip('🦀', endianness, 4);
+        }
+
+        // the scalar value follows the endianness like a u32
+        assert_eq!(
+            round_trip('🦀', Endianness::Big, 4)[..4],
+            [0, 0x01, 0xF9, 0x80]
+        );
+        assert_eq!(round_trip('a', Endianness::Little, 4)[..4], [0x61, 0, 0, 0]);
+
+        assert!(matches!(
+            bool::deserialize(Endianness::NA, &[2]),
+            Err(DvDeErr::InvalidBool(2))
+        ));
+        assert!(matches!(
+            char::deserialize(Endianness::Big, &[0, 0, 0xD8, 0]),
+            Err(DvDeErr::InvalidChar(0xD800))
+        ));
+        assert!(matches!(
+            char::deserialize(Endianness::Little, &0x110000u32.to_le_bytes()),
+            Err(DvDeErr::InvalidChar(0x110000))
+        ));
+        assert!(matches!(
+            bool::deserialize(Endianness::NA, &[]),
+            Err(DvDeErr::WrongBufferSize)
+        ));
+
+        assert_eq!(bool::SERIALIZED_SIZE, Some(1));
+        assert_eq!(char::SERIALIZED_SIZE, Some(4));
+    }
+
+    #[test]
+    fn option_round_trips() {
+        for endianness in [Endianness::Little, Endianness::Big] {
+            let buf = round_trip(None::<u32>, endianness, 1);
+            assert_eq!(buf[0], 0);
+
+            round_trip(Some(0xDEADBEEFu32), endianness, 5);
+            round_trip(Some(None::<u8>), endianness, 2);
+            round_trip(Some(Lba(3)), endianness, 9);
+        }
+
+        let buf = round_trip(Some(0xDEADBEEFu32), Endianness::Big, 5);
+        assert_eq!(buf[..5], [1, 0xDE, 0xAD, 0xBE, 0xEF]);
+
+        assert!(matches!(
+            Option::<u32>::deserialize(Endianness::Big, &[2, 0, 0, 0, 0]),
+            Err(DvDeErr::InvalidPresenceByte(2))
+        ));
+        assert!(matches!(
+            Option::<u32>::deserialize(Endianness::Big, &[1, 0, 0]),
+            Err(DvDeErr::WrongBufferSize)
+        ));
+        assert!(matches!(
+            Some(1u32).serialize(Endianness::Big, &mut [0u8; 4]),
+            Err(DvSerErr::BufferTooSmall)
+        ));
+        assert_eq!(Option::<u32>::SERIALIZED_SIZE, None);
+    }
+
+    #[derive(DvDeSer, Debug, PartialEq)]
+    struct Cached {
+        inode: u32,
+        #[dv(skip)]
+        cache: Option<Box<[u8]>>,
+        size: u32,
+    }
+
+    #[test]
+    fn skipped_fields() {
+        let value = Cached {
+            inode: 12,
+            cache: Some(Box::new([1, 2, 3])),
+            size: 0x1000,
+        };
+
+        let mut buf = [0u8; 16];
+        let written = value.serialize(Endianness::Little, &mut buf).unwrap();
+        assert_eq!(written, 8);
+        assert_eq!(buf[..8], [12, 0, 0, 0, 0, 0x10, 0, 0]);
+
+        // the skipped field comes back as its default
+        let (parsed, read) = Cached::deserialize(Endianness::Little, &buf[..8]).unwrap();
+        assert_eq!(read, 8);
+        assert_eq!(
+            parsed,
+            Cached {
+                inode: 12,
+                cache: None,
+                size: 0x1000,
+            }
+        );
+
+        assert_eq!(Cached::SERIALIZED_SIZE, Some(8));
+    }
+}
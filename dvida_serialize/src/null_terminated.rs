@@ -0,0 +1,86 @@
+extern crate alloc;
+
+use alloc::string::String;
+use core::ops::Deref;
+
+use crate::{DvConstSize, DvDeErr, DvDeserialize, DvSerErr, DvSerialize, Endianness};
+
+/// a string stored in a fixed N byte field, padded with zeros after the text
+///
+/// at most N - 1 bytes of text can be written so there's always a terminator, when reading a
+/// field without one all N bytes are taken as the text
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct NullTerminated<const N: usize> {
+    pub value: String,
+}
+
+impl<const N: usize> NullTerminated<N> {
+    pub fn new(value: impl Into<String>) -> Self {
+        Self {
+            value: value.into(),
+        }
+    }
+
+    pub fn into_inner(self) -> String {
+        self.value
+    }
+}
+
+impl<const N: usize> From<&str> for NullTerminated<N> {
+    fn from(value: &str) -> Self {
+        Self::new(value)
+    }
+}
+
+impl<const N: usize> From<String> for NullTerminated<N> {
+    fn from(value: String) -> Self {
+        Self::new(value)
+    }
+}
+
+impl<const N: usize> Deref for NullTerminated<N> {
+    type Target = str;
+
+    fn deref(&self) -> &Self::Target {
+        &self.value
+    }
+}
+
+impl<const N: usize> DvSerialize for NullTerminated<N> {
+    fn serialize(&self, _endianness: Endianness, target: &mut [u8]) -> Result<usize, DvSerErr> {
+        let bytes = self.value.as_bytes();
+        if bytes.len() >= N {
+            return Err(DvSerErr::BadStringLength(0, N.saturating_sub(1)));
+        }
+
+        if target.len() < N {
+            return Err(DvSerErr::BufferTooSmall);
+        }
+
+        target[..bytes.len()].copy_from_slice(bytes);
+        target[bytes.len()..N].fill(0);
+
+        Ok(N)
+    }
+}
+
+impl<const N: usize> DvDeserialize for NullTerminated<N> {
+    fn deserialize(_endianness: Endianness, input: &[u8]) -> Result<(Self, usize), DvDeErr>
+    where
+        Self: Sized,
+    {
+        if input.len() < N {
+            return Err(DvDeErr::WrongBufferSize);
+        }
+
+        let field = &input[..N];
+        let len = field.iter().position(|b| *b == 0).unwrap_or(N);
+        let value = core::str::from_utf8(&field[..len]).map_err(|_| DvDeErr::InvalidString)?;
+
+        Ok((Self::new(value), N))
+    }
+}
+
+impl<const N: usize> DvConstSize for NullTerminated<N> {
+    const SERIALIZED_SIZE: Option<usize> = Some(N);
+}
@@ -1,4 +1,4 @@
-use crate::{DvDeErr, DvDeserialize, DvSerErr, DvSerialize, Endianness};
+use crate::{DvConstSize, DvDeErr, DvDeserialize, DvSerErr, DvSerialize, Endianness};
 
 // Your existing macro for primitives
 macro_rules! impl_serialize_deserialize {
@@ -36,64 +36,152 @@ macro_rules! impl_serialize_deserialize {
                     Ok((number, SIZE))
                 }
             }
+
+            impl DvConstSize for $t {
+                const SERIALIZED_SIZE: Option<usize> = Some(core::mem::size_of::<$t>());
+            }
         )*
     };
 }
 
-// New macro for arrays of const size
-macro_rules! impl_serialize_deserialize_array {
-    ($($t:ty),*) => {
-        $(
-            impl<const N: usize> DvSerialize for [$t; N] {
-                fn serialize(&self, endianness: Endianness, target: &mut [u8]) -> Result<usize, DvSerErr> {
-                    const ELEM_SIZE: usize = core::mem::size_of::<$t>();
-                    let total_size = ELEM_SIZE * N;
+impl<T: DvSerialize, const N: usize> DvSerialize for [T; N] {
+    fn serialize(&self, endianness: Endianness, target: &mut [u8]) -> Result<usize, DvSerErr> {
+        let mut acc = 0;
 
-                    if target.len() < total_size {
-                        return Err(DvSerErr::BufferTooSmall);
-                    }
+        for elem in self.iter() {
+            acc += elem.serialize(endianness, &mut target[acc..])?;
+        }
 
-                    for (i, elem) in self.iter().enumerate() {
-                        let offset = i * ELEM_SIZE;
-                        elem.serialize(endianness, &mut target[offset..offset + ELEM_SIZE])?;
-                    }
+        Ok(acc)
+    }
+}
 
-                    Ok(total_size)
-                }
-            }
+impl<T: DvDeserialize, const N: usize> DvDeserialize for [T; N] {
+    fn deserialize(endianness: Endianness, input: &[u8]) -> Result<(Self, usize), DvDeErr>
+    where
+        Self: Sized,
+    {
+        let mut acc = 0;
+        let mut err = None;
 
-            impl<const N: usize> DvDeserialize for [$t; N] {
-                fn deserialize(endianness: Endianness, input: &[u8]) -> Result<(Self, usize), DvDeErr>
-                where
-                    Self: Sized,
-                {
-                    const ELEM_SIZE: usize = core::mem::size_of::<$t>();
-                    let total_size = ELEM_SIZE * N;
+        // once an element fails the rest aren't even attempted
+        let elems: [Option<T>; N] = core::array::from_fn(|_| {
+            if err.is_some() {
+                return None;
+            }
 
-                    if input.len() < total_size {
-                        return Err(DvDeErr::WrongBufferSize);
-                    }
+            match T::deserialize(endianness, &input[acc..]) {
+                Ok((elem, read)) => {
+                    acc += read;
+                    Some(elem)
+                }
+                Err(e) => {
+                    err = Some(e);
+                    None
+                }
+            }
+        });
 
-                    let mut result = [<$t>::default(); N];
+        if let Some(e) = err {
+            return Err(e);
+        }
 
-                    for i in 0..N {
-                        let offset = i * ELEM_SIZE;
-                        let (elem, _) = <$t>::deserialize(endianness, &input[offset..offset + ELEM_SIZE])?;
-                        result[i] = elem;
-                    }
+        Ok((elems.map(|elem| elem.expect("Every element was read")), acc))
+    }
+}
 
-                    Ok((result, total_size))
-                }
-            }
-        )*
+impl<T: DvConstSize, const N: usize> DvConstSize for [T; N] {
+    const SERIALIZED_SIZE: Option<usize> = match T::SERIALIZED_SIZE {
+        Some(size) => Some(size * N),
+        None => None,
     };
 }
 
 // Apply to primitives
 impl_serialize_deserialize!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128, f32, f64);
 
-// Apply to arrays of primitives
-impl_serialize_deserialize_array!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128, f32, f64);
+/// a single byte, anything but 0 or 1 is rejected when reading
+impl DvSerialize for bool {
+    fn serialize(&self, endianness: Endianness, target: &mut [u8]) -> Result<usize, DvSerErr> {
+        (*self as u8).serialize(endianness, target)
+    }
+}
+
+impl DvDeserialize for bool {
+    fn deserialize(endianness: Endianness, input: &[u8]) -> Result<(Self, usize), DvDeErr>
+    where
+        Self: Sized,
+    {
+        match u8::deserialize(endianness, input)? {
+            (0, read) => Ok((false, read)),
+            (1, read) => Ok((true, read)),
+            (value, _) => Err(DvDeErr::InvalidBool(value)),
+        }
+    }
+}
+
+impl DvConstSize for bool {
+    const SERIALIZED_SIZE: Option<usize> = Some(1);
+}
+
+/// the scalar value as a u32, surrogates and anything past 0x10FFFF are rejected when reading
+impl DvSerialize for char {
+    fn serialize(&self, endianness: Endianness, target: &mut [u8]) -> Result<usize, DvSerErr> {
+        (*self as u32).serialize(endianness, target)
+    }
+}
+
+impl DvDeserialize for char {
+    fn deserialize(endianness: Endianness, input: &[u8]) -> Result<(Self, usize), DvDeErr>
+    where
+        Self: Sized,
+    {
+        let (value, read) = u32::deserialize(endianness, input)?;
+        let c = char::from_u32(value).ok_or(DvDeErr::InvalidChar(value))?;
+
+        Ok((c, read))
+    }
+}
+
+impl DvConstSize for char {
+    const SERIALIZED_SIZE: Option<usize> = Some(4);
+}
+
+/// a presence byte, 0 for None and 1 for Some, then the value if there is one
+impl<T: DvSerialize> DvSerialize for Option<T> {
+    fn serialize(&self, endianness: Endianness, target: &mut [u8]) -> Result<usize, DvSerErr> {
+        let mut acc = self.is_some().serialize(endianness, target)?;
+
+        if let Some(value) = self {
+            acc += value.serialize(endianness, &mut target[acc..])?;
+        }
+
+        Ok(acc)
+    }
+}
+
+impl<T: DvDeserialize> DvDeserialize for Option<T> {
+    fn deserialize(endianness: Endianness, input: &[u8]) -> Result<(Self, usize), DvDeErr>
+    where
+        Self: Sized,
+    {
+        let (presence, acc) = u8::deserialize(endianness, input)?;
+
+        match presence {
+            0 => Ok((None, acc)),
+            1 => {
+                let (value, read) = T::deserialize(endianness, &input[acc..])?;
+                Ok((Some(value), acc + read))
+            }
+            _ => Err(DvDeErr::InvalidPresenceByte(presence)),
+        }
+    }
+}
+
+/// None and Some take different amounts of space
+impl<T> DvConstSize for Option<T> {
+    const SERIALIZED_SIZE: Option<usize> = None;
+}
 
 // #[derive(DvDeSer)]
 // struct Test {
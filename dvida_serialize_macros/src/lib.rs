@@ -1,8 +1,12 @@
 extern crate proc_macro;
 use proc_macro::TokenStream;
 
-use quote::quote;
-use syn::{Data, DeriveInput, Field, Ident, Type, parse_macro_input};
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote, quote_spanned};
+use syn::{
+    Attribute, Data, DataEnum, DataStruct, DeriveInput, Field, Fields, Ident, Index, Member, Type,
+    parse_macro_input, parse_quote, spanned::Spanned,
+};
 
 fn make_error(ident: &Ident, msg: &str) -> TokenStream {
     return syn::Error::new_spanned(&ident, msg)
@@ -10,10 +14,10 @@ fn make_error(ident: &Ident, msg: &str) -> TokenStream {
         .into();
 }
 
-#[proc_macro_derive(DvDeSer)]
+#[proc_macro_derive(DvDeSer, attributes(dv))]
 pub fn derive_dv_deser(input: TokenStream) -> TokenStream {
     let DeriveInput {
-        attrs: _,
+        attrs,
         vis: _,
         ident,
         generics,
@@ -25,35 +29,27 @@ pub fn derive_dv_deser(input: TokenStream) -> TokenStream {
     // Generates: impl<T: Clone, U> MyTrait for Foo<T, U> where U: Debug { ... }
     //            ^^^^^ impl_generics   ^^^^ ty_generics  ^^^^^^^^^^^^^^ where_clause
 
-    let data_struct = if let Data::Struct(data_struct) = data {
-        data_struct
-    } else {
-        return make_error(&ident, "Only structs are supported");
+    let bodies = match data {
+        Data::Struct(data_struct) => derive_struct(&data_struct),
+        Data::Enum(data_enum) => {
+            tag_type(&attrs).and_then(|tag_type| derive_enum(&data_enum, &tag_type))
+        }
+        Data::Union(_) => return make_error(&ident, "Only structs and enums are supported"),
     };
 
-    let names: Vec<Ident> = data_struct
-        .fields
-        .iter()
-        .filter_map(|f| f.ident.clone())
-        .collect();
-
-    let fields: Vec<&Field> = data_struct
-        .fields
-        .iter()
-        .filter(|f| match &f.ident {
-            Some(_) => true,
-            None => false,
-        })
-        .collect();
-
-    let types: Vec<&Type> = fields.iter().map(|f| &f.ty).collect();
+    let (serialize_body, deserialize_body, serialized_size) = match bodies {
+        Ok(bodies) => bodies,
+        Err(e) => return e.to_compile_error().into(),
+    };
 
     let expanded = quote! {
         impl #impl_generics DvSerialize for #ident #ty_generics #where_clause {
             fn serialize(&self, endianness: Endianness, target: &mut [u8]) -> Result<usize, DvSerErr> {
+                // unit structs never add to it
+                #[allow(unused_mut)]
                 let mut acc: usize = 0;
 
-                #( acc += self.#names.serialize(endianness, &mut target[acc..])?; )*
+                #serialize_body
 
                 Ok(acc)
             }
@@ -64,20 +60,277 @@ pub fn derive_dv_deser(input: TokenStream) -> TokenStream {
             where
                 Self: Sized,
             {
+                // unit structs never add to it
+                #[allow(unused_mut)]
                 let mut acc: usize = 0;
 
+                #deserialize_body
+            }
+
+        }
+
+        impl #impl_generics DvConstSize for #ident #ty_generics #where_clause {
+            const SERIALIZED_SIZE: Option<usize> = #serialized_size;
+        }
+    };
+
+    expanded.into()
+}
+
+/// the serialized size of a type as a const expression, only the primitives and arrays of them
+/// can be told apart by their name, anything else counts as variable length
+fn static_size(ty: &Type) -> TokenStream2 {
+    match ty {
+        Type::Path(type_path) if type_path.qself.is_none() => {
+            let is_primitive = type_path
+                .path
+                .get_ident()
+                .is_some_and(|ident| PRIMITIVES.iter().any(|primitive| ident == primitive));
+
+            if is_primitive {
+                quote! { Some(core::mem::size_of::<#ty>()) }
+            } else {
+                quote! { None::<usize> }
+            }
+        }
+        Type::Array(array) => {
+            let elem_size = static_size(&array.elem);
+            let len = &array.len;
+            quote! {
+                match #elem_size {
+                    Some(size) => Some(size * (#len)),
+                    None => None,
+                }
+            }
+        }
+        Type::Paren(paren) => static_size(&paren.elem),
+        Type::Group(group) => static_size(&group.elem),
+        _ => quote! { None::<usize> },
+    }
+}
+
+const PRIMITIVES: [&str; 14] = [
+    "u8", "u16", "u32", "u64", "u128", "i8", "i16", "i32", "i64", "i128", "f32", "f64", "bool",
+    "char",
+];
+
+/// adds up the sizes, None as soon as one of them is None
+fn sum_sizes<'a>(types: impl IntoIterator<Item = &'a Type>) -> TokenStream2 {
+    types.into_iter().fold(quote! { Some(0usize) }, |acc, ty| {
+        let size = static_size(ty);
+        quote! {
+            match (#acc, #size) {
+                (Some(acc), Some(size)) => Some(acc + size),
+                _ => None,
+            }
+        }
+    })
+}
+
+/// whether a field has #[dv(skip)], those aren't written and come back as their default
+fn is_skipped(field: &Field) -> syn::Result<bool> {
+    let mut skip = false;
+
+    for attr in field.attrs.iter().filter(|attr| attr.path().is_ident("dv")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("skip") {
+                skip = true;
+                Ok(())
+            } else {
+                Err(meta.error("Unknown dv attribute"))
+            }
+        })?;
+    }
+
+    Ok(skip)
+}
+
+type Bodies = (TokenStream2, TokenStream2, TokenStream2);
+
+fn derive_struct(data_struct: &DataStruct) -> syn::Result<Bodies> {
+    let mut types: Vec<&Type> = vec![];
+    let mut accessors: Vec<Member> = vec![];
+    let mut reads = vec![];
+    // every field in declaration order, tuple fields are read into numbered locals
+    let mut bindings: Vec<Ident> = vec![];
+
+    for (idx, field) in data_struct.fields.iter().enumerate() {
+        let (accessor, binding) = match &field.ident {
+            Some(name) => (Member::Named(name.clone()), name.clone()),
+            None => (
+                Member::Unnamed(Index::from(idx)),
+                format_ident!("field{}", idx),
+            ),
+        };
+        let ty = &field.ty;
+
+        if is_skipped(field)? {
+            // spanned so a type without Default is reported on the field
+            reads.push(quote_spanned! { ty.span() =>
+                let #binding = <#ty as ::core::default::Default>::default();
+            });
+        } else {
+            reads.push(quote! {
+                let (#binding, written) = <#ty>::deserialize(endianness, &input[acc..])?;
+                acc += written;
+            });
+            types.push(ty);
+            accessors.push(accessor);
+        }
+
+        bindings.push(binding);
+    }
+
+    let construct = match &data_struct.fields {
+        Fields::Named(_) => quote! { Self { #( #bindings ),* } },
+        Fields::Unnamed(_) => quote! { Self ( #( #bindings ),* ) },
+        Fields::Unit => quote! { Self },
+    };
+
+    let serialize_body = quote! {
+        #( acc += self.#accessors.serialize(endianness, &mut target[acc..])?; )*
+    };
+
+    let deserialize_body = quote! {
+        #( #reads )*
+
+        Ok((#construct, acc))
+    };
+
+    Ok((serialize_body, deserialize_body, sum_sizes(types)))
+}
+
+/// the width of an enum's discriminant, set with #[dv(tag = u16)], u8 if not given
+fn tag_type(attrs: &[Attribute]) -> syn::Result<Type> {
+    let mut tag_type: Type = parse_quote!(u8);
+
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("dv")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("tag") {
+                tag_type = meta.value()?.parse()?;
+                Ok(())
+            } else {
+                Err(meta.error("Unknown dv attribute"))
+            }
+        })?;
+    }
+
+    Ok(tag_type)
+}
+
+/// an enum is written as its discriminant followed by the fields of the active variant
+///
+/// the discriminants follow the rust rules, explicit ones are used as they are and the others
+/// are one more than the previous variant's
+fn derive_enum(data_enum: &DataEnum, tag_type: &Type) -> syn::Result<Bodies> {
+    let mut tag_consts = vec![];
+    let mut variant_sizes = vec![];
+    let mut tag_values = vec![];
+    let mut serialize_arms = vec![];
+    let mut deserialize_arms = vec![];
+
+    for (i, variant) in data_enum.variants.iter().enumerate() {
+        let variant_ident = &variant.ident;
+
+        for field in variant.fields.iter() {
+            if is_skipped(field)? {
+                return Err(syn::Error::new_spanned(
+                    field,
+                    "dv(skip) is only supported on struct fields",
+                ));
+            }
+        }
+        let tag_const = format_ident!("__DV_TAG_{}", i);
+
+        let tag_value = match (&variant.discriminant, tag_consts.last()) {
+            (Some((_, expr)), _) => quote! { (#expr) as #tag_type },
+            (None, Some(prev)) => quote! { #prev + 1 },
+            (None, None) => quote! { 0 },
+        };
+
+        // prefixed so a field called input or acc doesn't shadow the generated locals
+        let bindings: Vec<Ident> = (0..variant.fields.len())
+            .map(|idx| format_ident!("__dv_field_{}", idx))
+            .collect();
+        let names: Vec<Option<&Ident>> = variant.fields.iter().map(|f| f.ident.as_ref()).collect();
+        let types: Vec<&Type> = variant.fields.iter().map(|f| &f.ty).collect();
+        variant_sizes.push(sum_sizes(
+            core::iter::once(tag_type).chain(types.iter().copied()),
+        ));
+
+        let construct = match &variant.fields {
+            Fields::Named(_) => quote! { Self::#variant_ident { #( #names: #bindings ),* } },
+            Fields::Unnamed(_) => quote! { Self::#variant_ident ( #( #bindings ),* ) },
+            Fields::Unit => quote! { Self::#variant_ident },
+        };
+
+        serialize_arms.push(quote! {
+            #construct => {
+                acc += #tag_const.serialize(endianness, &mut target[acc..])?;
+                #( acc += #bindings.serialize(endianness, &mut target[acc..])?; )*
+            }
+        });
+
+        deserialize_arms.push(quote! {
+            #tag_const => {
                 #(
 
-                let (#names, written) = <#types>::deserialize(endianness, &input[acc..])?;
+                let (#bindings, written) = <#types>::deserialize(endianness, &input[acc..])?;
                 acc += written;
 
                 )*
 
-                Ok((Self { #( #names ),* }, acc))
+                Ok((#construct, acc))
             }
+        });
+
+        tag_consts.push(tag_const);
+        tag_values.push(tag_value);
+    }
+
+    let tag_defs = quote! {
+        #( const #tag_consts: #tag_type = #tag_values; )*
+    };
+
+    let serialize_body = quote! {
+        #tag_defs
 
+        match self {
+            #( #serialize_arms )*
         }
     };
 
-    expanded.into()
+    let deserialize_body = quote! {
+        #tag_defs
+
+        let (tag, written) = <#tag_type>::deserialize(endianness, input)?;
+        acc += written;
+
+        match tag {
+            #( #deserialize_arms )*
+            _ => Err(DvDeErr::UnknownVariant),
+        }
+    };
+
+    // only fixed if every variant comes out the same size
+    let variant_count = variant_sizes.len();
+    let serialized_size = quote! {{
+        let sizes: [Option<usize>; #variant_count] = [ #( #variant_sizes ),* ];
+        let mut res = None;
+        let mut i = 0;
+        while i < #variant_count {
+            res = match (i, res, sizes[i]) {
+                (0, _, size) => size,
+                (_, Some(prev), Some(size)) if prev == size => Some(prev),
+                _ => None,
+            };
+            if res.is_none() {
+                break;
+            }
+            i += 1;
+        }
+        res
+    }};
+
+    Ok((serialize_body, deserialize_body, serialized_size))
 }
@@ -1,12 +1,14 @@
-// TODO: support x2apic
-
-use core::sync::atomic::AtomicU64;
+use core::{
+    arch::x86_64::__cpuid,
+    sync::atomic::{AtomicBool, AtomicU64},
+};
 
 use crate::log;
 use alloc::{collections::btree_map::BTreeMap, format, string::String, vec::Vec};
 use bytemuck::{Pod, Zeroable};
 use x86_64::{
     PhysAddr, VirtAddr,
+    registers::model_specific::Msr,
     structures::paging::{Page, PhysFrame, Size4KiB},
 };
 
@@ -18,6 +20,64 @@ use crate::arch::x86_64::{
 };
 
 pub static LOCAL_APIC_ADDR: AtomicU64 = AtomicU64::new(0);
+/// picked once on the bsp, every core runs its local apic in the same mode
+pub static X2APIC_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// leaf 1 ecx
+const X2APIC_CPUID_BIT: u32 = 21;
+const APIC_BASE_MSR: u32 = 0x1B;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+/// in x2apic mode the register at mmio offset `n` is the msr `X2APIC_MSR_BASE + n / 16`
+const X2APIC_MSR_BASE: u32 = 0x800;
+const ICR_LOW_OFFSET: u64 = 0x300;
+const ICR_HIGH_OFFSET: u64 = 0x310;
+/// xapic only, set while the last ipi hasn't been accepted yet
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+/// fixed ipis have to be sent with the level asserted
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+
+pub fn is_x2apic_supported() -> bool {
+    __cpuid(1).ecx & (1 << X2APIC_CPUID_BIT) != 0
+}
+
+pub fn is_x2apic_enabled() -> bool {
+    X2APIC_ENABLED.load(core::sync::atomic::Ordering::Relaxed)
+}
+
+/// decides on the bsp whether the local apics are driven through msrs, the mmio interface stays
+/// the fallback on cpus without x2apic
+pub fn init_x2apic() {
+    X2APIC_ENABLED.store(is_x2apic_supported(), core::sync::atomic::Ordering::Relaxed);
+
+    enable_x2apic_on_core();
+}
+
+/// the mode is per core, every ap has to call this before it touches its local apic
+pub fn enable_x2apic_on_core() {
+    if !is_x2apic_enabled() {
+        return;
+    }
+
+    let mut apic_base = Msr::new(APIC_BASE_MSR);
+    unsafe {
+        let value = apic_base.read();
+        apic_base.write(value | APIC_BASE_ENABLE | APIC_BASE_X2APIC);
+    }
+}
+
+/// the msr standing in for the register at mmio `offset`
+pub fn x2apic_msr(offset: u64) -> u32 {
+    X2APIC_MSR_BASE + (offset >> 4) as u32
+}
+
+/// the whole interrupt command register, the destination sits in the top byte on the xapic and
+/// takes the whole upper half on the x2apic
+pub fn icr_value(x2apic: bool, destination: u32, command: u32) -> u64 {
+    let shift = if x2apic { 32 } else { 56 };
+
+    ((destination as u64) << shift) | command as u64
+}
 
 #[derive(Debug, Clone, Copy, Zeroable, Pod)]
 #[repr(C, packed)]
@@ -135,6 +195,8 @@ pub struct LocalNmiSourceData {
 #[derive(Debug, Clone, Copy)]
 pub struct LocalApic {
     pub base: VirtAddr,
+    /// registers are msrs instead of mmio
+    pub x2apic: bool,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -283,8 +345,11 @@ pub fn init_apic(
         madt_ptr += entry_header.record_length as u64 - size_of::<MadtEntryHeader>() as u64;
     }
 
+    init_x2apic();
+
     let local_apic = LocalApic {
         base: local_apic_addr,
+        x2apic: is_x2apic_enabled(),
     };
 
     for p_ids in processors_partial.drain(0..) {
@@ -324,8 +389,11 @@ pub fn init_apic(
         &mut None,
     );
 
-    let local_apic_id = local_apic.read_id() >> 24;
-    log!("Id of the bootstrap cpu: {local_apic_id}");
+    let local_apic_id = local_apic.apic_id();
+    log!(
+        "Id of the bootstrap cpu: {local_apic_id}, x2apic: {}",
+        local_apic.x2apic
+    );
 
     processors
         .get_mut(&(local_apic_id as u8))
@@ -427,6 +495,35 @@ macro_rules! pcie_offset_impl {
     };
 }
 
+/// accessors that go through [`LocalApic::read_register`] and [`LocalApic::write_register`], the
+/// offsets are the mmio ones and get turned into msrs in x2apic mode
+macro_rules! local_apic_registers {
+    ($(<$name:ident, $offset:expr, $access:tt>),* $(,)?) => {
+        $(local_apic_registers!(@register $name, $offset, $access);)*
+    };
+
+    (@register $name:ident, $offset:expr, "r") => {
+        paste::paste! {
+            pub fn [<read_$name>](&self) -> u32 {
+                self.read_register($offset)
+            }
+        }
+    };
+
+    (@register $name:ident, $offset:expr, "w") => {
+        paste::paste! {
+            pub fn [<write_$name>](&mut self, input: u32) {
+                self.write_register($offset, input)
+            }
+        }
+    };
+
+    (@register $name:ident, $offset:expr, "rw") => {
+        local_apic_registers!(@register $name, $offset, "r");
+        local_apic_registers!(@register $name, $offset, "w");
+    };
+}
+
 impl LocalApic {
     pub fn dump(&self) -> String {
         let mut s = String::new();
@@ -434,9 +531,9 @@ impl LocalApic {
 
         // Basic Info
         s.push_str(&format!(
-            "ID:            {:#010x} (Shifted: {})\n",
+            "ID:            {:#010x} (APIC ID: {})\n",
             self.read_id(),
-            self.read_id() >> 24
+            self.apic_id()
         ));
         s.push_str(&format!("Version:       {:#010x}\n", self.read_version()));
         s.push_str(&format!(
@@ -470,8 +567,7 @@ impl LocalApic {
         ));
 
         // ICR
-        s.push_str(&format!("ICR (High):    {:#010x}\n", self.read_icr_high()));
-        s.push_str(&format!("ICR (Low):     {:#010x}\n", self.read_icr_low()));
+        s.push_str(&format!("ICR:           {:#018x}\n", self.read_icr()));
 
         // Error
         s.push_str(&format!(
@@ -483,25 +579,25 @@ impl LocalApic {
         s
     }
 
-    pcie_offset_impl!(
+    local_apic_registers!(
         <id, 0x20, "r">,
         <version, 0x30, "r">,
         <task_priority, 0x80, "rw">,
+        // xapic only
         <arbitration_priority, 0x90, "r">,
         <processor_priority, 0xA0, "r">,
         <eoi, 0xB0, "w">,
+        // xapic only
         <remote_read, 0xC0, "r">,
+        // read only on the x2apic
         <logical_destination, 0xD0, "rw">,
+        // xapic only
         <destination_format, 0xE0, "rw">,
         <spurious_interrupt_vector, 0xF0, "rw">,
 
         <error_status, 0x280, "r">,
         <lvt_cmci, 0x2F0, "rw">,
 
-        // Interrupt Command Register (Split into two 32-bit halves)
-        <icr_low, 0x300, "rw">,
-        <icr_high, 0x310, "rw">,
-
         // Local Vector Table (LVT)
         <lvt_timer, 0x320, "rw">,
         <lvt_thermal, 0x330, "rw">,
@@ -513,31 +609,87 @@ impl LocalApic {
         // Timer Registers
         <timer_initial_count, 0x380, "rw">,
         <timer_current_count, 0x390, "r">,
-        <timer_divide_config, 0x3E0, "rw">
+        <timer_divide_config, 0x3E0, "rw">,
     );
 
+    pub fn read_register(&self, offset: u64) -> u32 {
+        if self.x2apic {
+            unsafe { Msr::new(x2apic_msr(offset)).read() as u32 }
+        } else {
+            let addr: *const u32 = (self.base + offset).as_ptr();
+            unsafe { addr.read_volatile() }
+        }
+    }
+
+    pub fn write_register(&mut self, offset: u64, value: u32) {
+        if self.x2apic {
+            unsafe { Msr::new(x2apic_msr(offset)).write(value as u64) }
+        } else {
+            let addr: *mut u32 = (self.base + offset).as_mut_ptr();
+            unsafe { addr.write_volatile(value) }
+        }
+    }
+
+    /// the id of this core's local apic, the xapic keeps it in the top byte of the register
+    pub fn apic_id(&self) -> u32 {
+        if self.x2apic {
+            self.read_id()
+        } else {
+            self.read_id() >> 24
+        }
+    }
+
+    /// a single msr on the x2apic, two registers on the xapic
+    pub fn read_icr(&self) -> u64 {
+        if self.x2apic {
+            unsafe { Msr::new(x2apic_msr(ICR_LOW_OFFSET)).read() }
+        } else {
+            (self.read_register(ICR_HIGH_OFFSET) as u64) << 32
+                | self.read_register(ICR_LOW_OFFSET) as u64
+        }
+    }
+
+    /// sends the ipi described by `value`, on the xapic the write to the low half is what sends
+    /// it so the high half goes first
+    pub fn write_icr(&mut self, value: u64) {
+        if self.x2apic {
+            unsafe { Msr::new(x2apic_msr(ICR_LOW_OFFSET)).write(value) }
+        } else {
+            self.write_register(ICR_HIGH_OFFSET, (value >> 32) as u32);
+            self.write_register(ICR_LOW_OFFSET, value as u32);
+
+            while self.read_register(ICR_LOW_OFFSET) & ICR_DELIVERY_PENDING != 0 {
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    /// `command` is the low half of the icr: vector, delivery mode, shorthand and so on
+    pub fn send_ipi(&mut self, destination: u32, command: u32) {
+        self.write_icr(icr_value(self.x2apic, destination, command));
+    }
+
+    /// raises `vector` on the core whose local apic has the id `destination`
+    pub fn send_fixed_ipi(&mut self, destination: u32, vector: u8) {
+        self.send_ipi(destination, ICR_LEVEL_ASSERT | vector as u32);
+    }
+
     pub fn read_isr(&self, number: u64) -> u32 {
         const ISR_BASE: u64 = 0x100;
         const ALIGNMENT: u64 = 0x10;
-        let addr = self.base + ISR_BASE + number * ALIGNMENT;
-        let addr: *const u32 = addr.as_ptr();
-        unsafe { addr.read_volatile() }
+        self.read_register(ISR_BASE + number * ALIGNMENT)
     }
 
     pub fn read_tmr(&self, number: u64) -> u32 {
         const TMR_BASE: u64 = 0x180;
         const ALIGNMENT: u64 = 0x10;
-        let addr = self.base + TMR_BASE + number * ALIGNMENT;
-        let addr: *const u32 = addr.as_ptr();
-        unsafe { addr.read_volatile() }
+        self.read_register(TMR_BASE + number * ALIGNMENT)
     }
 
     pub fn read_irr(&self, number: u64) -> u32 {
         const IRR_BASE: u64 = 0x200;
         const ALIGNMENT: u64 = 0x10;
-        let addr = self.base + IRR_BASE + number * ALIGNMENT;
-        let addr: *const u32 = addr.as_ptr();
-        unsafe { addr.read_volatile() }
+        self.read_register(IRR_BASE + number * ALIGNMENT)
     }
 
     pub fn enable(&mut self) {
@@ -753,5 +905,54 @@ impl IoApic {
 pub fn get_local_apic() -> LocalApic {
     LocalApic {
         base: VirtAddr::new(LOCAL_APIC_ADDR.load(core::sync::atomic::Ordering::Relaxed)),
+        x2apic: is_x2apic_enabled(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::arch::x86_64::__cpuid;
+
+    use x86_64::instructions::interrupts::without_interrupts;
+
+    use super::{get_local_apic, icr_value, x2apic_msr};
+    use crate::{
+        arch::x86_64::idt::SPURIOUS_INTERRUPT_HANDLER_IDX, end_test, get_per_cpu_data, test_name,
+    };
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn local_apic_registers_in_either_mode() {
+        test_name!("local apic registers mean the same through mmio and msrs");
+
+        assert_eq!(x2apic_msr(0x20), 0x802);
+        assert_eq!(x2apic_msr(0xB0), 0x80B);
+        assert_eq!(x2apic_msr(0x300), 0x830);
+        assert_eq!(x2apic_msr(0x3E0), 0x83E);
+
+        // the destination moves from the top byte to the whole upper half
+        assert_eq!(icr_value(false, 3, 0x4030), 0x0300_0000_0000_4030);
+        assert_eq!(icr_value(true, 0x1234, 0x4030), 0x0000_1234_0000_4030);
+
+        // run with -cpu qemu64,+x2apic and without, the values have to agree with cpuid either way
+        without_interrupts(|| {
+            let local_apic = get_local_apic();
+            let cpuid_id = if local_apic.x2apic {
+                __cpuid(0xB).edx
+            } else {
+                __cpuid(1).ebx >> 24
+            };
+            assert_eq!(local_apic.apic_id(), cpuid_id);
+            assert_eq!(local_apic.apic_id() as u64, get_per_cpu_data!().id);
+
+            let spurious = local_apic.read_spurious_interrupt_vector();
+            assert_eq!(spurious & 0xFF, SPURIOUS_INTERRUPT_HANDLER_IDX as u32);
+            assert_ne!(spurious & (1 << 8), 0);
+
+            // the lvt count sits in the same bits in both modes
+            assert_ne!((local_apic.read_version() >> 16) & 0xFF, 0);
+        });
+
+        end_test!();
     }
 }
@@ -0,0 +1,519 @@
+use crate::log;
+use bytemuck::{Pod, Zeroable};
+use once_cell_no_std::OnceCell;
+use x86_64::{VirtAddr, instructions::port::Port};
+
+use crate::arch::x86_64::{acpi::AcpiSdtHeader, memory::get_hhdm_offset};
+
+/// what the power off path uses, only there once the fadt has been parsed
+pub static POWER_CONTROL: OnceCell<PowerControl> = OnceCell::new();
+/// what reboot tries first, only there if the fadt has a usable one
+pub static RESET_REGISTER: OnceCell<ResetRegister> = OnceCell::new();
+
+/// generic address structure, `address_space` tells how `address` is reached
+#[derive(Debug, Clone, Copy, Pod, Zeroable, Default)]
+#[repr(C, packed)]
+pub struct GenericAddress {
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+pub const ADDRESS_SPACE_SYSTEM_MEMORY: u8 = 0;
+pub const ADDRESS_SPACE_SYSTEM_IO: u8 = 1;
+pub const ADDRESS_SPACE_PCI_CONFIG: u8 = 2;
+
+/// the fadt up to the extended pm1 control blocks, older and shorter tables leave the rest zeroed
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+#[repr(C, packed)]
+pub struct Fadt {
+    pub header: AcpiSdtHeader,
+    pub firmware_control: u32,
+    pub dsdt: u32,
+    pub reserved: u8,
+    pub preferred_pm_profile: u8,
+    pub sci_interrupt: u16,
+    pub smi_command: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub s4bios_request: u8,
+    pub pstate_control: u8,
+    pub pm1a_event_block: u32,
+    pub pm1b_event_block: u32,
+    pub pm1a_control_block: u32,
+    pub pm1b_control_block: u32,
+    pub pm2_control_block: u32,
+    pub pm_timer_block: u32,
+    pub gpe0_block: u32,
+    pub gpe1_block: u32,
+    pub pm1_event_length: u8,
+    pub pm1_control_length: u8,
+    pub pm2_control_length: u8,
+    pub pm_timer_length: u8,
+    pub gpe0_length: u8,
+    pub gpe1_length: u8,
+    pub gpe1_base: u8,
+    pub cstate_control: u8,
+    pub worst_c2_latency: u16,
+    pub worst_c3_latency: u16,
+    pub flush_size: u16,
+    pub flush_stride: u16,
+    pub duty_offset: u8,
+    pub duty_width: u8,
+    pub day_alarm: u8,
+    pub month_alarm: u8,
+    pub century: u8,
+    pub boot_architecture_flags: u16,
+    pub reserved2: u8,
+    pub flags: u32,
+    pub reset_register: GenericAddress,
+    pub reset_value: u8,
+    pub arm_boot_architecture_flags: u16,
+    pub minor_version: u8,
+    pub x_firmware_control: u64,
+    pub x_dsdt: u64,
+    pub x_pm1a_event_block: GenericAddress,
+    pub x_pm1b_event_block: GenericAddress,
+    pub x_pm1a_control_block: GenericAddress,
+    pub x_pm1b_control_block: GenericAddress,
+}
+
+/// fadt flags
+const RESET_REG_SUP: u32 = 1 << 10;
+
+/// the legacy pci configuration mechanism, reset registers in pci config space are on bus 0
+const PCI_CONFIG_ADDRESS_PORT: u16 = 0xCF8;
+const PCI_CONFIG_DATA_PORT: u16 = 0xCFC;
+const PCI_CONFIG_ENABLE: u32 = 1 << 31;
+
+/// pm1 control bits
+const SCI_EN: u16 = 1 << 0;
+const SLP_TYP_SHIFT: u16 = 10;
+const SLP_TYP_MASK: u16 = 0b111 << SLP_TYP_SHIFT;
+const SLP_EN: u16 = 1 << 13;
+
+/// aml opcodes the \_S5 object is made of
+const NAME_OP: u8 = 0x08;
+const PACKAGE_OP: u8 = 0x12;
+const BYTE_PREFIX: u8 = 0x0A;
+const ROOT_CHAR: u8 = b'\\';
+
+/// how long to wait for the firmware to hand the chipset over once asked to
+const ACPI_ENABLE_SPINS: usize = 1_000_000;
+
+impl Fadt {
+    /// copies as much of the table as it has, None if it isn't a fadt at all
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let header_len = size_of::<AcpiSdtHeader>();
+        if bytes.len() < header_len || &bytes[..4] != b"FACP" {
+            return None;
+        }
+
+        let header: AcpiSdtHeader = bytemuck::pod_read_unaligned(&bytes[..header_len]);
+        let len = (header.length as usize)
+            .min(bytes.len())
+            .min(size_of::<Self>());
+
+        let mut fadt = Self::zeroed();
+        bytemuck::bytes_of_mut(&mut fadt)[..len].copy_from_slice(&bytes[..len]);
+
+        Some(fadt)
+    }
+
+    pub fn dsdt_addr(&self) -> u64 {
+        match self.x_dsdt {
+            0 => self.dsdt as u64,
+            addr => addr,
+        }
+    }
+
+    /// the io port of a pm1 control block, the extended field wins if it's an io port
+    fn control_port(extended: GenericAddress, legacy: u32) -> Option<u16> {
+        let port = if extended.address_space == ADDRESS_SPACE_SYSTEM_IO && extended.address != 0 {
+            extended.address
+        } else {
+            legacy as u64
+        };
+
+        (port != 0).then_some(port as u16)
+    }
+
+    pub fn pm1a_control_port(&self) -> Option<u16> {
+        Self::control_port(self.x_pm1a_control_block, self.pm1a_control_block)
+    }
+
+    pub fn pm1b_control_port(&self) -> Option<u16> {
+        Self::control_port(self.x_pm1b_control_block, self.pm1b_control_block)
+    }
+}
+
+/// the SLP_TYPa and SLP_TYPb values of the \_S5 package. Only the shape every firmware emits is
+/// understood: a name holding a package whose first two elements are integer constants
+pub fn find_s5_sleep_types(aml: &[u8]) -> Option<(u8, u8)> {
+    let pos = aml.windows(4).position(|window| window == b"_S5_")?;
+
+    let named = match pos {
+        0 => false,
+        1 => aml[0] == NAME_OP,
+        _ => aml[pos - 1] == NAME_OP || (aml[pos - 2] == NAME_OP && aml[pos - 1] == ROOT_CHAR),
+    };
+    if !named || *aml.get(pos + 4)? != PACKAGE_OP {
+        return None;
+    }
+
+    // the top two bits of the first byte say how many more bytes the package length takes,
+    // the element count follows it
+    let mut idx = pos + 5;
+    idx += ((*aml.get(idx)? >> 6) as usize) + 2;
+
+    let mut element = || {
+        if *aml.get(idx)? == BYTE_PREFIX {
+            idx += 1;
+        }
+        // ZeroOp and OneOp are their own values
+        let value = *aml.get(idx)?;
+        idx += 1;
+        Some(value)
+    };
+
+    let slp_typ_a = element()?;
+    let slp_typ_b = element()?;
+
+    Some((slp_typ_a, slp_typ_b))
+}
+
+/// the pm1 control value that enters the sleep state `slp_typ`, the other bits are kept
+pub fn pm1_control_value(current: u16, slp_typ: u8) -> u16 {
+    (current & !(SLP_TYP_MASK | SLP_EN))
+        | (((slp_typ as u16) << SLP_TYP_SHIFT) & SLP_TYP_MASK)
+        | SLP_EN
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PowerControl {
+    pub pm1a_control: u16,
+    pub pm1b_control: Option<u16>,
+    pub slp_typ_a: u8,
+    pub slp_typ_b: u8,
+    /// where `acpi_enable` is written to take the chipset away from smm, 0 if it's always in
+    /// acpi mode
+    pub smi_command: u16,
+    pub acpi_enable: u8,
+}
+
+impl PowerControl {
+    /// what qemu puts in its \_S5 package, used when the dsdt can't be read
+    pub const FALLBACK_SLP_TYP: u8 = 0;
+
+    /// None without a pm1a control block, that one is required
+    pub fn new(fadt: &Fadt, dsdt: Option<&[u8]>) -> Option<Self> {
+        let (slp_typ_a, slp_typ_b) = dsdt
+            .and_then(find_s5_sleep_types)
+            .unwrap_or((Self::FALLBACK_SLP_TYP, Self::FALLBACK_SLP_TYP));
+
+        Some(Self {
+            pm1a_control: fadt.pm1a_control_port()?,
+            pm1b_control: fadt.pm1b_control_port(),
+            slp_typ_a,
+            slp_typ_b,
+            smi_command: fadt.smi_command as u16,
+            acpi_enable: fadt.acpi_enable,
+        })
+    }
+
+    /// the firmware might still be in legacy mode, SLP_EN does nothing until SCI_EN is set
+    fn enable_acpi(&self) {
+        let mut pm1a: Port<u16> = Port::new(self.pm1a_control);
+
+        unsafe {
+            if pm1a.read() & SCI_EN != 0 || self.smi_command == 0 || self.acpi_enable == 0 {
+                return;
+            }
+
+            Port::<u8>::new(self.smi_command).write(self.acpi_enable);
+
+            for _ in 0..ACPI_ENABLE_SPINS {
+                if pm1a.read() & SCI_EN != 0 {
+                    return;
+                }
+                core::hint::spin_loop();
+            }
+        }
+
+        log!("The firmware didn't switch to ACPI mode");
+    }
+
+    /// writes SLP_TYP | SLP_EN, only returns if the machine didn't go off
+    pub fn enter_s5(&self) {
+        self.enable_acpi();
+
+        unsafe {
+            let mut pm1a: Port<u16> = Port::new(self.pm1a_control);
+            let current = pm1a.read();
+            pm1a.write(pm1_control_value(current, self.slp_typ_a));
+
+            if let Some(pm1b) = self.pm1b_control {
+                let mut pm1b: Port<u16> = Port::new(pm1b);
+                let current = pm1b.read();
+                pm1b.write(pm1_control_value(current, self.slp_typ_b));
+            }
+        }
+    }
+}
+
+/// where writing the reset value restarts the machine
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetRegister {
+    Io {
+        port: u16,
+        value: u8,
+    },
+    /// a register of a device on pci bus 0
+    PciConfig {
+        device: u8,
+        function: u8,
+        offset: u8,
+        value: u8,
+    },
+}
+
+impl ResetRegister {
+    /// None if the fadt doesn't advertise one. Memory mapped ones would have to be mapped
+    /// first and no x86 firmware seems to use them, they're left out too
+    pub fn new(fadt: &Fadt) -> Option<Self> {
+        let register = fadt.reset_register;
+        let (address, value) = (register.address, fadt.reset_value);
+
+        if fadt.flags & RESET_REG_SUP == 0 || address == 0 {
+            return None;
+        }
+
+        match register.address_space {
+            ADDRESS_SPACE_SYSTEM_IO => Some(Self::Io {
+                port: address as u16,
+                value,
+            }),
+
+            // offset in bits 0..16, function in 16..32, device in 32..48
+            ADDRESS_SPACE_PCI_CONFIG => Some(Self::PciConfig {
+                device: (address >> 32) as u8,
+                function: (address >> 16) as u8,
+                offset: address as u8,
+                value,
+            }),
+
+            _ => None,
+        }
+    }
+
+    pub fn write(&self) {
+        match *self {
+            Self::Io { port, value } => unsafe { Port::<u8>::new(port).write(value) },
+
+            Self::PciConfig {
+                device,
+                function,
+                offset,
+                value,
+            } => unsafe {
+                let address = PCI_CONFIG_ENABLE
+                    | (device as u32) << 11
+                    | (function as u32) << 8
+                    | (offset as u32 & 0xFC);
+                Port::<u32>::new(PCI_CONFIG_ADDRESS_PORT).write(address);
+                Port::<u8>::new(PCI_CONFIG_DATA_PORT + (offset as u16 & 0b11)).write(value);
+            },
+        }
+    }
+}
+
+/// reads the tables shutdown and reboot need, the dsdt is only scanned for \_S5
+pub fn init_power_control(fadt_ptr: VirtAddr) {
+    let header = unsafe { *(fadt_ptr.as_ptr() as *const AcpiSdtHeader) };
+    let bytes =
+        unsafe { core::slice::from_raw_parts(fadt_ptr.as_ptr::<u8>(), header.length as usize) };
+
+    let Some(fadt) = Fadt::from_bytes(bytes) else {
+        log!("Malformed FADT, no ACPI shutdown");
+        return;
+    };
+
+    match ResetRegister::new(&fadt) {
+        Some(reset_register) => {
+            log!("ACPI reset register: {:?}", reset_register);
+            let _ = RESET_REGISTER.set(reset_register);
+        }
+        None => log!("No usable ACPI reset register"),
+    }
+
+    let dsdt = match fadt.dsdt_addr() {
+        0 => None,
+        addr => {
+            let ptr = get_hhdm_offset() + addr;
+            let header = unsafe { *(ptr.as_ptr() as *const AcpiSdtHeader) };
+            Some(unsafe { core::slice::from_raw_parts(ptr.as_ptr::<u8>(), header.length as usize) })
+        }
+    };
+
+    match PowerControl::new(&fadt, dsdt) {
+        Some(power_control) => {
+            log!("ACPI power control: {:?}", power_control);
+            let _ = POWER_CONTROL.set(power_control);
+        }
+        None => log!("No PM1a control block, no ACPI shutdown"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytemuck::Zeroable;
+
+    use super::{
+        ADDRESS_SPACE_PCI_CONFIG, ADDRESS_SPACE_SYSTEM_IO, ADDRESS_SPACE_SYSTEM_MEMORY, Fadt,
+        GenericAddress, PowerControl, RESET_REG_SUP, ResetRegister, find_s5_sleep_types,
+        pm1_control_value,
+    };
+    use crate::{end_test, test_name};
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn shutdown_registers() {
+        test_name!("acpi shutdown register values");
+
+        // Name(_S5_, Package(4) { 0x05, 0x05, Zero, Zero })
+        let aml = [
+            0x10, 0x08, b'\\', b'_', b'S', b'B', b'_', 0x08, b'_', b'S', b'5', b'_', 0x12, 0x0A,
+            0x04, 0x0A, 0x05, 0x0A, 0x05, 0x00, 0x00,
+        ];
+        assert_eq!(find_s5_sleep_types(&aml), Some((5, 5)));
+
+        // Name(\_S5_, Package(4) { Zero, One, ... })
+        let aml = [
+            0x08, b'\\', b'_', b'S', b'5', b'_', 0x12, 0x06, 0x04, 0x00, 0x01, 0x00, 0x00,
+        ];
+        assert_eq!(find_s5_sleep_types(&aml), Some((0, 1)));
+
+        // a method call instead of a name, or cut short
+        assert_eq!(
+            find_s5_sleep_types(&[0x14, b'_', b'S', b'5', b'_', 0x12]),
+            None
+        );
+        assert_eq!(
+            find_s5_sleep_types(&[0x08, b'_', b'S', b'5', b'_', 0x12, 0x06]),
+            None
+        );
+        assert_eq!(find_s5_sleep_types(b"no sleep states here"), None);
+
+        // SCI_EN and the other bits stay, an old SLP_TYP doesn't leak through
+        assert_eq!(pm1_control_value(0x0001, 5), 0x3401);
+        assert_eq!(pm1_control_value(0x1C01, 0), 0x2001);
+
+        let mut fadt = Fadt::zeroed();
+        fadt.header.signature = *b"FACP";
+        fadt.header.length = 116;
+        fadt.pm1a_control_block = 0x404;
+        fadt.smi_command = 0xB2;
+        fadt.acpi_enable = 0xF1;
+
+        // a revision 1 table stops before the extended fields
+        let parsed = Fadt::from_bytes(&bytemuck::bytes_of(&fadt)[..116]).unwrap();
+        assert_eq!(parsed.pm1a_control_port(), Some(0x404));
+        assert_eq!(parsed.pm1b_control_port(), None);
+
+        let power_control = PowerControl::new(&parsed, Some(&aml[..])).unwrap();
+        assert_eq!(
+            power_control,
+            PowerControl {
+                pm1a_control: 0x404,
+                pm1b_control: None,
+                slp_typ_a: 0,
+                slp_typ_b: 1,
+                smi_command: 0xB2,
+                acpi_enable: 0xF1,
+            }
+        );
+
+        // without a usable dsdt qemu's values are assumed
+        let power_control = PowerControl::new(&parsed, None).unwrap();
+        assert_eq!(power_control.slp_typ_a, PowerControl::FALLBACK_SLP_TYP);
+
+        // the extended block wins when it's an io port
+        fadt.header.length = size_of::<Fadt>() as u32;
+        fadt.x_pm1a_control_block = GenericAddress {
+            address_space: ADDRESS_SPACE_SYSTEM_IO,
+            address: 0x604,
+            ..Default::default()
+        };
+        let parsed = Fadt::from_bytes(bytemuck::bytes_of(&fadt)).unwrap();
+        assert_eq!(parsed.pm1a_control_port(), Some(0x604));
+
+        // nothing to write to
+        fadt.pm1a_control_block = 0;
+        fadt.x_pm1a_control_block = GenericAddress::default();
+        assert_eq!(PowerControl::new(&fadt, None), None);
+
+        assert!(Fadt::from_bytes(b"APIC").is_none());
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn reset_register_decoding() {
+        test_name!("acpi reset register decoding");
+
+        // what qemu's q35 fadt has, 0x6 to the reset control register at 0xCF9
+        let mut fadt = Fadt::zeroed();
+        fadt.header.signature = *b"FACP";
+        fadt.header.length = size_of::<Fadt>() as u32;
+        fadt.flags = RESET_REG_SUP;
+        fadt.reset_register = GenericAddress {
+            address_space: ADDRESS_SPACE_SYSTEM_IO,
+            bit_width: 8,
+            address: 0xCF9,
+            ..Default::default()
+        };
+        fadt.reset_value = 0x6;
+
+        let mut bytes = bytemuck::bytes_of(&fadt).to_vec();
+        let parsed = Fadt::from_bytes(&bytes).unwrap();
+        assert_eq!(
+            ResetRegister::new(&parsed),
+            Some(ResetRegister::Io {
+                port: 0xCF9,
+                value: 0x6
+            })
+        );
+
+        // the register is only there when the flag says so
+        bytes[112..116].copy_from_slice(&0u32.to_le_bytes());
+        assert_eq!(ResetRegister::new(&Fadt::from_bytes(&bytes).unwrap()), None);
+
+        // a revision 1 table ends before the reset register
+        let parsed = Fadt::from_bytes(&bytemuck::bytes_of(&fadt)[..116]).unwrap();
+        assert_eq!(ResetRegister::new(&parsed), None);
+
+        // device 0x1F function 3 offset 0x42 on bus 0
+        fadt.reset_register = GenericAddress {
+            address_space: ADDRESS_SPACE_PCI_CONFIG,
+            address: 0x001F_0003_0042,
+            ..Default::default()
+        };
+        assert_eq!(
+            ResetRegister::new(&fadt),
+            Some(ResetRegister::PciConfig {
+                device: 0x1F,
+                function: 3,
+                offset: 0x42,
+                value: 0x6
+            })
+        );
+
+        fadt.reset_register.address_space = ADDRESS_SPACE_SYSTEM_MEMORY;
+        assert_eq!(ResetRegister::new(&fadt), None);
+
+        end_test!();
+    }
+}
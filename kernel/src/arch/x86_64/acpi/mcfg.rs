@@ -1,5 +1,4 @@
-use crate::log;
-use alloc::{collections::btree_map::BTreeMap, vec, vec::Vec};
+use alloc::vec::Vec;
 use bytemuck::{Pod, Zeroable};
 use x86_64::{
     PhysAddr, VirtAddr,
@@ -9,7 +8,6 @@ use x86_64::{
 use crate::arch::x86_64::{
     acpi::{AcpiSdtHeader, MMIO_PAGE_TABLE_FLAGS},
     memory::{PAGE_SIZE, PAGE_SIZE_2_MIB, get_hhdm_offset, page_table::KERNEL_PAGE_TABLE},
-    pcie::{PciDevice, PciHeaderPartial},
 };
 
 #[derive(Debug, Clone, Copy, Pod, Zeroable)]
@@ -56,82 +54,35 @@ pub fn parse_mcfg(mut ptr: VirtAddr) -> McfgTable {
     McfgTable { header, entries }
 }
 
-const BUS_DEVICE_COUNT: u64 = 32;
-const DEVICE_FUNCTION_COUNT: u64 = 8;
-
-pub fn check_function(
-    address: VirtAddr,
-    devices: &mut BTreeMap<u8, BTreeMap<u8, BTreeMap<u8, Vec<PciDevice>>>>,
-) {
-    let header: PciHeaderPartial =
-        unsafe { (address.as_ptr() as *const PciHeaderPartial).read_volatile() };
-
-    if header.vendor_id != 0xFFFF {
-        let device = PciDevice {
-            address,
-            header_partial: header,
-        };
-
-        devices
-            .entry(header.class_code)
-            .or_insert_with(|| {
-                let mut map = BTreeMap::new();
-                map.insert(header.subclass, BTreeMap::new());
-                map
-            })
-            .entry(header.subclass)
-            .or_insert_with(|| {
-                let mut map = BTreeMap::new();
-                map.insert(header.prog_if, vec![device.clone()]);
-                map
-            })
-            .entry(header.prog_if)
-            .and_modify(|v| v.push(device.clone()))
-            .or_insert(vec![device]);
+/// each bus gets 1mib of configuration space, 4kib per function
+const BUS_SHIFT: u64 = 20;
+const DEVICE_SHIFT: u64 = 15;
+const FUNCTION_SHIFT: u64 = 12;
+
+impl McfgEntry {
+    /// the base address is where bus 0 would be even if the entry starts at a later bus
+    pub fn function_address(&self, bus: u8, device: u8, function: u8) -> PhysAddr {
+        PhysAddr::new(
+            self.base_addr
+                + ((bus as u64) << BUS_SHIFT)
+                + ((device as u64) << DEVICE_SHIFT)
+                + ((function as u64) << FUNCTION_SHIFT),
+        )
     }
-}
-
-pub fn iterate_pcie_buses(
-    entry: &McfgEntry,
-    devices: &mut BTreeMap<u8, BTreeMap<u8, BTreeMap<u8, Vec<PciDevice>>>>,
-) {
-    let base = get_hhdm_offset() + entry.base_addr;
-
-    for (bus_no, _) in (entry.start_pci_bus_number..=entry.end_pci_bus_number).enumerate() {
-        let bus_no = bus_no as u64;
-
-        for device_no in 0..BUS_DEVICE_COUNT {
-            for function_no in 0..DEVICE_FUNCTION_COUNT {
-                let address = base + ((bus_no << 20) + (device_no << 15) + (function_no << 12));
 
-                check_function(address, devices);
-            }
-        }
-    }
-}
+    /// maps the configuration space of every bus in the entry
+    pub fn map_config_space(&self) {
+        let page_table = KERNEL_PAGE_TABLE
+            .get()
+            .expect("Failed to get page table")
+            .spin_acquire_lock();
 
-pub fn iterate_pcie_entries(
-    entries: &[McfgEntry],
-) -> BTreeMap<u8, BTreeMap<u8, BTreeMap<u8, Vec<PciDevice>>>> {
-    let mut res: BTreeMap<u8, BTreeMap<u8, BTreeMap<u8, Vec<PciDevice>>>> = BTreeMap::new();
-
-    let page_table = KERNEL_PAGE_TABLE
-        .get()
-        .expect("Failed to get page table")
-        .spin_acquire_lock();
-
-    for entry in entries.iter() {
-        let pci_bus_count = entry.end_pci_bus_number as u64 - entry.start_pci_bus_number as u64 + 1;
-        let base_phys = PhysAddr::new(entry.base_addr);
+        let base_phys = self.function_address(self.start_pci_bus_number, 0, 0);
+        let end = self.function_address(self.end_pci_bus_number, 0, 0) + (1 << BUS_SHIFT);
 
         // map this entry to memory with as much as 2mib pages as possible
         let aligned_up_phys_addr = base_phys.align_up(PAGE_SIZE_2_MIB as u64);
-
-        let length =
-            pci_bus_count * BUS_DEVICE_COUNT * DEVICE_FUNCTION_COUNT * PAGE_SIZE as u64;
-
-        let end = base_phys + length;
-        let aligned_down_end = end.align_down(PAGE_SIZE_2_MIB);
+        let aligned_down_end = end.align_down(PAGE_SIZE_2_MIB as u64);
 
         for addr in (base_phys.as_u64()..aligned_up_phys_addr.as_u64()).step_by(PAGE_SIZE as usize)
         {
@@ -162,10 +113,5 @@ pub fn iterate_pcie_entries(
                 &mut None,
             );
         }
-
-        iterate_pcie_buses(entry, &mut res);
     }
-
-    log!("Found devices: {:#?}", res);
-    res
 }
@@ -7,9 +7,16 @@ use alloc::{vec, vec::Vec};
 use bytemuck::{Pod, Zeroable};
 use lazy_static::lazy_static;
 use limine::request::RsdpRequest;
-use x86_64::{VirtAddr, structures::paging::PageTableFlags};
+use x86_64::{
+    VirtAddr,
+    instructions::{interrupts, port::Port},
+    structures::paging::PageTableFlags,
+};
 
-use crate::arch::x86_64::memory::get_hhdm_offset;
+use crate::{
+    arch::x86_64::{acpi::facp::POWER_CONTROL, memory::get_hhdm_offset},
+    hal::vfs::sync_all,
+};
 
 #[derive(Clone, Copy, Pod, Zeroable, Default, Debug)]
 #[repr(C, packed)]
@@ -147,6 +154,34 @@ pub fn find_fadt(pointers: &[VirtAddr]) -> Option<VirtAddr> {
     find_table(pointers, [b'F', b'A', b'C', b'P'])
 }
 
+/// qemu's isa debug exit style power off ports, newer machines listen on the first one
+const QEMU_SHUTDOWN_PORT: u16 = 0x604;
+const BOCHS_SHUTDOWN_PORT: u16 = 0xB004;
+const QEMU_SHUTDOWN_VALUE: u16 = 0x2000;
+
+/// enters S5 through the fadt's pm1 control blocks, without a usable fadt the qemu and bochs
+/// ports are tried instead. Everything cached is written to the drives first
+pub async fn shutdown() -> ! {
+    sync_all().await;
+
+    interrupts::disable();
+    log!("Shutting down");
+
+    if let Some(power_control) = POWER_CONTROL.get() {
+        power_control.enter_s5();
+    }
+
+    unsafe {
+        Port::<u16>::new(QEMU_SHUTDOWN_PORT).write(QEMU_SHUTDOWN_VALUE);
+        Port::<u16>::new(BOCHS_SHUTDOWN_PORT).write(QEMU_SHUTDOWN_VALUE);
+    }
+
+    log!("The machine is still running, halting");
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
 lazy_static! {
     pub static ref MMIO_PAGE_TABLE_FLAGS: PageTableFlags = PageTableFlags::PRESENT
         | PageTableFlags::WRITABLE
@@ -0,0 +1,99 @@
+use core::arch::x86_64::__cpuid;
+
+use alloc::{string::String, vec::Vec};
+
+/// (leaf 1 register, bit, name) of the features worth reporting
+const EDX_FEATURES: [(u32, &str); 8] = [
+    (0, "fpu"),
+    (4, "tsc"),
+    (5, "msr"),
+    (9, "apic"),
+    (15, "cmov"),
+    (23, "mmx"),
+    (25, "sse"),
+    (26, "sse2"),
+];
+
+const ECX_FEATURES: [(u32, &str); 6] = [
+    (0, "sse3"),
+    (9, "ssse3"),
+    (19, "sse4_1"),
+    (20, "sse4_2"),
+    (21, "x2apic"),
+    (28, "avx"),
+];
+
+#[derive(Debug, Clone)]
+pub struct CpuInfo {
+    pub vendor: String,
+    /// empty when the cpu has no brand string leaves
+    pub brand: String,
+    pub family: u32,
+    pub model: u32,
+    pub stepping: u32,
+    pub features: Vec<&'static str>,
+}
+
+fn registers_to_string(registers: &[u32]) -> String {
+    let bytes: Vec<u8> = registers
+        .iter()
+        .flat_map(|reg| reg.to_le_bytes())
+        .take_while(|b| *b != 0)
+        .collect();
+
+    String::from_utf8_lossy(&bytes).trim().into()
+}
+
+impl CpuInfo {
+    pub fn read() -> Self {
+        let leaf0 = __cpuid(0);
+        let vendor = registers_to_string(&[leaf0.ebx, leaf0.edx, leaf0.ecx]);
+
+        let leaf1 = __cpuid(1);
+        let base_family = (leaf1.eax >> 8) & 0xF;
+        let base_model = (leaf1.eax >> 4) & 0xF;
+
+        let family = if base_family == 0xF {
+            base_family + ((leaf1.eax >> 20) & 0xFF)
+        } else {
+            base_family
+        };
+        let model = if base_family == 0x6 || base_family == 0xF {
+            (((leaf1.eax >> 16) & 0xF) << 4) | base_model
+        } else {
+            base_model
+        };
+
+        let features = EDX_FEATURES
+            .iter()
+            .filter(|(bit, _)| leaf1.edx & (1 << bit) != 0)
+            .chain(
+                ECX_FEATURES
+                    .iter()
+                    .filter(|(bit, _)| leaf1.ecx & (1 << bit) != 0),
+            )
+            .map(|(_, name)| *name)
+            .collect();
+
+        let brand = if __cpuid(0x8000_0000).eax >= 0x8000_0004 {
+            let registers: Vec<u32> = (0x8000_0002..=0x8000_0004)
+                .flat_map(|leaf| {
+                    let res = __cpuid(leaf);
+                    [res.eax, res.ebx, res.ecx, res.edx]
+                })
+                .collect();
+            registers_to_string(&registers)
+        } else {
+            String::new()
+        };
+
+        Self {
+            vendor,
+            brand,
+            family,
+            model,
+            stepping: leaf1.eax & 0xF,
+            features,
+        }
+    }
+}
@@ -6,15 +6,24 @@ pub enum ErrNo {
     OperationNotPermitted = -0x1,
     NoSuchFileOrDirectory = -0x2,
     InputOrOutputErr = -0x3,
+    ArgumentListTooLong = -0x7,
+    ExecFormatError = -0x8,
     BadFd = -0x9,
+    OutOfMemory = -0xc,
     PermissionDenied = -0xd,
+    BadAddress = -0xe,
     FileExists = -0x11,
     NotADirectory = -0x14,
     IsADirectory = -0x15,
     InvalidArgument = -0x16,
     TooManyOpenFiles = -0x18,
     NoSpaceLeft = -0x1c,
+    IllegalSeek = -0x1d,
+    ReadOnlyFilesystem = -0x1e,
+    BrokenPipe = -0x20,
     OperationNotSupported = -0x2d,
+    NameTooLong = -0x24,
+    TooManySymbolicLinks = -0x28,
     DirectoryNotEmpty = -0x42,
 }
 
@@ -34,12 +43,14 @@ impl From<HalFsIOErr> for ErrNo {
 
             HalFsIOErr::FileExists => Self::FileExists,
             HalFsIOErr::DirectoryNotEmpty => Self::DirectoryNotEmpty,
-            HalFsIOErr::NoPermsProvided => Self::OperationNotPermitted,
-            HalFsIOErr::BufTooSmall => Self::InvalidArgument,
+            HalFsIOErr::NoPermsProvided | HalFsIOErr::ReservedInode => Self::OperationNotPermitted,
+            HalFsIOErr::BufTooSmall | HalFsIOErr::InvalidArgument => Self::InvalidArgument,
             HalFsIOErr::IsDirectory => Self::IsADirectory,
             HalFsIOErr::NoSpaceLeft | HalFsIOErr::NoAvailableInode => Self::NoSpaceLeft,
             HalFsIOErr::NotADirectory => Self::NotADirectory,
             HalFsIOErr::Unsupported => Self::OperationNotSupported,
+            HalFsIOErr::ReadOnlyFilesystem => Self::ReadOnlyFilesystem,
+            HalFsIOErr::TooManySymlinks => Self::TooManySymbolicLinks,
         }
     }
 }
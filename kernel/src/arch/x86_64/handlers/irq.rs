@@ -1,10 +1,16 @@
-use core::{arch::naked_asm, time::Duration};
+use core::{
+    arch::naked_asm,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
 use crate::{
     BSP_IDX,
-    arch::x86_64::timer::MILLISECOND_TO_NANO_SECOND,
+    arch::x86_64::timer::Instant,
     drivers::ata::sata::task::ahci_interrupt_handler_by_idx,
-    ejcineque::wakers::{PRIMARY_IDE_WAKERS, SECONDARY_IDE_WAKERS, TIMER_WAKERS},
+    ejcineque::{
+        time::wheel_tick,
+        wakers::{PRIMARY_IDE_WAKERS, SECONDARY_IDE_WAKERS, TIMER_WAKERS},
+    },
     get_per_cpu_data, get_per_cpu_data_mut,
 };
 use macros::ahci_interrupt_handler_template;
@@ -17,7 +23,7 @@ use crate::{
     arch::x86_64::{
         acpi::apic::get_local_apic,
         handlers::InterruptNoErrcodeFrame,
-        scheduler::{DEFAULT_TICKS_PER_THREAD, syscall::resume_thread},
+        scheduler::{signal::run_next_thread, steal::request_work},
     },
     hal::keyboard::process_scancode,
     handler_wrapper_noerrcode, set_register, set_registers,
@@ -45,9 +51,44 @@ pub enum IrqIndex {
     SecondaryIDE,
 }
 
+impl IrqIndex {
+    pub const ALL: [IrqIndex; 16] = [
+        IrqIndex::Timer,
+        IrqIndex::Keyboard,
+        IrqIndex::Cascade,
+        IrqIndex::Com24,
+        IrqIndex::Com13,
+        IrqIndex::Sound,
+        IrqIndex::Floppy,
+        IrqIndex::Printer,
+        IrqIndex::Clock,
+        IrqIndex::Video,
+        IrqIndex::Open1,
+        IrqIndex::Open2,
+        IrqIndex::Mouse,
+        IrqIndex::Coprocessor,
+        IrqIndex::PrimaryIDE,
+        IrqIndex::SecondaryIDE,
+    ];
+}
+
+/// how many times each isa irq has fired, summed over every core
+static IRQ_COUNTS: [AtomicU64; 16] = [const { AtomicU64::new(0) }; 16];
+
+fn count_irq(irq: IrqIndex) {
+    IRQ_COUNTS[irq as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn irq_count(irq: IrqIndex) -> u64 {
+    IRQ_COUNTS[irq as usize].load(Ordering::Relaxed)
+}
+
 extern "C" fn timer_handler_inner(stack_frame: InterruptNoErrcodeFrame) {
+    count_irq(IrqIndex::Timer);
+
     x86_64::instructions::interrupts::without_interrupts(|| {
-        for w in TIMER_WAKERS.lock().drain(..) {
+        let due = TIMER_WAKERS.lock().expire_due(wheel_tick(Instant::now()));
+        for w in due {
             w.wake();
         }
 
@@ -57,44 +98,30 @@ extern "C" fn timer_handler_inner(stack_frame: InterruptNoErrcodeFrame) {
 
         let per_cpu_data = get_per_cpu_data_mut!();
 
+        per_cpu_data.scheduler_context.publish_load();
+
         if let Some(current_thread_idx) = per_cpu_data.scheduler_context.current_thread {
-            if let Some(ref mut thread) = per_cpu_data
-                .scheduler_context
-                .thread_map
-                .get_mut(&current_thread_idx)
-            {
-                let time_left = thread.time_left.saturating_sub(Duration::from_nanos_u128(
-                    MILLISECOND_TO_NANO_SECOND / per_cpu_data.apic_timer_ticks_per_ms as u128,
-                ));
-
-                if time_left.is_zero() {
-                    let registers = &mut thread.state.registers;
-
-                    set_registers!(registers, stack_frame);
-                    thread.state.state = crate::arch::x86_64::scheduler::State::Paused {
-                        instruction_pointer: stack_frame.rip,
-                        rflags: RFlags::from_bits_retain(stack_frame.rflags),
-                    };
-                    thread.state.stack_pointer = VirtAddr::new(stack_frame.rsp);
-
-                    let threads = &mut per_cpu_data.scheduler_context.thread_queue;
-                    threads.push_back(current_thread_idx);
-
-                    while let Some(thread_id) =
-                        per_cpu_data.scheduler_context.thread_queue.pop_front()
-                    {
-                        if let Some(thread) = per_cpu_data
-                            .scheduler_context
-                            .thread_map
-                            .get_mut(&thread_id)
-                        {
-                            thread.time_left = DEFAULT_TICKS_PER_THREAD;
-
-                            resume_thread(thread);
-                        }
-                    }
-                    panic!("KERNEL THREAD IS DEAD")
+            // nothing to save for the idle thread, it just stops once there's work
+            if per_cpu_data.scheduler_context.is_idle(current_thread_idx) {
+                if !per_cpu_data.scheduler_context.thread_queue.is_empty() {
+                    run_next_thread(&mut per_cpu_data.scheduler_context);
                 }
+
+                // nothing here, see if another core has too much
+                request_work(&per_cpu_data.scheduler_context);
+            } else if per_cpu_data.scheduler_context.charge_tick() {
+                // the slice is used up and the thread already waits at the back of the queue
+                let thread = per_cpu_data.scheduler_context.get_current_thread_ref();
+                let registers = &mut thread.state.registers;
+
+                set_registers!(registers, stack_frame);
+                thread.state.state = crate::arch::x86_64::scheduler::State::Paused {
+                    instruction_pointer: stack_frame.rip,
+                    rflags: RFlags::from_bits_retain(stack_frame.rflags),
+                };
+                thread.state.stack_pointer = VirtAddr::new(stack_frame.rsp);
+
+                run_next_thread(&mut per_cpu_data.scheduler_context);
             }
         }
     });
@@ -108,6 +135,8 @@ pub extern "x86-interrupt" fn timer_handler(_stack_frame: InterruptStackFrame) {
 }
 
 extern "C" fn keyboard_handler_inner(_stack_frame: InterruptNoErrcodeFrame) {
+    count_irq(IrqIndex::Keyboard);
+
     let mut port = Port::new(0x60);
     let scancode: u8 = unsafe { port.read() };
     process_scancode(scancode);
@@ -121,6 +150,8 @@ pub extern "x86-interrupt" fn keyboard_handler(_stack_frame: InterruptStackFrame
 }
 
 extern "C" fn primary_ide_handler_inner(_stack_frame: InterruptNoErrcodeFrame) {
+    count_irq(IrqIndex::PrimaryIDE);
+
     x86_64::instructions::interrupts::without_interrupts(|| {
         for w in PRIMARY_IDE_WAKERS.lock().drain(..) {
             w.wake();
@@ -136,6 +167,8 @@ pub extern "x86-interrupt" fn primary_ide_handler(_stack_frame: InterruptStackFr
 }
 
 extern "C" fn secondary_ide_handler_inner(_stack_frame: InterruptNoErrcodeFrame) {
+    count_irq(IrqIndex::SecondaryIDE);
+
     x86_64::instructions::interrupts::without_interrupts(|| {
         for w in SECONDARY_IDE_WAKERS.lock().drain(..) {
             w.wake();
@@ -150,4 +183,26 @@ pub extern "x86-interrupt" fn secondary_ide_handler(_stack_frame: InterruptStack
     handler_wrapper_noerrcode!(secondary_ide_handler_inner);
 }
 
+/// another core unmapped something and waits for this one, see
+/// [`SchedulerCpuContext::shoot_down`](crate::arch::x86_64::scheduler::SchedulerCpuContext::shoot_down)
+extern "C" fn tlb_shootdown_handler_inner(_stack_frame: InterruptNoErrcodeFrame) {
+    let context = &mut get_per_cpu_data_mut!().scheduler_context;
+    context.answer_shootdown();
+
+    get_local_apic().write_eoi(0);
+
+    // the interrupted thread belonged to a process that was just terminated, syscalls run with
+    // interrupts off so it was in user mode and nothing of it has to be saved
+    if let Some(id) = context.current_thread
+        && !context.thread_map.contains_key(&id)
+    {
+        run_next_thread(context);
+    }
+}
+
+#[unsafe(naked)]
+pub extern "x86-interrupt" fn tlb_shootdown_handler(_stack_frame: InterruptStackFrame) {
+    handler_wrapper_noerrcode!(tlb_shootdown_handler_inner);
+}
+
 ahci_interrupt_handler_template!();
@@ -4,10 +4,47 @@ use crate::log;
 use x86_64::structures::idt::{InterruptStackFrame, PageFaultErrorCode};
 
 use crate::{
-    arch::x86_64::handlers::{InterruptErrcodeFrame, InterruptNoErrcodeFrame},
-    handler_wrapper_errcode, handler_wrapper_noerrcode,
+    arch::x86_64::{
+        handlers::{InterruptErrcodeFrame, InterruptNoErrcodeFrame},
+        scheduler::{
+            process::resolve_user_fault,
+            signal::{SIGFPE, SIGILL, SIGSEGV, Signal, fault_current_thread},
+        },
+    },
+    get_per_cpu_data_mut, handler_wrapper_errcode, handler_wrapper_noerrcode,
 };
 
+pub const DIVIDE_ERROR_VECTOR: u8 = 0x0;
+pub const INVALID_OPCODE_VECTOR: u8 = 0x6;
+pub const GENERAL_PROTECTION_VECTOR: u8 = 0xd;
+pub const PAGE_FAULT_VECTOR: u8 = 0xe;
+
+pub fn is_user_mode(cs: u64) -> bool {
+    cs & 0b11 == 0b11
+}
+
+/// what a process gets for causing an exception, None for the ones only the kernel can cause
+pub fn exception_signal(vector: u8) -> Option<Signal> {
+    match vector {
+        DIVIDE_ERROR_VECTOR => Some(SIGFPE),
+        INVALID_OPCODE_VECTOR => Some(SIGILL),
+        GENERAL_PROTECTION_VECTOR | PAGE_FAULT_VECTOR => Some(SIGSEGV),
+        _ => None,
+    }
+}
+
+/// kills the process behind a fault from user mode and never returns then, kernel faults fall
+/// through to the caller
+fn kill_faulting_process(vector: u8, name: &str, cs: u64, rip: u64) {
+    if !is_user_mode(cs) {
+        return;
+    }
+
+    let signal = exception_signal(vector).expect("Not a fault a process can cause");
+    log!("[Exception: {}] at 0x{:x} in user mode", name, rip);
+    fault_current_thread(signal);
+}
+
 extern "C" fn breakpoint_handler_inner(stack_frame: InterruptNoErrcodeFrame) {
     log!("[Exception: Break Point]\n{:#?}", stack_frame);
 }
@@ -20,6 +57,23 @@ pub extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFram
 extern "C" fn pagefault_handler_inner(stack_frame: InterruptErrcodeFrame) {
     let faulting_address = x86_64::registers::control::Cr2::read().expect("Failed to get cr2");
     let err_code = PageFaultErrorCode::from_bits_truncate(stack_frame.err_code);
+
+    if is_user_mode(stack_frame.cs) {
+        // untouched pages of areas like the heap are handed out here
+        if !err_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+            && resolve_user_fault(faulting_address.as_u64())
+        {
+            return;
+        }
+
+        log!(
+            "Segmentation fault at 0x{:x}: {:?}",
+            faulting_address.as_u64(),
+            err_code
+        );
+        fault_current_thread(SIGSEGV);
+    }
+
     log!(
         "Page fault at 0x{:x}: {:#?}: {:?}",
         faulting_address.as_u64(),
@@ -36,6 +90,70 @@ pub extern "x86-interrupt" fn pagefault_handler(
     handler_wrapper_errcode!(pagefault_handler_inner);
 }
 
+extern "C" fn divide_error_handler_inner(stack_frame: InterruptNoErrcodeFrame) {
+    kill_faulting_process(
+        DIVIDE_ERROR_VECTOR,
+        "Divide Error",
+        stack_frame.cs,
+        stack_frame.rip,
+    );
+    panic!("[Kernal Panic: Divide Error]\n{:#?}", stack_frame);
+}
+
+#[unsafe(naked)]
+pub extern "x86-interrupt" fn divide_error_handler(_stack_frame: InterruptStackFrame) {
+    handler_wrapper_noerrcode!(divide_error_handler_inner);
+}
+
+extern "C" fn invalid_opcode_handler_inner(stack_frame: InterruptNoErrcodeFrame) {
+    kill_faulting_process(
+        INVALID_OPCODE_VECTOR,
+        "Invalid Opcode",
+        stack_frame.cs,
+        stack_frame.rip,
+    );
+    panic!("[Kernal Panic: Invalid Opcode]\n{:#?}", stack_frame);
+}
+
+#[unsafe(naked)]
+pub extern "x86-interrupt" fn invalid_opcode_handler(_stack_frame: InterruptStackFrame) {
+    handler_wrapper_noerrcode!(invalid_opcode_handler_inner);
+}
+
+/// a thread touched the fpu while another thread's state was loaded, see
+/// [`SchedulerCpuContext::claim_fpu`](crate::arch::x86_64::scheduler::SchedulerCpuContext::claim_fpu)
+extern "C" fn device_not_available_handler_inner(_stack_frame: InterruptNoErrcodeFrame) {
+    get_per_cpu_data_mut!().scheduler_context.claim_fpu();
+}
+
+#[unsafe(naked)]
+pub extern "x86-interrupt" fn device_not_available_handler(_stack_frame: InterruptStackFrame) {
+    handler_wrapper_noerrcode!(device_not_available_handler_inner);
+}
+
+extern "C" fn general_protection_handler_inner(stack_frame: InterruptErrcodeFrame) {
+    kill_faulting_process(
+        GENERAL_PROTECTION_VECTOR,
+        "General Protection Fault",
+        stack_frame.cs,
+        stack_frame.rip,
+    );
+
+    let err_code = stack_frame.err_code;
+    panic!(
+        "[Kernal Panic: General Protection Fault]\nErr Code: {:#x}\n{:#?}",
+        err_code, stack_frame
+    );
+}
+
+#[unsafe(naked)]
+pub extern "x86-interrupt" fn general_protection_handler(
+    _stack_frame: InterruptStackFrame,
+    _err_code: u64,
+) {
+    handler_wrapper_errcode!(general_protection_handler_inner);
+}
+
 extern "C" fn doublefault_handler_inner(stack_frame: InterruptErrcodeFrame) {
     let err_code = stack_frame.err_code;
     panic!(
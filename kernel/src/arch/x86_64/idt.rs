@@ -11,8 +11,10 @@ use x86_64::structures::idt::InterruptDescriptorTable;
 // 0-0x20: cpu exceptions
 // 0x20-0x30: isa
 // 0x30-0x38: ahci
+// 0x40: tlb shootdown
 pub const SPURIOUS_INTERRUPT_HANDLER_IDX: u8 = 0xFF;
 pub const AHCI_INTERRUPT_HANDLER_IDX: u8 = 0x30;
+pub const TLB_SHOOTDOWN_HANDLER_IDX: u8 = 0x40;
 
 static IDT: OnceCell<InterruptDescriptorTable> = OnceCell::new();
 
@@ -26,6 +28,11 @@ pub fn minimal_idt() -> InterruptDescriptorTable {
     let mut idt = InterruptDescriptorTable::new();
     idt.breakpoint.set_handler_fn(isr::breakpoint_handler);
     idt.double_fault.set_handler_fn(isr::doublefault_handler);
+    idt.divide_error.set_handler_fn(isr::divide_error_handler);
+    idt.invalid_opcode
+        .set_handler_fn(isr::invalid_opcode_handler);
+    idt.general_protection_fault
+        .set_handler_fn(isr::general_protection_handler);
     unsafe {
         idt.page_fault
             .set_handler_fn(isr::pagefault_handler)
@@ -39,6 +46,13 @@ pub fn init_idt(gsi_to_irq_mapping: [u32; 16]) {
     let mut idt = InterruptDescriptorTable::new();
     idt.breakpoint.set_handler_fn(isr::breakpoint_handler);
     idt.double_fault.set_handler_fn(isr::doublefault_handler);
+    idt.divide_error.set_handler_fn(isr::divide_error_handler);
+    idt.invalid_opcode
+        .set_handler_fn(isr::invalid_opcode_handler);
+    idt.general_protection_fault
+        .set_handler_fn(isr::general_protection_handler);
+    idt.device_not_available
+        .set_handler_fn(isr::device_not_available_handler);
 
     // the mapping usually maps timer to 2
     idt[PRIMARY_ISA_PIC_OFFSET + gsi_to_irq_mapping[IrqIndex::Timer as usize] as u8]
@@ -50,6 +64,7 @@ pub fn init_idt(gsi_to_irq_mapping: [u32; 16]) {
     idt[PRIMARY_ISA_PIC_OFFSET + gsi_to_irq_mapping[IrqIndex::SecondaryIDE as usize] as u8]
         .set_handler_fn(irq::secondary_ide_handler);
     idt[SPURIOUS_INTERRUPT_HANDLER_IDX].set_handler_fn(isr::spurious_interrupt_handler);
+    idt[TLB_SHOOTDOWN_HANDLER_IDX].set_handler_fn(irq::tlb_shootdown_handler);
     unsafe {
         idt.page_fault
             .set_handler_fn(isr::pagefault_handler)
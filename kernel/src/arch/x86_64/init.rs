@@ -1,5 +1,5 @@
 use crate::{IS_EXECUTOR_READY, SPAWNER};
-use alloc::sync::Arc;
+use alloc::{sync::Arc, vec::Vec};
 use limine::{mp::RequestFlags, request::MpRequest};
 
 use crate::{
@@ -26,20 +26,21 @@ pub static MP_REQUEST: MpRequest = MpRequest::new().with_flags(RequestFlags::emp
 use crate::{
     arch::x86_64::{
         acpi::{
-            apic::init_apic,
-            find_madt, find_mcfg,
-            mcfg::{iterate_pcie_entries, parse_mcfg},
-            parse_rsdp,
+            apic::init_apic, facp::init_power_control, find_fadt, find_madt, find_mcfg,
+            mcfg::parse_mcfg, parse_rsdp,
         },
         memory::{
             MemoryMappings,
             frame_allocator::{BitmapAllocator, FRAME_ALLOCATOR, deallocator_task},
             page_table::initialize_page_table,
+            pcid::init_pcid,
             per_cpu::setup_per_cpu_data,
         },
         mp::initialize_mp,
+        pcie::enumerate,
         pic::disable_pic,
         scheduler::{
+            fpu::init_fpu,
             load_kernel_thread,
             syscall::{enable_syscalls, set_per_cpu_data_for_core},
         },
@@ -87,6 +88,7 @@ pub fn init() -> ! {
         .set(Mutex::new(BitmapAllocator {
             bitmap: bit_map,
             next: 0,
+            zeroed: Vec::new(),
         }))
         .expect("Failed to set frame allocator");
 
@@ -124,15 +126,22 @@ pub fn init() -> ! {
 
     sync_tsc_lead(mp_response.cpus().len() as u32);
 
+    match find_fadt(&table_ptrs) {
+        Some(fadt) => init_power_control(fadt),
+        None => log!("No FADT found, shutdown falls back to the QEMU ports"),
+    }
+
     let mcfg = find_mcfg(&table_ptrs).expect("No mcfg found");
     let mcfg = parse_mcfg(mcfg);
     log!("mcfg table: {:?}", mcfg);
 
-    let mut device_tree = iterate_pcie_entries(&mcfg.entries);
+    let devices = enumerate(&mcfg.entries);
 
-    identify_storage_devices(&mut device_tree);
+    identify_storage_devices(&devices);
 
     enable_syscalls();
+    init_pcid();
+    init_fpu();
 
     log!("{}", local_apic.dump());
 
@@ -1,6 +1,9 @@
-use crate::ejcineque::sync::{
-    mpsc::unbounded::{UnboundedSender, unbounded_channel},
-    mutex::Mutex,
+use crate::ejcineque::{
+    futures::yield_now,
+    sync::{
+        mpsc::unbounded::{UnboundedSender, unbounded_channel},
+        mutex::Mutex,
+    },
 };
 use alloc::vec::Vec;
 use once_cell_no_std::OnceCell;
@@ -9,14 +12,55 @@ use x86_64::{
     structures::paging::{FrameAllocator, Page, PageTableFlags, PhysFrame, Size4KiB},
 };
 
-use crate::arch::x86_64::memory::{PAGE_SIZE, bitmap::BitMap, page_table::KERNEL_PAGE_TABLE};
+use crate::arch::x86_64::memory::{
+    PAGE_SIZE, bitmap::BitMap, get_hhdm_offset, page_table::KERNEL_PAGE_TABLE,
+};
+
+/// how many zeroed frames are kept around for allocations that have to be clean
+pub const ZEROED_POOL_SIZE: usize = 256;
 
 pub struct BitmapAllocator {
     pub bitmap: BitMap,
     pub next: usize,
+    /// zeroed by the deallocator, still marked as used in the bitmap
+    pub zeroed: Vec<PhysFrame>,
 }
 
 impl BitmapAllocator {
+    /// every frame the bitmap tracks, whether usable or not
+    pub fn total_frame_count(&self) -> usize {
+        self.bitmap.length as usize * 8
+    }
+
+    pub fn free_frame_count(&self) -> usize {
+        self.bitmap
+            .iter()
+            .map(|byte| byte.count_zeros() as usize)
+            .sum::<usize>()
+            + self.zeroed.len()
+    }
+
+    /// a frame full of zeros, only zeroed here if the pool ran dry
+    pub fn allocate_zeroed_frame(&mut self) -> Option<PhysFrame> {
+        if let Some(frame) = self.zeroed.pop() {
+            return Some(frame);
+        }
+
+        let frame = self.allocate_frame(&mut None)?;
+        zero_frame(frame);
+        Some(frame)
+    }
+
+    /// takes frames that have been zeroed already, what doesn't fit in the pool goes back to the
+    /// bitmap
+    pub fn recycle_zeroed(&mut self, frames: &[PhysFrame]) {
+        let room = ZEROED_POOL_SIZE.saturating_sub(self.zeroed.len());
+        let (pooled, rest) = frames.split_at(room.min(frames.len()));
+
+        self.zeroed.extend_from_slice(pooled);
+        self.free_frames(rest);
+    }
+
     pub fn free_frames(&mut self, frames: &[PhysFrame]) {
         for frame in frames.iter() {
             let idx = frame.start_address().as_u64() / PAGE_SIZE as u64;
@@ -113,7 +157,13 @@ unsafe impl FrameAllocator<Size4KiB, Option<&mut Vec<PhysFrame<Size4KiB>>>> for
             }
         }
 
-        None // Searched everything, no frames left
+        // the bitmap is out, but the pool still holds free frames
+        let frame = self.zeroed.pop()?;
+        if let Some(v) = context {
+            v.push(frame);
+        }
+
+        Some(frame)
     }
 }
 
@@ -167,6 +217,32 @@ pub fn setup_stack(guard_page_loc: u64, len_in_bytes_including_guard: u64) -> Vi
 
 pub static DEALLOCATOR_SENDER: OnceCell<UnboundedSender<Vec<PhysFrame>>> = OnceCell::new();
 
+pub fn zero_frame(frame: PhysFrame) {
+    unsafe {
+        core::ptr::write_bytes(
+            (get_hhdm_offset() + frame.start_address().as_u64()).as_mut_ptr::<u8>(),
+            0,
+            PAGE_SIZE as usize,
+        );
+    }
+}
+
+/// zeroes frames a thread left behind before anyone else can get them, so no process ever reads
+/// what a dead one had there. It yields after every frame to stay out of the way
+pub async fn scrub_frames(frames: Vec<PhysFrame>) {
+    for &frame in frames.iter() {
+        zero_frame(frame);
+        yield_now().await;
+    }
+
+    FRAME_ALLOCATOR
+        .get()
+        .expect("Failed to get allocator")
+        .lock()
+        .await
+        .recycle_zeroed(&frames);
+}
+
 /// intended to be used by interrupt handlers
 pub async fn deallocator_task() {
     let (tx, rx) = unbounded_channel::<Vec<PhysFrame>>();
@@ -175,11 +251,98 @@ pub async fn deallocator_task() {
         .expect("Failed to set deallocate sender");
 
     while let Some(v) = rx.recv().await {
-        FRAME_ALLOCATOR
+        scrub_frames(v).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{vec, vec::Vec};
+    use x86_64::{
+        PhysAddr,
+        structures::paging::{FrameAllocator, PhysFrame},
+    };
+
+    use super::{BitmapAllocator, FRAME_ALLOCATOR, scrub_frames};
+    use crate::{
+        arch::x86_64::memory::{PAGE_SIZE, bitmap::BitMap, get_hhdm_offset},
+        end_test,
+        terminal::test::block_on,
+        test_name,
+    };
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn freed_frames_come_back_zeroed() {
+        test_name!("a frame a thread freed is zeroed before it's handed out again");
+
+        let allocator = FRAME_ALLOCATOR
             .get()
-            .expect("Failed to get allocator")
-            .lock()
-            .await
-            .free_frames(&v);
+            .expect("Failed to get the frame allocator");
+        let frame = block_on(allocator.lock())
+            .allocate_frame(&mut None)
+            .expect("Failed to get physical frame");
+
+        // what a dead process left in there
+        let bytes = unsafe {
+            core::slice::from_raw_parts_mut(
+                (get_hhdm_offset() + frame.start_address().as_u64()).as_mut_ptr::<u8>(),
+                PAGE_SIZE as usize,
+            )
+        };
+        bytes.fill(0xa5);
+
+        // what the deallocator does with the frames of a dropped thread
+        block_on(scrub_frames(vec![frame]));
+
+        assert!(bytes.iter().all(|&byte| byte == 0));
+
+        // whichever frame the pool hands out, it's clean
+        let mut allocator = block_on(allocator.lock());
+        let pooled = allocator.zeroed.len();
+        let reused = allocator
+            .allocate_zeroed_frame()
+            .expect("Failed to get physical frame");
+        assert_eq!(allocator.zeroed.len(), pooled - 1);
+        let reused_bytes = unsafe {
+            core::slice::from_raw_parts(
+                (get_hhdm_offset() + reused.start_address().as_u64()).as_ptr::<u8>(),
+                PAGE_SIZE as usize,
+            )
+        };
+        assert!(reused_bytes.iter().all(|&byte| byte == 0));
+
+        allocator.free_frames(&[reused]);
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn full_bitmap_falls_back_to_the_pool() {
+        test_name!("with the bitmap used up frames still come out of the zeroed pool");
+
+        let mut bits = [0xffu8; 2];
+        let pooled = PhysFrame::containing_address(PhysAddr::new(0x10_0000));
+        let mut allocator = BitmapAllocator {
+            bitmap: BitMap {
+                start: bits.as_mut_ptr(),
+                length: bits.len() as u64,
+                page_length: 16,
+            },
+            next: 0,
+            zeroed: vec![pooled],
+        };
+
+        let mut taken = Vec::new();
+        assert_eq!(
+            allocator.allocate_frame(&mut Some(&mut taken)),
+            Some(pooled)
+        );
+        assert_eq!(taken, vec![pooled]);
+        assert_eq!(allocator.free_frame_count(), 0);
+        assert_eq!(allocator.allocate_frame(&mut None), None);
+
+        end_test!();
     }
 }
@@ -3,6 +3,7 @@ pub mod frame_allocator;
 pub mod heap;
 pub mod memmap;
 pub mod page_table;
+pub mod pcid;
 pub mod per_cpu;
 pub mod pmm;
 
@@ -48,12 +48,29 @@ impl KernelPageTable {
         unsafe {
             offset_table
                 .map_to(page, frame, flags, allocator.deref_mut(), context)
-                .unwrap_or_else(|_| panic!("Failed to map frame: {:?} to page {:?} with flags {:?}",
-                    frame, page, flags))
+                .unwrap_or_else(|_| {
+                    panic!(
+                        "Failed to map frame: {:?} to page {:?} with flags {:?}",
+                        frame, page, flags
+                    )
+                })
                 .flush();
         };
     }
 
+    /// returns the frame that was mapped there, freeing it is up to the caller
+    pub fn unmap(&self, page: Page<Size4KiB>) -> PhysFrame {
+        let mut offset_table =
+            unsafe { OffsetPageTable::new(&mut (*self.table_ptr), self.hhdm_offset) };
+
+        let (frame, flush) = offset_table
+            .unmap(page)
+            .unwrap_or_else(|_| panic!("Failed to unmap page {:?}", page));
+        flush.flush();
+
+        frame
+    }
+
     pub fn update_flags(&self, page: Page<Size4KiB>, flags: PageTableFlags) {
         let mut offset_table =
             unsafe { OffsetPageTable::new(&mut (*self.table_ptr), self.hhdm_offset) };
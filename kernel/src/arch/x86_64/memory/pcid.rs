@@ -0,0 +1,92 @@
+use core::arch::x86_64::__cpuid;
+
+use alloc::collections::btree_map::BTreeMap;
+use x86_64::{
+    PhysAddr,
+    registers::control::{Cr3, Cr4, Cr4Flags},
+};
+
+use crate::{arch::x86_64::scheduler::ProcessId, get_per_cpu_data_mut, log};
+
+/// leaf 1 ecx
+const PCID_CPUID_BIT: u32 = 17;
+/// set in cr3 to keep what the tlb cached under the new pcid
+pub const CR3_NO_FLUSH: u64 = 1 << 63;
+/// pcid 0 is what the kernel booted with, processes get the rest
+const PCID_COUNT: u16 = 1 << 12;
+
+pub fn is_pcid_supported() -> bool {
+    __cpuid(1).ecx & (1 << PCID_CPUID_BIT) != 0
+}
+
+/// turns on pcids for this core, false if the cpu can't. cr4.PCIDE can only be set while the low
+/// bits of cr3 are clear
+pub fn enable_pcid() -> bool {
+    if !is_pcid_supported() || Cr3::read_raw().1 != 0 {
+        return false;
+    }
+
+    unsafe {
+        Cr4::update(|flags| flags.insert(Cr4Flags::PCID));
+    }
+
+    true
+}
+
+/// called once on every core, the scheduler falls back to flushing on every switch without pcids
+pub fn init_pcid() {
+    let enabled = enable_pcid();
+    get_per_cpu_data_mut!().scheduler_context.pcids = enabled.then(PcidAllocator::default);
+
+    log!("PCID enabled: {}", enabled);
+}
+
+/// hands out pcids for the address spaces this core runs. They only tag this core's tlb so every
+/// core counts on its own. Keys include the process so a page table frame that gets freed and
+/// reused by another process never finds the old process' translations
+#[derive(Debug)]
+pub struct PcidAllocator {
+    assigned: BTreeMap<(ProcessId, PhysAddr), u16>,
+    next: u16,
+}
+
+impl Default for PcidAllocator {
+    fn default() -> Self {
+        Self {
+            assigned: BTreeMap::new(),
+            next: 1,
+        }
+    }
+}
+
+impl PcidAllocator {
+    /// the value to load into cr3. The first load of a pcid flushes whatever it tagged before,
+    /// after that the address space keeps its tlb entries across switches
+    pub fn cr3_for(&mut self, process: ProcessId, page_table: PhysAddr) -> u64 {
+        if let Some(&pcid) = self.assigned.get(&(process, page_table)) {
+            return page_table.as_u64() | pcid as u64 | CR3_NO_FLUSH;
+        }
+
+        // out of pcids, everyone starts over with a flush
+        if self.next == PCID_COUNT {
+            self.assigned.clear();
+            self.next = 1;
+        }
+
+        let pcid = self.next;
+        self.next += 1;
+        self.assigned.insert((process, page_table), pcid);
+
+        page_table.as_u64() | pcid as u64
+    }
+
+    /// for page tables about to be freed, a reused frame then gets a fresh pcid
+    pub fn release(&mut self, process: ProcessId, page_table: PhysAddr) {
+        self.assigned.remove(&(process, page_table));
+    }
+
+    /// a dead process' ids never come back, this only keeps the map small
+    pub fn release_process(&mut self, process: ProcessId) {
+        self.assigned.retain(|&(owner, _), _| owner != process);
+    }
+}
@@ -29,6 +29,7 @@ pub struct PerCPUData {
     /// used to temporarily save the rsp
     pub thread_rsp: u64,
     pub kernel_task_stack_ptr: u64,
+    pub idle_thread_stack_ptr: u64,
     pub rsp0_stack_ptr: u64,
     pub page_fault_stack_ptr: u64,
     /// the upper 32 bits can be used
@@ -104,6 +105,7 @@ pub fn setup_per_cpu_data(cpus: &[&Cpu]) {
         setup_stack!(cur_stack_base, syscall_stack_ptr);
         setup_stack!(cur_stack_base, rsp0_stack_ptr);
         setup_stack!(cur_stack_base, page_fault_stack_ptr);
+        setup_stack!(cur_stack_base, idle_thread_stack_ptr);
 
         let kernel_task_stack_ptr = setup_stack(cur_stack_base, STACK_SIZE * 2).as_u64();
         cur_stack_base += STACK_SIZE * 2;
@@ -124,6 +126,7 @@ pub fn setup_per_cpu_data(cpus: &[&Cpu]) {
                 syscall_stack_ptr,
                 thread_rsp: 0,
                 kernel_task_stack_ptr,
+                idle_thread_stack_ptr,
                 rsp0_stack_ptr,
                 page_fault_stack_ptr,
                 id: cpu.id as u64,
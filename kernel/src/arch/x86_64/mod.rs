@@ -1,4 +1,5 @@
 pub mod acpi;
+pub mod cpu_info;
 pub mod err;
 pub mod gdt;
 pub mod handlers;
@@ -11,3 +12,66 @@ pub mod pcie;
 pub mod pic;
 pub mod scheduler;
 pub mod timer;
+
+use core::arch::asm;
+
+use x86_64::{
+    VirtAddr,
+    instructions::{interrupts, port::Port},
+    structures::DescriptorTablePointer,
+};
+
+use crate::{arch::x86_64::acpi::facp::RESET_REGISTER, hal::vfs::sync_all, log};
+
+/// the 8042 keyboard controller, pulsing its output line 0 resets the cpu
+const KEYBOARD_CONTROLLER_PORT: u16 = 0x64;
+const KEYBOARD_CONTROLLER_INPUT_FULL: u8 = 1 << 1;
+const KEYBOARD_CONTROLLER_RESET: u8 = 0xFE;
+/// how long each method gets before the next one is tried
+const RESET_SPINS: usize = 1 << 24;
+
+fn wait_for_reset() {
+    for _ in 0..RESET_SPINS {
+        core::hint::spin_loop();
+    }
+}
+
+/// restarts the machine through the fadt's reset register, then the keyboard controller, and
+/// triple faults if neither did anything. Everything cached is written to the drives first
+pub async fn reboot() -> ! {
+    sync_all().await;
+
+    interrupts::disable();
+    log!("Rebooting");
+
+    if let Some(reset_register) = RESET_REGISTER.get() {
+        reset_register.write();
+        wait_for_reset();
+    }
+
+    unsafe {
+        let mut status = Port::<u8>::new(KEYBOARD_CONTROLLER_PORT);
+        for _ in 0..RESET_SPINS {
+            if status.read() & KEYBOARD_CONTROLLER_INPUT_FULL == 0 {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        status.write(KEYBOARD_CONTROLLER_RESET);
+    }
+    wait_for_reset();
+
+    log!("The machine is still running, triple faulting");
+    unsafe {
+        // with an empty idt the breakpoint can't be delivered, neither can the double fault
+        x86_64::instructions::tables::lidt(&DescriptorTablePointer {
+            limit: 0,
+            base: VirtAddr::zero(),
+        });
+        asm!("int3", options(nomem, nostack));
+    }
+
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
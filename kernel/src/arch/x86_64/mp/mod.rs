@@ -4,11 +4,13 @@ use limine::mp::Cpu;
 use crate::{
     IS_EXECUTOR_READY,
     arch::x86_64::{
-        acpi::apic::get_local_apic,
+        acpi::apic::{enable_x2apic_on_core, get_local_apic},
         gdt::init_gdt,
         idt::load_idt,
         init::MP_REQUEST,
+        memory::pcid::init_pcid,
         scheduler::{
+            fpu::init_fpu,
             load_kernel_thread,
             syscall::{enable_syscalls, set_per_cpu_data_for_core},
         },
@@ -49,6 +51,8 @@ pub fn initialize_mp() {
 }
 
 extern "C" fn ap_init(cpu: &Cpu) -> ! {
+    // logging already asks the local apic for the core id
+    enable_x2apic_on_core();
     log!("Initializing core: {:?}", cpu.id);
 
     set_per_cpu_data_for_core();
@@ -71,6 +75,8 @@ extern "C" fn ap_init(cpu: &Cpu) -> ! {
     log!("{}", local_apic.dump());
 
     enable_syscalls();
+    init_pcid();
+    init_fpu();
 
     while !IS_EXECUTOR_READY.load(core::sync::atomic::Ordering::Acquire) {
         core::hint::spin_loop();
@@ -1,6 +1,10 @@
+use alloc::vec::Vec;
 use x86_64::VirtAddr;
 
-use crate::pcie_offset_impl;
+use crate::{
+    arch::x86_64::{acpi::mcfg::McfgEntry, memory::get_hhdm_offset},
+    log, pcie_offset_impl,
+};
 
 #[macro_export]
 macro_rules! pcie_port_readonly {
@@ -62,22 +66,6 @@ macro_rules! pcie_port_readwrite {
     };
 }
 
-#[repr(C, packed)]
-#[derive(Debug, Copy, Clone)]
-pub struct PciHeaderPartial {
-    pub vendor_id: u16,
-    pub device_id: u16,
-    pub command: u16,
-    pub status: u16,
-    pub revision_id: u8,
-    pub prog_if: u8,
-    pub subclass: u8,
-    pub class_code: u8,
-    pub cache_line_size: u8,
-    pub latency_timer: u8,
-    pub header_type: u8,
-}
-
 #[derive(Debug, Copy, Clone)]
 #[repr(C, packed)]
 pub struct CapabilityNodeHeader {
@@ -158,6 +146,7 @@ pub enum PciBaseClass {
 
 #[repr(u8)]
 pub enum MassStorageControllerSubClass {
+    Ide = 0x01,
     Sata = 0x06,
 }
 
@@ -166,8 +155,239 @@ pub enum SataProgIf {
     Ahci = 0x01,
 }
 
+#[repr(u8)]
+pub enum IdeProgIf {
+    /// the controller has bus master registers in bar4
+    BusMastering = 0x80,
+}
+
+const BUS_DEVICE_COUNT: u8 = 32;
+const DEVICE_FUNCTION_COUNT: u8 = 8;
+
+/// the part of the configuration space every header type shares plus the bars
+const CONFIG_HEADER_DWORDS: usize = 16;
+const BARS_OFFSET: usize = 4;
+const NO_VENDOR: u16 = 0xFFFF;
+
+const HEADER_TYPE_MULTIFUNCTION: u8 = 0x80;
+const HEADER_TYPE_GENERAL: u8 = 0x00;
+const HEADER_TYPE_BRIDGE: u8 = 0x01;
+
+const BAR_IO_SPACE: u32 = 0b1;
+const BAR_TYPE_MASK: u32 = 0b110;
+const BAR_TYPE_64: u32 = 0b100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Bar {
+    /// not implemented, or the upper half of the 64 bit bar before it
+    #[default]
+    None,
+    Memory(u64),
+    Io(u16),
+}
+
+/// the addresses the bars hold, a 64 bit memory bar takes its upper half from the next one
+pub fn decode_bars(raw: &[u32]) -> [Bar; 6] {
+    let mut bars = [Bar::None; 6];
+    let mut idx = 0;
+
+    while idx < raw.len().min(bars.len()) {
+        let bar = raw[idx];
+
+        if bar & BAR_IO_SPACE != 0 {
+            let port = (bar & !0b11) as u16;
+            if port != 0 {
+                bars[idx] = Bar::Io(port);
+            }
+        } else {
+            let is_64_bit = bar & BAR_TYPE_MASK == BAR_TYPE_64;
+            let mut addr = (bar & !0xF) as u64;
+            if is_64_bit {
+                addr |= (raw.get(idx + 1).copied().unwrap_or(0) as u64) << 32;
+            }
+
+            if addr != 0 {
+                bars[idx] = Bar::Memory(addr);
+            }
+
+            // the upper half isn't a bar of its own
+            if is_64_bit {
+                idx += 1;
+            }
+        }
+
+        idx += 1;
+    }
+
+    bars
+}
+
+/// a function that answered while walking the configuration space
 #[derive(Debug, Clone)]
-pub struct PciDevice {
+pub struct PcieDevice {
+    /// where its configuration space is mapped
     pub address: VirtAddr,
-    pub header_partial: PciHeaderPartial,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class_code: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub header_type: u8,
+    pub bars: [Bar; 6],
+}
+
+impl PcieDevice {
+    /// None if nothing answered at the address, `header` is the first 64 bytes of the
+    /// configuration space
+    pub fn from_config(
+        address: VirtAddr,
+        (bus, device, function): (u8, u8, u8),
+        header: &[u32; CONFIG_HEADER_DWORDS],
+    ) -> Option<Self> {
+        let vendor_id = header[0] as u16;
+        if vendor_id == NO_VENDOR {
+            return None;
+        }
+
+        let [_, prog_if, subclass, class_code] = header[2].to_le_bytes();
+        let header_type = (header[3] >> 16) as u8;
+
+        // bridges only have two bars, the rest of their header is bus numbers and windows
+        let bar_count = match header_type & !HEADER_TYPE_MULTIFUNCTION {
+            HEADER_TYPE_GENERAL => 6,
+            HEADER_TYPE_BRIDGE => 2,
+            _ => 0,
+        };
+
+        Some(Self {
+            address,
+            bus,
+            device,
+            function,
+            vendor_id,
+            device_id: (header[0] >> 16) as u16,
+            class_code,
+            subclass,
+            prog_if,
+            header_type,
+            bars: decode_bars(&header[BARS_OFFSET..BARS_OFFSET + bar_count]),
+        })
+    }
+
+    pub fn is_multifunction(&self) -> bool {
+        self.header_type & HEADER_TYPE_MULTIFUNCTION != 0
+    }
+
+    pub fn header(&self) -> PciHeader {
+        PciHeader { base: self.address }
+    }
+}
+
+fn read_function(entry: &McfgEntry, bus: u8, device: u8, function: u8) -> Option<PcieDevice> {
+    let address = get_hhdm_offset() + entry.function_address(bus, device, function).as_u64();
+    let ptr = address.as_ptr::<u32>();
+    let header = core::array::from_fn(|idx| unsafe { ptr.add(idx).read_volatile() });
+
+    PcieDevice::from_config(address, (bus, device, function), &header)
+}
+
+/// maps the configuration space of every mcfg entry and returns every function on its buses
+pub fn enumerate(entries: &[McfgEntry]) -> Vec<PcieDevice> {
+    let mut devices = Vec::new();
+
+    for entry in entries {
+        entry.map_config_space();
+
+        for bus in entry.start_pci_bus_number..=entry.end_pci_bus_number {
+            for device in 0..BUS_DEVICE_COUNT {
+                // the other functions only exist if function 0 does and says so
+                let Some(first) = read_function(entry, bus, device, 0) else {
+                    continue;
+                };
+
+                let function_count = match first.is_multifunction() {
+                    true => DEVICE_FUNCTION_COUNT,
+                    false => 1,
+                };
+
+                devices.push(first);
+                devices.extend(
+                    (1..function_count)
+                        .filter_map(|function| read_function(entry, bus, device, function)),
+                );
+            }
+        }
+    }
+
+    log!("Found PCIe devices: {:#?}", devices);
+    devices
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+    use x86_64::VirtAddr;
+
+    use super::{
+        Bar, MassStorageControllerSubClass, PciBaseClass, PcieDevice, SataProgIf, decode_bars,
+    };
+    use crate::{end_test, test_name};
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn finds_q35_ahci_controller() {
+        test_name!("pcie finds the q35 ahci controller");
+
+        // the ich9 ahci controller qemu puts at 00:1f.2 on q35, abar in bar5
+        let mut ahci = [0u32; 16];
+        ahci[0] = 0x2922 << 16 | 0x8086;
+        ahci[2] = 0x01_06_01_02;
+        ahci[8] = 0xC0C1;
+        ahci[9] = 0xFEBD_5000;
+
+        let mut absent = [0u32; 16];
+        absent[0] = 0xFFFF_FFFF;
+
+        let devices = [(0x1F, 2, ahci), (0x1F, 3, absent)]
+            .into_iter()
+            .filter_map(|(device, function, header)| {
+                PcieDevice::from_config(VirtAddr::zero(), (0, device, function), &header)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(devices.len(), 1);
+
+        let controller = devices
+            .iter()
+            .find(|device| {
+                device.class_code == PciBaseClass::MassStorage as u8
+                    && device.subclass == MassStorageControllerSubClass::Sata as u8
+                    && device.prog_if == SataProgIf::Ahci as u8
+            })
+            .expect("No AHCI controller");
+        assert_eq!(
+            (controller.vendor_id, controller.device_id),
+            (0x8086, 0x2922)
+        );
+        assert_eq!(controller.bars[4], Bar::Io(0xC0C0));
+        assert_eq!(controller.bars[5], Bar::Memory(0xFEBD_5000));
+
+        // a 64 bit prefetchable bar takes up two slots
+        assert_eq!(
+            decode_bars(&[0xFE00_000C, 0x1, 0, 0xD001]),
+            [
+                Bar::Memory(0x1_FE00_0000),
+                Bar::None,
+                Bar::None,
+                Bar::Io(0xD000),
+                Bar::None,
+                Bar::None
+            ]
+        );
+
+        end_test!();
+    }
 }
@@ -131,6 +131,15 @@ impl From<ErrNo> for ElfErr {
     }
 }
 
+impl From<ElfErr> for ErrNo {
+    fn from(value: ElfErr) -> Self {
+        match value {
+            ElfErr::FsErr(e) => e,
+            ElfErr::NotELF | ElfErr::Unsupported | ElfErr::Corrupted => ErrNo::ExecFormatError,
+        }
+    }
+}
+
 async fn read_elf_header(fd: i64) -> Result<ElfHeader, ElfErr> {
     const BUF_SIZE: usize = 1024;
 
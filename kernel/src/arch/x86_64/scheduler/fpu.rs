@@ -0,0 +1,61 @@
+use core::arch::asm;
+
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+
+use crate::{arch::x86_64::scheduler::FPURegisterState, get_per_cpu_data, log};
+
+/// where the control words sit in the fxsave area
+const FCW_OFFSET: usize = 0;
+const MXCSR_OFFSET: usize = 24;
+/// what fninit leaves behind, every exception masked
+const DEFAULT_FCW: u16 = 0x037F;
+const DEFAULT_MXCSR: u32 = 0x1F80;
+
+/// the state a thread starts with the first time it touches the fpu
+impl Default for FPURegisterState {
+    fn default() -> Self {
+        let mut area = [0u8; 512];
+        area[FCW_OFFSET..FCW_OFFSET + 2].copy_from_slice(&DEFAULT_FCW.to_le_bytes());
+        area[MXCSR_OFFSET..MXCSR_OFFSET + 4].copy_from_slice(&DEFAULT_MXCSR.to_le_bytes());
+
+        Self(area)
+    }
+}
+
+impl FPURegisterState {
+    /// copies the x87 and sse registers into the area, the trap has to be off
+    pub fn save(&mut self) {
+        unsafe {
+            asm!("fxsave64 [{}]", in(reg) self.0.as_mut_ptr(), options(nostack, preserves_flags));
+        }
+    }
+
+    /// loads the registers back from the area, the trap has to be off
+    pub fn restore(&self) {
+        unsafe {
+            asm!("fxrstor64 [{}]", in(reg) self.0.as_ptr(), options(nostack, preserves_flags));
+        }
+    }
+}
+
+/// with the trap set the next x87 or sse instruction raises #NM instead of running
+pub fn set_fpu_trap(trap: bool) {
+    unsafe {
+        Cr0::update(|flags| flags.set(Cr0Flags::TASK_SWITCHED, trap));
+    }
+}
+
+/// lets user threads use x87 and sse, called once on every core. The kernel is built without sse
+/// so its own code never trips the trap. xcr0 is left alone, avx stays off and fxsave covers
+/// every register a thread can touch
+pub fn init_fpu() {
+    unsafe {
+        Cr0::update(|flags| {
+            flags.remove(Cr0Flags::EMULATE_COPROCESSOR);
+            flags.insert(Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+        });
+        Cr4::update(|flags| flags.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE));
+    }
+
+    log!("FPU enabled on core: {}", get_per_cpu_data!().id);
+}
@@ -1,13 +1,13 @@
 use core::ops::DerefMut;
 
-use alloc::{vec, vec::Vec};
+use alloc::{string::String, vec, vec::Vec};
 use bytemuck::{Pod, Zeroable};
 use x86_64::{
     PhysAddr, VirtAddr,
     registers::rflags::{self},
     structures::paging::{
         FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame,
-        Size4KiB, mapper::MapToError,
+        Size4KiB, Translate, mapper::MapToError,
     },
 };
 
@@ -21,6 +21,7 @@ use crate::{
         scheduler::{
             GPRegisterState, ThreadState,
             elf::{ElfFile, ElfProgramHeaderEntry, Flags, SegmentType},
+            signal::PendingSignals,
         },
     },
     crypto::random::random_number,
@@ -35,6 +36,8 @@ pub enum LoadErr {
     NoEnoughMemory,
     MappingErr(MapToError<Size4KiB>),
     Corrupted,
+    /// argv and envp don't fit on the initial stack
+    ArgumentsTooLong,
 }
 
 impl From<ErrNo> for LoadErr {
@@ -43,6 +46,19 @@ impl From<ErrNo> for LoadErr {
     }
 }
 
+impl From<LoadErr> for ErrNo {
+    fn from(value: LoadErr) -> Self {
+        match value {
+            LoadErr::VfsErr(e) => e,
+            LoadErr::NoEnoughMemory | LoadErr::MappingErr(MapToError::FrameAllocationFailed) => {
+                ErrNo::OutOfMemory
+            }
+            LoadErr::MappingErr(_) | LoadErr::Corrupted => ErrNo::ExecFormatError,
+            LoadErr::ArgumentsTooLong => ErrNo::ArgumentListTooLong,
+        }
+    }
+}
+
 pub struct MapEntry<'a> {
     pub entry: &'a ElfProgramHeaderEntry,
     pub frames: Vec<PhysFrame>,
@@ -256,6 +272,129 @@ pub async fn handle_tls(
     Ok(VirtAddr::new(TLS_START + aligned_length))
 }
 
+/// auxiliary vector keys, the values come from the system v abi
+pub mod auxv {
+    pub const AT_NULL: u64 = 0;
+    pub const AT_PHDR: u64 = 3;
+    pub const AT_PHENT: u64 = 4;
+    pub const AT_PHNUM: u64 = 5;
+    pub const AT_PAGESZ: u64 = 6;
+    pub const AT_ENTRY: u64 = 9;
+}
+
+/// the auxv entries describing `elf`, AT_PHDR is left out when no loaded segment covers the
+/// program headers
+pub fn build_auxv(elf: &ElfFile) -> Vec<(u64, u64)> {
+    let header = &elf.header;
+    let phdr_offset = header.header_table_offset;
+
+    let phdr = elf
+        .program_header_table
+        .iter()
+        .filter(|entry| entry.segment_type == SegmentType::Load as u32)
+        .find(|entry| {
+            phdr_offset >= entry.offset && phdr_offset < entry.offset + entry.size_in_file
+        })
+        .map(|entry| entry.vaddr + (phdr_offset - entry.offset));
+
+    let mut entries = vec![];
+
+    if let Some(phdr) = phdr {
+        entries.push((auxv::AT_PHDR, phdr));
+    }
+
+    entries.extend([
+        (
+            auxv::AT_PHENT,
+            header.program_header_table_entry_size as u64,
+        ),
+        (
+            auxv::AT_PHNUM,
+            header.program_header_table_entry_count as u64,
+        ),
+        (auxv::AT_PAGESZ, PAGE_SIZE as u64),
+        (auxv::AT_ENTRY, header.entry_offset),
+    ]);
+
+    entries
+}
+
+/// lays out the initial stack so that it ends at `stack_top`, returns the bytes and the rsp
+/// pointing at argc:
+///
+/// rsp -> argc, argv[0..argc], NULL, envp[..], NULL, auxv pairs, AT_NULL pair, padding, the
+/// strings themselves
+pub fn build_initial_stack(
+    stack_top: u64,
+    argv: &[String],
+    envp: &[String],
+    auxv: &[(u64, u64)],
+) -> (Vec<u8>, u64) {
+    let strings_len: usize = argv.iter().chain(envp).map(|s| s.len() + 1).sum();
+    let strings_start = stack_top - strings_len as u64;
+
+    let pointers_len = (argv.len() + envp.len() + 3 + (auxv.len() + 1) * 2) * size_of::<u64>();
+    // the abi wants rsp 16 byte aligned at the entry point
+    let rsp = (strings_start - pointers_len as u64) & !0xF;
+
+    let mut image = vec![0u8; (stack_top - rsp) as usize];
+    let mut pointer_offset = 0;
+    let mut string_offset = (strings_start - rsp) as usize;
+
+    let mut push_pointer = |image: &mut Vec<u8>, value: u64| {
+        image[pointer_offset..pointer_offset + size_of::<u64>()]
+            .copy_from_slice(&value.to_le_bytes());
+        pointer_offset += size_of::<u64>();
+    };
+
+    push_pointer(&mut image, argv.len() as u64);
+
+    for strings in [argv, envp] {
+        for string in strings {
+            push_pointer(&mut image, rsp + string_offset as u64);
+            image[string_offset..string_offset + string.len()].copy_from_slice(string.as_bytes());
+            // the null terminator is already there
+            string_offset += string.len() + 1;
+        }
+
+        push_pointer(&mut image, 0);
+    }
+
+    for &(key, value) in auxv.iter().chain([&(auxv::AT_NULL, 0)]) {
+        push_pointer(&mut image, key);
+        push_pointer(&mut image, value);
+    }
+
+    (image, rsp)
+}
+
+/// copies into memory mapped by a page table that isn't loaded, going through the hhdm
+fn copy_to_page_table(
+    page_table: &OffsetPageTable<'_>,
+    addr: u64,
+    bytes: &[u8],
+) -> Result<(), LoadErr> {
+    let mut written = 0;
+
+    while written < bytes.len() {
+        let virt = VirtAddr::new(addr + written as u64);
+        let phys = page_table
+            .translate_addr(virt)
+            .ok_or(LoadErr::ArgumentsTooLong)?;
+
+        let len = (PAGE_SIZE as usize - u64::from(virt.page_offset()) as usize).min(bytes.len() - written);
+        let mut buf = Buffer {
+            inner: (get_hhdm_offset() + phys.as_u64()).as_mut_ptr(),
+            len,
+        };
+
+        buf.copy_from_slice(&bytes[written..written + len]);
+        written += len;
+    }
+
+    Ok(())
+}
+
 pub async fn get_stack(
     page_table: &mut OffsetPageTable<'_>,
     allocated_frames: &mut Vec<PhysFrame<Size4KiB>>,
@@ -304,7 +443,12 @@ pub async fn get_stack(
     Ok(VirtAddr::new(STACK_GUARD_PAGE + STACK_LEN))
 }
 
-pub async fn load_elf(fd: i64, elf: ElfFile) -> Result<ThreadState, LoadErr> {
+pub async fn load_elf(
+    fd: i64,
+    elf: ElfFile,
+    argv: &[String],
+    envp: &[String],
+) -> Result<ThreadState, LoadErr> {
     let mut map_entries: Vec<MapEntry> = vec![];
     let page_table = unsafe { &mut *(create_page_table().await.as_mut_ptr() as *mut PageTable) };
     let mut offset_page_table = unsafe { OffsetPageTable::new(page_table, get_hhdm_offset()) };
@@ -378,7 +522,11 @@ pub async fn load_elf(fd: i64, elf: ElfFile) -> Result<ThreadState, LoadErr> {
         }
     }
 
-    let stack_top = get_stack(&mut offset_page_table, &mut allocated_frames).await? - 8;
+    let stack_top = get_stack(&mut offset_page_table, &mut allocated_frames).await?;
+
+    let (stack_image, stack_pointer) =
+        build_initial_stack(stack_top.as_u64(), argv, envp, &build_auxv(&elf));
+    copy_to_page_table(&offset_page_table, stack_pointer, &stack_image)?;
 
     let table_virt_addr = VirtAddr::from_ptr(page_table as *mut PageTable);
     let table_phys_addr = PhysAddr::new(table_virt_addr.as_u64() - get_hhdm_offset().as_u64());
@@ -386,8 +534,9 @@ pub async fn load_elf(fd: i64, elf: ElfFile) -> Result<ThreadState, LoadErr> {
     Ok(ThreadState {
         frames: allocated_frames,
         killed: false,
+        pending_signals: PendingSignals::default(),
         registers: GPRegisterState::default(),
-        stack_pointer: stack_top,
+        stack_pointer: VirtAddr::new(stack_pointer),
         state: crate::arch::x86_64::scheduler::State::Paused {
             instruction_pointer: elf.header.entry_offset,
             rflags: rflags::read(),
@@ -395,6 +544,136 @@ pub async fn load_elf(fd: i64, elf: ElfFile) -> Result<ThreadState, LoadErr> {
         thread_local_segment: tls_ptr.map_or(VirtAddr::zero(), |p| p),
         page_table_pointer: table_phys_addr,
         fpu_registers: None,
-        simd_registers: None,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::{string::String, vec::Vec};
+
+    use bytemuck::Zeroable;
+
+    use super::{auxv, build_auxv, build_initial_stack};
+    use crate::{
+        arch::x86_64::scheduler::elf::{ElfFile, ElfHeader, ElfProgramHeaderEntry, SegmentType},
+        end_test, test_name,
+    };
+
+    fn read_u64(image: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(image[offset..offset + 8].try_into().unwrap())
+    }
+
+    fn read_cstr(image: &[u8], base: u64, ptr: u64) -> &str {
+        let start = (ptr - base) as usize;
+        let len = image[start..].iter().position(|&b| b == 0).unwrap();
+        core::str::from_utf8(&image[start..start + len]).unwrap()
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn initial_stack_layout() {
+        test_name!("exec passes argv and envp on the initial stack");
+
+        const STACK_TOP: u64 = 0x7FFF_FFFF_0000 + 16 * 4096;
+
+        let argv: Vec<String> = ["/bin/echo", "hello", "world"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let envp: Vec<String> = ["PATH=/bin"].into_iter().map(String::from).collect();
+
+        let auxv = [(auxv::AT_PAGESZ, 4096), (auxv::AT_ENTRY, 0x40_1000)];
+
+        let (image, rsp) = build_initial_stack(STACK_TOP, &argv, &envp, &auxv);
+        assert_eq!(rsp % 16, 0);
+        assert_eq!(rsp + image.len() as u64, STACK_TOP);
+
+        assert_eq!(read_u64(&image, 0), 3);
+        for (idx, arg) in argv.iter().enumerate() {
+            let ptr = read_u64(&image, 8 + idx * 8);
+            assert_eq!(read_cstr(&image, rsp, ptr), arg);
+        }
+        assert_eq!(read_u64(&image, 8 + 3 * 8), 0);
+
+        let ptr = read_u64(&image, 8 + 4 * 8);
+        assert_eq!(read_cstr(&image, rsp, ptr), "PATH=/bin");
+        assert_eq!(read_u64(&image, 8 + 5 * 8), 0);
+
+        // the auxv pairs come right after envp and end with AT_NULL
+        let auxv_start = 8 + 6 * 8;
+        assert_eq!(read_u64(&image, auxv_start), auxv::AT_PAGESZ);
+        assert_eq!(read_u64(&image, auxv_start + 8), 4096);
+        assert_eq!(read_u64(&image, auxv_start + 16), auxv::AT_ENTRY);
+        assert_eq!(read_u64(&image, auxv_start + 24), 0x40_1000);
+        assert_eq!(read_u64(&image, auxv_start + 32), auxv::AT_NULL);
+        assert_eq!(read_u64(&image, auxv_start + 40), 0);
+
+        // the strings sit past the auxv
+        let first_string = read_u64(&image, 8) - rsp;
+        assert!(first_string as usize >= auxv_start + 48);
+
+        // no arguments at all still gives a valid frame
+        let (image, rsp) = build_initial_stack(STACK_TOP, &[], &[], &[]);
+        assert_eq!(rsp % 16, 0);
+        assert_eq!(&image[..40], &[0u8; 40]);
+
+        end_test!();
+    }
+
+    fn segment(segment_type: SegmentType, offset: u64, vaddr: u64) -> ElfProgramHeaderEntry {
+        let mut entry = ElfProgramHeaderEntry::zeroed();
+        entry.segment_type = segment_type as u32;
+        entry.offset = offset;
+        entry.vaddr = vaddr;
+        entry.size_in_file = 0x1000;
+        entry.size_in_memory = 0x1000;
+        entry
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn auxv_entries() {
+        test_name!("the auxv describes the program headers and the entry point in order");
+
+        let mut header = ElfHeader::zeroed();
+        header.entry_offset = 0x40_1000;
+        header.header_table_offset = 64;
+        header.program_header_table_entry_size = size_of::<ElfProgramHeaderEntry>() as u16;
+        header.program_header_table_entry_count = 2;
+
+        // the note covers the headers too, only loaded segments say where they end up
+        let mut elf = ElfFile {
+            header,
+            program_header_table: alloc::vec![
+                segment(SegmentType::Note, 0, 0x20_0000),
+                segment(SegmentType::Load, 0, 0x40_0000),
+            ],
+            section_header_table: Vec::new(),
+        };
+
+        assert_eq!(
+            build_auxv(&elf),
+            [
+                (auxv::AT_PHDR, 0x40_0040),
+                (auxv::AT_PHENT, 56),
+                (auxv::AT_PHNUM, 2),
+                (auxv::AT_PAGESZ, 4096),
+                (auxv::AT_ENTRY, 0x40_1000),
+            ]
+        );
+
+        // nothing loaded covers the headers, so there's no AT_PHDR and the rest keep their order
+        elf.program_header_table[1] = segment(SegmentType::Load, 0x1000, 0x40_1000);
+        assert_eq!(
+            build_auxv(&elf),
+            [
+                (auxv::AT_PHENT, 56),
+                (auxv::AT_PHNUM, 2),
+                (auxv::AT_PAGESZ, 4096),
+                (auxv::AT_ENTRY, 0x40_1000),
+            ]
+        );
+
+        end_test!();
+    }
+}
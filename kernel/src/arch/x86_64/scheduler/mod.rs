@@ -1,28 +1,50 @@
 pub mod elf;
+pub mod fpu;
 pub mod loader;
+pub mod process;
+pub mod run_queue;
+pub mod shootdown;
+pub mod signal;
+pub mod steal;
 pub mod syscall;
+pub mod uaccess;
+pub mod vma;
 
 use alloc::vec;
-use core::{sync::atomic::AtomicUsize, time::Duration};
-
-use alloc::{
-    collections::{btree_map::BTreeMap, vec_deque::VecDeque},
-    vec::Vec,
+use core::{
+    sync::atomic::{AtomicBool, AtomicUsize},
+    time::Duration,
 };
+
+use alloc::{boxed::Box, collections::btree_map::BTreeMap, vec::Vec};
 use x86_64::{
     PhysAddr, VirtAddr,
+    instructions::interrupts::without_interrupts,
     registers::rflags::{self, RFlags},
     structures::paging::PhysFrame,
 };
 
 use crate::{
-    EXECUTOR,
+    EXECUTOR, SPAWNER,
     arch::x86_64::{
         memory::{
-            frame_allocator::DEALLOCATOR_SENDER, get_hhdm_offset, page_table::KERNEL_PAGE_TABLE,
+            frame_allocator::DEALLOCATOR_SENDER,
+            get_hhdm_offset,
+            page_table::KERNEL_PAGE_TABLE,
+            pcid::{CR3_NO_FLUSH, PcidAllocator},
+            per_cpu::PER_CPU_DATA_PTRS,
         },
-        scheduler::syscall::resume_thread,
+        scheduler::{
+            fpu::set_fpu_trap,
+            run_queue::{Priority, RunQueue},
+            shootdown::Shootdown,
+            signal::PendingSignals,
+            syscall::resume_thread,
+        },
+        timer::TIMER_INTERVAL,
     },
+    ejcineque::executor::JoinHandle,
+    ejcineque::sync::spin::SpinMutex,
     get_per_cpu_data, get_per_cpu_data_mut, hcf, log,
 };
 
@@ -35,20 +57,55 @@ pub struct ProcessId(usize);
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
 pub struct CpuCoreId(u32);
 
+impl CpuCoreId {
+    pub fn new(id: u32) -> Self {
+        Self(id)
+    }
+
+    pub fn current() -> Self {
+        Self(get_per_cpu_data!().id as u32)
+    }
+
+    pub fn as_u32(&self) -> u32 {
+        self.0
+    }
+}
+
 pub struct SchedulerContext {
     pub processes: BTreeMap<ProcessId, Vec<(CpuCoreId, ThreadId)>>,
     pub cpu_contexts: Vec<SchedulerCpuContext>,
 }
 
-pub static THREAD_ID_COUNTER: AtomicUsize = AtomicUsize::new(0);
+/// starts at 1, id 0 belongs to the kernel thread every core boots into
+pub static THREAD_ID_COUNTER: AtomicUsize = AtomicUsize::new(1);
 
 #[derive(Debug, Default)]
 pub struct SchedulerCpuContext {
     pub thread_map: BTreeMap<ThreadId, Thread>,
-    pub thread_queue: VecDeque<ThreadId>,
+    pub thread_queue: RunQueue,
     pub current_thread: Option<ThreadId>,
     pub waiting_threads: BTreeMap<usize, ThreadId>,
     pub waiting_queue_idx: usize,
+    /// runs whenever the queue is empty, never queued itself
+    pub idle_thread: Option<ThreadId>,
+    /// the queue length as last published for the other cores
+    pub queue_len: AtomicUsize,
+    /// set while another core is being asked for threads
+    pub steal_pending: AtomicBool,
+    /// what the last switch put in cr3
+    pub loaded_page_table: Option<PhysAddr>,
+    /// how many switches had to load cr3
+    pub cr3_reloads: u64,
+    /// how many of those loads flushed the tlb
+    pub tlb_flushes: u64,
+    /// None if the core has no pcids, every load of cr3 flushes then
+    pub pcids: Option<PcidAllocator>,
+    /// whose state the fpu registers hold right now, it's only saved once someone else wants them
+    pub fpu_owner: Option<ThreadId>,
+    /// what another core asked this one to drop from its tlb, cleared once that's done
+    pub shootdown_request: SpinMutex<Option<Shootdown>>,
+    /// set once the core starts running threads, other cores leave it out of shootdowns before
+    pub takes_shootdowns: AtomicBool,
 }
 
 impl SchedulerCpuContext {
@@ -57,8 +114,175 @@ impl SchedulerCpuContext {
         let id = THREAD_ID_COUNTER.fetch_add(1, core::sync::atomic::Ordering::AcqRel);
 
         thread.id = ThreadId(id);
+        self.thread_queue.push_back(ThreadId(id), thread.priority);
         self.thread_map.insert(ThreadId(id), thread);
-        self.thread_queue.push_back(ThreadId(id));
+    }
+
+    /// queues a thread that already has an id, e.g. one coming over from another core
+    pub fn adopt_thread(&mut self, thread: Thread) {
+        let id = thread.id;
+
+        self.thread_queue.push_back(id, thread.priority);
+        self.thread_map.insert(id, thread);
+    }
+
+    /// puts a thread that's already known here back at the end of its priority's queue
+    pub fn requeue(&mut self, id: ThreadId) {
+        let priority = self
+            .thread_map
+            .get(&id)
+            .map_or(Priority::DEFAULT, |thread| thread.priority);
+
+        self.thread_queue.push_back(id, priority);
+    }
+
+    /// a token nothing waits on yet, to block a thread under
+    pub fn wait_token(&mut self) -> usize {
+        let token = self.waiting_queue_idx;
+        self.waiting_queue_idx += 1;
+        token
+    }
+
+    /// parks the running thread in `waiting_threads` until [`Self::wake`] gets the same token,
+    /// and picks the thread to run instead. What the thread should resume with has to be saved
+    /// before, it isn't queued again until then
+    pub fn block_current(&mut self, token: usize) -> ThreadId {
+        let id = self.current_thread.take().expect("No current thread");
+        self.waiting_threads.insert(token, id);
+
+        self.switch_task().id
+    }
+
+    /// puts the thread blocked under `token` back into the run queue, None if nothing waits on it
+    /// or the thread is gone by now
+    pub fn wake(&mut self, token: usize) -> Option<ThreadId> {
+        let id = self.waiting_threads.remove(&token)?;
+        if !self.thread_map.contains_key(&id) {
+            return None;
+        }
+
+        self.requeue(id);
+        Some(id)
+    }
+
+    /// takes the most recently queued thread that may run on `core`, pinned threads stay put
+    pub fn take_migratable(&mut self, core: CpuCoreId) -> Option<Thread> {
+        let thread_map = &self.thread_map;
+        let id = self.thread_queue.take_last(|id| {
+            thread_map
+                .get(id)
+                .is_some_and(|thread| thread.may_run_on(core))
+        })?;
+
+        self.thread_map.remove(&id)
+    }
+
+    /// takes a queued thread off this core so it can move to `to`, its saved state goes along
+    /// untouched. The thread running right now, the idle thread, threads pinned elsewhere and
+    /// threads parked in `waiting_threads` stay, the syscall they wait on wakes them on this core
+    pub fn detach_thread(&mut self, id: ThreadId, to: CpuCoreId) -> Option<Thread> {
+        if self.current_thread == Some(id)
+            || self.is_idle(id)
+            || self.waiting_threads.values().any(|&waiting| waiting == id)
+            || !self.thread_map.get(&id)?.may_run_on(to)
+        {
+            return None;
+        }
+
+        // its fpu state can't stay behind in this core's registers
+        if self.fpu_owner == Some(id) {
+            self.release_fpu();
+        }
+
+        self.thread_queue.retain(|&queued| queued != id);
+        let mut thread = self.thread_map.remove(&id)?;
+        self.publish_load();
+
+        // the slice it had left was measured on this core
+        thread.time_left = DEFAULT_TICKS_PER_THREAD;
+        Some(thread)
+    }
+
+    /// what to hand the resume code for cr3, 0 when `page_table` is loaded already. Threads of
+    /// one process share their page table so switching between them keeps the tlb, with pcids
+    /// switching between processes keeps it too
+    pub fn page_table_to_load(&mut self, process: ProcessId, page_table: PhysAddr) -> u64 {
+        if self.loaded_page_table == Some(page_table) {
+            return 0;
+        }
+
+        self.loaded_page_table = Some(page_table);
+        self.cr3_reloads += 1;
+
+        let cr3 = match self.pcids {
+            Some(ref mut pcids) => pcids.cr3_for(process, page_table),
+            None => page_table.as_u64(),
+        };
+
+        if cr3 & CR3_NO_FLUSH == 0 {
+            self.tlb_flushes += 1;
+        }
+
+        cr3
+    }
+
+    /// makes the next switch load cr3 no matter what, for when the loaded page table is freed
+    pub fn forget_loaded_page_table(&mut self) {
+        self.loaded_page_table = None;
+    }
+
+    /// `page_table` of `process` is about to be freed or lost some of its mappings, nothing cached
+    /// for it may be trusted again
+    pub fn forget_page_table(&mut self, process: ProcessId, page_table: PhysAddr) {
+        if self.loaded_page_table == Some(page_table) {
+            self.forget_loaded_page_table();
+        }
+
+        if let Some(ref mut pcids) = self.pcids {
+            pcids.release(process, page_table);
+        }
+    }
+
+    /// arms the trap unless `id` already has its state in the fpu, called before switching to it
+    pub fn prepare_fpu(&self, id: ThreadId) {
+        set_fpu_trap(self.fpu_owner != Some(id));
+    }
+
+    /// the running thread touched the fpu while the trap was armed, #NM ends up here. Whoever
+    /// had the fpu before gets its state saved and the running thread gets its own back, a thread
+    /// using the fpu for the first time gets its save area now and starts from a clean state
+    pub fn claim_fpu(&mut self) {
+        set_fpu_trap(false);
+        if self.fpu_owner == self.current_thread {
+            return;
+        }
+
+        self.stash_fpu_owner();
+
+        if let Some(thread) = self
+            .current_thread
+            .and_then(|id| self.thread_map.get_mut(&id))
+        {
+            thread.state.fpu_registers.get_or_insert_default().restore();
+        }
+
+        self.fpu_owner = self.current_thread;
+    }
+
+    /// saves the owner's state and leaves the fpu to nobody, the next user traps
+    pub fn release_fpu(&mut self) {
+        set_fpu_trap(false);
+        self.stash_fpu_owner();
+        set_fpu_trap(true);
+    }
+
+    /// the trap has to be off, an owner that's gone by now has nothing worth keeping
+    fn stash_fpu_owner(&mut self) {
+        if let Some(owner) = self.fpu_owner.take()
+            && let Some(thread) = self.thread_map.get_mut(&owner)
+        {
+            thread.state.fpu_registers.get_or_insert_default().save();
+        }
     }
 
     pub fn get_current_thread_ref(&mut self) -> &mut Thread {
@@ -66,9 +290,54 @@ impl SchedulerCpuContext {
         self.thread_map.get_mut(id).expect("Corrupted metadata")
     }
 
+    /// the idle thread starts over from its entry point every time, so nothing is saved for it
+    pub fn spawn_idle_thread(&mut self, mut thread: Thread) {
+        let id = ThreadId(THREAD_ID_COUNTER.fetch_add(1, core::sync::atomic::Ordering::AcqRel));
+
+        thread.id = id;
+        self.thread_map.insert(id, thread);
+        self.idle_thread = Some(id);
+    }
+
+    pub fn is_idle(&self, id: ThreadId) -> bool {
+        self.idle_thread == Some(id)
+    }
+
+    /// charges one timer interrupt to the running thread, true once its slice is used up. It's
+    /// then already back at the end of the queue with a fresh slice, so every queued thread gets
+    /// its turn before it runs again
+    pub fn charge_tick(&mut self) -> bool {
+        let Some(id) = self.current_thread else {
+            return false;
+        };
+
+        if self.is_idle(id) {
+            return false;
+        }
+
+        let Some(thread) = self.thread_map.get_mut(&id) else {
+            return false;
+        };
+
+        thread.ticks_run += 1;
+        thread.time_left = thread.time_left.saturating_sub(TIMER_INTERVAL);
+
+        if !thread.time_left.is_zero() {
+            return false;
+        }
+
+        thread.time_left = DEFAULT_TICKS_PER_THREAD;
+        self.requeue(id);
+        true
+    }
+
     pub fn switch_task(&mut self) -> &mut Thread {
         loop {
-            let id = self.thread_queue.pop_front().expect("KERNEL TASK IS DEAD");
+            let Some(id) = self.thread_queue.pop_front() else {
+                let id = self.idle_thread.expect("No idle thread");
+                self.current_thread = Some(id);
+                return self.thread_map.get_mut(&id).expect("Corrupted metadata");
+            };
 
             // remove stale thread
             if let Some(thread) = self.thread_map.get(&id) {
@@ -83,7 +352,7 @@ impl SchedulerCpuContext {
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, PartialEq, Eq)]
 pub struct GPRegisterState {
     pub rax: u64,
     pub rbx: u64,
@@ -102,11 +371,10 @@ pub struct GPRegisterState {
     pub r15: u64,
 }
 
-#[derive(Debug)]
-pub struct FPURegisterState {}
-
-#[derive(Debug)]
-pub struct SIMDRegisterState {}
+/// the fxsave area, x87 and sse registers together
+#[derive(Debug, Clone)]
+#[repr(C, align(16))]
+pub struct FPURegisterState(pub [u8; 512]);
 
 #[derive(Debug, PartialEq)]
 pub enum State {
@@ -121,6 +389,7 @@ pub enum State {
 #[derive(Debug)]
 pub struct ThreadState {
     pub killed: bool,
+    pub pending_signals: PendingSignals,
 
     pub registers: GPRegisterState,
     pub stack_pointer: VirtAddr,
@@ -129,8 +398,8 @@ pub struct ThreadState {
     /// cr3
     pub page_table_pointer: PhysAddr,
 
-    pub fpu_registers: Option<FPURegisterState>,
-    pub simd_registers: Option<SIMDRegisterState>,
+    /// None until the thread first uses the fpu
+    pub fpu_registers: Option<Box<FPURegisterState>>,
     pub state: State,
 
     pub frames: Vec<PhysFrame>,
@@ -145,14 +414,30 @@ pub enum PrivilageLevel {
 #[derive(Debug)]
 pub struct Thread {
     pub id: ThreadId,
+    pub process: ProcessId,
     pub state: ThreadState,
     pub privilage_level: PrivilageLevel,
     pub time_left: Duration,
+    /// the only core the thread may run on, None lets it go anywhere
+    pub cpu_affinity: Option<CpuCoreId>,
+    /// timer interrupts that hit while the thread was running
+    pub ticks_run: u64,
+    /// higher priorities get more turns per round, see [`RunQueue`]
+    pub priority: Priority,
+}
+
+impl Thread {
+    pub fn may_run_on(&self, core: CpuCoreId) -> bool {
+        self.cpu_affinity.is_none_or(|affinity| affinity == core)
+    }
 }
 
 impl Drop for Thread {
     fn drop(&mut self) {
         let frames_to_free = core::mem::take(&mut self.state.frames);
+        if frames_to_free.is_empty() {
+            return;
+        }
 
         DEALLOCATOR_SENDER
             .get()
@@ -163,16 +448,81 @@ impl Drop for Thread {
 
 pub const DEFAULT_TICKS_PER_THREAD: Duration = Duration::from_millis(5);
 
-pub fn load_kernel_thread() -> ! {
-    let per_cpu_data = get_per_cpu_data_mut!();
-    let kernel_task_stack_start = per_cpu_data.kernel_task_stack_ptr;
+/// moves a queued thread from one core to another. Each step runs on the core whose queue it
+/// touches, so the thread is never in two queues or in none while it can be picked
+pub fn migrate_thread(id: ThreadId, from: CpuCoreId, to: CpuCoreId) {
+    if from == to {
+        return;
+    }
+
+    SPAWNER
+        .get()
+        .expect("Failed to get spawner")
+        .spawn_on(from.0, async move {
+            let Some(thread) = without_interrupts(|| {
+                get_per_cpu_data_mut!()
+                    .scheduler_context
+                    .detach_thread(id, to)
+            }) else {
+                log!("Thread {:?} can't move to core {:?}", id, to);
+                return;
+            };
+
+            SPAWNER
+                .get()
+                .expect("Failed to get spawner")
+                .spawn_on(to.0, async move {
+                    without_interrupts(|| {
+                        let context = &mut get_per_cpu_data_mut!().scheduler_context;
+                        context.adopt_thread(thread);
+                        context.publish_load();
+                    });
+                });
+        });
+}
+
+/// runs `f` on the scheduler context of every core but the calling one, from a task spawned on
+/// each since only a core itself touches its context. The handles are done once their core is
+pub fn on_other_cores(
+    f: impl Fn(&mut SchedulerCpuContext) + Clone + Send + 'static,
+) -> Vec<JoinHandle<()>> {
+    let current = CpuCoreId::current();
+    let spawner = SPAWNER.get().expect("Failed to get spawner");
+
+    PER_CPU_DATA_PTRS
+        .get()
+        .expect("Failed to get per cpu data pointers")
+        .keys()
+        .filter(|&&core| core != current.0)
+        .map(|&core| {
+            let f = f.clone();
+            spawner.spawn_on(core, async move {
+                without_interrupts(|| f(&mut get_per_cpu_data_mut!().scheduler_context));
+            })
+        })
+        .collect()
+}
+
+/// tells every core that `page_table` is going away or had pages unmapped and waits until none of
+/// them has anything cached for it anymore, see [`SchedulerCpuContext::shoot_down`]
+pub fn forget_page_table(process: ProcessId, page_table: PhysAddr) {
+    without_interrupts(|| {
+        get_per_cpu_data_mut!()
+            .scheduler_context
+            .shoot_down(Shootdown::PageTable(process, page_table))
+    });
+}
 
-    let thread = Thread {
+/// a thread of the kernel process starting at `entry` on an empty stack
+fn kernel_thread(entry: extern "C" fn() -> !, stack_pointer: u64, rflags: RFlags) -> Thread {
+    Thread {
         id: ThreadId(0),
+        process: ProcessId(0),
         state: ThreadState {
             killed: false,
+            pending_signals: PendingSignals::default(),
             registers: GPRegisterState::default(),
-            stack_pointer: VirtAddr::new(kernel_task_stack_start),
+            stack_pointer: VirtAddr::new(stack_pointer),
             // kernel doesn't have a thread local segment
             thread_local_segment: VirtAddr::new(0),
             page_table_pointer: PhysAddr::new(
@@ -184,10 +534,9 @@ pub fn load_kernel_thread() -> ! {
                     - get_hhdm_offset().as_u64(),
             ),
             fpu_registers: None,
-            simd_registers: None,
             state: State::Paused {
-                instruction_pointer: kernel_thread_entry_point as *const () as u64,
-                rflags: rflags::read(),
+                instruction_pointer: entry as *const () as u64,
+                rflags,
             },
 
             // if the kernel dies no need to deallocate
@@ -195,11 +544,46 @@ pub fn load_kernel_thread() -> ! {
         },
         privilage_level: PrivilageLevel::Kernel,
         time_left: DEFAULT_TICKS_PER_THREAD,
-    };
+        cpu_affinity: None,
+        ticks_run: 0,
+        priority: Priority::DEFAULT,
+    }
+}
+
+pub fn load_kernel_thread() -> ! {
+    let per_cpu_data = get_per_cpu_data_mut!();
+
+    // the idt and the local apic are up, the ipi gets through from here on
+    per_cpu_data
+        .scheduler_context
+        .takes_shootdowns
+        .store(true, core::sync::atomic::Ordering::Release);
+
+    // the idle thread has to wake up for interrupts
+    per_cpu_data
+        .scheduler_context
+        .spawn_idle_thread(kernel_thread(
+            idle_thread_entry_point,
+            per_cpu_data.idle_thread_stack_ptr,
+            rflags::read() | RFlags::INTERRUPT_FLAG,
+        ));
+
+    let thread = kernel_thread(
+        kernel_thread_entry_point,
+        per_cpu_data.kernel_task_stack_ptr,
+        rflags::read(),
+    );
 
     resume_thread(&thread);
 }
 
+/// sleeps until an interrupt, the timer switches away as soon as something is queued
+extern "C" fn idle_thread_entry_point() -> ! {
+    loop {
+        x86_64::instructions::interrupts::enable_and_hlt();
+    }
+}
+
 #[unsafe(no_mangle)]
 extern "C" fn kernel_thread_entry_point() -> ! {
     let id = get_per_cpu_data!().id as u32;
@@ -220,3 +604,444 @@ extern "C" fn kernel_thread_entry_point() -> ! {
 }
 
 pub async fn load_thread() {}
+
+#[cfg(test)]
+mod tests {
+    use x86_64::{
+        PhysAddr, VirtAddr, instructions::interrupts::without_interrupts, registers::rflags::RFlags,
+    };
+
+    use alloc::vec::Vec;
+
+    use super::{
+        CpuCoreId, DEFAULT_TICKS_PER_THREAD, GPRegisterState, ProcessId, SchedulerCpuContext,
+        State, ThreadId, fpu::set_fpu_trap, idle_thread_entry_point, kernel_thread,
+        run_queue::Priority,
+    };
+    use crate::{
+        arch::x86_64::memory::pcid::{CR3_NO_FLUSH, PcidAllocator},
+        end_test, get_per_cpu_data_mut, test_name,
+    };
+
+    /// a core's context the way it comes up, nothing but its idle thread
+    pub(super) fn idle_core() -> SchedulerCpuContext {
+        let mut context = SchedulerCpuContext::default();
+        context.spawn_idle_thread(kernel_thread(
+            idle_thread_entry_point,
+            0,
+            RFlags::INTERRUPT_FLAG,
+        ));
+        context
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn idle_on_empty_queue() {
+        test_name!("an empty run queue idles instead of panicking");
+
+        let mut context = idle_core();
+        let idle = context.idle_thread.unwrap();
+        // id 0 is the kernel thread's
+        assert_ne!(idle, ThreadId(0));
+
+        assert_eq!(context.switch_task().id, idle);
+        assert_eq!(context.next_or_idle().unwrap().id, idle);
+        assert!(context.thread_queue.is_empty());
+
+        // a thread made ready takes over from the idle thread
+        context.spawn_thread(kernel_thread(idle_thread_entry_point, 0, RFlags::empty()));
+        let worker = *context.thread_queue.iter().next().unwrap();
+        assert!(!context.is_idle(worker));
+        assert_ne!(worker, ThreadId(0));
+        assert_eq!(context.next_or_idle().unwrap().id, worker);
+
+        // and once it's gone the core goes back to idling
+        assert_eq!(context.switch_task().id, idle);
+        assert_eq!(context.current_thread, Some(idle));
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn pinned_thread_stays_on_its_core() {
+        test_name!("an affined thread only runs on its core");
+
+        let (core0, core1) = (CpuCoreId::new(0), CpuCoreId::new(1));
+        let mut cores = [
+            SchedulerCpuContext::default(),
+            SchedulerCpuContext::default(),
+        ];
+
+        let mut pinned = kernel_thread(idle_thread_entry_point, 0, RFlags::empty());
+        pinned.cpu_affinity = Some(core1);
+        let free = kernel_thread(idle_thread_entry_point, 0, RFlags::empty());
+
+        cores[1].spawn_thread(pinned);
+        cores[1].spawn_thread(free);
+        let pinned = *cores[1].thread_queue.iter().next().unwrap();
+
+        for _ in 0..4 {
+            // core 0 has nothing and tries to take work off core 1
+            if let Some(thread) = cores[1].take_migratable(core0) {
+                assert_ne!(thread.id, pinned);
+                cores[0].adopt_thread(thread);
+            }
+
+            for (idx, core) in cores.iter_mut().enumerate() {
+                let Some(id) = core.thread_queue.pop_front() else {
+                    continue;
+                };
+
+                if id == pinned {
+                    assert_eq!(idx, 1);
+                }
+
+                // ran for its slice, back in the queue of the same core
+                core.requeue(id);
+            }
+        }
+
+        assert!(cores[1].thread_map.contains_key(&pinned));
+        assert!(!cores[0].thread_map.contains_key(&pinned));
+        assert!(cores[1].take_migratable(core0).is_none());
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn migrated_thread_keeps_its_state() {
+        test_name!("a migrated thread resumes with the same state");
+
+        let (core0, core1) = (CpuCoreId::new(0), CpuCoreId::new(1));
+        let mut cores = [
+            SchedulerCpuContext::default(),
+            SchedulerCpuContext::default(),
+        ];
+
+        // paused halfway through something on core 0
+        let mut thread = kernel_thread(idle_thread_entry_point, 0x8000, RFlags::empty());
+        thread.state.registers.rax = 1;
+        thread.state.registers.r15 = 0xdead_beef;
+        thread.state.thread_local_segment = VirtAddr::new(0x7000_0000);
+        thread.state.state = State::Paused {
+            instruction_pointer: 0x40_1000,
+            rflags: RFlags::INTERRUPT_FLAG,
+        };
+        cores[0].spawn_thread(thread);
+        let id = *cores[0].thread_queue.iter().next().unwrap();
+
+        let thread = cores[0].detach_thread(id, core1).unwrap();
+        assert!(cores[0].thread_queue.is_empty());
+        assert!(cores[0].thread_map.is_empty());
+        cores[1].adopt_thread(thread);
+
+        let resumed = cores[1].next_runnable().unwrap();
+        assert_eq!(resumed.id, id);
+        assert_eq!(
+            resumed.state.registers,
+            GPRegisterState {
+                rax: 1,
+                r15: 0xdead_beef,
+                ..Default::default()
+            }
+        );
+        assert_eq!(resumed.state.stack_pointer, VirtAddr::new(0x8000));
+        assert_eq!(
+            resumed.state.thread_local_segment,
+            VirtAddr::new(0x7000_0000)
+        );
+        assert_eq!(
+            resumed.state.state,
+            State::Paused {
+                instruction_pointer: 0x40_1000,
+                rflags: RFlags::INTERRUPT_FLAG,
+            }
+        );
+
+        // running, parked on a syscall or pinned elsewhere, it stays where it is
+        cores[1].current_thread = Some(id);
+        assert!(cores[1].detach_thread(id, core0).is_none());
+        cores[1].current_thread = None;
+
+        cores[1].waiting_threads.insert(0, id);
+        assert!(cores[1].detach_thread(id, core0).is_none());
+        cores[1].waiting_threads.clear();
+
+        cores[1].thread_map.get_mut(&id).unwrap().cpu_affinity = Some(core1);
+        assert!(cores[1].detach_thread(id, core0).is_none());
+        assert!(cores[1].thread_map.contains_key(&id));
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn round_robin_is_fair() {
+        test_name!("cpu bound threads get equal shares of the timer");
+
+        let mut context = SchedulerCpuContext::default();
+        for _ in 0..3 {
+            context.spawn_thread(kernel_thread(idle_thread_entry_point, 0, RFlags::empty()));
+        }
+        context.switch_task();
+
+        // none of them ever gives the core up on its own
+        for _ in 0..3000 {
+            if context.charge_tick() {
+                context.switch_task();
+            }
+        }
+
+        let slice = DEFAULT_TICKS_PER_THREAD.as_millis() as u64;
+        for thread in context.thread_map.values() {
+            assert!(thread.ticks_run.abs_diff(1000) <= slice);
+        }
+
+        // a thread that got preempted starts its next turn with a whole slice
+        let current = context.get_current_thread_ref().id;
+        while !context.charge_tick() {}
+        assert_eq!(context.thread_queue.iter().last(), Some(&current));
+        assert_eq!(
+            context.thread_map[&current].time_left,
+            DEFAULT_TICKS_PER_THREAD
+        );
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn blocked_thread_waits_for_wake() {
+        test_name!("a blocked thread gives up the core until something wakes it");
+
+        let mut context = idle_core();
+        for _ in 0..2 {
+            context.spawn_thread(kernel_thread(idle_thread_entry_point, 0, RFlags::empty()));
+        }
+
+        let waiter = context.switch_task().id;
+        let token = context.wait_token();
+        let waker = context.block_current(token);
+        assert_ne!(waker, waiter);
+        assert_eq!(context.current_thread, Some(waker));
+        assert!(context.thread_queue.iter().all(|&id| id != waiter));
+
+        // the waker runs out of its slice and nothing else is ready, the waiter stays parked
+        context.requeue(waker);
+        assert_eq!(context.switch_task().id, waker);
+
+        // a token nobody waits on wakes nothing
+        let unused = context.wait_token();
+        assert_eq!(context.wake(unused), None);
+
+        assert_eq!(context.wake(token), Some(waiter));
+        assert!(context.waiting_threads.is_empty());
+        assert_eq!(context.wake(token), None);
+
+        // back in the run queue, it's up once the waker's turn is over
+        context.requeue(waker);
+        assert_eq!(context.switch_task().id, waiter);
+
+        // with everyone blocked the core idles
+        let tokens = [context.wait_token(), context.wait_token()];
+        assert_eq!(context.block_current(tokens[0]), waker);
+        let next = context.block_current(tokens[1]);
+        assert!(context.is_idle(next));
+        assert_eq!(context.waiting_threads.len(), 2);
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn higher_priority_runs_more_often() {
+        test_name!("a high priority thread gets more of the timer than a low priority one");
+
+        let mut context = SchedulerCpuContext::default();
+        for priority in [Priority::LOWEST, Priority::HIGHEST] {
+            let mut thread = kernel_thread(idle_thread_entry_point, 0, RFlags::empty());
+            thread.priority = priority;
+            context.spawn_thread(thread);
+        }
+
+        // the high priority thread goes first even though it was queued last
+        assert_eq!(context.switch_task().priority, Priority::HIGHEST);
+
+        for _ in 0..3000 {
+            if context.charge_tick() {
+                context.switch_task();
+            }
+        }
+
+        let ticks = |priority| {
+            context
+                .thread_map
+                .values()
+                .find(|thread| thread.priority == priority)
+                .unwrap()
+                .ticks_run
+        };
+        let (low, high) = (ticks(Priority::LOWEST), ticks(Priority::HIGHEST));
+        assert_eq!(low + high, 3000);
+
+        // one turn for the lowest level per round against one per level for the highest
+        let ratio = (Priority::HIGHEST.weight() / Priority::LOWEST.weight()) as u64;
+        assert!(high > low * (ratio - 1));
+        // but the low priority thread isn't starved
+        assert!(low > 0);
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn same_address_space_keeps_cr3() {
+        test_name!("switching within one address space doesn't reload cr3");
+
+        let mut context = SchedulerCpuContext::default();
+        let (shared, other) = (PhysAddr::new(0x10_0000), PhysAddr::new(0x20_0000));
+        let (process, other_process) = (ProcessId(1), ProcessId(2));
+
+        assert_eq!(context.page_table_to_load(process, shared), shared.as_u64());
+
+        // two threads of the same process taking turns
+        for _ in 0..4 {
+            assert_eq!(context.page_table_to_load(process, shared), 0);
+        }
+        assert_eq!(context.cr3_reloads, 1);
+
+        // another process needs its own, without pcids that's a flush every time
+        assert_eq!(
+            context.page_table_to_load(other_process, other),
+            other.as_u64()
+        );
+        assert_eq!(context.page_table_to_load(process, shared), shared.as_u64());
+        assert_eq!(context.cr3_reloads, 3);
+        assert_eq!(context.tlb_flushes, 3);
+
+        // a freed page table is never trusted again
+        context.forget_loaded_page_table();
+        assert_eq!(context.page_table_to_load(process, shared), shared.as_u64());
+        assert_eq!(context.cr3_reloads, 4);
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn pcids_keep_the_tlb_across_processes() {
+        test_name!("with pcids switching address spaces only flushes the first time");
+
+        let mut context = SchedulerCpuContext {
+            pcids: Some(PcidAllocator::default()),
+            ..Default::default()
+        };
+        let spaces = [
+            (ProcessId(1), PhysAddr::new(0x10_0000)),
+            (ProcessId(2), PhysAddr::new(0x20_0000)),
+        ];
+
+        let first: Vec<_> = spaces
+            .iter()
+            .map(|&(process, table)| context.page_table_to_load(process, table))
+            .collect();
+        assert!(first.iter().all(|cr3| cr3 & CR3_NO_FLUSH == 0));
+        // each address space has its own tag
+        assert_ne!(first[0] & 0xfff, first[1] & 0xfff);
+
+        for _ in 0..3 {
+            for (&(process, table), &cr3) in spaces.iter().zip(&first) {
+                assert_eq!(
+                    context.page_table_to_load(process, table),
+                    cr3 | CR3_NO_FLUSH
+                );
+            }
+        }
+        assert_eq!(context.cr3_reloads, 8);
+        assert_eq!(context.tlb_flushes, 2);
+
+        // once the table is freed its frame may come back, and then it starts clean
+        let (process, table) = spaces[0];
+        context.forget_page_table(process, table);
+        assert_eq!(context.page_table_to_load(process, table) & CR3_NO_FLUSH, 0);
+        assert_eq!(context.tlb_flushes, 3);
+
+        end_test!();
+    }
+
+    fn write_xmm0(value: u64) {
+        unsafe { core::arch::asm!("movq xmm0, {}", in(reg) value) };
+    }
+
+    fn read_xmm0() -> u64 {
+        let value;
+        unsafe { core::arch::asm!("movq {}, xmm0", out(reg) value) };
+        value
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn threads_keep_their_own_fpu_state() {
+        test_name!("threads sharing a core never see each other's sse registers");
+
+        without_interrupts(|| {
+            // whatever really owns this core's fpu keeps its state, and no switch may come in
+            // between and arm the trap again
+            get_per_cpu_data_mut!().scheduler_context.release_fpu();
+
+            let mut context = SchedulerCpuContext::default();
+            context.spawn_thread(kernel_thread(idle_thread_entry_point, 0, RFlags::empty()));
+            context.spawn_thread(kernel_thread(idle_thread_entry_point, 0, RFlags::empty()));
+            let threads: Vec<_> = context.thread_queue.iter().copied().collect();
+            let values = [0xAAAA_AAAA_AAAA_AAAA, 0x5555_5555_5555_5555];
+
+            // neither has touched the fpu yet, so neither has a save area
+            assert!(
+                threads
+                    .iter()
+                    .all(|id| context.thread_map[id].state.fpu_registers.is_none())
+            );
+
+            for round in 0..4 {
+                for (&id, &value) in threads.iter().zip(&values) {
+                    // switching in arms the trap, the first sse instruction lands in #NM
+                    context.current_thread = Some(id);
+                    context.claim_fpu();
+
+                    if round == 0 {
+                        assert_eq!(read_xmm0(), 0);
+                    } else {
+                        assert_eq!(read_xmm0(), value);
+                    }
+
+                    write_xmm0(value);
+                    assert_eq!(context.fpu_owner, Some(id));
+                }
+            }
+
+            // switching back to the owner doesn't save anything
+            context.current_thread = Some(threads[1]);
+            context.claim_fpu();
+            assert_eq!(read_xmm0(), values[1]);
+
+            context.release_fpu();
+            assert!(context.fpu_owner.is_none());
+            set_fpu_trap(false);
+            for (id, value) in threads.iter().zip(values) {
+                let area = &context.thread_map[id]
+                    .state
+                    .fpu_registers
+                    .as_ref()
+                    .unwrap()
+                    .0;
+                // xmm0 sits at offset 160 of the fxsave area
+                assert_eq!(area[160..168], value.to_le_bytes());
+            }
+            set_fpu_trap(true);
+        });
+
+        end_test!();
+    }
+}
@@ -0,0 +1,985 @@
+use core::sync::atomic::AtomicUsize;
+
+use alloc::{collections::btree_map::BTreeMap, string::String, vec::Vec};
+
+use x86_64::{PhysAddr, registers::control::Cr3, structures::paging::PageTableFlags};
+
+use crate::{
+    arch::x86_64::{
+        err::ErrNo,
+        memory::{PAGE_SIZE, frame_allocator::FRAME_ALLOCATOR},
+        scheduler::{
+            ProcessId, ThreadState,
+            elf::read_elf,
+            forget_page_table,
+            loader::load_elf,
+            uaccess::check_user_range,
+            vma::{AddressSpace, Vma, VmaBacking, release_frames},
+        },
+    },
+    ejcineque::sync::spin::SpinMutex,
+    get_per_cpu_data,
+    hal::{
+        fs::{OpenAccessMode, OpenFlags, OpenFlagsValue},
+        path::Path,
+        vfs::{vfs_close, vfs_open},
+    },
+};
+
+/// 0 is the kernel
+pub static PROCESS_ID_COUNTER: AtomicUsize = AtomicUsize::new(1);
+
+/// the heap starts here and only ever grows up, far away from where the loader puts programs
+pub const HEAP_START: u64 = 0x0000_1000_0000_0000;
+/// brk requests past this fail
+pub const MAX_HEAP_SIZE: u64 = 1 << 30;
+/// anonymous mappings are placed between these
+pub const MMAP_BASE: u64 = 0x0000_2000_0000_0000;
+pub const MMAP_END: u64 = 0x0000_7000_0000_0000;
+
+pub static PROCESSES: SpinMutex<BTreeMap<ProcessId, Process>> = SpinMutex::new(BTreeMap::new());
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Environment {
+    vars: BTreeMap<String, String>,
+}
+
+impl Environment {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.vars.get(name).map(String::as_str)
+    }
+
+    /// keeps the old value if `overwrite` is false, like posix setenv
+    pub fn set(&mut self, name: &str, value: &str, overwrite: bool) -> Result<(), ErrNo> {
+        if !Self::is_valid_name(name) || value.contains('\0') {
+            return Err(ErrNo::InvalidArgument);
+        }
+
+        if overwrite || !self.vars.contains_key(name) {
+            self.vars.insert(name.into(), value.into());
+        }
+
+        Ok(())
+    }
+
+    /// removing a variable that isn't set is not an error
+    pub fn unset(&mut self, name: &str) -> Result<(), ErrNo> {
+        if !Self::is_valid_name(name) {
+            return Err(ErrNo::InvalidArgument);
+        }
+
+        self.vars.remove(name);
+        Ok(())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.vars.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    fn is_valid_name(name: &str) -> bool {
+        !name.is_empty() && !name.contains(['=', '\0'])
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Cwd {
+    /// always normalized
+    pub path: Path,
+    /// the opened directory, None until it has gone through the vfs
+    pub fd: Option<i64>,
+}
+
+impl Cwd {
+    pub fn root() -> Self {
+        Self {
+            path: Path::new_appended("/"),
+            fd: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Process {
+    pub id: ProcessId,
+    pub parent: Option<ProcessId>,
+    pub env: Environment,
+    pub cwd: Cwd,
+    /// the end of the heap, the heap area covers it rounded up to a page
+    pub program_break: u64,
+    pub address_space: AddressSpace,
+}
+
+impl Process {
+    /// the child gets a copy of the environment and the cwd, changes on either side aren't shared
+    pub fn spawn_child(&self, id: ProcessId) -> Process {
+        Process {
+            id,
+            parent: Some(self.id),
+            env: self.env.clone(),
+            cwd: self.cwd.clone(),
+            program_break: HEAP_START,
+            address_space: AddressSpace::default(),
+        }
+    }
+
+    /// relative paths start at the cwd
+    pub fn resolve_path(&self, path: &str) -> Path {
+        self.cwd.path.resolve(path)
+    }
+}
+
+/// registers a new process, inheriting the environment of `parent` if it exists
+pub fn spawn_process(parent: Option<ProcessId>) -> ProcessId {
+    let id = ProcessId(PROCESS_ID_COUNTER.fetch_add(1, core::sync::atomic::Ordering::AcqRel));
+
+    let mut processes = PROCESSES.lock();
+    let process = match parent.and_then(|parent| processes.get(&parent)) {
+        Some(parent) => parent.spawn_child(id),
+        None => Process {
+            id,
+            parent: None,
+            env: Environment::default(),
+            cwd: Cwd::root(),
+            program_break: HEAP_START,
+            address_space: AddressSpace::default(),
+        },
+    };
+
+    processes.insert(id, process);
+
+    id
+}
+
+pub fn remove_process(id: ProcessId) {
+    PROCESSES.lock().remove(&id);
+}
+
+fn resolve_path(process: ProcessId, path: &str) -> Result<Path, ErrNo> {
+    PROCESSES
+        .lock()
+        .get(&process)
+        .map(|process| process.resolve_path(path))
+        .ok_or(ErrNo::InvalidArgument)
+}
+
+/// opens a path relative to the process' cwd
+pub async fn open(process: ProcessId, path: &str, flags: OpenFlags) -> Result<i64, ErrNo> {
+    vfs_open(resolve_path(process, path)?, flags).await
+}
+
+/// the new cwd has to be an existing directory
+pub async fn chdir(process: ProcessId, path: &str) -> Result<(), ErrNo> {
+    let path = resolve_path(process, path)?;
+
+    let fd = vfs_open(
+        path.clone(),
+        OpenFlags {
+            access_mode: OpenAccessMode::Search,
+            flags: OpenFlagsValue::OpenDirectoryOnly as i32,
+            perms: None,
+        },
+    )
+    .await?;
+
+    let old_cwd = PROCESSES
+        .lock()
+        .get_mut(&process)
+        .map(|process| core::mem::replace(&mut process.cwd, Cwd { path, fd: Some(fd) }));
+
+    let Some(old_cwd) = old_cwd else {
+        vfs_close(fd).await?;
+        return Err(ErrNo::InvalidArgument);
+    };
+
+    // the cwd has already moved, a failed close can't undo that
+    if let Some(old_fd) = old_cwd.fd {
+        let _ = vfs_close(old_fd).await;
+    }
+
+    Ok(())
+}
+
+async fn load_program(fd: i64, argv: &[String], envp: &[String]) -> Result<ThreadState, ErrNo> {
+    let elf = read_elf(fd).await?;
+    Ok(load_elf(fd, elf, argv, envp).await?)
+}
+
+/// builds the state of the program that replaces the process' image, the caller swaps it in and
+/// frees the old address space that comes back with it once no core can reach it anymore. fds
+/// and the cwd carry over, the environment becomes `envp`
+pub async fn exec(
+    process: ProcessId,
+    path: &str,
+    argv: &[String],
+    envp: &[String],
+) -> Result<(ThreadState, AddressSpace), ErrNo> {
+    let fd = open(process, path, OpenFlags::default()).await?;
+    let state = load_program(fd, argv, envp).await;
+    vfs_close(fd).await?;
+    let state = state?;
+
+    let mut env = Environment::default();
+    // entries without a '=' can't be looked up anyway
+    for (name, value) in envp.iter().filter_map(|var| var.split_once('=')) {
+        env.set(name, value, true)?;
+    }
+
+    let mut processes = PROCESSES.lock();
+    let process = processes.get_mut(&process).ok_or(ErrNo::InvalidArgument)?;
+    process.env = env;
+    // the old image goes away along with its page table
+    process.program_break = HEAP_START;
+    let old_space = core::mem::take(&mut process.address_space);
+
+    Ok((state, old_space))
+}
+
+pub fn program_break(process: ProcessId) -> Result<u64, ErrNo> {
+    PROCESSES
+        .lock()
+        .get(&process)
+        .map(|process| process.program_break)
+        .ok_or(ErrNo::InvalidArgument)
+}
+
+/// moves the break, returning the new one. Growing only extends the heap area, the pages come in
+/// when they're touched. Shrinking unmaps whatever lies past the new end, see [`munmap`] for the
+/// tlb
+pub fn set_program_break(
+    process: ProcessId,
+    page_table: PhysAddr,
+    new_break: u64,
+) -> Result<u64, ErrNo> {
+    if !(HEAP_START..=HEAP_START + MAX_HEAP_SIZE).contains(&new_break) {
+        return Err(ErrNo::OutOfMemory);
+    }
+
+    let mut processes = PROCESSES.lock();
+    let state = processes.get_mut(&process).ok_or(ErrNo::InvalidArgument)?;
+    let space = &mut state.address_space;
+
+    let heap_end = new_break.next_multiple_of(PAGE_SIZE as u64);
+    if space.vmas.iter().any(|vma| {
+        vma.range.start != HEAP_START && vma.range.start < heap_end && vma.range.end > HEAP_START
+    }) {
+        return Err(ErrNo::OutOfMemory);
+    }
+
+    let (shrunk, released) = match space
+        .vmas
+        .iter()
+        .position(|vma| vma.range.start == HEAP_START)
+    {
+        Some(idx) if heap_end == HEAP_START => {
+            let mut heap = space.vmas.remove(idx);
+            (true, heap.release_from(page_table, HEAP_START))
+        }
+        Some(idx) => {
+            let heap = &mut space.vmas[idx];
+            let shrunk = heap_end < heap.range.end;
+            let released = heap.release_from(page_table, heap_end);
+            heap.range.end = heap_end;
+            (shrunk, released)
+        }
+        None if heap_end != HEAP_START => {
+            space.insert(Vma::new(
+                HEAP_START..heap_end,
+                PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+                VmaBacking::Anonymous,
+            ))?;
+            (false, Vec::new())
+        }
+        None => (false, Vec::new()),
+    };
+
+    state.program_break = new_break;
+    drop(processes);
+
+    // same as munmap, no core may still reach the frames once they're handed back
+    if shrunk {
+        forget_page_table(process, page_table);
+    }
+    release_frames(released);
+
+    Ok(new_break)
+}
+
+/// returns the old break like sbrk does
+pub fn sbrk(process: ProcessId, page_table: PhysAddr, increment: i64) -> Result<u64, ErrNo> {
+    let old_break = program_break(process)?;
+    let new_break = old_break
+        .checked_add_signed(increment)
+        .ok_or(ErrNo::OutOfMemory)?;
+
+    set_program_break(process, page_table, new_break)?;
+
+    Ok(old_break)
+}
+
+/// reserves `len` bytes of zero filled memory wherever there's room, nothing is backed until
+/// it's touched
+pub fn mmap_anonymous(process: ProcessId, len: u64, prot: PageTableFlags) -> Result<u64, ErrNo> {
+    if len == 0 {
+        return Err(ErrNo::InvalidArgument);
+    }
+
+    let len = len
+        .checked_next_multiple_of(PAGE_SIZE as u64)
+        .ok_or(ErrNo::OutOfMemory)?;
+
+    let mut processes = PROCESSES.lock();
+    let space = &mut processes
+        .get_mut(&process)
+        .ok_or(ErrNo::InvalidArgument)?
+        .address_space;
+
+    let start = space
+        .find_gap(len, MMAP_BASE..MMAP_END)
+        .ok_or(ErrNo::OutOfMemory)?;
+    space.insert(Vma::new(start..start + len, prot, VmaBacking::Anonymous))?;
+
+    Ok(start)
+}
+
+/// frees the pages inside the range, mappings that only partly overlap it are split. The heap
+/// can only be given back with brk. Unmapping only invalidates the tlb of this core under the
+/// pcid loaded right now, so the frames stay allocated until every core dropped what it cached
+/// for the address space
+pub fn munmap(process: ProcessId, page_table: PhysAddr, addr: u64, len: u64) -> Result<(), ErrNo> {
+    if addr % PAGE_SIZE as u64 != 0 || len == 0 {
+        return Err(ErrNo::InvalidArgument);
+    }
+
+    check_user_range(addr, len)?;
+    let end = (addr + len).next_multiple_of(PAGE_SIZE as u64);
+
+    if addr < HEAP_START + MAX_HEAP_SIZE && HEAP_START < end {
+        return Err(ErrNo::InvalidArgument);
+    }
+
+    let released = PROCESSES
+        .lock()
+        .get_mut(&process)
+        .ok_or(ErrNo::InvalidArgument)?
+        .address_space
+        .remove_range(page_table, addr..end)?;
+
+    forget_page_table(process, page_table);
+    release_frames(released);
+
+    Ok(())
+}
+
+/// backs every untouched page of the range that an area covers. The kernel reaches user memory
+/// through the hhdm where nothing faults, so syscalls call this before copying. The length comes
+/// from user space, so gaps between areas are skipped instead of walked and the process lock is
+/// only held for one page at a time
+pub async fn fault_in(
+    process: ProcessId,
+    page_table: PhysAddr,
+    addr: u64,
+    len: usize,
+) -> Result<(), ErrNo> {
+    check_user_range(addr, len as u64)?;
+
+    let mut allocator = FRAME_ALLOCATOR
+        .get()
+        .expect("Failed to get allocator")
+        .lock()
+        .await;
+
+    let end = addr + len as u64;
+    let mut page = addr & !(PAGE_SIZE as u64 - 1);
+    while page < end {
+        let mut processes = PROCESSES.lock();
+        let space = &mut processes
+            .get_mut(&process)
+            .ok_or(ErrNo::InvalidArgument)?
+            .address_space;
+
+        match space.find(page) {
+            Some(vma) => {
+                if !vma.is_resident(page) {
+                    space.fault_in(page_table, page, &mut allocator)?;
+                }
+                page += PAGE_SIZE as u64;
+            }
+            None => match space.next_area_after(page) {
+                Some(start) => page = start,
+                None => break,
+            },
+        }
+    }
+
+    Ok(())
+}
+
+/// called by the page fault handler for faults from user mode, nothing in here may wait
+pub fn resolve_user_fault(addr: u64) -> bool {
+    let context = &get_per_cpu_data!().scheduler_context;
+    let Some(process) = context
+        .current_thread
+        .and_then(|id| context.thread_map.get(&id))
+        .map(|thread| thread.process)
+    else {
+        return false;
+    };
+
+    let Some(mut allocator) = FRAME_ALLOCATOR.get().and_then(|a| a.try_lock()) else {
+        return false;
+    };
+
+    let Some(mut processes) = PROCESSES.try_lock() else {
+        return false;
+    };
+
+    processes.get_mut(&process).is_some_and(|process| {
+        process
+            .address_space
+            .fault_in(Cr3::read().0.start_address(), addr, &mut allocator)
+            .is_ok()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use core::{
+        sync::atomic::{AtomicBool, Ordering},
+        time::Duration,
+    };
+
+    use alloc::sync::Arc;
+    use x86_64::{
+        PhysAddr, VirtAddr,
+        instructions::interrupts::without_interrupts,
+        structures::paging::{PageTableFlags, PhysFrame, Size4KiB, mapper::Translate},
+    };
+
+    use super::{
+        Cwd, HEAP_START, MAX_HEAP_SIZE, MMAP_BASE, PROCESSES, chdir, fault_in, mmap_anonymous,
+        munmap, open, program_break, resolve_path, sbrk, set_program_break, spawn_process,
+    };
+    use crate::{
+        SPAWNER,
+        arch::x86_64::{
+            err::ErrNo,
+            memory::{
+                PAGE_SIZE, frame_allocator::FRAME_ALLOCATOR, get_hhdm_offset,
+                page_table::create_page_table, pcid::CR3_NO_FLUSH, per_cpu::PER_CPU_DATA_PTRS,
+            },
+            scheduler::{
+                CpuCoreId,
+                uaccess::{USER_SPACE_END, copy_from_user, copy_to_user, page_table_at},
+            },
+            timer::Instant,
+        },
+        end_test, get_per_cpu_data, get_per_cpu_data_mut,
+        hal::{
+            fs::{OpenAccessMode, OpenFlags, OpenFlagsValue},
+            path::Path,
+            vfs::{vfs_close, vfs_mkdir, vfs_open},
+        },
+        ignore,
+        terminal::test::block_on,
+        test_name,
+    };
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn env_inheritance() {
+        test_name!("child processes inherit a copy of the environment");
+
+        let parent = spawn_process(None);
+        {
+            let mut processes = PROCESSES.lock();
+            let env = &mut processes.get_mut(&parent).unwrap().env;
+            env.set("PATH", "/bin", true).unwrap();
+            env.set("PWD", "/", true).unwrap();
+            env.set("PWD", "/home", false).unwrap();
+            assert_eq!(env.set("A=B", "c", true), Err(ErrNo::InvalidArgument));
+            assert_eq!(env.set("", "c", true), Err(ErrNo::InvalidArgument));
+        }
+
+        let child = spawn_process(Some(parent));
+        {
+            let mut processes = PROCESSES.lock();
+            let env = &mut processes.get_mut(&parent).unwrap().env;
+            env.set("PATH", "/usr/bin", true).unwrap();
+            env.unset("PWD").unwrap();
+        }
+
+        {
+            let processes = PROCESSES.lock();
+            let child = processes.get(&child).unwrap();
+            assert_eq!(child.parent, Some(parent));
+            assert_eq!(child.env.get("PATH"), Some("/bin"));
+            assert_eq!(child.env.get("PWD"), Some("/"));
+
+            let parent = processes.get(&parent).unwrap();
+            assert_eq!(parent.env.get("PATH"), Some("/usr/bin"));
+            assert_eq!(parent.env.get("PWD"), None);
+        }
+
+        super::remove_process(child);
+        super::remove_process(parent);
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn cwd_resolution() {
+        test_name!("relative paths resolve against the cwd");
+
+        let parent = spawn_process(None);
+        assert_eq!(resolve_path(parent, "a/b").unwrap().as_str(), "/a/b");
+
+        PROCESSES.lock().get_mut(&parent).unwrap().cwd = Cwd {
+            path: Path::new_appended("/a/b"),
+            fd: None,
+        };
+
+        assert_eq!(resolve_path(parent, "../c").unwrap().as_str(), "/a/c");
+        assert_eq!(resolve_path(parent, "./d/../e").unwrap().as_str(), "/a/b/e");
+        assert_eq!(resolve_path(parent, "/x/./y").unwrap().as_str(), "/x/y");
+        // .. can't climb out of the root
+        assert_eq!(
+            resolve_path(parent, "../../../../c").unwrap().as_str(),
+            "/c"
+        );
+
+        // the child starts where the parent was
+        let child = spawn_process(Some(parent));
+        assert_eq!(resolve_path(child, "c").unwrap().as_str(), "/a/b/c");
+
+        super::remove_process(child);
+        super::remove_process(parent);
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn chdir_moves_relative_opens() {
+        test_name!("chdir only enters directories and relative opens follow it");
+
+        let cwd_fd = |process| PROCESSES.lock().get(&process).unwrap().cwd.fd;
+
+        for dir in ["/tmp/chdir", "/tmp/chdir/a", "/tmp/chdir/a/b"] {
+            block_on(vfs_mkdir(Path::new_appended(dir), 0o755)).expect("Failed to create dir");
+        }
+
+        let process = spawn_process(None);
+        block_on(chdir(process, "/tmp/chdir/a/b")).expect("Failed to chdir");
+
+        let create = OpenFlags {
+            access_mode: OpenAccessMode::ReadNWrite,
+            flags: OpenFlagsValue::CreateIfNotExist as i32,
+            perms: Some(0o644),
+        };
+        let fd = block_on(open(process, "../c", create)).expect("Failed to create ../c");
+        block_on(vfs_close(fd)).expect("Failed to close");
+
+        // ../c is next to the new cwd, not inside it or next to the root
+        let fd = block_on(vfs_open(
+            Path::new_appended("/tmp/chdir/a/c"),
+            OpenFlags::default(),
+        ))
+        .expect("../c didn't end up in /tmp/chdir/a");
+        block_on(vfs_close(fd)).expect("Failed to close");
+        assert_eq!(
+            block_on(vfs_open(
+                Path::new_appended("/tmp/chdir/a/b/c"),
+                OpenFlags::default()
+            )),
+            Err(ErrNo::NoSuchFileOrDirectory)
+        );
+
+        // files and missing paths leave the cwd alone
+        assert_eq!(block_on(chdir(process, "../c")), Err(ErrNo::NotADirectory));
+        assert_eq!(
+            block_on(chdir(process, "missing")),
+            Err(ErrNo::NoSuchFileOrDirectory)
+        );
+        assert_eq!(
+            resolve_path(process, ".").unwrap().as_str(),
+            "/tmp/chdir/a/b"
+        );
+
+        // moving on closes the directory the old cwd held open
+        let old_fd = cwd_fd(process).expect("The cwd wasn't opened");
+        block_on(chdir(process, "..")).expect("Failed to chdir");
+        assert_eq!(
+            resolve_path(process, "c").unwrap().as_str(),
+            "/tmp/chdir/a/c"
+        );
+        assert_eq!(block_on(vfs_close(old_fd)), Err(ErrNo::BadFd));
+
+        block_on(vfs_close(cwd_fd(process).unwrap())).expect("Failed to close");
+        super::remove_process(process);
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn sbrk_grow_and_shrink() {
+        test_name!("sbrk maps heap pages on demand and shrinking unmaps them");
+
+        let process = spawn_process(None);
+        let page_table =
+            PhysAddr::new(block_on(create_page_table()).as_u64() - get_hhdm_offset().as_u64());
+        let page = PAGE_SIZE as u64;
+
+        assert_eq!(
+            sbrk(process, page_table, 3 * page as i64 + 100),
+            Ok(HEAP_START)
+        );
+        assert_eq!(program_break(process), Ok(HEAP_START + 3 * page + 100));
+
+        // nothing is mapped until it's touched
+        assert_eq!(
+            copy_from_user(page_table, HEAP_START, 1),
+            Err(ErrNo::BadAddress)
+        );
+
+        block_on(fault_in(
+            process,
+            page_table,
+            HEAP_START,
+            3 * page as usize + 100,
+        ))
+        .unwrap();
+        {
+            let processes = PROCESSES.lock();
+            let heap = processes
+                .get(&process)
+                .unwrap()
+                .address_space
+                .find(HEAP_START)
+                .unwrap();
+            assert_eq!(heap.range, HEAP_START..HEAP_START + 4 * page);
+            assert_eq!(heap.resident_pages(), 4);
+        }
+
+        copy_to_user(page_table, HEAP_START + page - 2, b"heap").unwrap();
+        assert_eq!(
+            copy_from_user(page_table, HEAP_START + page - 2, 4).unwrap(),
+            b"heap"
+        );
+        assert!(
+            copy_from_user(page_table, HEAP_START + 2 * page, 16)
+                .unwrap()
+                .iter()
+                .all(|&b| b == 0)
+        );
+
+        // shrinking to the first page takes the rest away
+        assert_eq!(
+            sbrk(process, page_table, -(3 * page as i64 + 100) + 10),
+            Ok(HEAP_START + 3 * page + 100)
+        );
+        assert_eq!(program_break(process), Ok(HEAP_START + 10));
+        assert_eq!(
+            copy_from_user(page_table, HEAP_START + page, 1),
+            Err(ErrNo::BadAddress)
+        );
+        assert_eq!(copy_from_user(page_table, HEAP_START, 2).unwrap(), b"\0\0");
+
+        // out of bounds requests leave the break alone
+        assert_eq!(
+            set_program_break(process, page_table, HEAP_START + MAX_HEAP_SIZE + 1),
+            Err(ErrNo::OutOfMemory)
+        );
+        assert_eq!(
+            set_program_break(process, page_table, HEAP_START - 1),
+            Err(ErrNo::OutOfMemory)
+        );
+        assert_eq!(program_break(process), Ok(HEAP_START + 10));
+
+        assert_eq!(
+            set_program_break(process, page_table, HEAP_START),
+            Ok(HEAP_START)
+        );
+        assert!(
+            PROCESSES
+                .lock()
+                .get(&process)
+                .unwrap()
+                .address_space
+                .vmas
+                .is_empty()
+        );
+
+        super::remove_process(process);
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn anonymous_mmap() {
+        test_name!("anonymous mappings read as zero and keep what's written until munmap");
+
+        let process = spawn_process(None);
+        let page_table =
+            PhysAddr::new(block_on(create_page_table()).as_u64() - get_hhdm_offset().as_u64());
+        let page = PAGE_SIZE as u64;
+        let prot = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+
+        let addr = mmap_anonymous(process, 3 * page + 1, prot).unwrap();
+        assert_eq!(addr, MMAP_BASE);
+        let other = mmap_anonymous(process, page, prot).unwrap();
+        assert_eq!(other, addr + 4 * page);
+
+        // nothing is there before the pages are touched
+        assert_eq!(copy_from_user(page_table, addr, 1), Err(ErrNo::BadAddress));
+        block_on(fault_in(process, page_table, addr, 4 * page as usize)).unwrap();
+        assert!(
+            copy_from_user(page_table, addr, 4 * page as usize)
+                .unwrap()
+                .iter()
+                .all(|&b| b == 0)
+        );
+
+        copy_to_user(page_table, addr + 2 * page - 3, b"mapped").unwrap();
+        assert_eq!(
+            copy_from_user(page_table, addr + 2 * page - 3, 6).unwrap(),
+            b"mapped"
+        );
+
+        // the heap can't go away this way
+        assert_eq!(
+            munmap(process, page_table, HEAP_START, page),
+            Err(ErrNo::InvalidArgument)
+        );
+        assert_eq!(
+            munmap(process, page_table, addr + 1, page),
+            Err(ErrNo::InvalidArgument)
+        );
+
+        // taking the second page out splits the mapping around it
+        munmap(process, page_table, addr + page, page).unwrap();
+        assert_eq!(
+            copy_from_user(page_table, addr + 2 * page - 3, 6),
+            Err(ErrNo::BadAddress)
+        );
+        assert_eq!(
+            copy_from_user(page_table, addr + 2 * page, 3).unwrap(),
+            b"ped"
+        );
+        {
+            let processes = PROCESSES.lock();
+            let space = &processes.get(&process).unwrap().address_space;
+            assert_eq!(space.find(addr).unwrap().range, addr..addr + page);
+            assert_eq!(
+                space.find(addr + 2 * page).unwrap().range,
+                addr + 2 * page..addr + 4 * page
+            );
+        }
+
+        munmap(process, page_table, addr, 4 * page).unwrap();
+        assert_eq!(
+            copy_from_user(page_table, addr + 2 * page, 3),
+            Err(ErrNo::BadAddress)
+        );
+        assert!(
+            PROCESSES
+                .lock()
+                .get(&process)
+                .unwrap()
+                .address_space
+                .find(addr)
+                .is_none()
+        );
+
+        // the hole is handed out again
+        assert_eq!(mmap_anonymous(process, 2 * page, prot), Ok(addr));
+        assert_eq!(
+            mmap_anonymous(process, 0, prot),
+            Err(ErrNo::InvalidArgument)
+        );
+
+        super::remove_process(process);
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn unmapping_drops_cached_translations() {
+        test_name!("munmap and a shrinking brk make the next switch start with a fresh tlb");
+
+        let process = spawn_process(None);
+        let page_table =
+            PhysAddr::new(block_on(create_page_table()).as_u64() - get_hhdm_offset().as_u64());
+        let page = PAGE_SIZE as u64;
+        let prot = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+
+        // what switching to the process would load, 0 while its translations are still trusted
+        let cr3 = || {
+            without_interrupts(|| {
+                get_per_cpu_data_mut!()
+                    .scheduler_context
+                    .page_table_to_load(process, page_table)
+            })
+        };
+
+        let addr = mmap_anonymous(process, 2 * page, prot).unwrap();
+        block_on(fault_in(process, page_table, addr, 2 * page as usize)).unwrap();
+        cr3();
+        assert_eq!(cr3(), 0);
+
+        munmap(process, page_table, addr, page).unwrap();
+        let reload = cr3();
+        assert_ne!(reload, 0);
+        assert_eq!(reload & CR3_NO_FLUSH, 0);
+
+        sbrk(process, page_table, 2 * page as i64).unwrap();
+        block_on(fault_in(process, page_table, HEAP_START, 2 * page as usize)).unwrap();
+        // growing doesn't take anything away
+        assert_eq!(cr3(), 0);
+
+        sbrk(process, page_table, -(page as i64)).unwrap();
+        let reload = cr3();
+        assert_ne!(reload, 0);
+        assert_eq!(reload & CR3_NO_FLUSH, 0);
+
+        set_program_break(process, page_table, HEAP_START).unwrap();
+        munmap(process, page_table, addr + page, page).unwrap();
+        super::remove_process(process);
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn partial_munmap_waits_for_every_core() {
+        test_name!("a partial munmap keeps the frames until every core answered the shootdown");
+
+        let current = CpuCoreId::current().as_u32();
+        let Some(held) = PER_CPU_DATA_PTRS
+            .get()
+            .and_then(|ptrs| ptrs.keys().copied().find(|&id| id != current))
+        else {
+            ignore!();
+        };
+
+        let process = spawn_process(None);
+        let page_table =
+            PhysAddr::new(block_on(create_page_table()).as_u64() - get_hhdm_offset().as_u64());
+        let page = PAGE_SIZE as u64;
+        let prot = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+
+        let addr = mmap_anonymous(process, 3 * page, prot).unwrap();
+        block_on(fault_in(process, page_table, addr, 3 * page as usize)).unwrap();
+        let frame = page_table_at(page_table)
+            .translate_addr(VirtAddr::new(addr + page))
+            .unwrap();
+
+        let holding = Arc::new(AtomicBool::new(false));
+        let unmapped = Arc::new(AtomicBool::new(false));
+
+        // the other core sits with interrupts off, so the ipi stays pending until it lets go
+        let handle = {
+            let holding = holding.clone();
+            let unmapped = unmapped.clone();
+            SPAWNER.get().unwrap().spawn_on(held, async move {
+                without_interrupts(|| {
+                    holding.store(true, Ordering::Release);
+
+                    let deadline = Instant::now() + Duration::from_secs(1);
+                    while get_per_cpu_data!()
+                        .scheduler_context
+                        .shootdown_request
+                        .lock()
+                        .is_none()
+                    {
+                        if Instant::now() > deadline {
+                            return (false, false);
+                        }
+                        core::hint::spin_loop();
+                    }
+
+                    let mut kept = true;
+                    let deadline = Instant::now() + Duration::from_millis(10);
+                    while Instant::now() < deadline {
+                        kept &= !unmapped.load(Ordering::Acquire);
+                        if let Some(allocator) = FRAME_ALLOCATOR.get().and_then(|a| a.try_lock()) {
+                            let idx = (frame.as_u64() / PAGE_SIZE as u64) as usize;
+                            let frame = PhysFrame::<Size4KiB>::containing_address(frame);
+                            kept &= allocator.bitmap[idx / 8] & (1 << (idx % 8)) != 0
+                                && !allocator.zeroed.contains(&frame);
+                        }
+                        core::hint::spin_loop();
+                    }
+
+                    (true, kept)
+                })
+            })
+        };
+
+        while !holding.load(Ordering::Acquire) {
+            core::hint::spin_loop();
+        }
+
+        munmap(process, page_table, addr + page, page).unwrap();
+        unmapped.store(true, Ordering::Release);
+
+        let (saw_request, kept) = block_on(handle);
+        assert!(saw_request);
+        assert!(kept);
+
+        // the pages around the hole stay
+        let mapper = page_table_at(page_table);
+        assert!(mapper.translate_addr(VirtAddr::new(addr)).is_some());
+        assert!(mapper.translate_addr(VirtAddr::new(addr + page)).is_none());
+        assert!(
+            mapper
+                .translate_addr(VirtAddr::new(addr + 2 * page))
+                .is_some()
+        );
+
+        munmap(process, page_table, addr, 3 * page).unwrap();
+        super::remove_process(process);
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn fault_in_skips_unmapped_gaps() {
+        test_name!("faulting in a huge range only walks the areas inside it");
+
+        let process = spawn_process(None);
+        let page_table =
+            PhysAddr::new(block_on(create_page_table()).as_u64() - get_hhdm_offset().as_u64());
+        let page = PAGE_SIZE as u64;
+        let prot = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+
+        sbrk(process, page_table, 2 * page as i64).unwrap();
+        let addr = mmap_anonymous(process, page, prot).unwrap();
+
+        // terabytes lie between and after the two areas, a page by page walk wouldn't finish
+        let len = USER_SPACE_END - HEAP_START;
+        block_on(fault_in(process, page_table, HEAP_START, len as usize)).unwrap();
+        {
+            let processes = PROCESSES.lock();
+            let space = &processes.get(&process).unwrap().address_space;
+            assert_eq!(space.find(HEAP_START).unwrap().resident_pages(), 2);
+            assert_eq!(space.find(addr).unwrap().resident_pages(), 1);
+        }
+
+        // nothing past the user half
+        assert_eq!(
+            block_on(fault_in(process, page_table, HEAP_START, len as usize + 1)),
+            Err(ErrNo::BadAddress)
+        );
+
+        munmap(process, page_table, addr, page).unwrap();
+        set_program_break(process, page_table, HEAP_START).unwrap();
+        super::remove_process(process);
+
+        end_test!();
+    }
+}
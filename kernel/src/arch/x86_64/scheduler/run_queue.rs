@@ -0,0 +1,99 @@
+use alloc::collections::vec_deque::VecDeque;
+
+use crate::arch::x86_64::scheduler::ThreadId;
+
+/// how many priority levels there are, 0 is the lowest
+pub const PRIORITY_LEVELS: usize = 8;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Priority(u8);
+
+impl Priority {
+    pub const LOWEST: Self = Self(0);
+    pub const HIGHEST: Self = Self(PRIORITY_LEVELS as u8 - 1);
+    pub const DEFAULT: Self = Self(PRIORITY_LEVELS as u8 / 2);
+
+    /// levels past the highest one are clamped
+    pub fn new(level: u8) -> Self {
+        Self(level.min(Self::HIGHEST.0))
+    }
+
+    pub fn level(&self) -> usize {
+        self.0 as usize
+    }
+
+    /// how many turns the level gets per round
+    pub fn weight(&self) -> u32 {
+        self.0 as u32 + 1
+    }
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// one fifo per priority, picked from with weighted round robin. Every round each level gets as
+/// many turns as its weight, higher levels go first, so busy low priority threads still run once
+/// per round instead of starving
+#[derive(Debug, Default)]
+pub struct RunQueue {
+    levels: [VecDeque<ThreadId>; PRIORITY_LEVELS],
+    /// turns each level has left in this round
+    credits: [u32; PRIORITY_LEVELS],
+}
+
+impl RunQueue {
+    pub fn push_back(&mut self, id: ThreadId, priority: Priority) {
+        self.levels[priority.level()].push_back(id);
+    }
+
+    pub fn pop_front(&mut self) -> Option<ThreadId> {
+        if self.is_empty() {
+            return None;
+        }
+
+        loop {
+            if let Some(level) = (0..PRIORITY_LEVELS)
+                .rev()
+                .find(|&level| self.credits[level] > 0 && !self.levels[level].is_empty())
+            {
+                self.credits[level] -= 1;
+                return self.levels[level].pop_front();
+            }
+
+            // everyone waiting used up their turns, next round
+            for (level, credits) in self.credits.iter_mut().enumerate() {
+                *credits = Priority(level as u8).weight();
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.levels.iter().map(VecDeque::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.levels.iter().all(VecDeque::is_empty)
+    }
+
+    /// highest priority first, each level in queue order
+    pub fn iter(&self) -> impl Iterator<Item = &ThreadId> {
+        self.levels.iter().rev().flatten()
+    }
+
+    pub fn retain(&mut self, mut f: impl FnMut(&ThreadId) -> bool) {
+        for level in &mut self.levels {
+            level.retain(&mut f);
+        }
+    }
+
+    /// the most recently queued thread matching `f`, lower priorities are given up first
+    pub fn take_last(&mut self, mut f: impl FnMut(&ThreadId) -> bool) -> Option<ThreadId> {
+        self.levels.iter_mut().find_map(|level| {
+            let idx = level.iter().rposition(&mut f)?;
+            level.remove(idx)
+        })
+    }
+}
@@ -0,0 +1,126 @@
+use core::sync::atomic::Ordering;
+
+use alloc::vec::Vec;
+use x86_64::{
+    PhysAddr, instructions::interrupts::without_interrupts, registers::control::Cr3,
+    structures::paging::PhysFrame,
+};
+
+use crate::arch::x86_64::{
+    acpi::apic::get_local_apic,
+    idt::TLB_SHOOTDOWN_HANDLER_IDX,
+    memory::per_cpu::{PER_CPU_DATA_PTRS, PerCPUData},
+    scheduler::{CpuCoreId, ProcessId, SchedulerCpuContext},
+};
+
+/// what every core has to drop from its tlb before frames that were mapped may be freed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shootdown {
+    /// `page_table` of the process lost some of its mappings
+    PageTable(ProcessId, PhysAddr),
+    /// the process goes away with its threads and page tables
+    Process(ProcessId),
+}
+
+impl SchedulerCpuContext {
+    /// what `request` takes on this core
+    pub fn drop_translations(&mut self, request: Shootdown) {
+        match request {
+            Shootdown::PageTable(process, page_table) => {
+                self.forget_page_table(process, page_table);
+
+                // invlpg only reached the tlb of the core that unmapped, and only under the pcid
+                // it had loaded. Loading cr3 again without keeping anything drops the rest
+                let (frame, pcid) = Cr3::read_raw();
+                if frame.start_address() == page_table {
+                    unsafe { Cr3::write_raw(frame, pcid) };
+                }
+            }
+
+            Shootdown::Process(process) => {
+                // cr3 can't keep pointing at a table that's about to be freed, the kernel's own
+                // table maps everything this core runs until the next switch loads cr3 again
+                let (frame, _) = Cr3::read_raw();
+                let loaded = self.thread_map.values().any(|thread| {
+                    thread.process == process
+                        && thread.state.page_table_pointer == frame.start_address()
+                });
+                if let Some(idle) = self.idle_thread.and_then(|id| self.thread_map.get(&id))
+                    && loaded
+                {
+                    let kernel = PhysFrame::containing_address(idle.state.page_table_pointer);
+                    unsafe { Cr3::write_raw(kernel, 0) };
+                }
+
+                self.drop_process_threads(process);
+            }
+        }
+    }
+
+    /// handles what another core left in `shootdown_request`, the request is only cleared once
+    /// it's done since that is what the other core waits on
+    pub fn answer_shootdown(&mut self) {
+        let Some(request) = *self.shootdown_request.lock() else {
+            return;
+        };
+
+        self.drop_translations(request);
+        *self.shootdown_request.lock() = None;
+    }
+
+    /// drops what this core cached for `request`, then has every other core do the same and waits
+    /// until each of them has. Only then may the frames the request covers go back to the
+    /// allocator, a core still holding a translation could reach them once they belong to someone
+    /// else. Other cores may be spinning with interrupts off until it's their turn, nothing they
+    /// could be waiting on may be held across this
+    pub fn shoot_down(&mut self, request: Shootdown) {
+        without_interrupts(|| {
+            self.drop_translations(request);
+
+            let targets = shootdown_targets();
+            for &(id, target) in targets.iter() {
+                // one request per core at a time, the core that got there first may be waiting on
+                // this one to answer its own
+                loop {
+                    {
+                        let mut slot = target.shootdown_request.lock();
+                        if slot.is_none() {
+                            *slot = Some(request);
+                            break;
+                        }
+                    }
+
+                    self.answer_shootdown();
+                    core::hint::spin_loop();
+                }
+
+                get_local_apic().send_fixed_ipi(id, TLB_SHOOTDOWN_HANDLER_IDX);
+            }
+
+            for (_, target) in targets {
+                while target.shootdown_request.lock().is_some() {
+                    self.answer_shootdown();
+                    core::hint::spin_loop();
+                }
+            }
+        });
+    }
+}
+
+/// every other core that could have cached something, with the apic id to send the ipi to. A core
+/// that doesn't take shootdowns yet never ran a thread
+fn shootdown_targets() -> Vec<(u32, &'static SchedulerCpuContext)> {
+    let current = CpuCoreId::current();
+
+    PER_CPU_DATA_PTRS
+        .get()
+        .expect("Failed to get per cpu data pointers")
+        .iter()
+        .filter(|&(&id, _)| id != current.0)
+        .map(|(&id, &ptr)| {
+            let data = unsafe { &*(ptr as *const PerCPUData) };
+            (id, &data.scheduler_context)
+        })
+        .filter(|(_, context)| context.takes_shootdowns.load(Ordering::Acquire))
+        .collect()
+}
@@ -0,0 +1,515 @@
+use crate::{
+    arch::x86_64::{
+        err::ErrNo,
+        scheduler::{
+            DEFAULT_TICKS_PER_THREAD, ProcessId, SchedulerCpuContext, Thread, ThreadId,
+            on_other_cores,
+            process::{self, PROCESSES},
+            shootdown::Shootdown,
+            steal::request_work,
+            syscall::resume_thread,
+        },
+    },
+    get_per_cpu_data_mut, log,
+};
+
+pub type Signal = u8;
+
+/// same numbers as linux
+pub const SIGILL: Signal = 4;
+pub const SIGFPE: Signal = 8;
+pub const SIGKILL: Signal = 9;
+pub const SIGSEGV: Signal = 11;
+pub const SIGTERM: Signal = 15;
+pub const SIGCHLD: Signal = 17;
+
+/// signals go from 1 to 63, bit n of the mask stands for signal n
+pub const MAX_SIGNAL: Signal = 63;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignalAction {
+    Terminate,
+    Ignore,
+}
+
+/// there are no handlers yet so every signal does what it does by default
+pub fn default_action(signal: Signal) -> SignalAction {
+    match signal {
+        SIGCHLD => SignalAction::Ignore,
+        _ => SignalAction::Terminate,
+    }
+}
+
+pub fn check_signal(signal: Signal) -> Result<(), ErrNo> {
+    if (1..=MAX_SIGNAL).contains(&signal) {
+        Ok(())
+    } else {
+        Err(ErrNo::InvalidArgument)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PendingSignals(u64);
+
+impl PendingSignals {
+    pub fn raise(&mut self, signal: Signal) {
+        self.0 |= 1 << signal;
+    }
+
+    pub fn is_pending(&self, signal: Signal) -> bool {
+        self.0 & (1 << signal) != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// lowest number first
+    pub fn take_next(&mut self) -> Option<Signal> {
+        if self.0 == 0 {
+            return None;
+        }
+
+        let signal = self.0.trailing_zeros() as Signal;
+        self.0 &= !(1 << signal);
+        Some(signal)
+    }
+}
+
+impl SchedulerCpuContext {
+    /// marks `signal` pending on every thread of `process` on this core, returns whether there
+    /// was any
+    pub fn raise_signal(&mut self, process: ProcessId, signal: Signal) -> bool {
+        let mut found = false;
+
+        for thread in self
+            .thread_map
+            .values_mut()
+            .filter(|thread| thread.process == process)
+        {
+            thread.state.pending_signals.raise(signal);
+            found = true;
+        }
+
+        found
+    }
+
+    /// runs the default action of everything pending on `id`, false if the thread is gone
+    /// afterwards
+    pub fn deliver_signals(&mut self, id: ThreadId) -> bool {
+        let Some(thread) = self.thread_map.get_mut(&id) else {
+            return false;
+        };
+
+        while let Some(signal) = thread.state.pending_signals.take_next() {
+            match default_action(signal) {
+                SignalAction::Ignore => {}
+                SignalAction::Terminate => {
+                    log!(
+                        "Process {:?} terminated by signal {}",
+                        thread.process,
+                        signal
+                    );
+                    let process = thread.process;
+                    self.terminate_process(process);
+                    return false;
+                }
+            }
+        }
+
+        true
+    }
+
+    /// drops the process with every one of its threads. Its address space is only freed once every
+    /// core dropped its threads and moved off its page table, see [`Self::shoot_down`]. The ids
+    /// still in the queues are skipped when they come up
+    pub fn terminate_process(&mut self, process: ProcessId) {
+        self.shoot_down(Shootdown::Process(process));
+        process::remove_process(process);
+    }
+
+    /// what terminating a process takes on each core, its threads and whatever the core cached
+    /// for its address space go
+    pub fn drop_process_threads(&mut self, process: ProcessId) {
+        self.thread_map
+            .retain(|_, thread| thread.process != process);
+        // its page table goes back to the allocator with it
+        self.forget_loaded_page_table();
+        self.release_pcids(process);
+    }
+
+    pub fn release_pcids(&mut self, process: ProcessId) {
+        if let Some(ref mut pcids) = self.pcids {
+            pcids.release_process(process);
+        }
+    }
+
+    /// the thread `id` exited on its own, its frames go back to the allocator with it. The
+    /// process goes once it has no thread left on the core, the same way
+    /// [`Self::terminate_process`] takes it
+    pub fn exit_thread(&mut self, id: ThreadId) {
+        let Some(process) = self.thread_map.get(&id).map(|thread| thread.process) else {
+            return;
+        };
+
+        if self
+            .thread_map
+            .values()
+            .any(|thread| thread.process == process && thread.id != id)
+        {
+            self.thread_map.remove(&id);
+            // the page table may have gone with the frames
+            self.forget_loaded_page_table();
+            return;
+        }
+
+        // the thread stays until then, cr3 may still point at the page table it frees
+        self.terminate_process(process);
+    }
+
+    /// raises `signal` on `id` and delivers it on the spot, false if the thread didn't survive
+    pub fn fault_thread(&mut self, id: ThreadId, signal: Signal) -> bool {
+        if let Some(thread) = self.thread_map.get_mut(&id) {
+            thread.state.pending_signals.raise(signal);
+        }
+
+        self.deliver_signals(id)
+    }
+
+    /// the next queued thread that survives its pending signals, this is the last stop before
+    /// going back to user mode
+    pub fn next_runnable(&mut self) -> Option<&mut Thread> {
+        while let Some(id) = self.thread_queue.pop_front() {
+            if self.deliver_signals(id) {
+                return self.thread_map.get_mut(&id);
+            }
+        }
+
+        None
+    }
+
+    /// an empty queue isn't an error, the core idles until something shows up
+    pub fn next_or_idle(&mut self) -> Option<&mut Thread> {
+        let id = match self.next_runnable() {
+            Some(thread) => thread.id,
+            None => self.idle_thread?,
+        };
+        self.publish_load();
+
+        self.thread_map.get_mut(&id)
+    }
+}
+
+/// kill(pid, sig). Threads on this core get it right away, the other cores are told by a task
+/// spawned on each of them. Nobody may signal the kernel
+pub fn send_signal(
+    context: &mut SchedulerCpuContext,
+    process: ProcessId,
+    signal: Signal,
+) -> Result<(), ErrNo> {
+    check_signal(signal)?;
+
+    if process == ProcessId(0) {
+        return Err(ErrNo::OperationNotPermitted);
+    }
+
+    if !PROCESSES.lock().contains_key(&process) {
+        return Err(ErrNo::InvalidArgument);
+    }
+
+    context.raise_signal(process, signal);
+    on_other_cores(move |context| {
+        context.raise_signal(process, signal);
+    });
+
+    Ok(())
+}
+
+/// switches to the next thread that can run, the current one has either been parked or killed
+pub fn run_next_thread(context: &mut SchedulerCpuContext) -> ! {
+    let id = context.next_or_idle().expect("No idle thread").id;
+
+    // the queue ran dry, ask the busiest core for some of its threads
+    if context.is_idle(id) {
+        request_work(context);
+    }
+
+    let thread = context.thread_map.get_mut(&id).expect("Corrupted metadata");
+    thread.time_left = DEFAULT_TICKS_PER_THREAD;
+    resume_thread(thread)
+}
+
+/// for faults from user mode that nothing can fix, the faulting instruction is never retried
+pub fn fault_current_thread(signal: Signal) -> ! {
+    let context = &mut get_per_cpu_data_mut!().scheduler_context;
+
+    if let Some(id) = context.current_thread.take() {
+        context.fault_thread(id, signal);
+    }
+
+    run_next_thread(context)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{sync::Arc, vec};
+    use core::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use x86_64::{PhysAddr, VirtAddr, registers::rflags::RFlags};
+
+    use super::{PendingSignals, SIGCHLD, SIGILL, SIGKILL, SIGSEGV, SignalAction, default_action};
+    use crate::{
+        arch::x86_64::{
+            handlers::isr::{
+                DIVIDE_ERROR_VECTOR, INVALID_OPCODE_VECTOR, exception_signal, is_user_mode,
+            },
+            memory::pcid::{CR3_NO_FLUSH, PcidAllocator},
+            scheduler::{
+                GPRegisterState, PrivilageLevel, ProcessId, SchedulerCpuContext, State,
+                THREAD_ID_COUNTER, Thread, ThreadId, ThreadState, on_other_cores,
+                process::{PROCESSES, spawn_process},
+                run_queue::Priority,
+            },
+        },
+        end_test,
+        terminal::test::block_on,
+        test_name,
+    };
+
+    fn thread(process: ProcessId, privilage_level: PrivilageLevel) -> Thread {
+        Thread {
+            id: ThreadId(0),
+            process,
+            state: ThreadState {
+                killed: false,
+                pending_signals: PendingSignals::default(),
+                registers: GPRegisterState::default(),
+                stack_pointer: VirtAddr::new(0),
+                thread_local_segment: VirtAddr::new(0),
+                page_table_pointer: PhysAddr::new(0),
+                fpu_registers: None,
+                state: State::Paused {
+                    instruction_pointer: 0,
+                    rflags: RFlags::empty(),
+                },
+                frames: vec![],
+            },
+            privilage_level,
+            time_left: Duration::ZERO,
+            cpu_affinity: None,
+            ticks_run: 0,
+            priority: Priority::DEFAULT,
+        }
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn signal_mask() {
+        test_name!("pending signal mask");
+
+        let mut pending = PendingSignals::default();
+        assert!(pending.is_empty());
+
+        pending.raise(SIGSEGV);
+        pending.raise(SIGKILL);
+        assert!(pending.is_pending(SIGSEGV));
+        assert!(!pending.is_pending(SIGCHLD));
+
+        assert_eq!(pending.take_next(), Some(SIGKILL));
+        assert_eq!(pending.take_next(), Some(SIGSEGV));
+        assert_eq!(pending.take_next(), None);
+
+        assert_eq!(default_action(SIGSEGV), SignalAction::Terminate);
+        assert_eq!(default_action(SIGCHLD), SignalAction::Ignore);
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn segfault_terminates_process() {
+        test_name!("a user fault kills the process and nothing else");
+
+        let mut context = SchedulerCpuContext::default();
+        context.spawn_thread(thread(ProcessId(0), PrivilageLevel::Kernel));
+
+        let process = spawn_process(None);
+        context.spawn_thread(thread(process, PrivilageLevel::User));
+        context.spawn_thread(thread(process, PrivilageLevel::User));
+
+        // the first user thread touched an address no vma covers
+        let kernel = context.next_runnable().unwrap().id;
+        context.requeue(kernel);
+        let faulting = *context.thread_queue.iter().next().unwrap();
+        context
+            .thread_map
+            .get_mut(&faulting)
+            .unwrap()
+            .state
+            .pending_signals
+            .raise(SIGSEGV);
+
+        assert_eq!(context.next_runnable().unwrap().id, kernel);
+
+        // the sibling is gone with it before it could run again
+        assert_eq!(context.next_runnable().map(|thread| thread.id), None);
+        assert!(
+            context
+                .thread_map
+                .values()
+                .all(|thread| thread.process != process)
+        );
+        assert!(!PROCESSES.lock().contains_key(&process));
+
+        // ignored signals don't hurt
+        assert!(context.raise_signal(ProcessId(0), SIGCHLD));
+        assert!(context.deliver_signals(kernel));
+        assert!(!context.raise_signal(process, SIGKILL));
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn invalid_opcode_terminates_process() {
+        test_name!("an invalid opcode in user mode kills only that process");
+
+        assert!(is_user_mode(0x23));
+        assert!(!is_user_mode(0x8));
+        assert_eq!(exception_signal(INVALID_OPCODE_VECTOR), Some(SIGILL));
+        assert_eq!(
+            exception_signal(DIVIDE_ERROR_VECTOR).map(default_action),
+            Some(SignalAction::Terminate)
+        );
+        assert_eq!(exception_signal(0x8), None);
+
+        let mut context = SchedulerCpuContext::default();
+        context.spawn_thread(thread(ProcessId(0), PrivilageLevel::Kernel));
+
+        let faulting = spawn_process(None);
+        let bystander = spawn_process(None);
+        context.spawn_thread(thread(faulting, PrivilageLevel::User));
+        context.spawn_thread(thread(bystander, PrivilageLevel::User));
+
+        // what the #UD handler does with the thread that was running
+        let kernel = context.next_runnable().unwrap().id;
+        let current = context.thread_queue.pop_front().unwrap();
+        assert!(!context.fault_thread(current, SIGILL));
+        context.requeue(kernel);
+
+        let survivor = context.next_runnable().unwrap();
+        assert_eq!(survivor.process, bystander);
+        assert_eq!(context.next_runnable().unwrap().id, kernel);
+
+        assert!(!PROCESSES.lock().contains_key(&faulting));
+        assert!(PROCESSES.lock().contains_key(&bystander));
+
+        context.terminate_process(bystander);
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn exit_forgets_the_page_table() {
+        test_name!("an exiting thread leaves neither its page table loaded nor its pcid taken");
+
+        let mut context = SchedulerCpuContext {
+            pcids: Some(PcidAllocator::default()),
+            ..Default::default()
+        };
+        let process = spawn_process(None);
+        let table = PhysAddr::new(0x1000);
+        context.spawn_thread(thread(process, PrivilageLevel::User));
+        context.spawn_thread(thread(process, PrivilageLevel::User));
+
+        let first = context.next_runnable().unwrap().id;
+        context.page_table_to_load(process, table);
+        context.exit_thread(first);
+        assert_eq!(context.loaded_page_table, None);
+        assert!(!context.thread_map.contains_key(&first));
+        // its sibling is still around and keeps the pcid
+        assert!(PROCESSES.lock().contains_key(&process));
+        assert_ne!(context.page_table_to_load(process, table) & CR3_NO_FLUSH, 0);
+
+        let second = context.next_runnable().unwrap().id;
+        context.exit_thread(second);
+        assert_eq!(context.loaded_page_table, None);
+        // the pcid is free, whoever gets the table next starts with a flush
+        assert_eq!(context.page_table_to_load(process, table) & CR3_NO_FLUSH, 0);
+        assert!(context.thread_map.is_empty());
+        assert!(!PROCESSES.lock().contains_key(&process));
+
+        end_test!();
+    }
+
+    /// how many threads of `process` the other cores hold between them
+    fn threads_on_other_cores(process: ProcessId) -> usize {
+        let count = Arc::new(AtomicUsize::new(0));
+        let handles = on_other_cores({
+            let count = count.clone();
+            move |context| {
+                let threads = context
+                    .thread_map
+                    .values()
+                    .filter(|thread| thread.process == process)
+                    .count();
+                count.fetch_add(threads, Ordering::AcqRel);
+            }
+        });
+
+        for handle in handles {
+            block_on(handle);
+        }
+
+        count.load(Ordering::Acquire)
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn terminate_reaches_every_core() {
+        test_name!("terminating a process drops its threads on every core");
+
+        let process = spawn_process(None);
+        let bystander = spawn_process(None);
+
+        // each other core parks a thread of both, out of its queue so none of them can run
+        let handles = on_other_cores(move |context| {
+            for owner in [process, bystander] {
+                let mut parked = thread(owner, PrivilageLevel::User);
+                parked.id = ThreadId(THREAD_ID_COUNTER.fetch_add(1, Ordering::AcqRel));
+                context.thread_map.insert(parked.id, parked);
+            }
+        });
+        let other_cores = handles.len();
+        for handle in handles {
+            block_on(handle);
+        }
+        assert_eq!(threads_on_other_cores(process), other_cores);
+
+        let mut context = SchedulerCpuContext::default();
+        context.spawn_thread(thread(process, PrivilageLevel::User));
+        context.spawn_thread(thread(bystander, PrivilageLevel::User));
+        context.terminate_process(process);
+
+        // terminate only returns once every core has dropped them
+        assert_eq!(threads_on_other_cores(process), 0);
+        assert_eq!(threads_on_other_cores(bystander), other_cores);
+        assert!(
+            context
+                .thread_map
+                .values()
+                .all(|thread| thread.process == bystander)
+        );
+        assert!(!PROCESSES.lock().contains_key(&process));
+
+        context.terminate_process(bystander);
+        assert_eq!(threads_on_other_cores(bystander), 0);
+        assert!(context.thread_map.is_empty());
+
+        end_test!();
+    }
+}
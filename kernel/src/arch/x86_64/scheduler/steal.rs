@@ -0,0 +1,270 @@
+use core::sync::atomic::Ordering;
+
+use alloc::vec::Vec;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::{
+    SPAWNER,
+    arch::x86_64::{
+        memory::per_cpu::{PER_CPU_DATA_PTRS, PerCPUData},
+        scheduler::{CpuCoreId, DEFAULT_TICKS_PER_THREAD, SchedulerCpuContext, Thread},
+    },
+    get_per_cpu_data_mut,
+};
+
+/// a core needs at least this many queued threads before others take any of them
+pub const STEAL_THRESHOLD: usize = 2;
+
+impl SchedulerCpuContext {
+    /// makes the queue length visible to the other cores, they never look at the queue itself
+    pub fn publish_load(&self) {
+        self.queue_len
+            .store(self.thread_queue.len(), Ordering::Relaxed);
+    }
+
+    /// gives up half of the queue from the tail, leaving out threads pinned elsewhere
+    pub fn steal_half(&mut self, thief: CpuCoreId) -> Vec<Thread> {
+        let mut stolen = Vec::new();
+
+        for _ in 0..self.thread_queue.len() / 2 {
+            let Some(mut thread) = self.take_migratable(thief) else {
+                break;
+            };
+
+            // the slice it had left was measured on this core
+            thread.time_left = DEFAULT_TICKS_PER_THREAD;
+            stolen.push(thread);
+        }
+
+        self.publish_load();
+        stolen
+    }
+
+    /// the core the thief should ask for work going by `loads`, None while an earlier request
+    /// is still out or if nobody has enough to give away. A request counts as out from here on
+    pub fn claim_victim(
+        &self,
+        loads: impl IntoIterator<Item = (CpuCoreId, usize)>,
+        thief: CpuCoreId,
+    ) -> Option<CpuCoreId> {
+        if self.steal_pending.swap(true, Ordering::AcqRel) {
+            return None;
+        }
+
+        let victim = busiest_core(loads, thief);
+        if victim.is_none() {
+            self.steal_pending.store(false, Ordering::Release);
+        }
+
+        victim
+    }
+
+    /// queues what the victim handed over, after this the core may ask again
+    pub fn adopt_stolen(&mut self, stolen: Vec<Thread>) {
+        for thread in stolen {
+            self.adopt_thread(thread);
+        }
+
+        self.publish_load();
+        self.steal_pending.store(false, Ordering::Release);
+    }
+}
+
+/// the core with the longest queue worth stealing from, never the thief itself
+pub fn busiest_core(
+    loads: impl IntoIterator<Item = (CpuCoreId, usize)>,
+    thief: CpuCoreId,
+) -> Option<CpuCoreId> {
+    loads
+        .into_iter()
+        .filter(|&(core, load)| core != thief && load >= STEAL_THRESHOLD)
+        .max_by_key(|&(_, load)| load)
+        .map(|(core, _)| core)
+}
+
+fn published_loads() -> impl Iterator<Item = (CpuCoreId, usize)> {
+    PER_CPU_DATA_PTRS
+        .get()
+        .expect("Failed to get per cpu data pointers")
+        .iter()
+        .map(|(&id, &ptr)| {
+            let data = unsafe { &*(ptr as *const PerCPUData) };
+            (
+                CpuCoreId(id),
+                data.scheduler_context.queue_len.load(Ordering::Relaxed),
+            )
+        })
+}
+
+/// called by an idle core. Only a core itself touches its queue, so the busiest core is asked
+/// to hand threads over and sends them back through a task spawned on the thief
+pub fn request_work(context: &SchedulerCpuContext) {
+    let thief = CpuCoreId::current();
+    let Some(victim) = context.claim_victim(published_loads(), thief) else {
+        return;
+    };
+
+    let spawner = SPAWNER.get().expect("Failed to get spawner");
+
+    spawner.spawn_on(victim.0, async move {
+        let stolen =
+            without_interrupts(|| get_per_cpu_data_mut!().scheduler_context.steal_half(thief));
+
+        SPAWNER
+            .get()
+            .expect("Failed to get spawner")
+            .spawn_on(thief.0, async move {
+                without_interrupts(|| {
+                    get_per_cpu_data_mut!()
+                        .scheduler_context
+                        .adopt_stolen(stolen)
+                });
+            });
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+    use core::sync::atomic::Ordering;
+    use x86_64::registers::rflags::RFlags;
+
+    use super::{STEAL_THRESHOLD, busiest_core};
+    use crate::{
+        arch::x86_64::scheduler::{
+            CpuCoreId, SchedulerCpuContext, idle_thread_entry_point, kernel_thread,
+            tests::idle_core,
+        },
+        end_test, test_name,
+    };
+
+    /// what `published_loads` would read off these cores
+    fn loads(cores: &[SchedulerCpuContext]) -> Vec<(CpuCoreId, usize)> {
+        cores
+            .iter()
+            .enumerate()
+            .map(|(idx, core)| {
+                core.publish_load();
+                (
+                    CpuCoreId::new(idx as u32),
+                    core.queue_len.load(Ordering::Relaxed),
+                )
+            })
+            .collect()
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn idle_core_steals_work() {
+        test_name!("an idle core steals threads from the busy one");
+
+        let (busy, idle) = (CpuCoreId::new(0), CpuCoreId::new(1));
+        let mut cores = [idle_core(), idle_core()];
+
+        for i in 0..6 {
+            let mut thread = kernel_thread(idle_thread_entry_point, 0, RFlags::empty());
+            // the first two have to stay where they are
+            if i < 2 {
+                thread.cpu_affinity = Some(busy);
+            }
+            cores[0].spawn_thread(thread);
+        }
+
+        assert_eq!(busiest_core(loads(&cores), idle), Some(busy));
+        assert_eq!(busiest_core([(busy, 1), (idle, 0)], idle), None);
+        assert_eq!(busiest_core([(busy, 6), (idle, 0)], busy), None);
+
+        // one request at a time
+        assert_eq!(cores[1].claim_victim(loads(&cores), idle), Some(busy));
+        assert_eq!(cores[1].claim_victim(loads(&cores), idle), None);
+
+        let stolen = cores[0].steal_half(idle);
+        assert_eq!(stolen.len(), 3);
+        assert!(stolen.iter().all(|thread| thread.may_run_on(idle)));
+        cores[1].adopt_stolen(stolen);
+
+        assert_eq!(cores[0].thread_queue.len(), 3);
+        assert_eq!(cores[1].thread_queue.len(), 3);
+        assert_eq!(cores[1].queue_len.load(Ordering::Relaxed), 3);
+        let ids: Vec<_> = cores[1].thread_queue.iter().collect();
+        assert!(ids.iter().all(|id| !cores[0].thread_map.contains_key(id)));
+
+        // pinned threads are never handed over, however long the queue
+        assert_eq!(cores[1].claim_victim(loads(&cores), idle), Some(busy));
+        let stolen = cores[0].steal_half(idle);
+        assert_eq!(stolen.len(), 1);
+        cores[1].adopt_stolen(stolen);
+        assert!(
+            cores[0]
+                .thread_map
+                .values()
+                .all(|thread| thread.cpu_affinity == Some(busy) || cores[0].is_idle(thread.id))
+        );
+
+        // asking again comes back empty handed, and the request isn't left hanging
+        assert_eq!(cores[1].claim_victim(loads(&cores), idle), Some(busy));
+        let stolen = cores[0].steal_half(idle);
+        assert!(stolen.is_empty());
+        cores[1].adopt_stolen(stolen);
+        assert!(!cores[1].steal_pending.load(Ordering::Acquire));
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn threads_spread_across_cores() {
+        test_name!("threads spawned on one core end up spread over all of them");
+
+        let mut cores: Vec<_> = (0..4).map(|_| idle_core()).collect();
+        for _ in 0..8 {
+            cores[0].spawn_thread(kernel_thread(idle_thread_entry_point, 0, RFlags::empty()));
+        }
+
+        // every core that runs dry goes through the steps of request_work, with the tasks it
+        // would spawn on the victim and back on itself run in place
+        loop {
+            let mut moved = false;
+
+            for thief in 0..cores.len() {
+                if !cores[thief].thread_queue.is_empty() {
+                    continue;
+                }
+
+                let id = CpuCoreId::new(thief as u32);
+                let Some(victim) = cores[thief].claim_victim(loads(&cores), id) else {
+                    continue;
+                };
+
+                let stolen = cores[victim.as_u32() as usize].steal_half(id);
+                moved |= !stolen.is_empty();
+                cores[thief].adopt_stolen(stolen);
+            }
+
+            if !moved {
+                break;
+            }
+        }
+
+        assert_eq!(
+            cores
+                .iter()
+                .map(|core| core.thread_queue.len())
+                .sum::<usize>(),
+            8
+        );
+        assert!(cores.iter().all(|core| !core.thread_queue.is_empty()));
+        assert!(
+            cores
+                .iter()
+                .all(|core| core.thread_queue.len() < 2 * STEAL_THRESHOLD)
+        );
+        assert!(
+            cores
+                .iter()
+                .all(|core| !core.steal_pending.load(Ordering::Acquire))
+        );
+
+        end_test!();
+    }
+}
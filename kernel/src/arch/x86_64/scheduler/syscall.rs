@@ -1,33 +1,108 @@
-use core::arch::global_asm;
+use core::{arch::global_asm, time::Duration};
+
+use alloc::{string::String, vec, vec::Vec};
 
 use crate::{
+    SPAWNER,
     arch::x86_64::{
-        acpi::apic::get_local_apic, memory::per_cpu::PER_CPU_DATA_PTRS,
-        scheduler::DEFAULT_TICKS_PER_THREAD,
+        acpi::apic::get_local_apic,
+        memory::{PAGE_SIZE, frame_allocator::DEALLOCATOR_SENDER, per_cpu::PER_CPU_DATA_PTRS},
+        scheduler::process,
+        timer::Instant,
+    },
+    ejcineque::time::sleep_until,
+    get_per_cpu_data, get_per_cpu_data_mut,
+    hal::{
+        buffer::Buffer,
+        fs::{OpenAccessMode, OpenFlags, OpenFlagsValue},
+        vfs::{vfs_close, vfs_read, vfs_write},
     },
-    get_per_cpu_data, get_per_cpu_data_mut, log,
+    log,
 };
 use x86_64::{
-    VirtAddr,
+    PhysAddr, VirtAddr,
+    instructions::interrupts::without_interrupts,
     registers::{
         control::{Efer, EferFlags},
         model_specific::Msr,
         rflags::RFlags,
     },
+    structures::paging::PageTableFlags,
 };
 
 use crate::arch::x86_64::{
     err::ErrNo,
-    scheduler::{PrivilageLevel, State, Thread},
+    scheduler::{
+        PrivilageLevel, ProcessId, State, Thread, forget_page_table,
+        process::{PROCESSES, Process},
+        signal::{self, Signal},
+        uaccess::{copy_from_user, copy_to_user},
+    },
 };
 
+/// read(fd, buf, len), a single call moves at most 1 MiB
+pub const READ_SYSCALL: u64 = 0;
+/// write(fd, buf, len), fd 1 and 2 go to the terminal, a single call moves at most 1 MiB
 pub const WRITE_SYSCALL: u64 = 1;
+/// open(path, path_len, access_mode, flags, perms), flags are `OpenFlagsValue` bits
+pub const OPEN_SYSCALL: u64 = 2;
+/// close(fd)
+pub const CLOSE_SYSCALL: u64 = 3;
+/// mmap(addr, len, prot, flags, fd, offset), only anonymous mappings exist so far and addr is
+/// ignored, returns the start of the mapping
+pub const MMAP_SYSCALL: u64 = 9;
+/// munmap(addr, len), the range has to cover whole mappings
+pub const MUNMAP_SYSCALL: u64 = 0xb;
+/// brk(addr), returns the new break or the current one if it can't move, brk(0) just asks
+pub const BRK_SYSCALL: u64 = 0xc;
+/// nanosleep(secs, nanos), both signed like the fields of a timespec. Negative values and nanos
+/// of a whole second or more are rejected, the thread isn't scheduled again before the deadline
+pub const NANOSLEEP_SYSCALL: u64 = 0x23;
+pub const GETPID_SYSCALL: u64 = 0x27;
+pub const GETCWD_SYSCALL: u64 = 0x4f;
+/// chdir(path, path_len), the path has to lead to an existing directory
+pub const CHDIR_SYSCALL: u64 = 0x50;
+/// exec(path, path_len, argv, envp), argv and envp are null terminated arrays of c strings
+/// only returns on failure
+pub const EXEC_SYSCALL: u64 = 0x3b;
+/// exit(code), ends the calling thread and the process with its last thread
 pub const KILL_SYSCALL: u64 = 0x3c;
+/// kill(pid, sig), every signal terminates the target for now except SIGCHLD which does nothing
+pub const SIGNAL_SYSCALL: u64 = 0x3e;
+/// getenv(name, name_len, buf, buf_len), returns the length of the value
+/// nothing is copied if the buffer is too small
+pub const GETENV_SYSCALL: u64 = 0x200;
+/// setenv(name, name_len, value, value_len, overwrite)
+pub const SETENV_SYSCALL: u64 = 0x201;
+/// unsetenv(name, name_len)
+pub const UNSETENV_SYSCALL: u64 = 0x202;
+/// sbrk(increment), returns the old break
+pub const SBRK_SYSCALL: u64 = 0x203;
+
+pub mod mmap_flags {
+    pub const PROT_READ: u64 = 0x1;
+    pub const PROT_WRITE: u64 = 0x2;
+    pub const PROT_EXEC: u64 = 0x4;
+    pub const MAP_PRIVATE: u64 = 0x2;
+    pub const MAP_ANONYMOUS: u64 = 0x20;
+}
+
+/// longer sleeps are cut short, the deadline in tsc ticks would overflow on fast clocks
+const MAX_SLEEP: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+/// limits for a single argv/envp string and for the number of strings
+const MAX_ARG_LEN: u64 = 4096;
+const MAX_ARG_COUNT: u64 = 1024;
+/// longer paths are refused before anything is copied
+const MAX_PATH_LEN: u64 = 4096;
+/// reads and writes are cut to this, the caller comes back for the rest like after any short
+/// transfer. Everything goes through a kernel buffer of that size
+const MAX_IO_LEN: u64 = 1 << 20;
 
 const KERNEL_GS_BASE_MSR: u32 = 0xC0000102;
 
 pub fn set_per_cpu_data_for_core() {
-    let id = (get_local_apic().read_id() >> 24) as u32 & 0xFF;
+    let id = get_local_apic().apic_id();
     log!("{:?} {:?}", PER_CPU_DATA_PTRS, id);
 
     let ptr = PER_CPU_DATA_PTRS
@@ -152,6 +227,8 @@ extern "C" fn syscall_handler(stack_frame: SyscallFrame) {
 
     let current_thread = &mut per_cpu_data.scheduler_context.current_thread;
     let current_thread = current_thread.take().expect("Corrupted thread context");
+    let mut exited = false;
+    let mut signalled = None;
 
     if let Some(ref mut thread) = per_cpu_data
         .scheduler_context
@@ -167,47 +244,544 @@ extern "C" fn syscall_handler(stack_frame: SyscallFrame) {
         thread.state.stack_pointer = VirtAddr::new(stack_frame.rsp);
 
         match stack_frame.rax {
-            WRITE_SYSCALL => {
-                let idx = per_cpu_data.scheduler_context.waiting_queue_idx;
-                per_cpu_data.scheduler_context.waiting_queue_idx += 1;
+            READ_SYSCALL | WRITE_SYSCALL | OPEN_SYSCALL | CLOSE_SYSCALL | BRK_SYSCALL
+            | SBRK_SYSCALL | MMAP_SYSCALL | MUNMAP_SYSCALL | GETPID_SYSCALL | CHDIR_SYSCALL => {
+                let process = thread.process;
+                let page_table = thread.state.page_table_pointer;
+                let idx = per_cpu_data.scheduler_context.wait_token();
 
-                // interrupt will be disabled during the handler so this spin will not take too long
                 per_cpu_data
                     .scheduler_context
                     .waiting_threads
                     .insert(idx, current_thread);
 
-                todo!();
+                SPAWNER.get().expect("Failed to get spawner").spawn_on(
+                    per_cpu_data.id as u32,
+                    finish_dispatch(
+                        idx,
+                        process,
+                        page_table,
+                        stack_frame.rax,
+                        SyscallArgs::from(&stack_frame),
+                    ),
+                );
             }
 
             KILL_SYSCALL => {
                 log!("Terminating thread: {:?}", current_thread);
+                exited = true;
+            }
+
+            SIGNAL_SYSCALL => {
+                thread.state.state = State::Ready;
+                signalled = Some((ProcessId(stack_frame.rdi as usize), stack_frame.rsi));
+
+                per_cpu_data.scheduler_context.requeue(current_thread);
+            }
+
+            NANOSLEEP_SYSCALL => {
+                match sleep_deadline(stack_frame.rdi, stack_frame.rsi, Instant::now()) {
+                    Ok(deadline) => {
+                        let idx = per_cpu_data.scheduler_context.wait_token();
+
+                        per_cpu_data
+                            .scheduler_context
+                            .waiting_threads
+                            .insert(idx, current_thread);
+
+                        SPAWNER
+                            .get()
+                            .expect("Failed to get spawner")
+                            .spawn_on(per_cpu_data.id as u32, finish_sleep(idx, deadline));
+                    }
+
+                    Err(err) => {
+                        thread.state.state = State::Ready;
+                        registers.rax = err as u64;
+
+                        per_cpu_data.scheduler_context.requeue(current_thread);
+                    }
+                }
+            }
+
+            EXEC_SYSCALL => match exec_args(thread.state.page_table_pointer, &stack_frame) {
+                Ok((path, argv, envp)) => {
+                    let process = thread.process;
+                    let idx = per_cpu_data.scheduler_context.wait_token();
+
+                    per_cpu_data
+                        .scheduler_context
+                        .waiting_threads
+                        .insert(idx, current_thread);
+
+                    SPAWNER.get().expect("Failed to get spawner").spawn_on(
+                        per_cpu_data.id as u32,
+                        finish_exec(idx, process, path, argv, envp),
+                    );
+                }
+
+                Err(err) => {
+                    thread.state.state = State::Ready;
+                    registers.rax = err as u64;
+
+                    per_cpu_data.scheduler_context.requeue(current_thread);
+                }
+            },
+
+            GETENV_SYSCALL | SETENV_SYSCALL | UNSETENV_SYSCALL | GETCWD_SYSCALL => {
+                thread.state.state = State::Ready;
+                registers.rax = match process_syscall(
+                    thread.process,
+                    thread.state.page_table_pointer,
+                    &stack_frame,
+                ) {
+                    Ok(res) => res,
+                    Err(err) => err as u64,
+                };
+
+                per_cpu_data.scheduler_context.requeue(current_thread);
             }
 
             _ => {
                 thread.state.state = State::Ready;
                 registers.rax = ErrNo::OperationNotSupported as u64;
 
-                per_cpu_data
-                    .scheduler_context
-                    .thread_queue
-                    .push_back(current_thread);
+                per_cpu_data.scheduler_context.requeue(current_thread);
             }
         }
     }
 
-    while let Some(thread_id) = per_cpu_data.scheduler_context.thread_queue.pop_front() {
-        if let Some(thread) = per_cpu_data
-            .scheduler_context
-            .thread_map
-            .get_mut(&thread_id)
-        {
-            thread.time_left = DEFAULT_TICKS_PER_THREAD;
-            resume_thread(thread);
+    if exited {
+        per_cpu_data.scheduler_context.exit_thread(current_thread);
+    }
+
+    if let Some((process, signal)) = signalled {
+        let context = &mut per_cpu_data.scheduler_context;
+        let res = match Signal::try_from(signal) {
+            Ok(signal) => signal::send_signal(context, process, signal),
+            Err(_) => Err(ErrNo::InvalidArgument),
+        };
+
+        // signalling its own process only takes effect once the thread comes up again
+        if let Some(thread) = context.thread_map.get_mut(&current_thread) {
+            thread.state.registers.rax = match res {
+                Ok(()) => 0,
+                Err(err) => err as u64,
+            };
+        }
+    }
+
+    signal::run_next_thread(&mut per_cpu_data.scheduler_context)
+}
+
+/// copies the string out first, nothing reads user memory while `PROCESSES` is locked
+fn user_str(page_table: PhysAddr, ptr: u64, len: u64) -> Result<String, ErrNo> {
+    if len > MAX_ARG_LEN {
+        return Err(ErrNo::ArgumentListTooLong);
+    }
+
+    let bytes = copy_from_user(page_table, ptr, len as usize)?;
+
+    String::from_utf8(bytes).map_err(|_| ErrNo::InvalidArgument)
+}
+
+/// copies `value` to the user buffer if it fits and returns its length either way
+fn copy_to_user_if_fits(
+    page_table: PhysAddr,
+    value: &[u8],
+    buf: u64,
+    buf_len: u64,
+) -> Result<u64, ErrNo> {
+    if value.len() as u64 <= buf_len {
+        copy_to_user(page_table, buf, value)?;
+    }
+
+    Ok(value.len() as u64)
+}
+
+/// reads a null terminated string a page at a time, nothing past the page the string ends on is
+/// touched
+fn user_cstr(page_table: PhysAddr, ptr: u64) -> Result<String, ErrNo> {
+    let mut bytes = Vec::new();
+
+    loop {
+        let addr = ptr
+            .checked_add(bytes.len() as u64)
+            .ok_or(ErrNo::BadAddress)?;
+        let chunk =
+            (PAGE_SIZE as u64 - addr % PAGE_SIZE as u64).min(MAX_ARG_LEN - bytes.len() as u64);
+        if chunk == 0 {
+            return Err(ErrNo::ArgumentListTooLong);
+        }
+
+        let chunk = copy_from_user(page_table, addr, chunk as usize)?;
+        match chunk.iter().position(|&byte| byte == 0) {
+            Some(end) => {
+                bytes.extend_from_slice(&chunk[..end]);
+                break;
+            }
+            None => bytes.extend_from_slice(&chunk),
         }
     }
 
-    panic!("KERNEL THREAD IS DEAD")
+    String::from_utf8(bytes).map_err(|_| ErrNo::InvalidArgument)
+}
+
+/// reads a null terminated array of c strings, a null array is treated as empty
+fn user_cstr_array(page_table: PhysAddr, ptr: u64) -> Result<Vec<String>, ErrNo> {
+    let mut strings = Vec::new();
+
+    if ptr == 0 {
+        return Ok(strings);
+    }
+
+    loop {
+        if strings.len() as u64 == MAX_ARG_COUNT {
+            return Err(ErrNo::ArgumentListTooLong);
+        }
+
+        let addr = ptr + strings.len() as u64 * size_of::<u64>() as u64;
+        let entry = copy_from_user(page_table, addr, size_of::<u64>())?;
+
+        match u64::from_ne_bytes(entry.try_into().map_err(|_| ErrNo::BadAddress)?) {
+            0 => break,
+            string => strings.push(user_cstr(page_table, string)?),
+        }
+    }
+
+    Ok(strings)
+}
+
+/// everything is copied out now, the program's memory is gone by the time exec needs it
+fn exec_args(
+    page_table: PhysAddr,
+    frame: &SyscallFrame,
+) -> Result<(String, Vec<String>, Vec<String>), ErrNo> {
+    let path = user_str(page_table, frame.rdi, frame.rsi)?;
+    let argv = user_cstr_array(page_table, frame.rdx)?;
+    let envp = user_cstr_array(page_table, frame.r10)?;
+
+    Ok((path, argv, envp))
+}
+
+/// puts a thread parked in `waiting_threads` back into the run queue of this core
+fn wake_waiting_thread(waiting_idx: usize, f: impl FnOnce(&mut Thread)) {
+    without_interrupts(|| {
+        let scheduler_context = &mut get_per_cpu_data_mut!().scheduler_context;
+
+        let Some(thread) = scheduler_context
+            .waiting_threads
+            .get(&waiting_idx)
+            .and_then(|id| scheduler_context.thread_map.get_mut(id))
+        else {
+            scheduler_context.waiting_threads.remove(&waiting_idx);
+            return;
+        };
+
+        f(thread);
+
+        scheduler_context.wake(waiting_idx);
+    });
+}
+
+/// spawned on the core the thread is parked on so it can go straight back into that run queue
+async fn finish_exec(
+    waiting_idx: usize,
+    process: ProcessId,
+    path: String,
+    argv: Vec<String>,
+    envp: Vec<String>,
+) {
+    let (res, old_space) = match process::exec(process, &path, &argv, &envp).await {
+        Ok((state, old_space)) => (Ok(state), Some(old_space)),
+        Err(err) => (Err(err), None),
+    };
+
+    let mut old_state = None;
+    wake_waiting_thread(waiting_idx, |thread| match res {
+        Ok(mut state) => {
+            // pending signals survive exec
+            state.pending_signals = thread.state.pending_signals;
+            old_state = Some(core::mem::replace(&mut thread.state, state));
+        }
+
+        Err(err) => {
+            thread.state.registers.rax = err as u64;
+            thread.state.state = State::Ready;
+        }
+    });
+
+    // the old image is freed only once no core can reach it, its page table frame might come
+    // back as a new one
+    if let Some(old_state) = old_state {
+        forget_page_table(process, old_state.page_table_pointer);
+
+        DEALLOCATOR_SENDER
+            .get()
+            .expect("Failed to get deallocator sender")
+            .send(old_state.frames);
+    }
+    drop(old_space);
+}
+
+async fn finish_dispatch(
+    waiting_idx: usize,
+    process: ProcessId,
+    page_table: PhysAddr,
+    syscall_no: u64,
+    args: SyscallArgs,
+) {
+    let res = dispatch(process, page_table, syscall_no, args).await;
+
+    wake_waiting_thread(waiting_idx, |thread| {
+        thread.state.registers.rax = res as u64;
+        thread.state.state = State::Ready;
+    });
+}
+
+/// when a nanosleep that starts at `now` is over
+fn sleep_deadline(secs: u64, nanos: u64, now: Instant) -> Result<Instant, ErrNo> {
+    let (secs, nanos) = (secs as i64, nanos as i64);
+    if secs < 0 || !(0..1_000_000_000).contains(&nanos) {
+        return Err(ErrNo::InvalidArgument);
+    }
+
+    Ok(now + Duration::new(secs as u64, nanos as u32).min(MAX_SLEEP))
+}
+
+/// the timer wheel wakes this on the core the thread sleeps on
+async fn finish_sleep(waiting_idx: usize, deadline: Instant) {
+    sleep_until(deadline).await;
+
+    wake_waiting_thread(waiting_idx, |thread| {
+        thread.state.registers.rax = 0;
+        thread.state.state = State::Ready;
+    });
+}
+
+/// the argument registers in the order of the syscall abi
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SyscallArgs {
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub r10: u64,
+    pub r8: u64,
+    pub r9: u64,
+}
+
+impl From<&SyscallFrame> for SyscallArgs {
+    fn from(frame: &SyscallFrame) -> Self {
+        Self {
+            rdi: frame.rdi,
+            rsi: frame.rsi,
+            rdx: frame.rdx,
+            r10: frame.r10,
+            r8: frame.r8,
+            r9: frame.r9,
+        }
+    }
+}
+
+/// runs the syscalls that can wait on the vfs, user memory is reached through `page_table` since
+/// the caller's table is long gone by the time this is polled. Returns what goes into rax,
+/// negative errnos on failure
+pub async fn dispatch(
+    process: ProcessId,
+    page_table: PhysAddr,
+    syscall_no: u64,
+    args: SyscallArgs,
+) -> i64 {
+    let res = match syscall_no {
+        READ_SYSCALL => sys_read(process, page_table, args).await,
+        WRITE_SYSCALL => sys_write(process, page_table, args).await,
+        OPEN_SYSCALL => sys_open(process, page_table, args).await,
+        CLOSE_SYSCALL => vfs_close(args.rdi as i64).await,
+        BRK_SYSCALL => sys_brk(process, page_table, args),
+        SBRK_SYSCALL => {
+            process::sbrk(process, page_table, args.rdi as i64).map(|old_break| old_break as i64)
+        }
+        MMAP_SYSCALL => sys_mmap(process, args),
+        MUNMAP_SYSCALL => process::munmap(process, page_table, args.rdi, args.rsi).map(|_| 0),
+        GETPID_SYSCALL => Ok(process.0 as i64),
+        CHDIR_SYSCALL => sys_chdir(process, page_table, args).await,
+        _ => Err(ErrNo::OperationNotSupported),
+    };
+
+    res.unwrap_or_else(|err| err as i64)
+}
+
+/// faults the path in and copies it out, its length is checked before either happens
+async fn user_path(
+    process: ProcessId,
+    page_table: PhysAddr,
+    ptr: u64,
+    len: u64,
+) -> Result<String, ErrNo> {
+    if len > MAX_PATH_LEN {
+        return Err(ErrNo::NameTooLong);
+    }
+
+    process::fault_in(process, page_table, ptr, len as usize).await?;
+    let path = copy_from_user(page_table, ptr, len as usize)?;
+
+    String::from_utf8(path).map_err(|_| ErrNo::InvalidArgument)
+}
+
+/// reads into a kernel buffer first, the user pages aren't reachable through the hhdm as one slice
+async fn sys_read(
+    process: ProcessId,
+    page_table: PhysAddr,
+    args: SyscallArgs,
+) -> Result<i64, ErrNo> {
+    let (fd, ptr, len) = (args.rdi as i64, args.rsi, args.rdx.min(MAX_IO_LEN));
+    process::fault_in(process, page_table, ptr, len as usize).await?;
+
+    let buf = vec![0u8; len as usize].into_boxed_slice();
+    let bytes_read = vfs_read(fd, Buffer::from(&buf[..])).await?;
+
+    copy_to_user(page_table, ptr, &buf[..bytes_read as usize])?;
+
+    Ok(bytes_read)
+}
+
+async fn sys_write(
+    process: ProcessId,
+    page_table: PhysAddr,
+    args: SyscallArgs,
+) -> Result<i64, ErrNo> {
+    let len = args.rdx.min(MAX_IO_LEN) as usize;
+    process::fault_in(process, page_table, args.rsi, len).await?;
+    let buf = copy_from_user(page_table, args.rsi, len)?;
+
+    vfs_write(args.rdi as i64, Buffer::from(&buf[..])).await
+}
+
+async fn sys_open(
+    process: ProcessId,
+    page_table: PhysAddr,
+    args: SyscallArgs,
+) -> Result<i64, ErrNo> {
+    let path = user_path(process, page_table, args.rdi, args.rsi).await?;
+
+    let access_mode = match args.rdx {
+        0 => OpenAccessMode::ReadOnly,
+        1 => OpenAccessMode::WriteOnly,
+        2 => OpenAccessMode::ReadNWrite,
+        3 => OpenAccessMode::Search,
+        4 => OpenAccessMode::ExecuteOnly,
+        _ => return Err(ErrNo::InvalidArgument),
+    };
+
+    let flags = OpenFlags {
+        access_mode,
+        flags: args.r10 as i32,
+        perms: (args.r10 as i32 & OpenFlagsValue::CreateIfNotExist as i32 != 0)
+            .then_some(args.r8 as i32),
+    };
+
+    process::open(process, &path, flags).await
+}
+
+async fn sys_chdir(
+    process: ProcessId,
+    page_table: PhysAddr,
+    args: SyscallArgs,
+) -> Result<i64, ErrNo> {
+    let path = user_path(process, page_table, args.rdi, args.rsi).await?;
+
+    process::chdir(process, &path).await.map(|_| 0)
+}
+
+/// a break that can't be set leaves the old one in place, that's how the caller finds out
+fn sys_brk(process: ProcessId, page_table: PhysAddr, args: SyscallArgs) -> Result<i64, ErrNo> {
+    let current = process::program_break(process)?;
+
+    if args.rdi == 0 {
+        return Ok(current as i64);
+    }
+
+    Ok(process::set_program_break(process, page_table, args.rdi).unwrap_or(current) as i64)
+}
+
+/// pages are always readable once they're present, so PROT_READ doesn't change anything
+fn sys_mmap(process: ProcessId, args: SyscallArgs) -> Result<i64, ErrNo> {
+    let (len, prot, flags) = (args.rsi, args.rdx, args.r10);
+
+    // there's nothing to back file mappings with yet
+    if flags & mmap_flags::MAP_ANONYMOUS == 0 {
+        return Err(ErrNo::OperationNotSupported);
+    }
+
+    let mut page_flags = PageTableFlags::empty();
+    if prot & mmap_flags::PROT_WRITE != 0 {
+        page_flags |= PageTableFlags::WRITABLE;
+    }
+    if prot & mmap_flags::PROT_EXEC == 0 {
+        page_flags |= PageTableFlags::NO_EXECUTE;
+    }
+
+    process::mmap_anonymous(process, len, page_flags).map(|addr| addr as i64)
+}
+
+/// runs `f` with `PROCESSES` locked, user memory stays out of it
+fn with_process<R>(
+    process: ProcessId,
+    f: impl FnOnce(&mut Process) -> Result<R, ErrNo>,
+) -> Result<R, ErrNo> {
+    let mut processes = PROCESSES.lock();
+    let process = processes
+        .get_mut(&process)
+        .ok_or(ErrNo::OperationNotSupported)?;
+
+    f(process)
+}
+
+/// user memory is copied in before the process is looked up and copied out after
+fn process_syscall(
+    process: ProcessId,
+    page_table: PhysAddr,
+    frame: &SyscallFrame,
+) -> Result<u64, ErrNo> {
+    let (syscall, rdi, rsi, rdx, r10, r8) = (
+        frame.rax, frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8,
+    );
+
+    match syscall {
+        // getcwd(buf, buf_len)
+        GETCWD_SYSCALL => {
+            let cwd = with_process(process, |process| {
+                Ok(String::from(process.cwd.path.as_str()))
+            })?;
+
+            copy_to_user_if_fits(page_table, cwd.as_bytes(), rdi, rsi)
+        }
+
+        GETENV_SYSCALL => {
+            let name = user_str(page_table, rdi, rsi)?;
+            let value = with_process(process, |process| {
+                let value = process.env.get(&name).ok_or(ErrNo::NoSuchFileOrDirectory)?;
+                Ok(String::from(value))
+            })?;
+
+            copy_to_user_if_fits(page_table, value.as_bytes(), rdx, r10)
+        }
+
+        SETENV_SYSCALL => {
+            let name = user_str(page_table, rdi, rsi)?;
+            let value = user_str(page_table, rdx, r10)?;
+            with_process(process, |process| {
+                process.env.set(&name, &value, r8 != 0).map(|_| 0)
+            })
+        }
+
+        UNSETENV_SYSCALL => {
+            let name = user_str(page_table, rdi, rsi)?;
+            with_process(process, |process| process.env.unset(&name).map(|_| 0))
+        }
+
+        _ => Err(ErrNo::OperationNotSupported),
+    }
 }
 
 pub fn resume_thread(thread: &Thread) -> ! {
@@ -243,7 +817,6 @@ pub fn resume_thread(thread: &Thread) -> ! {
                 },
             };
 
-            let page_table_pointer = thread.state.page_table_pointer.as_u64();
             const TRUE: u64 = 1;
             const FALSE: u64 = 0;
             let is_kernel = if thread.privilage_level == PrivilageLevel::Kernel {
@@ -258,6 +831,10 @@ pub fn resume_thread(thread: &Thread) -> ! {
 
             let per_cpu_data = get_per_cpu_data_mut!();
             per_cpu_data.scheduler_context.current_thread = Some(thread.id);
+            let page_table_pointer = per_cpu_data
+                .scheduler_context
+                .page_table_to_load(thread.process, thread.state.page_table_pointer);
+            per_cpu_data.scheduler_context.prepare_fpu(thread.id);
 
             get_local_apic().write_eoi(0);
 
@@ -277,14 +854,16 @@ pub fn resume_thread(thread: &Thread) -> ! {
             set_registers!(syscall_frame, registers);
             syscall_frame.rsp = thread.state.stack_pointer.as_u64();
 
-            let page_table_pointer = thread.state.page_table_pointer.as_u64();
-
             unsafe {
                 Msr::new(IA32_FS_BASE).write(thread.state.thread_local_segment.as_u64());
             }
 
             let per_cpu_data = get_per_cpu_data_mut!();
             per_cpu_data.scheduler_context.current_thread = Some(thread.id);
+            let page_table_pointer = per_cpu_data
+                .scheduler_context
+                .page_table_to_load(thread.process, thread.state.page_table_pointer);
+            per_cpu_data.scheduler_context.prepare_fpu(thread.id);
 
             unsafe {
                 resume_thread_from_syscall(
@@ -312,3 +891,290 @@ unsafe extern "C" {
 }
 
 global_asm!(include_str!("./syscall_no_comment.s"));
+
+#[cfg(test)]
+mod tests {
+    use core::{
+        pin::pin,
+        sync::atomic::Ordering,
+        task::{Context, Waker},
+    };
+    use x86_64::{
+        PhysAddr, instructions::interrupts::without_interrupts, registers::rflags::RFlags,
+    };
+
+    use super::{
+        CHDIR_SYSCALL, GETPID_SYSCALL, MAX_ARG_LEN, MAX_IO_LEN, MAX_PATH_LEN, MAX_SLEEP,
+        OPEN_SYSCALL, READ_SYSCALL, SyscallArgs, SyscallFrame, WRITE_SYSCALL, dispatch, exec_args,
+        finish_sleep, sleep_deadline,
+    };
+    use crate::{
+        arch::x86_64::{
+            err::ErrNo,
+            memory::{PAGE_SIZE, get_hhdm_offset, page_table::create_page_table},
+            scheduler::{
+                CpuCoreId, ProcessId, State, THREAD_ID_COUNTER, ThreadId, idle_thread_entry_point,
+                kernel_thread,
+                process::{
+                    HEAP_START, fault_in, remove_process, sbrk, set_program_break, spawn_process,
+                },
+                uaccess::copy_to_user,
+            },
+            timer::Instant,
+        },
+        end_test, get_per_cpu_data_mut,
+        hal::vfs::{STDIN_FD, STDOUT_FD},
+        terminal::test::block_on,
+        test_name,
+    };
+
+    /// a process whose first `pages` heap pages are mapped in a page table of its own, the
+    /// kernel's table is left alone
+    fn process_with_heap(pages: u64) -> (ProcessId, PhysAddr) {
+        let process = spawn_process(None);
+        let page_table =
+            PhysAddr::new(block_on(create_page_table()).as_u64() - get_hhdm_offset().as_u64());
+        let len = pages * PAGE_SIZE as u64;
+
+        sbrk(process, page_table, len as i64).unwrap();
+        block_on(fault_in(process, page_table, HEAP_START, len as usize)).unwrap();
+
+        (process, page_table)
+    }
+
+    /// unmaps the heap again and drops the process
+    fn remove_process_with_heap(process: ProcessId, page_table: PhysAddr) {
+        set_program_break(process, page_table, HEAP_START).unwrap();
+        remove_process(process);
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn dispatch_write_and_getpid() {
+        test_name!("dispatch write to stdout and getpid");
+
+        let (process, page_table) = process_with_heap(1);
+
+        let msg = b"hello from dispatch\n";
+        copy_to_user(page_table, HEAP_START, msg).unwrap();
+
+        let args = SyscallArgs {
+            rdi: STDOUT_FD as u64,
+            rsi: HEAP_START,
+            rdx: msg.len() as u64,
+            ..Default::default()
+        };
+        assert_eq!(
+            block_on(dispatch(process, page_table, WRITE_SYSCALL, args)),
+            msg.len() as i64
+        );
+
+        // kernel memory is never user memory
+        let args = SyscallArgs {
+            rsi: msg.as_ptr() as u64,
+            ..args
+        };
+        assert_eq!(
+            block_on(dispatch(process, page_table, WRITE_SYSCALL, args)),
+            ErrNo::BadAddress as i64
+        );
+
+        assert_eq!(
+            block_on(dispatch(
+                process,
+                page_table,
+                GETPID_SYSCALL,
+                SyscallArgs::default()
+            )),
+            process.0 as i64
+        );
+
+        assert_eq!(
+            block_on(dispatch(
+                process,
+                page_table,
+                0x1337,
+                SyscallArgs::default()
+            )),
+            ErrNo::OperationNotSupported as i64
+        );
+
+        remove_process_with_heap(process, page_table);
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn user_lengths_are_capped() {
+        test_name!("lengths from user space are capped before anything is allocated");
+
+        // the heap covers the capped length but nowhere near what's asked for
+        let (process, page_table) = process_with_heap(MAX_IO_LEN / PAGE_SIZE as u64);
+
+        let args = SyscallArgs {
+            rdi: STDIN_FD as u64,
+            rsi: HEAP_START,
+            rdx: u64::MAX,
+            ..Default::default()
+        };
+        assert_eq!(
+            block_on(dispatch(process, page_table, READ_SYSCALL, args)),
+            0
+        );
+
+        let args = SyscallArgs {
+            rdi: HEAP_START,
+            rsi: MAX_PATH_LEN + 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            block_on(dispatch(process, page_table, OPEN_SYSCALL, args)),
+            ErrNo::NameTooLong as i64
+        );
+        assert_eq!(
+            block_on(dispatch(process, page_table, CHDIR_SYSCALL, args)),
+            ErrNo::NameTooLong as i64
+        );
+
+        remove_process_with_heap(process, page_table);
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn exec_copies_argv_and_envp() {
+        test_name!("exec copies its path, argv and envp out of user memory");
+
+        let (process, page_table) = process_with_heap(3);
+        let page = PAGE_SIZE as u64;
+
+        let pointers = |ptrs: &[u64]| -> alloc::vec::Vec<u8> {
+            ptrs.iter().flat_map(|ptr| ptr.to_ne_bytes()).collect()
+        };
+        let (argv, envp) = (HEAP_START + 256, HEAP_START + 512);
+        copy_to_user(page_table, HEAP_START, b"/bin/echo\0").unwrap();
+        copy_to_user(page_table, HEAP_START + 16, b"hello\0").unwrap();
+        copy_to_user(page_table, HEAP_START + 32, b"PATH=/bin\0").unwrap();
+        // read a page at a time, this one has to be put back together
+        copy_to_user(page_table, HEAP_START + page - 3, b"split\0").unwrap();
+        copy_to_user(
+            page_table,
+            argv,
+            &pointers(&[HEAP_START, HEAP_START + 16, HEAP_START + page - 3, 0]),
+        )
+        .unwrap();
+        copy_to_user(page_table, envp, &pointers(&[HEAP_START + 32, 0])).unwrap();
+
+        let mut frame = SyscallFrame {
+            rdi: HEAP_START,
+            rsi: 9,
+            rdx: argv,
+            r10: envp,
+            ..Default::default()
+        };
+        let (path, args, env) = exec_args(page_table, &frame).unwrap();
+        assert_eq!(path, "/bin/echo");
+        assert_eq!(args, ["/bin/echo", "hello", "split"]);
+        assert_eq!(env, ["PATH=/bin"]);
+
+        // a null envp is empty
+        frame.r10 = 0;
+        assert!(exec_args(page_table, &frame).unwrap().2.is_empty());
+
+        // a string without its terminator in reach
+        let long = HEAP_START + 2 * page;
+        copy_to_user(page_table, long, &[b'a'; MAX_ARG_LEN as usize]).unwrap();
+        copy_to_user(page_table, envp, &pointers(&[long, 0])).unwrap();
+        frame.r10 = envp;
+        assert_eq!(
+            exec_args(page_table, &frame),
+            Err(ErrNo::ArgumentListTooLong)
+        );
+
+        // pointers into memory that isn't mapped
+        copy_to_user(page_table, envp, &pointers(&[HEAP_START + 4 * page, 0])).unwrap();
+        assert_eq!(exec_args(page_table, &frame), Err(ErrNo::BadAddress));
+        frame.rdx = HEAP_START + 4 * page;
+        assert_eq!(exec_args(page_table, &frame), Err(ErrNo::BadAddress));
+
+        remove_process_with_heap(process, page_table);
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn nanosleep_waits_for_its_deadline() {
+        test_name!("a sleeping thread isn't scheduled before its deadline");
+
+        let now = Instant::now();
+        assert_eq!(
+            sleep_deadline(-1i64 as u64, 0, now),
+            Err(ErrNo::InvalidArgument)
+        );
+        assert_eq!(
+            sleep_deadline(0, -1i64 as u64, now),
+            Err(ErrNo::InvalidArgument)
+        );
+        assert_eq!(
+            sleep_deadline(0, 1_000_000_000, now),
+            Err(ErrNo::InvalidArgument)
+        );
+        assert_eq!(sleep_deadline(0, 0, now), Ok(now));
+        assert_eq!(sleep_deadline(u64::MAX >> 1, 0, now), Ok(now + MAX_SLEEP));
+
+        // a thread parked on this core the way the nanosleep syscall parks it
+        let deadline = sleep_deadline(0, 5_000_000, Instant::now()).unwrap();
+        let (sleeper, token) = without_interrupts(|| {
+            let context = &mut get_per_cpu_data_mut!().scheduler_context;
+            let mut thread = kernel_thread(idle_thread_entry_point, 0, RFlags::empty());
+            thread.id = ThreadId(THREAD_ID_COUNTER.fetch_add(1, Ordering::AcqRel));
+            thread.state.registers.rax = u64::MAX;
+            let id = thread.id;
+            context.thread_map.insert(id, thread);
+
+            let token = context.wait_token();
+            context.waiting_threads.insert(token, id);
+            (id, token)
+        });
+
+        // polled by hand with interrupts off, so the woken thread is taken back out before the
+        // scheduler could pick it
+        let mut sleep = pin!(finish_sleep(token, deadline));
+        let mut cx = Context::from_waker(Waker::noop());
+        let woken = loop {
+            let woken = without_interrupts(|| {
+                let polled_at = Instant::now();
+                let done = sleep.as_mut().poll(&mut cx).is_ready();
+                let context = &mut get_per_cpu_data_mut!().scheduler_context;
+
+                if !done {
+                    assert!(polled_at < deadline);
+                    assert_eq!(context.waiting_threads.get(&token), Some(&sleeper));
+                    assert!(context.thread_queue.iter().all(|&id| id != sleeper));
+                    return None;
+                }
+
+                assert!(Instant::now() >= deadline);
+                assert!(!context.waiting_threads.contains_key(&token));
+                Some(
+                    context
+                        .detach_thread(sleeper, CpuCoreId::current())
+                        .expect("The sleeper wasn't queued again"),
+                )
+            });
+
+            if let Some(thread) = woken {
+                break thread;
+            }
+        };
+
+        assert_eq!(woken.id, sleeper);
+        assert_eq!(woken.state.registers.rax, 0);
+        assert_eq!(woken.state.state, State::Ready);
+
+        end_test!();
+    }
+}
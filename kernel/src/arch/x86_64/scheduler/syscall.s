@@ -27,10 +27,14 @@ syscall_handler_wrapper:
     call syscall_handler          ; call the handler 
 
 ; rdi = stack frame with above layout
-; rsi = page table
+; rsi = page table, 0 to keep the one in cr3 and with it the tlb
 resume_thread_from_syscall:
-    mov rax, rsi
-    mov cr3, rax
+    test rsi, rsi
+    jz .syscall_keep_cr3
+
+    mov cr3, rsi
+
+    .syscall_keep_cr3:
 
     mov r15, qword ptr [rdi + 0]
     mov r14, qword ptr [rdi + 0x8]
@@ -53,12 +57,16 @@ resume_thread_from_syscall:
     sysretq
 
 ; rdi = stack frame with above layout
-; rsi = page table
+; rsi = page table, 0 to keep the one in cr3 and with it the tlb
 ; rdx = long return frame defined in syscall.rs
 ; rcx = 1 if is kernel, = 0 if is user space
 resume_paused_thread:
-    mov rax, rsi
-    mov cr3, rax
+    test rsi, rsi
+    jz .paused_keep_cr3
+
+    mov cr3, rsi
+
+    .paused_keep_cr3:
 
     cmp rcx, 0
     jne .resume
@@ -23,8 +23,10 @@ syscall_handler_wrapper:
     mov rdi, rsp                  
     call syscall_handler          
 resume_thread_from_syscall:
-    mov rax, rsi
-    mov cr3, rax
+    test rsi, rsi
+    jz .syscall_keep_cr3
+    mov cr3, rsi
+    .syscall_keep_cr3:
     mov r15, qword ptr [rdi + 0]
     mov r14, qword ptr [rdi + 0x8]
     mov r13, qword ptr [rdi + 0x10]
@@ -44,8 +46,10 @@ resume_thread_from_syscall:
     swapgs
     sysretq
 resume_paused_thread:
-    mov rax, rsi
-    mov cr3, rax
+    test rsi, rsi
+    jz .paused_keep_cr3
+    mov cr3, rsi
+    .paused_keep_cr3:
     cmp rcx, 0
     jne .resume
     swapgs
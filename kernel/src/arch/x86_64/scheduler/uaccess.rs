@@ -0,0 +1,97 @@
+use alloc::{vec, vec::Vec};
+use x86_64::{
+    PhysAddr, VirtAddr,
+    structures::paging::{
+        OffsetPageTable, PageTable, PageTableFlags, Translate, mapper::TranslateResult,
+    },
+};
+
+use crate::arch::x86_64::{
+    err::ErrNo,
+    memory::{PAGE_SIZE, get_hhdm_offset},
+};
+
+/// user pointers have to stay below the kernel half
+pub const USER_SPACE_END: u64 = 0x0000_8000_0000_0000;
+
+pub fn check_user_range(ptr: u64, len: u64) -> Result<(), ErrNo> {
+    match ptr.checked_add(len) {
+        Some(end) if ptr != 0 && end <= USER_SPACE_END => Ok(()),
+        _ => Err(ErrNo::BadAddress),
+    }
+}
+
+/// reaches a page table through the hhdm, whether or not it's the loaded one
+pub fn page_table_at(page_table: PhysAddr) -> OffsetPageTable<'static> {
+    let hhdm = get_hhdm_offset();
+    let table = unsafe { &mut *(hhdm + page_table.as_u64()).as_mut_ptr::<PageTable>() };
+
+    unsafe { OffsetPageTable::new(table, hhdm) }
+}
+
+/// walks `len` bytes of user memory starting at `addr` a page at a time, handing out the hhdm
+/// address of every piece. Works whether or not `page_table` is the loaded one, async syscalls
+/// finish long after the caller's table was switched out
+fn for_each_user_chunk(
+    page_table: PhysAddr,
+    addr: u64,
+    len: usize,
+    writable: bool,
+    mut f: impl FnMut(*mut u8, core::ops::Range<usize>),
+) -> Result<(), ErrNo> {
+    check_user_range(addr, len as u64)?;
+
+    let hhdm = get_hhdm_offset();
+    let table = page_table_at(page_table);
+
+    let mut done = 0;
+
+    while done < len {
+        let virt = VirtAddr::new(addr + done as u64);
+
+        let TranslateResult::Mapped {
+            frame,
+            offset,
+            flags,
+        } = table.translate(virt)
+        else {
+            return Err(ErrNo::BadAddress);
+        };
+
+        if !flags.contains(PageTableFlags::USER_ACCESSIBLE)
+            || (writable && !flags.contains(PageTableFlags::WRITABLE))
+        {
+            return Err(ErrNo::BadAddress);
+        }
+
+        let phys = frame.start_address() + offset;
+        let chunk = (PAGE_SIZE as usize - u64::from(virt.page_offset()) as usize).min(len - done);
+
+        f((hhdm + phys.as_u64()).as_mut_ptr(), done..done + chunk);
+        done += chunk;
+    }
+
+    Ok(())
+}
+
+/// copies user memory into the kernel, fails without a partial result if any page is missing
+pub fn copy_from_user(page_table: PhysAddr, addr: u64, len: usize) -> Result<Vec<u8>, ErrNo> {
+    let mut bytes = vec![0u8; len];
+
+    for_each_user_chunk(page_table, addr, len, false, |ptr, range| {
+        let len = range.len();
+        bytes[range].copy_from_slice(unsafe { core::slice::from_raw_parts(ptr, len) });
+    })?;
+
+    Ok(bytes)
+}
+
+/// the whole range is checked before anything is written
+pub fn copy_to_user(page_table: PhysAddr, addr: u64, bytes: &[u8]) -> Result<(), ErrNo> {
+    for_each_user_chunk(page_table, addr, bytes.len(), true, |_, _| {})?;
+
+    for_each_user_chunk(page_table, addr, bytes.len(), true, |ptr, range| {
+        let len = range.len();
+        unsafe { core::slice::from_raw_parts_mut(ptr, len) }.copy_from_slice(&bytes[range]);
+    })
+}
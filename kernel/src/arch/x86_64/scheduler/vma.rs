@@ -0,0 +1,390 @@
+use alloc::{collections::btree_map::BTreeMap, vec::Vec};
+use core::ops::Range;
+use x86_64::{
+    PhysAddr, VirtAddr,
+    structures::paging::{Mapper, Page, PageTableFlags, PhysFrame, Size4KiB},
+};
+
+use crate::arch::x86_64::{
+    err::ErrNo,
+    memory::{
+        PAGE_SIZE,
+        frame_allocator::{BitmapAllocator, DEALLOCATOR_SENDER, FRAME_ALLOCATOR},
+    },
+    scheduler::uaccess::page_table_at,
+};
+
+/// where the pages of an area come from when they are first touched
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaBacking {
+    /// zero filled frames
+    Anonymous,
+}
+
+/// a page aligned region of a user address space
+#[derive(Debug)]
+pub struct Vma {
+    pub range: Range<u64>,
+    /// the flags every page of the area is mapped with, on top of present and user accessible
+    pub prot: PageTableFlags,
+    pub backing: VmaBacking,
+    /// the pages faulted in so far keyed by their address
+    frames: BTreeMap<u64, PhysFrame>,
+}
+
+impl Vma {
+    pub fn new(range: Range<u64>, prot: PageTableFlags, backing: VmaBacking) -> Self {
+        Self {
+            range,
+            prot,
+            backing,
+            frames: BTreeMap::new(),
+        }
+    }
+
+    pub fn contains(&self, addr: u64) -> bool {
+        self.range.contains(&addr)
+    }
+
+    pub fn is_resident(&self, addr: u64) -> bool {
+        self.frames.contains_key(&page_start(addr))
+    }
+
+    pub fn resident_pages(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// cuts the area in two at the page boundary `at`, self keeps the part below it
+    pub fn split_off(&mut self, at: u64) -> Vma {
+        debug_assert!(self.contains(at) && at % PAGE_SIZE as u64 == 0);
+
+        let upper = Vma {
+            range: at..self.range.end,
+            prot: self.prot,
+            backing: self.backing,
+            frames: self.frames.split_off(&at),
+        };
+        self.range.end = at;
+
+        upper
+    }
+
+    /// unmaps the pages at or above `addr` and hands back their frames, the range itself is left
+    /// alone. Other cores may still have them cached, they're only freed with [`release_frames`]
+    /// once [`forget_page_table`](super::forget_page_table) returns
+    #[must_use]
+    pub fn release_from(&mut self, page_table: PhysAddr, addr: u64) -> Vec<PhysFrame> {
+        let released = self
+            .frames
+            .split_off(&page_start(addr.next_multiple_of(PAGE_SIZE as u64)));
+
+        if released.is_empty() {
+            return Vec::new();
+        }
+
+        let mut table = page_table_at(page_table);
+        for &page in released.keys() {
+            if let Ok((_, flush)) =
+                table.unmap(Page::<Size4KiB>::containing_address(VirtAddr::new(page)))
+            {
+                flush.flush();
+            }
+        }
+
+        released.into_values().collect()
+    }
+}
+
+/// the frames are still mapped, an area is only dropped along with its page table or after
+/// `release_from`
+impl Drop for Vma {
+    fn drop(&mut self) {
+        release_frames(core::mem::take(&mut self.frames).into_values().collect());
+    }
+}
+
+fn page_start(addr: u64) -> u64 {
+    addr & !(PAGE_SIZE as u64 - 1)
+}
+
+/// goes through the deallocator task once it's up, nothing here may wait on the allocator
+pub fn release_frames(frames: Vec<PhysFrame>) {
+    if frames.is_empty() {
+        return;
+    }
+
+    match DEALLOCATOR_SENDER.get() {
+        Some(sender) => sender.send(frames),
+        None => {
+            if let Some(mut allocator) = FRAME_ALLOCATOR.get().and_then(|a| a.try_lock()) {
+                allocator.free_frames(&frames);
+            }
+        }
+    }
+}
+
+/// the areas of one user address space
+#[derive(Debug, Default)]
+pub struct AddressSpace {
+    pub vmas: Vec<Vma>,
+    /// page table frames created while faulting pages in, they live as long as the page table
+    table_frames: Vec<PhysFrame>,
+}
+
+impl AddressSpace {
+    pub fn find(&self, addr: u64) -> Option<&Vma> {
+        self.vmas.iter().find(|vma| vma.contains(addr))
+    }
+
+    pub fn find_mut(&mut self, addr: u64) -> Option<&mut Vma> {
+        self.vmas.iter_mut().find(|vma| vma.contains(addr))
+    }
+
+    /// where the first area starting past `addr` begins
+    pub fn next_area_after(&self, addr: u64) -> Option<u64> {
+        self.vmas
+            .iter()
+            .map(|vma| vma.range.start)
+            .filter(|&start| start > addr)
+            .min()
+    }
+
+    pub fn overlaps(&self, range: &Range<u64>) -> bool {
+        self.vmas
+            .iter()
+            .any(|vma| vma.range.start < range.end && range.start < vma.range.end)
+    }
+
+    /// keeps the areas sorted by their start, fails if `vma` overlaps one of them
+    pub fn insert(&mut self, vma: Vma) -> Result<(), ErrNo> {
+        if vma.range.is_empty() || self.overlaps(&vma.range) {
+            return Err(ErrNo::InvalidArgument);
+        }
+
+        let idx = self
+            .vmas
+            .partition_point(|other| other.range.start < vma.range.start);
+        self.vmas.insert(idx, vma);
+
+        Ok(())
+    }
+
+    /// the lowest gap of `len` bytes inside `bounds`
+    pub fn find_gap(&self, len: u64, bounds: Range<u64>) -> Option<u64> {
+        let mut start = bounds.start;
+
+        for vma in self.vmas.iter().filter(|vma| vma.range.end > bounds.start) {
+            if vma.range.start >= start.checked_add(len)? {
+                break;
+            }
+
+            start = start.max(vma.range.end);
+        }
+
+        (start.checked_add(len)? <= bounds.end).then_some(start)
+    }
+
+    /// unmaps everything in the page aligned `range`, areas sticking out of it on either side are
+    /// cut down to what lies outside. The frames that were mapped come back like with
+    /// [`Vma::release_from`]
+    pub fn remove_range(
+        &mut self,
+        page_table: PhysAddr,
+        range: Range<u64>,
+    ) -> Result<Vec<PhysFrame>, ErrNo> {
+        if range.start % PAGE_SIZE as u64 != 0 || range.end % PAGE_SIZE as u64 != 0 {
+            return Err(ErrNo::InvalidArgument);
+        }
+
+        let mut released = Vec::new();
+        let mut idx = 0;
+        while idx < self.vmas.len() {
+            let vma = &mut self.vmas[idx];
+            if vma.range.end <= range.start || range.end <= vma.range.start {
+                idx += 1;
+                continue;
+            }
+
+            let tail = (vma.range.end > range.end).then(|| vma.split_off(range.end));
+
+            if vma.range.start < range.start {
+                released.extend(vma.release_from(page_table, range.start));
+                vma.range.end = range.start;
+                idx += 1;
+            } else {
+                let mut removed = self.vmas.remove(idx);
+                released.extend(removed.release_from(page_table, removed.range.start));
+            }
+
+            if let Some(tail) = tail {
+                self.vmas.insert(idx, tail);
+                idx += 1;
+            }
+        }
+
+        Ok(released)
+    }
+
+    /// backs the page holding `addr` with a frame, fails if no area covers it
+    pub fn fault_in(
+        &mut self,
+        page_table: PhysAddr,
+        addr: u64,
+        allocator: &mut BitmapAllocator,
+    ) -> Result<(), ErrNo> {
+        let page = page_start(addr);
+        let vma = self
+            .vmas
+            .iter_mut()
+            .find(|vma| vma.contains(addr))
+            .ok_or(ErrNo::BadAddress)?;
+
+        // the page is there already so the access itself isn't allowed
+        if vma.frames.contains_key(&page) {
+            return Err(ErrNo::BadAddress);
+        }
+
+        let frame = match vma.backing {
+            VmaBacking::Anonymous => allocator.allocate_zeroed_frame(),
+        }
+        .ok_or(ErrNo::OutOfMemory)?;
+
+        let mut table = page_table_at(page_table);
+        let res = unsafe {
+            table.map_to(
+                Page::<Size4KiB>::containing_address(VirtAddr::new(page)),
+                frame,
+                vma.prot | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE,
+                allocator,
+                &mut Some(&mut self.table_frames),
+            )
+        };
+
+        match res {
+            Ok(flush) => flush.flush(),
+            Err(_) => {
+                allocator.free_frames(&[frame]);
+                return Err(ErrNo::OutOfMemory);
+            }
+        }
+
+        vma.frames.insert(page, frame);
+
+        Ok(())
+    }
+}
+
+/// every core has to have moved off the page table and dropped what it cached for it before
+/// this, see [`Shootdown`](super::shootdown::Shootdown)
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        release_frames(core::mem::take(&mut self.table_frames));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+    use x86_64::{PhysAddr, structures::paging::PageTableFlags};
+
+    use super::{AddressSpace, Vma, VmaBacking};
+    use crate::{
+        arch::x86_64::{err::ErrNo, memory::PAGE_SIZE},
+        end_test, test_name,
+    };
+
+    const PAGE: u64 = PAGE_SIZE as u64;
+
+    fn anon(start: u64, pages: u64) -> Vma {
+        Vma::new(
+            start * PAGE..(start + pages) * PAGE,
+            PageTableFlags::WRITABLE,
+            VmaBacking::Anonymous,
+        )
+    }
+
+    fn ranges(space: &AddressSpace) -> Vec<(u64, u64)> {
+        space
+            .vmas
+            .iter()
+            .map(|vma| (vma.range.start / PAGE, vma.range.end / PAGE))
+            .collect()
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn vma_insert_and_lookup() {
+        test_name!("vma insertion and lookup");
+
+        let mut space = AddressSpace::default();
+        space.insert(anon(10, 5)).unwrap();
+        // adjacent areas on both sides are fine and stay sorted
+        space.insert(anon(15, 1)).unwrap();
+        space.insert(anon(2, 8)).unwrap();
+        assert_eq!(ranges(&space), [(2, 10), (10, 15), (15, 16)]);
+
+        // anything sharing a page with an existing area isn't
+        assert_eq!(space.insert(anon(14, 2)), Err(ErrNo::InvalidArgument));
+        assert_eq!(space.insert(anon(0, 3)), Err(ErrNo::InvalidArgument));
+        assert_eq!(space.insert(anon(11, 1)), Err(ErrNo::InvalidArgument));
+        assert_eq!(space.insert(anon(20, 0)), Err(ErrNo::InvalidArgument));
+        assert_eq!(space.vmas.len(), 3);
+
+        // the start belongs to an area, the end doesn't
+        assert_eq!(space.find(10 * PAGE).unwrap().range.start, 10 * PAGE);
+        assert_eq!(space.find(10 * PAGE - 1).unwrap().range.start, 2 * PAGE);
+        assert_eq!(space.find(16 * PAGE - 1).unwrap().range.start, 15 * PAGE);
+        assert!(space.find(16 * PAGE).is_none());
+        assert!(space.find(PAGE).is_none());
+
+        assert_eq!(space.find_gap(PAGE, 0..32 * PAGE), Some(0));
+        assert_eq!(space.find_gap(3 * PAGE, 0..32 * PAGE), Some(16 * PAGE));
+        assert_eq!(space.find_gap(20 * PAGE, 0..32 * PAGE), None);
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn vma_partial_unmap() {
+        test_name!("vma splitting on partial unmap");
+
+        // nothing is resident, so the page table is never touched
+        let page_table = PhysAddr::zero();
+        let mut space = AddressSpace::default();
+        space.insert(anon(0, 10)).unwrap();
+        space.insert(anon(20, 4)).unwrap();
+
+        // a hole in the middle leaves two areas with the same flags
+        space.remove_range(page_table, 4 * PAGE..6 * PAGE).unwrap();
+        assert_eq!(ranges(&space), [(0, 4), (6, 10), (20, 24)]);
+        assert!(
+            space
+                .vmas
+                .iter()
+                .all(|vma| vma.prot == PageTableFlags::WRITABLE)
+        );
+
+        // trimming either end
+        space.remove_range(page_table, 0..PAGE).unwrap();
+        space.remove_range(page_table, 9 * PAGE..21 * PAGE).unwrap();
+        assert_eq!(ranges(&space), [(1, 4), (6, 9), (21, 24)]);
+
+        // one range can take out several areas at once
+        space.remove_range(page_table, 2 * PAGE..22 * PAGE).unwrap();
+        assert_eq!(ranges(&space), [(1, 2), (22, 24)]);
+        assert!(space.find(7 * PAGE).is_none());
+
+        // unmapping nothing is fine, unaligned ranges aren't
+        space
+            .remove_range(page_table, 30 * PAGE..40 * PAGE)
+            .unwrap();
+        assert_eq!(
+            space.remove_range(page_table, PAGE + 1..2 * PAGE),
+            Err(ErrNo::InvalidArgument)
+        );
+        assert_eq!(space.vmas.len(), 2);
+
+        end_test!();
+    }
+}
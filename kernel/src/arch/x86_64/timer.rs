@@ -1,5 +1,5 @@
 use core::{
-    ops::Sub,
+    ops::{Add, Sub},
     sync::atomic::{AtomicBool, AtomicU32, AtomicU64},
     time::Duration,
 };
@@ -71,6 +71,8 @@ pub fn read_pit_count() -> u16 {
 }
 
 pub const TIMER_PERIODIC_MODE: u32 = 0x20000;
+/// the apic timer is loaded with a millisecond worth of ticks
+pub const TIMER_INTERVAL: Duration = Duration::from_millis(1);
 
 impl LocalApic {
     pub fn load_timer(&mut self, frequency: u32) {
@@ -188,6 +190,10 @@ pub fn calibrate_tsc() {
 pub struct Instant(u64);
 
 impl Instant {
+    pub fn from_ticks(ticks: u64) -> Self {
+        Self(ticks)
+    }
+
     pub fn now() -> Self {
         let ticks = (unsafe { core::arch::x86_64::_rdtsc() } as i64
             + get_per_cpu_data!().tsc_offset as i64) as u64;
@@ -217,6 +223,17 @@ impl Instant {
             boot_time_ms + self.0 / ticks_per_millis
         }
     }
+
+    /// time since the tsc started counting, zero before the tsc is calibrated
+    pub fn since_boot(&self) -> Duration {
+        let ticks_per_millis = TSC_TIMER_TICKS_PER_MS.load(core::sync::atomic::Ordering::Relaxed);
+
+        if ticks_per_millis == 0 {
+            Duration::ZERO
+        } else {
+            Duration::from_millis(self.0 / ticks_per_millis)
+        }
+    }
 }
 
 macro_rules! nanos_per_tick {
@@ -239,6 +256,24 @@ impl Sub<Instant> for Instant {
     }
 }
 
+/// how many tsc ticks `duration` lasts, rounded up so nothing waits for less than asked
+pub fn duration_to_ticks(duration: Duration, ticks_per_millis: u64) -> u64 {
+    (duration.as_nanos() * ticks_per_millis as u128).div_ceil(MILLISECOND_TO_NANO_SECOND) as u64
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Self::Output {
+        let ticks_per_millis = TSC_TIMER_TICKS_PER_MS.load(core::sync::atomic::Ordering::Relaxed);
+
+        Instant(
+            self.0
+                .saturating_add(duration_to_ticks(rhs, ticks_per_millis)),
+        )
+    }
+}
+
 pub fn blocking_sleep(time: Duration) {
     let instant = Instant::now();
 
@@ -1,3 +1,5 @@
+use core::str::FromStr;
+
 use crate::{crypto::guid::Guid, log};
 use limine::request::ExecutableCmdlineRequest;
 
@@ -9,6 +11,8 @@ pub static EXECUTABLE_CMDLINE_REQUEST: ExecutableCmdlineRequest = ExecutableCmdl
 pub struct ArgsRes {
     pub root_drive: Guid,
     pub root_entry: Guid,
+    /// time pio against dma on the first pata drive
+    pub pata_bench: bool,
 }
 
 pub fn parse_args() -> ArgsRes {
@@ -30,6 +34,7 @@ pub fn parse_args() -> ArgsRes {
             "root_partition_guid" => {
                 res.root_entry = Guid::from_str(val).unwrap_or(Guid::default())
             }
+            "pata_bench" => res.pata_bench = val == "true",
             _ => {}
         }
     }
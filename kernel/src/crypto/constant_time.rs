@@ -0,0 +1,55 @@
+use core::hint::black_box;
+
+/// ors together the xor of every byte pair, nothing about the position of a difference leaks
+/// since the loop always runs to the end
+fn accumulate_diff(a: &[u8], b: &[u8]) -> u8 {
+    a.iter()
+        .zip(b.iter())
+        .fold(0u8, |diff, (x, y)| black_box(diff | (x ^ y)))
+}
+
+/// compares the contents in time that only depends on the length, the lengths themselves aren't
+/// treated as secret
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    black_box(accumulate_diff(a, b)) == 0
+}
+
+/// a if choice is set, b otherwise, without a branch on choice
+pub fn ct_select(choice: bool, a: u8, b: u8) -> u8 {
+    let mask = (black_box(choice) as u8).wrapping_neg();
+    (a & mask) | (b & !mask)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{accumulate_diff, constant_time_eq, ct_select};
+    use crate::{end_test, test_name};
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn constant_time_comparison() {
+        test_name!("constant time comparison");
+
+        assert!(constant_time_eq(b"", b""));
+        assert!(constant_time_eq(b"EFI PART", b"EFI PART"));
+        assert!(!constant_time_eq(b"EFI PART", b"EFI PARt"));
+        assert!(!constant_time_eq(b"EFI PART", b"EFI PAR"));
+
+        // a mismatch in the first byte doesn't stop the last one from being looked at
+        let a = [0u8; 32];
+        let mut b = [0u8; 32];
+        b[0] = 0x01;
+        b[31] = 0x80;
+        assert_eq!(accumulate_diff(&a, &b), 0x81);
+        assert!(!constant_time_eq(&a, &b));
+
+        assert_eq!(ct_select(true, 0xAA, 0x55), 0xAA);
+        assert_eq!(ct_select(false, 0xAA, 0x55), 0x55);
+
+        end_test!();
+    }
+}
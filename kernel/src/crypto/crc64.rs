@@ -0,0 +1,80 @@
+/// CRC-64 over the ECMA-182 polynomial in the reflected form xz uses, the register starts as
+/// all ones and is inverted at the end just like crc32
+pub const CRC64_ECMA_POLY: u64 = 0xC96C5795D7870F42;
+pub const CRC64_INIT: u64 = 0xFFFFFFFFFFFFFFFF;
+
+static TABLE: [u64; 256] = make_table();
+
+const fn make_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ CRC64_ECMA_POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// feeds more data into a running crc, start it at CRC64_INIT and pass it to finish_crc once
+/// everything is in
+pub fn partial_crc(crc: &mut u64, data: &[u8]) {
+    for byte in data.iter() {
+        *crc = (*crc >> 8) ^ TABLE[((*crc ^ *byte as u64) & 0xFF) as usize];
+    }
+}
+
+pub fn finish_crc(crc: u64) -> u64 {
+    crc ^ CRC64_INIT
+}
+
+pub fn full_crc(data: &[u8]) -> u64 {
+    let mut crc = CRC64_INIT;
+    partial_crc(&mut crc, data);
+    finish_crc(crc)
+}
+
+pub fn is_verified_crc64(arr: &[u8], crc64: u64) -> bool {
+    full_crc(arr) == crc64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{end_test, test_name};
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn crc64_ecma() {
+        test_name!("CRC64 ECMA test vectors");
+
+        assert_eq!(full_crc(b""), 0);
+        assert_eq!(full_crc(b"a"), 0x330284772E652B05);
+        assert_eq!(full_crc(b"123456789"), 0x995DC9BBDF1939FA);
+        assert_eq!(
+            full_crc(b"The quick brown fox jumps over the lazy dog"),
+            0x5B5EB8C2E54AA1C4
+        );
+
+        // the same data split up across calls gives the same crc
+        let mut crc = CRC64_INIT;
+        partial_crc(&mut crc, b"1234");
+        partial_crc(&mut crc, b"");
+        partial_crc(&mut crc, b"56789");
+        assert_eq!(finish_crc(crc), 0x995DC9BBDF1939FA);
+
+        assert!(is_verified_crc64(b"123456789", 0x995DC9BBDF1939FA));
+        assert!(!is_verified_crc64(b"123456780", 0x995DC9BBDF1939FA));
+
+        end_test!();
+    }
+}
@@ -1,6 +1,6 @@
-use core::fmt;
+use core::{fmt, str::FromStr};
 
-use alloc::{format, string::String};
+use thiserror::Error;
 
 #[derive(PartialEq, Eq, Clone, Copy, Default, PartialOrd)]
 pub struct Guid {
@@ -13,6 +13,14 @@ pub struct Guid {
     pub data4: [u8; 8], // u16 & u48
 }
 
+#[derive(Debug, Error, PartialEq, Eq, Clone, Copy)]
+pub enum GuidParseErr {
+    #[error("Expected groups of 8-4-4-4-12 hex digits")]
+    BadFormat,
+    #[error("Invalid hex digit: {0}")]
+    BadDigit(char),
+}
+
 impl Ord for Guid {
     fn cmp(&self, other: &Self) -> core::cmp::Ordering {
         self.whole.cmp(&other.whole)
@@ -21,62 +29,90 @@ impl Ord for Guid {
 
 impl fmt::Debug for Guid {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{} whole: 0x{:x}", self.to_string(), self.whole)
+        fmt::Display::fmt(self, f)
     }
 }
 
+/// the canonical `xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx` form, the first three groups are stored
+/// in little endian on disk but are printed as numbers, the last two are printed byte by byte
 impl fmt::Display for Guid {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.to_string())
+        write!(
+            f,
+            "{:08x}-{:04x}-{:04x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+            self.data1,
+            self.data2,
+            self.data3,
+            self.data4[0],
+            self.data4[1],
+            self.data4[2],
+            self.data4[3],
+            self.data4[4],
+            self.data4[5],
+            self.data4[6],
+            self.data4[7]
+        )
     }
 }
 
-impl Guid {
-    pub fn from_str(val: &str) -> Option<Self> {
-        let mut parts = val.splitn(5, '-');
-
-        let data1 = u32::from_str_radix(parts.next()?, 16).ok()? as u128;
-        let data2 = u16::from_str_radix(parts.next()?, 16).ok()? as u128;
-        let data3 = u16::from_str_radix(parts.next()?, 16).ok()? as u128;
-
-        let data4_first = u64::from_str_radix(parts.next()?, 16).ok()?;
-        let data4_second = u64::from_str_radix(parts.next()?, 16).ok()?;
-        let data4 = data4_first << 48 | data4_second;
-
-        let data4: [u8; 8] = data4.to_be_bytes();
-
-        let data1_raw: [u8; 4] = (data1 as u32).to_le_bytes();
-        let data2_raw: [u8; 2] = (data2 as u16).to_le_bytes();
-        let data3_raw: [u8; 2] = (data3 as u16).to_le_bytes();
-
-        let whole = u128::from_le_bytes([
-            data1_raw[0],
-            data1_raw[1],
-            data1_raw[2],
-            data1_raw[3],
-            data2_raw[0],
-            data2_raw[1],
-            data3_raw[0],
-            data3_raw[1],
-            data4[0],
-            data4[1],
-            data4[2],
-            data4[3],
-            data4[4],
-            data4[5],
-            data4[6],
-            data4[7],
-        ]);
-
-        Some(Self {
-            whole,
-            data1: data1 as u32,
-            data2: data2 as u16,
-            data3: data3 as u16,
-            data4,
-        })
+/// parses a canonical hyphenated string into `N` bytes in the order the digits appear, the group
+/// lengths are given in hex digits
+pub(crate) fn parse_hyphenated<const N: usize>(
+    val: &str,
+    groups: &[usize],
+) -> Result<[u8; N], GuidParseErr> {
+    let mut res = [0u8; N];
+    let mut idx = 0;
+    let mut parts = val.split('-');
+
+    for &len in groups {
+        let part = parts.next().ok_or(GuidParseErr::BadFormat)?;
+        if part.len() != len {
+            return Err(GuidParseErr::BadFormat);
+        }
+
+        for pair in part.as_bytes().chunks(2) {
+            let mut byte = 0u8;
+            for &c in pair {
+                let digit = (c as char)
+                    .to_digit(16)
+                    .ok_or(GuidParseErr::BadDigit(c as char))?;
+                byte = byte << 4 | digit as u8;
+            }
+
+            res[idx] = byte;
+            idx += 1;
+        }
+    }
+
+    if parts.next().is_some() || idx != N {
+        return Err(GuidParseErr::BadFormat);
+    }
+
+    Ok(res)
+}
+
+impl FromStr for Guid {
+    type Err = GuidParseErr;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        let raw: [u8; 16] = parse_hyphenated(val, &[8, 4, 4, 4, 12])?;
+
+        let data1 = u32::from_be_bytes([raw[0], raw[1], raw[2], raw[3]]);
+        let data2 = u16::from_be_bytes([raw[4], raw[5]]);
+        let data3 = u16::from_be_bytes([raw[6], raw[7]]);
+
+        let mut bytes = [0u8; 16];
+        bytes[0..4].copy_from_slice(&data1.to_le_bytes());
+        bytes[4..6].copy_from_slice(&data2.to_le_bytes());
+        bytes[6..8].copy_from_slice(&data3.to_le_bytes());
+        bytes[8..16].copy_from_slice(&raw[8..16]);
+
+        Ok(Self::from_bytes(bytes))
     }
+}
 
+impl Guid {
     pub fn from_bytes(val: [u8; 16]) -> Self {
         let data1 = u32::from_le_bytes([val[0], val[1], val[2], val[3]]);
         let data2 = u16::from_le_bytes([val[4], val[5]]);
@@ -95,20 +131,62 @@ impl Guid {
         }
     }
 
-    pub fn to_string(&self) -> String {
-        format!(
-            "{:08x}-{:04x}-{:04x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
-            self.data1,
-            self.data2,
-            self.data3,
-            self.data4[0],
-            self.data4[1],
-            self.data4[2],
-            self.data4[3],
-            self.data4[4],
-            self.data4[5],
-            self.data4[6],
-            self.data4[7]
-        )
+    /// the on-disk (mixed endian) layout used by GPT, inverse of [`Guid::from_bytes`]
+    pub fn to_bytes(&self) -> [u8; 16] {
+        self.whole.to_le_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::str::FromStr;
+
+    use alloc::string::ToString;
+
+    use super::*;
+    use crate::{end_test, test_name};
+
+    // the EFI system partition type guid
+    const ESP_STR: &str = "c12a7328-f81f-11d2-ba4b-00a0c93ec93b";
+    const ESP_BYTES: [u8; 16] = [
+        0x28, 0x73, 0x2a, 0xc1, 0x1f, 0xf8, 0xd2, 0x11, 0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e, 0xc9,
+        0x3b,
+    ];
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn guid_format() {
+        test_name!("guid canonical formatting");
+
+        let guid = Guid::from_bytes(ESP_BYTES);
+        assert_eq!(guid.to_string(), ESP_STR);
+        assert_eq!(alloc::format!("{:?}", guid), ESP_STR);
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn guid_parse() {
+        test_name!("guid parsing round trip");
+
+        let guid = Guid::from_str(ESP_STR).expect("Failed to parse guid");
+        assert_eq!(guid.to_bytes(), ESP_BYTES);
+        assert_eq!(guid, Guid::from_bytes(ESP_BYTES));
+        assert_eq!(
+            Guid::from_str("C12A7328-F81F-11D2-BA4B-00A0C93EC93B"),
+            Ok(guid)
+        );
+
+        assert_eq!(
+            Guid::from_str("c12a7328-f81f-11d2-ba4b00a0c93ec93b"),
+            Err(GuidParseErr::BadFormat)
+        );
+        assert_eq!(
+            Guid::from_str("c12a7328-f81f-11d2-ba4b-00a0c93ec93g"),
+            Err(GuidParseErr::BadDigit('g'))
+        );
+
+        end_test!();
     }
 }
@@ -0,0 +1,143 @@
+use crate::crypto::{
+    constant_time_eq,
+    sha256::{BLOCK_SIZE, DIGEST_SIZE, Sha256, sha256},
+};
+
+const IPAD: u8 = 0x36;
+const OPAD: u8 = 0x5c;
+/// the shortest truncated mac `verify` takes, half the output as rfc 2104 recommends. Anything
+/// shorter would let a guess of a few bytes through
+pub const MIN_TRUNCATED_LEN: usize = DIGEST_SIZE / 2;
+
+/// RFC 2104 HMAC over SHA-256
+#[derive(Debug, Clone)]
+pub struct HmacSha256 {
+    inner: Sha256,
+    /// the key xored with opad, kept for the outer hash
+    outer_key: [u8; BLOCK_SIZE],
+}
+
+impl HmacSha256 {
+    /// keys longer than a block are hashed first, shorter ones are padded with zeros
+    pub fn new(key: &[u8]) -> Self {
+        let mut block_key = [0u8; BLOCK_SIZE];
+        if key.len() > BLOCK_SIZE {
+            block_key[..DIGEST_SIZE].copy_from_slice(&sha256(key));
+        } else {
+            block_key[..key.len()].copy_from_slice(key);
+        }
+
+        let inner_key = block_key.map(|b| b ^ IPAD);
+        let outer_key = block_key.map(|b| b ^ OPAD);
+
+        let mut inner = Sha256::new();
+        inner.update(&inner_key);
+
+        Self { inner, outer_key }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.inner.update(data);
+    }
+
+    pub fn finalize(self) -> [u8; DIGEST_SIZE] {
+        let inner_digest = self.inner.finalize();
+
+        let mut outer = Sha256::new();
+        outer.update(&self.outer_key);
+        outer.update(&inner_digest);
+        outer.finalize()
+    }
+
+    /// compares in constant time, a shorter expected value checks a truncated mac as long as it
+    /// keeps at least [`MIN_TRUNCATED_LEN`] bytes
+    pub fn verify(self, expected: &[u8]) -> bool {
+        if !(MIN_TRUNCATED_LEN..=DIGEST_SIZE).contains(&expected.len()) {
+            return false;
+        }
+
+        let mac = self.finalize();
+        constant_time_eq(&mac[..expected.len()], expected)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HmacSha256, MIN_TRUNCATED_LEN};
+    use crate::{crypto::sha256::from_hex, end_test, test_name};
+
+    fn mac(key: &[u8], data: &[u8]) -> [u8; 32] {
+        let mut hmac = HmacSha256::new(key);
+        hmac.update(data);
+        hmac.finalize()
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn hmac_rfc4231() {
+        test_name!("hmac-sha256 rfc 4231 vectors");
+
+        // test case 1
+        assert_eq!(
+            mac(&[0x0b; 20], b"Hi There"),
+            from_hex("b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7")
+        );
+        // test case 2, a key shorter than the output
+        assert_eq!(
+            mac(b"Jefe", b"what do ya want for nothing?"),
+            from_hex("5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843")
+        );
+        // test case 3
+        assert_eq!(
+            mac(&[0xaa; 20], &[0xdd; 50]),
+            from_hex("773ea91e36800e46854db8ebd09181a72959098b3ef8c122d9635514ced565fe")
+        );
+        // test case 4
+        let key: [u8; 25] = core::array::from_fn(|i| i as u8 + 1);
+        assert_eq!(
+            mac(&key, &[0xcd; 50]),
+            from_hex("82558a389a443c0ea4cc819899f2083a85f0faa3e578f8077a2e3ff46729665b")
+        );
+        // test case 6, a key longer than a block
+        assert_eq!(
+            mac(
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            ),
+            from_hex("60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54")
+        );
+        // test case 7
+        assert_eq!(
+            mac(
+                &[0xaa; 131],
+                b"This is a test using a larger than block-size key and a larger than block-size data. The key needs to be hashed before being used by the HMAC algorithm."
+            ),
+            from_hex("9b09ffa71b942fcb27635fbcd5b0e944bfdc63644f0713938a7f51535c3a35e2")
+        );
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn hmac_verify() {
+        test_name!("hmac-sha256 verify");
+
+        // test case 5 only specifies the first 128 bits
+        let truncated: [u8; 16] = from_hex("a3b6167473100ee06e0c796c2955552b");
+        let mut hmac = HmacSha256::new(&[0x0c; 20]);
+        hmac.update(b"Test With Truncation");
+        assert!(hmac.clone().verify(&truncated));
+
+        let mut wrong = truncated;
+        wrong[15] ^= 1;
+        assert!(!hmac.clone().verify(&wrong));
+        assert!(!hmac.clone().verify(&[]));
+
+        // a correct prefix that's too short to mean anything
+        assert!(!hmac.clone().verify(&truncated[..1]));
+        assert!(!hmac.verify(&truncated[..MIN_TRUNCATED_LEN - 1]));
+
+        end_test!();
+    }
+}
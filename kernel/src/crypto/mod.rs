@@ -1,9 +1,15 @@
+pub mod constant_time;
 pub mod crc32;
+pub mod crc64;
 pub mod guid;
+pub mod hmac;
 pub mod iterators;
 pub mod random;
+pub mod sha256;
 pub mod uuid;
 
+pub use constant_time::{constant_time_eq, ct_select};
+
 #[cfg(test)]
 mod tests {
     use crate::end_test;
@@ -6,13 +6,19 @@ use crate::time::Rtc;
 
 /// Algorithm adapted from https://en.wikipedia.org/wiki/Mersenne_Twister 11/12/2025
 
-pub static RANDOM_SENDER: OnceCell<UnboundedSender<UnboundedSender<u32>>> = OnceCell::new();
+pub static RANDOM_SENDER: OnceCell<UnboundedSender<RandomRequest>> = OnceCell::new();
+
+pub enum RandomRequest {
+    Next(UnboundedSender<u32>),
+    /// restarts the generator from a fixed seed, everything after it is reproducible
+    Seed(u64),
+}
 
 /// we are using Mersenne Twister here, or MT19937
 
 pub const W: usize = 32;
 pub const N: usize = 624;
-pub const M: usize = 32;
+pub const M: usize = 397;
 pub const R: usize = 31;
 
 pub const A: u32 = 0x9908B0DF;
@@ -29,44 +35,62 @@ pub const C: u32 = 0xEFC60000;
 pub const L: usize = 18;
 pub const F: u32 = 1812433253;
 
-struct RandState {
+pub const UMASK: u32 = 0xFFFFFFFF << R;
+pub const LMASK: u32 = 0xFFFFFFFF >> (W - R);
+
+pub struct RandState {
     state_array: [u32; N],
     index: isize,
 }
 
-fn init() -> RandState {
-    let mut res = RandState {
-        state_array: [0; N],
-        index: 0,
-    };
+impl RandState {
+    pub fn from_seed(mut seed: u32) -> Self {
+        let mut res = RandState {
+            state_array: [0; N],
+            index: 0,
+        };
+
+        res.state_array[0] = seed;
+        for i in 1..N {
+            // Knuth TAOCP Vol2. 3rd Ed. P.106 for multiplier.
+            seed = F
+                .wrapping_mul(seed ^ (seed >> (W - 2)))
+                .wrapping_add(i as u32);
+            res.state_array[i] = seed;
+        }
+
+        res
+    }
+
+    /// the same seed always gives the same sequence, it's the reference mt19937 one
+    pub fn deterministic(seed: u64) -> Self {
+        Self::from_seed((seed ^ (seed >> 32)) as u32)
+    }
 
-    let mut seed = (Rtc::datetime_to_unix_timestamp(
+    pub fn next_u32(&mut self) -> u32 {
+        random_u32(self)
+    }
+}
+
+fn init() -> RandState {
+    let seed = (Rtc::datetime_to_unix_timestamp(
         &Rtc::new()
             .read_datetime()
             .expect("Cannot get current time as seed for random"),
     ) & 0xFFFFFFFF) as u32;
 
-    for i in 0..N {
-        res.state_array[i] = seed;
-        // Knuth TAOCP Vol2. 3rd Ed. P.106 for multiplier.
-        seed = F * (seed ^ (seed >> (W - 2))) + i as u32;
-    }
-
-    res
+    RandState::from_seed(seed)
 }
 
 fn random_u32(state: &mut RandState) -> u32 {
     let mut k = state.index;
-    if k < 0 {
-        k += N as isize;
-    }
 
     let mut j = k - (N as isize - 1);
     if j < 0 {
         j += N as isize;
     }
 
-    let mut x = state.state_array[k as usize] & 0xFFFF0000 | state.state_array[j as usize] & 0xFFFF;
+    let mut x = state.state_array[k as usize] & UMASK | state.state_array[j as usize] & LMASK;
 
     let mut x_a = x >> 1;
     if x & 0x1 == 0x1 {
@@ -80,7 +104,8 @@ fn random_u32(state: &mut RandState) -> u32 {
 
     // compute the next value
     x = state.state_array[j as usize] ^ x_a;
-    state.state_array[k as usize + 1] = x;
+    state.state_array[k as usize] = x;
+    k += 1;
 
     if k >= N as isize {
         k = 0;
@@ -96,7 +121,7 @@ fn random_u32(state: &mut RandState) -> u32 {
 
 pub async fn run_random() {
     let mut state = init();
-    let (tx, rx) = unbounded_channel::<UnboundedSender<u32>>();
+    let (tx, rx) = unbounded_channel::<RandomRequest>();
 
     let _ = RANDOM_SENDER
         .set(tx.clone())
@@ -104,18 +129,28 @@ pub async fn run_random() {
 
     log!("Random initialization complete");
 
-    while let Some(sender) = rx.recv().await {
-        let res = random_u32(&mut state);
-        sender.send(res);
+    while let Some(request) = rx.recv().await {
+        match request {
+            RandomRequest::Next(sender) => sender.send(random_u32(&mut state)),
+            RandomRequest::Seed(seed) => state = RandState::deterministic(seed),
+        }
     }
 }
 
+/// for tests, the numbers handed out after this are the same on every boot
+pub fn seed_deterministic(seed: u64) {
+    RANDOM_SENDER
+        .get()
+        .expect("No Sender found")
+        .send(RandomRequest::Seed(seed));
+}
+
 pub async fn random_number() -> u32 {
     let sender = RANDOM_SENDER.get().expect("No Sender found").clone();
 
     let (tx, rx) = unbounded_channel::<u32>();
 
-    sender.send(tx);
+    sender.send(RandomRequest::Next(tx));
 
     if let Some(num) = rx.recv().await {
         num
@@ -123,3 +158,54 @@ pub async fn random_number() -> u32 {
         0
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{RANDOM_SENDER, RandState, random_number, seed_deterministic};
+    use crate::{end_test, ignore, terminal::test::block_on, test_name};
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn mt19937_reference_sequence() {
+        test_name!("mt19937 reference sequence");
+
+        // 5489 is the reference implementation's default seed
+        let mut state = RandState::deterministic(5489);
+        let first: [u32; 5] = core::array::from_fn(|_| state.next_u32());
+        assert_eq!(
+            first,
+            [3499211612, 581869302, 3890346734, 3586334585, 545404204]
+        );
+
+        // goes past the end of the state array a few times
+        for _ in 5..9999 {
+            state.next_u32();
+        }
+        assert_eq!(state.next_u32(), 4123659995);
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn seed_deterministic_sequence() {
+        test_name!("the random task follows mt19937 after seed_deterministic");
+
+        // needs the random task
+        if RANDOM_SENDER.get().is_none() {
+            ignore!();
+        }
+
+        // requests are answered in the order they were sent, the seed is in place before the
+        // first number is asked for
+        seed_deterministic(5489);
+        assert_eq!(block_on(random_number()), 3499211612);
+        assert_eq!(block_on(random_number()), 581869302);
+
+        // seeding again starts over
+        seed_deterministic(5489);
+        assert_eq!(block_on(random_number()), 3499211612);
+
+        end_test!();
+    }
+}
@@ -1,6 +1,9 @@
-use core::fmt;
+use core::{fmt, str::FromStr};
 
-use crate::crypto::random::random_number;
+use crate::crypto::{
+    guid::{GuidParseErr, parse_hyphenated},
+    random::random_number,
+};
 
 /// Generates a random UUID v4
 pub async fn uuid_v4() -> Uuid {
@@ -10,12 +13,17 @@ pub async fn uuid_v4() -> Uuid {
     let r3 = random_number().await;
     let r4 = random_number().await;
 
+    uuid_v4_from_words([r1, r2, r3, r4])
+}
+
+/// Builds a UUID v4 out of 128 random bits, a deterministically seeded `RandState` makes the
+/// result reproducible
+pub fn uuid_v4_from_words(words: [u32; 4]) -> Uuid {
     // Convert to bytes
     let mut bytes = [0u8; 16];
-    bytes[0..4].copy_from_slice(&r1.to_be_bytes());
-    bytes[4..8].copy_from_slice(&r2.to_be_bytes());
-    bytes[8..12].copy_from_slice(&r3.to_be_bytes());
-    bytes[12..16].copy_from_slice(&r4.to_be_bytes());
+    for (chunk, word) in bytes.chunks_exact_mut(4).zip(words) {
+        chunk.copy_from_slice(&word.to_be_bytes());
+    }
 
     // Set version (4) in the most significant 4 bits of byte 6
     bytes[6] = (bytes[6] & 0x0f) | 0x40;
@@ -33,6 +41,11 @@ pub struct Uuid {
 }
 
 impl Uuid {
+    /// Creates a UUID from its bytes in big endian order
+    pub fn from_bytes(bytes: [u8; 16]) -> Self {
+        Self { bytes }
+    }
+
     /// Returns the UUID as a byte array
     pub fn as_bytes(&self) -> &[u8; 16] {
         &self.bytes
@@ -87,6 +100,61 @@ impl fmt::Display for Uuid {
 
 impl fmt::Debug for Uuid {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Uuid({})", self)
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// Parses the hyphenated form, unlike `Guid` every byte is stored in the order it's written
+impl FromStr for Uuid {
+    type Err = GuidParseErr;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        Ok(Self {
+            bytes: parse_hyphenated(val, &[8, 4, 4, 4, 12])?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::str::FromStr;
+
+    use alloc::string::ToString;
+
+    use super::*;
+    use crate::{crypto::random::RandState, end_test, test_name};
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn uuid_round_trip() {
+        test_name!("uuid formatting and parsing");
+
+        let bytes = [
+            0x12, 0x3e, 0x45, 0x67, 0xe8, 0x9b, 0x12, 0xd3, 0xa4, 0x56, 0x42, 0x66, 0x14, 0x17,
+            0x40, 0x00,
+        ];
+        let uuid = Uuid::from_bytes(bytes);
+
+        assert_eq!(uuid.to_string(), "123e4567-e89b-12d3-a456-426614174000");
+        assert_eq!(
+            Uuid::from_str("123e4567-e89b-12d3-a456-426614174000"),
+            Ok(uuid)
+        );
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn uuid_v4_deterministic() {
+        test_name!("uuid v4 from a fixed seed");
+
+        let mut state = RandState::deterministic(5489);
+        let mut next = || uuid_v4_from_words(core::array::from_fn(|_| state.next_u32()));
+
+        assert_eq!(next().to_string(), "d091bb5c-22ae-4ef6-a7e1-faeed5c31f79");
+        assert_eq!(next().to_string(), "2082352c-f807-47df-a9d3-00053895afe1");
+
+        end_test!();
     }
 }
@@ -0,0 +1,105 @@
+use alloc::vec::Vec;
+use x86_64::{
+    PhysAddr, VirtAddr,
+    registers::control::Cr3,
+    structures::paging::{PhysFrame, Translate, mapper::TranslateResult},
+};
+
+use crate::{
+    arch::x86_64::{
+        memory::{PAGE_SIZE, frame_allocator::FRAME_ALLOCATOR, get_hhdm_offset},
+        scheduler::uaccess::page_table_at,
+    },
+    hal::storage::IoErr,
+};
+
+/// the physical address a device has to be given for `len` bytes at `virt`. Every page has to be
+/// mapped and follow the one before it in physical memory, a controller only ever sees the
+/// physical range
+pub fn dma_range(table: &impl Translate, virt: u64, len: usize) -> Result<PhysAddr, IoErr> {
+    if len == 0 {
+        return Err(IoErr::DmaUnreachable);
+    }
+
+    let translate = |addr: u64| match table.translate(VirtAddr::try_new(addr).ok()?) {
+        TranslateResult::Mapped { frame, offset, .. } => Some(frame.start_address() + offset),
+        _ => None,
+    };
+
+    let start = translate(virt).ok_or(IoErr::DmaUnreachable)?;
+    let end = virt.checked_add(len as u64).ok_or(IoErr::DmaUnreachable)?;
+
+    // the first byte of every following page
+    let mut page = (virt / PAGE_SIZE as u64 + 1) * PAGE_SIZE as u64;
+    while page < end {
+        if translate(page) != Some(start + (page - virt)) {
+            return Err(IoErr::DmaUnreachable);
+        }
+
+        page += PAGE_SIZE as u64;
+    }
+
+    Ok(start)
+}
+
+/// `dma_range` against the loaded page table, the kernel half is the same in all of them
+pub fn kernel_dma_range(virt: u64, len: usize) -> Result<PhysAddr, IoErr> {
+    dma_range(&page_table_at(Cr3::read().0.start_address()), virt, len)
+}
+
+/// physically contiguous frames straight from the frame allocator, seen through the hhdm, so a
+/// controller can be pointed at them without any doubt
+#[derive(Debug)]
+pub struct DmaBuffer {
+    frames: Vec<PhysFrame>,
+    len: usize,
+}
+
+impl DmaBuffer {
+    /// zeroed, None if there's no contiguous run of frames long enough
+    pub fn new(len: usize) -> Option<Self> {
+        let frames = FRAME_ALLOCATOR
+            .get()
+            .expect("Failed to get frame allocator")
+            .spin_acquire_lock()
+            .allocate_continuous_frames(&mut None, len.div_ceil(PAGE_SIZE as usize).max(1))?;
+
+        let mut buffer = Self { frames, len };
+        buffer.as_mut_slice().fill(0);
+        Some(buffer)
+    }
+
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.frames[0].start_address()
+    }
+
+    pub fn virt_addr(&self) -> VirtAddr {
+        get_hhdm_offset() + self.phys_addr().as_u64()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.virt_addr().as_ptr(), self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.virt_addr().as_mut_ptr(), self.len) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        FRAME_ALLOCATOR
+            .get()
+            .expect("Failed to get frame allocator")
+            .spin_acquire_lock()
+            .free_frames(&self.frames);
+    }
+}
@@ -1,3 +1,4 @@
+pub mod dma_buffer;
 pub mod pata;
 pub mod sata;
 
@@ -25,5 +26,9 @@ pub mod cmd {
     pub const IDENTITY: u8 = 0xEC;
     pub const LBA28: u8 = 0xE0;
     pub const LBA48: u8 = 0x40;
+    pub const READ_DMA: u8 = 0xC8;
+    pub const READ_DMA_EXT: u8 = 0x25;
+    pub const WRITE_DMA: u8 = 0xCA;
+    pub const WRITE_DMA_EXT: u8 = 0x35;
     pub const FLUSH_CACHE: u8 = 0xE7;
 }
@@ -0,0 +1,415 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+use alloc::boxed::Box;
+use bytemuck::{Pod, Zeroable};
+use x86_64::{
+    PhysAddr, VirtAddr,
+    instructions::port::{Port, PortGeneric, ReadWriteAccess},
+    structures::paging::FrameAllocator,
+};
+
+use crate::{
+    arch::x86_64::{
+        memory::{PAGE_SIZE, frame_allocator::FRAME_ALLOCATOR, get_hhdm_offset},
+        timer::Instant,
+    },
+    drivers::ata::{
+        cmd,
+        pata::{PataDevice, drive_err, pio::LBA28_MAX_SECTORS},
+    },
+    ejcineque::{self, futures::race::Either, sync::mpsc::unbounded::UnboundedReceiver},
+    hal::{
+        buffer::Buffer,
+        storage::{HalBlockDevice, HalStorageOperation, IoErr, SECTOR_SIZE},
+    },
+    log,
+};
+
+// bus master registers, relative to the channel's base in bar4
+const BM_COMMAND: u16 = 0x0;
+const BM_STATUS: u16 = 0x2;
+const BM_PRDT: u16 = 0x4;
+/// the secondary channel's bus master registers follow the primary's
+pub const BM_SECONDARY_OFFSET: u16 = 0x8;
+
+const BM_COMMAND_START: u8 = 0x1;
+/// the device writes to memory, so this is set for reads
+const BM_COMMAND_READ: u8 = 0x8;
+
+const BM_STATUS_ERROR: u8 = 0x2;
+const BM_STATUS_INTERRUPT: u8 = 0x4;
+
+const ATA_STATUS_ERROR: u8 = 0x1;
+
+/// a prd can't cross a 64KiB boundary
+const PRD_BOUNDARY: u64 = 0x10000;
+const PRD_END_OF_TABLE: u16 = 0x8000;
+/// the table lives in a single page
+pub const PRDT_ENTRIES: usize = PAGE_SIZE as usize / size_of::<PrdEntry>();
+
+/// 16MiB, at most 257 prds even if the buffer isn't 64KiB aligned
+const LBA48_MAX_DMA_SECTORS: u16 = 0x8000;
+
+/// timer ticks to wait for the completion irq
+const DMA_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// set from the `pata_bench` kernel argument, the first dma drive to come up times a 1MiB read
+/// over pio and over dma before serving requests
+pub static PATA_BENCHMARK: AtomicBool = AtomicBool::new(false);
+const BENCHMARK_LEN: usize = 1024 * 1024;
+
+#[derive(Pod, Zeroable, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct PrdEntry {
+    pub base: u32,
+    /// 0 means 64KiB
+    pub byte_count: u16,
+    pub flags: u16,
+}
+
+/// fills the table for a physically contiguous buffer, returns how many entries were used
+pub fn build_prdt(phys: u64, len: usize, table: &mut [PrdEntry]) -> Result<usize, IoErr> {
+    let end = phys + len as u64;
+
+    // the engine only takes 32 bit word aligned addresses
+    if len == 0 || !phys.is_multiple_of(2) || !len.is_multiple_of(2) || end > 1 << 32 {
+        return Err(IoErr::DmaUnreachable);
+    }
+
+    let mut addr = phys;
+    let mut used = 0;
+
+    while addr < end {
+        let chunk_end = ((addr / PRD_BOUNDARY + 1) * PRD_BOUNDARY).min(end);
+        let entry = table.get_mut(used).ok_or(IoErr::InputTooLarge)?;
+
+        *entry = PrdEntry {
+            base: addr as u32,
+            // a full 64KiB wraps around to 0, which is what the engine expects
+            byte_count: (chunk_end - addr) as u16,
+            flags: 0,
+        };
+
+        used += 1;
+        addr = chunk_end;
+    }
+
+    table[used - 1].flags = PRD_END_OF_TABLE;
+
+    Ok(used)
+}
+
+#[derive(Debug)]
+pub struct PataDma {
+    pub device: PataDevice,
+    pub bus_master_command: PortGeneric<u8, ReadWriteAccess>,
+    pub bus_master_status: PortGeneric<u8, ReadWriteAccess>,
+    pub bus_master_prdt: PortGeneric<u32, ReadWriteAccess>,
+    prdt_vaddr: VirtAddr,
+    prdt_paddr: PhysAddr,
+}
+
+unsafe impl Send for PataDma {}
+unsafe impl Sync for PataDma {}
+
+impl PataDma {
+    /// hands the device back if there is no frame the engine can reach for the prd table
+    pub fn new(device: PataDevice, bus_master_base: u16) -> Result<Self, PataDevice> {
+        let mut allocator = FRAME_ALLOCATOR
+            .get()
+            .expect("Failed to get allocator")
+            .spin_acquire_lock();
+
+        let Some(frame) = allocator.allocate_frame(&mut None) else {
+            return Err(device);
+        };
+
+        if frame.start_address().as_u64() + PAGE_SIZE as u64 > 1 << 32 {
+            allocator.free_frames(&[frame]);
+            return Err(device);
+        }
+
+        log!(
+            "PataDma::new: bus master registers at port {:#x}",
+            bus_master_base
+        );
+
+        Ok(Self {
+            device,
+            bus_master_command: Port::new(bus_master_base + BM_COMMAND),
+            bus_master_status: Port::new(bus_master_base + BM_STATUS),
+            bus_master_prdt: Port::new(bus_master_base + BM_PRDT),
+            prdt_vaddr: get_hhdm_offset() + frame.start_address().as_u64(),
+            prdt_paddr: frame.start_address(),
+        })
+    }
+
+    fn max_sectors_per_command(&self) -> u16 {
+        if self.device.lba48_supported {
+            LBA48_MAX_DMA_SECTORS
+        } else {
+            LBA28_MAX_SECTORS
+        }
+    }
+
+    /// the buffer has to be physically contiguous and in the hhdm, like every storage buffer
+    async fn dma_command(
+        &mut self,
+        lba: u64,
+        buffer: &Buffer,
+        write: bool,
+    ) -> Result<(), Box<dyn core::error::Error + Send + Sync>> {
+        let count = (buffer.len() / SECTOR_SIZE) as u16;
+        let phys = buffer.inner as u64 - get_hhdm_offset().as_u64();
+
+        let table = unsafe {
+            core::slice::from_raw_parts_mut(self.prdt_vaddr.as_mut_ptr::<PrdEntry>(), PRDT_ENTRIES)
+        };
+        build_prdt(phys, buffer.len(), table)?;
+
+        let lba = self.device.io_init(lba as i64, count)?;
+
+        let direction = if write { 0 } else { BM_COMMAND_READ };
+
+        unsafe {
+            self.bus_master_command.write(0);
+            self.bus_master_prdt.write(self.prdt_paddr.as_u64() as u32);
+            self.bus_master_command.write(direction);
+            // writing 1 clears them
+            self.bus_master_status
+                .write(BM_STATUS_ERROR | BM_STATUS_INTERRUPT);
+        }
+
+        let command = match (self.device.lba48_supported, write) {
+            (true, false) => cmd::READ_DMA_EXT,
+            (true, true) => cmd::WRITE_DMA_EXT,
+            (false, false) => cmd::READ_DMA,
+            (false, true) => cmd::WRITE_DMA,
+        };
+
+        if self.device.lba48_supported {
+            self.device.send_lba48(count, lba);
+        } else {
+            self.device.send_lba28(count, lba);
+        }
+
+        unsafe {
+            self.device.cmd_port.write(command);
+            self.bus_master_command.write(direction | BM_COMMAND_START);
+        }
+
+        let res = ejcineque::futures::race::race(
+            ejcineque::time::wait(DMA_TIMEOUT),
+            PataDevice::wait_io_async_future(self.device.port),
+        )
+        .await;
+
+        let bus_master_status = unsafe { self.bus_master_status.read() };
+
+        unsafe {
+            self.bus_master_command.write(direction);
+            self.bus_master_status
+                .write(BM_STATUS_ERROR | BM_STATUS_INTERRUPT);
+        }
+
+        // reading the status register also acknowledges the irq on the drive's side
+        let status = unsafe { self.device.status_port.read() };
+
+        // the irq can fire before the waker is registered, the status bit still tells
+        if matches!(res, Either::Left(_)) && bus_master_status & BM_STATUS_INTERRUPT == 0 {
+            return Err(Box::new(IoErr::IOTimeout));
+        }
+
+        if bus_master_status & BM_STATUS_ERROR != 0 || status & ATA_STATUS_ERROR != 0 {
+            return Err(Box::new(IoErr::DmaFailed));
+        }
+
+        Ok(())
+    }
+
+    pub async fn dma_transfer(
+        &mut self,
+        index: i64,
+        buffer: &Buffer,
+        write: bool,
+    ) -> Result<(), Box<dyn core::error::Error + Send + Sync>> {
+        let mut lba = self.device.get_lba(index);
+        let chunk_len = self.max_sectors_per_command() as usize * SECTOR_SIZE;
+        let mut offset = 0;
+
+        while offset < buffer.len() {
+            let chunk = Buffer {
+                inner: unsafe { buffer.inner.add(offset) },
+                len: chunk_len.min(buffer.len() - offset),
+            };
+
+            self.dma_command(lba, &chunk, write).await?;
+
+            lba += (chunk.len / SECTOR_SIZE) as u64;
+            offset += chunk.len;
+        }
+
+        if write {
+            self.device.flush_cache()?;
+        }
+
+        Ok(())
+    }
+
+    /// reads the first 1MiB over pio and then over dma, returns how long each took
+    pub async fn benchmark(
+        &mut self,
+    ) -> Result<(Duration, Duration), Box<dyn core::error::Error + Send + Sync>> {
+        let frames = FRAME_ALLOCATOR
+            .get()
+            .expect("Failed to get allocator")
+            .lock()
+            .await
+            .allocate_continuous_frames(&mut None, BENCHMARK_LEN / PAGE_SIZE as usize)
+            .ok_or(IoErr::Unavailable)?;
+
+        let mut buffer = Buffer {
+            inner: (get_hhdm_offset() + frames[0].start_address().as_u64()).as_mut_ptr(),
+            len: BENCHMARK_LEN,
+        };
+
+        let start = Instant::now();
+        let pio = self.device.pio_transfer(0, &mut buffer, false).await;
+        let pio_time = Instant::now() - start;
+
+        let start = Instant::now();
+        let dma = self.dma_transfer(0, &buffer, false).await;
+        let dma_time = Instant::now() - start;
+
+        FRAME_ALLOCATOR
+            .get()
+            .expect("Failed to get allocator")
+            .lock()
+            .await
+            .free_frames(&frames);
+
+        pio?;
+        dma?;
+
+        Ok((pio_time, dma_time))
+    }
+
+    async fn run_benchmark(&mut self) {
+        match self.benchmark().await {
+            Ok((pio, dma)) => {
+                let kib_per_sec = |time: Duration| {
+                    (BENCHMARK_LEN as u128 / 1024) * 1_000_000 / time.as_micros().max(1)
+                };
+
+                log!(
+                    "pata benchmark (1MiB read): pio {:?} ({} KiB/s), dma {:?} ({} KiB/s)",
+                    pio,
+                    kib_per_sec(pio),
+                    dma,
+                    kib_per_sec(dma)
+                );
+            }
+            Err(e) => log!("pata benchmark failed: {}", e),
+        }
+    }
+
+    pub async fn run_task(&mut self, rx: &UnboundedReceiver<HalStorageOperation>) {
+        if PATA_BENCHMARK.swap(false, Ordering::AcqRel) {
+            self.run_benchmark().await;
+        }
+
+        while let Some(op) = rx.recv().await {
+            match op {
+                HalStorageOperation::Read {
+                    buffer,
+                    lba,
+                    setter,
+                } => setter.set(
+                    self.dma_transfer(lba, &buffer, false)
+                        .await
+                        .map_err(drive_err),
+                ),
+
+                HalStorageOperation::Write {
+                    buffer,
+                    lba,
+                    setter,
+                } => setter.set(
+                    self.dma_transfer(lba, &buffer, true)
+                        .await
+                        .map_err(drive_err),
+                ),
+
+                // nothing to do with the bus master
+                op => self.device.pio_operation(op).await,
+            }
+        }
+    }
+}
+
+impl HalBlockDevice for PataDma {
+    fn run<'device, 'rx, 'future>(
+        &'device mut self,
+        rx: &'rx UnboundedReceiver<HalStorageOperation>,
+    ) -> core::pin::Pin<Box<dyn Future<Output = ()> + 'future + Send + Sync>>
+    where
+        'rx: 'future,
+        'device: 'future,
+    {
+        Box::pin(async move { self.run_task(rx).await })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{end_test, test_name};
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn prdt_splits_at_64k() {
+        test_name!("prd entries never cross a 64KiB boundary");
+
+        let mut table = [PrdEntry::default(); 4];
+
+        // a single sector
+        assert_eq!(build_prdt(0x1000, 512, &mut table).unwrap(), 1);
+        assert_eq!(
+            table[0],
+            PrdEntry {
+                base: 0x1000,
+                byte_count: 512,
+                flags: PRD_END_OF_TABLE,
+            }
+        );
+
+        // 1MiB-ish buffers that start mid way through a 64KiB region
+        assert_eq!(build_prdt(0x1F000, 0x20000, &mut table).unwrap(), 3);
+        assert_eq!({ table[0].base }, 0x1F000);
+        assert_eq!({ table[0].byte_count }, 0x1000);
+        assert_eq!({ table[0].flags }, 0);
+        assert_eq!({ table[1].base }, 0x20000);
+        // a whole 64KiB is encoded as 0
+        assert_eq!({ table[1].byte_count }, 0);
+        assert_eq!({ table[2].base }, 0x30000);
+        assert_eq!({ table[2].byte_count }, 0xF000);
+        assert_eq!({ table[2].flags }, PRD_END_OF_TABLE);
+
+        assert!(matches!(
+            build_prdt(0, 5 * 0x10000, &mut table),
+            Err(IoErr::InputTooLarge)
+        ));
+        // above 4GiB and odd addresses are out of the engine's reach
+        assert!(matches!(
+            build_prdt(0xFFFF_F000, 0x2000, &mut table),
+            Err(IoErr::DmaUnreachable)
+        ));
+        assert!(matches!(
+            build_prdt(0x1001, 512, &mut table),
+            Err(IoErr::DmaUnreachable)
+        ));
+
+        end_test!();
+    }
+}
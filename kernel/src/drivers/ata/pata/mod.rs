@@ -3,17 +3,24 @@ use super::{
     offsets::{COMMAND, DRIVE, ERROR, FEATURE, LBA_HIGH, LBA_LOW, LBA_MID, SECTOR_COUNT, STATUS},
 };
 use crate::crypto::binary_test;
+use crate::hal::storage::HalStorageOperationErr;
 use crate::log;
+use alloc::{boxed::Box, string::ToString};
 use x86_64::instructions::port::{
     Port, PortGeneric, PortReadOnly, PortWriteOnly, ReadOnlyAccess, ReadWriteAccess,
     WriteOnlyAccess,
 };
 
+pub mod dma;
 pub mod pio;
 
 pub const PATA_PRIMARY_BASE: u16 = 0x1F0;
 pub const PATA_SECONDARY_BASE: u16 = 0x170;
 
+fn drive_err(err: Box<dyn core::error::Error + Send + Sync>) -> HalStorageOperationErr {
+    HalStorageOperationErr::DriveErr(err.to_string())
+}
+
 pub enum PataIdentErr {
     DeviceNonExist,
     DeviceNotAta,
@@ -30,6 +37,8 @@ pub struct PataDevice {
     pub lba28_sector_count: u32,
     pub lba48_sector_count: u64,
     pub sectors_per_track: u16,
+    /// set when IDENTIFY reports at least one ultra dma mode
+    pub udma_supported: bool,
 
     pub port: u16,
     pub data_port: PortGeneric<u16, ReadWriteAccess>,
@@ -55,6 +64,7 @@ impl PataDevice {
             lba28_sector_count: 0,
             lba48_sector_count: 0,
             sectors_per_track: 1,
+            udma_supported: false,
 
             port: base_port,
             data_port: Port::new(base_port),
@@ -94,9 +104,13 @@ impl PataDevice {
             | ((buf[101] as u64) << 16)
             | (buf[100] as u64);
 
+        // word 88 only means something when bit 2 of word 53 is set
+        self.udma_supported = binary_test(buf[53].into(), 2) && buf[88] & 0x7F != 0;
+
         log!("=== ATA Drive Identify Result (port {:#x}) ===", self.port);
         log!("  LBA48 supported: {}", self.lba48_supported);
         log!("  Sectors per track: {}", self.sectors_per_track);
+        log!("  UDMA supported: {}", self.udma_supported);
         log!(
             "  LBA28 sector count: {:#x} ({} sectors)",
             self.lba28_sector_count,
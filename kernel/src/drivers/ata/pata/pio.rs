@@ -1,20 +1,24 @@
 use crate::ejcineque;
+use crate::ejcineque::sync::mpsc::unbounded::UnboundedReceiver;
 use crate::ejcineque::wakers::{PRIMARY_IDE_WAKERS, SECONDARY_IDE_WAKERS};
 use alloc::boxed::Box;
+use core::time::Duration;
 
 use crate::crypto::binary_test;
 use crate::drivers::ata::cmd;
-use crate::drivers::ata::pata::{PATA_PRIMARY_BASE, PATA_SECONDARY_BASE};
-use crate::hal::storage::IoErr;
+use crate::drivers::ata::pata::{PATA_PRIMARY_BASE, PATA_SECONDARY_BASE, drive_err};
+use crate::hal::storage::{HalBlockDevice, HalIdentifyData, HalStorageOperation, IoErr};
 
 use super::PataDevice;
 
 const WAIT_TIME: u32 = 100000;
-const WAIT_TICK_TIME: u32 = 10;
+const WAIT_TIMEOUT: Duration = Duration::from_millis(10);
 const SECTOR_SIZE: u16 = 512;
+/// lba28 commands take a single byte sector count
+pub(super) const LBA28_MAX_SECTORS: u16 = 0xFF;
 
 impl PataDevice {
-    fn get_lba(&self, index: i64) -> u64 {
+    pub(super) fn get_lba(&self, index: i64) -> u64 {
         
 
         // log!("get_lba: index={}, resolved_lba={}", index, lba);
@@ -61,7 +65,7 @@ impl PataDevice {
         Ok(())
     }
 
-    fn wait_init(&mut self) -> Result<(), Box<dyn core::error::Error + Send + Sync>> {
+    pub(super) fn wait_init(&mut self) -> Result<(), Box<dyn core::error::Error + Send + Sync>> {
         // log!("wait_init: starting");
         let mut timer = 0;
         while binary_test(unsafe { self.status_port.read() } as u64, 7) {
@@ -77,7 +81,7 @@ impl PataDevice {
         Ok(())
     }
 
-    fn io_init(
+    pub(super) fn io_init(
         &mut self,
         index: i64,
         count: u16,
@@ -99,7 +103,7 @@ impl PataDevice {
         Ok(lba)
     }
 
-    fn send_lba28(&mut self, count: u16, lba: u64) {
+    pub(super) fn send_lba28(&mut self, count: u16, lba: u64) {
         // log!("send_lba28: count={}, lba={:#x}", count, lba);
         unsafe {
             self.drive_port
@@ -128,7 +132,7 @@ impl PataDevice {
         }
     }
 
-    fn send_lba48(&mut self, count: u16, lba: u64) {
+    pub(super) fn send_lba48(&mut self, count: u16, lba: u64) {
         // log!("send_lba48: count={}, lba=0x{:#x}", count, lba);
         unsafe {
             self.drive_port.write(cmd::LBA48);
@@ -181,14 +185,16 @@ impl PataDevice {
         Ok(())
     }
 
-    fn wait_io_async_future(port: u16) -> WaitIOFuture {
+    pub(super) fn wait_io_async_future(port: u16) -> WaitIOFuture {
         WaitIOFuture {
             port,
             is_done: false,
         }
     }
 
-    async fn wait_io_async(&mut self) -> Result<(), Box<dyn core::error::Error + Send + Sync>> {
+    pub(super) async fn wait_io_async(
+        &mut self,
+    ) -> Result<(), Box<dyn core::error::Error + Send + Sync>> {
         for _ in 0..14 {
             unsafe {
                 self.status_port.read();
@@ -196,7 +202,7 @@ impl PataDevice {
         }
 
         let res = ejcineque::futures::race::race(
-            ejcineque::time::wait(WAIT_TICK_TIME),
+            ejcineque::time::wait(WAIT_TIMEOUT),
             Self::wait_io_async_future(self.port),
         )
         .await;
@@ -294,7 +300,7 @@ impl PataDevice {
         Ok(())
     }
 
-    fn flush_cache(&mut self) -> Result<(), Box<dyn core::error::Error + Send + Sync>> {
+    pub(super) fn flush_cache(&mut self) -> Result<(), Box<dyn core::error::Error + Send + Sync>> {
         // log!("flush_cache: flushing drive cache");
         unsafe {
             self.cmd_port.write(cmd::FLUSH_CACHE);
@@ -440,7 +446,7 @@ impl PataDevice {
         //     count
         // );
 
-        if input.len() < (count * SECTOR_SIZE).into() {
+        if input.len() < count as usize * SECTOR_SIZE as usize {
             // log!(
             //     "pio_write_sectors: FAILED - input too small (need {}, have {})",
             //     count * SECTOR_SIZE,
@@ -483,7 +489,7 @@ impl PataDevice {
         //     count
         // );
 
-        if input.len() < (count * SECTOR_SIZE).into() {
+        if input.len() < count as usize * SECTOR_SIZE as usize {
             // log!(
             //     "pio_write_sectors_async: FAILED - input too small (need {}, have {})",
             //     count * SECTOR_SIZE,
@@ -515,6 +521,97 @@ impl PataDevice {
     }
 }
 
+impl PataDevice {
+    /// the most sectors a single command can move
+    pub(super) fn max_sectors_per_command(&self) -> u16 {
+        if self.lba48_supported {
+            u16::MAX
+        } else {
+            LBA28_MAX_SECTORS
+        }
+    }
+
+    /// splits the buffer into as many commands as the addressing mode needs
+    pub(super) async fn pio_transfer(
+        &mut self,
+        index: i64,
+        buffer: &mut [u8],
+        write: bool,
+    ) -> Result<(), Box<dyn core::error::Error + Send + Sync>> {
+        let mut lba = self.get_lba(index);
+        let chunk_len = self.max_sectors_per_command() as usize * SECTOR_SIZE as usize;
+
+        for chunk in buffer.chunks_mut(chunk_len) {
+            let count = (chunk.len() / SECTOR_SIZE as usize) as u16;
+
+            if write {
+                self.pio_write_sectors_async(lba as i64, count, chunk)
+                    .await?;
+            } else {
+                self.pio_read_sectors_async(lba as i64, count, chunk)
+                    .await?;
+            }
+
+            lba += count as u64;
+        }
+
+        Ok(())
+    }
+
+    pub(super) async fn pio_operation(&mut self, op: HalStorageOperation) {
+        match op {
+            HalStorageOperation::Read {
+                mut buffer,
+                lba,
+                setter,
+            } => setter.set(
+                self.pio_transfer(lba, &mut buffer, false)
+                    .await
+                    .map_err(drive_err),
+            ),
+
+            HalStorageOperation::Write {
+                mut buffer,
+                lba,
+                setter,
+            } => setter.set(
+                self.pio_transfer(lba, &mut buffer, true)
+                    .await
+                    .map_err(drive_err),
+            ),
+
+            HalStorageOperation::Flush { setter } => {
+                setter.set(self.flush_cache().map_err(drive_err))
+            }
+
+            HalStorageOperation::Identify { setter } => setter.set(HalIdentifyData {
+                sector_count: self.sector_count(),
+                sectors_per_track: self.sectors_per_track,
+            }),
+        }
+    }
+
+    /// pata channels do one command at a time, so operations are handled in order
+    pub async fn run_task(&mut self, rx: &UnboundedReceiver<HalStorageOperation>) {
+        while let Some(op) = rx.recv().await {
+            self.pio_operation(op).await;
+        }
+    }
+}
+
+impl HalBlockDevice for PataDevice {
+    fn run<'device, 'rx, 'future>(
+        &'device mut self,
+        rx: &'rx UnboundedReceiver<HalStorageOperation>,
+    ) -> core::pin::Pin<Box<dyn Future<Output = ()> + 'future + Send + Sync>>
+    where
+        'rx: 'future,
+        'device: 'future,
+    {
+        Box::pin(async move { self.run_task(rx).await })
+    }
+}
+
 pub struct WaitIOFuture {
     is_done: bool,
     port: u16,
@@ -59,24 +59,23 @@ impl AhciHbaPorts {
     );
 }
 
+/// CAP.S64A, without it the hba can't reach memory above 4GiB
+pub const CAP_S64A: u32 = 0x1 << 31;
+
+impl AhciHbaPorts {
+    pub fn supports_64bit_addressing(&self) -> bool {
+        self.read_cap() & CAP_S64A != 0
+    }
+}
+
 pub const HBA_PORT_PORTS_OFFSET: u64 = 0x100;
 pub const HBA_PORT_SIZE: u64 = 0x80;
 
 impl AhciHba {
-    pub fn new(location: VirtAddr, hba_idx: usize) -> Self {
-        // the BAR address *can* be 64 bits so we use the mask to check, if it's 64 bits bars[4]
-        // will be used as the higher half
+    /// `phys_base` is the abar, what bar5 of the controller points at
+    pub fn new(location: VirtAddr, phys_base: u64, hba_idx: usize) -> Self {
         let header: PciHeader = PciHeader { base: location };
 
-        let mut phys_base = (header.read_bar5() & 0xFFFF_FFF0) as u64;
-
-        let is_64_bit = (header.read_bar5() & 0b0100) != 0;
-
-        if is_64_bit {
-            let upper_bits = header.read_bar4() as u64;
-            phys_base |= upper_bits << 32;
-        }
-
         let base = get_hhdm_offset() + phys_base;
 
         let page_table = KERNEL_PAGE_TABLE
@@ -137,7 +136,7 @@ impl AhciHba {
         let mut msi_data = MessageDataRegister::default();
         msi_data.set_vector(idx as u32);
         let mut msi_addr = MessageAddressRegister::default();
-        msi_addr.set_destination_id(get_local_apic().read_id());
+        msi_addr.set_destination_id(get_local_apic().apic_id());
 
         msi_cap_node.write_message_addr_register(msi_addr.0);
 
@@ -163,7 +162,7 @@ impl AhciHba {
         self.ports.write_ghc(ghc);
 
         // doesn't support 32 bits only yet
-        if self.ports.read_cap() & (0x1 << 31) == 0 {
+        if !self.ports.supports_64bit_addressing() {
             return Vec::new();
         }
 
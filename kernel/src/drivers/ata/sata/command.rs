@@ -2,7 +2,10 @@ use bitfield::bitfield;
 use bytemuck::{Pod, Zeroable};
 use smart_default::SmartDefault;
 
-use crate::drivers::ata::sata::fis::FisRegH2D;
+use crate::{
+    drivers::ata::{dma_buffer::kernel_dma_range, sata::fis::FisRegH2D},
+    hal::storage::IoErr,
+};
 
 #[repr(C, align(2))]
 #[derive(Clone, Copy, Debug, SmartDefault)]
@@ -34,8 +37,8 @@ pub struct IdentifyData {
     pub capabilities1: u16,
     /// Word 49: Capabilities
     pub capabilities2: u16,
-    /// Words 51-59: Obsolete
-    pub _reserved3: [u16; 9],
+    /// Words 50-59: Obsolete
+    pub _reserved3: [u16; 10],
     /// Words 60-61: Total number of user-addressable logical sectors (LBA28)
     pub lba28_sectors: u32,
     /// Words 62-79: Obsolete
@@ -44,17 +47,128 @@ pub struct IdentifyData {
     pub major_version: u16,
     /// Word 81: Minor version number
     pub minor_version: u16,
-    /// Word 82: Command set supported
+    /// Word 82: Command set supported (Bit 5: 1=volatile write cache support)
     pub command_set_supported1: u16,
-    /// Word 83: Command set supported (Bit 10: 1=LBA48 support)
+    /// Word 83: Command set supported (Bit 10: 1=LBA48 support, Bit 13: 1=FLUSH CACHE EXT)
     pub command_set_supported2: u16,
-    /// Words 84-99: Reserved
-    pub _reserved5: [u16; 16],
+    /// Word 84: Command set/feature supported extension
+    pub command_set_supported3: u16,
+    /// Word 85: Command set enabled (Bit 5: 1=volatile write cache enabled)
+    pub command_set_enabled1: u16,
+    /// Word 86: Command set enabled
+    pub command_set_enabled2: u16,
+    /// Word 87: Command set/feature default
+    pub command_set_default: u16,
+    /// Words 88-99: Reserved
+    pub _reserved5: [u16; 12],
     /// Words 100-103: Total number of user-addressable logical sectors (LBA48)
     pub lba48_sectors: u64,
-    /// Words 104-255: Reserved
-    #[default([0; 152])]
-    pub _reserved6: [u16; 152],
+    /// Words 104-105: Reserved
+    pub _reserved6: [u16; 2],
+    /// Word 106: Physical/logical sector size (Bits 3:0: log2 of logical sectors per physical)
+    pub sector_size_info: u16,
+    /// Words 107-116: Reserved
+    pub _reserved7: [u16; 10],
+    /// Words 117-118: Logical sector size in words, valid if bit 12 of word 106 is set
+    pub logical_sector_words: [u16; 2],
+    /// Words 119-255: Reserved
+    #[default([0; 137])]
+    pub _reserved8: [u16; 137],
+}
+
+const _: () = assert!(size_of::<IdentifyData>() == 512);
+
+/// ata strings swap the bytes of every word
+fn ata_string<const N: usize>(raw: &[u8; N]) -> [u8; N] {
+    let mut res = *raw;
+    for pair in res.chunks_exact_mut(2) {
+        pair.swap(0, 1);
+    }
+
+    res
+}
+
+/// strings are padded with spaces
+fn trim_ata_string(bytes: &[u8]) -> &str {
+    core::str::from_utf8(bytes)
+        .unwrap_or("")
+        .trim_matches(|c| c == ' ' || c == '\0')
+}
+
+/// the parts of the identify data that are used to pick device specific behaviour
+#[derive(Clone, Copy, Debug, SmartDefault)]
+pub struct AtaDriveInfo {
+    #[default([0; 40])]
+    pub model: [u8; 40],
+    pub serial: [u8; 20],
+    pub firmware_rev: [u8; 8],
+    #[default(512)]
+    pub logical_sector_size: u32,
+    #[default(512)]
+    pub physical_sector_size: u32,
+    pub write_cache_supported: bool,
+    pub write_cache_enabled: bool,
+    pub flush_cache_ext_supported: bool,
+}
+
+impl AtaDriveInfo {
+    const SECTOR_SIZE_INFO_VALID: u16 = 0x4000;
+    const LOGICAL_SECTOR_LONGER_THAN_256_WORDS: u16 = 0x1 << 12;
+    const MULTIPLE_LOGICAL_PER_PHYSICAL: u16 = 0x1 << 13;
+    const COMMAND_SET_VALID: u16 = 0x4000;
+    const WRITE_CACHE: u16 = 0x1 << 5;
+    const FLUSH_CACHE_EXT: u16 = 0x1 << 13;
+
+    pub fn model(&self) -> &str {
+        trim_ata_string(&self.model)
+    }
+
+    pub fn serial(&self) -> &str {
+        trim_ata_string(&self.serial)
+    }
+
+    pub fn firmware_rev(&self) -> &str {
+        trim_ata_string(&self.firmware_rev)
+    }
+}
+
+impl From<&IdentifyData> for AtaDriveInfo {
+    fn from(data: &IdentifyData) -> Self {
+        // bits 15:14 have to be 01 for the word to mean anything
+        let sector_size_valid = data.sector_size_info & 0xC000 == Self::SECTOR_SIZE_INFO_VALID;
+
+        let logical_sector_size = if sector_size_valid
+            && data.sector_size_info & Self::LOGICAL_SECTOR_LONGER_THAN_256_WORDS != 0
+        {
+            (data.logical_sector_words[0] as u32 | (data.logical_sector_words[1] as u32) << 16) * 2
+        } else {
+            512
+        };
+
+        let physical_sector_size = if sector_size_valid
+            && data.sector_size_info & Self::MULTIPLE_LOGICAL_PER_PHYSICAL != 0
+        {
+            logical_sector_size << (data.sector_size_info & 0xF)
+        } else {
+            logical_sector_size
+        };
+
+        let command_set_valid = data.command_set_supported2 & 0xC000 == Self::COMMAND_SET_VALID;
+
+        Self {
+            model: ata_string(&data.model),
+            serial: ata_string(&data.serial),
+            firmware_rev: ata_string(&data.firmware_rev),
+            logical_sector_size,
+            physical_sector_size,
+            write_cache_supported: command_set_valid
+                && data.command_set_supported1 & Self::WRITE_CACHE != 0,
+            write_cache_enabled: command_set_valid
+                && data.command_set_enabled1 & Self::WRITE_CACHE != 0,
+            flush_cache_ext_supported: command_set_valid
+                && data.command_set_supported2 & Self::FLUSH_CACHE_EXT != 0,
+        }
+    }
 }
 
 bitfield! {
@@ -121,3 +235,191 @@ pub struct PrdtEntry {
     pub _reserved: u32,
     pub flags: u32,
 }
+
+/// the byte count field is 22 bits wide
+pub const PRDT_ENTRY_MAX_BYTES: usize = 1 << 22;
+/// as far as an hba without 64 bit addressing reaches
+pub const DMA_32BIT_LIMIT: u64 = 1 << 32;
+
+impl PrdtEntry {
+    /// points the controller at `len` bytes of the kernel buffer at `virt`. The buffer has to be
+    /// word aligned, an even number of bytes and physically contiguous, anything else would have
+    /// the controller read or write memory the buffer doesn't own. Without `addr64`, the hba's
+    /// CAP.S64A, it also has to lie below 4GiB
+    pub fn for_buffer(virt: u64, len: usize, interrupt: bool, addr64: bool) -> Result<Self, IoErr> {
+        if !len.is_multiple_of(2) || len > PRDT_ENTRY_MAX_BYTES {
+            return Err(IoErr::DmaUnreachable);
+        }
+
+        let phys = kernel_dma_range(virt, len)?.as_u64();
+        if !phys.is_multiple_of(2) || (!addr64 && phys + len as u64 > DMA_32BIT_LIMIT) {
+            return Err(IoErr::DmaUnreachable);
+        }
+
+        let mut flags = PrdtEntryFlags(0);
+        flags.set_interrupt(interrupt);
+        flags.set_byte_count(len as u32 - 1);
+
+        Ok(Self {
+            data_base_low: phys as u32,
+            data_base_high: (phys >> 32) as u32,
+            flags: flags.0,
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use x86_64::{
+        VirtAddr,
+        structures::paging::{FrameAllocator, Page, PageTableFlags},
+    };
+
+    use super::{AtaDriveInfo, DMA_32BIT_LIMIT, IdentifyData, PrdtEntry};
+    use crate::{
+        arch::x86_64::memory::{
+            PAGE_SIZE, frame_allocator::FRAME_ALLOCATOR, page_table::KERNEL_PAGE_TABLE,
+        },
+        drivers::ata::dma_buffer::DmaBuffer,
+        end_test,
+        hal::storage::IoErr,
+        test_name,
+    };
+
+    fn put_ata_string(dst: &mut [u8], s: &str) {
+        dst.fill(b' ');
+        dst[..s.len()].copy_from_slice(s.as_bytes());
+        for pair in dst.chunks_exact_mut(2) {
+            pair.swap(0, 1);
+        }
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn identify_qemu_harddisk() {
+        test_name!("identify data of a qemu disk");
+
+        // the fields qemu's ide-hd fills in for a disk with a 4K physical block size
+        let mut data = IdentifyData::default();
+        put_ata_string(&mut data.serial, "QM00001");
+        put_ata_string(&mut data.firmware_rev, "2.5+");
+        put_ata_string(&mut data.model, "QEMU HARDDISK");
+        data.command_set_supported1 = 0x1 << 14 | 0x1 << 5 | 0x1;
+        data.command_set_supported2 = 0x1 << 14 | 0x1 << 13 | 0x1 << 12 | 0x1 << 10;
+        data.command_set_enabled1 = 0x1 << 14 | 0x1 << 5 | 0x1;
+        data.sector_size_info = 0x4000 | 0x1 << 13 | 3;
+
+        let info = AtaDriveInfo::from(&data);
+        assert_eq!(info.model(), "QEMU HARDDISK");
+        assert_eq!(info.serial(), "QM00001");
+        assert_eq!(info.firmware_rev(), "2.5+");
+        assert_eq!(info.logical_sector_size, 512);
+        assert_eq!(info.physical_sector_size, 4096);
+        assert!(info.write_cache_supported);
+        assert!(info.write_cache_enabled);
+        assert!(info.flush_cache_ext_supported);
+
+        // a 4K native drive
+        data.sector_size_info = 0x4000 | 0x1 << 12;
+        data.logical_sector_words = [2048, 0];
+        let info = AtaDriveInfo::from(&data);
+        assert_eq!(info.logical_sector_size, 4096);
+        assert_eq!(info.physical_sector_size, 4096);
+
+        // nothing is reported without the validity bits
+        let info = AtaDriveInfo::from(&IdentifyData::default());
+        assert_eq!(info.model(), "");
+        assert_eq!(info.logical_sector_size, 512);
+        assert!(!info.flush_cache_ext_supported);
+
+        end_test!();
+    }
+
+    /// nothing else is mapped this low in the kernel's address space
+    const SCATTERED_PAGES: u64 = 0x5000_0000;
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn prdt_rejects_scattered_buffers() {
+        test_name!("the prdt builder only takes dma safe buffers");
+
+        let page = PAGE_SIZE as usize;
+
+        // two pages next to each other in virtual memory but not in physical memory
+        let frames = {
+            let mut allocator = FRAME_ALLOCATOR
+                .get()
+                .expect("Failed to get the frame allocator")
+                .spin_acquire_lock();
+            [(); 3].map(|_| {
+                allocator
+                    .allocate_frame(&mut None)
+                    .expect("Failed to get physical frame")
+            })
+        };
+
+        let table = KERNEL_PAGE_TABLE
+            .get()
+            .expect("Failed to get kernel page table")
+            .spin_acquire_lock();
+        for (idx, frame) in [frames[2], frames[0]].into_iter().enumerate() {
+            table.map_to(
+                Page::containing_address(VirtAddr::new(SCATTERED_PAGES + (idx * page) as u64)),
+                frame,
+                PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+                &mut None,
+            );
+        }
+        drop(table);
+
+        assert!(matches!(
+            PrdtEntry::for_buffer(SCATTERED_PAGES, 2 * page, false, true),
+            Err(IoErr::DmaUnreachable)
+        ));
+        // either page on its own is fine
+        let entry =
+            PrdtEntry::for_buffer(SCATTERED_PAGES + page as u64, page, false, true).unwrap();
+        let base = entry.data_base_low as u64 | (entry.data_base_high as u64) << 32;
+        assert_eq!(base, frames[0].start_address().as_u64());
+
+        // misaligned, odd sized or not mapped at all
+        assert!(PrdtEntry::for_buffer(SCATTERED_PAGES + 1, 512, false, true).is_err());
+        assert!(PrdtEntry::for_buffer(SCATTERED_PAGES, 511, false, true).is_err());
+        assert!(
+            PrdtEntry::for_buffer(SCATTERED_PAGES + 2 * page as u64, 512, false, true).is_err()
+        );
+
+        // what the driver uses instead spans pages just fine
+        let buffer = DmaBuffer::new(2 * page).expect("Failed to get a dma buffer");
+        let virt = buffer.virt_addr().as_u64();
+        let entry = PrdtEntry::for_buffer(virt, buffer.len(), false, true).unwrap();
+        let base = entry.data_base_low as u64 | (entry.data_base_high as u64) << 32;
+        assert_eq!(base, buffer.phys_addr().as_u64());
+        assert!(buffer.as_slice().iter().all(|&byte| byte == 0));
+
+        // an hba without S64A only takes it if it's in the low 4GiB
+        assert_eq!(
+            PrdtEntry::for_buffer(virt, buffer.len(), false, false).is_ok(),
+            base + buffer.len() as u64 <= DMA_32BIT_LIMIT
+        );
+
+        let table = KERNEL_PAGE_TABLE
+            .get()
+            .expect("Failed to get kernel page table")
+            .spin_acquire_lock();
+        for idx in 0..2 {
+            table.unmap(Page::containing_address(VirtAddr::new(
+                SCATTERED_PAGES + (idx * page) as u64,
+            )));
+        }
+        drop(table);
+        FRAME_ALLOCATOR
+            .get()
+            .expect("Failed to get the frame allocator")
+            .spin_acquire_lock()
+            .free_frames(&frames);
+
+        end_test!();
+    }
+}
@@ -1,11 +1,11 @@
 use crate::{
-    arch::x86_64::memory::get_hhdm_offset,
     drivers::ata::sata::{
         AhciSata,
-        command::{CommandHeader, CommandHeaderFlags, CommandTable, PrdtEntry, PrdtEntryFlags},
+        command::{CommandHeader, CommandHeaderFlags, CommandTable, PrdtEntry},
         fis::{self, AtaCommand, DEVICE_LBA_MODE, FORCE_UNIT_FLUSH, FisRegH2DFlags},
+        task::AhciErr,
     },
-    hal::{buffer::Buffer, storage::SECTOR_SIZE},
+    hal::buffer::Buffer,
     log,
 };
 
@@ -15,13 +15,19 @@ impl AhciSata {
         self.identify_data.command_set_supported2 & LBA_48_SUPPORTED_MASK != 0
     }
 
-    pub async fn start_read_sectors(&mut self, cmd_queue_idx: usize, lba: i64, buffer: Buffer) {
+    pub async fn start_read_sectors(
+        &mut self,
+        cmd_queue_idx: usize,
+        lba: i64,
+        buffer: Buffer,
+    ) -> Result<(), AhciErr> {
         // only supports lba48
         if !self.lba48_supported() {
-            return;
+            return Ok(());
         }
 
-        let count = (buffer.len() / SECTOR_SIZE) as u16;
+        let sector_size = self.drive_info.logical_sector_size;
+        let count = (buffer.len() / sector_size as usize) as u16;
 
         let lba: u64 = if lba < 0 {
             self.identify_data.lba48_sectors + lba as u64
@@ -34,12 +40,17 @@ impl AhciSata {
         let cmd_tables_phys_addr = (self.dma_20kb_buffer_paddr
             + Self::nth_command_table_offset(cmd_queue_idx as u64))
         .as_u64();
+        let addr64 = self.hba_ports.supports_64bit_addressing();
         // use the first slot
         let buf = self.get_buffer();
 
-        // this is to make sure the buffer is 32 bytes aligned
-        let result_buf_ptr = (buffer.inner as u64) - get_hhdm_offset().as_u64();
-        assert_eq!(result_buf_ptr % 4, 0);
+        let prdt_entry = PrdtEntry::for_buffer(
+            buffer.inner as u64,
+            count as usize * sector_size as usize,
+            false,
+            addr64,
+        )
+        .map_err(AhciErr::Dma)?;
 
         let cmd_table: &mut CommandTable = bytemuck::from_bytes_mut(
             &mut buf[Self::nth_command_table_offset(cmd_queue_idx as u64) as usize
@@ -66,16 +77,7 @@ impl AhciSata {
             ..Default::default()
         };
 
-        let mut prdt_flags = PrdtEntryFlags(0);
-        prdt_flags.set_interrupt(false);
-        prdt_flags.set_byte_count((count as u32 * SECTOR_SIZE as u32) - 1);
-
-        cmd_table.prdt_table[0] = PrdtEntry {
-            data_base_low: result_buf_ptr as u32,
-            data_base_high: (result_buf_ptr >> 32) as u32,
-            flags: prdt_flags.0,
-            ..Default::default()
-        };
+        cmd_table.prdt_table[0] = prdt_entry;
 
         let cmd_header: &mut CommandHeader = bytemuck::from_bytes_mut(
             &mut buf[cmd_queue_idx * size_of::<CommandHeader>()
@@ -138,17 +140,25 @@ impl AhciSata {
         // }
         //
         // log!("{}", buffer);
+
+        Ok(())
     }
 
     /// this will be mainly used for page cache, the buffer will be a page
     /// doesn't check the 4gib boundary
-    pub async fn start_write_sectors(&mut self, cmd_queue_idx: usize, lba: i64, buffer: Buffer) {
+    pub async fn start_write_sectors(
+        &mut self,
+        cmd_queue_idx: usize,
+        lba: i64,
+        buffer: Buffer,
+    ) -> Result<(), AhciErr> {
         // only supports lba48
         if !self.lba48_supported() {
-            return;
+            return Ok(());
         }
 
-        let count = (buffer.len() / SECTOR_SIZE) as u16;
+        let sector_size = self.drive_info.logical_sector_size;
+        let count = (buffer.len() / sector_size as usize) as u16;
 
         let lba: u64 = if lba < 0 {
             self.identify_data.lba48_sectors + lba as u64
@@ -159,12 +169,17 @@ impl AhciSata {
         let cmd_tables_phys_addr = (self.dma_20kb_buffer_paddr
             + Self::nth_command_table_offset(cmd_queue_idx as u64))
         .as_u64();
+        let addr64 = self.hba_ports.supports_64bit_addressing();
         // use the first slot
         let buf = self.get_buffer();
 
-        // this is to make sure the buffer is 32 bytes aligned
-        let result_buf_ptr = (buffer.inner as u64) - get_hhdm_offset().as_u64();
-        assert_eq!(result_buf_ptr % 4, 0);
+        let prdt_entry = PrdtEntry::for_buffer(
+            buffer.inner as u64,
+            count as usize * sector_size as usize,
+            false,
+            addr64,
+        )
+        .map_err(AhciErr::Dma)?;
 
         let cmd_table: &mut CommandTable = bytemuck::from_bytes_mut(
             &mut buf[Self::nth_command_table_offset(cmd_queue_idx as u64) as usize
@@ -192,16 +207,7 @@ impl AhciSata {
             ..Default::default()
         };
 
-        let mut prdt_flags = PrdtEntryFlags(0);
-        prdt_flags.set_interrupt(false);
-        prdt_flags.set_byte_count((count as u32 * SECTOR_SIZE as u32) - 1);
-
-        cmd_table.prdt_table[0] = PrdtEntry {
-            data_base_low: result_buf_ptr as u32,
-            data_base_high: (result_buf_ptr >> 32) as u32,
-            flags: prdt_flags.0,
-            ..Default::default()
-        };
+        cmd_table.prdt_table[0] = prdt_entry;
 
         let cmd_header: &mut CommandHeader = bytemuck::from_bytes_mut(
             &mut buf[cmd_queue_idx * size_of::<CommandHeader>()
@@ -228,6 +234,8 @@ impl AhciSata {
         core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
 
         self.ports.write_command_issue(0x1 << cmd_queue_idx);
+
+        Ok(())
     }
 
     pub async fn issue_flush(&mut self, cmd_queue_idx: usize) {
@@ -1,6 +1,6 @@
 use core::time::Duration;
 
-use alloc::{boxed::Box, vec};
+use alloc::boxed::Box;
 use bitfield::bitfield;
 use x86_64::{PhysAddr, VirtAddr, structures::paging::Page};
 
@@ -13,13 +13,16 @@ use crate::{
         },
         timer::Instant,
     },
-    drivers::ata::sata::{
-        ahci::AhciHbaPorts,
-        command::{
-            CommandHeader, CommandHeaderFlags, CommandTable, IdentifyData, PrdtEntry,
-            PrdtEntryFlags,
+    drivers::ata::{
+        dma_buffer::DmaBuffer,
+        sata::{
+            ahci::AhciHbaPorts,
+            command::{
+                AtaDriveInfo, CommandHeader, CommandHeaderFlags, CommandTable, IdentifyData,
+                PrdtEntry,
+            },
+            fis::{AtaCommand, FisRegH2DFlags},
         },
-        fis::{AtaCommand, FisRegH2DFlags},
     },
     ejcineque::{futures::yield_now, sync::mpsc::unbounded::UnboundedReceiver},
     hal::storage::{HalBlockDevice, HalStorageOperation, SECTOR_SIZE},
@@ -73,6 +76,7 @@ bitfield! {
     pub interface_comm_control, set_interface_comm_control: 31, 28;       // ICC: Interface Communication Control
 }
 
+#[derive(Debug)]
 pub struct TimeOut {}
 
 #[derive(Debug)]
@@ -88,6 +92,7 @@ pub struct AhciSata {
     pub dma_20kb_buffer_paddr: PhysAddr,
     pub max_cmd_slots: u64,
     pub identify_data: IdentifyData,
+    pub drive_info: AtaDriveInfo,
     pub hba_idx: usize,
     pub ports_idx: usize,
 }
@@ -341,6 +346,7 @@ impl AhciSata {
             dma_20kb_buffer_paddr: frames[0].start_address(),
             max_cmd_slots,
             identify_data: IdentifyData::default(),
+            drive_info: AtaDriveInfo::default(),
             hba_idx,
             ports_idx,
         })
@@ -357,8 +363,47 @@ impl AhciSata {
             == 0
     }
 
-    pub async fn com_reset(&mut self) {
-        todo!()
+    /// polls the port until `cond` holds, yielding to the executor in between
+    async fn wait_until(
+        &mut self,
+        timeout: Duration,
+        cond: impl Fn(&mut AhciSataPorts) -> bool,
+    ) -> Result<(), TimeOut> {
+        let start = Instant::now();
+        loop {
+            if cond(&mut self.ports) {
+                return Ok(());
+            }
+
+            if Instant::now() - start > timeout {
+                return Err(TimeOut {});
+            }
+
+            yield_now().await;
+        }
+    }
+
+    /// resets the phy, the command engine has to be stopped before this is called
+    pub async fn com_reset(&mut self) -> Result<(), TimeOut> {
+        let mut control_port = PortControl(self.ports.read_sata_control());
+        control_port.set_det_init(PortControl::DET_COMRESET);
+        self.ports.write_sata_control(control_port.0);
+
+        // DET has to stay at 1 for at least 1ms so the device sees the COMRESET
+        let start = Instant::now();
+        while Instant::now() - start < Duration::from_millis(1) {
+            yield_now().await;
+        }
+
+        let mut control_port = PortControl(self.ports.read_sata_control());
+        control_port.set_det_init(PortControl::DET_NO_ACTION);
+        self.ports.write_sata_control(control_port.0);
+
+        self.wait_until(Duration::from_secs(1), |ports| {
+            PortStatus(ports.read_sata_status()).device_detection()
+                == PortStatus::DET_PRESENT_WITH_PHY
+        })
+        .await
     }
 
     fn reset_cmd(&mut self) {
@@ -392,101 +437,78 @@ impl AhciSata {
         Ok(())
     }
 
-    pub async fn failure_reset(&mut self) {
-        'outer: loop {
-            self.disable_interrupts();
-            self.reset_cmd();
-
-            let time = Instant::now();
-            'inner: loop {
-                let cmd_status = self.ports.read_command_and_status();
-                let cur = Instant::now();
-                if cur - time > Duration::from_secs(1) {
-                    continue 'outer;
-                }
-
-                if cmd_status & (Self::COMMAND_LIST_RUNNING | Self::FIS_RECEIVE_RUNNING) == 0 {
-                    break 'inner;
-                }
-                yield_now().await;
-            }
-
-            let mut control_port = PortControl(self.ports.read_sata_control());
-            control_port.set_det_init(PortControl::DET_COMRESET);
-            self.ports.write_sata_control(control_port.0);
-
-            let start = Instant::now();
+    /// stops the port, COMRESETs the device and brings the command engine back up
+    /// every command that was issued is lost and has to be reissued by the caller
+    pub async fn failure_reset(&mut self) -> Result<(), TimeOut> {
+        self.disable_interrupts();
 
-            loop {
-                if PortStatus(self.ports.read_sata_status()).device_detection()
-                    == PortStatus::DET_PRESENT_WITH_PHY
-                {
-                    break;
-                }
+        let mut cmd = PortCmdAndStatus(self.ports.read_command_and_status());
+        cmd.set_start(false);
+        self.ports.write_command_and_status(cmd.0);
 
-                let now = Instant::now();
-                if now - start >= Duration::from_secs(1) {
-                    continue 'outer;
-                }
+        self.wait_until(Duration::from_millis(500), |ports| {
+            !PortCmdAndStatus(ports.read_command_and_status()).cmd_list_running()
+        })
+        .await?;
 
-                yield_now().await;
-            }
+        let mut cmd = PortCmdAndStatus(self.ports.read_command_and_status());
+        cmd.set_fis_recv_enable(false);
+        self.ports.write_command_and_status(cmd.0);
 
-            let mut control_port = PortControl(self.ports.read_sata_control());
-            control_port.set_det_init(PortControl::DET_NO_ACTION);
-            self.ports.write_sata_control(control_port.0);
+        self.wait_until(Duration::from_millis(500), |ports| {
+            !PortCmdAndStatus(ports.read_command_and_status()).fis_recv_running()
+        })
+        .await?;
 
-            self.ports
-                .write_command_list_base_lower(self.dma_20kb_buffer_paddr.as_u64() as u32);
-            self.ports
-                .write_command_list_base_higher((self.dma_20kb_buffer_paddr.as_u64() >> 32) as u32);
+        self.com_reset().await?;
 
-            let received_fis_area = self.dma_20kb_buffer_paddr.as_u64() + RECEIVED_FIS_AREA_OFFSET;
+        self.ports
+            .write_command_list_base_lower(self.dma_20kb_buffer_paddr.as_u64() as u32);
+        self.ports
+            .write_command_list_base_higher((self.dma_20kb_buffer_paddr.as_u64() >> 32) as u32);
 
-            self.ports.write_fis_base_lower(received_fis_area as u32);
-            self.ports
-                .write_fis_base_higher((received_fis_area >> 32) as u32);
+        let received_fis_area = self.dma_20kb_buffer_paddr.as_u64() + RECEIVED_FIS_AREA_OFFSET;
 
-            // resets sata error
-            self.ports.write_sata_error(0xFFFFFFFF);
-            // this only writes to the non-reserved bits
-            // self.write_sata_error(0b00000_11111_11111_1_0000_1111_000000_11);
-            self.ports.write_interrupt_status(0);
+        self.ports.write_fis_base_lower(received_fis_area as u32);
+        self.ports
+            .write_fis_base_higher((received_fis_area >> 32) as u32);
 
-            let start = Instant::now();
-            loop {
-                let tfd = self.ports.read_task_file_data();
-                if (tfd & 0x88) == 0 {
-                    break;
-                } // BSY and DRQ are bits 7 and 3
-                if Instant::now() - start > Duration::from_secs(1) {
-                    log!("Timeout waiting for port to become non-busy");
-                    continue 'outer;
-                }
-                yield_now().await;
-            }
+        // resets sata error
+        self.ports.write_sata_error(0xFFFFFFFF);
+        self.ports
+            .write_interrupt_status(self.ports.read_interrupt_status());
+
+        // BSY and DRQ are bits 7 and 3
+        if let Err(err) = self
+            .wait_until(Duration::from_secs(1), |ports| {
+                ports.read_task_file_data() & 0x88 == 0
+            })
+            .await
+        {
+            log!("Timeout waiting for port to become non-busy");
+            return Err(err);
+        }
 
-            let mut cmd = PortCmdAndStatus(self.ports.read_command_and_status());
-            cmd.set_fis_recv_enable(true);
-            self.ports.write_command_and_status(cmd.0);
+        let mut cmd = PortCmdAndStatus(self.ports.read_command_and_status());
+        cmd.set_fis_recv_enable(true);
+        self.ports.write_command_and_status(cmd.0);
 
-            while !PortCmdAndStatus(self.ports.read_command_and_status()).fis_recv_running() {
-                core::hint::spin_loop();
-                yield_now().await;
-            }
+        self.wait_until(Duration::from_millis(500), |ports| {
+            PortCmdAndStatus(ports.read_command_and_status()).fis_recv_running()
+        })
+        .await?;
 
-            cmd.set_start(true);
-            self.ports.write_command_and_status(cmd.0);
+        cmd.set_start(true);
+        self.ports.write_command_and_status(cmd.0);
 
-            while !PortCmdAndStatus(self.ports.read_command_and_status()).cmd_list_running() {
-                core::hint::spin_loop();
-                yield_now().await;
-            }
+        self.wait_until(Duration::from_millis(500), |ports| {
+            PortCmdAndStatus(ports.read_command_and_status()).cmd_list_running()
+        })
+        .await?;
 
-            self.enable_interrupts();
+        self.enable_interrupts();
 
-            break 'outer;
-        }
+        Ok(())
     }
 
     pub fn init(&mut self) -> Result<(), TimeOut> {
@@ -627,12 +649,11 @@ impl AhciSata {
 
     pub fn identify(&mut self) {
         let cmd_tables_phys_addr = (self.dma_20kb_buffer_paddr + CMD_TABLES_OFFSET).as_u64();
+        let addr64 = self.hba_ports.supports_64bit_addressing();
         // use the first slot
         let buf = self.get_buffer();
 
-        // this is to make sure the buffer is 32 bytes aligned
-        let result_buf = vec![0u32; SECTOR_SIZE / 4].into_boxed_slice();
-        let result_buf_ptr = (result_buf.as_ptr() as u64) - get_hhdm_offset().as_u64();
+        let result_buf = DmaBuffer::new(SECTOR_SIZE).expect("Failed to get a dma buffer");
 
         let cmd_table: &mut CommandTable = bytemuck::from_bytes_mut(
             &mut buf[Self::nth_command_table_offset(0) as usize
@@ -649,16 +670,13 @@ impl AhciSata {
             ..Default::default()
         };
 
-        let mut prdt_flags = PrdtEntryFlags(0);
-        prdt_flags.set_interrupt(false);
-        prdt_flags.set_byte_count(SECTOR_SIZE as u32 - 1);
-
-        cmd_table.prdt_table[0] = PrdtEntry {
-            data_base_low: result_buf_ptr as u32,
-            data_base_high: (result_buf_ptr >> 32) as u32,
-            flags: prdt_flags.0,
-            ..Default::default()
-        };
+        cmd_table.prdt_table[0] = PrdtEntry::for_buffer(
+            result_buf.virt_addr().as_u64(),
+            result_buf.len(),
+            false,
+            addr64,
+        )
+        .expect("The identify buffer is always dma safe");
 
         let cmd_header: &mut CommandHeader =
             bytemuck::from_bytes_mut(&mut buf[0..size_of::<CommandHeader>()]);
@@ -704,11 +722,24 @@ impl AhciSata {
             panic!("The disk is still busy or requesting data despite CI being 0!");
         }
 
-        let identify_data = &unsafe { *(result_buf.as_ptr() as *const IdentifyData) };
+        let identify_data =
+            &unsafe { (result_buf.as_slice().as_ptr() as *const IdentifyData).read_unaligned() };
 
         log!("{:?}", identify_data);
 
         self.identify_data = *identify_data;
+        self.drive_info = AtaDriveInfo::from(identify_data);
+
+        log!(
+            "model: {}, serial: {}, firmware: {}, sectors: {}/{} bytes (logical/physical), write cache: {}, flush cache ext: {}",
+            self.drive_info.model(),
+            self.drive_info.serial(),
+            self.drive_info.firmware_rev(),
+            self.drive_info.logical_sector_size,
+            self.drive_info.physical_sector_size,
+            self.drive_info.write_cache_supported,
+            self.drive_info.flush_cache_ext_supported
+        );
 
         // self.ports
         //     .write_interrupt_status(self.ports.read_interrupt_status());
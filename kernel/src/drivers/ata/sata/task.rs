@@ -1,4 +1,4 @@
-use core::{ops::DerefMut, sync::atomic::AtomicU8, task::Waker};
+use core::{ops::DerefMut, sync::atomic::AtomicU8, time::Duration};
 
 use alloc::string::ToString;
 use lazy_static::lazy_static;
@@ -7,6 +7,7 @@ use thiserror::Error;
 use x86_64::{VirtAddr, instructions::interrupts::without_interrupts};
 
 use crate::{
+    arch::x86_64::timer::Instant,
     drivers::ata::sata::{
         AhciSata, AhciSataPorts, AtaError, PortCmdAndStatus, PortInterruptStatus, PortSataError,
         PortTaskFileData,
@@ -20,12 +21,17 @@ use crate::{
             spin::SpinMutex,
         },
     },
-    hal::storage::{HalIdentifyData, HalStorageOperation},
+    hal::storage::{HalIdentifyData, HalStorageOperation, IoErr},
     log,
 };
 
 pub static CUR_AHCI_IDX: AtomicU8 = AtomicU8::new(0x0);
 
+/// how long a command may stay in flight before the port gets reset
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+/// timer ticks between two checks for timed out commands
+const TIMEOUT_CHECK_TIME: Duration = Duration::from_millis(10);
+
 lazy_static! {
     /// max support 8 ahci's
     pub static ref AHCI_SENDERS_MAP: [[SpinMutex<Option<UnboundedSender<AhciSataInterruptData>>>; 32]; 8] = Default::default();
@@ -37,6 +43,9 @@ lazy_static! {
 #[derive(Debug)]
 pub struct AhciTaskState {
     pub operations: [Option<HalStorageOperation>; 32],
+    pub issued_at: [Option<Instant>; 32],
+    /// whether the operation in the slot has already been reissued after a timeout
+    pub retried: [bool; 32],
     pub remaining_operations: u64,
 }
 
@@ -46,6 +55,10 @@ pub enum AhciErr {
     ATA(AtaError),
     #[error("Internal drive error")]
     Internal,
+    #[error("Command timed out")]
+    TimedOut,
+    #[error("The buffer can't be used for DMA: {0}")]
+    Dma(IoErr),
 }
 
 impl AhciSata {
@@ -102,7 +115,9 @@ impl AhciSata {
                 }
             }
 
-            self.failure_reset().await;
+            if let Err(err) = self.failure_reset().await {
+                log!("failed to reset the port: {:?}", err);
+            }
 
             return;
         }
@@ -115,7 +130,9 @@ impl AhciSata {
             }
 
             log!("interface non fatal error");
-            self.com_reset().await;
+            if let Err(err) = self.failure_reset().await {
+                log!("failed to reset the port: {:?}", err);
+            }
         }
 
         if interrupt_status.host_bus_data_error() {
@@ -126,7 +143,9 @@ impl AhciSata {
             }
 
             log!("host bus data error");
-            self.com_reset().await;
+            if let Err(err) = self.failure_reset().await {
+                log!("failed to reset the port: {:?}", err);
+            }
         }
 
         if interrupt_status.task_file_error() {
@@ -194,23 +213,71 @@ impl AhciSata {
         op: HalStorageOperation,
         state: &mut AhciTaskState,
     ) {
-        match &op {
+        let result = match &op {
             HalStorageOperation::Read { buffer, lba, .. } => {
-                self.start_read_sectors(i, *lba, buffer.clone()).await;
+                self.start_read_sectors(i, *lba, buffer.clone()).await
             }
 
             HalStorageOperation::Write { buffer, lba, .. } => {
-                self.start_write_sectors(i, *lba, buffer.clone()).await;
+                self.start_write_sectors(i, *lba, buffer.clone()).await
             }
 
             HalStorageOperation::Flush { .. } => {
                 self.issue_flush(i).await;
+                Ok(())
             }
 
-            _ => {}
+            _ => Ok(()),
+        };
+
+        if let Err(err) = result {
+            self.finish_operation(op, Some(err), state);
+            return;
         }
 
         state.operations[i] = Some(op);
+        state.issued_at[i] = Some(Instant::now());
+    }
+
+    /// resets the port if any command has been in flight for too long, everything that was in
+    /// flight is reissued, except commands that already timed out once
+    async fn check_timeouts(&mut self, state: &mut AhciTaskState) {
+        let now = Instant::now();
+        let timed_out: u32 = (0..32)
+            .filter(|&i| {
+                state.operations[i].is_some()
+                    && state.issued_at[i].is_some_and(|t| now - t > COMMAND_TIMEOUT)
+            })
+            .fold(0, |mask, i| mask | (0x1 << i));
+
+        if timed_out == 0 {
+            return;
+        }
+
+        log!("ahci commands timed out: {:#b}", timed_out);
+
+        let reset = self.failure_reset().await;
+        if let Err(err) = &reset {
+            log!("failed to reset the port: {:?}", err);
+        }
+
+        for i in 0..32 {
+            let Some(op) = state.operations[i].take() else {
+                continue;
+            };
+
+            let is_timed_out = timed_out & (0x1 << i) != 0;
+            if reset.is_err() || (is_timed_out && state.retried[i]) {
+                self.finish_operation(op, Some(AhciErr::TimedOut), state);
+                continue;
+            }
+
+            if is_timed_out {
+                state.retried[i] = true;
+            }
+
+            self.launch_operation(i, op, state).await;
+        }
     }
 
     async fn start_operation(&mut self, op: HalStorageOperation, state: &mut AhciTaskState) {
@@ -226,6 +293,7 @@ impl AhciSata {
 
         for i in 0..=self.max_cmd_slots as usize {
             if state.operations[i].is_none() {
+                state.retried[i] = false;
                 self.launch_operation(i, op, state).await;
 
                 break;
@@ -239,6 +307,8 @@ impl AhciSata {
 
         let mut state = AhciTaskState {
             operations,
+            issued_at: [None; 32],
+            retried: [false; 32],
             remaining_operations,
         };
 
@@ -247,25 +317,29 @@ impl AhciSata {
         *AHCI_SENDERS_MAP[self.hba_idx][self.ports_idx].lock() = Some(ahci_tx);
 
         loop {
-            let sata_future = ahci_rx.recv();
+            // the timer wakes the task up every now and then to look for stuck commands
+            let sata_future = ejcineque::futures::race::race(
+                ahci_rx.recv(),
+                ejcineque::time::wait(TIMEOUT_CHECK_TIME),
+            );
 
-            if remaining_operations > 0 {
+            if state.remaining_operations > 0 {
                 let combined_future = ejcineque::futures::race::race(rx.recv(), sata_future);
 
                 match combined_future.await {
                     Either::Left(Some(op)) => {
                         self.start_operation(op, &mut state).await;
                     }
-                    Either::Right(Some(data)) => {
+                    Either::Right(Either::Left(Some(data))) => {
                         self.handle_interrupt(&mut state, data).await;
                     }
                     _ => {}
                 }
-            } else {
-                if let Some(data) = sata_future.await {
-                    self.handle_interrupt(&mut state, data).await;
-                }
+            } else if let Either::Left(Some(data)) = sata_future.await {
+                self.handle_interrupt(&mut state, data).await;
             }
+
+            self.check_timeouts(&mut state).await;
         }
     }
 }
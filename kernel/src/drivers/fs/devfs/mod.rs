@@ -0,0 +1,447 @@
+use alloc::{boxed::Box, collections::btree_map::BTreeMap, format, string::String, vec};
+use dvida_serialize::DvSerialize;
+
+use crate::{
+    drivers::fs::ext2::{EXT2_FT_BLKDEV, EXT2_S_IFBLK, EXT2_S_IFDIR},
+    hal::{
+        buffer::Buffer,
+        fs::{DirEnt64, FileStat, HalFsIOErr, HalIOCtx, HalInode, OpenFlags, OpenFlagsValue},
+        gpt::GptReader,
+        path::Path,
+        storage,
+    },
+    log,
+};
+
+/// a whole disk or one of its partitions, lbas are absolute on the device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DevNode {
+    pub device_idx: usize,
+    pub start_lba: i64,
+    pub sector_count: u64,
+    /// the logical sector size of the device, 4096 on 4kn drives
+    pub sector_size: usize,
+}
+
+impl DevNode {
+    pub fn size(&self) -> u64 {
+        self.sector_count * self.sector_size as u64
+    }
+}
+
+/// the sectors covering a byte range
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SectorSpan {
+    pub first_lba: i64,
+    pub count: usize,
+    /// where the range starts inside the first sector
+    pub offset: usize,
+    pub sector_size: usize,
+}
+
+impl SectorSpan {
+    pub fn new(head: usize, len: usize, sector_size: usize) -> Self {
+        let first = head / sector_size;
+        let last = (head + len).div_ceil(sector_size);
+
+        Self {
+            first_lba: first as i64,
+            count: last - first,
+            offset: head % sector_size,
+            sector_size,
+        }
+    }
+
+    pub fn is_aligned(&self, len: usize) -> bool {
+        self.offset == 0 && len.is_multiple_of(self.sector_size)
+    }
+
+    pub fn byte_len(&self) -> usize {
+        self.count * self.sector_size
+    }
+}
+
+/// sda, sdb, ..., sdz, sdaa, ...
+pub fn disk_name(device_idx: usize) -> String {
+    let mut suffix = vec![];
+    let mut idx = device_idx;
+
+    loop {
+        suffix.push(b'a' + (idx % 26) as u8);
+        if idx < 26 {
+            break;
+        }
+        idx = idx / 26 - 1;
+    }
+    suffix.reverse();
+
+    format!("sd{}", String::from_utf8_lossy(&suffix))
+}
+
+/// None is the /dev directory itself
+#[derive(Debug, Clone)]
+pub struct DevfsInode {
+    pub name: Option<String>,
+}
+
+/// exposes every storage device, and every partition of a gpt disk, as a file
+#[derive(Debug, Default)]
+pub struct Devfs {
+    pub nodes: BTreeMap<String, DevNode>,
+}
+
+impl Devfs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// builds the nodes from the storage registry, the drives have to be running already
+    pub async fn scan() -> Self {
+        let mut devfs = Self::new();
+
+        for device_idx in storage::storage_device_indices() {
+            let disk = disk_name(device_idx);
+
+            let sector_count = match storage::get_identify_data(device_idx).await {
+                Ok(data) => data.sector_count,
+                Err(e) => {
+                    log!("devfs: failed to identify device {}: {:?}", device_idx, e);
+                    continue;
+                }
+            };
+            let sector_size = match storage::logical_sector_size_by_idx(device_idx) {
+                Ok(sector_size) => sector_size,
+                Err(e) => {
+                    log!(
+                        "devfs: failed to get the sector size of {}: {:?}",
+                        device_idx,
+                        e
+                    );
+                    continue;
+                }
+            };
+
+            devfs.nodes.insert(
+                disk.clone(),
+                DevNode {
+                    device_idx,
+                    start_lba: 0,
+                    sector_count,
+                    sector_size,
+                },
+            );
+
+            // disks without a gpt only get the whole disk node
+            let Ok((_, entries)) = GptReader::new(device_idx).read_gpt().await else {
+                continue;
+            };
+
+            for (i, entry) in entries.iter().enumerate() {
+                if entry.is_empty() {
+                    continue;
+                }
+
+                devfs.nodes.insert(
+                    format!("{}{}", disk, i + 1),
+                    DevNode {
+                        device_idx,
+                        start_lba: entry.start_lba as i64,
+                        sector_count: entry.end_lba - entry.start_lba + 1,
+                        sector_size,
+                    },
+                );
+            }
+        }
+
+        devfs
+    }
+
+    fn node(&self, inode: &DevfsInode) -> Result<DevNode, HalFsIOErr> {
+        let name = inode.name.as_ref().ok_or(HalFsIOErr::IsDirectory)?;
+
+        self.nodes
+            .get(name)
+            .copied()
+            .ok_or(HalFsIOErr::NoSuchFileOrDirectory)
+    }
+
+    pub fn resolve_path(&self, path: &Path) -> Result<DevfsInode, HalFsIOErr> {
+        let path = path.normalize();
+        let mut components = path.components();
+
+        match (components.next(), components.next()) {
+            (None, _) => Ok(DevfsInode { name: None }),
+            (Some(name), None) if self.nodes.contains_key(&name) => {
+                Ok(DevfsInode { name: Some(name) })
+            }
+            _ => Err(HalFsIOErr::NoSuchFileOrDirectory),
+        }
+    }
+
+    pub fn open_file(&mut self, path: Path, flags: OpenFlags) -> Result<HalInode, HalFsIOErr> {
+        match self.resolve_path(&path) {
+            Ok(inode) => Ok(HalInode::Devfs(inode)),
+            // device nodes come from the hardware, they can't be made by hand
+            Err(_) if flags.flags & OpenFlagsValue::CreateIfNotExist as i32 != 0 => {
+                Err(HalFsIOErr::Unsupported)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn read_span(&self, node: &DevNode, span: &SectorSpan) -> Result<Box<[u8]>, HalFsIOErr> {
+        let buffer: Buffer = vec![0u8; span.byte_len()].into_boxed_slice().into();
+        let res = storage::read_sectors_by_idx(
+            node.device_idx,
+            buffer.clone(),
+            node.start_lba + span.first_lba,
+        )
+        .await;
+        // the storage layer only borrows the allocation, it comes back whatever happened
+        let sectors: Box<[u8]> = buffer.into();
+
+        res.map(|_| sectors).map_err(Into::into)
+    }
+
+    pub async fn read(
+        &mut self,
+        inode: &mut DevfsInode,
+        buf: &mut [u8],
+        ctx: &mut HalIOCtx,
+    ) -> Result<usize, HalFsIOErr> {
+        let node = self.node(inode)?;
+
+        let to_read = (buf.len() as u64).min(node.size().saturating_sub(ctx.head as u64)) as usize;
+        if to_read == 0 {
+            return Ok(0);
+        }
+
+        let span = SectorSpan::new(ctx.head, to_read, node.sector_size);
+        let sectors = self.read_span(&node, &span).await?;
+
+        buf[..to_read].copy_from_slice(&sectors[span.offset..span.offset + to_read]);
+        ctx.head += to_read;
+
+        Ok(to_read)
+    }
+
+    /// partial sectors are read first so the bytes around the range are kept
+    pub async fn write(
+        &mut self,
+        inode: &mut DevfsInode,
+        buf: &[u8],
+        ctx: &mut HalIOCtx,
+    ) -> Result<usize, HalFsIOErr> {
+        let node = self.node(inode)?;
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let to_write = (buf.len() as u64).min(node.size().saturating_sub(ctx.head as u64)) as usize;
+        if to_write == 0 {
+            return Err(HalFsIOErr::NoSpaceLeft);
+        }
+
+        let span = SectorSpan::new(ctx.head, to_write, node.sector_size);
+        let mut sectors = if span.is_aligned(to_write) {
+            vec![0u8; span.byte_len()].into_boxed_slice()
+        } else {
+            self.read_span(&node, &span).await?
+        };
+
+        sectors[span.offset..span.offset + to_write].copy_from_slice(&buf[..to_write]);
+        let buffer: Buffer = sectors.into();
+        let res = storage::write_sectors_by_idx(
+            node.device_idx,
+            buffer.clone(),
+            node.start_lba + span.first_lba,
+        )
+        .await;
+        drop(Box::<[u8]>::from(buffer));
+
+        res?;
+        ctx.head += to_write;
+
+        Ok(to_write)
+    }
+
+    /// same record format as the other filesystems, the offset is the index of the next node
+    pub fn iter_dir(
+        &mut self,
+        offset: &mut i64,
+        buf: &mut [u8],
+        inode: &mut DevfsInode,
+    ) -> Result<bool, HalFsIOErr> {
+        if inode.name.is_some() {
+            return Err(HalFsIOErr::NotADirectory);
+        }
+
+        let mut bytes_written = 0;
+        for name in self.nodes.keys().skip(*offset as usize) {
+            let entry = DirEnt64 {
+                inode_idx: *offset as u64 + 2,
+                offset: *offset + 1,
+                file_type: EXT2_FT_BLKDEV,
+                name: name.clone(),
+            };
+
+            if entry.rec_len() + bytes_written >= buf.len() {
+                return Ok(false);
+            }
+
+            bytes_written += entry.serialize(
+                dvida_serialize::Endianness::Little,
+                &mut buf[bytes_written..],
+            )?;
+            *offset += 1;
+        }
+
+        Ok(true)
+    }
+
+    pub fn file_size(&self, inode: &DevfsInode) -> Result<u64, HalFsIOErr> {
+        Ok(self.node(inode)?.size())
+    }
+
+    pub fn stat(&self, path: &Path) -> Result<FileStat, HalFsIOErr> {
+        let inode = self.resolve_path(path)?;
+
+        Ok(match inode.name {
+            Some(_) => {
+                let node = self.node(&inode)?;
+                FileStat {
+                    size: node.size(),
+                    mode: (EXT2_S_IFBLK | 0o660) as u32,
+                    nlink: 1,
+                    blocks: node.sector_count,
+                    ..Default::default()
+                }
+            }
+            None => FileStat {
+                ino: 1,
+                mode: (EXT2_S_IFDIR | 0o755) as u32,
+                nlink: 2,
+                ..Default::default()
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{end_test, hal::storage::SECTOR_SIZE, ignore, terminal::test::block_on, test_name};
+
+    const NATIVE_4K: usize = 4096;
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn devfs_names_and_spans() {
+        test_name!("devfs names and spans");
+
+        assert_eq!(disk_name(0), "sda");
+        assert_eq!(disk_name(25), "sdz");
+        assert_eq!(disk_name(26), "sdaa");
+
+        let span = SectorSpan::new(510, 4, SECTOR_SIZE);
+        assert_eq!(span.first_lba, 0);
+        assert_eq!(span.count, 2);
+        assert_eq!(span.offset, 510);
+        assert!(!span.is_aligned(4));
+
+        let span = SectorSpan::new(1024, 512, SECTOR_SIZE);
+        assert_eq!(span.first_lba, 2);
+        assert_eq!(span.count, 1);
+        assert!(span.is_aligned(512));
+
+        // the same range on a 4kn drive sits inside its first sector
+        let span = SectorSpan::new(1024, 512, NATIVE_4K);
+        assert_eq!(span.first_lba, 0);
+        assert_eq!(span.count, 1);
+        assert_eq!(span.offset, 1024);
+        assert_eq!(span.byte_len(), NATIVE_4K);
+        assert!(!span.is_aligned(512));
+        assert!(SectorSpan::new(2 * NATIVE_4K, NATIVE_4K, NATIVE_4K).is_aligned(NATIVE_4K));
+
+        let mut devfs = Devfs::new();
+        devfs.nodes.insert(
+            "sda1".into(),
+            DevNode {
+                device_idx: 0,
+                start_lba: 2048,
+                sector_count: 8,
+                sector_size: SECTOR_SIZE,
+            },
+        );
+        devfs.nodes.insert(
+            "sdb".into(),
+            DevNode {
+                device_idx: 1,
+                start_lba: 0,
+                sector_count: 8,
+                sector_size: NATIVE_4K,
+            },
+        );
+        assert!(devfs.resolve_path(&Path::new_appended("/sda1")).is_ok());
+        assert!(matches!(
+            devfs.resolve_path(&Path::new_appended("/sdc")),
+            Err(HalFsIOErr::NoSuchFileOrDirectory)
+        ));
+        assert_eq!(
+            devfs
+                .stat(&Path::new_appended("/sda1"))
+                .expect("Failed to stat")
+                .size,
+            8 * SECTOR_SIZE as u64
+        );
+        assert_eq!(
+            devfs
+                .stat(&Path::new_appended("/sdb"))
+                .expect("Failed to stat")
+                .size,
+            8 * NATIVE_4K as u64
+        );
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn devfs_first_sector() {
+        test_name!("devfs first sector");
+
+        // needs a running drive
+        let Some(&device_idx) = storage::storage_device_indices().first() else {
+            ignore!();
+        };
+
+        let mut devfs = block_on(Devfs::scan());
+        let HalInode::Devfs(mut inode) = devfs
+            .open_file(
+                Path::new_appended(&disk_name(device_idx)),
+                OpenFlags::default(),
+            )
+            .expect("Failed to open")
+        else {
+            panic!("Not a devfs inode");
+        };
+
+        let sector_size =
+            storage::logical_sector_size_by_idx(device_idx).expect("Failed to get sector size");
+        let mut through_devfs = vec![0u8; sector_size];
+        let mut ctx = HalIOCtx::new();
+        assert_eq!(
+            block_on(devfs.read(&mut inode, &mut through_devfs, &mut ctx)).ok(),
+            Some(sector_size)
+        );
+
+        let direct: Buffer = vec![0u8; sector_size].into_boxed_slice().into();
+        block_on(storage::read_sectors_by_idx(device_idx, direct.clone(), 0))
+            .expect("Failed to read");
+        let direct: Box<[u8]> = direct.into();
+
+        assert_eq!(&through_devfs[..], &direct[..]);
+
+        end_test!();
+    }
+}
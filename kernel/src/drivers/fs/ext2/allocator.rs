@@ -30,20 +30,21 @@ pub struct BlockAllocator {
 }
 
 impl BlockAllocator {
+    /// the groups an allocation spilling out of `exclude_group_idx` walks through, bounded by the
+    /// number of groups on disk rather than the number of blocks in one
+    pub fn candidate_groups(&self, exclude_group_idx: i64) -> impl Iterator<Item = i64> {
+        (0..self.block_groups_count).filter(move |group_number| *group_number != exclude_group_idx)
+    }
+
     pub async fn allocate_n_blocks(
         &mut self,
         exclude_group_idx: i64,
         mut remaining_blocks: usize,
     ) -> Result<Vec<AllocatedBlock>, HalFsIOErr> {
         let mut blocks_allocated = vec![];
-        let group_count = self.block_groups_count;
 
         // iterate over block groups
-        for group_number in 0..group_count {
-            if group_number == exclude_group_idx {
-                continue;
-            }
-
+        for group_number in self.candidate_groups(exclude_group_idx) {
             if remaining_blocks == 0 {
                 break;
             }
@@ -136,8 +137,7 @@ impl BlockAllocator {
             // map bitmap index to data block LBA
             let block_lba = group.get_group_lba() + (idx as i64) * group.sectors_per_block;
 
-            let global_idx =
-                group_number as u32 * self.group_manager.blocks_per_group + idx as u32;
+            let global_idx = group_number as u32 * self.group_manager.blocks_per_group + idx as u32;
 
             let allocated_block = AllocatedBlock {
                 addr: block_lba,
@@ -175,47 +175,31 @@ impl BlockAllocator {
         Ok(blocks_allocated)
     }
 
+    /// sets the bits of every reserved block, each touched bitmap is read and written once no
+    /// matter how many blocks were taken from its group
     pub async fn write_newly_allocated_blocks(
         &mut self,
         mut buf: Box<[u8]>,
     ) -> Result<(), HalFsIOErr> {
-        let mut cur_bitmap_lba = -1;
-
-        let mut allocated_blocks_map: BTreeMap<i64, i64> = BTreeMap::new();
-
-        for AllocatedBlock {
-            gr_number,
-            block_global_idx: block_idx,
-            ..
-        } in self.allocated_block_indices.lock().await.iter()
-        {
-            allocated_blocks_map
-                .entry(*gr_number)
-                .and_modify(|v| *v += 1)
-                .or_insert(1);
+        let updates = bitmap_updates(self.allocated_block_indices.lock().await.iter());
+        if updates.is_empty() {
+            return Ok(());
+        }
 
+        for (gr_number, relative_indices) in updates.iter() {
             let group = self.group_manager.get_group(*gr_number).await?;
             let block_bitmap_lba = group.get_block_bitmap_lba();
 
-            if cur_bitmap_lba != block_bitmap_lba {
-                if cur_bitmap_lba != -1 {
-                    self.io_handler
-                        .write_sectors(buf.clone(), cur_bitmap_lba)
-                        .await?;
-                }
-
-                buf = self.io_handler.read_sectors(buf, block_bitmap_lba).await?;
-                cur_bitmap_lba = block_bitmap_lba
-            }
-
-            let mut target = buf[*block_idx as usize / 8];
-            target |= 0x1 << (*block_idx as usize % 8);
-            buf[*block_idx as usize / 8] = target;
+            buf = self.io_handler.read_sectors(buf, block_bitmap_lba).await?;
+            set_bitmap_bits(&mut buf, relative_indices);
+            self.io_handler
+                .write_sectors(buf.clone(), block_bitmap_lba)
+                .await?;
         }
 
-        self.io_handler
-            .write_sectors(buf.clone(), cur_bitmap_lba)
-            .await?;
+        let allocated_blocks_map = updates
+            .into_iter()
+            .map(|(group_idx, relative_indices)| (group_idx, relative_indices.len() as i64));
 
         let mut cur_group_buffer_lba = -1;
         for (group_idx, num_allocated) in allocated_blocks_map {
@@ -248,9 +232,20 @@ impl BlockAllocator {
 
         self.allocated_block_indices.lock().await.clear();
 
+        // metadata goes to the media right away, a crash can't leave it only in the caches
+        self.io_handler.flush().await?;
+
         Ok(())
     }
 
+    /// drops reservations that never made it to the bitmap, the blocks are free again right away
+    pub async fn cancel_allocation(&mut self, blocks: &[AllocatedBlock]) {
+        let mut reserved = self.allocated_block_indices.lock().await;
+        for block in blocks {
+            reserved.remove(block);
+        }
+    }
+
     pub async fn add_freed_block(&mut self, block: u32) {
         self.unwritten_freed_blocks.lock().await.insert(block);
     }
@@ -285,11 +280,194 @@ impl BlockAllocator {
                 &mut buf[byte_offset as usize..byte_offset as usize + size_of::<GroupDescriptor>()],
             );
 
-            descriptor.bg_free_blocks_count -= 1;
+            descriptor.bg_free_blocks_count += 1;
+        }
+
+        if cur_group_buffer_lba != -1 {
+            self.io_handler
+                .write_sectors(buf, cur_group_buffer_lba)
+                .await?;
         }
 
         self.unwritten_freed_blocks.lock().await.clear();
 
+        self.io_handler.flush().await?;
+
         Ok(())
     }
 }
+
+/// the block bitmap bits each group needs set, keyed by group number
+pub fn bitmap_updates<'a>(
+    blocks: impl IntoIterator<Item = &'a AllocatedBlock>,
+) -> BTreeMap<i64, Vec<u32>> {
+    let mut updates: BTreeMap<i64, Vec<u32>> = BTreeMap::new();
+
+    for block in blocks {
+        updates
+            .entry(block.gr_number)
+            .or_default()
+            .push(block.block_relatve_idx);
+    }
+
+    updates
+}
+
+pub fn set_bitmap_bits(bitmap: &mut [u8], relative_indices: &[u32]) {
+    for idx in relative_indices {
+        bitmap[*idx as usize / 8] |= 1 << (idx % 8);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::{bitmap_updates, set_bitmap_bits};
+    use crate::{
+        crypto::guid::Guid,
+        drivers::fs::ext2::{
+            BLOCKS_PER_GROUP,
+            create_file::AllocatedBlock,
+            structs::{Ext2Fs, RAM_DISK_BLOCKS_PER_GROUP},
+        },
+        end_test,
+        hal::ram_disk,
+        terminal::test::block_on,
+        test_name,
+    };
+
+    fn block(gr_number: i64, block_relatve_idx: u32) -> AllocatedBlock {
+        AllocatedBlock {
+            addr: 0,
+            block_global_idx: gr_number as u32 * 8192 + block_relatve_idx,
+            block_relatve_idx,
+            gr_number,
+        }
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn batched_bitmap_updates() {
+        test_name!("batched bitmap updates");
+
+        // 100 blocks out of one group and a couple out of another
+        let blocks: Vec<AllocatedBlock> = (0..100)
+            .map(|idx| block(1, idx + 3))
+            .chain([block(2, 0), block(2, 9)])
+            .collect();
+
+        // one bitmap write per touched group rather than one per block
+        let updates = bitmap_updates(&blocks);
+        assert_eq!(updates.len(), 2);
+        assert_eq!(updates[&1].len(), 100);
+        assert_eq!(updates[&2], [0, 9]);
+
+        let mut bitmap = [0u8; 16];
+        bitmap[0] = 0b0000_0101;
+        set_bitmap_bits(&mut bitmap, &updates[&1]);
+        assert_eq!(bitmap[0], 0b1111_1101);
+        assert!(bitmap[1..12].iter().all(|byte| *byte == 0xFF));
+        assert_eq!(bitmap[12], 0b0111_1111);
+        assert_eq!(bitmap[13], 0);
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn allocation_scans_the_real_groups() {
+        test_name!("block allocation walks every group on disk and nothing past it");
+
+        // two full groups and a short one at the end
+        let fs = Ext2Fs::detached_with_blocks(false, 2 * BLOCKS_PER_GROUP + 100);
+        assert_eq!(fs.super_block.block_groups_count(), 3);
+
+        let groups: Vec<i64> = fs.block_allocator.candidate_groups(-1).collect();
+        assert_eq!(groups, [0, 1, 2]);
+
+        let groups: Vec<i64> = fs.block_allocator.candidate_groups(1).collect();
+        assert_eq!(groups, [0, 2]);
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn bitmap_written_once_per_group() {
+        test_name!("flushing an allocation writes each touched block bitmap once");
+
+        let guid = Guid::from_bytes([0x50; 16]);
+        block_on(async {
+            let mut fs = Ext2Fs::on_ram_disk(guid, 3).await;
+            let mut bitmap_lbas = Vec::new();
+            let mut free_before = Vec::new();
+            for group_number in 0..3 {
+                let group = fs.get_group(group_number).await.unwrap();
+                bitmap_lbas.push(group.get_block_bitmap_lba());
+                free_before.push(group.descriptor.bg_free_blocks_count);
+            }
+
+            let in_group_1 = fs
+                .block_allocator
+                .allocate_n_blocks_in_group(1, 100)
+                .await
+                .expect("Failed to allocate");
+            let in_group_2 = fs
+                .block_allocator
+                .allocate_n_blocks_in_group(2, 3)
+                .await
+                .expect("Failed to allocate");
+            assert!(in_group_1.iter().all(|block| block.gr_number == 1));
+            assert!(in_group_2.iter().all(|block| block.gr_number == 2));
+
+            ram_disk::with_disk(guid, |disk| {
+                disk.writes.clear();
+                disk.flushes = 0;
+            });
+            let buf = fs.get_buffer();
+            fs.block_allocator
+                .write_newly_allocated_blocks(buf)
+                .await
+                .expect("Failed to write the bitmaps");
+
+            let (writes, flushes, flushed_writes) = ram_disk::with_disk(guid, |disk| {
+                (disk.writes.clone(), disk.flushes, disk.flushed_writes)
+            })
+            .unwrap();
+            // one flush once everything is written
+            assert_eq!(flushes, 1);
+            assert_eq!(flushed_writes, writes.len());
+            let bitmap_writes = |lba: i64| {
+                writes
+                    .iter()
+                    .filter(|(write_lba, _)| *write_lba == lba)
+                    .count()
+            };
+            assert_eq!(bitmap_writes(bitmap_lbas[0]), 0);
+            assert_eq!(bitmap_writes(bitmap_lbas[1]), 1);
+            assert_eq!(bitmap_writes(bitmap_lbas[2]), 1);
+
+            // the bits and the counts made it to the disk
+            for (group_number, blocks) in [(1, &in_group_1), (2, &in_group_2)] {
+                let group = fs.get_group(group_number).await.unwrap();
+                assert_eq!(
+                    { group.descriptor.bg_free_blocks_count },
+                    free_before[group_number as usize] - blocks.len() as u16
+                );
+
+                let bitmap = fs
+                    .read_sectors(fs.get_buffer(), group.get_block_bitmap_lba())
+                    .await
+                    .unwrap();
+                for block in blocks.iter() {
+                    let idx = block.block_global_idx % RAM_DISK_BLOCKS_PER_GROUP;
+                    assert!(bitmap[idx as usize / 8] & (1 << (idx % 8)) != 0);
+                }
+            }
+        });
+        ram_disk::unregister(guid);
+
+        end_test!();
+    }
+}
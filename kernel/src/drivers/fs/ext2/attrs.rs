@@ -0,0 +1,186 @@
+use crate::{
+    drivers::fs::ext2::{Inode, structs::Ext2Fs},
+    hal::{fs::HalFsIOErr, path::Path},
+    time,
+};
+
+/// every bit chmod is allowed to touch: suid, sgid, sticky and the rwx triplets
+pub const EXT2_PERMISSION_MASK: u16 = 0x0FFF;
+
+impl Inode {
+    /// replaces the permission bits, the file type bits are kept as they are
+    pub fn set_permissions(&mut self, perms: u16, now: u32) -> Result<(), HalFsIOErr> {
+        if perms & !EXT2_PERMISSION_MASK != 0 {
+            return Err(HalFsIOErr::InvalidArgument);
+        }
+
+        self.i_mode = self.file_type() | perms;
+        self.i_ctime = now;
+
+        Ok(())
+    }
+
+    pub fn set_owner(&mut self, uid: u16, gid: u16, now: u32) {
+        self.i_uid = uid;
+        self.i_gid = gid;
+        self.i_ctime = now;
+    }
+
+    pub fn set_times(&mut self, atime: u32, mtime: u32, now: u32) {
+        self.i_atime = atime;
+        self.i_mtime = mtime;
+        self.i_ctime = now;
+    }
+}
+
+impl Ext2Fs {
+    pub async fn chmod(&mut self, path: &Path, perms: u16) -> Result<(), HalFsIOErr> {
+        self.ensure_writable()?;
+
+        let mut inode = self.resolve_path(path).await?;
+        inode
+            .inode
+            .set_permissions(perms, time::get_unix_timestamp())?;
+
+        self.write_inode(&inode).await
+    }
+
+    pub async fn chown(&mut self, path: &Path, uid: u16, gid: u16) -> Result<(), HalFsIOErr> {
+        self.ensure_writable()?;
+
+        let mut inode = self.resolve_path(path).await?;
+        inode.inode.set_owner(uid, gid, time::get_unix_timestamp());
+
+        self.write_inode(&inode).await
+    }
+
+    pub async fn utime(&mut self, path: &Path, atime: u32, mtime: u32) -> Result<(), HalFsIOErr> {
+        self.ensure_writable()?;
+
+        let mut inode = self.resolve_path(path).await?;
+        inode
+            .inode
+            .set_times(atime, mtime, time::get_unix_timestamp());
+
+        self.write_inode(&inode).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        crypto::guid::Guid,
+        drivers::fs::ext2::{
+            EXT2_S_IFDIR, EXT2_S_IFREG, EXT2_S_ISVTX, open::ROOT_DIRECTORY_INODE_IDX,
+            structs::Ext2Fs,
+        },
+        end_test,
+        hal::{fs::HalFsIOErr, path::Path, ram_disk},
+        terminal::test::block_on,
+        test_name,
+    };
+
+    /// a ram disk holding the regular file `/a` and the directory `/dir`, with the inode numbers
+    /// of both
+    async fn disk_with_file(guid: Guid) -> (Ext2Fs, u32, u32) {
+        let mut fs = Ext2Fs::on_ram_disk(guid, 1).await;
+        let mut root = fs
+            .get_nth_inode(ROOT_DIRECTORY_INODE_IDX as u32)
+            .await
+            .unwrap();
+        let file = fs.create_file(&mut root, "a", 0o644).await.unwrap();
+        let dir = fs.mkdir(Path::new_appended("/dir"), 0o700).await.unwrap();
+
+        (fs, file.absolute_idx, dir.absolute_idx)
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn ext2_chmod() {
+        test_name!("ext2 chmod");
+
+        let guid = Guid::from_bytes([0x6c; 16]);
+        block_on(async {
+            let (mut fs, file, dir) = disk_with_file(guid).await;
+            let (file_path, dir_path) = (Path::new_appended("/a"), Path::new_appended("/dir"));
+
+            fs.chmod(&file_path, 0o755).await.unwrap();
+            let stat = fs.stat(&file_path).await.unwrap();
+            assert_eq!(stat.mode, (EXT2_S_IFREG | 0o755) as u32);
+            let inode = fs.get_nth_inode(file).await.unwrap().inode;
+            assert_eq!({ inode.i_mode }, EXT2_S_IFREG | 0o755);
+            assert!(inode.is_regular_file());
+
+            fs.chmod(&dir_path, EXT2_S_ISVTX | 0o777).await.unwrap();
+            let inode = fs.get_nth_inode(dir).await.unwrap().inode;
+            assert!(inode.is_directory());
+            assert_eq!(inode.permissions(), EXT2_S_ISVTX | 0o777);
+
+            // bits in the type field are refused and nothing changes
+            assert!(matches!(
+                fs.chmod(&dir_path, EXT2_S_IFREG | 0o777).await,
+                Err(HalFsIOErr::InvalidArgument)
+            ));
+            let stat = fs.stat(&dir_path).await.unwrap();
+            assert_eq!(stat.mode, (EXT2_S_IFDIR | EXT2_S_ISVTX | 0o777) as u32);
+            let inode = fs.get_nth_inode(dir).await.unwrap().inode;
+            assert_eq!({ inode.i_mode }, EXT2_S_IFDIR | EXT2_S_ISVTX | 0o777);
+        });
+        ram_disk::unregister(guid);
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn ext2_chown() {
+        test_name!("ext2 chown");
+
+        let guid = Guid::from_bytes([0x6d; 16]);
+        block_on(async {
+            let (mut fs, file, _) = disk_with_file(guid).await;
+            let path = Path::new_appended("/a");
+
+            fs.chown(&path, 1000, 100).await.unwrap();
+            let stat = fs.stat(&path).await.unwrap();
+            assert_eq!((stat.uid, stat.gid), (1000, 100));
+
+            // the owner changes, the mode stays
+            let inode = fs.get_nth_inode(file).await.unwrap().inode;
+            assert_eq!((inode.i_uid, inode.i_gid), (1000, 100));
+            assert_eq!({ inode.i_mode }, EXT2_S_IFREG | 0o644);
+
+            assert!(matches!(
+                fs.chown(&Path::new_appended("/b"), 1, 1).await,
+                Err(HalFsIOErr::NoSuchFileOrDirectory)
+            ));
+        });
+        ram_disk::unregister(guid);
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn ext2_utime() {
+        test_name!("ext2 utime");
+
+        let guid = Guid::from_bytes([0x6e; 16]);
+        block_on(async {
+            let (mut fs, file, _) = disk_with_file(guid).await;
+            let path = Path::new_appended("/a");
+
+            fs.utime(&path, 946_684_800, 1_000_000_000).await.unwrap();
+
+            let inode = fs.get_nth_inode(file).await.unwrap().inode;
+            assert_eq!(inode.i_atime, 946_684_800);
+            assert_eq!(inode.i_mtime, 1_000_000_000);
+
+            let stat = fs.stat(&path).await.unwrap();
+            assert_eq!((stat.atime, stat.mtime), (946_684_800, 1_000_000_000));
+        });
+        ram_disk::unregister(guid);
+
+        end_test!();
+    }
+}
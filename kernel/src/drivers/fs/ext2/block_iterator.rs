@@ -1,3 +1,4 @@
+use crate::log;
 use alloc::boxed::Box;
 use alloc::{vec, vec::Vec};
 
@@ -25,19 +26,85 @@ impl Ext2Fs {
             io_handler: self.io_handler,
             block_allocator: self.block_allocator.clone(),
 
-            cur_ind_buf: None,
-            cur_ind_buf_block_idx: 0,
-            cur_double_ind_buf: None,
-            cur_double_ind_buf_block_idx: 0,
-            cur_triple_ind_buf: None,
-            cur_triple_ind_buf_block_idx: 0,
-            blocks_limit: inode.i_size.div_ceil(self.super_block.block_size()) as usize,
+            ind: CachedBlock::default(),
+            double_ind: CachedBlock::default(),
+            triple_ind: CachedBlock::default(),
+            blocks_limit: self.logical_block_count(inode) as usize,
             cur_idx: 0,
             cur_block_idx: 0,
         }
     }
 }
 
+/// an indirect block the iterator keeps in memory. Pointers set in it are only written once the
+/// block is evicted for another one or the iterator is flushed
+#[derive(Default)]
+struct CachedBlock {
+    buf: Option<Box<[u8]>>,
+    block_idx: u32,
+    dirty: bool,
+}
+
+impl CachedBlock {
+    fn holds(&self, block_idx: u32) -> bool {
+        self.buf.is_some() && self.block_idx == block_idx
+    }
+
+    /// the block number stored at entry `idx`
+    fn entry(&self, idx: usize) -> u32 {
+        let buf = self.buf.as_ref().expect("Indirect block isn't loaded");
+        u32::from_le_bytes(buf[idx * 4..idx * 4 + 4].try_into().unwrap())
+    }
+
+    fn set_entry(&mut self, idx: usize, value: u32) {
+        let buf = self.buf.as_mut().expect("Indirect block isn't loaded");
+        buf[idx * 4..idx * 4 + 4].copy_from_slice(&value.to_le_bytes());
+        self.dirty = true;
+    }
+
+    /// the contents that still have to reach the disk, the block stays cached
+    fn take_dirty(&mut self) -> Option<(u32, Box<[u8]>)> {
+        if !self.dirty {
+            return None;
+        }
+
+        self.dirty = false;
+        Some((self.block_idx, self.buf.clone()?))
+    }
+
+    /// makes `block_idx` the cached block, whatever was cached before is written back first
+    async fn load(
+        &mut self,
+        io_handler: IoHandler,
+        block_idx: u32,
+        block_size: usize,
+    ) -> Result<(), HalFsIOErr> {
+        if self.holds(block_idx) {
+            return Ok(());
+        }
+
+        if let Some((dirty_idx, dirty_buf)) = self.take_dirty() {
+            io_handler.write_block(dirty_buf, dirty_idx).await?;
+        }
+
+        let buf = self
+            .buf
+            .take()
+            .unwrap_or_else(|| vec![0u8; block_size].into_boxed_slice());
+        self.fill(block_idx, io_handler.read_block(buf, block_idx).await?);
+
+        Ok(())
+    }
+
+    /// `buf` holds what's on disk for `block_idx`, anything dirty has to be taken out before
+    fn fill(&mut self, block_idx: u32, buf: Box<[u8]>) {
+        debug_assert!(!self.dirty, "Evicting a dirty indirect block");
+
+        self.buf = Some(buf);
+        self.block_idx = block_idx;
+    }
+}
+
 pub struct InodeBlockIterator {
     blocks: [u32; 15],
     group_number: i64,
@@ -46,16 +113,12 @@ pub struct InodeBlockIterator {
     io_handler: IoHandler,
     block_allocator: BlockAllocator,
 
-    cur_ind_buf: Option<Box<[u8]>>,
-    cur_ind_buf_block_idx: u32,
-
-    cur_double_ind_buf: Option<Box<[u8]>>,
-    cur_double_ind_buf_block_idx: u32,
-
-    cur_triple_ind_buf: Option<Box<[u8]>>,
-    cur_triple_ind_buf_block_idx: u32,
+    ind: CachedBlock,
+    double_ind: CachedBlock,
+    triple_ind: CachedBlock,
 
-    /// will be initialized as aligned up i_size
+    /// will be initialized as aligned up i_size, i_blocks is in sectors and also counts the
+    /// indirect blocks so it can't be used here
     blocks_limit: usize,
     cur_idx: usize,
     cur_block_idx: u32,
@@ -77,84 +140,40 @@ impl InodeBlockIterator {
         Ok(buf)
     }
 
-    async fn handle_ind_block(
+    /// the entry at `offset_in_ind_block`, 0 if the indirect block itself is a hole
+    async fn ind_entry(
         &mut self,
-        mut buf: Box<[u8]>,
         offset_in_ind_block: usize,
         ind_block_idx: u32,
-    ) -> Result<Box<[u8]>, HalFsIOErr> {
+    ) -> Result<u32, HalFsIOErr> {
+        // a hole, nothing below it was ever allocated
         if ind_block_idx == 0 {
-            buf.fill(0);
-            return Ok(buf);
+            return Ok(0);
         }
 
-        if self.cur_ind_buf.is_none() {
-            let mut ind_buf = vec![0u8; self.block_size].into_boxed_slice();
-            ind_buf = self
-                .io_handler
-                .read_block(ind_buf, self.blocks[INODE_BLOCK_LIMIT as usize])
-                .await?;
-            self.cur_ind_buf_block_idx = self.blocks[INODE_BLOCK_LIMIT as usize];
-            self.cur_ind_buf = Some(ind_buf);
-        }
-
-        if ind_block_idx != self.cur_ind_buf_block_idx {
-            let mut ind_buf = self.cur_ind_buf.take().unwrap();
-            self.cur_ind_buf_block_idx = ind_block_idx;
-            ind_buf = self.io_handler.read_block(ind_buf, ind_block_idx).await?;
-            self.cur_ind_buf = Some(ind_buf);
-        }
-
-        let ind_buf = self.cur_ind_buf.as_ref().unwrap();
-        let block_idx: u32 =
-            *bytemuck::from_bytes(&ind_buf[offset_in_ind_block..offset_in_ind_block + 4]);
+        self.ind
+            .load(self.io_handler, ind_block_idx, self.block_size)
+            .await?;
 
-        self.handle_block(buf, block_idx).await
+        Ok(self.ind.entry(offset_in_ind_block))
     }
 
-    async fn handle_double_ind_block(
+    async fn double_ind_entry(
         &mut self,
-        mut buf: Box<[u8]>,
         offset_in_double_ind_block: usize,
         offset_in_ind_block: usize,
         double_ind_block_idx: u32,
-    ) -> Result<Box<[u8]>, HalFsIOErr> {
+    ) -> Result<u32, HalFsIOErr> {
         if double_ind_block_idx == 0 {
-            buf.fill(0);
-            return Ok(buf);
-        }
-
-        if self.cur_double_ind_buf.is_none() {
-            let mut double_ind_buf = vec![0u8; self.block_size].into_boxed_slice();
-            double_ind_buf = self
-                .io_handler
-                .read_block(
-                    double_ind_buf,
-                    self.blocks[(INODE_BLOCK_LIMIT + 1) as usize],
-                )
-                .await?;
-            self.cur_double_ind_buf_block_idx = self.blocks[(INODE_BLOCK_LIMIT + 1) as usize];
-            self.cur_double_ind_buf = Some(double_ind_buf);
-        }
-
-        if double_ind_block_idx != self.cur_double_ind_buf_block_idx {
-            let mut double_ind_buf = self.cur_double_ind_buf.take().unwrap();
-            self.cur_double_ind_buf_block_idx = double_ind_block_idx;
-            double_ind_buf = self
-                .io_handler
-                .read_block(double_ind_buf, double_ind_block_idx)
-                .await?;
-            self.cur_double_ind_buf = Some(double_ind_buf);
+            return Ok(0);
         }
 
-        let double_ind_buf = self.cur_double_ind_buf.as_ref().unwrap();
-        let ind_block_idx: u32 = *bytemuck::from_bytes(
-            &double_ind_buf[offset_in_double_ind_block..offset_in_double_ind_block + 4],
-        );
+        self.double_ind
+            .load(self.io_handler, double_ind_block_idx, self.block_size)
+            .await?;
+        let ind_block_idx = self.double_ind.entry(offset_in_double_ind_block);
 
-        self
-            .handle_ind_block(buf, offset_in_ind_block, ind_block_idx)
-            .await
+        self.ind_entry(offset_in_ind_block, ind_block_idx).await
     }
 
     pub async fn next(&mut self, buf: Box<[u8]>) -> Result<BlockIterElement, HalFsIOErr> {
@@ -172,6 +191,19 @@ impl InodeBlockIterator {
         Ok(res)
     }
 
+    /// like next but only the block number is looked up, the block itself isn't read. None once
+    /// the end is reached
+    pub async fn next_idx(&mut self) -> Result<Option<u32>, HalFsIOErr> {
+        if self.cur_idx >= self.blocks_limit {
+            return Ok(None);
+        }
+
+        let res = self.locate().await?;
+        self.cur_idx += 1;
+
+        Ok(res)
+    }
+
     pub async fn next_set(&mut self) -> Result<BlockIterSetRes, HalFsIOErr> {
         let res = self.set().await?;
         self.cur_idx += 1;
@@ -191,86 +223,114 @@ impl InodeBlockIterator {
         self.cur_idx
     }
 
-    /// takes in a buffer and returns a struct BlockIterElement
-    /// if the array is terminated the buffer won't be modified
-    pub async fn get(&mut self, mut buf: Box<[u8]>) -> Result<BlockIterElement, HalFsIOErr> {
+    /// direct blocks past the end of the file are walked as well, creating a file preallocates
+    /// some that the size doesn't cover
+    pub fn include_preallocated(&mut self) {
+        let last_direct = self.blocks[..INODE_BLOCK_LIMIT as usize]
+            .iter()
+            .rposition(|&block_idx| block_idx != 0)
+            .map_or(0, |idx| idx + 1);
+
+        self.blocks_limit = self.blocks_limit.max(last_direct);
+    }
+
+    /// the indirect blocks lookups have gone through most recently, one per level at most
+    pub fn loaded_indirect_blocks(&self) -> impl Iterator<Item = u32> + '_ {
+        [&self.ind, &self.double_ind, &self.triple_ind]
+            .into_iter()
+            .filter(|block| block.buf.is_some())
+            .map(|block| block.block_idx)
+    }
+
+    /// splits an index into one of the indirect ranges into the offset in each level of the
+    /// tree, outermost first. Fails instead of handing out an offset past the end of a block
+    fn indirect_offsets<const LEVELS: usize>(
+        &self,
+        idx_in_range: usize,
+    ) -> Result<[usize; LEVELS], HalFsIOErr> {
         let num_idx_per_block = self.block_size / 4;
+        let mut offsets = [0; LEVELS];
+        let mut rest = idx_in_range;
 
-        if (self.cur_idx as u32) < INODE_BLOCK_LIMIT {
-            buf = self.handle_block(buf, self.blocks[self.cur_idx]).await?;
-        } else if (self.cur_idx as u32) < INODE_IND_BLOCK_LIMIT {
-            buf = self
-                .handle_ind_block(
-                    buf,
-                    self.cur_idx - INODE_BLOCK_LIMIT as usize,
-                    self.blocks[INODE_BLOCK_LIMIT as usize],
-                )
-                .await?;
-        } else if (self.cur_idx as u32) < INODE_DOUBLE_IND_BLOCK_LIMIT {
-            let offset_in_ind_block =
-                ((self.cur_idx - INODE_IND_BLOCK_LIMIT as usize) % num_idx_per_block) * 4;
-            let offset_in_double_ind_block =
-                ((self.cur_idx - INODE_IND_BLOCK_LIMIT as usize) / num_idx_per_block) * 4;
-            buf = self
-                .handle_double_ind_block(
-                    buf,
+        for offset in offsets.iter_mut().rev() {
+            *offset = rest % num_idx_per_block;
+            rest /= num_idx_per_block;
+        }
+
+        // whatever is left over doesn't fit in the outermost block
+        if rest != 0 {
+            return Err(HalFsIOErr::FileTooLarge);
+        }
+
+        Ok(offsets)
+    }
+
+    /// the block number at the current index, 0 for a hole and None past the end. Only the
+    /// indirect blocks on the way are read
+    async fn locate(&mut self) -> Result<Option<u32>, HalFsIOErr> {
+        let block_idx = if self.cur_idx < INODE_BLOCK_LIMIT as usize {
+            self.blocks[self.cur_idx]
+        } else if self.cur_idx < INODE_IND_BLOCK_LIMIT as usize {
+            let [offset_in_ind_block] =
+                self.indirect_offsets::<1>(self.cur_idx - INODE_BLOCK_LIMIT as usize)?;
+            self.ind_entry(offset_in_ind_block, self.blocks[INODE_BLOCK_LIMIT as usize])
+                .await?
+        } else if self.cur_idx < INODE_DOUBLE_IND_BLOCK_LIMIT as usize {
+            let [offset_in_double_ind_block, offset_in_ind_block] =
+                self.indirect_offsets::<2>(self.cur_idx - INODE_IND_BLOCK_LIMIT as usize)?;
+            self.double_ind_entry(
+                offset_in_double_ind_block,
+                offset_in_ind_block,
+                self.blocks[INODE_BLOCK_LIMIT as usize + 1],
+            )
+            .await?
+        } else if self.cur_idx < INODE_TRIPLE_IND_BLOCK_LIMIT as usize {
+            let triple_ind_block_idx = self.blocks[INODE_BLOCK_LIMIT as usize + 2];
+
+            if triple_ind_block_idx == 0 {
+                0
+            } else {
+                let idx_in_triple = self.cur_idx - INODE_DOUBLE_IND_BLOCK_LIMIT as usize;
+                let [
+                    offset_in_triple_ind_block,
                     offset_in_double_ind_block,
                     offset_in_ind_block,
-                    self.blocks[INODE_BLOCK_LIMIT as usize + 1],
-                )
-                .await?;
-        } else if (self.cur_idx as u32) < INODE_TRIPLE_IND_BLOCK_LIMIT {
-            if self.blocks[INODE_BLOCK_LIMIT as usize + 2] == 0 {
-                buf.fill(0);
-            } else {
-                if self.cur_triple_ind_buf.is_none() {
-                    let mut triple_ind_buf = vec![0u8; self.block_size].into_boxed_slice();
-                    triple_ind_buf = self
-                        .io_handler
-                        .read_block(
-                            triple_ind_buf,
-                            self.blocks[(INODE_BLOCK_LIMIT + 2) as usize],
-                        )
-                        .await?;
-                    self.cur_triple_ind_buf = Some(triple_ind_buf);
-                }
-
-                let triple_ind_buf = self.cur_triple_ind_buf.as_ref().unwrap();
-                let offset_in_ind_block = ((self.cur_idx - INODE_DOUBLE_IND_BLOCK_LIMIT as usize)
-                    % num_idx_per_block)
-                    * 4;
-
-                let offset_in_double_ind_block = (((self.cur_idx
-                    - INODE_DOUBLE_IND_BLOCK_LIMIT as usize)
-                    / num_idx_per_block)
-                    % num_idx_per_block)
-                    * 4;
-
-                let offset_in_triple_ind_block = ((self.cur_idx
-                    - INODE_DOUBLE_IND_BLOCK_LIMIT as usize)
-                    / (num_idx_per_block * num_idx_per_block))
-                    * 4;
-
-                let double_ind_block_idx: u32 = *bytemuck::from_bytes(
-                    &triple_ind_buf[offset_in_triple_ind_block..offset_in_triple_ind_block + 4],
-                );
-
-                buf = self
-                    .handle_double_ind_block(
-                        buf,
-                        offset_in_double_ind_block,
-                        offset_in_ind_block,
-                        double_ind_block_idx,
-                    )
+                ] = self.indirect_offsets::<3>(idx_in_triple)?;
+
+                self.triple_ind
+                    .load(self.io_handler, triple_ind_block_idx, self.block_size)
                     .await?;
+                let double_ind_block_idx = self.triple_ind.entry(offset_in_triple_ind_block);
+
+                self.double_ind_entry(
+                    offset_in_double_ind_block,
+                    offset_in_ind_block,
+                    double_ind_block_idx,
+                )
+                .await?
             }
+        } else if self.cur_idx < self.blocks_limit {
+            // the size claims more blocks than the triple indirect block can address
+            return Err(HalFsIOErr::Corrupted);
         } else {
+            return Ok(None);
+        };
+
+        Ok(Some(block_idx))
+    }
+
+    /// takes in a buffer and returns a struct BlockIterElement
+    /// if the array is terminated the buffer won't be modified
+    pub async fn get(&mut self, mut buf: Box<[u8]>) -> Result<BlockIterElement, HalFsIOErr> {
+        let Some(block_idx) = self.locate().await? else {
             return Ok(BlockIterElement {
                 buf,
                 is_terminated: true,
                 block_idx: 0,
             });
-        }
+        };
+
+        buf = self.handle_block(buf, block_idx).await?;
 
         Ok(BlockIterElement {
             buf,
@@ -279,6 +339,27 @@ impl InodeBlockIterator {
         })
     }
 
+    /// one block from the allocator, zeroed on disk if it's going to hold block numbers
+    async fn allocate_block(
+        &mut self,
+        zeroed: bool,
+        allocated_blocks: &mut Vec<AllocatedBlock>,
+    ) -> Result<u32, HalFsIOErr> {
+        let block = self
+            .block_allocator
+            .allocate_n_blocks_in_group(self.group_number, 1)
+            .await?
+            .remove(0);
+        let block_idx = block.block_global_idx;
+
+        if zeroed {
+            self.clear_block(block_idx).await?;
+        }
+        allocated_blocks.push(block);
+
+        Ok(block_idx)
+    }
+
     async fn handle_set_block(
         &mut self,
         // guaranteed to not be 0
@@ -286,39 +367,18 @@ impl InodeBlockIterator {
         offset_in_ind_block: usize,
         allocated_blocks: &mut Vec<AllocatedBlock>,
     ) -> Result<(), HalFsIOErr> {
-        if self.cur_ind_buf_block_idx != ind_block_idx {
-            let mut buf = vec![0u8; self.block_size].into_boxed_slice();
-            buf = self.io_handler.read_block(buf, ind_block_idx).await?;
-            self.cur_ind_buf_block_idx = ind_block_idx;
-            self.cur_ind_buf = Some(buf);
-        }
-
-        let buf = self.cur_ind_buf.as_mut().unwrap();
-
-        let num: &mut u32 = bytemuck::from_bytes_mut(
-            &mut buf[offset_in_ind_block * 4..offset_in_ind_block * 4 + 4],
-        );
-
-        let mut res = *num;
-
-        if *num == 0 {
-            let block = self
-                .block_allocator
-                .allocate_n_blocks_in_group(self.group_number, 1)
-                .await?
-                .remove(0);
-
-            *num = block.block_global_idx;
-            res = *num;
+        self.ind
+            .load(self.io_handler, ind_block_idx, self.block_size)
+            .await?;
 
-            self.io_handler
-                .write_block(buf.clone(), self.cur_ind_buf_block_idx)
-                .await?;
+        let mut block_idx = self.ind.entry(offset_in_ind_block);
 
-            allocated_blocks.push(block);
+        if block_idx == 0 {
+            block_idx = self.allocate_block(false, allocated_blocks).await?;
+            self.ind.set_entry(offset_in_ind_block, block_idx);
         }
 
-        self.cur_block_idx = res;
+        self.cur_block_idx = block_idx;
 
         Ok(())
     }
@@ -331,40 +391,16 @@ impl InodeBlockIterator {
         offset_in_ind_block: usize,
         allocated_blocks: &mut Vec<AllocatedBlock>,
     ) -> Result<(), HalFsIOErr> {
-        if self.cur_double_ind_buf_block_idx != double_ind_block_idx {
-            let mut buf = vec![0u8; self.block_size].into_boxed_slice();
-            buf = self
-                .io_handler
-                .read_block(buf, double_ind_block_idx)
-                .await?;
-            self.cur_double_ind_buf_block_idx = double_ind_block_idx;
-            self.cur_double_ind_buf = Some(buf);
-        }
-
-        let buf = self.cur_double_ind_buf.as_mut().unwrap();
-
-        let num: &mut u32 = bytemuck::from_bytes_mut(
-            &mut buf[offset_in_double_ind_block * 4..offset_in_double_ind_block * 4 + 4],
-        );
-
-        let mut ind_block_idx = *num;
-
-        if *num == 0 {
-            let block = self
-                .block_allocator
-                .allocate_n_blocks_in_group(self.group_number, 1)
-                .await?
-                .remove(0);
-
-            *num = block.block_global_idx;
-            ind_block_idx = *num;
+        self.double_ind
+            .load(self.io_handler, double_ind_block_idx, self.block_size)
+            .await?;
 
-            self.io_handler
-                .write_block(buf.clone(), self.cur_double_ind_buf_block_idx)
-                .await?;
+        let mut ind_block_idx = self.double_ind.entry(offset_in_double_ind_block);
 
-            self.clear_block(block.block_global_idx).await?;
-            allocated_blocks.push(block);
+        if ind_block_idx == 0 {
+            ind_block_idx = self.allocate_block(true, allocated_blocks).await?;
+            self.double_ind
+                .set_entry(offset_in_double_ind_block, ind_block_idx);
         }
 
         self.handle_set_block(ind_block_idx, offset_in_ind_block, allocated_blocks)
@@ -375,35 +411,23 @@ impl InodeBlockIterator {
 
     async fn handle_set_double_indirect_block(
         &mut self,
+        // guaranteed to not be 0
+        triple_ind_block_idx: u32,
         offset_in_triple_ind_block: usize,
         offset_in_double_ind_block: usize,
         offset_in_ind_block: usize,
         allocated_blocks: &mut Vec<AllocatedBlock>,
     ) -> Result<(), HalFsIOErr> {
-        let buf = self.cur_triple_ind_buf.as_mut().unwrap();
-
-        let num: &mut u32 = bytemuck::from_bytes_mut(
-            &mut buf[offset_in_triple_ind_block * 4..offset_in_triple_ind_block * 4 + 4],
-        );
-
-        let mut double_ind_block_idx = *num;
-
-        if *num == 0 {
-            let block = self
-                .block_allocator
-                .allocate_n_blocks_in_group(self.group_number, 1)
-                .await?
-                .remove(0);
-
-            *num = block.block_global_idx;
-            double_ind_block_idx = *num;
+        self.triple_ind
+            .load(self.io_handler, triple_ind_block_idx, self.block_size)
+            .await?;
 
-            self.io_handler
-                .write_block(buf.clone(), self.cur_triple_ind_buf_block_idx)
-                .await?;
+        let mut double_ind_block_idx = self.triple_ind.entry(offset_in_triple_ind_block);
 
-            self.clear_block(block.block_global_idx).await?;
-            allocated_blocks.push(block);
+        if double_ind_block_idx == 0 {
+            double_ind_block_idx = self.allocate_block(true, allocated_blocks).await?;
+            self.triple_ind
+                .set_entry(offset_in_triple_ind_block, double_ind_block_idx);
         }
 
         self.handle_set_indirect_block(
@@ -423,87 +447,50 @@ impl InodeBlockIterator {
         allocated_blocks: &mut Vec<AllocatedBlock>,
     ) -> Result<(), HalFsIOErr> {
         if self.blocks[idx] == 0 {
-            let block = self
-                .block_allocator
-                .allocate_n_blocks_in_group(self.group_number, 1)
-                .await?
-                .remove(0);
-
-            self.blocks[idx] = block.block_global_idx as u32;
-
-            self.clear_block(self.blocks[idx]).await?;
-
-            allocated_blocks.push(block);
+            self.blocks[idx] = self.allocate_block(true, allocated_blocks).await?;
         }
 
         Ok(())
     }
 
     async fn clear_block(&self, block_idx: u32) -> Result<(), HalFsIOErr> {
-        let mut buf = vec![0u8; self.block_size].into_boxed_slice();
-        buf.fill(0);
-        Ok(self.io_handler.write_block(buf.clone(), block_idx).await?)
+        let buf = vec![0u8; self.block_size].into_boxed_slice();
+        Ok(self.io_handler.write_block(buf, block_idx).await?)
     }
 
-    /// allocate a block for the current location
+    /// allocate a block for the current location, the indirect blocks pointing to it are only
+    /// updated in memory until [`Self::flush`] or [`Self::finalize`]
     pub async fn set(&mut self) -> Result<BlockIterSetRes, HalFsIOErr> {
-        let blocks_per_group = self.block_size / 4;
         let mut allocated_blocks = vec![];
 
         if self.cur_idx < INODE_BLOCK_LIMIT as usize {
             if self.blocks[self.cur_idx] == 0 {
-                let block = self
-                    .block_allocator
-                    .allocate_n_blocks_in_group(self.group_number, 1)
-                    .await?
-                    .remove(0);
-
-                self.blocks[self.cur_idx] = block.block_global_idx as u32;
-                allocated_blocks.push(block);
+                self.blocks[self.cur_idx] =
+                    self.allocate_block(false, &mut allocated_blocks).await?;
             }
 
             self.cur_block_idx = self.blocks[self.cur_idx];
         } else if self.cur_idx < INODE_IND_BLOCK_LIMIT as usize {
+            let [offset_in_ind_block] =
+                self.indirect_offsets::<1>(self.cur_idx - INODE_BLOCK_LIMIT as usize)?;
+
             self.handle_block_in_blocks_array(INODE_BLOCK_LIMIT as usize, &mut allocated_blocks)
                 .await?;
 
-            if self.cur_ind_buf.is_none() {
-                let mut buf = vec![0u8; self.block_size].into_boxed_slice();
-                buf = self
-                    .io_handler
-                    .read_block(buf, self.blocks[INODE_BLOCK_LIMIT as usize])
-                    .await?;
-                self.cur_ind_buf = Some(buf);
-                self.cur_ind_buf_block_idx = self.blocks[INODE_BLOCK_LIMIT as usize];
-            }
-
             let ind_block_idx = self.blocks[INODE_BLOCK_LIMIT as usize];
-            let offset_in_ind_block = self.cur_idx - INODE_BLOCK_LIMIT as usize;
 
             self.handle_set_block(ind_block_idx, offset_in_ind_block, &mut allocated_blocks)
                 .await?;
         } else if self.cur_idx < INODE_DOUBLE_IND_BLOCK_LIMIT as usize {
+            let [offset_in_double_ind_block, offset_in_ind_block] =
+                self.indirect_offsets::<2>(self.cur_idx - INODE_IND_BLOCK_LIMIT as usize)?;
+
             self.handle_block_in_blocks_array(
                 INODE_BLOCK_LIMIT as usize + 1,
                 &mut allocated_blocks,
             )
             .await?;
 
-            if self.cur_double_ind_buf.is_none() {
-                let mut buf = vec![0u8; self.block_size].into_boxed_slice();
-                buf = self
-                    .io_handler
-                    .read_block(buf, self.blocks[INODE_BLOCK_LIMIT as usize + 1])
-                    .await?;
-                self.cur_double_ind_buf = Some(buf);
-                self.cur_double_ind_buf_block_idx = self.blocks[INODE_BLOCK_LIMIT as usize + 1];
-            }
-
-            let offset_in_double_ind_block =
-                (self.cur_idx - INODE_IND_BLOCK_LIMIT as usize) / blocks_per_group;
-            let offset_in_ind_block =
-                (self.cur_idx - INODE_IND_BLOCK_LIMIT as usize) % blocks_per_group;
-
             self.handle_set_indirect_block(
                 self.blocks[INODE_BLOCK_LIMIT as usize + 1],
                 offset_in_double_ind_block,
@@ -511,38 +498,31 @@ impl InodeBlockIterator {
                 &mut allocated_blocks,
             )
             .await?;
-        } else {
+        } else if self.cur_idx < INODE_TRIPLE_IND_BLOCK_LIMIT as usize {
+            let idx_in_triple = self.cur_idx - INODE_DOUBLE_IND_BLOCK_LIMIT as usize;
+            let [
+                offset_in_triple_ind_block,
+                offset_in_double_ind_block,
+                offset_in_ind_block,
+            ] = self.indirect_offsets::<3>(idx_in_triple)?;
+
             self.handle_block_in_blocks_array(
                 INODE_BLOCK_LIMIT as usize + 2,
                 &mut allocated_blocks,
             )
             .await?;
 
-            if self.cur_triple_ind_buf.is_none() {
-                let mut buf = vec![0u8; self.block_size].into_boxed_slice();
-                buf = self
-                    .io_handler
-                    .read_block(buf, self.blocks[INODE_BLOCK_LIMIT as usize + 2])
-                    .await?;
-                self.cur_triple_ind_buf = Some(buf);
-                self.cur_triple_ind_buf_block_idx = self.blocks[INODE_BLOCK_LIMIT as usize + 2];
-            }
-
-            let offset_in_triple_ind_block = (self.cur_idx - INODE_DOUBLE_IND_BLOCK_LIMIT as usize)
-                / (blocks_per_group * blocks_per_group);
-            let offset_in_double_ind_block = (self.cur_idx - INODE_DOUBLE_IND_BLOCK_LIMIT as usize)
-                / blocks_per_group
-                % blocks_per_group;
-            let offset_in_ind_block =
-                (self.cur_idx - INODE_DOUBLE_IND_BLOCK_LIMIT as usize) % blocks_per_group;
-
             self.handle_set_double_indirect_block(
+                self.blocks[INODE_BLOCK_LIMIT as usize + 2],
                 offset_in_triple_ind_block,
                 offset_in_double_ind_block,
                 offset_in_ind_block,
                 &mut allocated_blocks,
             )
             .await?;
+        } else {
+            // past the last block the triple indirect block can address
+            return Err(HalFsIOErr::FileTooLarge);
         }
 
         Ok(BlockIterSetRes {
@@ -551,12 +531,48 @@ impl InodeBlockIterator {
         })
     }
 
+    /// every cached indirect block that [`Self::set`] changed, the lowest level first so a parent
+    /// never points at a block that isn't on disk yet. They're no longer dirty afterwards
+    fn take_dirty_blocks(&mut self) -> Vec<(u32, Box<[u8]>)> {
+        [&mut self.ind, &mut self.double_ind, &mut self.triple_ind]
+            .into_iter()
+            .filter_map(CachedBlock::take_dirty)
+            .collect()
+    }
+
+    /// writes back every cached indirect block that [`Self::set`] changed
+    pub async fn flush(&mut self) -> Result<(), HalFsIOErr> {
+        for (block_idx, buf) in self.take_dirty_blocks() {
+            self.io_handler.write_block(buf, block_idx).await?;
+        }
+
+        Ok(())
+    }
+
+    /// ends a [`Self::set`] session, everything it linked is on disk once this returns and the
+    /// result is what the inode's i_block has to be set to before the inode is written
+    pub async fn finalize(mut self) -> Result<[u32; 15], HalFsIOErr> {
+        self.flush().await?;
+
+        Ok(self.blocks)
+    }
+
+    /// true while [`Self::set`] left changes that only live in memory
+    pub fn is_dirty(&self) -> bool {
+        self.ind.dirty || self.double_ind.dirty || self.triple_ind.dirty
+    }
+
     pub fn get_blocks_array(&self) -> [u32; 15] {
         self.blocks
     }
+}
 
-    pub fn into_blocks_array(self) -> [u32; 15] {
-        self.blocks
+/// dropping can't wait for the disk, whoever called set has to finalize before letting go
+impl Drop for InodeBlockIterator {
+    fn drop(&mut self) {
+        if self.is_dirty() {
+            log!("Block iterator dropped with unwritten indirect blocks, the file is corrupted");
+        }
     }
 }
 
@@ -568,9 +584,238 @@ pub struct BlockIterElement {
     pub block_idx: u32,
 }
 
+impl BlockIterElement {
+    /// the block was never allocated, the buffer has been filled with zeros
+    pub fn is_hole(&self) -> bool {
+        !self.is_terminated && self.block_idx == 0
+    }
+}
+
 pub struct BlockIterSetRes {
     /// if it is empty it means that there was lba originally
     pub allocated_blocks: Vec<AllocatedBlock>,
     /// the address of the block at this index
     pub block_idx: u32,
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::CachedBlock;
+    use crate::{
+        crypto::guid::Guid,
+        drivers::fs::ext2::{
+            EXT2_S_IFREG, Inode,
+            read::{INODE_BLOCK_LIMIT, INODE_IND_BLOCK_LIMIT, INODE_TRIPLE_IND_BLOCK_LIMIT},
+            structs::Ext2Fs,
+        },
+        end_test,
+        hal::{fs::HalFsIOErr, ram_disk},
+        terminal::test::block_on,
+        test_name,
+    };
+
+    const BLOCK_SIZE: usize = 1024;
+
+    /// entry `idx` of `block_idx` as it is on disk
+    async fn disk_entry(fs: &Ext2Fs, block_idx: u32, idx: usize) -> u32 {
+        let buf = fs
+            .io_handler
+            .read_block(fs.get_buffer(), block_idx)
+            .await
+            .expect("Failed to read an indirect block");
+        u32::from_le_bytes(buf[idx * 4..idx * 4 + 4].try_into().unwrap())
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn indirect_blocks_written_back() {
+        test_name!("dirty indirect blocks are written back when evicted or flushed");
+
+        let guid = Guid::from_bytes([0x65; 16]);
+        block_on(async {
+            let fs = Ext2Fs::on_ram_disk(guid, 1).await;
+            let io_handler = fs.io_handler;
+            let mut cache = CachedBlock::default();
+            let last = BLOCK_SIZE / 4 - 1;
+            // free blocks at the end of the group, nothing else touches them
+            let (first, second) = (1000, 1001);
+
+            // a write filling the end of one indirect block and spilling into the next
+            cache.load(io_handler, first, BLOCK_SIZE).await.unwrap();
+            cache.set_entry(last - 1, 7);
+            cache.set_entry(last, 8);
+            assert_eq!(disk_entry(&fs, first, last).await, 0);
+
+            cache.load(io_handler, second, BLOCK_SIZE).await.unwrap();
+            assert_eq!(disk_entry(&fs, first, last - 1).await, 7);
+            assert_eq!(disk_entry(&fs, first, last).await, 8);
+            cache.set_entry(0, 9);
+
+            // going back reads what was written, not a stale copy
+            cache.load(io_handler, first, BLOCK_SIZE).await.unwrap();
+            assert_eq!(cache.entry(last), 8);
+            assert_eq!(disk_entry(&fs, second, 0).await, 9);
+
+            // nothing changed since the last load, nothing to write
+            assert!(cache.take_dirty().is_none());
+
+            // loading the block that's already cached keeps the changes in memory
+            cache.set_entry(1, 10);
+            cache.load(io_handler, first, BLOCK_SIZE).await.unwrap();
+            assert!(cache.dirty);
+            assert_eq!(disk_entry(&fs, first, 1).await, 0);
+
+            // flushing writes the cached block and keeps it around
+            let (block_idx, buf) = cache.take_dirty().unwrap();
+            assert_eq!(block_idx, first);
+            io_handler.write_block(buf, block_idx).await.unwrap();
+            assert_eq!(disk_entry(&fs, first, 1).await, 10);
+            assert!(cache.holds(first));
+            assert!(!cache.dirty);
+        });
+        ram_disk::unregister(guid);
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn set_across_the_direct_blocks() {
+        test_name!("setting blocks on both sides of the direct ones links each where it belongs");
+
+        let guid = Guid::from_bytes([0x66; 16]);
+        block_on(async {
+            let fs = Ext2Fs::on_ram_disk(guid, 1).await;
+            let direct = INODE_BLOCK_LIMIT as usize;
+            let inode = Inode {
+                i_mode: EXT2_S_IFREG,
+                i_size: (direct as u32 + 1) * BLOCK_SIZE as u32,
+                ..Default::default()
+            };
+
+            let mut iterator = fs.create_block_iterator(&inode, 0);
+            iterator.skip(direct - 1);
+            let last_direct = iterator.next_set().await.unwrap();
+            let first_indirect = iterator.set().await.unwrap();
+
+            // the last direct block, then the indirect block along with the first block it holds
+            assert_eq!(last_direct.allocated_blocks.len(), 1);
+            assert_eq!(first_indirect.allocated_blocks.len(), 2);
+
+            // the indirect block was zeroed when allocated, the pointer only lives in memory so far
+            let ind_block_idx = iterator.get_blocks_array()[direct];
+            assert_ne!(ind_block_idx, 0);
+            assert_eq!(disk_entry(&fs, ind_block_idx, 0).await, 0);
+            assert!(iterator.is_dirty());
+
+            let blocks = iterator.finalize().await.expect("Failed to finalize");
+            assert!(blocks[..direct - 1].iter().all(|&block_idx| block_idx == 0));
+            assert_eq!(blocks[direct - 1], last_direct.block_idx);
+            assert_eq!(blocks[direct], ind_block_idx);
+            assert!(blocks[direct + 1..].iter().all(|&block_idx| block_idx == 0));
+
+            assert_eq!(
+                disk_entry(&fs, ind_block_idx, 0).await,
+                first_indirect.block_idx
+            );
+            assert_eq!(disk_entry(&fs, ind_block_idx, 1).await, 0);
+        });
+        ram_disk::unregister(guid);
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn finalize_persists_set_session() {
+        test_name!("finalizing the block iterator persists every indirect block");
+
+        let guid = Guid::from_bytes([0x67; 16]);
+        block_on(async {
+            let fs = Ext2Fs::on_ram_disk(guid, 1).await;
+            let direct = INODE_BLOCK_LIMIT as usize;
+            let inode = Inode {
+                i_mode: EXT2_S_IFREG,
+                i_size: (INODE_IND_BLOCK_LIMIT + 1) * BLOCK_SIZE as u32,
+                ..Default::default()
+            };
+
+            // a set session into the indirect range and then the double indirect one
+            let mut iterator = fs.create_block_iterator(&inode, 0);
+            iterator.skip(direct + 3);
+            let in_ind = iterator.set().await.unwrap();
+            let cur_idx = iterator.cur_idx();
+            iterator.skip(INODE_IND_BLOCK_LIMIT as usize - cur_idx);
+            let in_double_ind = iterator.set().await.unwrap();
+
+            // the indirect block was written when the double indirect range evicted it
+            let blocks = iterator.get_blocks_array();
+            let (ind, double_ind) = (blocks[direct], blocks[direct + 1]);
+            assert_eq!(disk_entry(&fs, ind, 3).await, in_ind.block_idx);
+
+            // the rest only lives in memory until finalize
+            assert!(iterator.is_dirty());
+            assert_eq!(disk_entry(&fs, double_ind, 0).await, 0);
+
+            let finalized = iterator.finalize().await.expect("Failed to finalize");
+            assert_eq!(finalized, blocks);
+
+            let second_ind = disk_entry(&fs, double_ind, 0).await;
+            assert_ne!(second_ind, 0);
+            assert_ne!(second_ind, ind);
+            assert_eq!(
+                disk_entry(&fs, second_ind, 0).await,
+                in_double_ind.block_idx
+            );
+            assert_eq!(disk_entry(&fs, ind, 3).await, in_ind.block_idx);
+        });
+        ram_disk::unregister(guid);
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn past_triple_indirect_capacity() {
+        test_name!("blocks past the triple indirect capacity fail cleanly");
+
+        let fs = Ext2Fs::detached(false);
+        let inode = Inode {
+            i_mode: EXT2_S_IFREG,
+            i_size: u32::MAX,
+            ..Default::default()
+        };
+        let mut iterator = fs.create_block_iterator(&inode, 0);
+
+        // a 32 bit i_size stops short of the capacity, this is the size a corrupt inode claims
+        let capacity = INODE_TRIPLE_IND_BLOCK_LIMIT as usize;
+        iterator.blocks_limit = capacity + 10;
+        iterator.skip(capacity + 5);
+
+        let res = block_on(iterator.get(vec![0u8; BLOCK_SIZE].into_boxed_slice()));
+        assert!(matches!(res, Err(HalFsIOErr::Corrupted)));
+
+        // refused before anything is allocated, the detached fs can't allocate
+        assert!(matches!(
+            block_on(iterator.set()),
+            Err(HalFsIOErr::FileTooLarge)
+        ));
+
+        // the last block the triple indirect block addresses and the one after it
+        let per_block = BLOCK_SIZE / 4;
+        assert_eq!(
+            iterator
+                .indirect_offsets::<3>(per_block.pow(3) - 1)
+                .unwrap(),
+            [per_block - 1; 3]
+        );
+        assert!(matches!(
+            iterator.indirect_offsets::<3>(per_block.pow(3)),
+            Err(HalFsIOErr::FileTooLarge)
+        ));
+
+        end_test!();
+    }
+}
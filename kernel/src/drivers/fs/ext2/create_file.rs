@@ -3,7 +3,7 @@ use alloc::{boxed::Box, vec::Vec};
 
 use crate::{
     drivers::fs::ext2::{
-        BLOCK_GROUP_DESCRIPTOR_SIZE, BLOCK_SIZE, Inode, InodePlus,
+        BLOCK_SIZE, EXT2_S_IFDIR, EXT2_S_IFREG, Inode, InodePlus,
         structs::{Ext2Fs, block_group_size},
     },
     hal::{fs::HalFsIOErr, storage::SECTOR_SIZE},
@@ -13,6 +13,18 @@ use crate::{
 pub const RESERVED_BOOT_RECORD_OFFSET: i64 = 2;
 pub const BLOCK_SECTOR_SIZE: i64 = BLOCK_SIZE as i64 / SECTOR_SIZE as i64 ;
 
+/// a freshly allocated inode with nothing in it yet, only the permission bits of `perms` are kept
+pub fn new_inode(perms: i32, is_dir: bool, time: u32) -> Inode {
+    Inode {
+        i_mode: (perms as u16 & 0o7777) | if is_dir { EXT2_S_IFDIR } else { EXT2_S_IFREG },
+        i_atime: time,
+        i_ctime: time,
+        i_mtime: time,
+        i_links_count: if is_dir { 2 } else { 1 },
+        ..Default::default()
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd)]
 pub struct AllocatedBlock {
     pub addr: i64,
@@ -53,52 +65,13 @@ impl Ext2Fs {
             .await?;
 
         for (idx, block) in blocks_allocated.iter().enumerate() {
-            inode.i_block[idx] = block.addr as u32;
+            inode.i_block[idx] = block.block_global_idx;
         }
-        inode.i_blocks += blocks_allocated.len() as u32 * BLOCK_SIZE / SECTOR_SIZE as u32;
+        inode.i_blocks += self.blocks_to_i_blocks(blocks_allocated.len() as u32);
 
         Ok(blocks_allocated)
     }
 
-    pub async fn find_available_inode(&self) -> Result<InodePlus, HalFsIOErr> {
-        let group_count = self.super_block.block_groups_count();
-
-        let cur_lba = 0;
-        let mut buf: Box<[u8]> = self.get_buffer();
-        let table_lba = self.get_block_group_table_lba();
-
-        for group_idx in 0..group_count {
-            let lba_offset =
-                (group_idx as i64 * BLOCK_GROUP_DESCRIPTOR_SIZE as i64) / SECTOR_SIZE as i64;
-
-            let lba = table_lba + lba_offset;
-            if cur_lba != lba {
-                buf = self.read_sectors(buf, lba).await?;
-            }
-
-            let block_group = self.get_group_from_buffer(group_idx as i64, &buf)?;
-
-            if block_group.descriptor.bg_free_inodes_count == 0 {
-                continue;
-            }
-
-            let mut bitmap_buf = self.get_buffer();
-            bitmap_buf = self
-                .read_sectors(bitmap_buf, block_group.get_inode_bitmap_lba())
-                .await?;
-
-            for idx in 0..self.super_block.s_inodes_per_group as usize {
-                if bitmap_buf[idx / 8] & 0x1 << (idx % 8) == 0 {
-                    let ino = Inode::default();
-
-                    return Ok(self.relative_idx_to_inode_plus(ino, group_idx, idx as u32));
-                }
-            }
-        }
-
-        Err(HalFsIOErr::NoAvailableInode)
-    }
-
     pub async fn write_newly_allocated_blocks(
         &mut self,
         buf: Box<[u8]>,
@@ -115,11 +88,28 @@ impl Ext2Fs {
         let buf = self.get_buffer();
         self.write_newly_allocated_blocks(buf, blocks).await?;
 
-        self.write_new_inode(inode).await?;
+        self.write_inode(inode).await?;
 
         Ok(())
     }
 
+    /// gives back an inode whose creation failed before any directory pointed at it, along with
+    /// the blocks only reserved for it so far. `err` is what made the creation fail and is passed on
+    pub async fn abandon_new_inode<T>(
+        &mut self,
+        inode_num: u32,
+        reserved: &[AllocatedBlock],
+        err: HalFsIOErr,
+    ) -> Result<T, HalFsIOErr> {
+        self.block_allocator.cancel_allocation(reserved).await;
+
+        if let Err(free_err) = self.free_inode(inode_num).await {
+            log!("Failed to free inode {inode_num} after {err:?}: {free_err:?}");
+        }
+
+        Err(err)
+    }
+
     pub async fn create_file(
         &mut self,
         inode: &mut InodePlus,
@@ -136,6 +126,12 @@ impl Ext2Fs {
         is_dir: bool,
         perms: i32,
     ) -> Result<InodePlus, HalFsIOErr> {
+        self.ensure_writable()?;
+
+        if is_dir {
+            return self.create_directory(dir_inode, name, perms as u16).await;
+        }
+
         if name.len() > 255 {
             return Err(HalFsIOErr::NameTooLong);
         }
@@ -152,57 +148,34 @@ impl Ext2Fs {
             .read_datetime()
             .map_or_else(|| 0, |dt| rtc_to_posix(&dt));
 
-        let mut allocated_inode = self.find_available_inode().await?;
+        // the bitmap and the free counts are taken care of here, new inodes go next to their
+        // directory if there's room
+        let inode_num = self.allocate_inode(dir_inode.group_number).await?;
 
-        log!("Found availble inode: {:?}", allocated_inode);
+        let mut allocated_inode =
+            self.global_idx_to_inode_plus(new_inode(perms, false, time), inode_num);
+
+        log!("Allocated inode: {:?}", allocated_inode);
 
         let inode = &mut allocated_inode.inode;
 
-        // Set mode and file type bits (directory vs regular file)
-        const S_IFDIR: u16 = 0x4000;
-        const S_IFREG: u16 = 0x8000;
-        inode.i_mode = (perms as u16) | if is_dir { S_IFDIR } else { S_IFREG };
-        inode.i_uid = 0; // TODO: support UID
-        inode.i_size = 0;
-        inode.i_atime = time;
-        inode.i_ctime = time;
-        inode.i_mtime = time;
-        inode.i_dtime = 0;
-        inode.i_gid = 0; // TODO: support GID
-        inode.i_links_count = if is_dir { 2 } else { 1 };
-        inode.i_blocks = 0;
-        inode.i_flags = 0;
-        inode.i_osd1 = 0;
-        inode.i_osd2 = [0; 12];
-        inode.i_block = [0; 15];
-        inode.i_file_acl = 0;
-        inode.i_dir_acl = 0;
-        inode.i_faddr = 0;
-        inode.i_generation = 0;
-
-        let blocks = self
+        let blocks = match self
             .allocated_blocks_for_new_inode(
                 inode,
                 allocated_inode.group_number.into(),
-                if is_dir {
-                    self.super_block.s_prealloc_dir_blocks as usize
-                } else {
-                    self.super_block.s_prealloc_blocks as usize
-                },
+                self.super_block.s_prealloc_blocks as usize,
             )
-            .await?;
-
-        self.add_dir_entry(dir_inode, allocated_inode.absolute_idx as u32, name)
-            .await?;
-
-        if is_dir {
-            let temp = allocated_inode.absolute_idx as u32;
-            self.add_dir_entry(&mut allocated_inode, temp, ".").await?;
-            self.add_dir_entry(&mut allocated_inode, dir_inode.absolute_idx, "..")
-                .await?;
-
-            dir_inode.inode.i_links_count = dir_inode.inode.i_links_count.saturating_add(1);
-            self.write_inode(dir_inode).await?;
+            .await
+        {
+            Ok(blocks) => blocks,
+            Err(e) => return self.abandon_new_inode(inode_num, &[], e).await,
+        };
+
+        if let Err(e) = self
+            .add_dir_entry(dir_inode, allocated_inode.absolute_idx as u32, name)
+            .await
+        {
+            return self.abandon_new_inode(inode_num, &blocks, e).await;
         }
 
         self.write_changes(&allocated_inode, &blocks).await?;
@@ -211,3 +184,136 @@ impl Ext2Fs {
         Ok(allocated_inode)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::{string::String, vec};
+    use dvida_serialize::{DvSerialize, Endianness};
+
+    use super::new_inode;
+    use crate::{
+        crypto::guid::Guid,
+        drivers::fs::ext2::{
+            DirEntry, EXT2_S_IFDIR, EXT2_S_IFREG, dirs::place_dir_entry,
+            open::ROOT_DIRECTORY_INODE_IDX, structs::Ext2Fs,
+        },
+        end_test,
+        hal::{fs::HalFsIOErr, path::Path, ram_disk},
+        terminal::test::block_on,
+        test_name,
+    };
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn ext2_create_file_in_root() {
+        test_name!("ext2 create a file in the root directory");
+
+        let file = new_inode(0o100644, false, 1_700_000_000);
+        assert_eq!(file.i_mode, EXT2_S_IFREG | 0o644);
+        assert_eq!(file.i_links_count, 1);
+        assert_eq!(file.i_mtime, 1_700_000_000);
+        assert_eq!((file.i_size, file.i_blocks, file.i_block), (0, 0, [0; 15]));
+
+        let dir = new_inode(0o755, true, 0);
+        assert_eq!(dir.i_mode, EXT2_S_IFDIR | 0o755);
+        assert_eq!(dir.i_links_count, 2);
+
+        let guid = Guid::from_bytes([0x61; 16]);
+        block_on(async {
+            let mut fs = Ext2Fs::on_ram_disk(guid, 1).await;
+            let mut root = fs
+                .get_nth_inode(ROOT_DIRECTORY_INODE_IDX as u32)
+                .await
+                .unwrap();
+
+            let hello = fs.create_file(&mut root, "hello.txt", 0o644).await.unwrap();
+            let b = fs.create_file(&mut root, "b", 0o644).await.unwrap();
+            assert_ne!(hello.absolute_idx, b.absolute_idx);
+
+            // both are found through the directory on disk, next to what was there before
+            let root = fs
+                .get_nth_inode(ROOT_DIRECTORY_INODE_IDX as u32)
+                .await
+                .unwrap();
+            for (name, inode) in [("hello.txt", &hello), ("b", &b)] {
+                assert_eq!(
+                    fs.find_entry_by_name(name, &root).await.unwrap(),
+                    Some(inode.absolute_idx as i64)
+                );
+            }
+            assert_eq!(
+                fs.find_entry_by_name("..", &root).await.unwrap(),
+                Some(ROOT_DIRECTORY_INODE_IDX as i64)
+            );
+            assert_eq!(fs.find_entry_by_name("c", &root).await.unwrap(), None);
+
+            let resolved = fs
+                .resolve_path(&Path::new_appended("/hello.txt"))
+                .await
+                .unwrap();
+            assert_eq!(resolved.absolute_idx, hello.absolute_idx);
+            assert!(resolved.inode.is_regular_file());
+        });
+        ram_disk::unregister(guid);
+
+        // no slack left means a new block has to be allocated
+        let mut full = vec![0u8; 264];
+        let mut long = DirEntry::new(14, "a".repeat(250));
+        long.rec_len = 264;
+        long.serialize(Endianness::Little, &mut full).unwrap();
+        let mut entry = DirEntry::new(15, String::from("b"));
+        assert!(!place_dir_entry(&mut full, 264, &mut entry).unwrap());
+
+        // a zero record length would never move on
+        const BLOCK_SIZE: usize = 1024;
+        assert!(matches!(
+            place_dir_entry(&mut vec![0u8; BLOCK_SIZE], BLOCK_SIZE, &mut entry),
+            Err(HalFsIOErr::Corrupted)
+        ));
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn failed_create_frees_the_inode() {
+        test_name!("a file that can't be created doesn't keep its inode");
+
+        let guid = Guid::from_bytes([0x62; 16]);
+        block_on(async {
+            let mut fs = Ext2Fs::on_ram_disk(guid, 1).await;
+            let mut root = fs
+                .get_nth_inode(ROOT_DIRECTORY_INODE_IDX as u32)
+                .await
+                .unwrap();
+            let free_inodes = fs.super_block.s_free_inodes_count;
+
+            // more blocks than the direct pointers can hold fails after the inode was taken
+            fs.super_block.s_prealloc_blocks = 13;
+            let res = fs.create_file(&mut root, "a", 0o644).await;
+            assert!(matches!(res, Err(HalFsIOErr::Unsupported)));
+            assert_eq!({ fs.super_block.s_free_inodes_count }, free_inodes);
+            assert_eq!(
+                {
+                    fs.get_group(0)
+                        .await
+                        .unwrap()
+                        .descriptor
+                        .bg_free_inodes_count
+                },
+                free_inodes as u16
+            );
+            assert_eq!(fs.find_entry_by_name("a", &root).await.unwrap(), None);
+
+            // the next file gets the same inode
+            fs.super_block.s_prealloc_blocks = 0;
+            let next = fs.allocate_inode(0).await.unwrap();
+            fs.free_inode(next).await.unwrap();
+            let file = fs.create_file(&mut root, "a", 0o644).await.unwrap();
+            assert_eq!(file.absolute_idx, next);
+        });
+        ram_disk::unregister(guid);
+
+        end_test!();
+    }
+}
@@ -1,36 +1,64 @@
-use alloc::boxed::Box;
+use alloc::{boxed::Box, collections::btree_set::BTreeSet};
 use dvida_serialize::DvDeserialize;
 
 use crate::{
-    drivers::fs::ext2::{
-        BLOCK_SIZE, InodePlus,
-        create_file::RESERVED_BOOT_RECORD_OFFSET,
-        read::{INODE_BLOCK_LIMIT, INODE_DOUBLE_IND_BLOCK_LIMIT, INODE_IND_BLOCK_LIMIT},
-        structs::Ext2Fs,
-    },
+    drivers::fs::ext2::{InodePlus, inode_allocator::clear_bitmap_bit, structs::Ext2Fs},
     hal::{fs::HalFsIOErr, path::Path},
 };
 
+/// the group of a block and its bit in that group's block bitmap
+pub fn block_bitmap_position(block_idx: u32, blocks_per_group: u32) -> (u32, usize) {
+    (
+        block_idx / blocks_per_group,
+        (block_idx % blocks_per_group) as usize,
+    )
+}
+
 impl Ext2Fs {
     pub async fn delete_file(&mut self, path: Path) -> Result<(), HalFsIOErr> {
-        let (directory_inode, file_inode) = self.walk_path(&path).await?;
-        self.find_entry_by_name_and_delete(
+        self.ensure_writable()?;
+
+        let (mut directory_inode, _) = self.walk_path(&path).await?;
+
+        self.unlink(
+            &mut directory_inode,
             &path.file_name().ok_or(HalFsIOErr::BadPath)?,
-            &directory_inode,
         )
-        .await?;
+        .await
+    }
+
+    /// removes `name` from `dir`, the inode and its blocks are freed along with the last link.
+    /// Directories go through rmdir
+    pub async fn unlink(&mut self, dir: &mut InodePlus, name: &str) -> Result<(), HalFsIOErr> {
+        self.ensure_writable()?;
 
-        let Some(mut file_inode) = file_inode else {
-            return Err(HalFsIOErr::NoSuchFileOrDirectory);
-        };
+        let inode_num = self
+            .find_entry_by_name(name, dir)
+            .await?
+            .ok_or(HalFsIOErr::NoSuchFileOrDirectory)?;
+
+        let mut inode = self.get_nth_inode(inode_num as u32).await?;
+        if inode.inode.is_directory() {
+            return Err(HalFsIOErr::IsDirectory);
+        }
 
-        file_inode.inode.i_links_count -= 1;
+        self.find_entry_by_name_and_delete(name, dir).await?;
 
-        if file_inode.inode.i_links_count == 0 {
-            self.free_inode(&mut file_inode).await?;
+        let time = crate::time::Rtc::new()
+            .read_datetime()
+            .map_or(0, |dt| crate::time::formats::rtc_to_posix(&dt));
+
+        dir.inode.i_mtime = time;
+        dir.inode.i_ctime = time;
+        self.write_inode(dir).await?;
+
+        inode.inode.i_links_count = inode.inode.i_links_count.saturating_sub(1);
+        if inode.inode.i_links_count == 0 {
+            return self.release_inode(&mut inode).await;
         }
 
-        Ok(())
+        inode.inode.i_ctime = time;
+        self.write_inode(&inode).await
     }
 
     /// doesn't write changes to the super block
@@ -40,6 +68,8 @@ impl Ext2Fs {
         cur_bitmap_lba: &mut i64,
         mut buf: Box<[u8]>,
     ) -> Result<Box<[u8]>, HalFsIOErr> {
+        self.ensure_writable()?;
+
         let block_group = self
             .group_manager
             .get_group_from_block_idx(block_lba)
@@ -51,9 +81,10 @@ impl Ext2Fs {
             *cur_bitmap_lba = bitmap_lba;
         }
 
-        let block_rel_idx = block_lba as usize % self.group_manager.blocks_per_group as usize;
+        let (_, block_rel_idx) =
+            block_bitmap_position(block_lba, self.group_manager.blocks_per_group);
 
-        buf[block_rel_idx / 8] &= !(1 << (block_rel_idx % 8));
+        clear_bitmap_bit(&mut buf, block_rel_idx);
         self.write_sectors(buf.clone(), bitmap_lba).await?;
 
         self.block_allocator.add_freed_block(block_lba).await;
@@ -67,14 +98,13 @@ impl Ext2Fs {
         cur_bitmap_lba: &mut i64,
         mut cur_buf: Box<[u8]>,
     ) -> Result<Box<[u8]>, HalFsIOErr> {
-        let mut buf: Box<[u8]> = Box::new([0u8; BLOCK_SIZE as usize]);
+        let mut buf: Box<[u8]> = self.get_buffer();
         buf = self.io_handler.read_block(buf, block_idx).await?;
-        for i in (0..BLOCK_SIZE).step_by(4) {
-            let idx = u32::deserialize(dvida_serialize::Endianness::Little, &buf[i as usize..])?.0;
+        for i in (0..buf.len()).step_by(4) {
+            let idx = u32::deserialize(dvida_serialize::Endianness::Little, &buf[i..])?.0;
+            // holes can be followed by allocated blocks so keep going
             if idx == 0 {
-                // remaining pointers are zero; stop iterating so we still free the
-                // indirect block itself below
-                break;
+                continue;
             }
 
             cur_buf = self.free_block(idx, cur_bitmap_lba, cur_buf).await?;
@@ -92,17 +122,17 @@ impl Ext2Fs {
         cur_bitmap_lba: &mut i64,
         mut cur_buf: Box<[u8]>,
     ) -> Result<Box<[u8]>, HalFsIOErr> {
-        let mut buf: Box<[u8]> = Box::new([0u8; BLOCK_SIZE as usize]);
+        let mut buf: Box<[u8]> = self.get_buffer();
         buf = self.io_handler.read_block(buf, block_idx).await?;
-        for i in (0..BLOCK_SIZE).step_by(4) {
-            let lba = u32::deserialize(dvida_serialize::Endianness::Little, &buf[i as usize..])?.0;
+        for i in (0..buf.len()).step_by(4) {
+            let lba = u32::deserialize(dvida_serialize::Endianness::Little, &buf[i..])?.0;
             if lba == 0 {
-                break;
+                continue;
             }
 
             // lba is the address of an indirect block
             cur_buf = self
-                .free_indirect_block(block_idx, cur_bitmap_lba, cur_buf)
+                .free_indirect_block(lba, cur_bitmap_lba, cur_buf)
                 .await?;
         }
 
@@ -118,13 +148,12 @@ impl Ext2Fs {
         cur_bitmap_lba: &mut i64,
         mut cur_buf: Box<[u8]>,
     ) -> Result<Box<[u8]>, HalFsIOErr> {
-        let mut buf: Box<[u8]> = Box::new([0u8; BLOCK_SIZE as usize]);
+        let mut buf: Box<[u8]> = self.get_buffer();
         buf = self.io_handler.read_block(buf, block_idx).await?;
-        for i in (0..BLOCK_SIZE).step_by(4) {
-            let block_idx =
-                u32::deserialize(dvida_serialize::Endianness::Little, &buf[i as usize..])?.0;
+        for i in (0..buf.len()).step_by(4) {
+            let block_idx = u32::deserialize(dvida_serialize::Endianness::Little, &buf[i..])?.0;
             if block_idx == 0 {
-                break;
+                continue;
             }
 
             // lba is the address of a double-indirect block
@@ -139,92 +168,165 @@ impl Ext2Fs {
         Ok(cur_buf)
     }
 
-    /// doesn't update the changes in the superblock to the filesystem
+    /// frees every block the inode points at, indirect blocks included, by walking it with the
+    /// block iterator. Doesn't update the changes in the superblock to the filesystem
     pub async fn free_blocks(&mut self, inode: &mut InodePlus) -> Result<(), HalFsIOErr> {
-        let mut cur_buf: Box<[u8]> = Box::new([0u8; BLOCK_SIZE as usize]);
+        self.ensure_writable()?;
+
+        // fast symlinks and device nodes keep their data where the block pointers would be, and
+        // own no blocks
+        if inode.inode.i_blocks == 0 {
+            return Ok(());
+        }
+
+        let mut iterator = self.create_block_iterator(&inode.inode, inode.group_number.into());
+        iterator.include_preallocated();
+
+        let mut indirect_blocks = BTreeSet::new();
+        let mut cur_buf: Box<[u8]> = self.get_buffer();
         let mut cur_bitmap_lba = 0;
-        for i in 0..INODE_BLOCK_LIMIT as usize {
-            if inode.inode.i_block[i] == 0 {
-                return Ok(());
+        while let Some(block_idx) = iterator.next_idx().await? {
+            indirect_blocks.extend(iterator.loaded_indirect_blocks());
+
+            // a hole, the blocks after it can still be allocated
+            if block_idx == 0 {
+                continue;
             }
 
             cur_buf = self
-                .free_block(inode.inode.i_block[i], &mut cur_bitmap_lba, cur_buf)
+                .free_block(block_idx, &mut cur_bitmap_lba, cur_buf)
                 .await?;
         }
 
-        if inode.inode.i_blocks > INODE_BLOCK_LIMIT {
+        for block_idx in indirect_blocks {
             cur_buf = self
-                .free_indirect_block(
-                    inode.inode.i_block[INODE_BLOCK_LIMIT as usize],
-                    &mut cur_bitmap_lba,
-                    cur_buf,
-                )
-                .await?;
-        }
-        if inode.inode.i_blocks > INODE_IND_BLOCK_LIMIT {
-            cur_buf = self
-                .free_double_indirect_block(
-                    inode.inode.i_block[INODE_BLOCK_LIMIT as usize + 1],
-                    &mut cur_bitmap_lba,
-                    cur_buf,
-                )
-                .await?;
-        }
-        if inode.inode.i_blocks > INODE_DOUBLE_IND_BLOCK_LIMIT {
-            cur_buf = self
-                .free_triple_indirect_block(
-                    inode.inode.i_block[INODE_BLOCK_LIMIT as usize + 2],
-                    &mut cur_bitmap_lba,
-                    cur_buf,
-                )
+                .free_block(block_idx, &mut cur_bitmap_lba, cur_buf)
                 .await?;
         }
 
-        self.super_block.s_free_blocks_count +=
-            inode.inode.i_blocks / self.super_block.block_size();
+        self.super_block.s_free_blocks_count += self.inode_block_count(&inode.inode);
+        inode.inode.i_blocks = 0;
+        inode.inode.i_block = [0; 15];
 
         Ok(())
     }
 
-    pub async fn free_inode(&mut self, inode: &mut InodePlus) -> Result<(), HalFsIOErr> {
-        self.free_blocks(inode).await?;
-
-        let time = crate::time::formats::rtc_to_posix(
-            &crate::time::Rtc::new()
-                .read_datetime()
-                .expect("Failed to get time"),
-        );
-
-        inode.inode.i_dtime = time;
-        inode.inode.i_blocks = 0;
-
-        self.write_inode(inode).await?;
-
-        let inode_bitmap_lba = self
-            .get_group(inode.group_number as i64)
-            .await?
-            .get_inode_bitmap_lba();
-
-        let mut buf: Box<[u8]> = Box::new([0u8; BLOCK_SIZE as usize]);
-        buf = self.read_sectors(buf, inode_bitmap_lba).await?;
+    /// frees the inode's data blocks and then the inode itself
+    pub async fn release_inode(&mut self, inode: &mut InodePlus) -> Result<(), HalFsIOErr> {
+        self.ensure_writable()?;
 
-        buf[inode.relative_idx as usize / 8] &= !(1 << (inode.relative_idx % 8));
-
-        self.write_sectors(buf.clone(), inode_bitmap_lba).await?;
-
-        self.super_block.s_free_inodes_count += 1;
-
-        buf.fill(0);
+        self.free_blocks(inode).await?;
+        self.block_allocator.write_freed_blocks().await?;
 
-        let super_block_bytes = bytemuck::bytes_of(&self.super_block);
-        for i in 0..super_block_bytes.len() {
-            buf[i] = super_block_bytes[i];
-        }
+        // the superblock gets written with the freed block count here
+        self.free_inode(inode.absolute_idx).await
+    }
+}
 
-        self.write_sectors(buf, RESERVED_BOOT_RECORD_OFFSET).await?;
-        self.block_allocator.write_freed_blocks().await?;
+#[cfg(test)]
+mod tests {
+    use alloc::{collections::btree_set::BTreeSet, vec};
+
+    use crate::{
+        crypto::guid::Guid,
+        drivers::fs::ext2::{
+            InodePlus,
+            open::ROOT_DIRECTORY_INODE_IDX,
+            read::INODE_BLOCK_LIMIT,
+            structs::{Ext2Fs, RAM_DISK_BLOCKS_PER_GROUP},
+        },
+        end_test,
+        hal::{
+            fs::{HalFsIOErr, HalIOCtx},
+            ram_disk,
+        },
+        terminal::test::block_on,
+        test_name,
+    };
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn ext2_unlink_frees_blocks() {
+        test_name!("ext2 unlink frees blocks for reuse");
+
+        let guid = Guid::from_bytes([0x63; 16]);
+        block_on(async {
+            let mut fs = Ext2Fs::on_ram_disk(guid, 1).await;
+            let free_before = fs
+                .get_group(0)
+                .await
+                .unwrap()
+                .descriptor
+                .bg_free_blocks_count;
+            let mut root = fs
+                .get_nth_inode(ROOT_DIRECTORY_INODE_IDX as u32)
+                .await
+                .unwrap();
+
+            // two blocks past the direct ones, so an indirect block is needed as well
+            let block_size = fs.super_block.block_size() as usize;
+            let data = vec![0xAB; (INODE_BLOCK_LIMIT as usize + 2) * block_size];
+            let mut file = fs.create_file(&mut root, "a", 0o644).await.unwrap();
+            let written = fs
+                .write(&mut file, &data, &mut HalIOCtx::new())
+                .await
+                .unwrap();
+            assert_eq!(written, data.len());
+
+            let file = fs.get_nth_inode(file.absolute_idx).await.unwrap();
+            let ind_block_idx = file.inode.i_block[INODE_BLOCK_LIMIT as usize];
+            let ind = fs
+                .io_handler
+                .read_block(fs.get_buffer(), ind_block_idx)
+                .await
+                .unwrap();
+            let mut blocks: BTreeSet<u32> = file.inode.i_block[..=INODE_BLOCK_LIMIT as usize]
+                .iter()
+                .copied()
+                .collect();
+            blocks.extend(
+                ind.chunks(4)
+                    .take(2)
+                    .map(|entry| u32::from_le_bytes(entry.try_into().unwrap())),
+            );
+            assert!(!blocks.contains(&0));
+            assert_eq!(blocks.len(), INODE_BLOCK_LIMIT as usize + 3);
+
+            fs.unlink(&mut root, "a").await.unwrap();
+            assert_eq!(fs.find_entry_by_name("a", &root).await.unwrap(), None);
+
+            // every bit is clear on disk and the group counts them as free again
+            let group = fs.get_group(0).await.unwrap();
+            assert_eq!({ group.descriptor.bg_free_blocks_count }, free_before);
+            let bitmap = fs
+                .read_sectors(fs.get_buffer(), group.get_block_bitmap_lba())
+                .await
+                .unwrap();
+            for &block_idx in blocks.iter() {
+                let idx = block_idx % RAM_DISK_BLOCKS_PER_GROUP;
+                assert_eq!(bitmap[idx as usize / 8] & (1 << (idx % 8)), 0);
+            }
 
-        Ok(())
+            // they were the first free blocks, so they're exactly what comes out next
+            let reused: BTreeSet<u32> = fs
+                .block_allocator
+                .allocate_n_blocks_in_group(0, blocks.len())
+                .await
+                .unwrap()
+                .iter()
+                .map(|block| block.block_global_idx)
+                .collect();
+            assert_eq!(reused, blocks);
+        });
+        ram_disk::unregister(guid);
+
+        // nothing is looked up before these are checked
+        let file = InodePlus::default();
+        assert!(matches!(
+            block_on(Ext2Fs::detached(false).unlink(&mut file.clone(), "a")),
+            Err(HalFsIOErr::NotADirectory)
+        ));
+
+        end_test!();
     }
 }
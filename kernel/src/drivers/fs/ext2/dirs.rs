@@ -1,10 +1,14 @@
 use crate::log;
-use alloc::{boxed::Box, string::ToString};
+use alloc::{
+    boxed::Box,
+    string::{String, ToString},
+};
 use dvida_serialize::{DvDeserialize, DvSerialize};
 
 use crate::{
     drivers::fs::ext2::{
         BLOCK_SIZE, DirEntry, DirEntryPartial, Inode, InodePlus,
+        create_file::new_inode,
         read::Progress,
         structs::{BlockIterElement, Ext2Fs},
     },
@@ -14,6 +18,90 @@ use crate::{
     },
 };
 
+/// makes room for `entry` in a directory block by shrinking the first entry with enough slack
+/// to its minimum length, the new entry takes over the rest of its record. Returns false if the
+/// block is full
+pub fn place_dir_entry(
+    buf: &mut [u8],
+    block_size: usize,
+    entry: &mut DirEntry,
+) -> Result<bool, HalFsIOErr> {
+    let mut progr = 0;
+
+    while progr + size_of::<DirEntryPartial>() <= block_size {
+        let entry_partial: &mut DirEntryPartial =
+            bytemuck::from_bytes_mut(&mut buf[progr..progr + size_of::<DirEntryPartial>()]);
+
+        let rec_len = entry_partial.rec_len as usize;
+        if rec_len < size_of::<DirEntryPartial>() || rec_len + progr > block_size {
+            return Err(HalFsIOErr::Corrupted);
+        }
+
+        // if it can fit, shrink this entry
+        if entry_partial.rec_len - entry_partial.min_reclen() >= entry.record_length() {
+            log!(
+                "add_dir_entry: found entry that is long enough: {:?} for: {:?} with record length of: {:?}",
+                entry_partial,
+                entry,
+                entry.record_length()
+            );
+
+            entry.rec_len = entry_partial.rec_len - entry_partial.min_reclen();
+            entry_partial.rec_len = entry_partial.min_reclen();
+
+            let new_reclen = entry_partial.rec_len as usize;
+
+            entry.serialize(
+                dvida_serialize::Endianness::Little,
+                &mut buf[progr + new_reclen..],
+            )?;
+
+            return Ok(true);
+        }
+
+        progr += rec_len;
+    }
+
+    Ok(false)
+}
+
+/// the first block of a new directory, ".." runs to the end of the block
+pub fn init_dir_block(buf: &mut [u8], self_idx: u32, parent_idx: u32) -> Result<(), HalFsIOErr> {
+    buf.fill(0);
+
+    let dot_len = DirEntry::new(self_idx, String::from("."))
+        .serialize(dvida_serialize::Endianness::Little, buf)?;
+
+    let mut dotdot = DirEntry::new(parent_idx, String::from(".."));
+    dotdot.rec_len = (buf.len() - dot_len) as u16;
+    dotdot.serialize(dvida_serialize::Endianness::Little, &mut buf[dot_len..])?;
+
+    Ok(())
+}
+
+/// whether a directory block holds anything but "." and ".."
+pub fn is_dir_block_empty(buf: &[u8]) -> Result<bool, HalFsIOErr> {
+    let mut progr = 0;
+
+    while progr < buf.len() {
+        let (entry, bytes_read) =
+            DirEntry::deserialize(dvida_serialize::Endianness::Little, &buf[progr..])
+                .map_err(|_| HalFsIOErr::Corrupted)?;
+
+        if bytes_read < size_of::<DirEntryPartial>() {
+            return Err(HalFsIOErr::Corrupted);
+        }
+
+        if entry.inode != 0 && entry.name != "." && entry.name != ".." {
+            return Ok(false);
+        }
+
+        progr += bytes_read;
+    }
+
+    Ok(true)
+}
+
 impl Ext2Fs {
     pub async fn add_dir_entry(
         &mut self,
@@ -21,6 +109,8 @@ impl Ext2Fs {
         child_inode_idx: u32,
         name: &str,
     ) -> Result<(), HalFsIOErr> {
+        self.ensure_writable()?;
+
         let mut buf: Box<[u8]> = self.get_buffer();
         let time = crate::time::formats::rtc_to_posix(
             &crate::time::Rtc::new()
@@ -45,47 +135,18 @@ impl Ext2Fs {
                 break;
             }
 
-            let mut progr = 0;
-
-            while progr < buf.len() {
-                let entry_partial: &mut DirEntryPartial =
-                    bytemuck::from_bytes_mut(&mut buf[progr..progr + size_of::<DirEntryPartial>()]);
-
-                if entry_partial.rec_len + progr as u16 > self.super_block.block_size() as u16 {
-                    return Err(HalFsIOErr::Corrupted);
-                }
-
-                // if it can fit, shrink this entry
-                if entry_partial.rec_len - entry_partial.min_reclen() >= entry.record_length() {
-                    log!(
-                        "add_dir_entry: found entry that is long enough: {:?} for: {:?} with record length of: {:?}",
-                        entry_partial,
-                        entry,
-                        entry.record_length()
-                    );
+            if place_dir_entry(&mut buf, self.super_block.block_size() as usize, &mut entry)? {
+                self.io_handler.write_block(buf.clone(), block_idx).await?;
+                inode.inode.i_mtime = time;
 
-                    entry.rec_len = entry_partial.rec_len as u16 - entry_partial.min_reclen();
-                    entry_partial.rec_len = entry_partial.min_reclen();
-
-                    let new_reclen = entry_partial.rec_len as usize;
-
-                    entry.serialize(
-                        dvida_serialize::Endianness::Little,
-                        &mut buf[progr + new_reclen..],
-                    )?;
-                    self.io_handler.write_block(buf.clone(), block_idx).await?;
-
-                    inode.inode.i_mtime = time;
-
-                    return Ok(());
-                }
-
-                progr += entry_partial.rec_len as usize;
+                return Ok(());
             }
         }
 
         // if we are here we need to allocate a new block
         let res = blocks_iterator.set().await?;
+        inode.inode.i_block = blocks_iterator.finalize().await?;
+        inode.inode.i_blocks += self.blocks_to_i_blocks(res.allocated_blocks.len() as u32);
 
         entry.rec_len = self.super_block.block_size() as u16;
 
@@ -96,6 +157,7 @@ impl Ext2Fs {
         entry.serialize(dvida_serialize::Endianness::Little, &mut buf)?;
 
         inode.inode.i_size += self.super_block.block_size();
+        inode.inode.i_mtime = time;
         self.io_handler.write_block(buf.clone(), block_idx).await?;
 
         self.block_allocator
@@ -106,26 +168,87 @@ impl Ext2Fs {
     }
 
     pub async fn mkdir(&mut self, path: Path, perms: i32) -> Result<InodePlus, HalFsIOErr> {
+        self.ensure_writable()?;
+
         let (mut dir_inode, file_inode) = self.walk_path(&path).await?;
 
         if file_inode.is_some() {
             return Err(HalFsIOErr::FileExists);
         }
 
-        self
-            .create_inode(
-                &mut dir_inode,
-                &path.file_name().ok_or(HalFsIOErr::BadPath)?,
-                true,
-                perms,
-            )
+        self.create_directory(
+            &mut dir_inode,
+            &path.file_name().ok_or(HalFsIOErr::BadPath)?,
+            perms as u16,
+        )
+        .await
+    }
+
+    /// the new directory gets one block holding "." and "..", the parent gains a link through
+    /// the new ".."
+    pub async fn create_directory(
+        &mut self,
+        parent: &mut InodePlus,
+        name: &str,
+        perms: u16,
+    ) -> Result<InodePlus, HalFsIOErr> {
+        self.ensure_writable()?;
+
+        if name.len() > 255 {
+            return Err(HalFsIOErr::NameTooLong);
+        }
+
+        if !parent.inode.is_directory() {
+            return Err(HalFsIOErr::NotADirectory);
+        }
+
+        let time = crate::time::Rtc::new()
+            .read_datetime()
+            .map_or(0, |dt| crate::time::formats::rtc_to_posix(&dt));
+
+        let inode_num = self.allocate_inode(parent.group_number).await?;
+        let mut dir = self.global_idx_to_inode_plus(new_inode(perms as i32, true, time), inode_num);
+
+        let blocks = match self
+            .allocated_blocks_for_new_inode(&mut dir.inode, dir.group_number.into(), 1)
             .await
+        {
+            Ok(blocks) => blocks,
+            Err(e) => return self.abandon_new_inode(inode_num, &[], e).await,
+        };
+        dir.inode.i_size = self.super_block.block_size();
+
+        let mut buf = self.get_buffer();
+        init_dir_block(&mut buf, inode_num, parent.absolute_idx)?;
+        self.io_handler
+            .write_block(buf.clone(), blocks[0].block_global_idx)
+            .await?;
+        self.block_allocator
+            .write_newly_allocated_blocks(buf)
+            .await?;
+
+        // counted as a directory from here on, freeing it takes the count back down
+        self.write_inode(&dir).await?;
+        self.adjust_inode_counts(dir.group_number, 0, 1).await?;
+
+        if let Err(e) = self.add_dir_entry(parent, inode_num, name).await {
+            if let Err(release_err) = self.release_inode(&mut dir).await {
+                log!("Failed to release inode {inode_num} after {e:?}: {release_err:?}");
+            }
+            return Err(e);
+        }
+        parent.inode.i_links_count = parent.inode.i_links_count.saturating_add(1);
+        self.write_inode(parent).await?;
+
+        Ok(dir)
     }
 
     pub async fn rmdir(&mut self, path: Path) -> Result<(), HalFsIOErr> {
+        self.ensure_writable()?;
+
         let (mut dir_inode, file_inode) = self.walk_path(&path).await?;
 
-        let Some(file_inode) = file_inode else {
+        let Some(mut file_inode) = file_inode else {
             return Err(HalFsIOErr::NoSuchFileOrDirectory);
         };
 
@@ -133,11 +256,21 @@ impl Ext2Fs {
             return Err(HalFsIOErr::NotADirectory);
         }
 
-        if !self.is_dir_empty(&dir_inode).await? {
+        if !self.is_dir_empty(&file_inode).await? {
             return Err(HalFsIOErr::DirectoryNotEmpty);
         }
 
-        self.free_inode(&mut dir_inode).await?;
+        self.find_entry_by_name_and_delete(
+            &path.file_name().ok_or(HalFsIOErr::BadPath)?,
+            &dir_inode,
+        )
+        .await?;
+
+        // the ".." of the removed directory pointed here
+        dir_inode.inode.i_links_count = dir_inode.inode.i_links_count.saturating_sub(1);
+        self.write_inode(&dir_inode).await?;
+
+        self.release_inode(&mut file_inode).await?;
 
         Ok(())
     }
@@ -197,7 +330,7 @@ impl Ext2Fs {
     pub async fn iter_dir(
         &mut self,
         offset: &mut i64,
-        mut buf: Box<[u8]>,
+        buf: &mut [u8],
         inode: &mut InodePlus,
     ) -> Result<bool, HalFsIOErr> {
         if !inode.inode.is_directory() {
@@ -212,7 +345,7 @@ impl Ext2Fs {
 
         loop {
             let (reached_end, finished) = self
-                .read_entries_till_next_block(&inode.inode, &mut buf, offset, &mut progress)
+                .read_entries_till_next_block(&inode.inode, buf, offset, &mut progress)
                 .await?;
 
             if reached_end {
@@ -227,3 +360,135 @@ impl Ext2Fs {
         Ok(false)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::{string::String, vec};
+    use dvida_serialize::{DvDeserialize, DvSerialize, Endianness};
+
+    use super::{init_dir_block, is_dir_block_empty, place_dir_entry};
+    use crate::{
+        drivers::fs::ext2::{DirEntry, EXT2_S_IFREG, Inode, InodePlus, structs::Ext2Fs},
+        end_test,
+        hal::fs::HalFsIOErr,
+        terminal::test::block_on,
+        test_name,
+    };
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn ext2_create_directory() {
+        test_name!("ext2 create directory");
+
+        const BLOCK_SIZE: usize = 1024;
+        let mut block = vec![0xFFu8; BLOCK_SIZE];
+        init_dir_block(&mut block, 12, 2).unwrap();
+
+        let (dot, dot_len) = DirEntry::deserialize(Endianness::Little, &block).unwrap();
+        assert_eq!((dot.inode, dot.name.as_str(), dot_len), (12, ".", 12));
+        let (dotdot, dotdot_len) =
+            DirEntry::deserialize(Endianness::Little, &block[dot_len..]).unwrap();
+        assert_eq!(
+            (dotdot.inode, dotdot.name.as_str(), dotdot_len),
+            (2, "..", BLOCK_SIZE - 12)
+        );
+
+        // a fresh directory is empty
+        assert!(is_dir_block_empty(&block).unwrap());
+
+        // until something is put in it
+        let mut entry = DirEntry::new(13, String::from("file"));
+        assert!(place_dir_entry(&mut block, BLOCK_SIZE, &mut entry).unwrap());
+        assert!(!is_dir_block_empty(&block).unwrap());
+
+        // a block that was never initialized doesn't pass for empty
+        assert!(matches!(
+            is_dir_block_empty(&vec![0u8; BLOCK_SIZE]),
+            Err(HalFsIOErr::Corrupted)
+        ));
+
+        // both checks happen before anything is allocated
+        let file = InodePlus {
+            inode: Inode {
+                i_mode: EXT2_S_IFREG,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut fs = Ext2Fs::detached(false);
+        assert!(matches!(
+            block_on(fs.is_dir_empty(&file)),
+            Err(HalFsIOErr::NotADirectory)
+        ));
+        assert!(matches!(
+            block_on(fs.create_directory(&mut file.clone(), "dir", 0o755)),
+            Err(HalFsIOErr::NotADirectory)
+        ));
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn ext2_dir_entry_raw_name() {
+        test_name!("ext2 dir entry names are raw bytes");
+
+        // inode 12, rec_len 16, name_len 5, file_type 1, "caf\xc3\xa9" and padding
+        let mut block = vec![12, 0, 0, 0, 16, 0, 5, 1];
+        block.extend_from_slice(&[b'c', b'a', b'f', 0xC3, 0xA9, 0, 0, 0]);
+
+        let (entry, len) = DirEntry::deserialize(Endianness::Little, &block).unwrap();
+        assert_eq!(
+            (entry.inode, entry.name.as_str(), len),
+            (12, "caf\u{e9}", 16)
+        );
+
+        // bytes that aren't utf-8 don't make the whole directory unreadable
+        block[8] = 0xFF;
+        let (entry, _) = DirEntry::deserialize(Endianness::Little, &block).unwrap();
+        assert_eq!(entry.name.as_str(), "\u{fffd}af\u{e9}");
+
+        // a name running past the record
+        block[6] = 9;
+        assert!(DirEntry::deserialize(Endianness::Little, &block).is_err());
+
+        // or past the buffer
+        block[4] = 32;
+        block[6] = 20;
+        assert!(DirEntry::deserialize(Endianness::Little, &block).is_err());
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn ext2_dir_entry_padding() {
+        test_name!("ext2 dir entry padding is zeroed");
+
+        let mut block = vec![0xAAu8; 32];
+        let mut entry = DirEntry::new(7, String::from("ab"));
+        entry.rec_len = 24;
+
+        assert_eq!(entry.serialize(Endianness::Little, &mut block).unwrap(), 24);
+        assert_eq!(&block[..10], &[7, 0, 0, 0, 24, 0, 2, 0, b'a', b'b']);
+        assert!(block[10..24].iter().all(|&b| b == 0));
+        // nothing past the record is touched
+        assert!(block[24..].iter().all(|&b| b == 0xAA));
+
+        // the record has to hold the name and stay aligned
+        entry.rec_len = 10;
+        assert!(entry.serialize(Endianness::Little, &mut block).is_err());
+        assert!(
+            DirEntry::new(7, String::from("ab"))
+                .serialize_till_end(Endianness::Little, &mut block[..14])
+                .is_err()
+        );
+        assert!(
+            DirEntry::new(7, String::from("a long name"))
+                .serialize_till_end(Endianness::Little, &mut block[..16])
+                .is_err()
+        );
+
+        end_test!();
+    }
+}
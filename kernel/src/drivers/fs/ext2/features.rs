@@ -0,0 +1,187 @@
+use crate::{
+    drivers::fs::ext2::{
+        EXT2_FEATURE_COMPAT_DIR_INDEX, EXT2_FEATURE_COMPAT_DIR_PREALLOC,
+        EXT2_FEATURE_COMPAT_EXT_ATTR, EXT2_FEATURE_COMPAT_HAS_JOURNAL,
+        EXT2_FEATURE_COMPAT_IMAGIC_INODES, EXT2_FEATURE_COMPAT_RESIZE_INODE,
+        EXT2_FEATURE_INCOMPAT_COMPRESSION, EXT2_FEATURE_INCOMPAT_FILETYPE,
+        EXT2_FEATURE_INCOMPAT_JOURNAL_DEV, EXT2_FEATURE_INCOMPAT_META_BG,
+        EXT2_FEATURE_INCOMPAT_RECOVER, EXT2_FEATURE_RO_COMPAT_BTREE_DIR,
+        EXT2_FEATURE_RO_COMPAT_LARGE_FILE, EXT2_FEATURE_RO_COMPAT_SPARSE_SUPER, SuperBlock,
+    },
+    hal::fs::HalFsMountErr,
+};
+
+macro_rules! feature_flags {
+    ($(#[$meta:meta])* $name:ident { $($flag:ident = $value:expr),* $(,)? }) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+        pub struct $name(pub u32);
+
+        impl $name {
+            $(pub const $flag: Self = Self($value);)*
+
+            /// every flag this type has a name for
+            pub const ALL: Self = Self(0 $(| $value)*);
+
+            pub const fn empty() -> Self {
+                Self(0)
+            }
+
+            pub const fn bits(&self) -> u32 {
+                self.0
+            }
+
+            pub const fn contains(&self, other: Self) -> bool {
+                self.0 & other.0 == other.0
+            }
+
+            pub const fn union(self, other: Self) -> Self {
+                Self(self.0 | other.0)
+            }
+
+            /// the set bits that aren't in `known`
+            pub const fn difference(self, known: Self) -> Self {
+                Self(self.0 & !known.0)
+            }
+
+            pub const fn is_empty(&self) -> bool {
+                self.0 == 0
+            }
+        }
+
+        impl core::ops::BitOr for $name {
+            type Output = Self;
+
+            fn bitor(self, rhs: Self) -> Self {
+                self.union(rhs)
+            }
+        }
+    };
+}
+
+feature_flags! {
+    /// features that don't affect how the filesystem can be accessed
+    CompatFlags {
+        DIR_PREALLOC = EXT2_FEATURE_COMPAT_DIR_PREALLOC,
+        IMAGIC_INODES = EXT2_FEATURE_COMPAT_IMAGIC_INODES,
+        HAS_JOURNAL = EXT2_FEATURE_COMPAT_HAS_JOURNAL,
+        EXT_ATTR = EXT2_FEATURE_COMPAT_EXT_ATTR,
+        RESIZE_INODE = EXT2_FEATURE_COMPAT_RESIZE_INODE,
+        DIR_INDEX = EXT2_FEATURE_COMPAT_DIR_INDEX,
+    }
+}
+
+feature_flags! {
+    /// features that have to be understood to read the filesystem at all
+    IncompatFlags {
+        COMPRESSION = EXT2_FEATURE_INCOMPAT_COMPRESSION,
+        FILETYPE = EXT2_FEATURE_INCOMPAT_FILETYPE,
+        RECOVER = EXT2_FEATURE_INCOMPAT_RECOVER,
+        JOURNAL_DEV = EXT2_FEATURE_INCOMPAT_JOURNAL_DEV,
+        META_BG = EXT2_FEATURE_INCOMPAT_META_BG,
+    }
+}
+
+feature_flags! {
+    /// features that have to be understood to write to the filesystem
+    RoCompatFlags {
+        SPARSE_SUPER = EXT2_FEATURE_RO_COMPAT_SPARSE_SUPER,
+        LARGE_FILE = EXT2_FEATURE_RO_COMPAT_LARGE_FILE,
+        BTREE_DIR = EXT2_FEATURE_RO_COMPAT_BTREE_DIR,
+    }
+}
+
+/// directory entries always carry the file type byte in this driver
+pub const SUPPORTED_INCOMPAT: IncompatFlags = IncompatFlags::FILETYPE;
+/// the block group layout doesn't handle sparse superblocks yet
+pub const SUPPORTED_RO_COMPAT: RoCompatFlags = RoCompatFlags::LARGE_FILE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MountMode {
+    ReadWrite,
+    ReadOnly,
+}
+
+impl SuperBlock {
+    pub fn compat_features(&self) -> CompatFlags {
+        CompatFlags(self.s_feature_compat)
+    }
+
+    pub fn incompat_features(&self) -> IncompatFlags {
+        IncompatFlags(self.s_feature_incompat)
+    }
+
+    pub fn ro_compat_features(&self) -> RoCompatFlags {
+        RoCompatFlags(self.s_feature_ro_compat)
+    }
+
+    /// how the filesystem can be safely mounted, revision 0 filesystems don't have feature
+    /// fields so they are always fine
+    pub fn mount_mode(&self) -> Result<MountMode, HalFsMountErr> {
+        if !self.is_dynamic_rev() {
+            return Ok(MountMode::ReadWrite);
+        }
+
+        let unknown_incompat = self.incompat_features().difference(SUPPORTED_INCOMPAT);
+        if !unknown_incompat.is_empty() {
+            return Err(HalFsMountErr::UnsupportedIncompatFeatures(
+                unknown_incompat.bits(),
+            ));
+        }
+
+        if !self
+            .ro_compat_features()
+            .difference(SUPPORTED_RO_COMPAT)
+            .is_empty()
+        {
+            return Ok(MountMode::ReadOnly);
+        }
+
+        Ok(MountMode::ReadWrite)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytemuck::Zeroable;
+
+    use super::*;
+    use crate::{drivers::fs::ext2::EXT2_DYNAMIC_REV, end_test, test_name};
+
+    fn super_block(incompat: u32, ro_compat: u32) -> SuperBlock {
+        let mut super_block = SuperBlock::zeroed();
+        super_block.s_rev_level = EXT2_DYNAMIC_REV;
+        super_block.s_feature_incompat = incompat;
+        super_block.s_feature_ro_compat = ro_compat;
+        super_block
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn ext2_feature_check() {
+        test_name!("ext2 mount feature check");
+
+        let known = super_block(EXT2_FEATURE_INCOMPAT_FILETYPE, 0);
+        assert!(known.incompat_features().contains(IncompatFlags::FILETYPE));
+        assert_eq!(known.mount_mode(), Ok(MountMode::ReadWrite));
+
+        let unknown = super_block(EXT2_FEATURE_INCOMPAT_FILETYPE | 0x8000, 0);
+        assert_eq!(
+            unknown.mount_mode(),
+            Err(HalFsMountErr::UnsupportedIncompatFeatures(0x8000))
+        );
+
+        let journal = super_block(EXT2_FEATURE_INCOMPAT_RECOVER, 0);
+        assert!(journal.mount_mode().is_err());
+
+        let sparse = super_block(0, EXT2_FEATURE_RO_COMPAT_SPARSE_SUPER);
+        assert_eq!(sparse.mount_mode(), Ok(MountMode::ReadOnly));
+
+        // revision 0 doesn't have feature fields, whatever is in there is garbage
+        let mut old = super_block(0xFFFF, 0xFFFF);
+        old.s_rev_level = 0;
+        assert_eq!(old.mount_mode(), Ok(MountMode::ReadWrite));
+
+        end_test!();
+    }
+}
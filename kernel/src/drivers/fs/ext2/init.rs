@@ -1,22 +1,65 @@
 use crate::{
     crypto::guid::Guid,
-    hal::{buffer::Buffer, storage::read_sectors_by_guid},
+    hal::{
+        buffer::Buffer,
+        storage::{SECTOR_SIZE, logical_sector_size_by_guid, read_sectors_by_guid},
+    },
     log,
 };
-use alloc::boxed::Box;
+use alloc::{boxed::Box, vec};
 
 use crate::{drivers::fs::ext2::SuperBlock, hal::gpt::GPTEntry};
 
+/// the superblock always starts 1024 bytes into the partition, whatever the block size
+pub const SUPERBLOCK_OFFSET: usize = 1024;
+/// the last lba of a partition is inclusive, with 512 byte sectors this many past the first one
+/// cover the boot block and the superblock
+const MIN_PARTITION_LBA_SPAN: u64 = 3;
+
+/// the lba of the sector the superblock starts in, 2 sectors past `partition_start` with 512 byte
+/// sectors. Passing 0 gives the lba relative to the partition
+pub fn superblock_lba(partition_start: i64, sector_size: usize) -> i64 {
+    partition_start + (SUPERBLOCK_OFFSET / sector_size) as i64
+}
+
+/// picks the superblock out of the sectors read from [`superblock_lba`], None unless the magic
+/// says it's really ext2
+pub fn parse_superblock(buf: &[u8], sector_size: usize) -> Option<SuperBlock> {
+    let start = SUPERBLOCK_OFFSET % sector_size;
+    let super_block: SuperBlock =
+        bytemuck::pod_read_unaligned(buf.get(start..start + size_of::<SuperBlock>())?);
+
+    super_block.is_valid().then_some(super_block)
+}
+
+/// whether the partition is too small to even hold the superblock, an entry that ends before it
+/// starts counts as too small rather than wrapping around
+pub fn is_partition_too_small(entry: &GPTEntry) -> bool {
+    let (start_lba, end_lba) = (entry.start_lba, entry.end_lba);
+    end_lba.saturating_sub(start_lba) < MIN_PARTITION_LBA_SPAN
+}
+
 pub async fn identify_ext2(drive_id: Guid, entry: &GPTEntry) -> Option<SuperBlock> {
-    let buf: Box<[u8]> = Box::new([0u8; 1024]);
-    let buffer: Buffer = buf.into();
+    let sector_size = match logical_sector_size_by_guid(drive_id).await {
+        Ok(size) => size,
+        Err(err) => {
+            log!("Failed to identify ext2 because of drive error: {}", err);
+            return None;
+        }
+    };
 
-    if entry.start_lba - entry.end_lba < 3 {
+    if is_partition_too_small(entry) {
         log!("Failed to identify ext2 because the GPT entry is too small");
         return None;
     }
 
-    match read_sectors_by_guid(drive_id, buffer.clone(), (entry.start_lba + 2) as i64).await {
+    // on 4kn drives the superblock shares its sector with the boot block
+    let read_len = SUPERBLOCK_OFFSET.div_ceil(sector_size) * sector_size;
+    let buf: Box<[u8]> = vec![0u8; read_len].into_boxed_slice();
+    let buffer: Buffer = buf.into();
+    let lba = superblock_lba(entry.start_lba as i64, sector_size);
+
+    match read_sectors_by_guid(drive_id, buffer.clone(), lba).await {
         Ok(_) => {}
         Err(err) => {
             log!("Failed to identify ext2 because of read error: {}", err);
@@ -25,15 +68,191 @@ pub async fn identify_ext2(drive_id: Guid, entry: &GPTEntry) -> Option<SuperBloc
     }
 
     let buf: Box<[u8]> = buffer.into();
-    let super_block: SuperBlock = *bytemuck::from_bytes(&buf[0..size_of::<SuperBlock>()]);
+    let Some(super_block) = parse_superblock(&buf, sector_size) else {
+        log!("Didn't find superblock");
+        return None;
+    };
 
     log!("Read Superblock: {:?}", super_block);
 
-    if super_block.s_magic == 0xEF53 {
-        log!("Found superblock");
-        Some(super_block)
-    } else {
-        log!("Didn't find superblock");
-        None
+    // a garbage block size would make every lba computed from it nonsense
+    if let Err(err) = super_block.validate() {
+        log!("Refusing to mount ext2: {:?}", err);
+        return None;
+    }
+
+    log!("Found superblock");
+
+    if !(super_block.block_size() as usize).is_multiple_of(sector_size) {
+        log!(
+            "Refusing to mount ext2: block size {} isn't a multiple of the {} byte sector size",
+            super_block.block_size(),
+            sector_size
+        );
+        return None;
+    }
+
+    // block to lba conversions in the driver still assume 512 byte sectors
+    if sector_size != SECTOR_SIZE {
+        log!(
+            "Refusing to mount ext2 on a drive with {} byte sectors",
+            sector_size
+        );
+        return None;
+    }
+
+    Some(super_block)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+    use bytemuck::Zeroable;
+
+    use super::{SUPERBLOCK_OFFSET, is_partition_too_small, parse_superblock, superblock_lba};
+    use crate::{
+        crypto::guid::Guid,
+        drivers::fs::ext2::{
+            BLOCKS_PER_GROUP, EXT2_DYNAMIC_REV, EXT2_SUPER_MAGIC, INODES_PER_GROUP, SuperBlock,
+            structs::Ext2Fs,
+        },
+        end_test,
+        hal::{fs::HalFsMountErr, gpt::GPTEntry, ram_disk},
+        terminal::test::block_on,
+        test_name,
+    };
+
+    fn partition(start_lba: u64, end_lba: u64) -> GPTEntry {
+        let mut entry = GPTEntry::default();
+        entry.start_lba = start_lba;
+        entry.end_lba = end_lba;
+        entry
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn partition_size_check() {
+        test_name!("tiny partitions are rejected and normal ones accepted");
+
+        // two blocks can't hold the superblock
+        assert!(is_partition_too_small(&partition(2048, 2049)));
+        // lbas 2048..=2051 hold exactly the boot block and the superblock
+        assert!(!is_partition_too_small(&partition(2048, 2051)));
+        assert!(!is_partition_too_small(&partition(
+            2048,
+            2048 + 1024 * 1024
+        )));
+        // a corrupt entry doesn't wrap around into a huge partition
+        assert!(is_partition_too_small(&partition(4096, 2048)));
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn superblock_location() {
+        test_name!("the superblock is read 1024 bytes in and only trusted with the right magic");
+
+        // the first 4 KiB of a partition with nothing but the superblock in it
+        let mut image = vec![0u8; 4096];
+        let mut super_block = SuperBlock::zeroed();
+        super_block.s_magic = EXT2_SUPER_MAGIC;
+        super_block.s_blocks_count = 1234;
+        let bytes = bytemuck::bytes_of(&super_block);
+        image[SUPERBLOCK_OFFSET..SUPERBLOCK_OFFSET + bytes.len()].copy_from_slice(bytes);
+
+        for sector_size in [512, 4096] {
+            let lba = superblock_lba(0, sector_size) as usize;
+            let read = &image[lba * sector_size..(lba + 1) * sector_size];
+            let found = parse_superblock(read, sector_size).unwrap();
+            assert_eq!({ found.s_blocks_count }, 1234);
+        }
+
+        // two 512 byte sectors in, not one
+        assert_eq!(superblock_lba(2048, 512), 2050);
+        assert!(parse_superblock(&image[512..1024], 512).is_none());
+
+        // anything without the magic isn't ext2
+        image[SUPERBLOCK_OFFSET + 56] = 0;
+        assert!(parse_superblock(&image[1024..1536], 512).is_none());
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn superblock_written_into_4kn_sector() {
+        test_name!("the superblock goes 1024 bytes into the first sector of a 4kn drive");
+
+        const NATIVE_4K: usize = 4096;
+        let guid = Guid::from_bytes([0x4b; 16]);
+        let mut fs = block_on(Ext2Fs::on_ram_disk(guid, 1));
+
+        // the same bytes seen as a 4kn drive, the boot block shares the superblock's sector
+        ram_disk::with_disk(guid, |disk| {
+            disk.sector_size = NATIVE_4K;
+            disk.data[..SUPERBLOCK_OFFSET].fill(0xb0);
+            disk.writes.clear();
+        });
+        fs.io_handler.sector_size = NATIVE_4K;
+
+        fs.super_block.s_free_blocks_count -= 1;
+        block_on(fs.write_super_block()).expect("Failed to write the superblock");
+
+        let disk = ram_disk::unregister(guid).unwrap();
+        assert_eq!(disk.writes, [(0, NATIVE_4K)]);
+        // and synced right after
+        assert_eq!(disk.flushed_writes, 1);
+        assert!(
+            disk.data[..SUPERBLOCK_OFFSET]
+                .iter()
+                .all(|&byte| byte == 0xb0)
+        );
+
+        let written = parse_superblock(&disk.data[..NATIVE_4K], NATIVE_4K).unwrap();
+        assert_eq!({ written.s_free_blocks_count }, {
+            fs.super_block.s_free_blocks_count
+        });
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn superblock_layout_validation() {
+        test_name!("a superblock with an impossible layout is rejected before mounting");
+
+        let mut super_block = SuperBlock::zeroed();
+        super_block.s_magic = EXT2_SUPER_MAGIC;
+        super_block.s_rev_level = EXT2_DYNAMIC_REV;
+        super_block.s_log_block_size = 0;
+        super_block.s_blocks_per_group = BLOCKS_PER_GROUP;
+        super_block.s_inodes_per_group = INODES_PER_GROUP;
+        super_block.s_inode_size = 256;
+        assert_eq!(super_block.validate(), Ok(()));
+
+        let invalid = |change: fn(&mut SuperBlock)| {
+            let mut broken = super_block;
+            change(&mut broken);
+            broken.validate() == Err(HalFsMountErr::InvalidLayout)
+        };
+
+        // 1024 << 7, and a shift count that would overflow
+        assert!(invalid(|super_block| super_block.s_log_block_size = 7));
+        assert!(invalid(|super_block| super_block.s_log_block_size = 40));
+        assert!(invalid(|super_block| super_block.s_blocks_per_group = 0));
+        assert!(invalid(|super_block| super_block.s_inodes_per_group = 0));
+        // more blocks than a 1024 byte bitmap can track
+        assert!(invalid(|super_block| super_block.s_blocks_per_group = 8193));
+        assert!(invalid(|super_block| super_block.s_inode_size = 200));
+        assert!(invalid(|super_block| super_block.s_inode_size = 64));
+
+        // revision 0 doesn't have an inode size to check
+        let mut old = super_block;
+        old.s_rev_level = 0;
+        old.s_inode_size = 0;
+        assert_eq!(old.validate(), Ok(()));
+
+        end_test!();
     }
 }
@@ -3,7 +3,8 @@ use dvida_serialize::{DvDeserialize, DvSerialize};
 
 use crate::{
     drivers::fs::ext2::{
-        BLOCK_GROUP_DESCRIPTOR_SIZE, GroupDescriptor, INODE_SIZE, Inode, structs::Ext2Fs,
+        Inode,
+        structs::{Ext2BlockGroup, Ext2Fs},
     },
     hal::{fs::HalFsIOErr, storage::SECTOR_SIZE},
 };
@@ -19,37 +20,77 @@ pub struct InodePlus {
 }
 
 impl Ext2Fs {
+    /// i_blocks counts 512 byte sectors no matter the block size, this is the number of fs
+    /// blocks it stands for, indirect blocks included
     pub fn inode_block_count(&self, inode: &Inode) -> u32 {
-        inode.i_blocks * (SECTOR_SIZE as u32) / self.super_block.block_size()
+        inode.i_blocks / self.sectors_per_block()
+    }
+
+    /// how many fs blocks the size spans, holes included, this is what indexes the block
+    /// pointers and not i_blocks
+    pub fn logical_block_count(&self, inode: &Inode) -> u32 {
+        inode.i_size.div_ceil(self.super_block.block_size())
+    }
+
+    pub fn sectors_per_block(&self) -> u32 {
+        self.super_block.block_size() / SECTOR_SIZE as u32
+    }
+
+    /// converts a number of fs blocks into the unit i_blocks is kept in
+    pub fn blocks_to_i_blocks(&self, block_count: u32) -> u32 {
+        block_count * self.sectors_per_block()
     }
 
     pub fn global_idx_to_inode_plus(&self, inode: Inode, idx: u32) -> InodePlus {
-        
+        let (group_number, relative_idx) = self.inode_group_and_index(idx);
+
         InodePlus {
             inode,
-            relative_idx: (idx - 1) % self.super_block.s_inodes_per_group,
-            group_number: (idx - 1) / self.super_block.s_inodes_per_group,
+            relative_idx,
+            group_number,
             absolute_idx: idx,
         }
     }
 
-    pub fn relative_idx_to_inode_plus(
-        &self,
-        inode: Inode,
-        group_number: u32,
-        idx: u32,
-    ) -> InodePlus {
-        InodePlus {
-            inode,
-            relative_idx: idx,
-            group_number,
-            absolute_idx: group_number * self.super_block.s_inodes_per_group + idx + 1,
-        }
+    /// where the inode `idx` of a group sits in the group's inode table, as the sector past the
+    /// start of the table and the byte offset in that sector
+    pub fn inode_table_offset(&self, idx: u32) -> (i64, usize) {
+        let offset = idx as i64 * self.super_block.inode_size() as i64;
+
+        (
+            offset / SECTOR_SIZE as i64,
+            (offset % SECTOR_SIZE as i64) as usize,
+        )
+    }
+
+    /// the group an inode number falls into and its index in that group's inode table, inode
+    /// numbers start at 1
+    pub fn inode_group_and_index(&self, inode_num: u32) -> (u32, u32) {
+        let inodes_per_group = self.super_block.s_inodes_per_group;
+
+        (
+            (inode_num - 1) / inodes_per_group,
+            (inode_num - 1) % inodes_per_group,
+        )
+    }
+
+    /// the lba of the sector holding the inode `idx` of `group` and the byte offset in it
+    pub fn inode_disk_location(&self, group: &Ext2BlockGroup, idx: u32) -> (i64, usize) {
+        let (sector_offset, byte_offset) = self.inode_table_offset(idx);
+
+        (group.get_inode_table_lba() + sector_offset, byte_offset)
+    }
+
+    /// where an inode lives on the partition, every inode read and write goes through this
+    pub async fn inode_to_disk(&self, inode_num: u32) -> Result<(i64, usize), HalFsIOErr> {
+        let (group_number, idx) = self.inode_group_and_index(inode_num);
+        let group = self.get_group(group_number as i64).await?;
+
+        Ok(self.inode_disk_location(&group, idx))
     }
 
     pub async fn get_nth_inode(&self, idx: u32) -> Result<InodePlus, HalFsIOErr> {
-        let group_number = (idx - 1) / self.super_block.s_inodes_per_group;
-        let offset = (idx - 1) % self.super_block.s_inodes_per_group;
+        let (group_number, offset) = self.inode_group_and_index(idx);
 
         self.get_inode_in_group(group_number, offset).await
     }
@@ -59,79 +100,124 @@ impl Ext2Fs {
         group_number: u32,
         idx: u32,
     ) -> Result<InodePlus, HalFsIOErr> {
-        let block_group = self.get_group(group_number as i64).await?;
-        let lba = block_group.get_inode_table_lba();
-
-        let sector_offset = (idx as i64 * INODE_SIZE) / SECTOR_SIZE as i64;
-        let byte_offset = (idx as i64 * INODE_SIZE) % SECTOR_SIZE as i64;
+        let absolute_idx = self.super_block.s_inodes_per_group * group_number + idx + 1;
+        let (lba, byte_offset) = self.inode_to_disk(absolute_idx).await?;
 
         let mut buf: Box<[u8]> = Box::new([0u8; SECTOR_SIZE]);
-        buf = self.read_sectors(buf, lba + sector_offset).await?;
+        buf = self.read_sectors(buf, lba).await?;
 
         Ok(InodePlus {
-            inode: Inode::deserialize(
-                dvida_serialize::Endianness::Little,
-                &buf[byte_offset as usize..],
-            )?
-            .0,
+            inode: Inode::deserialize(dvida_serialize::Endianness::Little, &buf[byte_offset..])?.0,
             group_number,
             relative_idx: idx,
-            absolute_idx: self.super_block.s_inodes_per_group * group_number + idx + 1,
+            absolute_idx,
         })
     }
 
     pub async fn write_inode(&mut self, inode: &InodePlus) -> Result<(), HalFsIOErr> {
-        self.do_write_inode(inode, false).await
-    }
+        self.ensure_writable()?;
 
-    pub async fn write_new_inode(&mut self, inode: &InodePlus) -> Result<(), HalFsIOErr> {
-        self.do_write_inode(inode, true).await
-    }
+        let (lba, byte_offset) = self.inode_to_disk(inode.absolute_idx).await?;
 
-    pub async fn do_write_inode(
-        &mut self,
-        inode: &InodePlus,
-        is_new: bool,
-    ) -> Result<(), HalFsIOErr> {
-        let block_group = self.get_group(inode.group_number as i64).await?;
-        let lba = block_group.get_inode_table_lba();
+        let mut buf: Box<[u8]> = Box::new([0u8; SECTOR_SIZE]);
+        buf = self.read_sectors(buf, lba).await?;
 
-        let sector_offset = (inode.relative_idx as i64 * INODE_SIZE) / SECTOR_SIZE as i64;
-        let byte_offset = (inode.relative_idx as i64 * INODE_SIZE) % SECTOR_SIZE as i64;
+        inode
+            .inode
+            .serialize(dvida_serialize::Endianness::Little, &mut buf[byte_offset..])?;
 
-        let mut buf: Box<[u8]> = Box::new([0u8; SECTOR_SIZE]);
-        buf = self.read_sectors(buf, lba + sector_offset).await?;
-
-        inode.inode.serialize(
-            dvida_serialize::Endianness::Little,
-            &mut buf[byte_offset as usize..],
-        )?;
-
-        self.write_sectors(buf.clone(), lba + sector_offset).await?;
-
-        if is_new {
-            let gr_number = inode.group_number as i64;
-            let lba = self.get_block_group_table_lba();
-            let lba_offset = (gr_number * BLOCK_GROUP_DESCRIPTOR_SIZE as i64) / SECTOR_SIZE as i64;
-            let byte_offset = (gr_number * BLOCK_GROUP_DESCRIPTOR_SIZE as i64) % SECTOR_SIZE as i64;
-            buf = self.read_sectors(buf, lba + lba_offset).await?;
-            let descriptor: &mut GroupDescriptor = bytemuck::from_bytes_mut(
-                &mut buf[byte_offset as usize..byte_offset as usize + size_of::<GroupDescriptor>()],
-            );
-            descriptor.bg_free_inodes_count -= 1;
-            descriptor.bg_used_dirs_count += inode.inode.is_directory() as u16;
-            self.write_sectors(buf.clone(), lba + lba_offset).await?;
-
-            self.super_block.s_free_inodes_count -= 1;
-
-            let super_block_bytes = bytemuck::bytes_of(&self.super_block);
-            for i in 0..super_block_bytes.len() {
-                buf[i] = super_block_bytes[i];
-            }
-
-            self.write_sectors(buf, 3).await?;
-        }
+        self.write_sectors(buf, lba).await?;
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bytemuck::Zeroable;
+
+    use crate::{
+        drivers::fs::ext2::{
+            BLOCKS_PER_GROUP, EXT2_DYNAMIC_REV, EXT2_GOOD_OLD_REV, GroupDescriptor,
+            open::ROOT_DIRECTORY_INODE_IDX,
+            structs::{Ext2BlockGroup, Ext2Fs},
+        },
+        end_test, test_name,
+    };
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn inode_offsets_follow_inode_size() {
+        test_name!("inodes are found using the superblock's inode size");
+
+        let mut fs = Ext2Fs::detached(false);
+        fs.super_block.s_rev_level = EXT2_DYNAMIC_REV;
+        fs.super_block.s_inode_size = 256;
+
+        // two inodes per sector
+        assert_eq!(fs.inode_table_offset(0), (0, 0));
+        assert_eq!(fs.inode_table_offset(1), (0, 256));
+        assert_eq!(fs.inode_table_offset(2), (1, 0));
+        assert_eq!(fs.inode_table_offset(7), (3, 256));
+
+        // inodes bigger than a sector start at a sector boundary
+        fs.super_block.s_inode_size = 1024;
+        assert_eq!(fs.inode_table_offset(3), (6, 0));
+
+        // the field isn't there on revision 0, every inode is 128 bytes
+        fs.super_block.s_rev_level = EXT2_GOOD_OLD_REV;
+        assert_eq!(fs.super_block.inode_size(), 128);
+        assert_eq!(fs.inode_table_offset(5), (1, 128));
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn inode_to_disk_location() {
+        test_name!("inode numbers map to the right group, sector and offset");
+
+        let mut fs = Ext2Fs::detached_with_blocks(false, BLOCKS_PER_GROUP * 3);
+        fs.super_block.s_rev_level = EXT2_DYNAMIC_REV;
+        fs.super_block.s_inode_size = 256;
+        let inodes_per_group = fs.super_block.s_inodes_per_group;
+
+        let mut descriptor = GroupDescriptor::zeroed();
+        descriptor.bg_inode_table = 5;
+        let group = |group_number: i64, descriptor: GroupDescriptor| Ext2BlockGroup {
+            group_number,
+            block_size: 1024,
+            blocks_per_group: BLOCKS_PER_GROUP as i64,
+            sectors_per_block: 2,
+            descriptor,
+        };
+
+        // the root directory is the second inode of the first group
+        assert_eq!(
+            fs.inode_group_and_index(ROOT_DIRECTORY_INODE_IDX as u32),
+            (0, 1)
+        );
+        assert_eq!(fs.inode_disk_location(&group(0, descriptor), 1), (10, 256));
+
+        // the fifth inode of the third group, the table starts at block 5 + 2 groups
+        let inode_num = inodes_per_group * 2 + 5;
+        assert_eq!(fs.inode_group_and_index(inode_num), (2, 4));
+        descriptor.bg_inode_table = 5 + BLOCKS_PER_GROUP * 2;
+        assert_eq!(
+            fs.inode_disk_location(&group(2, descriptor), 4),
+            ((5 + BLOCKS_PER_GROUP as i64 * 2) * 2 + 2, 0)
+        );
+
+        // the last inode of a group still belongs to it
+        assert_eq!(
+            fs.inode_group_and_index(inodes_per_group),
+            (0, inodes_per_group - 1)
+        );
+        assert_eq!(fs.inode_group_and_index(inodes_per_group + 1), (1, 0));
+
+        let inode = fs.global_idx_to_inode_plus(Default::default(), inode_num);
+        assert_eq!((inode.group_number, inode.relative_idx), (2, 4));
+
+        end_test!();
+    }
+}
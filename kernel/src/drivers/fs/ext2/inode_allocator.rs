@@ -0,0 +1,298 @@
+use alloc::boxed::Box;
+
+use crate::{
+    drivers::fs::ext2::{Inode, structs::Ext2Fs},
+    hal::fs::HalFsIOErr,
+    log, time,
+};
+
+/// sets the first clear bit in `start..limit` and returns its index
+pub fn set_first_clear_bit(bitmap: &mut [u8], start: usize, limit: usize) -> Option<usize> {
+    let limit = limit.min(bitmap.len() * 8);
+
+    for idx in start..limit {
+        if bitmap[idx / 8] & (1 << (idx % 8)) == 0 {
+            bitmap[idx / 8] |= 1 << (idx % 8);
+            return Some(idx);
+        }
+    }
+
+    None
+}
+
+/// clears the bit and returns whether it was set before
+pub fn clear_bitmap_bit(bitmap: &mut [u8], idx: usize) -> bool {
+    let was_set = bitmap[idx / 8] & (1 << (idx % 8)) != 0;
+    bitmap[idx / 8] &= !(1 << (idx % 8));
+
+    was_set
+}
+
+/// what's left on disk of a freed inode, everything but the deletion time is zeroed
+pub fn dead_inode(dtime: u32) -> Inode {
+    Inode {
+        i_dtime: dtime,
+        ..Default::default()
+    }
+}
+
+impl Ext2Fs {
+    /// takes a free inode, looking at the preferred group first and then at every other group in
+    /// order. The bitmap and the free counts are written immediately, the returned number is the
+    /// global (1 based) inode number
+    pub async fn allocate_inode(&mut self, preferred_group: u32) -> Result<u32, HalFsIOErr> {
+        self.ensure_writable()?;
+
+        let group_count = self.super_block.block_groups_count();
+        let inodes_per_group = self.super_block.s_inodes_per_group;
+        let preferred_group = preferred_group.min(group_count.saturating_sub(1));
+
+        for group_number in (preferred_group..group_count).chain(0..preferred_group) {
+            let group = self.get_group(group_number as i64).await?;
+            if group.descriptor.bg_free_inodes_count == 0 {
+                continue;
+            }
+
+            // the reserved inodes all live at the start of the first group
+            let start = if group_number == 0 {
+                self.super_block.first_ino() as usize - 1
+            } else {
+                0
+            };
+
+            let bitmap_lba = group.get_inode_bitmap_lba();
+            let mut buf: Box<[u8]> = self.get_buffer();
+            buf = self.read_sectors(buf, bitmap_lba).await?;
+
+            let Some(idx) = set_first_clear_bit(&mut buf, start, inodes_per_group as usize) else {
+                log!(
+                    "allocate_inode: group {} claims free inodes but its bitmap is full",
+                    group_number
+                );
+                continue;
+            };
+
+            self.write_sectors(buf, bitmap_lba).await?;
+            self.adjust_inode_counts(group_number, -1, 0).await?;
+
+            return Ok(group_number * inodes_per_group + idx as u32 + 1);
+        }
+
+        Err(HalFsIOErr::NoAvailableInode)
+    }
+
+    /// returns an inode to the free pool, this doesn't touch its data blocks, see `release_inode`
+    /// for that
+    pub async fn free_inode(&mut self, inode_num: u32) -> Result<(), HalFsIOErr> {
+        self.ensure_writable()?;
+
+        if inode_num < self.super_block.first_ino() {
+            return Err(HalFsIOErr::ReservedInode);
+        }
+
+        if inode_num > self.super_block.s_inodes_count {
+            return Err(HalFsIOErr::NoSuchFileOrDirectory);
+        }
+
+        let mut inode = self.get_nth_inode(inode_num).await?;
+        let was_directory = inode.inode.is_directory();
+
+        let bitmap_lba = self
+            .get_group(inode.group_number as i64)
+            .await?
+            .get_inode_bitmap_lba();
+
+        let mut buf: Box<[u8]> = self.get_buffer();
+        buf = self.read_sectors(buf, bitmap_lba).await?;
+
+        // an inode that's already free may have been handed out again, its contents aren't ours
+        // to overwrite
+        if !clear_bitmap_bit(&mut buf, inode.relative_idx as usize) {
+            log!("free_inode: inode {} was already free", inode_num);
+            return Ok(());
+        }
+
+        self.write_sectors(buf, bitmap_lba).await?;
+        self.adjust_inode_counts(inode.group_number, 1, -(was_directory as i16))
+            .await?;
+
+        inode.inode = dead_inode(time::get_unix_timestamp());
+        self.write_inode(&inode).await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{collections::btree_set::BTreeSet, vec};
+
+    use super::*;
+    use crate::{
+        crypto::guid::Guid,
+        drivers::fs::ext2::{
+            EXT2_GOOD_OLD_FIRST_INO, EXT2_S_IFREG, init::identify_ext2, structs::RAM_DISK_INODES,
+        },
+        end_test,
+        hal::ram_disk,
+        terminal::test::block_on,
+        test_name,
+    };
+
+    /// the free inode count of group 0 and of the superblock, both read back from the disk
+    async fn free_inode_counts(fs: &Ext2Fs) -> (u16, u32) {
+        let group = fs.get_group(0).await.expect("Failed to read the group");
+        let super_block = identify_ext2(fs.drive_id, &fs.entry)
+            .await
+            .expect("Failed to read the superblock");
+
+        (
+            group.descriptor.bg_free_inodes_count,
+            super_block.s_free_inodes_count,
+        )
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn inode_bitmap_allocation() {
+        test_name!("inode bitmap allocation");
+
+        let mut bitmap = vec![0u8; 4];
+        // pretend inodes 1-10 are reserved and 12 is already taken
+        bitmap[0] = 0xFF;
+        bitmap[1] = 0b0000_1011;
+
+        let mut handed_out = BTreeSet::new();
+        for _ in 0..5 {
+            let idx = set_first_clear_bit(&mut bitmap, 10, 32).expect("Bitmap full");
+            assert!(handed_out.insert(idx));
+        }
+
+        assert_eq!(
+            handed_out.into_iter().collect::<alloc::vec::Vec<_>>(),
+            vec![10, 12, 13, 14, 15]
+        );
+        assert_eq!(bitmap[1], 0xFF);
+        assert_eq!(bitmap[2], 0);
+
+        // the limit is respected even if the buffer is larger
+        assert_eq!(set_first_clear_bit(&mut bitmap, 0, 16), None);
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn inode_bitmap_free() {
+        test_name!("inode bitmap free");
+
+        let mut bitmap = vec![0u8; 4];
+        bitmap[0] = 0xFF;
+        bitmap[1] = 0b0000_0011;
+
+        let free_before = bitmap.iter().map(|b| b.count_zeros()).sum::<u32>();
+
+        let idx = set_first_clear_bit(&mut bitmap, 10, 32).expect("Bitmap full");
+        assert_eq!(idx, 10);
+        assert!(clear_bitmap_bit(&mut bitmap, idx));
+        assert_eq!(bitmap[1], 0b0000_0011);
+        assert_eq!(
+            bitmap.iter().map(|b| b.count_zeros()).sum::<u32>(),
+            free_before
+        );
+
+        // freeing twice is noticed
+        assert!(!clear_bitmap_bit(&mut bitmap, idx));
+
+        // the slot is handed out again
+        assert_eq!(set_first_clear_bit(&mut bitmap, 10, 32), Some(10));
+
+        let dead = dead_inode(1_700_000_000);
+        assert_eq!(dead.i_dtime, 1_700_000_000);
+        assert_eq!(dead.i_mode, 0);
+        assert_eq!(dead.i_links_count, 0);
+        assert_eq!(dead.i_block, [0; 15]);
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn free_reserved_inode() {
+        test_name!("free reserved inode");
+
+        let mut fs = Ext2Fs::detached(false);
+        // revision 0 reserves the first 10 inodes
+        assert!(matches!(
+            block_on(fs.free_inode(2)),
+            Err(HalFsIOErr::ReservedInode)
+        ));
+        assert!(matches!(
+            block_on(fs.free_inode(10)),
+            Err(HalFsIOErr::ReservedInode)
+        ));
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn allocate_and_free_update_counts() {
+        test_name!("allocating and freeing inodes updates the bitmap and the free counts");
+
+        let guid = Guid::from_bytes([0x38; 16]);
+        block_on(async {
+            let mut fs = Ext2Fs::on_ram_disk(guid, 1).await;
+            let free = RAM_DISK_INODES - (EXT2_GOOD_OLD_FIRST_INO - 1);
+            assert_eq!(free_inode_counts(&fs).await, (free as u16, free));
+
+            let mut handed_out = BTreeSet::new();
+            for _ in 0..5 {
+                let inode_num = fs.allocate_inode(0).await.expect("Failed to allocate");
+                assert!(inode_num >= EXT2_GOOD_OLD_FIRST_INO);
+                assert!(handed_out.insert(inode_num));
+            }
+            assert_eq!(
+                handed_out.iter().copied().collect::<alloc::vec::Vec<_>>(),
+                vec![11, 12, 13, 14, 15]
+            );
+
+            assert_eq!(free_inode_counts(&fs).await, (free as u16 - 5, free - 5));
+            assert_eq!({ fs.super_block.s_free_inodes_count }, free - 5);
+
+            let bitmap_lba = fs.get_group(0).await.unwrap().get_inode_bitmap_lba();
+            let bitmap = fs.read_sectors(fs.get_buffer(), bitmap_lba).await.unwrap();
+            // inodes 1 to 15 are taken, 16 is the next free one
+            assert_eq!((bitmap[0], bitmap[1]), (0xFF, 0b0111_1111));
+
+            fs.free_inode(13).await.expect("Failed to free");
+            assert_eq!(free_inode_counts(&fs).await, (free as u16 - 4, free - 4));
+            assert_eq!({ fs.super_block.s_free_inodes_count }, free - 4);
+
+            let bitmap = fs.read_sectors(fs.get_buffer(), bitmap_lba).await.unwrap();
+            assert_eq!(bitmap[1], 0b0110_1111);
+            assert_ne!({ fs.get_nth_inode(13).await.unwrap().inode.i_dtime }, 0);
+
+            // freeing it twice doesn't count it twice
+            fs.free_inode(13).await.expect("Failed to free");
+            assert_eq!(free_inode_counts(&fs).await, (free as u16 - 4, free - 4));
+
+            // nor does it write over an inode whose bit is already clear
+            let mut stray = fs.get_nth_inode(16).await.unwrap();
+            stray.inode.i_mode = EXT2_S_IFREG | 0o644;
+            fs.write_inode(&stray).await.unwrap();
+            fs.free_inode(16).await.expect("Failed to free");
+            let stray = fs.get_nth_inode(16).await.unwrap();
+            assert_eq!(
+                ({ stray.inode.i_mode }, { stray.inode.i_dtime }),
+                (EXT2_S_IFREG | 0o644, 0)
+            );
+
+            // the freed slot is the first one handed out again
+            assert_eq!(fs.allocate_inode(0).await.unwrap(), 13);
+        });
+        ram_disk::unregister(guid);
+
+        end_test!();
+    }
+}
@@ -16,6 +16,8 @@ pub struct IoHandler {
     pub drive_id: Guid,
     pub start_lba: i64,
     pub block_size: u32,
+    /// the logical sector size of the drive
+    pub sector_size: usize,
 }
 
 impl IoHandler {
@@ -68,6 +70,11 @@ impl IoHandler {
     ) -> Result<(), HalStorageOperationErr> {
         storage::write_sectors_by_guid(self.drive_id, buffer.into(), self.start_lba + lba).await
     }
+
+    /// waits until everything written so far is on the physical media
+    pub async fn flush(&self) -> Result<(), HalStorageOperationErr> {
+        storage::flush_by_guid(self.drive_id).await
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
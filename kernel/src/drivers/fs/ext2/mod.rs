@@ -1,14 +1,20 @@
 pub mod allocator;
+pub mod attrs;
 pub mod block_iterator;
 pub mod create_file;
 pub mod delete;
 pub mod dirs;
+pub mod features;
 pub mod init;
 pub mod inode;
+pub mod inode_allocator;
 pub mod managers;
 pub mod open;
 pub mod read;
+pub mod stat;
 pub mod structs;
+pub mod symlink;
+pub mod truncate;
 pub mod write;
 
 use alloc::string::String;
@@ -16,6 +22,8 @@ use bytemuck::{Pod, Zeroable};
 use dvida_serialize::*;
 pub use inode::InodePlus;
 
+use crate::hal::fs::HalFsMountErr;
+
 /// The ext2 superblock structure - located at byte offset 1024 from start
 /// All fields stored in little-endian format on disk
 #[derive(Debug, Clone, Pod, Zeroable, Copy)]
@@ -225,6 +233,17 @@ impl DirEntry {
         &self,
         endianness: Endianness,
         target: &mut [u8],
+    ) -> Result<usize, DvSerErr> {
+        self.serialize_with_rec_len(endianness, target, target.len() as u16)
+    }
+
+    /// writes the entry over exactly `rec_len` bytes, everything after the name is zeroed. rec_len
+    /// has to be 4 byte aligned and leave room for the name
+    fn serialize_with_rec_len(
+        &self,
+        endianness: Endianness,
+        target: &mut [u8],
+        rec_len: u16,
     ) -> Result<usize, DvSerErr> {
         let mut acc: usize = 0;
 
@@ -233,20 +252,29 @@ impl DirEntry {
         }
 
         let name_len = self.name.len() as u8;
-
-        let length = target.len() as u16;
+        let min_rec_len =
+            (size_of::<DirEntryPartial>() as u16 + name_len as u16 + EXT2_DIR_ENTRY_ALIGNMENT - 1)
+                & !(EXT2_DIR_ENTRY_ALIGNMENT - 1);
+
+        if rec_len < min_rec_len
+            || rec_len % EXT2_DIR_ENTRY_ALIGNMENT != 0
+            || target.len() < rec_len as usize
+        {
+            return Err(DvSerErr::BufferTooSmall);
+        }
 
         acc += self.inode.serialize(endianness, &mut target[acc..])?;
-        acc += length.serialize(endianness, &mut target[acc..])?;
-        acc += name_len.serialize(endianness, &mut target[acc..])?; // name_len is ignored here 
+        acc += rec_len.serialize(endianness, &mut target[acc..])?;
+        acc += name_len.serialize(endianness, &mut target[acc..])?;
         acc += self.file_type.serialize(endianness, &mut target[acc..])?;
 
-        for (idx, char) in self.name.bytes().enumerate() {
-            target[acc..][idx] = char;
-        }
+        target[acc..acc + name_len as usize].copy_from_slice(self.name.as_bytes());
+        acc += name_len as usize;
+
+        // stale bytes of whatever was here before must not leak into the padding
+        target[acc..rec_len as usize].fill(0);
 
-        acc = length as usize;
-        Ok(acc)
+        Ok(rec_len as usize)
     }
 }
 
@@ -266,15 +294,14 @@ impl DvDeserialize for DirEntry {
         let (file_type, size) = u8::deserialize(endianness, &input[acc..])?;
         acc += size;
 
-        let mut name = String::new();
-        for i in 0..name_len as usize {
-            if i >= input[acc..].len() {
-                return Err(DvDeErr::WrongBufferSize);
-            }
-
-            name.push(input[acc..][i] as char);
+        // names are raw bytes on disk, the name has to fit in both the record and the buffer
+        let name_end = acc + name_len as usize;
+        if name_end > rec_len as usize || name_end > input.len() {
+            return Err(DvDeErr::WrongBufferSize);
         }
 
+        let name = String::from_utf8_lossy(&input[acc..name_end]).into_owned();
+
         // set acc to be rec_len so it points to the next entry
         acc = rec_len as usize;
 
@@ -292,32 +319,14 @@ impl DvDeserialize for DirEntry {
 
 impl DvSerialize for DirEntry {
     fn serialize(&self, endianness: Endianness, target: &mut [u8]) -> Result<usize, DvSerErr> {
-        let mut acc: usize = 0;
-
-        if self.name.len() > 255 {
-            return Err(DvSerErr::BadStringLength(0, 255));
-        }
-
-        let name_len = self.name.len() as u8;
-
-        acc += self.inode.serialize(endianness, &mut target[acc..])?;
-        acc += self
-            .record_length()
-            .serialize(endianness, &mut target[acc..])?;
-        acc += name_len.serialize(endianness, &mut target[acc..])?; // name_len is ignored here 
-        acc += self.file_type.serialize(endianness, &mut target[acc..])?;
-
-        for (idx, char) in self.name.bytes().enumerate() {
-            target[acc..][idx] = char;
-        }
-
-        acc = self.record_length() as usize;
-        Ok(acc)
+        self.serialize_with_rec_len(endianness, target, self.rec_len)
     }
 }
 
 pub const BLOCK_SIZE: u32 = 1024;
 pub const LOG_BLOCK_SIZE: u32 = 1;
+/// 4096 byte blocks are the largest the driver reads
+pub const MAX_LOG_BLOCK_SIZE: u32 = 2;
 pub const S_R_BLOCKS_COUNT: u32 = 1024;
 pub const FIRST_DATA_BLOCK: u32 = 1;
 pub const MAX_MOUNT_COUNT: u16 = 64;
@@ -348,6 +357,9 @@ pub const EXT2_OS_LITES: u32 = 4;
 pub const EXT2_GOOD_OLD_REV: u32 = 0; // Original format
 pub const EXT2_DYNAMIC_REV: u32 = 1; // V2 format with dynamic inode sizes
 
+// First non-reserved inode for revision 0
+pub const EXT2_GOOD_OLD_FIRST_INO: u32 = 11;
+
 // Magic number
 pub const EXT2_SUPER_MAGIC: u16 = 0xEF53;
 
@@ -438,6 +450,36 @@ impl SuperBlock {
         self.s_magic == EXT2_SUPER_MAGIC
     }
 
+    /// Checks the fields every layout computation depends on, run before any of them
+    pub fn validate(&self) -> Result<(), HalFsMountErr> {
+        // 1024, 2048 or 4096 byte blocks
+        if self.s_log_block_size > MAX_LOG_BLOCK_SIZE {
+            return Err(HalFsMountErr::InvalidLayout);
+        }
+
+        // each group's bitmaps take up exactly one block
+        let bits_per_block = self.block_size() * 8;
+        let (blocks_per_group, inodes_per_group) =
+            (self.s_blocks_per_group, self.s_inodes_per_group);
+        if !(1..=bits_per_block).contains(&blocks_per_group)
+            || !(1..=bits_per_block).contains(&inodes_per_group)
+        {
+            return Err(HalFsMountErr::InvalidLayout);
+        }
+
+        // revision 0 inodes are always 128 bytes and the field isn't there
+        let inode_size = self.s_inode_size as u32;
+        if self.is_dynamic_rev()
+            && (!inode_size.is_power_of_two()
+                || inode_size < INODE_SIZE as u32
+                || inode_size > self.block_size())
+        {
+            return Err(HalFsMountErr::InvalidLayout);
+        }
+
+        Ok(())
+    }
+
     /// Returns the total number of block groups
     pub fn block_groups_count(&self) -> u32 {
         self.s_blocks_count.div_ceil(self.s_blocks_per_group)
@@ -447,6 +489,24 @@ impl SuperBlock {
     pub fn is_dynamic_rev(&self) -> bool {
         self.s_rev_level >= EXT2_DYNAMIC_REV
     }
+
+    /// Returns the first inode number that isn't reserved
+    pub fn first_ino(&self) -> u32 {
+        if self.is_dynamic_rev() {
+            self.s_first_ino
+        } else {
+            EXT2_GOOD_OLD_FIRST_INO
+        }
+    }
+
+    /// Returns the size of an on-disk inode, revision 0 always uses 128 bytes
+    pub fn inode_size(&self) -> u32 {
+        if self.is_dynamic_rev() {
+            self.s_inode_size as u32
+        } else {
+            INODE_SIZE as u32
+        }
+    }
 }
 
 impl Inode {
@@ -474,4 +534,14 @@ impl Inode {
     pub fn is_symlink(&self) -> bool {
         self.file_type() == EXT2_S_IFLNK
     }
+
+    /// Returns the size in bytes, regular files keep the high 32 bits in i_dir_acl when the
+    /// large file feature is on
+    pub fn size(&self, large_file: bool) -> u64 {
+        if large_file && self.is_regular_file() {
+            (self.i_dir_acl as u64) << 32 | self.i_size as u64
+        } else {
+            self.i_size as u64
+        }
+    }
 }
@@ -1,20 +1,20 @@
 use crate::log;
-use alloc::boxed::Box;
+use alloc::{boxed::Box, string::String, vec::Vec};
 use dvida_serialize::DvDeserialize;
 
 use crate::{
     drivers::fs::ext2::{
         DirEntry, DirEntryPartial, InodePlus,
+        dirs::is_dir_block_empty,
         structs::{BlockIterElement, Ext2Fs},
+        symlink::{MAX_SYMLINK_FOLLOWS, symlink_restart_path},
     },
     hal::{
         fs::{HalFsIOErr, HalInode, OpenFlags, OpenFlagsValue},
         path::Path,
-        storage::SECTOR_SIZE,
     },
 };
 
-pub const SUPERBLOCK_SIZE: i64 = 2;
 pub const LBA_ADDR_LEN: usize = 4;
 
 impl Ext2Fs {
@@ -116,12 +116,32 @@ impl Ext2Fs {
         self.do_find_entry_by_name(name, inode, true, false).await
     }
 
-    // TODO: refactor this
+    /// a directory is empty when nothing but "." and ".." is left in it
     pub async fn is_dir_empty(&mut self, inode: &InodePlus) -> Result<bool, HalFsIOErr> {
-        Ok(self
-            .do_find_entry_by_name("", inode, false, false)
-            .await?
-            .is_none())
+        if !inode.inode.is_directory() {
+            return Err(HalFsIOErr::NotADirectory);
+        }
+
+        let mut buf: Box<[u8]> = self.get_buffer();
+        let mut blocks_iterator =
+            self.create_block_iterator(&inode.inode, inode.group_number.into());
+
+        loop {
+            let BlockIterElement {
+                buf: buffer,
+                is_terminated,
+                ..
+            } = blocks_iterator.next(buf).await?;
+            if is_terminated {
+                return Ok(true);
+            }
+
+            if !is_dir_block_empty(&buffer)? {
+                return Ok(false);
+            }
+
+            buf = buffer;
+        }
     }
 
     /// returns the index of the inode if the find_is_empty flag is not up
@@ -187,61 +207,109 @@ impl Ext2Fs {
     /// takes in a path
     /// returns a tuple (the inode to the directory, Option<the inode to the file>)
     /// If the file doesn't exist the Option will be None
+    /// A symlink in the last component is handed back as is
     pub async fn walk_path(
         &mut self,
         path: &Path,
     ) -> Result<(InodePlus, Option<InodePlus>), HalFsIOErr> {
-        let group = self.get_group(0).await?;
-        let inode_table_loc = group.get_inode_table_lba();
-
-        let mut buf: Box<[u8]> = Box::new([0u8; SECTOR_SIZE]);
-        buf = self.read_sectors(buf, inode_table_loc).await?;
-        let temp = self.super_block.s_inode_size;
-        log!("inode size: {:?}", temp);
+        self.walk_path_following(path, false).await
+    }
 
-        let mut inode = self.get_nth_inode(2).await?;
+    /// like walk_path, symlinks in the middle of the path are always followed while `follow_last`
+    /// decides about the last component
+    pub async fn walk_path_following(
+        &mut self,
+        path: &Path,
+        follow_last: bool,
+    ) -> Result<(InodePlus, Option<InodePlus>), HalFsIOErr> {
+        let mut inode = self.get_nth_inode(ROOT_DIRECTORY_INODE_IDX as u32).await?;
 
         log!("Root directory Inode: {:?}", inode);
 
         let mut directory_inode_idx = ROOT_DIRECTORY_INODE_IDX as u32;
+        let mut directory_path = Path::new_appended("/");
 
         let mut file_inode: Option<InodePlus> = None;
 
-        let mut it = path.normalize().components().peekable();
-        while let Some(component) = it.next() {
+        let mut components: Vec<String> = path.normalize().components().collect();
+        let mut follows = 0;
+        let mut idx = 0;
+
+        while idx < components.len() {
+            let component = &components[idx];
+            let is_last = idx + 1 == components.len();
             log!("current component: {}", component);
-            match self.find_entry_by_name(&component, &inode).await {
-                Ok(Some(res)) => {
-                    if it.peek().is_none() {
-                        file_inode = Some(self.get_nth_inode(res as u32).await?);
-                        break;
+
+            match self.find_entry_by_name(component, &inode).await? {
+                Some(res) => {
+                    let found = self.get_nth_inode(res as u32).await?;
+
+                    if found.inode.is_symlink() && (!is_last || follow_last) {
+                        follows += 1;
+                        if follows > MAX_SYMLINK_FOLLOWS {
+                            return Err(HalFsIOErr::TooManySymlinks);
+                        }
+
+                        let target = self.read_symlink(&found).await?;
+                        let restart =
+                            symlink_restart_path(&directory_path, &target, &components[idx + 1..]);
+                        log!("following symlink to {}", restart.as_str());
+
+                        // the restart path is absolute, so resolution starts over at the root
+                        components = restart.components().collect();
+                        idx = 0;
+                        inode = self.get_nth_inode(ROOT_DIRECTORY_INODE_IDX as u32).await?;
+                        directory_inode_idx = ROOT_DIRECTORY_INODE_IDX as u32;
+                        directory_path = Path::new_appended("/");
+                        file_inode = None;
+                        continue;
                     }
 
-                    inode = self.get_nth_inode(res as u32).await?;
+                    if is_last {
+                        file_inode = Some(found);
+                        break;
+                    }
 
+                    inode = found;
                     directory_inode_idx = res as u32;
+                    directory_path = directory_path.join(component);
                 }
-                Ok(None) => {
-                    if it.peek().is_none() {
+                None => {
+                    if is_last {
                         file_inode = None;
                     } else {
                         return Err(HalFsIOErr::NoSuchFileOrDirectory);
                     }
                 }
-                Err(e) => return Err(e),
             }
+
+            idx += 1;
         }
 
         Ok((self.get_nth_inode(directory_inode_idx).await?, file_inode))
     }
 
+    /// walks the path and returns the inode it names, including the root directory
+    pub async fn resolve_path(&mut self, path: &Path) -> Result<InodePlus, HalFsIOErr> {
+        let (directory_inode, file_inode) = self.walk_path(path).await?;
+
+        // the root has no entry of its own, walk_path only hands it back as the directory
+        match file_inode {
+            Some(inode) => Ok(inode),
+            None if path.normalize().components().next().is_none() => Ok(directory_inode),
+            None => Err(HalFsIOErr::NoSuchFileOrDirectory),
+        }
+    }
+
     /// This function assumes that everything is initialized like the init function
     pub async fn open_file(
         &mut self,
         path: Path,
         flags: OpenFlags,
     ) -> Result<HalInode, HalFsIOErr> {
-        let (mut directory_inode, file_inode) = self.walk_path(&path).await?;
+        let follow_last = flags.flags & OpenFlagsValue::NoSymlink as i32 == 0;
+        let (mut directory_inode, file_inode) =
+            self.walk_path_following(&path, follow_last).await?;
         // remember whether the file existed before we attempt creation
         let existed = file_inode.is_some();
 
@@ -151,6 +151,7 @@ impl Ext2Fs {
 
         let mut block_iterator =
             self.create_block_iterator(inode, victim_inode.group_number.into());
+        block_iterator.skip(progress.block_idx as usize);
 
         let mut block_buf = self.get_buffer();
 
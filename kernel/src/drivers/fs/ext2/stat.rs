@@ -0,0 +1,147 @@
+use crate::{
+    drivers::fs::ext2::{InodePlus, features::RoCompatFlags, structs::Ext2Fs},
+    hal::{
+        fs::{FileStat, HalFsIOErr},
+        path::Path,
+    },
+};
+
+impl InodePlus {
+    pub fn stat(&self, large_file: bool) -> FileStat {
+        let inode = &self.inode;
+
+        FileStat {
+            ino: self.absolute_idx as u64,
+            size: inode.size(large_file),
+            mode: inode.i_mode as u32,
+            uid: inode.i_uid as u32,
+            gid: inode.i_gid as u32,
+            atime: inode.i_atime as u64,
+            mtime: inode.i_mtime as u64,
+            ctime: inode.i_ctime as u64,
+            nlink: inode.i_links_count as u32,
+            blocks: inode.i_blocks as u64,
+        }
+    }
+}
+
+impl Ext2Fs {
+    pub async fn stat(&mut self, path: &Path) -> Result<FileStat, HalFsIOErr> {
+        let inode = self.resolve_path(path).await?;
+
+        Ok(inode.stat(self.has_large_files()))
+    }
+
+    /// whether regular files keep the high half of their size in i_dir_acl
+    pub fn has_large_files(&self) -> bool {
+        self.super_block
+            .ro_compat_features()
+            .contains(RoCompatFlags::LARGE_FILE)
+    }
+
+    pub fn file_size(&self, inode: &InodePlus) -> u64 {
+        inode.inode.size(self.has_large_files())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        crypto::guid::Guid,
+        drivers::fs::ext2::{
+            EXT2_S_IFDIR, EXT2_S_IFREG, Inode, InodePlus, open::ROOT_DIRECTORY_INODE_IDX,
+            structs::Ext2Fs,
+        },
+        end_test,
+        hal::{
+            fs::{HalFsIOErr, HalIOCtx},
+            path::Path,
+            ram_disk,
+        },
+        terminal::test::block_on,
+        test_name,
+    };
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn ext2_stat() {
+        test_name!("ext2 stat");
+
+        let inode = InodePlus {
+            inode: Inode {
+                i_mode: EXT2_S_IFREG | 0o644,
+                i_uid: 1000,
+                i_gid: 100,
+                i_size: 5,
+                i_dir_acl: 1,
+                i_links_count: 2,
+                i_blocks: 2,
+                i_mtime: 1_700_000_000,
+                ..Default::default()
+            },
+            absolute_idx: 12,
+            group_number: 0,
+            relative_idx: 11,
+        };
+
+        let stat = inode.stat(false);
+        assert_eq!(stat.ino, 12);
+        assert_eq!(stat.size, 5);
+        assert_eq!(stat.mode, (EXT2_S_IFREG | 0o644) as u32);
+        assert_eq!(stat.nlink, 2);
+        assert_eq!(stat.uid, 1000);
+        assert_eq!(stat.gid, 100);
+        assert_eq!(stat.blocks, 2);
+        assert_eq!(stat.mtime, 1_700_000_000);
+
+        // with large files the high half of the size lives in i_dir_acl
+        assert_eq!(inode.stat(true).size, (1 << 32) + 5);
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn ext2_stat_on_disk() {
+        test_name!("ext2 stat of files on a disk");
+
+        let guid = Guid::from_bytes([0x6b; 16]);
+        block_on(async {
+            let mut fs = Ext2Fs::on_ram_disk(guid, 1).await;
+            let mut root = fs
+                .get_nth_inode(ROOT_DIRECTORY_INODE_IDX as u32)
+                .await
+                .unwrap();
+            let mut file = fs.create_file(&mut root, "a", 0o640).await.unwrap();
+            fs.write(&mut file, b"hello", &mut HalIOCtx::new())
+                .await
+                .unwrap();
+            fs.mkdir(Path::new_appended("/dir"), 0o755).await.unwrap();
+
+            let path = Path::new_appended("/a");
+            let resolved = fs.resolve_path(&path).await.unwrap();
+            assert_eq!(resolved.absolute_idx, file.absolute_idx);
+
+            let stat = fs.stat(&path).await.unwrap();
+            assert_eq!(stat.ino, file.absolute_idx as u64);
+            assert_eq!(stat.size, 5);
+            assert_eq!(stat.mode, (EXT2_S_IFREG | 0o640) as u32);
+            assert_eq!(stat.nlink, 1);
+            assert_eq!(stat.blocks, fs.blocks_to_i_blocks(1) as u64);
+
+            // a directory is linked from its parent and from its own "."
+            let stat = fs.stat(&Path::new_appended("/dir")).await.unwrap();
+            assert_eq!(stat.mode, (EXT2_S_IFDIR | 0o755) as u32);
+            assert_eq!(stat.nlink, 2);
+            assert_eq!(stat.size, fs.super_block.block_size() as u64);
+
+            assert!(matches!(
+                fs.stat(&Path::new_appended("/b")).await,
+                Err(HalFsIOErr::NoSuchFileOrDirectory)
+            ));
+        });
+        ram_disk::unregister(guid);
+
+        end_test!();
+    }
+}
@@ -1,15 +1,18 @@
 use crate::log;
 use crate::{crypto::guid::Guid, ejcineque::sync::mutex::Mutex};
-use alloc::{boxed::Box, collections::btree_set::BTreeSet, sync::Arc};
+use alloc::{boxed::Box, collections::btree_set::BTreeSet, sync::Arc, vec};
 
 use crate::{
     drivers::fs::ext2::{
-        GroupDescriptor, SuperBlock, create_file::RESERVED_BOOT_RECORD_OFFSET, init::identify_ext2,
+        BLOCK_GROUP_DESCRIPTOR_SIZE, GroupDescriptor, SuperBlock,
+        create_file::RESERVED_BOOT_RECORD_OFFSET,
+        features::MountMode,
+        init::{SUPERBLOCK_OFFSET, identify_ext2, superblock_lba},
     },
     hal::{
         fs::HalFsIOErr,
         gpt::GPTEntry,
-        storage::{HalStorageOperationErr, SECTOR_SIZE},
+        storage::{HalStorageOperationErr, SECTOR_SIZE, logical_sector_size_by_guid},
     },
 };
 
@@ -86,6 +89,8 @@ pub struct Ext2Fs {
     pub buffer_manager: BufferManager,
 
     pub super_block: SuperBlock,
+    /// every mutating operation refuses to run when this is set
+    pub read_only: bool,
 }
 
 impl Ext2Fs {
@@ -94,12 +99,23 @@ impl Ext2Fs {
             .await
             .expect("Failed to mount ext2");
 
+        let mode = super_block
+            .mount_mode()
+            .expect("Refusing to mount ext2 with unsupported features");
+        if mode == MountMode::ReadOnly {
+            log!("ext2 has unsupported read-only compatible features, mounting read-only");
+        }
+
         log!("Mounted ext2");
 
         let io_handler = IoHandler {
             drive_id,
             start_lba: entry.start_lba as i64,
             block_size: super_block.block_size(),
+            // identify_ext2 already got it once
+            sector_size: logical_sector_size_by_guid(drive_id)
+                .await
+                .expect("Failed to get the sector size"),
         };
 
         let group_manager = GroupManager {
@@ -130,7 +146,25 @@ impl Ext2Fs {
             buffer_manager,
             entry,
             super_block,
+            read_only: mode == MountMode::ReadOnly,
+        }
+    }
+
+    /// mounts the filesystem without ever writing to the disk, useful for inspecting damaged
+    /// filesystems
+    pub async fn new_read_only(drive_id: Guid, entry: GPTEntry) -> Self {
+        let mut fs = Self::new(drive_id, entry).await;
+        fs.read_only = true;
+        fs
+    }
+
+    /// has to be called at the entry of every operation that modifies the disk
+    pub fn ensure_writable(&self) -> Result<(), HalFsIOErr> {
+        if self.read_only {
+            return Err(HalFsIOErr::ReadOnlyFilesystem);
         }
+
+        Ok(())
     }
 
     /// relative LBA
@@ -196,8 +230,267 @@ impl Ext2Fs {
     pub fn get_buffer(&self) -> Box<[u8]> {
         self.buffer_manager.get_buffer()
     }
+
+    /// writes the in-memory superblock back and syncs, the rest of its sector is preserved. On 4kn
+    /// drives that includes the boot block in front of it
+    pub async fn write_super_block(&mut self) -> Result<(), HalFsIOErr> {
+        self.ensure_writable()?;
+
+        let sector_size = self.io_handler.sector_size;
+        let lba = superblock_lba(0, sector_size);
+        let mut buf: Box<[u8]> = vec![0u8; sector_size].into_boxed_slice();
+        buf = self.read_sectors(buf, lba).await?;
+
+        let start = SUPERBLOCK_OFFSET % sector_size;
+        let super_block_bytes = bytemuck::bytes_of(&self.super_block);
+        buf[start..start + super_block_bytes.len()].copy_from_slice(super_block_bytes);
+
+        self.write_sectors(buf, lba).await?;
+
+        self.sync().await
+    }
+
+    /// waits until everything written to the filesystem so far is on the physical media
+    pub async fn sync(&self) -> Result<(), HalFsIOErr> {
+        if self.read_only {
+            return Ok(());
+        }
+
+        Ok(self.io_handler.flush().await?)
+    }
+
+    /// applies the deltas to a group descriptor and the superblock's free inode count
+    pub async fn adjust_inode_counts(
+        &mut self,
+        group_number: u32,
+        free_inodes_delta: i16,
+        used_dirs_delta: i16,
+    ) -> Result<(), HalFsIOErr> {
+        self.ensure_writable()?;
+
+        let gr_number = group_number as i64;
+        let lba = self.get_block_group_table_lba();
+        let lba_offset = (gr_number * BLOCK_GROUP_DESCRIPTOR_SIZE as i64) / SECTOR_SIZE as i64;
+        let byte_offset =
+            ((gr_number * BLOCK_GROUP_DESCRIPTOR_SIZE as i64) % SECTOR_SIZE as i64) as usize;
+
+        let mut buf: Box<[u8]> = Box::new([0u8; SECTOR_SIZE]);
+        buf = self.read_sectors(buf, lba + lba_offset).await?;
+
+        let descriptor: &mut GroupDescriptor = bytemuck::from_bytes_mut(
+            &mut buf[byte_offset..byte_offset + size_of::<GroupDescriptor>()],
+        );
+        descriptor.bg_free_inodes_count = descriptor
+            .bg_free_inodes_count
+            .checked_add_signed(free_inodes_delta)
+            .ok_or(HalFsIOErr::Corrupted)?;
+        descriptor.bg_used_dirs_count = descriptor
+            .bg_used_dirs_count
+            .checked_add_signed(used_dirs_delta)
+            .ok_or(HalFsIOErr::Corrupted)?;
+
+        self.write_sectors(buf, lba + lba_offset).await?;
+
+        self.super_block.s_free_inodes_count = self
+            .super_block
+            .s_free_inodes_count
+            .checked_add_signed(free_inodes_delta as i32)
+            .ok_or(HalFsIOErr::Corrupted)?;
+
+        self.write_super_block().await
+    }
 }
 
 pub fn block_group_size(blocks_per_group: i64, block_size: i64) -> i64 {
     blocks_per_group * (block_size / SECTOR_SIZE as i64)
 }
+
+/// every group of [`Ext2Fs::on_ram_disk`] has this many blocks and [`RAM_DISK_INODES`] inodes
+#[cfg(test)]
+pub const RAM_DISK_BLOCKS_PER_GROUP: u32 = 1024;
+#[cfg(test)]
+pub const RAM_DISK_INODES: u32 = 256;
+/// the inode table of a group takes this many blocks
+#[cfg(test)]
+const RAM_DISK_INODE_TABLE_BLOCKS: u32 =
+    RAM_DISK_INODES * super::INODE_SIZE as u32 / super::BLOCK_SIZE;
+/// the block holding the root directory of [`Ext2Fs::on_ram_disk`], the first group's inode table
+/// ends right before it
+#[cfg(test)]
+pub const RAM_DISK_ROOT_BLOCK: u32 = 5 + RAM_DISK_INODE_TABLE_BLOCKS;
+
+#[cfg(test)]
+impl Ext2Fs {
+    /// a filesystem that isn't backed by any registered drive, every I/O on it fails
+    pub fn detached(read_only: bool) -> Self {
+        Self::detached_with_blocks(read_only, super::BLOCKS_PER_GROUP)
+    }
+
+    /// formats a filesystem of `groups` groups of 1024 byte blocks onto a ram disk registered
+    /// under `guid` and mounts it. The first group keeps the superblock, the descriptors, its
+    /// bitmaps and inode table and the root directory in the blocks up to
+    /// [`RAM_DISK_ROOT_BLOCK`], every other group starts with its bitmaps and inode table
+    pub async fn on_ram_disk(guid: Guid, groups: u32) -> Self {
+        use bytemuck::Zeroable;
+        use dvida_serialize::DvSerialize;
+
+        use crate::{
+            drivers::fs::ext2::{
+                EXT2_ROOT_INO, EXT2_VALID_FS, INODE_SIZE, create_file::new_inode,
+                dirs::init_dir_block, inode_allocator::set_first_clear_bit,
+            },
+            hal::ram_disk,
+        };
+
+        let block_size = super::BLOCK_SIZE as usize;
+        let blocks_count = groups * RAM_DISK_BLOCKS_PER_GROUP;
+        let mut disk = alloc::vec![0u8; blocks_count as usize * block_size];
+        let block = |idx: u32| idx as usize * block_size..(idx as usize + 1) * block_size;
+        // the bits past the end of a group don't stand for any block
+        let bitmap_end = block_size as u32 * 8;
+
+        let (mut free_blocks, mut free_inodes) = (0, 0);
+        let mut descriptors = alloc::vec::Vec::new();
+        for group_number in 0..groups {
+            let start = group_number * RAM_DISK_BLOCKS_PER_GROUP;
+            let (block_bitmap, used) = if group_number == 0 {
+                (3, RAM_DISK_ROOT_BLOCK + 1)
+            } else {
+                (start, 2 + RAM_DISK_INODE_TABLE_BLOCKS)
+            };
+
+            let mut bitmap = alloc::vec![0u8; block_size];
+            for idx in (0..used).chain(RAM_DISK_BLOCKS_PER_GROUP..bitmap_end) {
+                set_first_clear_bit(&mut bitmap, idx as usize, idx as usize + 1);
+            }
+            let group_free_blocks = bitmap.iter().map(|byte| byte.count_zeros()).sum::<u32>();
+            disk[block(block_bitmap)].copy_from_slice(&bitmap);
+
+            // the reserved inodes all live in the first group
+            let reserved = if group_number == 0 {
+                super::EXT2_GOOD_OLD_FIRST_INO - 1
+            } else {
+                0
+            };
+            bitmap.fill(0);
+            for idx in (0..reserved).chain(RAM_DISK_INODES..bitmap_end) {
+                set_first_clear_bit(&mut bitmap, idx as usize, idx as usize + 1);
+            }
+            let group_free_inodes = bitmap.iter().map(|byte| byte.count_zeros()).sum::<u32>();
+            disk[block(block_bitmap + 1)].copy_from_slice(&bitmap);
+
+            let mut descriptor = GroupDescriptor::zeroed();
+            descriptor.bg_block_bitmap = block_bitmap;
+            descriptor.bg_inode_bitmap = block_bitmap + 1;
+            descriptor.bg_inode_table = block_bitmap + 2;
+            descriptor.bg_free_blocks_count = group_free_blocks as u16;
+            descriptor.bg_free_inodes_count = group_free_inodes as u16;
+            descriptor.bg_used_dirs_count = (group_number == 0) as u16;
+            descriptors.extend_from_slice(bytemuck::bytes_of(&descriptor));
+            descriptors.resize(
+                descriptors.len() + BLOCK_GROUP_DESCRIPTOR_SIZE - size_of::<GroupDescriptor>(),
+                0,
+            );
+
+            free_blocks += group_free_blocks;
+            free_inodes += group_free_inodes;
+        }
+        disk[block(2)][..descriptors.len()].copy_from_slice(&descriptors);
+
+        let mut root = new_inode(0o755, true, 0);
+        root.i_size = block_size as u32;
+        root.i_blocks = (block_size / SECTOR_SIZE) as u32;
+        root.i_block[0] = RAM_DISK_ROOT_BLOCK;
+        let root_offset = block(5).start + (EXT2_ROOT_INO as usize - 1) * INODE_SIZE as usize;
+        root.serialize(
+            dvida_serialize::Endianness::Little,
+            &mut disk[root_offset..],
+        )
+        .expect("Failed to serialize the root inode");
+        init_dir_block(
+            &mut disk[block(RAM_DISK_ROOT_BLOCK)],
+            EXT2_ROOT_INO,
+            EXT2_ROOT_INO,
+        )
+        .expect("Failed to initialize the root directory");
+
+        let mut super_block = SuperBlock::zeroed();
+        super_block.s_magic = super::EXT2_SUPER_MAGIC;
+        super_block.s_state = EXT2_VALID_FS;
+        super_block.s_blocks_count = blocks_count;
+        super_block.s_free_blocks_count = free_blocks;
+        super_block.s_inodes_count = groups * RAM_DISK_INODES;
+        super_block.s_free_inodes_count = free_inodes;
+        super_block.s_first_data_block = super::FIRST_DATA_BLOCK;
+        super_block.s_blocks_per_group = RAM_DISK_BLOCKS_PER_GROUP;
+        super_block.s_inodes_per_group = RAM_DISK_INODES;
+        let super_block_bytes = bytemuck::bytes_of(&super_block);
+        disk[block(1)][..super_block_bytes.len()].copy_from_slice(super_block_bytes);
+
+        let sectors = disk.len() / SECTOR_SIZE;
+        ram_disk::register(guid, sectors, SECTOR_SIZE);
+        ram_disk::with_disk(guid, |ram_disk| ram_disk.data = disk);
+
+        let mut entry = GPTEntry::default();
+        entry.start_lba = 0;
+        entry.end_lba = sectors as u64 - 1;
+
+        Self::new(guid, entry).await
+    }
+
+    /// mounts the disk this filesystem sits on again, this time read-only
+    pub async fn remount_read_only(self) -> Self {
+        Self::new_read_only(self.drive_id, self.entry).await
+    }
+
+    /// same as [`Ext2Fs::detached`] but spread over as many groups as `blocks_count` needs
+    pub fn detached_with_blocks(read_only: bool, blocks_count: u32) -> Self {
+        use bytemuck::Zeroable;
+
+        let mut super_block = SuperBlock::zeroed();
+        super_block.s_magic = super::EXT2_SUPER_MAGIC;
+        super_block.s_log_block_size = 0;
+        super_block.s_blocks_count = blocks_count;
+        super_block.s_blocks_per_group = super::BLOCKS_PER_GROUP;
+        super_block.s_inodes_count =
+            super::INODES_PER_GROUP * blocks_count.div_ceil(super::BLOCKS_PER_GROUP);
+        super_block.s_inodes_per_group = super::INODES_PER_GROUP;
+        super_block.s_first_data_block = super::FIRST_DATA_BLOCK;
+
+        let io_handler = IoHandler {
+            drive_id: Guid::default(),
+            start_lba: 0,
+            block_size: super_block.block_size(),
+            sector_size: SECTOR_SIZE,
+        };
+
+        let group_manager = GroupManager {
+            block_size: super_block.block_size(),
+            blocks_per_group: super_block.s_blocks_per_group,
+            first_data_block: super_block.s_first_data_block,
+            io_handler,
+        };
+
+        let buffer_manager = BufferManager {
+            block_size: super_block.block_size() as usize,
+        };
+
+        Self {
+            drive_id: Guid::default(),
+            entry: GPTEntry::default(),
+            io_handler,
+            block_allocator: BlockAllocator {
+                block_groups_count: super_block.block_groups_count() as i64,
+                group_manager,
+                io_handler,
+                buffer_manager,
+                allocated_block_indices: Arc::new(Mutex::new(BTreeSet::new())),
+                unwritten_freed_blocks: Arc::new(Mutex::new(BTreeSet::new())),
+            },
+            group_manager,
+            buffer_manager,
+            super_block,
+            read_only,
+        }
+    }
+}
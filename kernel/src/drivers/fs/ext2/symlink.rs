@@ -0,0 +1,241 @@
+use alloc::{string::String, vec};
+
+use crate::{
+    drivers::fs::ext2::{Inode, InodePlus, structs::Ext2Fs},
+    hal::{
+        fs::{HalFsIOErr, HalIOCtx},
+        path::Path,
+    },
+};
+
+/// how many links a single lookup may go through before it counts as a loop, same as linux
+pub const MAX_SYMLINK_FOLLOWS: usize = 40;
+
+/// targets shorter than i_block itself are stored inline
+pub const FAST_SYMLINK_MAX_LEN: u32 = 60;
+
+impl Inode {
+    /// a fast symlink keeps its target in i_block and owns no data blocks
+    pub fn is_fast_symlink(&self) -> bool {
+        self.is_symlink() && self.i_size < FAST_SYMLINK_MAX_LEN && self.i_blocks == 0
+    }
+
+    /// None unless this is a fast symlink
+    pub fn fast_symlink_target(&self) -> Option<String> {
+        if !self.is_fast_symlink() {
+            return None;
+        }
+
+        let bytes: &[u8] = bytemuck::cast_slice(&self.i_block);
+        Some(String::from_utf8_lossy(&bytes[..self.i_size as usize]).into_owned())
+    }
+}
+
+/// where resolution restarts after a link in `dir` pointing at `target`, with `rest` still to
+/// walk. Absolute targets start over from the root
+pub fn symlink_restart_path(dir: &Path, target: &str, rest: &[String]) -> Path {
+    let mut path = dir.resolve(target);
+
+    for component in rest {
+        path = path.join(component);
+    }
+
+    path.normalize()
+}
+
+impl Ext2Fs {
+    /// slow symlinks keep the target in their data blocks like a regular file
+    pub async fn read_symlink(&mut self, inode: &InodePlus) -> Result<String, HalFsIOErr> {
+        if !inode.inode.is_symlink() {
+            return Err(HalFsIOErr::InvalidArgument);
+        }
+
+        if let Some(target) = inode.inode.fast_symlink_target() {
+            return Ok(target);
+        }
+
+        let mut inode = inode.clone();
+        let mut buf = vec![0u8; inode.inode.i_size as usize];
+        let bytes_read = self
+            .read(&mut inode, &mut buf, &mut HalIOCtx::new())
+            .await?;
+
+        if bytes_read != buf.len() {
+            return Err(HalFsIOErr::Corrupted);
+        }
+
+        String::from_utf8(buf).map_err(|_| HalFsIOErr::Corrupted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{format, string::String, vec};
+
+    use super::symlink_restart_path;
+    use crate::{
+        crypto::guid::Guid,
+        drivers::fs::ext2::{EXT2_S_IFLNK, EXT2_S_IFREG, Inode, InodePlus, structs::Ext2Fs},
+        end_test,
+        hal::{
+            fs::{HalFsIOErr, HalIOCtx},
+            path::Path,
+            ram_disk,
+        },
+        terminal::test::block_on,
+        test_name,
+    };
+
+    fn fast_symlink(target: &str) -> Inode {
+        let mut inode = Inode {
+            i_mode: EXT2_S_IFLNK | 0o777,
+            i_size: target.len() as u32,
+            ..Default::default()
+        };
+
+        bytemuck::cast_slice_mut::<u32, u8>(&mut inode.i_block)[..target.len()]
+            .copy_from_slice(target.as_bytes());
+
+        inode
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn ext2_symlink_resolution() {
+        test_name!("ext2 symlink resolution");
+
+        // a -> b in /dir, opening /dir/a/file goes on from /dir/b/file
+        let link = fast_symlink("b");
+        assert!(link.is_fast_symlink());
+        assert_eq!(link.fast_symlink_target().as_deref(), Some("b"));
+
+        let dir = Path::new_appended("/dir");
+        let rest = vec![String::from("file")];
+        let target = link.fast_symlink_target().unwrap();
+        assert_eq!(
+            symlink_restart_path(&dir, &target, &rest).as_str(),
+            "/dir/b/file"
+        );
+        assert_eq!(symlink_restart_path(&dir, "b", &[]).as_str(), "/dir/b");
+
+        // absolute targets leave the directory of the link behind
+        assert_eq!(
+            symlink_restart_path(&dir, "/etc/../usr", &rest).as_str(),
+            "/usr/file"
+        );
+        assert_eq!(symlink_restart_path(&dir, "../x", &[]).as_str(), "/x");
+
+        // long targets live in data blocks
+        let slow = Inode {
+            i_mode: EXT2_S_IFLNK | 0o777,
+            i_size: 100,
+            i_blocks: 2,
+            ..Default::default()
+        };
+        assert!(!slow.is_fast_symlink());
+        assert_eq!(slow.fast_symlink_target(), None);
+
+        let file = Inode {
+            i_mode: EXT2_S_IFREG,
+            i_size: 1,
+            ..Default::default()
+        };
+        assert_eq!(file.fast_symlink_target(), None);
+
+        end_test!();
+    }
+
+    /// creates `name` in `dir` as a regular file and turns it into a link to `target`, short
+    /// targets go inline like a real fast symlink, long ones are written to the data blocks
+    async fn make_symlink(
+        fs: &mut Ext2Fs,
+        dir: &mut InodePlus,
+        name: &str,
+        target: &str,
+    ) -> InodePlus {
+        let mut link = fs.create_file(dir, name, 0o777).await.unwrap();
+
+        if target.len() < super::FAST_SYMLINK_MAX_LEN as usize {
+            fs.free_blocks(&mut link).await.unwrap();
+            fs.block_allocator.write_freed_blocks().await.unwrap();
+            link.inode.i_block = [0; 15];
+            bytemuck::cast_slice_mut::<u32, u8>(&mut link.inode.i_block)[..target.len()]
+                .copy_from_slice(target.as_bytes());
+        } else {
+            let written = fs
+                .write(&mut link, target.as_bytes(), &mut HalIOCtx::new())
+                .await
+                .unwrap();
+            assert_eq!(written, target.len());
+        }
+
+        link.inode.i_mode = EXT2_S_IFLNK | 0o777;
+        link.inode.i_size = target.len() as u32;
+        fs.write_inode(&link).await.unwrap();
+
+        link
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn ext2_paths_resolve_through_symlinks() {
+        test_name!("ext2 paths resolve through fast and slow symlinks on disk");
+
+        let guid = Guid::from_bytes([0x60; 16]);
+        block_on(async {
+            let mut fs = Ext2Fs::on_ram_disk(guid, 1).await;
+            let mut dir = fs.mkdir(Path::new_appended("/dir"), 0o755).await.unwrap();
+            let mut target_dir = fs.mkdir(Path::new_appended("/dir/b"), 0o755).await.unwrap();
+            let file = fs
+                .create_file(&mut target_dir, "file", 0o644)
+                .await
+                .unwrap();
+
+            // /dir/a -> b
+            let fast = make_symlink(&mut fs, &mut dir, "a", "b").await;
+            let fast = fs.get_nth_inode(fast.absolute_idx).await.unwrap();
+            assert!(fast.inode.is_fast_symlink());
+            assert_eq!(fs.read_symlink(&fast).await.unwrap(), "b");
+
+            let resolved = fs
+                .resolve_path(&Path::new_appended("/dir/a/file"))
+                .await
+                .unwrap();
+            assert_eq!(resolved.absolute_idx, file.absolute_idx);
+
+            // the last component is handed back as the link unless asked to follow it
+            let link = fs
+                .resolve_path(&Path::new_appended("/dir/a"))
+                .await
+                .unwrap();
+            assert_eq!(link.absolute_idx, fast.absolute_idx);
+            let (_, followed) = fs
+                .walk_path_following(&Path::new_appended("/dir/a"), true)
+                .await
+                .unwrap();
+            assert_eq!(followed.unwrap().absolute_idx, target_dir.absolute_idx);
+
+            // /slow -> /dir/./././.../b, too long to fit in i_block
+            let target = format!("/dir/{}b", "./".repeat(40));
+            let mut root = fs.resolve_path(&Path::new_appended("/")).await.unwrap();
+            let slow = make_symlink(&mut fs, &mut root, "slow", &target).await;
+            let slow = fs.get_nth_inode(slow.absolute_idx).await.unwrap();
+            assert!(slow.inode.is_symlink() && !slow.inode.is_fast_symlink());
+            assert_eq!(fs.read_symlink(&slow).await.unwrap(), target);
+
+            let resolved = fs
+                .resolve_path(&Path::new_appended("/slow/file"))
+                .await
+                .unwrap();
+            assert_eq!(resolved.absolute_idx, file.absolute_idx);
+
+            // a link to itself never resolves
+            make_symlink(&mut fs, &mut dir, "loop", "loop").await;
+            let res = fs.resolve_path(&Path::new_appended("/dir/loop/file")).await;
+            assert!(matches!(res, Err(HalFsIOErr::TooManySymlinks)));
+        });
+        ram_disk::unregister(guid);
+
+        end_test!();
+    }
+}
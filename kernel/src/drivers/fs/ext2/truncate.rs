@@ -0,0 +1,421 @@
+use alloc::boxed::Box;
+
+use crate::{
+    drivers::fs::ext2::{InodePlus, read::INODE_BLOCK_LIMIT, structs::Ext2Fs},
+    hal::fs::HalFsIOErr,
+};
+
+/// how many of the first `keep` logical blocks fall into the direct, indirect, double and triple
+/// indirect ranges, with `ptrs` addresses per indirect block
+pub fn kept_per_level(keep: u64, ptrs: u64) -> [u64; 4] {
+    let mut rest = keep;
+    let mut kept = [0; 4];
+
+    for (level, span) in [
+        INODE_BLOCK_LIMIT as u64,
+        ptrs,
+        ptrs * ptrs,
+        ptrs * ptrs * ptrs,
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        kept[level] = rest.min(span);
+        rest -= kept[level];
+    }
+
+    kept
+}
+
+fn read_ptr(buf: &[u8], slot: usize) -> u32 {
+    u32::from_le_bytes(buf[slot * 4..slot * 4 + 4].try_into().unwrap())
+}
+
+fn clear_ptr(buf: &mut [u8], slot: usize) {
+    buf[slot * 4..slot * 4 + 4].fill(0);
+}
+
+impl Ext2Fs {
+    /// shrinking frees every block past the new end, indirect blocks included once nothing in
+    /// them is left. Growing only moves i_size, the gap reads back as a hole
+    pub async fn truncate(
+        &mut self,
+        inode: &mut InodePlus,
+        new_size: u64,
+    ) -> Result<(), HalFsIOErr> {
+        self.ensure_writable()?;
+
+        if inode.inode.is_directory() {
+            return Err(HalFsIOErr::IsDirectory);
+        }
+
+        // symlinks and device nodes may keep their data in i_block itself, only regular files
+        // are guaranteed to hold block pointers there
+        if !inode.inode.is_regular_file() {
+            return Err(HalFsIOErr::InvalidArgument);
+        }
+
+        let large_file = self.has_large_files() && inode.inode.is_regular_file();
+        if !large_file && new_size > u32::MAX as u64 {
+            return Err(HalFsIOErr::FileTooLarge);
+        }
+
+        let block_size = self.super_block.block_size() as u64;
+        if new_size < self.file_size(inode) {
+            self.zero_tail(inode, new_size).await?;
+            self.free_blocks_from(inode, new_size.div_ceil(block_size))
+                .await?;
+        }
+
+        inode.inode.i_size = new_size as u32;
+        if large_file {
+            inode.inode.i_dir_acl = (new_size >> 32) as u32;
+        }
+
+        let time = crate::time::Rtc::new()
+            .read_datetime()
+            .map_or(0, |dt| crate::time::formats::rtc_to_posix(&dt));
+        inode.inode.i_mtime = time;
+        inode.inode.i_ctime = time;
+
+        self.write_inode(inode).await
+    }
+
+    /// zeroes the rest of the block the new end falls into, growing the file later has to read
+    /// zeros there
+    async fn zero_tail(&mut self, inode: &InodePlus, size: u64) -> Result<(), HalFsIOErr> {
+        let block_size = self.super_block.block_size() as u64;
+        let offset = (size % block_size) as usize;
+
+        if offset == 0 {
+            return Ok(());
+        }
+
+        let mut blocks_iterator =
+            self.create_block_iterator(&inode.inode, inode.group_number.into());
+        blocks_iterator.skip((size / block_size) as usize);
+
+        let element = blocks_iterator.next(self.get_buffer()).await?;
+        if element.is_terminated || element.is_hole() {
+            return Ok(());
+        }
+
+        let mut buf = element.buf;
+        buf[offset..].fill(0);
+        Ok(self.io_handler.write_block(buf, element.block_idx).await?)
+    }
+
+    /// frees every block from logical block `keep` on and updates i_blocks and the free counts
+    async fn free_blocks_from(
+        &mut self,
+        inode: &mut InodePlus,
+        keep: u64,
+    ) -> Result<(), HalFsIOErr> {
+        let ptrs = (self.super_block.block_size() / 4) as u64;
+        let kept = kept_per_level(keep, ptrs);
+
+        let mut cur_buf: Box<[u8]> = self.get_buffer();
+        let mut cur_bitmap_lba = 0;
+
+        for slot in kept[0] as usize..INODE_BLOCK_LIMIT as usize {
+            let block = inode.inode.i_block[slot];
+            if block == 0 {
+                continue;
+            }
+
+            cur_buf = self.free_block(block, &mut cur_bitmap_lba, cur_buf).await?;
+            inode.inode.i_block[slot] = 0;
+        }
+
+        for (level, span) in [ptrs, ptrs * ptrs, ptrs * ptrs * ptrs]
+            .into_iter()
+            .enumerate()
+        {
+            let slot = INODE_BLOCK_LIMIT as usize + level;
+            let block = inode.inode.i_block[slot];
+            let keep = kept[level + 1];
+
+            if block == 0 || keep >= span {
+                continue;
+            }
+
+            cur_buf = match (level, keep) {
+                (0, 0) => {
+                    self.free_indirect_block(block, &mut cur_bitmap_lba, cur_buf)
+                        .await?
+                }
+                (1, 0) => {
+                    self.free_double_indirect_block(block, &mut cur_bitmap_lba, cur_buf)
+                        .await?
+                }
+                (_, 0) => {
+                    self.free_triple_indirect_block(block, &mut cur_bitmap_lba, cur_buf)
+                        .await?
+                }
+                _ => {
+                    self.truncate_indirect_tree(block, level, keep, &mut cur_bitmap_lba, cur_buf)
+                        .await?
+                }
+            };
+
+            if keep == 0 {
+                inode.inode.i_block[slot] = 0;
+            }
+        }
+
+        let freed = self
+            .block_allocator
+            .unwritten_freed_blocks
+            .lock()
+            .await
+            .len() as u32;
+        self.block_allocator.write_freed_blocks().await?;
+
+        self.super_block.s_free_blocks_count += freed;
+        inode.inode.i_blocks = inode
+            .inode
+            .i_blocks
+            .saturating_sub(self.blocks_to_i_blocks(freed));
+
+        self.write_super_block().await
+    }
+
+    /// keeps the first `keep` data blocks under an indirect block of the given level (0 for a
+    /// single indirect block) and frees the rest, the block itself stays
+    async fn truncate_indirect_tree(
+        &mut self,
+        block_idx: u32,
+        level: usize,
+        keep: u64,
+        cur_bitmap_lba: &mut i64,
+        mut cur_buf: Box<[u8]>,
+    ) -> Result<Box<[u8]>, HalFsIOErr> {
+        let ptrs = (self.super_block.block_size() / 4) as usize;
+        // how many data blocks hang off each slot
+        let child_span = (ptrs as u64).pow(level as u32);
+
+        let mut buf = self.get_buffer();
+        buf = self.io_handler.read_block(buf, block_idx).await?;
+
+        for slot in 0..ptrs {
+            let child = read_ptr(&buf, slot);
+            let child_keep = keep.saturating_sub(slot as u64 * child_span);
+
+            if child == 0 || child_keep >= child_span {
+                continue;
+            }
+
+            cur_buf = match (level, child_keep) {
+                (0, _) => self.free_block(child, cur_bitmap_lba, cur_buf).await?,
+                (1, 0) => {
+                    self.free_indirect_block(child, cur_bitmap_lba, cur_buf)
+                        .await?
+                }
+                (_, 0) => {
+                    self.free_double_indirect_block(child, cur_bitmap_lba, cur_buf)
+                        .await?
+                }
+                // only the slot the new end falls into is cut partway
+                _ => {
+                    Box::pin(self.truncate_indirect_tree(
+                        child,
+                        level - 1,
+                        child_keep,
+                        cur_bitmap_lba,
+                        cur_buf,
+                    ))
+                    .await?
+                }
+            };
+
+            if child_keep == 0 {
+                clear_ptr(&mut buf, slot);
+            }
+        }
+
+        self.io_handler.write_block(buf, block_idx).await?;
+
+        Ok(cur_buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use crate::{
+        crypto::guid::Guid,
+        drivers::fs::ext2::{
+            EXT2_S_IFCHR, EXT2_S_IFDIR, EXT2_S_IFLNK, EXT2_S_IFREG, InodePlus,
+            open::ROOT_DIRECTORY_INODE_IDX,
+            read::INODE_BLOCK_LIMIT,
+            structs::{Ext2Fs, RAM_DISK_BLOCKS_PER_GROUP},
+        },
+        end_test,
+        hal::{
+            fs::{HalFsIOErr, HalIOCtx},
+            ram_disk,
+        },
+        terminal::test::block_on,
+        test_name,
+    };
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn ext2_truncate_rejects() {
+        test_name!("ext2 truncate only takes regular files");
+
+        // nothing of these is on a disk, each is turned away before any I/O
+        let mut fs = Ext2Fs::detached(false);
+        let mut file = InodePlus::default();
+        file.inode.i_mode = EXT2_S_IFREG;
+        file.inode.i_size = 5000;
+
+        let mut dir = InodePlus::default();
+        dir.inode.i_mode = EXT2_S_IFDIR;
+        assert!(matches!(
+            block_on(fs.truncate(&mut dir, 0)),
+            Err(HalFsIOErr::IsDirectory)
+        ));
+
+        // the target of a fast symlink and the number of a device sit where the block pointers
+        // would be, none of it may be freed as a block
+        for mode in [EXT2_S_IFLNK, EXT2_S_IFCHR] {
+            let mut inline = InodePlus::default();
+            inline.inode.i_mode = mode | 0o777;
+            inline.inode.i_size = 4;
+            inline.inode.i_block[0] = 0x2F746D70;
+            assert!(matches!(
+                block_on(fs.truncate(&mut inline, 0)),
+                Err(HalFsIOErr::InvalidArgument)
+            ));
+            assert_eq!(inline.inode.i_size, 4);
+            assert_eq!(inline.inode.i_block[0], 0x2F746D70);
+        }
+
+        // without large files the size has to fit i_size
+        assert!(matches!(
+            block_on(fs.truncate(&mut file, 1 << 32)),
+            Err(HalFsIOErr::FileTooLarge)
+        ));
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn ext2_truncate_frees_blocks() {
+        test_name!("ext2 truncate frees the blocks past the new end");
+
+        let guid = Guid::from_bytes([0x64; 16]);
+        block_on(async {
+            let mut fs = Ext2Fs::on_ram_disk(guid, 1).await;
+            let block_size = fs.super_block.block_size() as usize;
+            let free_count = async |fs: &Ext2Fs| {
+                fs.get_group(0)
+                    .await
+                    .unwrap()
+                    .descriptor
+                    .bg_free_blocks_count
+            };
+            let is_used = async |fs: &Ext2Fs, block_idx: u32| {
+                let bitmap_lba = fs.get_group(0).await.unwrap().get_block_bitmap_lba();
+                let bitmap = fs.read_sectors(fs.get_buffer(), bitmap_lba).await.unwrap();
+                let idx = block_idx % RAM_DISK_BLOCKS_PER_GROUP;
+                bitmap[idx as usize / 8] & (1 << (idx % 8)) != 0
+            };
+
+            let free_before = free_count(&fs).await;
+            let mut root = fs
+                .get_nth_inode(ROOT_DIRECTORY_INODE_IDX as u32)
+                .await
+                .unwrap();
+            let mut file = fs.create_file(&mut root, "a", 0o644).await.unwrap();
+
+            // three blocks into the indirect range, every block filled with its number plus one
+            let data: alloc::vec::Vec<u8> = (0..INODE_BLOCK_LIMIT as usize + 3)
+                .flat_map(|idx| vec![idx as u8 + 1; block_size])
+                .collect();
+            fs.write(&mut file, &data, &mut HalIOCtx::new())
+                .await
+                .unwrap();
+            let mut file = fs.get_nth_inode(file.absolute_idx).await.unwrap();
+
+            let direct: [u32; 12] = file.inode.i_block[..INODE_BLOCK_LIMIT as usize]
+                .try_into()
+                .unwrap();
+            let ind = file.inode.i_block[INODE_BLOCK_LIMIT as usize];
+            let ind_buf = fs
+                .io_handler
+                .read_block(fs.get_buffer(), ind)
+                .await
+                .unwrap();
+            let entries: [u32; 3] = core::array::from_fn(|slot| {
+                u32::from_le_bytes(ind_buf[slot * 4..slot * 4 + 4].try_into().unwrap())
+            });
+            let free_written = free_count(&fs).await;
+            assert_eq!(free_written, free_before - 16);
+
+            // one byte into the indirect range keeps the indirect block and its first entry
+            fs.truncate(
+                &mut file,
+                (INODE_BLOCK_LIMIT as usize * block_size + 1) as u64,
+            )
+            .await
+            .unwrap();
+            assert!(is_used(&fs, ind).await && is_used(&fs, entries[0]).await);
+            assert!(!is_used(&fs, entries[1]).await && !is_used(&fs, entries[2]).await);
+            assert_eq!(file.inode.i_block[INODE_BLOCK_LIMIT as usize], ind);
+            assert_eq!(file.inode.i_blocks, fs.blocks_to_i_blocks(14));
+            assert_eq!(free_count(&fs).await, free_written + 2);
+
+            // exactly at the end of the direct blocks the indirect block goes as well
+            fs.truncate(&mut file, (INODE_BLOCK_LIMIT as usize * block_size) as u64)
+                .await
+                .unwrap();
+            assert!(!is_used(&fs, ind).await && !is_used(&fs, entries[0]).await);
+            assert_eq!(file.inode.i_block[INODE_BLOCK_LIMIT as usize], 0);
+            for &block_idx in direct.iter() {
+                assert!(is_used(&fs, block_idx).await);
+            }
+
+            // a cut inside the first block keeps it with the rest of it zeroed
+            fs.truncate(&mut file, 100).await.unwrap();
+            assert!(is_used(&fs, direct[0]).await);
+            for &block_idx in direct[1..].iter() {
+                assert!(!is_used(&fs, block_idx).await);
+            }
+            assert_eq!(file.inode.i_block[1..], [0; 14]);
+            assert_eq!(file.inode.i_blocks, fs.blocks_to_i_blocks(1));
+            let first = fs
+                .io_handler
+                .read_block(fs.get_buffer(), direct[0])
+                .await
+                .unwrap();
+            assert!(first[..100].iter().all(|&byte| byte == 1));
+            assert!(first[100..].iter().all(|&byte| byte == 0));
+
+            // what's written to disk matches what truncate left in memory
+            let on_disk = fs.get_nth_inode(file.absolute_idx).await.unwrap();
+            assert_eq!({ on_disk.inode.i_size }, 100);
+            assert_eq!(on_disk.inode.i_block, file.inode.i_block);
+
+            // nothing is left at zero, growing again only moves the size and reads back zeros
+            fs.truncate(&mut file, 0).await.unwrap();
+            assert!(!is_used(&fs, direct[0]).await);
+            assert_eq!(free_count(&fs).await, free_before);
+            fs.truncate(&mut file, 5000).await.unwrap();
+            assert_eq!((file.inode.i_size, file.inode.i_blocks), (5000, 0));
+            let mut buf = vec![0xFFu8; 5000];
+            let bytes_read = fs
+                .read(&mut file, &mut buf, &mut HalIOCtx::new())
+                .await
+                .unwrap();
+            assert_eq!(bytes_read, 5000);
+            assert!(buf.iter().all(|&byte| byte == 0));
+        });
+        ram_disk::unregister(guid);
+
+        end_test!();
+    }
+}
@@ -1,6 +1,7 @@
 use crate::log;
 use crate::time;
 use alloc::{boxed::Box, vec::Vec};
+use core::ops::Range;
 
 use crate::{
     drivers::fs::ext2::{
@@ -9,12 +10,25 @@ use crate::{
     hal::fs::{HalFsIOErr, HalIOCtx},
 };
 
+/// the indices of the blocks a write of len bytes at head lands in, everything before the start
+/// is skipped and stays a hole if it was never written
+pub fn touched_blocks(head: usize, len: usize, block_size: u32) -> Range<u32> {
+    if len == 0 {
+        return 0..0;
+    }
+
+    let block_size = block_size as usize;
+    (head / block_size) as u32..(head + len).div_ceil(block_size) as u32
+}
+
 impl Ext2Fs {
     pub async fn allocate_n_blocks(
         &mut self,
         exclude_group_idx: i64,
         remaining_blocks: usize,
     ) -> Result<Vec<AllocatedBlock>, HalFsIOErr> {
+        self.ensure_writable()?;
+
         self.block_allocator
             .allocate_n_blocks(exclude_group_idx, remaining_blocks)
             .await
@@ -25,6 +39,8 @@ impl Ext2Fs {
         group_number: i64,
         num: usize,
     ) -> Result<Vec<AllocatedBlock>, HalFsIOErr> {
+        self.ensure_writable()?;
+
         self.block_allocator
             .allocate_n_blocks_in_group(group_number, num)
             .await
@@ -36,22 +52,20 @@ impl Ext2Fs {
         input: &[u8],
         ctx: &mut HalIOCtx,
         block_idx: u32,
+        is_fresh_block: bool,
         progress: &mut Progress,
     ) -> Result<(), HalFsIOErr> {
         log!("Prepared to write input for block {block_idx}");
         let mut buf: Box<[u8]> = Box::new([0u8; BLOCK_SIZE as usize]);
 
         // if we are not at the start of a block we need to make sure the existing data doesn't
-        // get overwritten
-        if progress.offset != 0 {
+        // get overwritten, a block that was just allocated has nothing worth keeping and the
+        // part before the head is a hole that has to read back as zeros
+        if progress.offset != 0 && !is_fresh_block {
             buf = self.io_handler.read_block(buf, block_idx).await?;
         }
 
         for i in progress.offset..self.super_block.block_size() {
-            if ctx.head as u32 >= inode.i_size {
-                inode.i_size += 1;
-            }
-
             if progress.bytes_written >= input.len() {
                 break;
             }
@@ -61,6 +75,9 @@ impl Ext2Fs {
             ctx.head += 1;
         }
 
+        // writing past the end leaves the skipped range as a hole
+        inode.i_size = inode.i_size.max(ctx.head as u32);
+
         self.io_handler.write_block(buf.clone(), block_idx).await?;
         progress.block_idx += 1;
         progress.offset = 0;
@@ -68,12 +85,16 @@ impl Ext2Fs {
         Ok(())
     }
 
+    /// only the blocks the input lands in get allocated, writing past the end of the file leaves
+    /// the block pointers in between at 0 and reading them back gives zeros
     pub async fn write(
         &mut self,
         victim_inode: &mut InodePlus,
         buf: &[u8],
         ctx: &mut HalIOCtx,
     ) -> Result<usize, HalFsIOErr> {
+        self.ensure_writable()?;
+
         log!("write: input: {:?}", buf);
         let inode = &mut victim_inode.inode;
 
@@ -89,13 +110,22 @@ impl Ext2Fs {
 
         let mut blocks_allocated_count = 0;
 
+        let touched = touched_blocks(ctx.head, buf.len(), self.super_block.block_size());
         let mut iterator = self.create_block_iterator(inode, victim_inode.group_number.into());
-        iterator.skip(progress.block_idx as usize);
-        while progress.bytes_written < buf.len() {
+        iterator.skip(touched.start as usize);
+        for _ in touched {
             let res = iterator.next_set().await?;
+            let is_fresh_block = !res.allocated_blocks.is_empty();
             blocks_allocated_count += res.allocated_blocks.len();
-            self.write_till_next_block(inode, buf, ctx, res.block_idx, &mut progress)
-                .await?;
+            self.write_till_next_block(
+                inode,
+                buf,
+                ctx,
+                res.block_idx,
+                is_fresh_block,
+                &mut progress,
+            )
+            .await?;
         }
 
         let time = time::formats::rtc_to_posix(
@@ -104,8 +134,8 @@ impl Ext2Fs {
                 .expect("Failed to get time"),
         );
         inode.i_mtime = time;
-        inode.i_block = iterator.into_blocks_array();
-        inode.i_blocks += blocks_allocated_count as u32 * self.super_block.block_size();
+        inode.i_block = iterator.finalize().await?;
+        inode.i_blocks += self.blocks_to_i_blocks(blocks_allocated_count as u32);
 
         self.write_inode(victim_inode).await?;
         let buf = self.get_buffer();
@@ -116,3 +146,254 @@ impl Ext2Fs {
         Ok(progress.bytes_written)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::{vec, vec::Vec};
+
+    use super::touched_blocks;
+    use crate::{
+        crypto::guid::Guid,
+        drivers::fs::ext2::{
+            open::ROOT_DIRECTORY_INODE_IDX,
+            read::{IND_BLOCK_ADDR_COUNT, INODE_IND_BLOCK_LIMIT},
+            structs::Ext2Fs,
+        },
+        end_test,
+        hal::{
+            fs::{HalFsIOErr, HalIOCtx},
+            path::Path,
+            ram_disk,
+        },
+        terminal::test::block_on,
+        test_name,
+    };
+
+    fn is_read_only<T>(res: Result<T, HalFsIOErr>) -> bool {
+        matches!(res, Err(HalFsIOErr::ReadOnlyFilesystem))
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn write_on_read_only_mount() {
+        test_name!("ext2 refuses every change on a read-only mount");
+
+        let guid = Guid::from_bytes([0x6a; 16]);
+        block_on(async {
+            let mut fs = Ext2Fs::on_ram_disk(guid, 1).await;
+            let mut root = fs
+                .get_nth_inode(ROOT_DIRECTORY_INODE_IDX as u32)
+                .await
+                .unwrap();
+            let file = fs.create_file(&mut root, "a", 0o644).await.unwrap();
+            let mut file = fs.get_nth_inode(file.absolute_idx).await.unwrap();
+            fs.write(&mut file, b"hello", &mut HalIOCtx::new())
+                .await
+                .unwrap();
+            fs.mkdir(Path::new_appended("/dir"), 0o755).await.unwrap();
+
+            let mut fs = fs.remount_read_only().await;
+            let image = ram_disk::with_disk(guid, |disk| disk.data.clone()).unwrap();
+            let mut root = fs
+                .get_nth_inode(ROOT_DIRECTORY_INODE_IDX as u32)
+                .await
+                .unwrap();
+            let mut file = fs.get_nth_inode(file.absolute_idx).await.unwrap();
+            let before = file.inode.clone();
+
+            let mut ctx = HalIOCtx::new();
+            assert!(is_read_only(fs.write(&mut file, b"bye", &mut ctx).await));
+            assert_eq!(ctx.head, 0);
+            assert!(is_read_only(fs.write_inode(&file).await));
+            assert!(is_read_only(fs.truncate(&mut file, 0).await));
+            assert!(is_read_only(fs.allocate_n_blocks(-1, 1).await));
+            assert!(is_read_only(fs.allocate_n_blocks_in_group(0, 1).await));
+            assert!(is_read_only(fs.allocate_inode(0).await));
+            assert!(is_read_only(fs.free_inode(file.absolute_idx).await));
+            assert!(is_read_only(fs.free_blocks(&mut file).await));
+            assert!(is_read_only(fs.release_inode(&mut file).await));
+            assert!(is_read_only(fs.write_super_block().await));
+
+            assert!(is_read_only(fs.create_file(&mut root, "b", 0o644).await));
+            assert!(is_read_only(
+                fs.create_directory(&mut root, "c", 0o755).await
+            ));
+            assert!(is_read_only(
+                fs.add_dir_entry(&mut root, file.absolute_idx, "d").await
+            ));
+            assert!(is_read_only(
+                fs.mkdir(Path::new_appended("/e"), 0o755).await
+            ));
+            assert!(is_read_only(fs.rmdir(Path::new_appended("/dir")).await));
+            assert!(is_read_only(fs.unlink(&mut root, "a").await));
+            assert!(is_read_only(fs.delete_file(Path::new_appended("/a")).await));
+
+            let path = Path::new_appended("/a");
+            assert!(is_read_only(fs.chmod(&path, 0o600).await));
+            assert!(is_read_only(fs.chown(&path, 1, 1).await));
+            assert!(is_read_only(fs.utime(&path, 1, 1).await));
+
+            // neither the inode in memory nor a single byte of the disk moved
+            assert_eq!(
+                (file.inode.i_size, file.inode.i_blocks, file.inode.i_block),
+                (before.i_size, before.i_blocks, before.i_block)
+            );
+            assert_eq!(
+                (
+                    file.inode.i_mode,
+                    file.inode.i_links_count,
+                    file.inode.i_mtime
+                ),
+                (before.i_mode, before.i_links_count, before.i_mtime)
+            );
+            assert_eq!(
+                ram_disk::with_disk(guid, |disk| disk.data == image),
+                Some(true)
+            );
+        });
+        ram_disk::unregister(guid);
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn ext2_sparse_write() {
+        test_name!("ext2 sparse write");
+
+        const MB: usize = 1024 * 1024;
+
+        // writing at 1MB into an empty file only touches the block the data lands in
+        assert_eq!(touched_blocks(MB, 5, 1024), 1024..1025);
+        assert_eq!(touched_blocks(1000, 100, 1024), 0..2);
+        assert!(touched_blocks(MB, 0, 1024).is_empty());
+
+        let guid = Guid::from_bytes([0x68; 16]);
+        block_on(async {
+            let mut fs = Ext2Fs::on_ram_disk(guid, 1).await;
+            let block_size = fs.super_block.block_size() as usize;
+            let pointers = async |fs: &Ext2Fs, block_idx: u32| -> Vec<u32> {
+                let buf = fs
+                    .io_handler
+                    .read_block(fs.get_buffer(), block_idx)
+                    .await
+                    .unwrap();
+                buf.chunks_exact(4)
+                    .map(|entry| u32::from_le_bytes(entry.try_into().unwrap()))
+                    .collect()
+            };
+
+            let mut root = fs
+                .get_nth_inode(ROOT_DIRECTORY_INODE_IDX as u32)
+                .await
+                .unwrap();
+            let mut file = fs.create_file(&mut root, "sparse", 0o644).await.unwrap();
+            let mut ctx = HalIOCtx { head: MB };
+            assert_eq!(fs.write(&mut file, b"hello", &mut ctx).await.unwrap(), 5);
+
+            let mut file = fs.get_nth_inode(file.absolute_idx).await.unwrap();
+            assert_eq!({ file.inode.i_size }, MB as u32 + 5);
+
+            // block 1024 is reached through the doubly indirect block, none of the pointers
+            // before it was set
+            let i_block = { file.inode.i_block };
+            assert_eq!(i_block[..13], [0; 13]);
+            assert_ne!(i_block[13], 0);
+            assert_eq!(i_block[14], 0);
+
+            let logical = (MB / block_size) as u32 - INODE_IND_BLOCK_LIMIT;
+            let (outer, inner) = (
+                (logical / IND_BLOCK_ADDR_COUNT) as usize,
+                (logical % IND_BLOCK_ADDR_COUNT) as usize,
+            );
+            let double = pointers(&fs, i_block[13]).await;
+            for (idx, &ptr) in double.iter().enumerate() {
+                assert_eq!(ptr != 0, idx == outer);
+            }
+            let ind = pointers(&fs, double[outer]).await;
+            for (idx, &ptr) in ind.iter().enumerate() {
+                assert_eq!(ptr != 0, idx == inner);
+            }
+            let data = fs
+                .io_handler
+                .read_block(fs.get_buffer(), ind[inner])
+                .await
+                .unwrap();
+            assert_eq!(&data[..5], b"hello");
+
+            // the data block and the two indirect blocks above it, nothing for the hole
+            assert_eq!({ file.inode.i_blocks }, fs.blocks_to_i_blocks(3));
+
+            // the hole reads back as zeros right up to the data
+            let mut buf = vec![0xffu8; block_size + 5];
+            let mut ctx = HalIOCtx {
+                head: MB - block_size,
+            };
+            assert_eq!(
+                fs.read(&mut file, &mut buf, &mut ctx).await.unwrap(),
+                block_size + 5
+            );
+            assert!(buf[..block_size].iter().all(|b| *b == 0));
+            assert_eq!(&buf[block_size..], b"hello");
+
+            let mut buf = vec![0xffu8; 4 * block_size];
+            let mut ctx = HalIOCtx::new();
+            assert_eq!(
+                fs.read(&mut file, &mut buf, &mut ctx).await.unwrap(),
+                buf.len()
+            );
+            assert!(buf.iter().all(|b| *b == 0));
+        });
+        ram_disk::unregister(guid);
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn ext2_i_blocks_units() {
+        test_name!("ext2 i_blocks units");
+
+        // 300 blocks run past the 12 direct and 256 singly indirect ones, so besides the data
+        // the file takes the indirect block, the doubly indirect one and one indirect block
+        // below that
+        const DATA_BLOCKS: u32 = 300;
+        const INDIRECT_BLOCKS: u32 = 3;
+
+        let guid = Guid::from_bytes([0x69; 16]);
+        block_on(async {
+            let mut fs = Ext2Fs::on_ram_disk(guid, 1).await;
+            let block_size = fs.super_block.block_size();
+
+            let mut root = fs
+                .get_nth_inode(ROOT_DIRECTORY_INODE_IDX as u32)
+                .await
+                .unwrap();
+            let mut file = fs.create_file(&mut root, "a", 0o644).await.unwrap();
+            let data = vec![0x5au8; (DATA_BLOCKS * block_size) as usize];
+            fs.write(&mut file, &data, &mut HalIOCtx::new())
+                .await
+                .unwrap();
+
+            // i_blocks counts 512 byte sectors, not fs blocks
+            let file = fs.get_nth_inode(file.absolute_idx).await.unwrap();
+            assert_eq!(
+                { file.inode.i_blocks },
+                (DATA_BLOCKS + INDIRECT_BLOCKS) * block_size / 512
+            );
+            assert_eq!(
+                fs.inode_block_count(&file.inode),
+                DATA_BLOCKS + INDIRECT_BLOCKS
+            );
+            assert_eq!(fs.logical_block_count(&file.inode), DATA_BLOCKS);
+        });
+        ram_disk::unregister(guid);
+
+        // the same count with 4KiB blocks is four times as many sectors
+        let mut fs = Ext2Fs::detached(false);
+        fs.super_block.s_log_block_size = 2;
+        assert_eq!(fs.blocks_to_i_blocks(DATA_BLOCKS), DATA_BLOCKS * 8);
+
+        end_test!();
+    }
+}
@@ -1 +1,4 @@
+pub mod devfs;
 pub mod ext2;
+pub mod procfs;
+pub mod tmpfs;
@@ -0,0 +1,336 @@
+use core::fmt::Write;
+
+use alloc::string::String;
+use dvida_serialize::DvSerialize;
+
+use crate::{
+    arch::x86_64::{
+        cpu_info::CpuInfo,
+        handlers::irq::{IrqIndex, irq_count},
+        memory::{PAGE_SIZE, frame_allocator::FRAME_ALLOCATOR},
+        timer::Instant,
+    },
+    drivers::fs::ext2::{EXT2_FT_REG_FILE, EXT2_S_IFDIR, EXT2_S_IFREG},
+    hal::{
+        fs::{DirEnt64, FileStat, HalFsIOErr, HalIOCtx, HalInode, OpenFlags, OpenFlagsValue},
+        path::Path,
+    },
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcFile {
+    MemInfo,
+    Uptime,
+    Interrupts,
+    CpuInfo,
+}
+
+impl ProcFile {
+    pub const ALL: [ProcFile; 4] = [
+        ProcFile::MemInfo,
+        ProcFile::Uptime,
+        ProcFile::Interrupts,
+        ProcFile::CpuInfo,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProcFile::MemInfo => "meminfo",
+            ProcFile::Uptime => "uptime",
+            ProcFile::Interrupts => "interrupts",
+            ProcFile::CpuInfo => "cpuinfo",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|file| file.name() == name)
+    }
+
+    /// inode numbers are fixed, 1 is the directory itself
+    pub fn ino(&self) -> u64 {
+        *self as u64 + 2
+    }
+
+    /// builds the contents from the current kernel state, nothing is cached between reads
+    pub async fn generate(&self) -> String {
+        let mut out = String::new();
+
+        // writing into a String can't fail
+        let _ = match self {
+            ProcFile::MemInfo => match FRAME_ALLOCATOR.get() {
+                Some(allocator) => {
+                    let allocator = allocator.lock().await;
+                    let total = allocator.total_frame_count() * PAGE_SIZE as usize / 1024;
+                    let free = allocator.free_frame_count() * PAGE_SIZE as usize / 1024;
+                    write!(out, "MemTotal: {total} kB\nMemFree: {free} kB\n")
+                }
+                None => write!(out, "MemTotal: 0 kB\nMemFree: 0 kB\n"),
+            },
+            ProcFile::Uptime => {
+                let uptime = Instant::now().since_boot();
+                writeln!(
+                    out,
+                    "{}.{:02}",
+                    uptime.as_secs(),
+                    uptime.subsec_millis() / 10
+                )
+            }
+            ProcFile::Interrupts => IrqIndex::ALL.iter().try_for_each(|irq| {
+                writeln!(
+                    out,
+                    "{:>3}: {:>10} {:?}",
+                    *irq as usize,
+                    irq_count(*irq),
+                    irq
+                )
+            }),
+            ProcFile::CpuInfo => {
+                let info = CpuInfo::read();
+                write!(
+                    out,
+                    "vendor_id\t: {}\ncpu family\t: {}\nmodel\t\t: {}\nmodel name\t: {}\nstepping\t: {}\nflags\t\t: {}\n",
+                    info.vendor,
+                    info.family,
+                    info.model,
+                    info.brand,
+                    info.stepping,
+                    info.features.join(" ")
+                )
+            }
+        };
+
+        out
+    }
+}
+
+/// None is the /proc directory itself
+#[derive(Debug, Clone)]
+pub struct ProcfsInode {
+    pub file: Option<ProcFile>,
+}
+
+/// a read only filesystem whose files are generated from kernel state when read
+#[derive(Debug, Default)]
+pub struct Procfs;
+
+impl Procfs {
+    pub fn new() -> Self {
+        Self
+    }
+
+    pub fn resolve_path(&self, path: &Path) -> Result<ProcfsInode, HalFsIOErr> {
+        let path = path.normalize();
+        let mut components = path.components();
+
+        let file = match (components.next(), components.next()) {
+            (None, _) => None,
+            (Some(name), None) => {
+                Some(ProcFile::from_name(&name).ok_or(HalFsIOErr::NoSuchFileOrDirectory)?)
+            }
+            (Some(_), Some(_)) => return Err(HalFsIOErr::NoSuchFileOrDirectory),
+        };
+
+        Ok(ProcfsInode { file })
+    }
+
+    pub fn open_file(&mut self, path: Path, flags: OpenFlags) -> Result<HalInode, HalFsIOErr> {
+        let inode = self.resolve_path(&path);
+
+        match inode {
+            Ok(_) if flags.flags & OpenFlagsValue::Truncate as i32 != 0 => {
+                Err(HalFsIOErr::ReadOnlyFilesystem)
+            }
+            Ok(inode) => Ok(HalInode::Procfs(inode)),
+            Err(_) if flags.flags & OpenFlagsValue::CreateIfNotExist as i32 != 0 => {
+                Err(HalFsIOErr::ReadOnlyFilesystem)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    pub async fn read(
+        &mut self,
+        inode: &mut ProcfsInode,
+        buf: &mut [u8],
+        ctx: &mut HalIOCtx,
+    ) -> Result<usize, HalFsIOErr> {
+        let Some(file) = inode.file else {
+            return Err(HalFsIOErr::IsDirectory);
+        };
+
+        let content = file.generate().await;
+        let content = content.as_bytes();
+
+        let start = ctx.head.min(content.len());
+        let to_read = buf.len().min(content.len() - start);
+        buf[..to_read].copy_from_slice(&content[start..start + to_read]);
+        ctx.head += to_read;
+
+        Ok(to_read)
+    }
+
+    pub fn write(
+        &mut self,
+        _inode: &mut ProcfsInode,
+        _buf: &[u8],
+        _ctx: &mut HalIOCtx,
+    ) -> Result<usize, HalFsIOErr> {
+        Err(HalFsIOErr::ReadOnlyFilesystem)
+    }
+
+    /// same record format as the other filesystems, the offset is the index of the next file
+    pub fn iter_dir(
+        &mut self,
+        offset: &mut i64,
+        buf: &mut [u8],
+        inode: &mut ProcfsInode,
+    ) -> Result<bool, HalFsIOErr> {
+        if inode.file.is_some() {
+            return Err(HalFsIOErr::NotADirectory);
+        }
+
+        let mut bytes_written = 0;
+        for file in ProcFile::ALL.iter().skip(*offset as usize) {
+            let entry = DirEnt64 {
+                inode_idx: file.ino(),
+                offset: *offset + 1,
+                file_type: EXT2_FT_REG_FILE,
+                name: file.name().into(),
+            };
+
+            if entry.rec_len() + bytes_written >= buf.len() {
+                return Ok(false);
+            }
+
+            bytes_written += entry.serialize(
+                dvida_serialize::Endianness::Little,
+                &mut buf[bytes_written..],
+            )?;
+            *offset += 1;
+        }
+
+        Ok(true)
+    }
+
+    pub async fn file_size(&self, inode: &ProcfsInode) -> Result<u64, HalFsIOErr> {
+        match inode.file {
+            Some(file) => Ok(file.generate().await.len() as u64),
+            None => Err(HalFsIOErr::IsDirectory),
+        }
+    }
+
+    /// the size is that of the content right now, it can change by the next read
+    pub async fn stat(&self, path: &Path) -> Result<FileStat, HalFsIOErr> {
+        let inode = self.resolve_path(path)?;
+
+        Ok(match inode.file {
+            Some(file) => FileStat {
+                ino: file.ino(),
+                size: file.generate().await.len() as u64,
+                mode: (EXT2_S_IFREG | 0o444) as u32,
+                nlink: 1,
+                ..Default::default()
+            },
+            None => FileStat {
+                ino: 1,
+                mode: (EXT2_S_IFDIR | 0o555) as u32,
+                nlink: 2,
+                ..Default::default()
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{end_test, ignore, terminal::test::block_on, test_name};
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn procfs_uptime() {
+        use crate::arch::x86_64::timer::{TSC_TIMER_TICKS_PER_MS, blocking_sleep};
+        use core::{sync::atomic::Ordering, time::Duration};
+
+        test_name!("procfs uptime");
+
+        // without a calibrated tsc there's no uptime to report
+        if TSC_TIMER_TICKS_PER_MS.load(Ordering::Relaxed) == 0 {
+            ignore!();
+        }
+
+        let mut fs = Procfs::new();
+        let HalInode::Procfs(mut inode) = fs
+            .open_file(Path::new_appended("/uptime"), OpenFlags::default())
+            .expect("Failed to open")
+        else {
+            panic!("Not a procfs inode");
+        };
+
+        // in hundredths of a second
+        let mut read_uptime = || {
+            let mut ctx = HalIOCtx::new();
+            let mut buf = [0u8; 64];
+            let len = block_on(fs.read(&mut inode, &mut buf, &mut ctx)).expect("Failed to read");
+
+            let text = core::str::from_utf8(&buf[..len]).expect("Not utf8");
+            let (secs, centis) = text.trim().split_once('.').expect("Uptime has no fraction");
+            let secs: u64 = secs.parse().expect("Bad seconds");
+            let centis: u64 = centis.parse().expect("Bad fraction");
+            secs * 100 + centis
+        };
+
+        let first = read_uptime();
+        // the tsc has been counting since before the tests started
+        assert!(first > 0);
+        assert!(first as u128 <= Instant::now().since_boot().as_millis() / 10);
+
+        blocking_sleep(Duration::from_millis(20));
+        let second = read_uptime();
+        assert!(second > first);
+        assert!(second as u128 <= Instant::now().since_boot().as_millis() / 10);
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn procfs_read_only() {
+        test_name!("procfs read only");
+
+        let mut fs = Procfs::new();
+        let create = OpenFlags {
+            flags: OpenFlagsValue::CreateIfNotExist as i32,
+            perms: Some(0o644),
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            fs.open_file(Path::new_appended("/new"), create),
+            Err(HalFsIOErr::ReadOnlyFilesystem)
+        ));
+        assert!(matches!(
+            fs.open_file(Path::new_appended("/missing"), OpenFlags::default()),
+            Err(HalFsIOErr::NoSuchFileOrDirectory)
+        ));
+
+        let mut inode = ProcfsInode {
+            file: Some(ProcFile::Interrupts),
+        };
+        assert!(matches!(
+            fs.write(&mut inode, b"0", &mut HalIOCtx::new()),
+            Err(HalFsIOErr::ReadOnlyFilesystem)
+        ));
+
+        let mut root = ProcfsInode { file: None };
+        let mut offset = 0;
+        let mut buf = [0u8; 256];
+        assert!(
+            fs.iter_dir(&mut offset, &mut buf, &mut root)
+                .expect("Failed to list")
+        );
+        assert_eq!(offset, ProcFile::ALL.len() as i64);
+
+        end_test!();
+    }
+}
@@ -0,0 +1,622 @@
+use alloc::{collections::btree_map::BTreeMap, string::String, vec::Vec};
+use dvida_serialize::DvSerialize;
+
+use crate::{
+    drivers::fs::ext2::{EXT2_FT_DIR, EXT2_FT_REG_FILE, EXT2_S_IFDIR, EXT2_S_IFREG},
+    hal::{
+        fs::{DirEnt64, FileStat, HalFsIOErr, HalIOCtx, HalInode, OpenFlags, OpenFlagsValue},
+        path::Path,
+    },
+    utils::tar::{TarEntryKind, TarReader},
+};
+
+pub const TMPFS_ROOT_INO: u64 = 1;
+
+#[derive(Debug)]
+pub enum TmpfsNodeKind {
+    File(Vec<u8>),
+    /// name to inode number
+    Directory(BTreeMap<String, u64>),
+}
+
+/// timestamps aren't kept, tmpfs is meant to be usable before anything else is up
+#[derive(Debug)]
+pub struct TmpfsNode {
+    pub kind: TmpfsNodeKind,
+    /// permission bits only, the type comes from the kind
+    pub perms: u16,
+    pub nlink: u32,
+}
+
+impl TmpfsNode {
+    pub fn is_directory(&self) -> bool {
+        matches!(self.kind, TmpfsNodeKind::Directory(_))
+    }
+
+    pub fn size(&self) -> u64 {
+        match &self.kind {
+            TmpfsNodeKind::File(data) => data.len() as u64,
+            TmpfsNodeKind::Directory(entries) => entries.len() as u64,
+        }
+    }
+}
+
+/// handle to an opened tmpfs node
+#[derive(Debug, Clone)]
+pub struct TmpfsInode {
+    pub ino: u64,
+}
+
+/// an in memory filesystem, every node lives on the heap and is gone once unmounted
+#[derive(Debug)]
+pub struct Tmpfs {
+    pub nodes: BTreeMap<u64, TmpfsNode>,
+    pub next_ino: u64,
+}
+
+impl Default for Tmpfs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Tmpfs {
+    pub fn new() -> Self {
+        let mut nodes = BTreeMap::new();
+        nodes.insert(
+            TMPFS_ROOT_INO,
+            TmpfsNode {
+                kind: TmpfsNodeKind::Directory(BTreeMap::new()),
+                perms: 0o755,
+                nlink: 2,
+            },
+        );
+
+        Self {
+            nodes,
+            next_ino: TMPFS_ROOT_INO + 1,
+        }
+    }
+
+    fn node(&self, ino: u64) -> Result<&TmpfsNode, HalFsIOErr> {
+        self.nodes
+            .get(&ino)
+            .ok_or(HalFsIOErr::NoSuchFileOrDirectory)
+    }
+
+    fn node_mut(&mut self, ino: u64) -> Result<&mut TmpfsNode, HalFsIOErr> {
+        self.nodes
+            .get_mut(&ino)
+            .ok_or(HalFsIOErr::NoSuchFileOrDirectory)
+    }
+
+    fn entries(&self, ino: u64) -> Result<&BTreeMap<String, u64>, HalFsIOErr> {
+        match &self.node(ino)?.kind {
+            TmpfsNodeKind::Directory(entries) => Ok(entries),
+            TmpfsNodeKind::File(_) => Err(HalFsIOErr::NotADirectory),
+        }
+    }
+
+    fn entries_mut(&mut self, ino: u64) -> Result<&mut BTreeMap<String, u64>, HalFsIOErr> {
+        match &mut self.node_mut(ino)?.kind {
+            TmpfsNodeKind::Directory(entries) => Ok(entries),
+            TmpfsNodeKind::File(_) => Err(HalFsIOErr::NotADirectory),
+        }
+    }
+
+    /// same contract as the ext2 one: (parent directory, the node itself if it exists)
+    pub fn walk_path(&self, path: &Path) -> Result<(u64, Option<u64>), HalFsIOErr> {
+        let mut directory = TMPFS_ROOT_INO;
+
+        let mut it = path.normalize().components().peekable();
+        while let Some(component) = it.next() {
+            let found = self.entries(directory)?.get(&component).copied();
+
+            if it.peek().is_none() {
+                return Ok((directory, found));
+            }
+
+            directory = found.ok_or(HalFsIOErr::NoSuchFileOrDirectory)?;
+        }
+
+        Ok((directory, None))
+    }
+
+    pub fn resolve_path(&self, path: &Path) -> Result<u64, HalFsIOErr> {
+        match self.walk_path(path)? {
+            (_, Some(ino)) => Ok(ino),
+            (directory, None) if path.normalize().components().next().is_none() => Ok(directory),
+            _ => Err(HalFsIOErr::NoSuchFileOrDirectory),
+        }
+    }
+
+    fn create_node(
+        &mut self,
+        path: &Path,
+        kind: TmpfsNodeKind,
+        perms: i32,
+    ) -> Result<u64, HalFsIOErr> {
+        let (directory, existing) = self.walk_path(path)?;
+        if existing.is_some() {
+            return Err(HalFsIOErr::FileExists);
+        }
+
+        let name = path.file_name().ok_or(HalFsIOErr::BadPath)?;
+        let is_dir = matches!(kind, TmpfsNodeKind::Directory(_));
+
+        let ino = self.next_ino;
+        self.next_ino += 1;
+
+        self.entries_mut(directory)?.insert(name, ino);
+        self.nodes.insert(
+            ino,
+            TmpfsNode {
+                kind,
+                perms: perms as u16 & 0o7777,
+                nlink: if is_dir { 2 } else { 1 },
+            },
+        );
+
+        if is_dir {
+            self.node_mut(directory)?.nlink += 1;
+        }
+
+        Ok(ino)
+    }
+
+    /// creates every directory along the path that doesn't exist yet, returns the last one
+    pub fn mkdir_all(&mut self, path: &Path, perms: i32) -> Result<u64, HalFsIOErr> {
+        let mut current = Path::new_appended("/");
+        let mut ino = TMPFS_ROOT_INO;
+
+        for component in path.normalize().components() {
+            current = current.join(&component);
+            ino = match self.walk_path(&current)? {
+                (_, Some(ino)) => ino,
+                (_, None) => {
+                    self.create_node(&current, TmpfsNodeKind::Directory(BTreeMap::new()), perms)?
+                }
+            };
+        }
+
+        if !self.node(ino)?.is_directory() {
+            return Err(HalFsIOErr::NotADirectory);
+        }
+
+        Ok(ino)
+    }
+
+    /// builds a tmpfs out of a tar archive, parent directories missing from the archive are
+    /// made with 0o755 and a later entry for the same file replaces the earlier one
+    pub fn from_tar(archive: &[u8]) -> Result<Self, HalFsIOErr> {
+        let mut fs = Self::new();
+
+        for entry in TarReader::new(archive) {
+            let entry = entry.map_err(|_| HalFsIOErr::Corrupted)?;
+            let path = Path::new_appended(&entry.name).normalize();
+            let perms = entry.mode as u16 & 0o7777;
+
+            match entry.kind {
+                TarEntryKind::Directory => {
+                    let ino = fs.mkdir_all(&path, 0o755)?;
+                    fs.node_mut(ino)?.perms = perms;
+                }
+                TarEntryKind::File => {
+                    if let Some(parent) = path.parent() {
+                        fs.mkdir_all(&parent, 0o755)?;
+                    }
+
+                    match fs.walk_path(&path)? {
+                        (_, Some(ino)) => {
+                            let node = fs.node_mut(ino)?;
+                            let TmpfsNodeKind::File(data) = &mut node.kind else {
+                                return Err(HalFsIOErr::IsDirectory);
+                            };
+                            *data = entry.data.to_vec();
+                            node.perms = perms;
+                        }
+                        (_, None) => {
+                            fs.create_node(
+                                &path,
+                                TmpfsNodeKind::File(entry.data.to_vec()),
+                                perms as i32,
+                            )?;
+                        }
+                    }
+                }
+                // tmpfs has no links or device nodes
+                _ => {}
+            }
+        }
+
+        Ok(fs)
+    }
+
+    pub fn open_file(&mut self, path: Path, flags: OpenFlags) -> Result<HalInode, HalFsIOErr> {
+        let (_, file) = self.walk_path(&path)?;
+
+        let ino = match file {
+            Some(_) if flags.flags & OpenFlagsValue::ErrorIfCreateFileExists as i32 != 0 => {
+                return Err(HalFsIOErr::FileExists);
+            }
+            Some(ino) => ino,
+            None if flags.flags & OpenFlagsValue::CreateIfNotExist as i32 != 0 => self
+                .create_node(
+                    &path,
+                    TmpfsNodeKind::File(Vec::new()),
+                    flags.perms.ok_or(HalFsIOErr::NoPermsProvided)?,
+                )?,
+            None => return Err(HalFsIOErr::NoSuchFileOrDirectory),
+        };
+
+        if flags.flags & OpenFlagsValue::Truncate as i32 != 0
+            && let TmpfsNodeKind::File(data) = &mut self.node_mut(ino)?.kind
+        {
+            data.clear();
+        }
+
+        Ok(HalInode::Tmpfs(TmpfsInode { ino }))
+    }
+
+    pub fn read(
+        &mut self,
+        inode: &mut TmpfsInode,
+        buf: &mut [u8],
+        ctx: &mut HalIOCtx,
+    ) -> Result<usize, HalFsIOErr> {
+        let TmpfsNodeKind::File(data) = &self.node(inode.ino)?.kind else {
+            return Err(HalFsIOErr::IsDirectory);
+        };
+
+        let start = ctx.head.min(data.len());
+        let to_read = buf.len().min(data.len() - start);
+        buf[..to_read].copy_from_slice(&data[start..start + to_read]);
+        ctx.head += to_read;
+
+        Ok(to_read)
+    }
+
+    pub fn write(
+        &mut self,
+        inode: &mut TmpfsInode,
+        buf: &[u8],
+        ctx: &mut HalIOCtx,
+    ) -> Result<usize, HalFsIOErr> {
+        let TmpfsNodeKind::File(data) = &mut self.node_mut(inode.ino)?.kind else {
+            return Err(HalFsIOErr::IsDirectory);
+        };
+
+        // writing past the end leaves a zero filled hole, same as on disk
+        let end = ctx.head + buf.len();
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+
+        data[ctx.head..end].copy_from_slice(buf);
+        ctx.head = end;
+
+        Ok(buf.len())
+    }
+
+    /// fills buf with DirEnt64 records starting at the offset-th entry, returns true once every
+    /// entry has been written
+    pub fn iter_dir(
+        &mut self,
+        offset: &mut i64,
+        buf: &mut [u8],
+        inode: &mut TmpfsInode,
+    ) -> Result<bool, HalFsIOErr> {
+        let entries = self.entries(inode.ino)?;
+
+        let mut bytes_written = 0;
+        for (name, ino) in entries.iter().skip(*offset as usize) {
+            let entry = DirEnt64 {
+                inode_idx: *ino,
+                offset: *offset + 1,
+                file_type: if self.node(*ino)?.is_directory() {
+                    EXT2_FT_DIR
+                } else {
+                    EXT2_FT_REG_FILE
+                },
+                name: name.clone(),
+            };
+
+            if entry.rec_len() + bytes_written >= buf.len() {
+                return Ok(false);
+            }
+
+            bytes_written += entry.serialize(
+                dvida_serialize::Endianness::Little,
+                &mut buf[bytes_written..],
+            )?;
+            *offset += 1;
+        }
+
+        Ok(true)
+    }
+
+    pub fn mkdir(&mut self, path: Path, perms: i32) -> Result<TmpfsInode, HalFsIOErr> {
+        let ino = self.create_node(&path, TmpfsNodeKind::Directory(BTreeMap::new()), perms)?;
+
+        Ok(TmpfsInode { ino })
+    }
+
+    pub fn rmdir(&mut self, path: Path) -> Result<(), HalFsIOErr> {
+        let (directory, file) = self.walk_path(&path)?;
+        let ino = file.ok_or(HalFsIOErr::NoSuchFileOrDirectory)?;
+
+        if !self.entries(ino)?.is_empty() {
+            return Err(HalFsIOErr::DirectoryNotEmpty);
+        }
+
+        self.entries_mut(directory)?
+            .retain(|_, child| *child != ino);
+        self.nodes.remove(&ino);
+        self.node_mut(directory)?.nlink -= 1;
+
+        Ok(())
+    }
+
+    /// removes the name, the node goes away with its last link
+    pub fn delete_file(&mut self, path: Path) -> Result<(), HalFsIOErr> {
+        let (directory, file) = self.walk_path(&path)?;
+        let ino = file.ok_or(HalFsIOErr::NoSuchFileOrDirectory)?;
+
+        if self.node(ino)?.is_directory() {
+            return Err(HalFsIOErr::IsDirectory);
+        }
+
+        self.entries_mut(directory)?
+            .remove(&path.file_name().ok_or(HalFsIOErr::BadPath)?);
+
+        let node = self.node_mut(ino)?;
+        node.nlink -= 1;
+        if node.nlink == 0 {
+            self.nodes.remove(&ino);
+        }
+
+        Ok(())
+    }
+
+    pub fn file_size(&self, inode: &TmpfsInode) -> Result<u64, HalFsIOErr> {
+        Ok(self.node(inode.ino)?.size())
+    }
+
+    pub fn is_directory(&self, inode: &TmpfsInode) -> Result<bool, HalFsIOErr> {
+        Ok(self.node(inode.ino)?.is_directory())
+    }
+
+    pub fn stat(&self, path: &Path) -> Result<FileStat, HalFsIOErr> {
+        let ino = self.resolve_path(path)?;
+        let node = self.node(ino)?;
+
+        let file_type = if node.is_directory() {
+            EXT2_S_IFDIR
+        } else {
+            EXT2_S_IFREG
+        };
+
+        Ok(FileStat {
+            ino,
+            size: node.size(),
+            mode: (file_type | node.perms) as u32,
+            nlink: node.nlink,
+            blocks: node.size().div_ceil(512),
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{vec, vec::Vec};
+
+    use super::*;
+    use crate::{
+        end_test,
+        hal::fs::HalFs,
+        terminal::test::block_on,
+        test_name,
+        utils::tar::{TAR_BLOCK_SIZE, tests::push_entry},
+    };
+
+    fn create_flags() -> OpenFlags {
+        OpenFlags {
+            flags: OpenFlagsValue::CreateIfNotExist as i32,
+            perms: Some(0o644),
+            ..Default::default()
+        }
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn tmpfs_files() {
+        test_name!("tmpfs files");
+
+        let mut fs = Tmpfs::new();
+        let path = Path::new_appended("/notes.txt");
+
+        let HalInode::Tmpfs(mut inode) = fs
+            .open_file(path.clone(), create_flags())
+            .expect("Failed to create")
+        else {
+            panic!("Not a tmpfs inode");
+        };
+
+        let mut ctx = HalIOCtx::new();
+        assert_eq!(
+            fs.write(&mut inode, b"hello tmpfs", &mut ctx).ok(),
+            Some(11)
+        );
+
+        let mut ctx = HalIOCtx::new();
+        let mut buf = [0u8; 32];
+        assert_eq!(fs.read(&mut inode, &mut buf, &mut ctx).ok(), Some(11));
+        assert_eq!(&buf[..11], b"hello tmpfs");
+        // at the end
+        assert_eq!(fs.read(&mut inode, &mut buf, &mut ctx).ok(), Some(0));
+
+        let stat = fs.stat(&path).expect("Failed to stat");
+        assert_eq!(stat.size, 11);
+        assert_eq!(stat.mode, (EXT2_S_IFREG | 0o644) as u32);
+        assert_eq!(stat.nlink, 1);
+
+        assert!(matches!(
+            fs.open_file(Path::new_appended("/missing"), OpenFlags::default()),
+            Err(HalFsIOErr::NoSuchFileOrDirectory)
+        ));
+
+        fs.delete_file(path.clone()).expect("Failed to unlink");
+        assert!(matches!(
+            fs.stat(&path),
+            Err(HalFsIOErr::NoSuchFileOrDirectory)
+        ));
+        assert_eq!(fs.nodes.len(), 1);
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn tmpfs_directories() {
+        test_name!("tmpfs directories");
+
+        let mut fs = Tmpfs::new();
+        let mut dir = fs
+            .mkdir(Path::new_appended("/a"), 0o755)
+            .expect("Failed to mkdir");
+        fs.mkdir(Path::new_appended("/a/b"), 0o700)
+            .expect("Failed to mkdir");
+        fs.open_file(Path::new_appended("/a/c"), create_flags())
+            .expect("Failed to create");
+
+        assert!(matches!(
+            fs.mkdir(Path::new_appended("/a/b"), 0o700),
+            Err(HalFsIOErr::FileExists)
+        ));
+        assert!(matches!(
+            fs.mkdir(Path::new_appended("/x/y"), 0o700),
+            Err(HalFsIOErr::NoSuchFileOrDirectory)
+        ));
+
+        let mut offset = 0;
+        let mut buf = vec![0u8; 256];
+        assert!(
+            fs.iter_dir(&mut offset, &mut buf, &mut dir)
+                .expect("Failed to read dir")
+        );
+        assert_eq!(offset, 2);
+
+        // first record is "b", names are sorted
+        let first = DirEnt64 {
+            inode_idx: 0,
+            offset: 0,
+            file_type: 0,
+            name: "b".into(),
+        };
+        assert_eq!(buf[18], EXT2_FT_DIR);
+        assert_eq!(buf[19], b'b');
+        assert_eq!(buf[first.rec_len() + 18], EXT2_FT_REG_FILE);
+        assert_eq!(buf[first.rec_len() + 19], b'c');
+
+        assert!(matches!(
+            fs.rmdir(Path::new_appended("/a")),
+            Err(HalFsIOErr::DirectoryNotEmpty)
+        ));
+        fs.rmdir(Path::new_appended("/a/b"))
+            .expect("Failed to rmdir");
+        assert_eq!(
+            fs.stat(&Path::new_appended("/a"))
+                .expect("Failed to stat")
+                .nlink,
+            2
+        );
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn tmpfs_through_hal() {
+        test_name!("tmpfs through hal");
+
+        let mut fs = HalFs::Tmpfs(Tmpfs::new());
+
+        let mut inode = block_on(fs.open_file(Path::new_appended("/f"), create_flags()))
+            .expect("Failed to create");
+
+        let mut ctx = HalIOCtx::new();
+        assert_eq!(
+            block_on(fs.write(&mut inode, b"vfs", &mut ctx)).ok(),
+            Some(3)
+        );
+
+        let mut ctx = HalIOCtx::new();
+        let mut buf = [0u8; 3];
+        assert_eq!(
+            block_on(fs.read(&mut inode, &mut buf, &mut ctx)).ok(),
+            Some(3)
+        );
+        assert_eq!(&buf, b"vfs");
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn tmpfs_from_tar() {
+        test_name!("tmpfs from a tar archive");
+
+        let mut archive = Vec::new();
+        push_entry(&mut archive, "./", b'5', 0o755, &[]);
+        push_entry(&mut archive, "./etc/", b'5', 0o750, &[]);
+        push_entry(&mut archive, "./etc/motd", b'0', 0o644, b"welcome\n");
+        // the parent directories aren't in the archive
+        push_entry(&mut archive, "usr/share/doc/readme", b'0', 0o600, b"readme");
+        push_entry(&mut archive, "etc/motd", b'0', 0o644, b"replaced\n");
+        push_entry(&mut archive, "dev/null", b'3', 0o666, &[]);
+        archive.extend_from_slice(&[0u8; TAR_BLOCK_SIZE * 2]);
+
+        let mut fs = Tmpfs::from_tar(&archive).expect("Failed to unpack");
+
+        let HalInode::Tmpfs(mut inode) = fs
+            .open_file(Path::new_appended("/etc/motd"), OpenFlags::default())
+            .expect("Failed to open")
+        else {
+            panic!("Not a tmpfs inode");
+        };
+        let mut buf = [0u8; 32];
+        let len = fs
+            .read(&mut inode, &mut buf, &mut HalIOCtx::new())
+            .expect("Failed to read");
+        assert_eq!(&buf[..len], b"replaced\n");
+
+        let stat = fs
+            .stat(&Path::new_appended("/etc"))
+            .expect("Failed to stat");
+        assert_eq!(stat.mode, (EXT2_S_IFDIR | 0o750) as u32);
+        let stat = fs
+            .stat(&Path::new_appended("/usr/share/doc"))
+            .expect("Failed to stat");
+        assert_eq!(stat.mode, (EXT2_S_IFDIR | 0o755) as u32);
+        assert_eq!(
+            fs.stat(&Path::new_appended("/usr/share/doc/readme"))
+                .expect("Failed to stat")
+                .size,
+            6
+        );
+
+        // the device node is skipped
+        assert!(matches!(
+            fs.stat(&Path::new_appended("/dev/null")),
+            Err(HalFsIOErr::NoSuchFileOrDirectory)
+        ));
+
+        assert!(matches!(
+            Tmpfs::from_tar(&archive[..100]),
+            Err(HalFsIOErr::Corrupted)
+        ));
+
+        end_test!();
+    }
+}
@@ -6,10 +6,11 @@ use limine::mp::Cpu;
 use x86_64::instructions::interrupts::without_interrupts;
 
 use super::sync::spin::SpinMutex as Mutex;
+use super::sync::spsc::cell::{SpscCellGetFuture, spsc_cells};
 use core::arch::asm;
 use core::future::Future;
 use core::pin::Pin;
-use core::sync::atomic::AtomicU64;
+use core::sync::atomic::{AtomicU64, Ordering};
 use core::task::{Context, Poll, Waker};
 
 #[derive(Debug, Clone, Copy, Ord, PartialEq, Eq, PartialOrd)]
@@ -28,14 +29,46 @@ impl Task {
     }
 }
 
+/// counters shared by the spawner, every core's context and the wakers
+#[derive(Debug, Default)]
+pub struct ExecutorStats {
+    pub tasks_spawned: AtomicU64,
+    pub tasks_completed: AtomicU64,
+    pub polls: AtomicU64,
+    pub wakeups: AtomicU64,
+    /// how many times a core halted because its queue was empty
+    pub idle_ticks: AtomicU64,
+}
+
+impl ExecutorStats {
+    fn bump(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// a snapshot of the executor counters, polls growing much faster than completed tasks while the
+/// queues stay full means something is spinning instead of parking
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExecutorMetrics {
+    pub tasks_spawned: u64,
+    pub tasks_completed: u64,
+    pub polls: u64,
+    pub wakeups: u64,
+    /// tasks waiting to be polled across all cores
+    pub ready_queue_depth: usize,
+    pub idle_ticks: u64,
+}
+
 #[derive(Debug, Clone)]
 pub struct TaskWaker {
     pub id: TaskID,
     pub tasks: Arc<Mutex<VecDeque<TaskID>>>,
+    pub stats: Arc<ExecutorStats>,
 }
 
 impl Wake for TaskWaker {
     fn wake(self: Arc<Self>) {
+        ExecutorStats::bump(&self.stats.wakeups);
         self.tasks.lock().push_back(self.id);
     }
 }
@@ -44,26 +77,33 @@ impl Wake for TaskWaker {
 pub struct Spawner {
     pub counter: Arc<AtomicU64>,
     pub contexts: Arc<BTreeMap<u32, ExecutorContext>>,
+    pub stats: Arc<ExecutorStats>,
 }
 
-impl Spawner {
-    pub fn spawn(&self, future: impl Future<Output = ()> + 'static + Send) {
-        let future = Box::pin(future);
+/// resolves to what the spawned future returned. Dropping it or calling `detach` lets the task
+/// run on without anyone waiting for it
+pub struct JoinHandle<T> {
+    output: SpscCellGetFuture<T>,
+}
 
-        // Get ID and increment counter atomically, then release lock
-        let id = {
-            let id = TaskID(self.counter.load(core::sync::atomic::Ordering::SeqCst));
+impl<T> JoinHandle<T> {
+    /// for tasks nobody is going to wait for
+    pub fn detach(self) {}
+}
 
-            if self.counter.load(core::sync::atomic::Ordering::SeqCst) == u64::MAX {
-                self.counter.swap(0, core::sync::atomic::Ordering::AcqRel);
-            } else {
-                self.counter
-                    .swap(id.0 + 1, core::sync::atomic::Ordering::AcqRel);
-            }
+impl<T> Future for JoinHandle<T> {
+    type Output = T;
 
-            id // Lock is dropped here
-        };
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        without_interrupts(|| Pin::new(&mut self.output).poll(cx))
+    }
+}
 
+impl Spawner {
+    pub fn spawn<T: Send + 'static>(
+        &self,
+        future: impl Future<Output = T> + 'static + Send,
+    ) -> JoinHandle<T> {
         // load balancing
         let queue_id = *self
             .contexts
@@ -76,6 +116,35 @@ impl Spawner {
             .expect("No context")
             .0;
 
+        self.spawn_on(queue_id, future)
+    }
+
+    /// pins the task to the given core, for tasks that touch that core's scheduler context
+    pub fn spawn_on<T: Send + 'static>(
+        &self,
+        queue_id: u32,
+        future: impl Future<Output = T> + 'static + Send,
+    ) -> JoinHandle<T> {
+        let (getter, setter) = spsc_cells();
+        let future = Box::pin(async move {
+            let output = future.await;
+            without_interrupts(|| setter.set(output));
+        });
+
+        // Get ID and increment counter atomically, then release lock
+        let id = {
+            let id = TaskID(self.counter.load(core::sync::atomic::Ordering::SeqCst));
+
+            if self.counter.load(core::sync::atomic::Ordering::SeqCst) == u64::MAX {
+                self.counter.swap(0, core::sync::atomic::Ordering::AcqRel);
+            } else {
+                self.counter
+                    .swap(id.0 + 1, core::sync::atomic::Ordering::AcqRel);
+            }
+
+            id // Lock is dropped here
+        };
+
         let task = Task {
             id,
             future,
@@ -97,6 +166,12 @@ impl Spawner {
                 .lock()
                 .insert(id, Arc::new(Mutex::new(task)));
         });
+
+        ExecutorStats::bump(&self.stats.tasks_spawned);
+
+        JoinHandle {
+            output: getter.get(),
+        }
     }
 }
 
@@ -105,6 +180,7 @@ pub struct ExecutorContext {
     pub tasks: Arc<Mutex<VecDeque<TaskID>>>,
     pub tasks_map: Arc<Mutex<BTreeMap<TaskID, Arc<Mutex<Task>>>>>,
     pub wakers: Arc<Mutex<BTreeMap<TaskID, Arc<TaskWaker>>>>,
+    pub stats: Arc<ExecutorStats>,
 }
 
 impl ExecutorContext {
@@ -120,56 +196,67 @@ impl ExecutorContext {
                 if !is_empty {
                     break;
                 }
+                ExecutorStats::bump(&self.stats.idle_ticks);
                 unsafe {
                     asm!("hlt");
                 }
             }
 
-            let id = match without_interrupts(|| self.tasks.lock().pop_front()) {
-                Some(i) => i,
-                None => continue,
-            };
-
-            let mut task = None;
-
-            without_interrupts(|| {
-                task = self
-                    .tasks_map
-                    .lock()
-                    .get_mut(&id)
-                    .map_or(None, |v| Some(v.clone()));
-            });
-
-            let task = match task {
-                Some(t) => t,
-                None => continue,
-            };
-
-            let waker = without_interrupts(|| {
-                self.wakers
-                    .lock()
-                    .entry(id)
-                    .or_insert_with(|| {
-                        Arc::new(TaskWaker {
-                            id,
-                            tasks: self.tasks.clone(),
-                        })
+            self.poll_next();
+        }
+    }
+
+    /// polls the task at the front of the queue, returns false if there was nothing to poll
+    pub fn poll_next(&self) -> bool {
+        let id = match without_interrupts(|| self.tasks.lock().pop_front()) {
+            Some(i) => i,
+            None => return false,
+        };
+
+        let mut task = None;
+
+        without_interrupts(|| {
+            task = self
+                .tasks_map
+                .lock()
+                .get_mut(&id)
+                .map_or(None, |v| Some(v.clone()));
+        });
+
+        let task = match task {
+            Some(t) => t,
+            None => return true,
+        };
+
+        let waker = without_interrupts(|| {
+            self.wakers
+                .lock()
+                .entry(id)
+                .or_insert_with(|| {
+                    Arc::new(TaskWaker {
+                        id,
+                        tasks: self.tasks.clone(),
+                        stats: self.stats.clone(),
                     })
-                    .clone()
-            });
+                })
+                .clone()
+        });
 
-            let waker = Waker::from(waker);
+        let waker = Waker::from(waker);
 
-            let mut ctx = Context::from_waker(&waker);
-            match task.lock().poll(&mut ctx) {
-                Poll::Ready(_) => {
-                    // the task is finished, remove it
-                    self.tasks_map.lock().remove(&id);
-                    self.wakers.lock().remove(&id);
-                }
-                Poll::Pending => {}
+        let mut ctx = Context::from_waker(&waker);
+        ExecutorStats::bump(&self.stats.polls);
+        match task.lock().poll(&mut ctx) {
+            Poll::Ready(_) => {
+                // the task is finished, remove it
+                self.tasks_map.lock().remove(&id);
+                self.wakers.lock().remove(&id);
+                ExecutorStats::bump(&self.stats.tasks_completed);
             }
+            Poll::Pending => {}
         }
+
+        true
     }
 }
 
@@ -177,6 +264,7 @@ impl ExecutorContext {
 pub struct Executor {
     pub counter: Arc<AtomicU64>,
     pub contexts: Arc<BTreeMap<u32, ExecutorContext>>,
+    pub stats: Arc<ExecutorStats>,
 }
 
 impl Executor {
@@ -184,16 +272,24 @@ impl Executor {
         Spawner {
             counter: self.counter.clone(),
             contexts: self.contexts.clone(),
+            stats: self.stats.clone(),
         }
     }
 
     pub fn new(cpus: &[&Cpu]) -> Self {
+        Self::with_queues(cpus.iter().map(|cpu| cpu.id))
+    }
+
+    /// one run queue per id, the ids are the cores' lapic ids
+    pub fn with_queues(queue_ids: impl IntoIterator<Item = u32>) -> Self {
+        let stats = Arc::new(ExecutorStats::default());
         let mut contexts = BTreeMap::new();
 
-        for cpu in cpus.iter() {
+        for queue_id in queue_ids {
             contexts.insert(
-                cpu.id,
+                queue_id,
                 ExecutorContext {
+                    stats: stats.clone(),
                     ..Default::default()
                 },
             );
@@ -202,6 +298,88 @@ impl Executor {
         Executor {
             counter: Arc::new(0.into()),
             contexts: contexts.into(),
+            stats,
+        }
+    }
+
+    pub fn metrics(&self) -> ExecutorMetrics {
+        let ready_queue_depth = without_interrupts(|| {
+            self.contexts
+                .values()
+                .map(|context| context.tasks.lock().len())
+                .sum()
+        });
+
+        ExecutorMetrics {
+            tasks_spawned: self.stats.tasks_spawned.load(Ordering::Relaxed),
+            tasks_completed: self.stats.tasks_completed.load(Ordering::Relaxed),
+            polls: self.stats.polls.load(Ordering::Relaxed),
+            wakeups: self.stats.wakeups.load(Ordering::Relaxed),
+            ready_queue_depth,
+            idle_ticks: self.stats.idle_ticks.load(Ordering::Relaxed),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Executor;
+    use crate::{ejcineque::futures::yield_now, end_test, terminal::test::block_on, test_name};
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn executor_metrics() {
+        test_name!("executor metrics");
+
+        let executor = Executor::with_queues([0]);
+        let context = executor.contexts.get(&0).expect("No context").clone();
+        let spawner = executor.spawner();
+
+        spawner.spawn(async {});
+        spawner.spawn(async {
+            yield_now().await;
+        });
+
+        let metrics = executor.metrics();
+        assert_eq!(metrics.tasks_spawned, 2);
+        assert_eq!(metrics.ready_queue_depth, 2);
+        assert_eq!(metrics.polls, 0);
+
+        while context.poll_next() {}
+
+        // the yielding task gets polled twice and woke itself once in between
+        let metrics = executor.metrics();
+        assert_eq!(metrics.tasks_completed, 2);
+        assert_eq!(metrics.polls, 3);
+        assert_eq!(metrics.wakeups, 1);
+        assert_eq!(metrics.ready_queue_depth, 0);
+        assert_eq!(metrics.idle_ticks, 0);
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn join_handle_output() {
+        test_name!("awaiting a join handle gives the task's output");
+
+        let executor = Executor::with_queues([0]);
+        let context = executor.contexts.get(&0).expect("No context").clone();
+        let spawner = executor.spawner();
+
+        let answer = spawner.spawn(async {
+            yield_now().await;
+            42
+        });
+        // another task waits for it like kernel_main would
+        let doubled = spawner.spawn(async move { answer.await * 2 });
+        spawner.spawn(async { 7 }).detach();
+
+        while context.poll_next() {}
+
+        assert_eq!(block_on(doubled), 84);
+        assert_eq!(executor.metrics().tasks_completed, 3);
+
+        end_test!();
+    }
+}
@@ -2,6 +2,7 @@ use core::task::Poll;
 
 pub mod multi_race;
 pub mod race;
+pub mod select;
 
 pub struct YieldFuture {
     yielded: bool,
@@ -0,0 +1,91 @@
+use core::pin::Pin;
+
+use alloc::{boxed::Box, vec, vec::Vec};
+
+/// polls its futures in the order they were added and resolves to the index and output of the
+/// first one that's ready, the others are dropped right then. Futures with different outputs
+/// can be mapped into one enum first, e.g. `async { Event::Read(read.await) }`
+pub struct Select<'a, T> {
+    futures: Vec<Pin<Box<dyn Future<Output = T> + Send + 'a>>>,
+}
+
+impl<'a, T> Default for Select<'a, T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a, T> Select<'a, T> {
+    pub fn new() -> Self {
+        Select { futures: vec![] }
+    }
+
+    pub fn add(mut self, future: impl Future<Output = T> + Send + 'a) -> Self {
+        self.futures.push(Box::pin(future));
+        self
+    }
+}
+
+impl<'a, T> Future for Select<'a, T> {
+    type Output = (usize, T);
+
+    fn poll(
+        mut self: Pin<&mut Self>,
+        cx: &mut core::task::Context<'_>,
+    ) -> core::task::Poll<Self::Output> {
+        for (idx, future) in self.futures.iter_mut().enumerate() {
+            if let core::task::Poll::Ready(res) = future.as_mut().poll(cx) {
+                // the losers never get polled again
+                self.futures.clear();
+                return core::task::Poll::Ready((idx, res));
+            }
+        }
+
+        core::task::Poll::Pending
+    }
+}
+
+#[macro_export]
+/// select!(a, b, c) resolves to (index, output) of whichever of them finishes first
+macro_rules! select {
+    ($($future:expr),+ $(,)?) => {
+        $crate::ejcineque::futures::select::Select::new()
+            $(.add($future))+
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use core::{future::pending, time::Duration};
+
+    use crate::{
+        ejcineque::{futures::yield_now, time::wait},
+        end_test,
+        terminal::test::block_on,
+        test_name,
+    };
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn select_returns_the_winner() {
+        test_name!("select resolves to the first ready future and its index");
+
+        assert_eq!(block_on(select!(async { 42 }, pending::<u32>())), (0, 42));
+        assert_eq!(block_on(select!(pending::<u32>(), async { 7 })), (1, 7));
+
+        // earlier ones win ties
+        assert_eq!(block_on(select!(async { 1 }, async { 2 })), (0, 1));
+
+        // a read that never finishes loses against its timeout
+        let (idx, ()) = block_on(select!(
+            async {
+                yield_now().await;
+                pending::<()>().await
+            },
+            wait(Duration::ZERO),
+        ));
+        assert_eq!(idx, 1);
+
+        end_test!();
+    }
+}
@@ -9,13 +9,52 @@ use crate::{
 };
 
 pub const PAGE_SIZE: usize = 4096;
+/// the logical sector size of most drives, 4kn drives get theirs from the page sized pool
 pub const SECTOR_SIZE: usize = 512;
+/// the block size ext2 is formatted with
+pub const BLOCK_SIZE: usize = 1024;
 
 lazy_static! {
     pub static ref DISK_IO_BUFFER_POOL_PAGE_SIZE: DiskIOBufferPool<PAGE_SIZE> =
         DiskIOBufferPool::new();
     pub static ref DISK_IO_BUFFER_POOL_SECTOR_SIZE: DiskIOBufferPool<SECTOR_SIZE> =
         DiskIOBufferPool::new();
+    pub static ref DISK_IO_BUFFER_POOL_BLOCK_SIZE: DiskIOBufferPool<BLOCK_SIZE> =
+        DiskIOBufferPool::new();
+}
+
+/// a buffer of one logical sector, the pools only cover 512 byte and 4kn drives
+pub enum SectorBufHandle {
+    Legacy(DiskIOBufferPoolHandle<SECTOR_SIZE>),
+    Native4K(DiskIOBufferPoolHandle<PAGE_SIZE>),
+}
+
+impl SectorBufHandle {
+    pub fn get_buffer(&self) -> Buffer {
+        match self {
+            Self::Legacy(handle) => handle.get_buffer(),
+            Self::Native4K(handle) => handle.get_buffer(),
+        }
+    }
+}
+
+/// a buffer of one `sector_size` sector, from the heap once all 64 of the pool are taken
+/// None for sector sizes no pool is made for
+pub fn get_sector_buf(sector_size: usize) -> Option<SectorBufHandle> {
+    match sector_size {
+        SECTOR_SIZE => Some(SectorBufHandle::Legacy(
+            DISK_IO_BUFFER_POOL_SECTOR_SIZE.get_buffer(),
+        )),
+        PAGE_SIZE => Some(SectorBufHandle::Native4K(
+            DISK_IO_BUFFER_POOL_PAGE_SIZE.get_buffer(),
+        )),
+        _ => None,
+    }
+}
+
+/// a block sized buffer, from the heap once all 64 of the pool are taken
+pub fn get_block_buf() -> DiskIOBufferPoolHandle<BLOCK_SIZE> {
+    DISK_IO_BUFFER_POOL_BLOCK_SIZE.get_buffer()
 }
 
 pub struct DiskIOBufferPool<const N: usize> {
@@ -95,7 +134,7 @@ impl<const N: usize> DiskIOBufferPool<N> {
                     // if the buffer pool is full allocate a new one
                     // used unsafe since the assert in new already checked
                     let layout = Layout::from_size_align_unchecked(N, N);
-                    
+
                     alloc::alloc::alloc(layout) as u64
                 }
             }
@@ -143,3 +182,61 @@ impl<const N: usize> Drop for DiskIOBufferPoolHandle<N> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+    use core::sync::atomic::Ordering;
+
+    use lazy_static::lazy_static;
+
+    use super::{
+        BLOCK_SIZE, DiskIOBufferPool, PAGE_SIZE, SECTOR_SIZE, get_block_buf, get_sector_buf,
+    };
+    use crate::{end_test, test_name};
+
+    lazy_static! {
+        // a pool of its own, the global ones are shared with everything doing disk io
+        static ref TEST_POOL: DiskIOBufferPool<SECTOR_SIZE> = DiskIOBufferPool::new();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn disk_io_buffer_pool() {
+        test_name!("disk io buffer pool falls back to the heap");
+
+        let mut handles: Vec<_> = (0..64).map(|_| TEST_POOL.get_buffer()).collect();
+        assert!(handles.iter().all(|handle| handle.idx.is_some()));
+        assert_eq!(handles[0].get_buffer().len, SECTOR_SIZE);
+        assert_eq!(TEST_POOL.mask.load(Ordering::Acquire), u64::MAX);
+
+        // the pool is exhausted
+        let extra = TEST_POOL.get_buffer();
+        assert_eq!(extra.idx, None);
+        assert_eq!(extra.inner % SECTOR_SIZE as u64, 0);
+        drop(extra);
+
+        // dropping a handle gives its slot back
+        let freed = handles.swap_remove(5);
+        let idx = freed.idx.unwrap();
+        drop(freed);
+        assert_eq!(TEST_POOL.mask.load(Ordering::Acquire), !(1 << idx));
+        assert_eq!(TEST_POOL.get_buffer().idx, Some(idx));
+
+        drop(handles);
+        assert_eq!(TEST_POOL.mask.load(Ordering::Acquire), 0);
+
+        let block = get_block_buf();
+        assert_eq!(block.get_buffer().len, BLOCK_SIZE);
+        assert!(block.idx.is_some());
+
+        // sector buffers follow the drive's logical sector size
+        for sector_size in [SECTOR_SIZE, PAGE_SIZE] {
+            let sector = get_sector_buf(sector_size).expect("No pool for the sector size");
+            assert_eq!(sector.get_buffer().len, sector_size);
+        }
+        assert!(get_sector_buf(520).is_none());
+
+        end_test!();
+    }
+}
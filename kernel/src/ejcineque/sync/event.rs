@@ -0,0 +1,71 @@
+use alloc::vec::Vec;
+
+use crate::ejcineque::sync::{
+    mpsc::unbounded::{UnboundedReceiver, UnboundedSender, unbounded_channel},
+    spin::SpinMutex,
+};
+
+/// Fans every published event out to all the current subscribers, each subscriber gets its own
+/// queue so a slow one never holds the publisher up
+#[derive(Debug)]
+pub struct EventBus<T: Clone> {
+    subscribers: SpinMutex<Vec<UnboundedSender<T>>>,
+}
+
+impl<T: Clone> Default for EventBus<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Clone> EventBus<T> {
+    pub const fn new() -> Self {
+        Self {
+            subscribers: SpinMutex::new(Vec::new()),
+        }
+    }
+
+    /// only events published after this call are received
+    pub fn subscribe(&self) -> UnboundedReceiver<T> {
+        let (tx, rx) = unbounded_channel();
+        self.subscribers.lock().push(tx);
+        rx
+    }
+
+    /// subscribers whose receiver has been dropped are forgotten here
+    pub fn publish(&self, event: T) {
+        let mut subscribers = self.subscribers.lock();
+        subscribers.retain(|tx| !tx.is_closed());
+
+        for tx in subscribers.iter() {
+            tx.send(event.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{end_test, terminal::test::block_on, test_name};
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn event_bus_fan_out() {
+        test_name!("event bus delivers to every subscriber");
+
+        let bus: EventBus<u32> = EventBus::new();
+        let first = bus.subscribe();
+        let second = bus.subscribe();
+
+        bus.publish(7);
+        assert_eq!(block_on(first.recv()), Some(7));
+        assert_eq!(block_on(second.recv()), Some(7));
+
+        drop(second);
+        bus.publish(8);
+        assert_eq!(first.try_recv(), Some(8));
+        assert_eq!(bus.subscribers.lock().len(), 1);
+
+        end_test!();
+    }
+}
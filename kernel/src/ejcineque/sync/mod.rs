@@ -1,4 +1,6 @@
+pub mod event;
 pub mod mpsc;
 pub mod mutex;
+pub mod once;
 pub mod spin;
 pub mod spsc;
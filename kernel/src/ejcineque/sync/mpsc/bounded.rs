@@ -1,8 +1,9 @@
-use alloc::sync::Arc;
+use alloc::{collections::vec_deque::VecDeque, sync::Arc};
 use core::cell::UnsafeCell;
 use core::mem::MaybeUninit;
 use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-use core::task::Waker;
+use core::task::{Poll, Waker};
+use spin::Mutex;
 
 // Slot state for the ring buffer
 #[derive(Debug)]
@@ -350,3 +351,277 @@ pub fn lockfree_channel<T, const CAPACITY: usize>()
     };
     (tx, rx)
 }
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    /// the channel is at capacity, the message is handed back
+    Full(T),
+    /// the receiver is gone
+    Closed(T),
+}
+
+#[derive(Debug)]
+struct BoundedChannel<T> {
+    buffer: VecDeque<T>,
+    capacity: usize,
+    rx_wakers: VecDeque<Waker>,
+    /// senders waiting for room
+    tx_wakers: VecDeque<Waker>,
+    sender_count: u64,
+    receiver_dropped: bool,
+}
+
+#[derive(Debug)]
+pub struct BoundedSender<T> {
+    channel: Arc<Mutex<BoundedChannel<T>>>,
+}
+
+impl<T> Clone for BoundedSender<T> {
+    fn clone(&self) -> Self {
+        self.channel.lock().sender_count += 1;
+
+        BoundedSender {
+            channel: self.channel.clone(),
+        }
+    }
+}
+
+impl<T> Drop for BoundedSender<T> {
+    fn drop(&mut self) {
+        let mut channel_guard = self.channel.lock();
+        channel_guard.sender_count -= 1;
+
+        // the receiver has to find out nothing else is coming
+        if channel_guard.sender_count == 0 {
+            for waker in channel_guard.rx_wakers.drain(..) {
+                waker.wake();
+            }
+        }
+    }
+}
+
+impl<T> BoundedSender<T> {
+    /// true once the receiving end is gone
+    pub fn is_closed(&self) -> bool {
+        self.channel.lock().receiver_dropped
+    }
+
+    pub fn try_send(&self, msg: T) -> Result<(), TrySendError<T>> {
+        let mut channel_guard = self.channel.lock();
+
+        if channel_guard.receiver_dropped {
+            return Err(TrySendError::Closed(msg));
+        }
+
+        if channel_guard.buffer.len() >= channel_guard.capacity {
+            return Err(TrySendError::Full(msg));
+        }
+
+        channel_guard.buffer.push_back(msg);
+
+        if let Some(waker) = channel_guard.rx_wakers.pop_front() {
+            waker.wake();
+        }
+
+        Ok(())
+    }
+
+    /// waits until there's room, gives the message back if the receiver is gone
+    pub fn send(&self, msg: T) -> SendFuture<'_, T> {
+        SendFuture {
+            tx: self,
+            msg: Some(msg),
+        }
+    }
+}
+
+pub struct SendFuture<'a, T> {
+    tx: &'a BoundedSender<T>,
+    msg: Option<T>,
+}
+
+// the message is never pinned, it's only moved into the channel
+impl<T> Unpin for SendFuture<'_, T> {}
+
+impl<'a, T> Future for SendFuture<'a, T> {
+    type Output = Result<(), T>;
+
+    fn poll(
+        self: core::pin::Pin<&mut Self>,
+        cx: &mut core::task::Context<'_>,
+    ) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let msg = this.msg.take().expect("SendFuture polled after completion");
+
+        match this.tx.try_send(msg) {
+            Ok(()) => Poll::Ready(Ok(())),
+            Err(TrySendError::Closed(msg)) => Poll::Ready(Err(msg)),
+            Err(TrySendError::Full(msg)) => {
+                let mut channel_guard = this.tx.channel.lock();
+
+                // the receiver might have made room in between
+                if channel_guard.buffer.len() < channel_guard.capacity
+                    || channel_guard.receiver_dropped
+                {
+                    cx.waker().wake_by_ref();
+                } else {
+                    channel_guard.tx_wakers.push_back(cx.waker().clone());
+                }
+
+                this.msg = Some(msg);
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct BoundedReceiver<T> {
+    channel: Arc<Mutex<BoundedChannel<T>>>,
+}
+
+impl<T> Drop for BoundedReceiver<T> {
+    fn drop(&mut self) {
+        let mut channel_guard = self.channel.lock();
+        channel_guard.receiver_dropped = true;
+
+        // blocked senders get their messages back
+        for waker in channel_guard.tx_wakers.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> BoundedReceiver<T> {
+    pub fn recv(&self) -> BoundedRecvFuture<'_, T> {
+        BoundedRecvFuture { rx: self }
+    }
+
+    pub fn try_recv(&self) -> Option<T> {
+        let mut channel_guard = self.channel.lock();
+        let msg = channel_guard.buffer.pop_front()?;
+
+        // there's room for one more now
+        if let Some(waker) = channel_guard.tx_wakers.pop_front() {
+            waker.wake();
+        }
+
+        Some(msg)
+    }
+}
+
+pub struct BoundedRecvFuture<'a, T> {
+    rx: &'a BoundedReceiver<T>,
+}
+
+impl<'a, T> Future for BoundedRecvFuture<'a, T> {
+    type Output = Option<T>;
+
+    fn poll(
+        self: core::pin::Pin<&mut Self>,
+        cx: &mut core::task::Context<'_>,
+    ) -> Poll<Self::Output> {
+        if let Some(msg) = self.rx.try_recv() {
+            return Poll::Ready(Some(msg));
+        }
+
+        let mut channel_guard = self.rx.channel.lock();
+
+        // a sender could have slipped one in after try_recv let go of the lock
+        if !channel_guard.buffer.is_empty() {
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+
+        if channel_guard.sender_count == 0 {
+            return Poll::Ready(None);
+        }
+
+        channel_guard.rx_wakers.push_back(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+/// like `unbounded_channel`, but at most `capacity` messages wait in the channel and senders
+/// are held back until the receiver catches up
+pub fn bounded_channel<T>(capacity: usize) -> (BoundedSender<T>, BoundedReceiver<T>) {
+    assert!(capacity > 0, "a bounded channel needs room for a message");
+
+    let channel = Arc::new(Mutex::new(BoundedChannel {
+        buffer: VecDeque::with_capacity(capacity),
+        capacity,
+        rx_wakers: VecDeque::new(),
+        tx_wakers: VecDeque::new(),
+        sender_count: 1,
+        receiver_dropped: false,
+    }));
+
+    let tx = BoundedSender {
+        channel: channel.clone(),
+    };
+
+    let rx = BoundedReceiver { channel };
+
+    (tx, rx)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{sync::Arc, task::Wake};
+    use core::{
+        pin::pin,
+        sync::atomic::{AtomicUsize, Ordering},
+        task::{Context, Poll, Waker},
+    };
+
+    use super::{TrySendError, bounded_channel};
+    use crate::{end_test, terminal::test::block_on, test_name};
+
+    struct CountingWaker(AtomicUsize);
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn bounded_channel_backpressure() {
+        test_name!("a full bounded channel holds the sender back");
+
+        let (tx, rx) = bounded_channel::<u32>(2);
+        block_on(tx.send(1)).unwrap();
+        tx.try_send(2).unwrap();
+        assert_eq!(tx.try_send(3), Err(TrySendError::Full(3)));
+
+        let woken = Arc::new(CountingWaker(AtomicUsize::new(0)));
+        let waker = Waker::from(woken.clone());
+        let mut ctx = Context::from_waker(&waker);
+
+        let mut blocked = pin!(tx.send(3));
+        assert!(blocked.as_mut().poll(&mut ctx).is_pending());
+        assert_eq!(woken.0.load(Ordering::SeqCst), 0);
+
+        // taking one out makes room and wakes the sender
+        assert_eq!(block_on(rx.recv()), Some(1));
+        assert_eq!(woken.0.load(Ordering::SeqCst), 1);
+        assert_eq!(blocked.as_mut().poll(&mut ctx), Poll::Ready(Ok(())));
+
+        assert_eq!(rx.try_recv(), Some(2));
+        assert_eq!(rx.try_recv(), Some(3));
+        assert_eq!(rx.try_recv(), None);
+
+        // the receiver going away hands the message back
+        tx.try_send(4).unwrap();
+        tx.try_send(5).unwrap();
+        let mut blocked = pin!(tx.send(6));
+        assert!(blocked.as_mut().poll(&mut ctx).is_pending());
+        drop(rx);
+        assert_eq!(woken.0.load(Ordering::SeqCst), 2);
+        assert_eq!(blocked.as_mut().poll(&mut ctx), Poll::Ready(Err(6)));
+        assert!(tx.is_closed());
+
+        end_test!();
+    }
+}
@@ -8,6 +8,7 @@ struct UnboundedChannel<T> {
     buffer: VecDeque<T>,
     rx_wakers: VecDeque<Waker>,
     sender_count: u64,
+    receiver_dropped: bool,
 }
 
 #[derive(Debug)]
@@ -32,6 +33,11 @@ impl<T> Drop for UnboundedSender<T> {
 }
 
 impl<T> UnboundedSender<T> {
+    /// true once the receiving end is gone, messages sent after that are never read
+    pub fn is_closed(&self) -> bool {
+        self.channel.lock().receiver_dropped
+    }
+
     pub fn send(&self, msg: T) {
         // get guard and push message
         let mut channel_guard = self.channel.lock();
@@ -49,6 +55,12 @@ pub struct UnboundedReceiver<T> {
     channel: Arc<Mutex<UnboundedChannel<T>>>,
 }
 
+impl<T> Drop for UnboundedReceiver<T> {
+    fn drop(&mut self) {
+        self.channel.lock().receiver_dropped = true;
+    }
+}
+
 impl<T> UnboundedReceiver<T> {
     pub fn recv(&self) -> RecvFuture<'_, T> {
         // '_ will explicitly ask the compiler to infer the
@@ -92,6 +104,7 @@ impl<'a, T> Future for RecvFuture<'a, T> {
 pub fn unbounded_channel<T>() -> (UnboundedSender<T>, UnboundedReceiver<T>) {
     let channel: Arc<Mutex<UnboundedChannel<T>>> = Arc::new(Mutex::new(UnboundedChannel {
         sender_count: 1,
+        receiver_dropped: false,
         buffer: VecDeque::with_capacity(128),
         rx_wakers: VecDeque::with_capacity(128),
     }));
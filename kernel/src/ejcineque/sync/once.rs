@@ -0,0 +1,204 @@
+use core::{
+    cell::UnsafeCell,
+    mem::MaybeUninit,
+    pin::Pin,
+    sync::atomic::{AtomicU8, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+use alloc::vec::Vec;
+
+use crate::ejcineque::sync::spin::SpinMutex;
+
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum OnceState {
+    Uninit = 0,
+    Initializing = 1,
+    Ready = 2,
+}
+
+unsafe impl<T: Send + Sync> Send for AsyncOnce<T> {}
+unsafe impl<T: Send + Sync> Sync for AsyncOnce<T> {}
+
+/// A cell that is initialized by an async function exactly once, the awaiters that lose the race
+/// are parked until the winner is done instead of spinning
+pub struct AsyncOnce<T> {
+    state: AtomicU8,
+    value: UnsafeCell<MaybeUninit<T>>,
+    wakers: SpinMutex<Vec<Waker>>,
+}
+
+impl<T> Default for AsyncOnce<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> AsyncOnce<T> {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(OnceState::Uninit as u8),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+            wakers: SpinMutex::new(Vec::new()),
+        }
+    }
+
+    fn state(&self) -> OnceState {
+        match self.state.load(Ordering::Acquire) {
+            0 => OnceState::Uninit,
+            1 => OnceState::Initializing,
+            _ => OnceState::Ready,
+        }
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        if self.state() == OnceState::Ready {
+            // SAFETY: the value is written before the state is set to ready and never again
+            Some(unsafe { (*self.value.get()).assume_init_ref() })
+        } else {
+            None
+        }
+    }
+
+    fn wake_all(&self) {
+        for waker in self.wakers.lock().drain(..) {
+            waker.wake();
+        }
+    }
+
+    /// runs `init` if nobody has yet, otherwise waits for whoever is running it
+    pub async fn get_or_init(&self, init: impl AsyncFnOnce() -> T) -> &T {
+        let mut init = Some(init);
+
+        loop {
+            if let Some(value) = self.get() {
+                return value;
+            }
+
+            if self
+                .state
+                .compare_exchange(
+                    OnceState::Uninit as u8,
+                    OnceState::Initializing as u8,
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                )
+                .is_ok()
+            {
+                // puts the cell back to uninit if the initializing future gets dropped halfway
+                let guard = InitGuard { once: self };
+                let init = init.take().expect("Initializer ran twice");
+                let value = init().await;
+
+                // SAFETY: only the task that won the compare exchange gets here
+                unsafe { (*self.value.get()).write(value) };
+                core::mem::forget(guard);
+
+                self.state.store(OnceState::Ready as u8, Ordering::Release);
+                self.wake_all();
+
+                continue;
+            }
+
+            WaitInit { once: self }.await;
+        }
+    }
+}
+
+impl<T> Drop for AsyncOnce<T> {
+    fn drop(&mut self) {
+        if self.state() == OnceState::Ready {
+            unsafe { self.value.get_mut().assume_init_drop() };
+        }
+    }
+}
+
+struct InitGuard<'a, T> {
+    once: &'a AsyncOnce<T>,
+}
+
+impl<T> Drop for InitGuard<'_, T> {
+    fn drop(&mut self) {
+        self.once
+            .state
+            .store(OnceState::Uninit as u8, Ordering::Release);
+        self.once.wake_all();
+    }
+}
+
+struct WaitInit<'a, T> {
+    once: &'a AsyncOnce<T>,
+}
+
+impl<T> Future for WaitInit<'_, T> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if self.once.state() != OnceState::Initializing {
+            return Poll::Ready(());
+        }
+
+        let mut wakers = self.once.wakers.lock();
+        // the initializer may have finished between the check above and taking the lock
+        if self.once.state() != OnceState::Initializing {
+            return Poll::Ready(());
+        }
+
+        wakers.push(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::AtomicUsize;
+
+    use super::*;
+    use crate::{ejcineque::futures::yield_now, end_test, terminal::test::block_on, test_name};
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn async_once_concurrent() {
+        test_name!("AsyncOnce runs the initializer once");
+
+        let once: AsyncOnce<u64> = AsyncOnce::new();
+        let calls = AtomicUsize::new(0);
+        let counter = &calls;
+
+        let init = move || async move {
+            counter.fetch_add(1, Ordering::SeqCst);
+            // give the other task a chance to see the cell mid initialization
+            yield_now().await;
+            42
+        };
+
+        let mut first = core::pin::pin!(once.get_or_init(init));
+        let mut second = core::pin::pin!(once.get_or_init(init));
+        let mut ctx = Context::from_waker(Waker::noop());
+
+        let mut first_res = None;
+        let mut second_res = None;
+        while first_res.is_none() || second_res.is_none() {
+            if first_res.is_none()
+                && let Poll::Ready(v) = first.as_mut().poll(&mut ctx)
+            {
+                first_res = Some(v);
+            }
+
+            if second_res.is_none()
+                && let Poll::Ready(v) = second.as_mut().poll(&mut ctx)
+            {
+                second_res = Some(v);
+            }
+        }
+
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(first_res, Some(&42));
+        assert_eq!(second_res, Some(&42));
+        assert!(core::ptr::eq(first_res.unwrap(), second_res.unwrap()));
+        assert_eq!(block_on(once.get_or_init(async || 7)), &42);
+
+        end_test!();
+    }
+}
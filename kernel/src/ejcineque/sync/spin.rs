@@ -52,6 +52,20 @@ impl<T> SpinMutex<T> {
 
         SpinMutexGuard { mutex: self }
     }
+
+    /// for interrupt handlers, spinning there would never end if the interrupted code holds the
+    /// lock
+    pub fn try_lock<'a>(&'a self) -> Option<SpinMutexGuard<'a, T>> {
+        self.is_locked
+            .compare_exchange(
+                false,
+                true,
+                core::sync::atomic::Ordering::Acquire,
+                core::sync::atomic::Ordering::Relaxed,
+            )
+            .ok()
+            .map(|_| SpinMutexGuard { mutex: self })
+    }
 }
 
 pub struct SpinMutexGuard<'a, T> {
@@ -1,40 +1,97 @@
-use core::task::Poll;
+use core::{task::Poll, time::Duration};
 
 use super::wakers::TIMER_WAKERS;
+use crate::arch::x86_64::timer::Instant;
 
-unsafe impl Send for WaitFuture {}
-unsafe impl Sync for WaitFuture {}
+/// the timer wheel counts the 1ms timer interrupts since boot
+pub fn wheel_tick(instant: Instant) -> u64 {
+    instant.since_boot().as_millis() as u64
+}
 
-pub struct WaitFuture {
-    tick_count: u32,
+/// parked in the timer wheel until the tick after its deadline
+pub struct SleepFuture {
+    deadline: Instant,
 }
 
-impl Future for WaitFuture {
+impl SleepFuture {
+    pub fn is_due(&self, now: Instant) -> bool {
+        now >= self.deadline
+    }
+}
+
+impl Future for SleepFuture {
     type Output = ();
 
     fn poll(
-        mut self: core::pin::Pin<&mut Self>,
+        self: core::pin::Pin<&mut Self>,
         cx: &mut core::task::Context<'_>,
     ) -> core::task::Poll<Self::Output> {
-        self.tick_count -= 1;
-
-        if self.tick_count <= 0 {
+        if self.is_due(Instant::now()) {
             Poll::Ready(())
         } else {
             x86_64::instructions::interrupts::without_interrupts(|| {
-                TIMER_WAKERS.lock().push(cx.waker().clone());
+                // the tick is rounded down so the one after it is the first past the deadline
+                TIMER_WAKERS
+                    .lock()
+                    .register_deadline(wheel_tick(self.deadline) + 1, cx.waker().clone());
             });
             Poll::Pending
         }
     }
 }
 
-fn wait_int(tick_count: u32) -> WaitFuture {
-    WaitFuture {
-        tick_count: tick_count + 1,
-    }
+pub fn sleep_until(deadline: Instant) -> SleepFuture {
+    SleepFuture { deadline }
+}
+
+/// the deadline is fixed when this is called, not when it's first polled
+pub fn wait(duration: Duration) -> SleepFuture {
+    sleep_until(Instant::now() + duration)
 }
 
-pub async fn wait(tick_count: u32) {
-    wait_int(tick_count).await;
+#[cfg(test)]
+mod tests {
+    use core::time::Duration;
+
+    use super::{sleep_until, wait};
+    use crate::{
+        arch::x86_64::timer::{Instant, duration_to_ticks},
+        end_test,
+        terminal::test::block_on,
+        test_name,
+    };
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn sleep_wakes_after_deadline() {
+        test_name!("sleep wakes once its deadline has passed");
+
+        // a made up tsc running at 1000 ticks per ms
+        const TICKS_PER_MS: u64 = 1000;
+        let start = 5_000;
+        let sleep = sleep_until(Instant::from_ticks(
+            start + duration_to_ticks(Duration::from_millis(10), TICKS_PER_MS),
+        ));
+
+        let mut now = start;
+        let mut elapsed_ms = 0;
+        while !sleep.is_due(Instant::from_ticks(now)) {
+            now += TICKS_PER_MS;
+            elapsed_ms += 1;
+        }
+        assert_eq!(elapsed_ms, 10);
+
+        // a deadline that has passed stays passed however often it's asked
+        assert!(sleep.is_due(Instant::from_ticks(now + 1)));
+
+        // partial ticks round up
+        assert_eq!(duration_to_ticks(Duration::from_nanos(1), TICKS_PER_MS), 1);
+        assert_eq!(duration_to_ticks(Duration::ZERO, TICKS_PER_MS), 0);
+
+        // nothing to wait for
+        block_on(wait(Duration::ZERO));
+        block_on(sleep_until(Instant::now()));
+
+        end_test!();
+    }
 }
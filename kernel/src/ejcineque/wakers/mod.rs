@@ -1,7 +1,9 @@
+pub mod timer_wheel;
+
 use alloc::vec::Vec;
 use core::task::Waker;
 
-use crate::ejcineque::sync::spin::SpinMutex;
+use crate::ejcineque::{sync::spin::SpinMutex, wakers::timer_wheel::TimerWheel};
 use lazy_static::lazy_static;
 // use spin::Mutex;
 
@@ -14,5 +16,5 @@ use lazy_static::lazy_static;
 lazy_static! {
     pub static ref PRIMARY_IDE_WAKERS: SpinMutex<Vec<Waker>> = SpinMutex::new(Vec::new());
     pub static ref SECONDARY_IDE_WAKERS: SpinMutex<Vec<Waker>> = SpinMutex::new(Vec::new());
-    pub static ref TIMER_WAKERS: SpinMutex<Vec<Waker>> = SpinMutex::new(Vec::new());
+    pub static ref TIMER_WAKERS: SpinMutex<TimerWheel> = SpinMutex::new(TimerWheel::new());
 }
@@ -0,0 +1,239 @@
+use core::{array, mem, task::Waker};
+
+use alloc::vec::Vec;
+
+const SLOT_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+const LEVELS: usize = 4;
+/// deadlines this far out don't fit in the wheel and wait in the overflow list
+const WHEEL_SPAN: u64 = 1 << (SLOT_BITS as usize * LEVELS);
+
+type Slot = Vec<(u64, Waker)>;
+
+/// a hierarchical timer wheel counting in timer ticks. Level n has 64 slots of 64^n ticks each,
+/// timers move down a level whenever the wheel below them wraps around, so a tick only looks at
+/// the timers that are actually due
+pub struct TimerWheel {
+    /// the last tick that has been expired
+    now: u64,
+    levels: [[Slot; SLOTS]; LEVELS],
+    overflow: Slot,
+    len: usize,
+}
+
+impl Default for TimerWheel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TimerWheel {
+    pub fn new() -> Self {
+        Self {
+            now: 0,
+            levels: array::from_fn(|_| array::from_fn(|_| Vec::new())),
+            overflow: Vec::new(),
+            len: 0,
+        }
+    }
+
+    /// how many timers are waiting
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// `waker` is woken once `expire_due` reaches `tick`, right away if that already happened
+    pub fn register_deadline(&mut self, tick: u64, waker: Waker) {
+        let mut due = Vec::new();
+        self.place(tick, waker, &mut due);
+
+        for waker in due {
+            waker.wake();
+        }
+    }
+
+    /// moves the wheel up to `now` and hands out the wakers of everything that came due on the
+    /// way, waking them is left to the caller so it can drop the lock first
+    pub fn expire_due(&mut self, now: u64) -> Vec<Waker> {
+        let mut due = Vec::new();
+
+        if now <= self.now {
+            return due;
+        }
+
+        // after a long gap it's cheaper to sort everything again than to walk every tick
+        if now - self.now >= WHEEL_SPAN {
+            let timers: Vec<_> = self
+                .levels
+                .iter_mut()
+                .flatten()
+                .flat_map(mem::take)
+                .chain(mem::take(&mut self.overflow))
+                .collect();
+
+            self.now = now;
+            self.len = 0;
+
+            for (tick, waker) in timers {
+                self.place(tick, waker, &mut due);
+            }
+
+            return due;
+        }
+
+        while self.now < now {
+            self.now += 1;
+            let tick = self.now;
+
+            if tick.is_multiple_of(WHEEL_SPAN) {
+                let timers = mem::take(&mut self.overflow);
+                self.len -= timers.len();
+                self.reinsert(timers, &mut due);
+            }
+
+            // the higher levels first so their timers can still land in this tick's slot
+            for level in (1..LEVELS).rev() {
+                let span = 1 << (SLOT_BITS as usize * level);
+
+                if tick.is_multiple_of(span) {
+                    let timers = mem::take(&mut self.levels[level][Self::slot(tick, level)]);
+                    self.len -= timers.len();
+                    self.reinsert(timers, &mut due);
+                }
+            }
+
+            let timers = mem::take(&mut self.levels[0][Self::slot(tick, 0)]);
+            self.len -= timers.len();
+            due.extend(timers.into_iter().map(|(_, waker)| waker));
+        }
+
+        due
+    }
+
+    fn slot(tick: u64, level: usize) -> usize {
+        (tick >> (SLOT_BITS as usize * level)) as usize & (SLOTS - 1)
+    }
+
+    fn reinsert(&mut self, timers: Slot, due: &mut Vec<Waker>) {
+        for (tick, waker) in timers {
+            self.place(tick, waker, due);
+        }
+    }
+
+    fn place(&mut self, tick: u64, waker: Waker, due: &mut Vec<Waker>) {
+        if tick <= self.now {
+            due.push(waker);
+            return;
+        }
+
+        let delta = tick - self.now;
+        self.len += 1;
+
+        match (0..LEVELS).find(|&level| delta < 1 << (SLOT_BITS as usize * (level + 1))) {
+            Some(level) => self.levels[level][Self::slot(tick, level)].push((tick, waker)),
+            None => self.overflow.push((tick, waker)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{sync::Arc, task::Wake, vec::Vec};
+    use core::sync::atomic::{AtomicU64, Ordering};
+
+    use super::TimerWheel;
+    use crate::{end_test, test_name};
+
+    /// remembers the tick it was woken at
+    struct Timer {
+        deadline: u64,
+        woken_at: AtomicU64,
+        now: Arc<AtomicU64>,
+    }
+
+    impl Wake for Timer {
+        fn wake(self: Arc<Self>) {
+            self.wake_by_ref();
+        }
+
+        fn wake_by_ref(self: &Arc<Self>) {
+            let previous = self
+                .woken_at
+                .swap(self.now.load(Ordering::Relaxed), Ordering::Relaxed);
+            assert_eq!(previous, u64::MAX, "woken twice");
+        }
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn timer_wheel_wakes_only_due() {
+        test_name!("timer wheel only wakes timers that are due");
+
+        let now = Arc::new(AtomicU64::new(0));
+        let mut wheel = TimerWheel::new();
+
+        // spread over every level, the last two past the third level and the whole wheel
+        let timers: Vec<_> = (0..1000u64)
+            .map(|i| (i * 7919) % 5000 + 1)
+            .chain([300_000, 20_000_000])
+            .map(|deadline| {
+                Arc::new(Timer {
+                    deadline,
+                    woken_at: AtomicU64::new(u64::MAX),
+                    now: now.clone(),
+                })
+            })
+            .collect();
+
+        for timer in &timers {
+            wheel.register_deadline(timer.deadline, timer.clone().into());
+        }
+        assert_eq!(wheel.len(), timers.len());
+
+        let expire = |wheel: &mut TimerWheel, tick: u64| {
+            now.store(tick, Ordering::Relaxed);
+            for waker in wheel.expire_due(tick) {
+                waker.wake();
+            }
+
+            for timer in &timers {
+                let woken_at = timer.woken_at.load(Ordering::Relaxed);
+                if timer.deadline <= tick {
+                    // never early, and on the first call that got past the deadline
+                    assert!(woken_at >= timer.deadline && woken_at <= tick);
+                } else {
+                    assert_eq!(woken_at, u64::MAX);
+                }
+            }
+        };
+
+        for tick in [
+            1, 2, 63, 64, 65, 100, 4095, 4096, 4097, 5000, 299_999, 300_000,
+        ] {
+            expire(&mut wheel, tick);
+        }
+
+        assert_eq!(wheel.len(), 1);
+
+        // a gap longer than the wheel
+        expire(&mut wheel, 19_999_999);
+        expire(&mut wheel, 40_000_000);
+        assert!(wheel.is_empty());
+
+        // a deadline that has passed wakes on the spot
+        let late = Arc::new(Timer {
+            deadline: 1,
+            woken_at: AtomicU64::new(u64::MAX),
+            now: now.clone(),
+        });
+        wheel.register_deadline(late.deadline, late.clone().into());
+        assert_ne!(late.woken_at.load(Ordering::Relaxed), u64::MAX);
+        assert!(wheel.is_empty());
+
+        end_test!();
+    }
+}
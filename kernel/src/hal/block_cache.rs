@@ -0,0 +1,321 @@
+use alloc::{boxed::Box, vec::Vec};
+
+use crate::{hal::storage::HalStorageOperationErr, utils::lru::LruCache};
+
+/// blocks kept per device unless the capacity is changed
+pub const DEFAULT_CACHE_CAPACITY: usize = 256;
+
+/// where cache misses are read from and dirty blocks are written back to, lbas count in blocks
+pub trait BlockBackend {
+    fn read_blocks(
+        &mut self,
+        lba: i64,
+        buffer: &mut [u8],
+    ) -> impl Future<Output = Result<(), HalStorageOperationErr>> + Send;
+
+    fn write_blocks(
+        &mut self,
+        lba: i64,
+        buffer: &[u8],
+    ) -> impl Future<Output = Result<(), HalStorageOperationErr>> + Send;
+}
+
+#[derive(Debug)]
+struct CachedBlock {
+    data: Box<[u8]>,
+    dirty: bool,
+}
+
+/// a write-back lru cache of single blocks keyed by lba
+///
+/// negative lbas count from the end of the drive, the same block could show up under two keys so
+/// they always go straight to the backend
+#[derive(Debug)]
+pub struct BlockCache {
+    blocks: LruCache<i64, CachedBlock>,
+    block_size: usize,
+}
+
+impl BlockCache {
+    pub fn new(capacity: usize, block_size: usize) -> Self {
+        Self {
+            blocks: LruCache::new(capacity),
+            block_size,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.blocks.capacity()
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    pub fn dirty_count(&self) -> usize {
+        self.blocks.iter().filter(|(_, block)| block.dirty).count()
+    }
+
+    /// makes room for `lba` if needed, a dirty victim is written back before its slot is reused
+    async fn insert<B: BlockBackend>(
+        &mut self,
+        backend: &mut B,
+        lba: i64,
+        data: Box<[u8]>,
+        dirty: bool,
+    ) -> Result<(), HalStorageOperationErr> {
+        if !self.blocks.contains_key(&lba)
+            && self.blocks.len() >= self.blocks.capacity()
+            && let Some((victim_lba, victim)) = self.blocks.pop_lru()
+            && victim.dirty
+            && let Err(e) = backend.write_blocks(victim_lba, &victim.data).await
+        {
+            // keep the only copy of the data around
+            self.blocks.put(victim_lba, victim);
+            return Err(e);
+        }
+
+        self.blocks.put(lba, CachedBlock { data, dirty });
+
+        Ok(())
+    }
+
+    pub async fn read<B: BlockBackend>(
+        &mut self,
+        backend: &mut B,
+        lba: i64,
+        buffer: &mut [u8],
+    ) -> Result<(), HalStorageOperationErr> {
+        if lba < 0 {
+            return backend.read_blocks(lba, buffer).await;
+        }
+
+        let block_size = self.block_size;
+        let all_cached =
+            (0..buffer.len() / block_size).all(|idx| self.blocks.contains_key(&(lba + idx as i64)));
+
+        if all_cached {
+            for (idx, block) in buffer.chunks_mut(block_size).enumerate() {
+                let cached = self
+                    .blocks
+                    .get(&(lba + idx as i64))
+                    .expect("The block was just checked");
+                block.copy_from_slice(&cached.data);
+            }
+
+            return Ok(());
+        }
+
+        backend.read_blocks(lba, buffer).await?;
+
+        for (idx, block) in buffer.chunks_mut(block_size).enumerate() {
+            let block_lba = lba + idx as i64;
+
+            match self.blocks.get(&block_lba) {
+                // what's on the drive is older than the cached copy
+                Some(cached) if cached.dirty => block.copy_from_slice(&cached.data),
+                Some(_) => {}
+                None => {
+                    self.insert(backend, block_lba, (&*block).into(), false)
+                        .await?
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// only touches the cache, the blocks reach the backend on eviction or flush
+    pub async fn write<B: BlockBackend>(
+        &mut self,
+        backend: &mut B,
+        lba: i64,
+        buffer: &[u8],
+    ) -> Result<(), HalStorageOperationErr> {
+        if lba < 0 {
+            return backend.write_blocks(lba, buffer).await;
+        }
+
+        for (idx, block) in buffer.chunks(self.block_size).enumerate() {
+            let block_lba = lba + idx as i64;
+
+            match self.blocks.get_mut(&block_lba) {
+                Some(cached) => {
+                    cached.data.copy_from_slice(block);
+                    cached.dirty = true;
+                }
+                None => self.insert(backend, block_lba, block.into(), true).await?,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// writes every dirty block back, they stay cached as clean blocks
+    pub async fn flush<B: BlockBackend>(
+        &mut self,
+        backend: &mut B,
+    ) -> Result<(), HalStorageOperationErr> {
+        let dirty: Vec<i64> = self
+            .blocks
+            .iter()
+            .filter(|(_, block)| block.dirty)
+            .map(|(lba, _)| *lba)
+            .collect();
+
+        for lba in dirty {
+            let block = self
+                .blocks
+                .peek_mut(&lba)
+                .expect("The block was just listed");
+            backend.write_blocks(lba, &block.data).await?;
+            block.dirty = false;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{collections::btree_map::BTreeMap, vec, vec::Vec};
+
+    use super::{BlockBackend, BlockCache};
+    use crate::{
+        end_test, hal::storage::HalStorageOperationErr, terminal::test::block_on, test_name,
+    };
+
+    const BLOCK_SIZE: usize = 8;
+
+    /// a drive in memory that remembers every access
+    #[derive(Default)]
+    struct MockBackend {
+        blocks: BTreeMap<i64, Vec<u8>>,
+        reads: Vec<i64>,
+        writes: Vec<i64>,
+    }
+
+    impl BlockBackend for MockBackend {
+        async fn read_blocks(
+            &mut self,
+            lba: i64,
+            buffer: &mut [u8],
+        ) -> Result<(), HalStorageOperationErr> {
+            for (idx, block) in buffer.chunks_mut(BLOCK_SIZE).enumerate() {
+                let lba = lba + idx as i64;
+                self.reads.push(lba);
+                match self.blocks.get(&lba) {
+                    Some(data) => block.copy_from_slice(data),
+                    None => block.fill(0),
+                }
+            }
+
+            Ok(())
+        }
+
+        async fn write_blocks(
+            &mut self,
+            lba: i64,
+            buffer: &[u8],
+        ) -> Result<(), HalStorageOperationErr> {
+            for (idx, block) in buffer.chunks(BLOCK_SIZE).enumerate() {
+                let lba = lba + idx as i64;
+                self.writes.push(lba);
+                self.blocks.insert(lba, block.to_vec());
+            }
+
+            Ok(())
+        }
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn block_cache_read_hit() {
+        test_name!("block cache read hit");
+
+        let mut backend = MockBackend::default();
+        backend.blocks.insert(3, vec![0xab; BLOCK_SIZE]);
+        let mut cache = BlockCache::new(4, BLOCK_SIZE);
+
+        let mut buf = [0u8; BLOCK_SIZE];
+        block_on(cache.read(&mut backend, 3, &mut buf)).unwrap();
+        assert_eq!(buf, [0xab; BLOCK_SIZE]);
+        assert_eq!(backend.reads, [3]);
+
+        // the second read is served from memory
+        let mut buf = [0u8; BLOCK_SIZE];
+        block_on(cache.read(&mut backend, 3, &mut buf)).unwrap();
+        assert_eq!(buf, [0xab; BLOCK_SIZE]);
+        assert_eq!(backend.reads, [3]);
+
+        // negative lbas always reach the drive
+        block_on(cache.read(&mut backend, -1, &mut buf)).unwrap();
+        assert_eq!(backend.reads, [3, -1]);
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn block_cache_write_back() {
+        test_name!("block cache write back");
+
+        let mut backend = MockBackend::default();
+        let mut cache = BlockCache::new(4, BLOCK_SIZE);
+
+        let data = [0x11u8; BLOCK_SIZE * 2];
+        block_on(cache.write(&mut backend, 5, &data)).unwrap();
+        assert!(backend.writes.is_empty());
+        assert_eq!(cache.dirty_count(), 2);
+
+        // reads see the dirty data without touching the drive
+        let mut buf = [0u8; BLOCK_SIZE];
+        block_on(cache.read(&mut backend, 6, &mut buf)).unwrap();
+        assert_eq!(buf, [0x11; BLOCK_SIZE]);
+        assert!(backend.reads.is_empty());
+
+        block_on(cache.flush(&mut backend)).unwrap();
+        assert_eq!(backend.writes, [5, 6]);
+        assert_eq!(backend.blocks.get(&6), Some(&vec![0x11; BLOCK_SIZE]));
+        assert_eq!(cache.dirty_count(), 0);
+
+        // nothing left to write
+        block_on(cache.flush(&mut backend)).unwrap();
+        assert_eq!(backend.writes, [5, 6]);
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn block_cache_dirty_eviction() {
+        test_name!("block cache dirty eviction");
+
+        let mut backend = MockBackend::default();
+        let mut cache = BlockCache::new(2, BLOCK_SIZE);
+
+        block_on(cache.write(&mut backend, 0, &[0x22; BLOCK_SIZE])).unwrap();
+        let mut buf = [0u8; BLOCK_SIZE];
+        block_on(cache.read(&mut backend, 1, &mut buf)).unwrap();
+        assert!(backend.writes.is_empty());
+
+        // block 0 is the oldest, it has to reach the drive before block 2 takes its place
+        block_on(cache.read(&mut backend, 2, &mut buf)).unwrap();
+        assert_eq!(backend.writes, [0]);
+        assert_eq!(backend.blocks.get(&0), Some(&vec![0x22; BLOCK_SIZE]));
+        assert_eq!(cache.len(), 2);
+
+        // clean victims are dropped without a write
+        block_on(cache.read(&mut backend, 3, &mut buf)).unwrap();
+        assert_eq!(backend.writes, [0]);
+
+        block_on(cache.read(&mut backend, 0, &mut buf)).unwrap();
+        assert_eq!(buf, [0x22; BLOCK_SIZE]);
+
+        end_test!();
+    }
+}
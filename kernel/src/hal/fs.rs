@@ -5,7 +5,12 @@ use dvida_serialize::{DvDeErr, DvSerErr, DvSerialize};
 
 use crate::{
     crypto::guid::Guid,
-    drivers::fs::ext2::{self, structs::Ext2Fs},
+    drivers::fs::{
+        devfs::{Devfs, DevfsInode},
+        ext2::{self, structs::Ext2Fs},
+        procfs::{Procfs, ProcfsInode},
+        tmpfs::{Tmpfs, TmpfsInode},
+    },
     hal::{gpt::GPTEntry, path::Path, storage::HalStorageOperationErr},
 };
 
@@ -31,6 +36,21 @@ impl DirEnt64 {
     }
 }
 
+/// the filesystems never fill buf up to the end, the records stop at the first rec_len of 0
+fn dirents_len(buf: &[u8]) -> usize {
+    const REC_LEN_OFFSET: usize = size_of::<u64>() + size_of::<i64>();
+
+    let mut len = 0;
+    while let Some(&[low, high]) = buf.get(len + REC_LEN_OFFSET..len + REC_LEN_OFFSET + 2) {
+        match u16::from_le_bytes([low, high]) {
+            0 => break,
+            rec_len => len += rec_len as usize,
+        }
+    }
+
+    len
+}
+
 impl DvSerialize for DirEnt64 {
     fn serialize(
         &self,
@@ -65,6 +85,23 @@ impl DvSerialize for DirEnt64 {
     }
 }
 
+/// filesystem independent metadata of a file, times are in seconds since the epoch
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileStat {
+    pub ino: u64,
+    pub size: u64,
+    /// file type and permission bits
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub atime: u64,
+    pub mtime: u64,
+    pub ctime: u64,
+    pub nlink: u32,
+    /// 512-byte sectors in use
+    pub blocks: u64,
+}
+
 #[derive(Debug, Default)]
 pub struct FileSystem {
     pub drive_id: Guid,
@@ -129,10 +166,87 @@ pub struct OpenFlags {
 #[derive(Debug, Clone)]
 pub enum HalInode {
     Ext2(ext2::InodePlus),
+    Tmpfs(TmpfsInode),
+    Procfs(ProcfsInode),
+    Devfs(DevfsInode),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
 }
 
+/// an opened inode and the position the next read or write starts at
 #[derive(Debug)]
-pub enum HalFsMountErr {}
+pub struct OpenFile {
+    pub inode: HalInode,
+    pub ctx: HalIOCtx,
+}
+
+impl OpenFile {
+    pub fn new(inode: HalInode) -> Self {
+        Self {
+            inode,
+            ctx: HalIOCtx::new(),
+        }
+    }
+
+    pub fn position(&self) -> usize {
+        self.ctx.head
+    }
+
+    /// moving past the end is allowed, the next write leaves a zero filled gap
+    pub async fn seek(&mut self, fs: &mut HalFs, pos: SeekFrom) -> Result<usize, HalFsIOErr> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(offset) => (0, offset as i64),
+            SeekFrom::Current(offset) => (self.ctx.head as u64, offset),
+            SeekFrom::End(offset) => (fs.file_size(&self.inode).await?, offset),
+        };
+
+        let head = base
+            .checked_add_signed(offset)
+            .ok_or(HalFsIOErr::InvalidArgument)?;
+        self.ctx.head = usize::try_from(head).map_err(|_| HalFsIOErr::InvalidArgument)?;
+
+        Ok(self.ctx.head)
+    }
+
+    pub async fn read(&mut self, fs: &mut HalFs, buf: &mut [u8]) -> Result<usize, HalFsIOErr> {
+        fs.read(&mut self.inode, buf, &mut self.ctx).await
+    }
+
+    pub async fn write(&mut self, fs: &mut HalFs, buf: &[u8]) -> Result<usize, HalFsIOErr> {
+        fs.write(&mut self.inode, buf, &mut self.ctx).await
+    }
+
+    /// for a directory the position is the offset of the next entry. Returns how many bytes of
+    /// DirEnt64 records were put in buf, 0 once every entry has been read
+    pub async fn read_dir(&mut self, fs: &mut HalFs, buf: &mut [u8]) -> Result<usize, HalFsIOErr> {
+        buf.fill(0);
+
+        let mut offset = self.ctx.head as i64;
+        let finished = fs.read_dir(&mut self.inode, &mut offset, buf).await?;
+        self.ctx.head = offset as usize;
+
+        let len = dirents_len(buf);
+        // not even the next entry fits
+        if len == 0 && !finished {
+            return Err(HalFsIOErr::InvalidArgument);
+        }
+
+        Ok(len)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum HalFsMountErr {
+    /// the filesystem uses features that have to be understood to read it
+    UnsupportedIncompatFeatures(u32),
+    /// the superblock describes a layout that can't exist, reading by it would go anywhere
+    InvalidLayout,
+}
 
 #[derive(Debug)]
 pub enum HalFsIOErr {
@@ -154,6 +268,12 @@ pub enum HalFsIOErr {
     NoAvailableInode,
     FileExists,
     Unsupported,
+    ReadOnlyFilesystem,
+    /// the inode is one of the reserved ones below `s_first_ino`
+    ReservedInode,
+    InvalidArgument,
+    /// resolving the path went through too many symlinks, most likely a loop
+    TooManySymlinks,
 }
 
 #[derive(Debug)]
@@ -196,4 +316,229 @@ pub enum HalFs {
     #[default]
     Unidentified,
     Ext2(Ext2Fs),
+    Tmpfs(Tmpfs),
+    Procfs(Procfs),
+    Devfs(Devfs),
+}
+
+impl HalFs {
+    pub async fn open_file(
+        &mut self,
+        path: Path,
+        flags: OpenFlags,
+    ) -> Result<HalInode, HalFsIOErr> {
+        match self {
+            HalFs::Ext2(ext2) => ext2.open_file(path, flags).await,
+            HalFs::Tmpfs(tmpfs) => tmpfs.open_file(path, flags),
+            HalFs::Procfs(procfs) => procfs.open_file(path, flags),
+            HalFs::Devfs(devfs) => devfs.open_file(path, flags),
+            HalFs::Unidentified => panic!("Bad fs"),
+        }
+    }
+
+    pub async fn mkdir(&mut self, path: Path, perms: i32) -> Result<(), HalFsIOErr> {
+        match self {
+            HalFs::Ext2(ext2) => ext2.mkdir(path, perms).await.map(|_| ()),
+            HalFs::Tmpfs(tmpfs) => tmpfs.mkdir(path, perms).map(|_| ()),
+            // their directories come from the kernel
+            HalFs::Procfs(_) | HalFs::Devfs(_) => Err(HalFsIOErr::Unsupported),
+            HalFs::Unidentified => panic!("Bad fs"),
+        }
+    }
+
+    pub async fn rmdir(&mut self, path: Path) -> Result<(), HalFsIOErr> {
+        match self {
+            HalFs::Ext2(ext2) => ext2.rmdir(path).await,
+            HalFs::Tmpfs(tmpfs) => tmpfs.rmdir(path),
+            HalFs::Procfs(_) | HalFs::Devfs(_) => Err(HalFsIOErr::Unsupported),
+            HalFs::Unidentified => panic!("Bad fs"),
+        }
+    }
+
+    pub async fn unlink(&mut self, path: Path) -> Result<(), HalFsIOErr> {
+        match self {
+            HalFs::Ext2(ext2) => ext2.delete_file(path).await,
+            HalFs::Tmpfs(tmpfs) => tmpfs.delete_file(path),
+            HalFs::Procfs(_) | HalFs::Devfs(_) => Err(HalFsIOErr::Unsupported),
+            HalFs::Unidentified => panic!("Bad fs"),
+        }
+    }
+
+    /// fills buf with DirEnt64 records starting at offset, returns true once every entry has been
+    /// written
+    pub async fn read_dir(
+        &mut self,
+        inode: &mut HalInode,
+        offset: &mut i64,
+        buf: &mut [u8],
+    ) -> Result<bool, HalFsIOErr> {
+        match (self, inode) {
+            (HalFs::Ext2(ext2), HalInode::Ext2(inode)) => ext2.iter_dir(offset, buf, inode).await,
+            (HalFs::Tmpfs(tmpfs), HalInode::Tmpfs(inode)) => tmpfs.iter_dir(offset, buf, inode),
+            (HalFs::Procfs(procfs), HalInode::Procfs(inode)) => procfs.iter_dir(offset, buf, inode),
+            (HalFs::Devfs(devfs), HalInode::Devfs(inode)) => devfs.iter_dir(offset, buf, inode),
+            (HalFs::Unidentified, _) => panic!("Bad fs"),
+            _ => Err(HalFsIOErr::Internal),
+        }
+    }
+
+    /// only ext2 keeps anything on a drive, the rest have nothing to sync
+    pub async fn sync(&mut self) -> Result<(), HalFsIOErr> {
+        match self {
+            HalFs::Ext2(ext2) => ext2.sync().await,
+            HalFs::Tmpfs(_) | HalFs::Procfs(_) | HalFs::Devfs(_) | HalFs::Unidentified => Ok(()),
+        }
+    }
+
+    pub async fn read(
+        &mut self,
+        inode: &mut HalInode,
+        buf: &mut [u8],
+        ctx: &mut HalIOCtx,
+    ) -> Result<usize, HalFsIOErr> {
+        match (self, inode) {
+            (HalFs::Ext2(ext2), HalInode::Ext2(inode)) => ext2.read(inode, buf, ctx).await,
+            (HalFs::Tmpfs(tmpfs), HalInode::Tmpfs(inode)) => tmpfs.read(inode, buf, ctx),
+            (HalFs::Procfs(procfs), HalInode::Procfs(inode)) => procfs.read(inode, buf, ctx).await,
+            (HalFs::Devfs(devfs), HalInode::Devfs(inode)) => devfs.read(inode, buf, ctx).await,
+            (HalFs::Unidentified, _) => panic!("Bad fs"),
+            // the inode was opened on another filesystem
+            _ => Err(HalFsIOErr::Internal),
+        }
+    }
+
+    pub async fn file_size(&mut self, inode: &HalInode) -> Result<u64, HalFsIOErr> {
+        match (self, inode) {
+            (HalFs::Ext2(ext2), HalInode::Ext2(inode)) => Ok(ext2.file_size(inode)),
+            (HalFs::Tmpfs(tmpfs), HalInode::Tmpfs(inode)) => tmpfs.file_size(inode),
+            (HalFs::Procfs(procfs), HalInode::Procfs(inode)) => procfs.file_size(inode).await,
+            (HalFs::Devfs(devfs), HalInode::Devfs(inode)) => devfs.file_size(inode),
+            (HalFs::Unidentified, _) => panic!("Bad fs"),
+            _ => Err(HalFsIOErr::Internal),
+        }
+    }
+
+    pub fn is_directory(&self, inode: &HalInode) -> Result<bool, HalFsIOErr> {
+        match (self, inode) {
+            (HalFs::Ext2(_), HalInode::Ext2(inode)) => Ok(inode.inode.is_directory()),
+            (HalFs::Tmpfs(tmpfs), HalInode::Tmpfs(inode)) => tmpfs.is_directory(inode),
+            (HalFs::Procfs(_), HalInode::Procfs(inode)) => Ok(inode.file.is_none()),
+            (HalFs::Devfs(_), HalInode::Devfs(inode)) => Ok(inode.name.is_none()),
+            (HalFs::Unidentified, _) => panic!("Bad fs"),
+            _ => Err(HalFsIOErr::Internal),
+        }
+    }
+
+    pub async fn write(
+        &mut self,
+        inode: &mut HalInode,
+        buf: &[u8],
+        ctx: &mut HalIOCtx,
+    ) -> Result<usize, HalFsIOErr> {
+        match (self, inode) {
+            (HalFs::Ext2(ext2), HalInode::Ext2(inode)) => ext2.write(inode, buf, ctx).await,
+            (HalFs::Tmpfs(tmpfs), HalInode::Tmpfs(inode)) => tmpfs.write(inode, buf, ctx),
+            (HalFs::Procfs(procfs), HalInode::Procfs(inode)) => procfs.write(inode, buf, ctx),
+            (HalFs::Devfs(devfs), HalInode::Devfs(inode)) => devfs.write(inode, buf, ctx).await,
+            (HalFs::Unidentified, _) => panic!("Bad fs"),
+            _ => Err(HalFsIOErr::Internal),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{end_test, terminal::test::block_on, test_name};
+
+    fn tmpfs_file() -> (HalFs, OpenFile) {
+        let mut fs = HalFs::Tmpfs(Tmpfs::new());
+        let flags = OpenFlags {
+            flags: OpenFlagsValue::CreateIfNotExist as i32,
+            perms: Some(0o644),
+            ..Default::default()
+        };
+        let inode =
+            block_on(fs.open_file(Path::new_appended("/file"), flags)).expect("Failed to create");
+
+        (fs, OpenFile::new(inode))
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn open_file_seek() {
+        test_name!("open file seek");
+
+        let (mut fs, mut file) = tmpfs_file();
+        assert_eq!(block_on(file.write(&mut fs, b"0123456789")).ok(), Some(10));
+        assert_eq!(file.position(), 10);
+
+        assert_eq!(
+            block_on(file.seek(&mut fs, SeekFrom::Start(3))).ok(),
+            Some(3)
+        );
+        let mut buf = [0u8; 2];
+        assert_eq!(block_on(file.read(&mut fs, &mut buf)).ok(), Some(2));
+        assert_eq!(&buf, b"34");
+
+        assert_eq!(
+            block_on(file.seek(&mut fs, SeekFrom::Current(-1))).ok(),
+            Some(4)
+        );
+
+        assert_eq!(
+            block_on(file.seek(&mut fs, SeekFrom::End(-2))).ok(),
+            Some(8)
+        );
+        assert_eq!(block_on(file.read(&mut fs, &mut buf)).ok(), Some(2));
+        assert_eq!(&buf, b"89");
+
+        assert!(matches!(
+            block_on(file.seek(&mut fs, SeekFrom::End(-11))),
+            Err(HalFsIOErr::InvalidArgument)
+        ));
+        // a failed seek doesn't move the cursor
+        assert_eq!(file.position(), 10);
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn open_file_sparse_write() {
+        test_name!("open file sparse write");
+
+        let (mut fs, mut file) = tmpfs_file();
+        assert_eq!(block_on(file.write(&mut fs, b"ab")).ok(), Some(2));
+
+        assert_eq!(block_on(file.seek(&mut fs, SeekFrom::End(4))).ok(), Some(6));
+        assert_eq!(block_on(file.write(&mut fs, b"cd")).ok(), Some(2));
+        assert_eq!(block_on(fs.file_size(&file.inode)).ok(), Some(8));
+
+        block_on(file.seek(&mut fs, SeekFrom::Start(0))).expect("Failed to seek");
+        let mut buf = [0xFFu8; 8];
+        assert_eq!(block_on(file.read(&mut fs, &mut buf)).ok(), Some(8));
+        assert_eq!(&buf, b"ab\0\0\0\0cd");
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn sync_flushes_ext2() {
+        test_name!("syncing an ext2 mount flushes its drive");
+
+        let guid = Guid::from_bytes([0x59; 16]);
+        let mut fs = HalFs::Ext2(block_on(Ext2Fs::on_ram_disk(guid, 1)));
+        crate::hal::ram_disk::with_disk(guid, |disk| disk.flushes = 0);
+
+        block_on(fs.sync()).expect("Failed to sync");
+        let disk = crate::hal::ram_disk::unregister(guid).unwrap();
+        assert_eq!(disk.flushes, 1);
+
+        // nothing to flush for the filesystems living in memory
+        assert!(block_on(HalFs::Tmpfs(Tmpfs::new()).sync()).is_ok());
+
+        end_test!();
+    }
 }
@@ -1,6 +1,6 @@
 use core::ops::Deref;
 
-use crate::ejcineque::pools::{DISK_IO_BUFFER_POOL_SECTOR_SIZE, DiskIOBufferPoolHandle};
+use crate::ejcineque::sync::event::EventBus;
 use crate::hal::buffer::Buffer;
 use crate::{hal, log};
 use alloc::boxed::Box;
@@ -36,6 +36,70 @@ impl GPTHeader {
     pub fn guid(&self) -> Guid {
         Guid::from_bytes(self.guid)
     }
+
+    /// the spec allows any entry size of the form 128 * 2^n
+    pub fn is_entry_size_valid(&self) -> bool {
+        let entry_size = self.entry_size;
+        entry_size >= size_of::<GPTEntry>() as u32 && entry_size.is_power_of_two()
+    }
+
+    /// size of the entry array in bytes, as declared by the header
+    pub fn array_len(&self) -> usize {
+        self.entry_num as usize * self.entry_size as usize
+    }
+
+    /// number of sectors the entry array occupies on disk
+    pub fn array_sectors(&self, sector_size: usize) -> usize {
+        self.array_len().div_ceil(sector_size)
+    }
+
+    /// recomputes both checksums, the array has to be laid out by [`GPTHeader::serialize_entries`]
+    pub fn update_crcs(&mut self, arr: &[u8]) {
+        self.array_crc32 = crypto::crc32::full_crc(&arr[0..self.array_len()]);
+        self.header_crc32 = 0;
+        self.header_crc32 = crypto::crc32::full_crc(bytemuck::bytes_of(self));
+    }
+
+    /// the header stored at the end of the disk, its array sits right before it
+    pub fn to_backup(&self, sector_size: usize) -> Self {
+        let mut backup = *self;
+        backup.loc = self.backup_loc;
+        backup.backup_loc = self.loc;
+        backup.array_start = self.backup_loc - self.array_sectors(sector_size) as u64;
+        backup
+    }
+
+    /// parses `entry_num` entries of `entry_size` bytes each, the bytes past the standard entry
+    /// are ignored
+    pub fn parse_entries(&self, arr: &[u8]) -> Result<Vec<GPTEntry>, GPTErr> {
+        if arr.len() < self.array_len() {
+            return Err(GPTErr::BufferTooSmall);
+        }
+
+        Ok(arr[0..self.array_len()]
+            .chunks(self.entry_size as usize)
+            .map(|slice| *bytemuck::from_bytes(&slice[0..size_of::<GPTEntry>()]))
+            .collect())
+    }
+
+    /// lays the entries out with a stride of `entry_size`, padding every entry and the missing
+    /// trailing entries with zeroes, the result is rounded up to whole sectors
+    pub fn serialize_entries(
+        &self,
+        entries: &[GPTEntry],
+        sector_size: usize,
+    ) -> Result<Vec<u8>, GPTErr> {
+        if entries.len() > self.entry_num as usize {
+            return Err(GPTErr::NoFreeSlot);
+        }
+
+        let mut arr = vec![0u8; self.array_sectors(sector_size) * sector_size];
+        for (slot, entry) in arr.chunks_mut(self.entry_size as usize).zip(entries.iter()) {
+            slot[0..size_of::<GPTEntry>()].copy_from_slice(bytemuck::bytes_of(entry));
+        }
+
+        Ok(arr)
+    }
 }
 
 #[derive(PartialEq, Eq, Clone, Debug, Copy, Pod, Zeroable, Default)]
@@ -83,6 +147,12 @@ pub enum GPTErr {
     GPTNonExist,
     #[error("The GPT table is corrupted")]
     GPTCorrupted,
+    #[error("Header CRC mismatch: expected {expected:#x}, computed {computed:#x}")]
+    BadHeaderCrc { expected: u32, computed: u32 },
+    #[error("Partition entry array CRC mismatch: expected {expected:#x}, computed {computed:#x}")]
+    BadArrayCrc { expected: u32, computed: u32 },
+    #[error("The header doesn't start with the EFI PART signature")]
+    BadSignature,
     #[error("The Array size is bad")]
     BadArrayEntrySize,
     #[error("There is no free slot")]
@@ -103,21 +173,72 @@ pub enum GPTErr {
     Io(String),
     #[error("Drive didn't respond")]
     DriveDidntRespond,
+    #[error(
+        "Table write failed (backup written: {backup_written}, primary written: {primary_written})"
+    )]
+    PartialTableWrite {
+        backup_written: bool,
+        primary_written: bool,
+    },
+}
+
+/// published on [`PARTITION_EVENTS`] every time a partition table is written, anything caching
+/// partitions of that device (e.g. mounted filesystems) is stale afterwards
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PartitionChanged {
+    pub device_idx: usize,
+}
+
+pub static PARTITION_EVENTS: EventBus<PartitionChanged> = EventBus::new();
+
+/// which of the two copies of the table a write belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GptCopy {
+    Primary,
+    Backup,
+}
+
+/// a single step of [`GptReader::write_table`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GptTableWrite {
+    Sectors {
+        copy: GptCopy,
+        lba: i64,
+        data: Vec<u8>,
+    },
+    Flush {
+        copy: GptCopy,
+    },
 }
 
 pub struct GptReader {
     idx: usize,
+    /// the header lives in lba 1 whatever the size, so every offset depends on it
+    sector_size: usize,
 }
 
-pub const SECTOR_SIZE: usize = 512;
+pub use hal::storage::SECTOR_SIZE;
+pub const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
 
 impl GptReader {
-    pub fn get_buffer() -> DiskIOBufferPoolHandle<SECTOR_SIZE> {
-        DISK_IO_BUFFER_POOL_SECTOR_SIZE.get_buffer()
+    pub fn new(idx: usize) -> Self {
+        Self {
+            idx,
+            sector_size: hal::storage::logical_sector_size_by_idx(idx).unwrap_or(SECTOR_SIZE),
+        }
     }
 
-    pub fn new(idx: usize) -> Self {
-        Self { idx }
+    pub fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    /// reads a single sector into a heap buffer
+    async fn read_sector(&self, lba: i64) -> Result<Box<[u8]>, GPTErr> {
+        let buf: Buffer = vec![0u8; self.sector_size].into_boxed_slice().into();
+        let res = self.read_sectors_async(lba, buf.clone()).await;
+        let buf: Box<[u8]> = buf.into();
+
+        res.map(|_| buf).map_err(|e| GPTErr::Io(e.to_string()))
     }
 
     async fn read_sectors_async(
@@ -128,26 +249,64 @@ impl GptReader {
         Ok(hal::storage::read_sectors_by_idx(self.idx, buf, lba).await?)
     }
 
-    fn is_valid_header(&self, buf: &mut [u8]) -> bool {
-        let header: &mut GPTHeader = bytemuck::from_bytes_mut(&mut buf[0..size_of::<GPTHeader>()]);
+    /// checks the signature and the header CRC of a raw header sector
+    pub fn parse_header(buf: &[u8]) -> Result<GPTHeader, GPTErr> {
+        if buf.len() < size_of::<GPTHeader>() {
+            return Err(GPTErr::BufferTooSmall);
+        }
+
+        let mut header: GPTHeader = *bytemuck::from_bytes(&buf[0..size_of::<GPTHeader>()]);
 
-        let crc = header.header_crc32;
+        if header.sig != *GPT_SIGNATURE {
+            log!("GPT header signature mismatch");
+            return Err(GPTErr::BadSignature);
+        }
+
+        let expected = header.header_crc32;
         header.header_crc32 = 0;
+        let computed = crypto::crc32::full_crc(bytemuck::bytes_of(&header));
+        header.header_crc32 = expected;
+
+        if expected != computed {
+            log!(
+                "Header CRC mismatch: expected={:#x} computed={:#x}",
+                expected,
+                computed
+            );
+            return Err(GPTErr::BadHeaderCrc { expected, computed });
+        }
 
-        let ok = crypto::crc32::is_verified_crc32(bytemuck::bytes_of(header), crc);
-        log!("Header CRC validation result={}", ok);
-        ok
+        Ok(header)
+    }
+
+    /// checks the CRC of the partition entry array described by the header
+    pub fn verify_array(header: &GPTHeader, arr: &[u8]) -> Result<(), GPTErr> {
+        let len = header.entry_num as usize * header.entry_size as usize;
+        if arr.len() < len {
+            return Err(GPTErr::BufferTooSmall);
+        }
+
+        let expected = header.array_crc32;
+        let computed = crypto::crc32::full_crc(&arr[0..len]);
+
+        if expected != computed {
+            log!(
+                "GPT array CRC mismatch: expected={:#x} computed={:#x}",
+                expected,
+                computed
+            );
+            return Err(GPTErr::BadArrayCrc { expected, computed });
+        }
+
+        Ok(())
     }
 
     async fn is_normal_present(&self) -> bool {
         log!("Checking primary GPT presence at LBA 1");
-        let handle = Self::get_buffer();
-        let buf: Buffer = handle.get_buffer();
-
-        if self.read_sectors_async(1, buf.clone()).await.is_err() {
+        let Ok(buf) = self.read_sector(1).await else {
             log!("Failed to read primary GPT sector");
             return false;
-        }
+        };
 
         let present = buf.starts_with(b"EFI PART");
         if present {
@@ -161,13 +320,10 @@ impl GptReader {
 
     async fn is_backup_present(&self) -> bool {
         log!("Checking backup GPT presence at LBA -1");
-        let handle = Self::get_buffer();
-        let buf: Buffer = handle.get_buffer();
-
-        if self.read_sectors_async(-1, buf.clone()).await.is_err() {
+        let Ok(buf) = self.read_sector(-1).await else {
             log!("Failed to read backup GPT sector");
             return false;
-        }
+        };
 
         let present = buf.starts_with(b"EFI PART");
         if present {
@@ -197,6 +353,241 @@ impl GptReader {
         normal || backup
     }
 
+    async fn write_sectors_async(
+        &self,
+        lba: i64,
+        buf: Buffer,
+    ) -> Result<(), Box<dyn core::error::Error + Send + Sync>> {
+        Ok(hal::storage::write_sectors_by_idx(self.idx, buf, lba).await?)
+    }
+
+    async fn flush_async(&self) -> Result<(), Box<dyn core::error::Error + Send + Sync>> {
+        Ok(hal::storage::flush_by_idx(self.idx).await?)
+    }
+
+    /// the writes needed to persist a table, the backup copy goes first so a crash halfway
+    /// through always leaves one consistent copy behind
+    pub fn table_writes(
+        header: &GPTHeader,
+        entries: &[GPTEntry],
+        sector_size: usize,
+    ) -> Result<Vec<GptTableWrite>, GPTErr> {
+        if !header.is_entry_size_valid() {
+            return Err(GPTErr::BadArrayEntrySize);
+        }
+
+        let arr = header.serialize_entries(entries, sector_size)?;
+
+        let mut primary = *header;
+        primary.update_crcs(&arr);
+        let mut backup = primary.to_backup(sector_size);
+        backup.update_crcs(&arr);
+
+        let header_sector = |header: &GPTHeader| {
+            let mut buf = vec![0u8; sector_size];
+            buf[0..size_of::<GPTHeader>()].copy_from_slice(bytemuck::bytes_of(header));
+            buf
+        };
+
+        Ok(vec![
+            GptTableWrite::Sectors {
+                copy: GptCopy::Backup,
+                lba: -1 - header.array_sectors(sector_size) as i64,
+                data: arr.clone(),
+            },
+            GptTableWrite::Sectors {
+                copy: GptCopy::Backup,
+                lba: -1,
+                data: header_sector(&backup),
+            },
+            GptTableWrite::Flush {
+                copy: GptCopy::Backup,
+            },
+            GptTableWrite::Sectors {
+                copy: GptCopy::Primary,
+                lba: primary.array_start as i64,
+                data: arr,
+            },
+            GptTableWrite::Sectors {
+                copy: GptCopy::Primary,
+                lba: 1,
+                data: header_sector(&primary),
+            },
+            GptTableWrite::Flush {
+                copy: GptCopy::Primary,
+            },
+        ])
+    }
+
+    /// runs the writes in order and stops at the first failure, a copy only counts as written
+    /// once its flush went through
+    pub async fn apply_table_writes(
+        device_idx: usize,
+        writes: Vec<GptTableWrite>,
+        mut write: impl AsyncFnMut(i64, Vec<u8>) -> Result<(), GPTErr>,
+        mut flush: impl AsyncFnMut() -> Result<(), GPTErr>,
+    ) -> Result<(), GPTErr> {
+        let mut backup_written = false;
+        let mut primary_written = false;
+
+        for op in writes {
+            let (copy, res) = match op {
+                GptTableWrite::Sectors { copy, lba, data } => (copy, write(lba, data).await),
+                GptTableWrite::Flush { copy } => {
+                    let res = flush().await;
+                    if res.is_ok() {
+                        match copy {
+                            GptCopy::Backup => backup_written = true,
+                            GptCopy::Primary => primary_written = true,
+                        }
+                    }
+                    (copy, res)
+                }
+            };
+
+            if let Err(e) = res {
+                log!(
+                    "Failed to write the {:?} GPT: {} (backup written: {}, primary written: {})",
+                    copy,
+                    e,
+                    backup_written,
+                    primary_written
+                );
+
+                if backup_written {
+                    PARTITION_EVENTS.publish(PartitionChanged { device_idx });
+                }
+
+                return Err(GPTErr::PartialTableWrite {
+                    backup_written,
+                    primary_written,
+                });
+            }
+        }
+
+        PARTITION_EVENTS.publish(PartitionChanged { device_idx });
+
+        Ok(())
+    }
+
+    pub async fn write_table(
+        &self,
+        header: &GPTHeader,
+        entries: &[GPTEntry],
+    ) -> Result<(), GPTErr> {
+        log!("Writing GPT table (backup first)");
+        let writes = Self::table_writes(header, entries, self.sector_size)?;
+
+        Self::apply_table_writes(
+            self.idx,
+            writes,
+            async |lba, data| {
+                let buffer: Buffer = data.into_boxed_slice().into();
+                let res = self.write_sectors_async(lba, buffer.clone()).await;
+                // hand the allocation back to the box so it gets freed
+                let _: Box<[u8]> = buffer.into();
+                res.map_err(|e| GPTErr::Io(e.to_string()))
+            },
+            async || {
+                self.flush_async()
+                    .await
+                    .map_err(|e| GPTErr::Io(e.to_string()))
+            },
+        )
+        .await
+    }
+
+    /// puts the entry in the first empty slot, the range has to be usable and can't overlap any
+    /// existing partition
+    pub fn insert_entry(
+        header: &GPTHeader,
+        entries: &mut Vec<GPTEntry>,
+        entry: GPTEntry,
+    ) -> Result<usize, GPTErr> {
+        let (start, end) = (entry.start_lba, entry.end_lba);
+        if start > end || start < header.first_usable_block || end > header.last_usable_block {
+            return Err(GPTErr::InvalidLBARange);
+        }
+
+        if entries
+            .iter()
+            .filter(|e| !e.is_empty())
+            .any(|e| start <= e.end_lba && e.start_lba <= end)
+        {
+            return Err(GPTErr::OverlappingPartition);
+        }
+
+        entries.resize(header.entry_num as usize, GPTEntry::default());
+        let idx = entries
+            .iter()
+            .position(|e| e.is_empty())
+            .ok_or(GPTErr::NoFreeSlot)?;
+        entries[idx] = entry;
+
+        Ok(idx)
+    }
+
+    pub fn remove_entry(entries: &mut [GPTEntry], idx: usize) -> Result<(), GPTErr> {
+        let entry = entries.get_mut(idx).ok_or(GPTErr::InvalidEntryIndex)?;
+        if entry.is_empty() {
+            return Err(GPTErr::EntryAlreadyEmpty);
+        }
+
+        *entry = GPTEntry::default();
+        Ok(())
+    }
+
+    /// moves the end of an existing partition, the new range has the same rules as a fresh entry
+    pub fn change_entry_end(
+        header: &GPTHeader,
+        entries: &mut [GPTEntry],
+        idx: usize,
+        end_lba: u64,
+    ) -> Result<(), GPTErr> {
+        let start = match entries.get(idx) {
+            None => return Err(GPTErr::InvalidEntryIndex),
+            Some(e) if e.is_empty() => return Err(GPTErr::EntryAlreadyEmpty),
+            Some(e) => e.start_lba,
+        };
+
+        if start > end_lba || end_lba > header.last_usable_block {
+            return Err(GPTErr::InvalidLBARange);
+        }
+
+        if entries
+            .iter()
+            .enumerate()
+            .filter(|(i, e)| *i != idx && !e.is_empty())
+            .any(|(_, e)| start <= e.end_lba && e.start_lba <= end_lba)
+        {
+            return Err(GPTErr::OverlappingPartition);
+        }
+
+        entries[idx].end_lba = end_lba;
+        Ok(())
+    }
+
+    /// returns the index of the slot the entry landed in
+    pub async fn add_entry(&self, entry: GPTEntry) -> Result<usize, GPTErr> {
+        let (header, mut entries) = self.read_gpt().await?;
+        let idx = Self::insert_entry(&header, &mut entries, entry)?;
+        self.write_table(&header, &entries).await?;
+        Ok(idx)
+    }
+
+    pub async fn delete_entry(&self, idx: usize) -> Result<(), GPTErr> {
+        let (header, mut entries) = self.read_gpt().await?;
+        Self::remove_entry(&mut entries, idx)?;
+        self.write_table(&header, &entries).await
+    }
+
+    /// the start stays put, only the last lba of the partition changes
+    pub async fn resize_entry(&self, idx: usize, end_lba: u64) -> Result<(), GPTErr> {
+        let (header, mut entries) = self.read_gpt().await?;
+        Self::change_entry_end(&header, &mut entries, idx, end_lba)?;
+        self.write_table(&header, &entries).await
+    }
+
     pub async fn get_table(
         &self,
         lba: i64,
@@ -205,36 +596,21 @@ impl GptReader {
         log!("Reading GPT table at lba={} (is_backup={})", lba, is_backup);
 
         // Read header
-        let handle = Self::get_buffer();
-        let mut header_buf: Buffer = handle.get_buffer();
-        self.read_sectors_async(lba, header_buf.clone())
-            .await
-            .map_err(|e| {
-                log!(
-                    "Failed to read GPT header at lba={}: {}",
-                    lba,
-                    e.to_string()
-                );
-                GPTErr::Io(e.to_string())
-            })?;
+        let header_buf = self.read_sector(lba).await.inspect_err(|e| {
+            log!("Failed to read GPT header at lba={}: {}", lba, e);
+        })?;
 
-        if !self.is_valid_header(&mut header_buf) {
-            log!("Invalid GPT header detected at lba={}", lba);
-            return Err(GPTErr::GPTCorrupted);
-        }
-
-        let result_header: GPTHeader =
-            *bytemuck::from_bytes(&header_buf[0..size_of::<GPTHeader>()]);
+        let result_header = Self::parse_header(&header_buf).inspect_err(|e| {
+            log!("Invalid GPT header detected at lba={}: {}", lba, e);
+        })?;
 
-        if !(result_header.entry_size / 128).is_power_of_two() {
+        if !result_header.is_entry_size_valid() {
             let entry_size = result_header.entry_size;
             log!("GPT entry size appears invalid: {}", entry_size);
             return Err(GPTErr::BadArrayEntrySize);
         }
 
-        let arr_block_count: i64 = ((result_header.entry_num * result_header.entry_size / 512)
-            + !(result_header.entry_num * result_header.entry_size).is_multiple_of(512) as u32)
-            .into();
+        let arr_block_count = result_header.array_sectors(self.sector_size) as i64;
 
         let arr_lba: i64 = if is_backup {
             -1 - arr_block_count
@@ -248,8 +624,8 @@ impl GptReader {
             arr_block_count
         );
 
-        let arr_sectors = (result_header.entry_num * result_header.entry_size / 512) as u16;
-        let arr_buf = vec![0u32; arr_sectors as usize * 512 / 4].into_boxed_slice();
+        let arr_buf =
+            vec![0u32; arr_block_count as usize * self.sector_size / 4].into_boxed_slice();
         let buffer: Buffer = arr_buf.into();
 
         self.read_sectors_async(arr_lba, buffer.clone())
@@ -263,17 +639,9 @@ impl GptReader {
                 GPTErr::Io(e.to_string())
             })?;
 
-        if !crypto::crc32::is_verified_crc32(buffer.deref(), result_header.array_crc32) {
-            let crc = result_header.array_crc32;
-            log!("GPT array CRC mismatch: expected={} (lba={})", crc, arr_lba);
-            return Err(GPTErr::GPTCorrupted);
-        }
+        Self::verify_array(&result_header, buffer.deref())?;
 
-        let result_array: Vec<GPTEntry> = buffer
-            .deref()
-            .chunks(result_header.entry_size as usize)
-            .map(|slice| *bytemuck::from_bytes(&slice[0..size_of::<GPTEntry>()]))
-            .collect();
+        let result_array = result_header.parse_entries(buffer.deref())?;
 
         let entry_num = result_header.entry_num;
         log!(
@@ -322,8 +690,389 @@ impl GptReader {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
     use crate::{end_test, ignore, test_name};
 
+    /// builds a valid header sector and a matching entry array with one entry in it
+    fn make_table(entry_size: u32, entry_num: u32) -> (Vec<u8>, Vec<u8>) {
+        let mut arr = vec![0u8; (entry_size * entry_num) as usize];
+        let mut entry = GPTEntry::zeroed();
+        entry.start_lba = 34;
+        entry.end_lba = 2047;
+        arr[0..size_of::<GPTEntry>()].copy_from_slice(bytemuck::bytes_of(&entry));
+
+        let mut header = GPTHeader::zeroed();
+        header.sig = *GPT_SIGNATURE;
+        header.revision = 0x00010000;
+        header.size = size_of::<GPTHeader>() as u32;
+        header.loc = 1;
+        header.array_start = 2;
+        header.entry_num = entry_num;
+        header.entry_size = entry_size;
+        header.array_crc32 = crypto::crc32::full_crc(&arr);
+        header.header_crc32 = crypto::crc32::full_crc(bytemuck::bytes_of(&header));
+
+        let mut header_buf = vec![0u8; SECTOR_SIZE];
+        header_buf[0..size_of::<GPTHeader>()].copy_from_slice(bytemuck::bytes_of(&header));
+
+        (header_buf, arr)
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn gpt_valid_table() {
+        test_name!("gpt validation accepts a well formed table");
+
+        let (header_buf, arr) = make_table(128, 128);
+        let header = GptReader::parse_header(&header_buf).expect("Header rejected");
+        GptReader::verify_array(&header, &arr).expect("Array rejected");
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn gpt_bad_header_crc() {
+        test_name!("gpt validation reports a bad header CRC");
+
+        let (mut header_buf, _) = make_table(128, 128);
+        // flip a bit in the revision field, which is covered by the header CRC
+        header_buf[8] ^= 1;
+
+        assert!(matches!(
+            GptReader::parse_header(&header_buf),
+            Err(GPTErr::BadHeaderCrc { expected, computed }) if expected != computed
+        ));
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn gpt_bad_array_crc() {
+        test_name!("gpt validation reports a bad array CRC");
+
+        let (header_buf, mut arr) = make_table(128, 128);
+        arr[40] ^= 0xFF;
+
+        let header = GptReader::parse_header(&header_buf).expect("Header rejected");
+        assert!(matches!(
+            GptReader::verify_array(&header, &arr),
+            Err(GPTErr::BadArrayCrc { .. })
+        ));
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn gpt_bad_signature() {
+        test_name!("gpt validation reports a bad signature");
+
+        let (mut header_buf, _) = make_table(128, 128);
+        header_buf[0] = b'X';
+
+        assert!(matches!(
+            GptReader::parse_header(&header_buf),
+            Err(GPTErr::BadSignature)
+        ));
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn gpt_large_entries() {
+        test_name!("gpt table with 256-byte entries");
+
+        let (header_buf, _) = make_table(256, 4);
+        let header = GptReader::parse_header(&header_buf).expect("Header rejected");
+        assert!(header.is_entry_size_valid());
+        assert_eq!(header.array_sectors(SECTOR_SIZE), 2);
+
+        let mut entry = GPTEntry::zeroed();
+        entry.unique_guid = [0xAB; 16];
+        entry.start_lba = 2048;
+        entry.end_lba = 4095;
+        entry.flags = 0x1;
+
+        let arr = header
+            .serialize_entries(&[GPTEntry::default(), entry], SECTOR_SIZE)
+            .expect("Failed to serialize entries");
+        assert_eq!(arr.len(), 2 * SECTOR_SIZE);
+        // the second entry starts at the declared stride, not right after the first one
+        assert_eq!(
+            &arr[256..256 + size_of::<GPTEntry>()],
+            bytemuck::bytes_of(&entry)
+        );
+        assert!(
+            arr[256 + size_of::<GPTEntry>()..512]
+                .iter()
+                .all(|&b| b == 0)
+        );
+
+        let entries = header.parse_entries(&arr).expect("Failed to parse entries");
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[1], entry);
+        assert!(entries[2].is_empty() && entries[3].is_empty());
+
+        let mut bad = header;
+        bad.entry_size = 384;
+        assert!(!bad.is_entry_size_valid());
+        bad.entry_size = 64;
+        assert!(!bad.is_entry_size_valid());
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn gpt_write_order() {
+        use crate::terminal::test::block_on;
+        use core::cell::RefCell;
+
+        test_name!("gpt write_table writes the backup before the primary");
+
+        let (header_buf, _) = make_table(128, 128);
+        let mut header = GptReader::parse_header(&header_buf).expect("Header rejected");
+        header.backup_loc = 0x1000;
+
+        let writes =
+            GptReader::table_writes(&header, &[], SECTOR_SIZE).expect("Failed to plan writes");
+
+        // mock device recording the lba of every write and None for every flush, in order
+        let log: RefCell<Vec<Option<i64>>> = RefCell::new(Vec::new());
+        block_on(GptReader::apply_table_writes(
+            0,
+            writes.clone(),
+            async |lba, _| {
+                log.borrow_mut().push(Some(lba));
+                Ok(())
+            },
+            async || {
+                log.borrow_mut().push(None);
+                Ok(())
+            },
+        ))
+        .expect("Write failed");
+        // each copy is flushed right after its sectors, the primary isn't touched before the
+        // backup is on the disk
+        assert_eq!(
+            log.into_inner(),
+            vec![Some(-33), Some(-1), None, Some(2), Some(1), None]
+        );
+
+        let copies: Vec<GptCopy> = writes
+            .iter()
+            .map(|w| match w {
+                GptTableWrite::Sectors { copy, .. } | GptTableWrite::Flush { copy } => *copy,
+            })
+            .collect();
+        let first_primary = copies
+            .iter()
+            .position(|c| *c == GptCopy::Primary)
+            .expect("No primary writes");
+        assert!(
+            copies[..first_primary]
+                .iter()
+                .all(|c| *c == GptCopy::Backup)
+        );
+        assert!(matches!(
+            writes[first_primary - 1],
+            GptTableWrite::Flush {
+                copy: GptCopy::Backup
+            }
+        ));
+
+        // the backup header points at its own array at the end of the disk
+        let GptTableWrite::Sectors { data, .. } = &writes[1] else {
+            panic!("Expected the backup header");
+        };
+        let backup = GptReader::parse_header(data).expect("Backup header rejected");
+        assert_eq!({ backup.loc }, 0x1000);
+        assert_eq!({ backup.array_start }, 0x1000 - 32);
+
+        // the primary array write fails
+        let mut count = 0;
+        let res = block_on(GptReader::apply_table_writes(
+            0,
+            writes,
+            async |_, _| {
+                count += 1;
+                if count == 3 {
+                    Err(GPTErr::DriveDidntRespond)
+                } else {
+                    Ok(())
+                }
+            },
+            async || Ok(()),
+        ));
+        assert!(matches!(
+            res,
+            Err(GPTErr::PartialTableWrite {
+                backup_written: true,
+                primary_written: false
+            })
+        ));
+
+        // the backup only counts once its flush went through, nothing after it is attempted
+        let writes =
+            GptReader::table_writes(&header, &[], SECTOR_SIZE).expect("Failed to plan writes");
+        let mut written = 0;
+        let res = block_on(GptReader::apply_table_writes(
+            0,
+            writes,
+            async |_, _| {
+                written += 1;
+                Ok(())
+            },
+            async || Err(GPTErr::DriveDidntRespond),
+        ));
+        assert!(matches!(
+            res,
+            Err(GPTErr::PartialTableWrite {
+                backup_written: false,
+                primary_written: false
+            })
+        ));
+        assert_eq!(written, 2);
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn gpt_4kn_layout() {
+        test_name!("gpt tables on 4k-native disks use 4096 byte sectors");
+
+        let (header_buf, _) = make_table(128, 128);
+        let header = GptReader::parse_header(&header_buf).expect("Header rejected");
+
+        // 16KiB of entries fit in 4 sectors instead of 32
+        assert_eq!(header.array_sectors(4096), 4);
+
+        let writes = GptReader::table_writes(&header, &[], 4096).expect("Failed to plan writes");
+        let sectors: Vec<(i64, usize)> = writes
+            .iter()
+            .filter_map(|w| match w {
+                GptTableWrite::Sectors { lba, data, .. } => Some((*lba, data.len())),
+                GptTableWrite::Flush { .. } => None,
+            })
+            .collect();
+        assert_eq!(
+            sectors,
+            vec![(-5, 4 * 4096), (-1, 4096), (2, 4 * 4096), (1, 4096)]
+        );
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn gpt_add_entry_notifies() {
+        use crate::terminal::test::block_on;
+
+        test_name!("adding a gpt entry notifies subscribers");
+
+        let (header_buf, arr) = make_table(128, 128);
+        let mut header = GptReader::parse_header(&header_buf).expect("Header rejected");
+        header.first_usable_block = 34;
+        header.last_usable_block = 0xFFFF;
+        header.backup_loc = 0x10000;
+        let mut entries = header.parse_entries(&arr).expect("Failed to parse entries");
+
+        let events = PARTITION_EVENTS.subscribe();
+
+        let mut entry = GPTEntry::zeroed();
+        entry.start_lba = 1024;
+        entry.end_lba = 2047;
+        assert!(matches!(
+            GptReader::insert_entry(&header, &mut entries, entry),
+            Err(GPTErr::OverlappingPartition)
+        ));
+
+        entry.start_lba = 2048;
+        entry.end_lba = 4095;
+        let idx = GptReader::insert_entry(&header, &mut entries, entry).expect("Insert failed");
+        assert_eq!(idx, 1);
+
+        let writes =
+            GptReader::table_writes(&header, &entries, SECTOR_SIZE).expect("Failed to plan writes");
+        block_on(GptReader::apply_table_writes(
+            3,
+            writes,
+            async |_, _| Ok(()),
+            async || Ok(()),
+        ))
+        .expect("Write failed");
+
+        assert_eq!(
+            block_on(events.recv()),
+            Some(PartitionChanged { device_idx: 3 })
+        );
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn gpt_resize_entry_notifies() {
+        use crate::terminal::test::block_on;
+
+        test_name!("resizing a gpt entry checks the new range and notifies subscribers");
+
+        let (header_buf, arr) = make_table(128, 128);
+        let mut header = GptReader::parse_header(&header_buf).expect("Header rejected");
+        header.first_usable_block = 34;
+        header.last_usable_block = 0xFFFF;
+        header.backup_loc = 0x10000;
+        let mut entries = header.parse_entries(&arr).expect("Failed to parse entries");
+
+        let mut entry = GPTEntry::zeroed();
+        entry.start_lba = 4096;
+        entry.end_lba = 8191;
+        let idx = GptReader::insert_entry(&header, &mut entries, entry).expect("Insert failed");
+
+        let events = PARTITION_EVENTS.subscribe();
+
+        // growing the first partition into the second one
+        assert!(matches!(
+            GptReader::change_entry_end(&header, &mut entries, 0, 4096),
+            Err(GPTErr::OverlappingPartition)
+        ));
+        assert!(matches!(
+            GptReader::change_entry_end(&header, &mut entries, idx, 0x10000),
+            Err(GPTErr::InvalidLBARange)
+        ));
+        assert!(matches!(
+            GptReader::change_entry_end(&header, &mut entries, 5, 100),
+            Err(GPTErr::EntryAlreadyEmpty)
+        ));
+
+        GptReader::change_entry_end(&header, &mut entries, 0, 4095).expect("Resize failed");
+        GptReader::change_entry_end(&header, &mut entries, idx, 0xFFFF).expect("Resize failed");
+        assert_eq!({ entries[0].end_lba }, 4095);
+        assert_eq!({ entries[idx].start_lba }, 4096);
+        assert_eq!({ entries[idx].end_lba }, 0xFFFF);
+
+        let writes =
+            GptReader::table_writes(&header, &entries, SECTOR_SIZE).expect("Failed to plan writes");
+        block_on(GptReader::apply_table_writes(
+            4,
+            writes,
+            async |_, _| Ok(()),
+            async || Ok(()),
+        ))
+        .expect("Write failed");
+
+        assert_eq!(
+            block_on(events.recv()),
+            Some(PartitionChanged { device_idx: 4 })
+        );
+
+        end_test!();
+    }
+
     #[test_case]
     #[allow(unreachable_code)]
     fn gptheader() {
@@ -0,0 +1,35 @@
+use limine::request::ModuleRequest;
+
+use crate::{drivers::fs::tmpfs::Tmpfs, log};
+
+#[used]
+#[unsafe(link_section = ".requests")]
+pub static MODULE_REQUEST: ModuleRequest = ModuleRequest::new();
+
+/// the first boot module is taken as the initrd, limine maps it in the higher half so it can be
+/// read in place
+pub fn initrd_bytes() -> Option<&'static [u8]> {
+    let module = MODULE_REQUEST.get_response()?.modules().first()?;
+
+    Some(unsafe { core::slice::from_raw_parts(module.addr(), module.size() as usize) })
+}
+
+/// unpacks the initrd, a tar archive, into a tmpfs. None if there's no module or it's broken
+pub fn load_initrd() -> Option<Tmpfs> {
+    let archive = initrd_bytes()?;
+
+    match Tmpfs::from_tar(archive) {
+        Ok(fs) => {
+            log!(
+                "Unpacked a {} byte initrd into {} nodes",
+                archive.len(),
+                fs.nodes.len()
+            );
+            Some(fs)
+        }
+        Err(e) => {
+            log!("Failed to unpack the initrd: {:?}", e);
+            None
+        }
+    }
+}
@@ -1,8 +1,13 @@
+pub mod block_cache;
 pub mod buffer;
 pub mod fs;
 pub mod gpt;
+pub mod initrd;
 pub mod keyboard;
 pub mod path;
 pub mod perms;
+pub mod pipe;
+#[cfg(test)]
+pub mod ram_disk;
 pub mod storage;
 pub mod vfs;
@@ -159,6 +159,12 @@ impl Path {
         Path { raw: result }
     }
 
+    /// Resolves a path that may be relative against this directory
+    /// '..' at the root stays at the root
+    pub fn resolve(&self, path: &str) -> Path {
+        self.join(path).normalize()
+    }
+
     /// Returns true (always, for compatibility)
     pub fn is_absolute(&self) -> bool {
         true
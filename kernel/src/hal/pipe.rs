@@ -0,0 +1,128 @@
+use core::{
+    future::poll_fn,
+    task::{Poll, Waker},
+};
+
+use alloc::{collections::vec_deque::VecDeque, sync::Arc};
+
+use crate::{arch::x86_64::err::ErrNo, ejcineque::sync::spin::SpinMutex};
+
+/// same as a linux pipe page
+pub const PIPE_CAPACITY: usize = 4096;
+
+#[derive(Debug)]
+struct PipeInner {
+    buffer: VecDeque<u8>,
+    capacity: usize,
+    read_wakers: VecDeque<Waker>,
+    write_wakers: VecDeque<Waker>,
+    reader_closed: bool,
+    writer_closed: bool,
+}
+
+fn wake_all(wakers: &mut VecDeque<Waker>) {
+    while let Some(waker) = wakers.pop_front() {
+        waker.wake();
+    }
+}
+
+#[derive(Debug)]
+pub struct PipeReader {
+    pipe: Arc<SpinMutex<PipeInner>>,
+}
+
+impl PipeReader {
+    /// waits until something is buffered, 0 means the write end is closed and the pipe is drained
+    pub async fn read(&self, buf: &mut [u8]) -> usize {
+        if buf.is_empty() {
+            return 0;
+        }
+
+        poll_fn(|cx| {
+            let mut pipe = self.pipe.lock();
+
+            if pipe.buffer.is_empty() {
+                if pipe.writer_closed {
+                    return Poll::Ready(0);
+                }
+
+                pipe.read_wakers.push_back(cx.waker().clone());
+                return Poll::Pending;
+            }
+
+            let len = buf.len().min(pipe.buffer.len());
+            for (dst, src) in buf.iter_mut().zip(pipe.buffer.drain(..len)) {
+                *dst = src;
+            }
+
+            wake_all(&mut pipe.write_wakers);
+            Poll::Ready(len)
+        })
+        .await
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        let mut pipe = self.pipe.lock();
+        pipe.reader_closed = true;
+        wake_all(&mut pipe.write_wakers);
+    }
+}
+
+#[derive(Debug)]
+pub struct PipeWriter {
+    pipe: Arc<SpinMutex<PipeInner>>,
+}
+
+impl PipeWriter {
+    /// waits for room until the whole buffer is in the pipe, fails once nobody can read it
+    pub async fn write(&self, buf: &[u8]) -> Result<usize, ErrNo> {
+        let mut written = 0;
+
+        poll_fn(|cx| {
+            let mut pipe = self.pipe.lock();
+
+            if pipe.reader_closed {
+                return Poll::Ready(Err(ErrNo::BrokenPipe));
+            }
+
+            let len = (pipe.capacity - pipe.buffer.len()).min(buf.len() - written);
+            pipe.buffer.extend(&buf[written..written + len]);
+            written += len;
+
+            if len > 0 {
+                wake_all(&mut pipe.read_wakers);
+            }
+
+            if written == buf.len() {
+                Poll::Ready(Ok(written))
+            } else {
+                pipe.write_wakers.push_back(cx.waker().clone());
+                Poll::Pending
+            }
+        })
+        .await
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        let mut pipe = self.pipe.lock();
+        pipe.writer_closed = true;
+        wake_all(&mut pipe.read_wakers);
+    }
+}
+
+pub fn pipe(capacity: usize) -> (PipeReader, PipeWriter) {
+    let pipe = Arc::new(SpinMutex::new(PipeInner {
+        buffer: VecDeque::with_capacity(capacity),
+        capacity,
+        read_wakers: VecDeque::new(),
+        write_wakers: VecDeque::new(),
+        reader_closed: false,
+        writer_closed: false,
+    }));
+
+    (PipeReader { pipe: pipe.clone() }, PipeWriter { pipe })
+}
@@ -0,0 +1,95 @@
+//! disks that only live in memory. A test registers one under a guid and the storage layer serves
+//! it before looking at the real drives, so everything above it runs unchanged
+
+use alloc::{collections::btree_map::BTreeMap, string::ToString, vec, vec::Vec};
+use spin::Mutex;
+
+use crate::{
+    crypto::guid::Guid,
+    hal::storage::{HalStorageOperationErr, check_transfer_len},
+};
+
+pub struct RamDisk {
+    pub data: Vec<u8>,
+    pub sector_size: usize,
+    /// the first lba and the length in bytes of every write, oldest first
+    pub writes: Vec<(i64, usize)>,
+    pub flushes: usize,
+    /// how many of `writes` the last flush covered
+    pub flushed_writes: usize,
+}
+
+impl RamDisk {
+    fn range(
+        &self,
+        lba: i64,
+        len: usize,
+    ) -> Result<core::ops::Range<usize>, HalStorageOperationErr> {
+        check_transfer_len(len, self.sector_size)?;
+
+        let start = usize::try_from(lba)
+            .map_err(|_| HalStorageOperationErr::DriveErr("negative lba".to_string()))?
+            * self.sector_size;
+        if start + len > self.data.len() {
+            return Err(HalStorageOperationErr::DriveErr(
+                "lba past the end of the disk".to_string(),
+            ));
+        }
+
+        Ok(start..start + len)
+    }
+}
+
+static RAM_DISKS: Mutex<BTreeMap<Guid, RamDisk>> = Mutex::new(BTreeMap::new());
+
+/// a zeroed disk of `sectors` sectors, replaces whatever was registered under the guid
+pub fn register(guid: Guid, sectors: usize, sector_size: usize) {
+    RAM_DISKS.lock().insert(
+        guid,
+        RamDisk {
+            data: vec![0u8; sectors * sector_size],
+            sector_size,
+            writes: Vec::new(),
+            flushes: 0,
+            flushed_writes: 0,
+        },
+    );
+}
+
+pub fn unregister(guid: Guid) -> Option<RamDisk> {
+    RAM_DISKS.lock().remove(&guid)
+}
+
+/// None when nothing is registered under the guid
+pub fn with_disk<R>(guid: Guid, f: impl FnOnce(&mut RamDisk) -> R) -> Option<R> {
+    RAM_DISKS.lock().get_mut(&guid).map(f)
+}
+
+pub fn sector_size(guid: Guid) -> Option<usize> {
+    with_disk(guid, |disk| disk.sector_size)
+}
+
+pub fn read(guid: Guid, lba: i64, buffer: &mut [u8]) -> Option<Result<(), HalStorageOperationErr>> {
+    with_disk(guid, |disk| {
+        let range = disk.range(lba, buffer.len())?;
+        buffer.copy_from_slice(&disk.data[range]);
+        Ok(())
+    })
+}
+
+pub fn write(guid: Guid, lba: i64, buffer: &[u8]) -> Option<Result<(), HalStorageOperationErr>> {
+    with_disk(guid, |disk| {
+        let range = disk.range(lba, buffer.len())?;
+        disk.data[range].copy_from_slice(buffer);
+        disk.writes.push((lba, buffer.len()));
+        Ok(())
+    })
+}
+
+pub fn flush(guid: Guid) -> Option<Result<(), HalStorageOperationErr>> {
+    with_disk(guid, |disk| {
+        disk.flushes += 1;
+        disk.flushed_writes = disk.writes.len();
+        Ok(())
+    })
+}
@@ -1,12 +1,14 @@
 use core::fmt::Debug;
 use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 use crate::arch::x86_64::pcie::{
-    MassStorageControllerSubClass, PciBaseClass, PciDevice, SataProgIf,
+    Bar, IdeProgIf, MassStorageControllerSubClass, PciBaseClass, PcieDevice, SataProgIf,
 };
 use crate::args::ArgsRes;
 use crate::crypto::guid::Guid;
-use crate::drivers::ata::pata::PataDevice;
+use crate::drivers::ata::pata::dma::{BM_SECONDARY_OFFSET, PATA_BENCHMARK, PataDma};
+use crate::drivers::ata::pata::{PATA_PRIMARY_BASE, PATA_SECONDARY_BASE, PataDevice};
 use crate::drivers::ata::sata::AhciSata;
 use crate::drivers::ata::sata::ahci::AhciHba;
 use crate::drivers::ata::sata::task::CUR_AHCI_IDX;
@@ -16,14 +18,15 @@ use crate::ejcineque::sync::mpsc::unbounded::{
 };
 use crate::ejcineque::sync::mutex::Mutex;
 use crate::ejcineque::sync::spsc::cell::{SpscCellSetter, spsc_cells};
+use crate::hal::block_cache::{BlockBackend, BlockCache, DEFAULT_CACHE_CAPACITY};
 use crate::hal::buffer::Buffer;
 use crate::hal::gpt::GptReader;
 use crate::hal::vfs::spawn_vfs_task;
-use crate::{SPAWNER, log};
+use crate::{SPAWNER, log, serial_iprint};
 use alloc::collections::btree_map::BTreeMap;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
-use alloc::{boxed::Box, string::String};
+use alloc::{boxed::Box, format, string::String};
 use once_cell_no_std::OnceCell;
 use thiserror::Error;
 
@@ -31,7 +34,7 @@ use thiserror::Error;
 pub enum DeviceType {
     Unidentified,
     PataPio(PataDevice),
-    PataDma,
+    PataDma(PataDma),
     SataAhci(AhciHba),
     Nvme,
 }
@@ -39,6 +42,7 @@ pub enum DeviceType {
 pub const PRIMARY: usize = 0;
 pub const SECONDARY: usize = 1;
 
+/// the sector size of every device that doesn't report its own, 4Kn disks use 4096
 pub const SECTOR_SIZE: usize = 512;
 
 #[derive(Debug, Error)]
@@ -57,6 +61,12 @@ pub enum IoErr {
     FlushCacheTimeout,
     #[error("Input buffer is too small")]
     InputTooSmall,
+    #[error("Input buffer is too large")]
+    InputTooLarge,
+    #[error("The buffer is out of the DMA engine's reach")]
+    DmaUnreachable,
+    #[error("The DMA transfer failed")]
+    DmaFailed,
 }
 
 #[derive(Debug)]
@@ -64,6 +74,10 @@ pub struct HalStorageDevice {
     pub tx: UnboundedSender<HalStorageOperation>,
     pub rx: UnboundedReceiver<HalStorageOperation>,
     pub device_inner: Arc<Mutex<Box<dyn HalBlockDevice>>>,
+    /// every transfer has to be a multiple of this, lbas count in these units
+    pub logical_sector_size: usize,
+    /// write-back cache of logical sectors sitting in front of the driver
+    pub cache: Mutex<BlockCache>,
 }
 
 #[derive(Debug)]
@@ -136,12 +150,188 @@ macro_rules! get_storage_devices_by_guid {
 impl HalStorageDevice {
     pub fn sata_ahci(sata: AhciSata) -> Self {
         let (tx, rx) = unbounded_channel::<HalStorageOperation>();
+        // the drive was identified in init()
+        let logical_sector_size = sata.drive_info.logical_sector_size as usize;
+
         HalStorageDevice {
             tx,
             rx,
             device_inner: Arc::new(Mutex::new(Box::new(sata))),
+            logical_sector_size,
+            cache: Mutex::new(BlockCache::new(DEFAULT_CACHE_CAPACITY, logical_sector_size)),
+        }
+    }
+
+    pub fn pata_pio(pata: PataDevice) -> Self {
+        let (tx, rx) = unbounded_channel::<HalStorageOperation>();
+
+        HalStorageDevice {
+            tx,
+            rx,
+            device_inner: Arc::new(Mutex::new(Box::new(pata))),
+            logical_sector_size: SECTOR_SIZE,
+            cache: Mutex::new(BlockCache::new(DEFAULT_CACHE_CAPACITY, SECTOR_SIZE)),
         }
     }
+
+    pub fn pata_dma(pata: PataDma) -> Self {
+        let (tx, rx) = unbounded_channel::<HalStorageOperation>();
+
+        HalStorageDevice {
+            tx,
+            rx,
+            device_inner: Arc::new(Mutex::new(Box::new(pata))),
+            logical_sector_size: SECTOR_SIZE,
+            cache: Mutex::new(BlockCache::new(DEFAULT_CACHE_CAPACITY, SECTOR_SIZE)),
+        }
+    }
+
+    pub fn logical_sector_size(&self) -> usize {
+        self.logical_sector_size
+    }
+
+    fn backend(&self) -> DeviceBackend {
+        DeviceBackend {
+            tx: self.tx.clone(),
+        }
+    }
+}
+
+/// hands the cache's misses and write-backs to the driver task
+struct DeviceBackend {
+    tx: UnboundedSender<HalStorageOperation>,
+}
+
+impl BlockBackend for DeviceBackend {
+    async fn read_blocks(
+        &mut self,
+        lba: i64,
+        buffer: &mut [u8],
+    ) -> Result<(), HalStorageOperationErr> {
+        let (getter, setter) = spsc_cells::<Result<(), HalStorageOperationErr>>();
+
+        self.tx.send(HalStorageOperation::Read {
+            buffer: Buffer {
+                inner: buffer.as_mut_ptr(),
+                len: buffer.len(),
+            },
+            lba,
+            setter,
+        });
+
+        getter.get().await
+    }
+
+    async fn write_blocks(
+        &mut self,
+        lba: i64,
+        buffer: &[u8],
+    ) -> Result<(), HalStorageOperationErr> {
+        let (getter, setter) = spsc_cells::<Result<(), HalStorageOperationErr>>();
+
+        self.tx.send(HalStorageOperation::Write {
+            buffer: buffer.into(),
+            lba,
+            setter,
+        });
+
+        getter.get().await
+    }
+}
+
+pub fn logical_sector_size_by_idx(index: usize) -> Result<usize, HalStorageOperationErr> {
+    STORAGE_DEVICES_BY_IDX
+        .get()
+        .and_then(|devices| devices.get(&StorageDeviceIdx(index)))
+        .map(HalStorageDevice::logical_sector_size)
+        .ok_or(HalStorageOperationErr::DriveDidntRespond)
+}
+
+pub async fn logical_sector_size_by_guid(guid: Guid) -> Result<usize, HalStorageOperationErr> {
+    #[cfg(test)]
+    if let Some(sector_size) = crate::hal::ram_disk::sector_size(guid) {
+        return Ok(sector_size);
+    }
+
+    let idx = get_storage_devices_by_guid!()
+        .lock()
+        .await
+        .get(&guid)
+        .ok_or(HalStorageOperationErr::DriveDidntRespond)?
+        .0;
+
+    logical_sector_size_by_idx(idx)
+}
+
+/// transfers have to cover whole sectors
+pub fn check_transfer_len(len: usize, sector_size: usize) -> Result<(), HalStorageOperationErr> {
+    if len == 0 || !len.is_multiple_of(sector_size) {
+        return Err(HalStorageOperationErr::UnalignedBuffer(len, sector_size));
+    }
+
+    Ok(())
+}
+
+/// logs every sector read and write to serial when set, off by default
+static STORAGE_TRACE: AtomicBool = AtomicBool::new(false);
+const TRACE_PREVIEW_LEN: usize = 16;
+
+pub fn set_storage_tracing(enabled: bool) {
+    STORAGE_TRACE.store(enabled, Ordering::Release);
+}
+
+pub fn is_storage_tracing() -> bool {
+    STORAGE_TRACE.load(Ordering::Acquire)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageTraceOp {
+    Read,
+    Write,
+}
+
+/// writes a trace line straight to serial, going through the terminal could recurse into the
+/// storage stack. Returns the line for inspection, None when tracing is off
+pub fn trace_sectors(
+    op: StorageTraceOp,
+    index: usize,
+    lba: i64,
+    buffer: &[u8],
+    sector_size: usize,
+) -> Option<String> {
+    if !is_storage_tracing() {
+        return None;
+    }
+
+    let mut preview = String::new();
+    for byte in buffer.iter().take(TRACE_PREVIEW_LEN) {
+        preview += &format!("{:02x}", byte);
+    }
+
+    let line = format!(
+        "[storage] {:?} dev={} lba={} count={} data={}{}",
+        op,
+        index,
+        lba,
+        buffer.len().div_ceil(sector_size),
+        preview,
+        if buffer.len() > TRACE_PREVIEW_LEN {
+            ".."
+        } else {
+            ""
+        }
+    );
+    serial_iprint!("{}\n", line);
+
+    Some(line)
+}
+
+/// indices of every registered device, empty before the drives are identified
+pub fn storage_device_indices() -> Vec<usize> {
+    STORAGE_DEVICES_BY_IDX
+        .get()
+        .map(|devices| devices.keys().map(|idx| idx.0).collect())
+        .unwrap_or_default()
 }
 
 pub async fn get_identify_data(idx: usize) -> Result<HalIdentifyData, HalStorageOperationErr> {
@@ -163,6 +353,11 @@ pub async fn read_sectors_by_guid(
     buffer: Buffer,
     lba: i64,
 ) -> Result<(), HalStorageOperationErr> {
+    #[cfg(test)]
+    if let Some(res) = crate::hal::ram_disk::read(guid, lba, &mut buffer.clone()) {
+        return res;
+    }
+
     read_sectors_by_idx(
         get_storage_devices_by_guid!()
             .lock()
@@ -181,21 +376,24 @@ pub async fn read_sectors_by_idx(
     buffer: Buffer,
     lba: i64,
 ) -> Result<(), HalStorageOperationErr> {
-    let sender = get_storage_devices!()
+    let device = get_storage_devices!()
         .get(&StorageDeviceIdx(index))
-        .ok_or(HalStorageOperationErr::DriveDidntRespond)?
-        .tx
-        .clone();
-
-    let (getter, setter) = spsc_cells::<Result<(), HalStorageOperationErr>>();
-
-    sender.send(HalStorageOperation::Read {
-        buffer,
-        lba,
-        setter,
-    });
+        .ok_or(HalStorageOperationErr::DriveDidntRespond)?;
+    let sector_size = device.logical_sector_size();
+    check_transfer_len(buffer.len(), sector_size)?;
+
+    let mut data = buffer.clone();
+    let res = device
+        .cache
+        .lock()
+        .await
+        .read(&mut device.backend(), lba, &mut data)
+        .await;
+    if res.is_ok() {
+        trace_sectors(StorageTraceOp::Read, index, lba, &buffer, sector_size);
+    }
 
-    getter.get().await
+    res
 }
 
 pub async fn write_sectors_by_guid(
@@ -203,6 +401,11 @@ pub async fn write_sectors_by_guid(
     buffer: Buffer,
     lba: i64,
 ) -> Result<(), HalStorageOperationErr> {
+    #[cfg(test)]
+    if let Some(res) = crate::hal::ram_disk::write(guid, lba, &buffer) {
+        return res;
+    }
+
     write_sectors_by_idx(
         get_storage_devices_by_guid!()
             .lock()
@@ -221,23 +424,90 @@ pub async fn write_sectors_by_idx(
     buffer: Buffer,
     lba: i64,
 ) -> Result<(), HalStorageOperationErr> {
-    let sender = get_storage_devices!()
+    let device = get_storage_devices!()
         .get(&StorageDeviceIdx(index))
-        .ok_or(HalStorageOperationErr::DriveDidntRespond)?
-        .tx
-        .clone();
+        .ok_or(HalStorageOperationErr::DriveDidntRespond)?;
+    let sector_size = device.logical_sector_size();
+    check_transfer_len(buffer.len(), sector_size)?;
+
+    trace_sectors(StorageTraceOp::Write, index, lba, &buffer, sector_size);
+
+    device
+        .cache
+        .lock()
+        .await
+        .write(&mut device.backend(), lba, &buffer)
+        .await
+}
+
+/// writes the dirty cached sectors back and waits until the drive's write cache is on the physical
+/// media
+pub async fn flush_by_idx(index: usize) -> Result<(), HalStorageOperationErr> {
+    let device = get_storage_devices!()
+        .get(&StorageDeviceIdx(index))
+        .ok_or(HalStorageOperationErr::DriveDidntRespond)?;
+
+    device
+        .cache
+        .lock()
+        .await
+        .flush(&mut device.backend())
+        .await?;
 
     let (getter, setter) = spsc_cells::<Result<(), HalStorageOperationErr>>();
 
-    sender.send(HalStorageOperation::Write {
-        buffer,
-        lba,
-        setter,
-    });
+    device.tx.send(HalStorageOperation::Flush { setter });
 
     getter.get().await
 }
 
+pub async fn flush_by_guid(guid: Guid) -> Result<(), HalStorageOperationErr> {
+    #[cfg(test)]
+    if let Some(res) = crate::hal::ram_disk::flush(guid) {
+        return res;
+    }
+
+    flush_by_idx(
+        get_storage_devices_by_guid!()
+            .lock()
+            .await
+            .get(&guid)
+            .ok_or(HalStorageOperationErr::DriveDidntRespond)?
+            .0,
+    )
+    .await
+}
+
+/// flushes every drive, one failing doesn't stop the others. Returns the first error
+pub async fn flush_all() -> Result<(), HalStorageOperationErr> {
+    let mut res = Ok(());
+
+    for idx in storage_device_indices() {
+        if let Err(err) = flush_by_idx(idx).await {
+            log!("Failed to flush drive {}: {}", idx, err);
+            res = res.and(Err(err));
+        }
+    }
+
+    res
+}
+
+/// flushes the old cache before swapping in an empty one holding `blocks` sectors
+pub async fn set_cache_capacity_by_idx(
+    index: usize,
+    blocks: usize,
+) -> Result<(), HalStorageOperationErr> {
+    let device = get_storage_devices!()
+        .get(&StorageDeviceIdx(index))
+        .ok_or(HalStorageOperationErr::DriveDidntRespond)?;
+
+    let mut cache = device.cache.lock().await;
+    cache.flush(&mut device.backend()).await?;
+    *cache = BlockCache::new(blocks, device.logical_sector_size());
+
+    Ok(())
+}
+
 #[derive(Debug, Clone, Error)]
 pub enum HalStorageOperationErr {
     #[error("Drive didn't respond")]
@@ -248,31 +518,86 @@ pub enum HalStorageOperationErr {
     NoEnoughSpace,
     #[error("Internal error at {0}, {1}: {2}")]
     Internal(u32, u32, String),
+    #[error("A buffer of {0} bytes isn't a whole number of {1} byte sectors")]
+    UnalignedBuffer(usize, usize),
 }
 
-pub fn identify_storage_devices(
-    device_tree: &mut BTreeMap<u8, BTreeMap<u8, BTreeMap<u8, Vec<PciDevice>>>>,
-) {
-    let mut storage_devices_list: Vec<HalStorageDevice> = Vec::new();
+/// the channels are assumed to be in compatibility mode, on the legacy ports and irqs 14/15
+fn identify_ide_controller(device: &PcieDevice) -> Vec<HalStorageDevice> {
+    const BUS_MASTER_ENABLE: u16 = 0x1 << 2;
 
-    if let Some(m) = device_tree.get(&(PciBaseClass::MassStorage as u8)) {
-        for device in m.values().flatten().flat_map(|(_, b)| b) {
-            if device.header_partial.subclass == MassStorageControllerSubClass::Sata as u8
-                && device.header_partial.prog_if == SataProgIf::Ahci as u8
-            {
-                log!("Initializing AHCI..");
-                let idx = CUR_AHCI_IDX.fetch_add(1, core::sync::atomic::Ordering::AcqRel);
-                if idx >= 8 {
-                    log!("Too many AHCI devices, skipping");
-                }
+    let mut header = device.header();
 
-                let mut ahci = AhciHba::new(device.address, idx as usize);
+    let bus_master_base = match device.bars[4] {
+        Bar::Io(port) if device.prog_if & IdeProgIf::BusMastering as u8 != 0 => Some(port),
+        _ => None,
+    };
+
+    if bus_master_base.is_some() {
+        header.write_command(header.read_command() | BUS_MASTER_ENABLE);
+    }
 
-                for device in ahci.init().drain(0..) {
-                    let device = HalStorageDevice::sata_ahci(device);
-                    storage_devices_list.push(device)
+    let mut devices = Vec::new();
+
+    for (port, bus_master_offset) in [
+        (PATA_PRIMARY_BASE, 0),
+        (PATA_SECONDARY_BASE, BM_SECONDARY_OFFSET),
+    ] {
+        let mut pata = PataDevice::new(port);
+        if pata.identify().is_err() {
+            continue;
+        }
+
+        let pata = match bus_master_base {
+            Some(base) if pata.udma_supported => {
+                match PataDma::new(pata, base + bus_master_offset) {
+                    Ok(dma) => {
+                        devices.push(HalStorageDevice::pata_dma(dma));
+                        continue;
+                    }
+                    Err(pata) => pata,
                 }
             }
+            _ => pata,
+        };
+
+        log!("Falling back to PIO for the drive at port {:#x}", port);
+        devices.push(HalStorageDevice::pata_pio(pata));
+    }
+
+    devices
+}
+
+pub fn identify_storage_devices(devices: &[PcieDevice]) {
+    let mut storage_devices_list: Vec<HalStorageDevice> = Vec::new();
+
+    for device in devices
+        .iter()
+        .filter(|device| device.class_code == PciBaseClass::MassStorage as u8)
+    {
+        if device.subclass == MassStorageControllerSubClass::Sata as u8
+            && device.prog_if == SataProgIf::Ahci as u8
+        {
+            let Bar::Memory(abar) = device.bars[5] else {
+                log!("AHCI controller without an ABAR, skipping");
+                continue;
+            };
+
+            log!("Initializing AHCI..");
+            let idx = CUR_AHCI_IDX.fetch_add(1, core::sync::atomic::Ordering::AcqRel);
+            if idx >= 8 {
+                log!("Too many AHCI devices, skipping");
+            }
+
+            let mut ahci = AhciHba::new(device.address, abar, idx as usize);
+
+            for device in ahci.init().drain(0..) {
+                let device = HalStorageDevice::sata_ahci(device);
+                storage_devices_list.push(device)
+            }
+        } else if device.subclass == MassStorageControllerSubClass::Ide as u8 {
+            log!("Initializing IDE..");
+            storage_devices_list.extend(identify_ide_controller(device));
         }
     }
 
@@ -291,6 +616,7 @@ pub fn identify_storage_devices(
 
 pub async fn run_storage_devices(args: ArgsRes) {
     let mut storage_devices_by_guid_list: BTreeMap<Guid, StorageDeviceIdx> = BTreeMap::new();
+    PATA_BENCHMARK.store(args.pata_bench, Ordering::Release);
 
     for device in STORAGE_DEVICES_BY_IDX.get().expect("Rust error") {
         let device_inner = device.1.device_inner.clone();
@@ -320,3 +646,63 @@ pub async fn run_storage_devices(args: ArgsRes) {
     yield_now().await;
     log!("VFS task launched");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{end_test, test_name};
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn storage_trace_line() {
+        test_name!("storage tracing reports the lba");
+
+        let buf = [0xABu8; SECTOR_SIZE * 2];
+
+        set_storage_tracing(false);
+        assert!(trace_sectors(StorageTraceOp::Read, 0, 42, &buf, SECTOR_SIZE).is_none());
+
+        set_storage_tracing(true);
+        let line =
+            trace_sectors(StorageTraceOp::Read, 1, 42, &buf, SECTOR_SIZE).expect("No trace line");
+        set_storage_tracing(false);
+
+        assert!(line.contains("Read"));
+        assert!(line.contains("dev=1"));
+        assert!(line.contains("lba=42 "));
+        assert!(line.contains("count=2"));
+        assert!(line.contains("abababab"));
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn storage_4kn_transfers() {
+        test_name!("transfers on a 4Kn drive have to be whole 4096 byte sectors");
+
+        const NATIVE_4K: usize = 4096;
+
+        assert!(check_transfer_len(NATIVE_4K, NATIVE_4K).is_ok());
+        assert!(check_transfer_len(NATIVE_4K * 3, NATIVE_4K).is_ok());
+        assert!(matches!(
+            check_transfer_len(SECTOR_SIZE, NATIVE_4K),
+            Err(HalStorageOperationErr::UnalignedBuffer(
+                SECTOR_SIZE,
+                NATIVE_4K
+            ))
+        ));
+        assert!(check_transfer_len(0, NATIVE_4K).is_err());
+        // the same buffer is fine on a 512 byte drive
+        assert!(check_transfer_len(SECTOR_SIZE, SECTOR_SIZE).is_ok());
+
+        let buf = [0u8; NATIVE_4K * 2];
+        set_storage_tracing(true);
+        let line =
+            trace_sectors(StorageTraceOp::Write, 0, 7, &buf, NATIVE_4K).expect("No trace line");
+        set_storage_tracing(false);
+        assert!(line.contains("count=2"));
+
+        end_test!();
+    }
+}
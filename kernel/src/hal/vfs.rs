@@ -1,22 +1,31 @@
 use crate::{
     crypto::guid::Guid,
-    drivers::fs::ext2::structs::Ext2Fs,
+    drivers::fs::{devfs::Devfs, ext2::structs::Ext2Fs, procfs::Procfs, tmpfs::Tmpfs},
     ejcineque::sync::{
         mpsc::unbounded::{UnboundedSender, unbounded_channel},
+        spin::SpinMutex,
         spsc::cell::{SpscCellSetter, spsc_cells},
     },
     get_storage_devices_by_guid,
-    hal::gpt::GptReader,
-    log,
+    hal::{
+        gpt::GptReader,
+        initrd::load_initrd,
+        pipe::{PIPE_CAPACITY, PipeReader, PipeWriter, pipe},
+        storage,
+    },
+    iprint, log,
 };
-use alloc::collections::btree_map::BTreeMap;
+use alloc::{collections::btree_map::BTreeMap, string::String, sync::Arc};
+use core::sync::atomic::{AtomicI64, Ordering};
 use once_cell_no_std::OnceCell;
 
 use crate::{
     arch::x86_64::err::ErrNo,
     hal::{
         buffer::Buffer,
-        fs::{FileSystem, HalIOCtx, HalInode, OpenFlags},
+        fs::{
+            FileSystem, HalFs, HalFsIOErr, HalInode, OpenFile, OpenFlags, OpenFlagsValue, SeekFrom,
+        },
         path::Path,
     },
 };
@@ -58,6 +67,33 @@ pub enum VfsOperationType {
 
     Close {
         inode_id: i64,
+        cell: SpscCellSetter<Result<i64, ErrNo>>,
+    },
+
+    Mkdir {
+        path: Path,
+        perms: i32,
+        cell: SpscCellSetter<Result<i64, ErrNo>>,
+    },
+
+    Rmdir {
+        path: Path,
+        cell: SpscCellSetter<Result<i64, ErrNo>>,
+    },
+
+    Unlink {
+        path: Path,
+        cell: SpscCellSetter<Result<i64, ErrNo>>,
+    },
+
+    ReadDir {
+        inode_id: i64,
+        buffer: Buffer,
+        cell: SpscCellSetter<Result<i64, ErrNo>>,
+    },
+
+    Sync {
+        cell: SpscCellSetter<Result<i64, ErrNo>>,
     },
 }
 
@@ -66,8 +102,7 @@ pub struct VfsOperation {
 }
 
 pub struct HalOpenedInode {
-    pub inode: HalInode,
-    pub ctx: HalIOCtx,
+    pub file: OpenFile,
     pub count: usize,
     pub mount_point_id: i64,
 }
@@ -75,8 +110,7 @@ pub struct HalOpenedInode {
 impl HalOpenedInode {
     pub fn from_inode(inode: HalInode, id: i64) -> Self {
         Self {
-            inode,
-            ctx: HalIOCtx::new(),
+            file: OpenFile::new(inode),
             count: 1,
             mount_point_id: id,
         }
@@ -85,6 +119,26 @@ impl HalOpenedInode {
 
 pub static VFS_SENDER: OnceCell<UnboundedSender<VfsOperation>> = OnceCell::new();
 
+pub const STDIN_FD: i64 = 0;
+pub const STDOUT_FD: i64 = 1;
+pub const STDERR_FD: i64 = 2;
+
+/// opened inodes and pipes share one fd space, the standard streams take the first three
+static FD_COUNTER: AtomicI64 = AtomicI64::new(STDERR_FD + 1);
+
+fn is_std_stream(fd: i64) -> bool {
+    (STDIN_FD..=STDERR_FD).contains(&fd)
+}
+
+#[derive(Debug, Clone)]
+pub enum PipeEnd {
+    Read(Arc<PipeReader>),
+    Write(Arc<PipeWriter>),
+}
+
+/// pipes bypass the vfs task, a read waiting for data would stall every other operation
+pub static PIPES: SpinMutex<BTreeMap<i64, PipeEnd>> = SpinMutex::new(BTreeMap::new());
+
 #[derive(Default)]
 pub struct MountPointArray {
     pub mount_points: BTreeMap<i64, FileSystem>,
@@ -118,6 +172,36 @@ impl MountPointArray {
         }
     }
 
+    /// the mount point with the longest prefix of the path, a prefix only counts if it ends at a
+    /// component boundary
+    pub fn find_mount_point(&self, path: &Path) -> Option<i64> {
+        self.path_to_id_map
+            .iter()
+            .filter(|(mount, _)| {
+                let mount = mount.as_str();
+                match path.as_str().strip_prefix(mount) {
+                    Some(rest) => mount.ends_with('/') || rest.is_empty() || rest.starts_with('/'),
+                    None => false,
+                }
+            })
+            .max_by_key(|(mount, _)| mount.as_str().len())
+            .map(|(_, id)| *id)
+    }
+
+    /// moves a mount to another path, files opened through it stay valid since the id is kept
+    pub fn move_mount(&mut self, from: &Path, to: Path) -> bool {
+        let Some(id) = self.path_to_id_map.remove(from) else {
+            return false;
+        };
+
+        if let Some(fs) = self.mount_points.get_mut(&id) {
+            fs.mounted_at = to.clone();
+        }
+        self.path_to_id_map.insert(to, id);
+
+        true
+    }
+
     pub fn contains_path(&self, path: &Path) -> bool {
         match self.path_to_id_map.get(path) {
             Some(id) => self.mount_points.get(id).is_some(),
@@ -127,7 +211,7 @@ impl MountPointArray {
 }
 
 macro_rules! find_inode_and_process {
-    {  $opened_inodes:ident, $inode_id:ident, $cell:ident, $mount_points:ident, | $inode_alias:ident, $ext2_ino_alias:ident, $ext2_alias:ident | => $ext2_handle:block } => {
+    {  $opened_inodes:ident, $inode_id:ident, $cell:ident, $mount_points:ident, | $inode_alias:ident, $fs_alias:ident | => $handle:block } => {
         let $inode_alias = match $opened_inodes.get_mut(&$inode_id) {
             Some(inode) => inode,
             None => {
@@ -136,26 +220,15 @@ macro_rules! find_inode_and_process {
             }
         };
 
-        let fs = match $mount_points.get_mount_point_by_id($inode_alias.mount_point_id) {
-            Some(fs) => fs,
+        let $fs_alias = match $mount_points.get_mount_point_by_id($inode_alias.mount_point_id) {
+            Some(fs) => &mut fs.fs_impl,
             None => {
                 $cell.set(Err(ErrNo::BadFd));
                 continue;
             }
         };
 
-        match fs.fs_impl {
-            crate::hal::fs::HalFs::Ext2(ref mut $ext2_alias) => {
-                #[allow(irrefutable_let_patterns)]
-                if let HalInode::Ext2(ref mut $ext2_ino_alias) = $inode_alias.inode {
-
-                    $ext2_handle
-                } else {
-                    $cell.set(Err(ErrNo::BadFd));
-                }
-            }
-            crate::hal::fs::HalFs::Unidentified => panic!("Bad fs"),
-        }
+        $handle
     };
 }
 
@@ -165,59 +238,82 @@ pub async fn spawn_vfs_task(drive_id: Guid, entry_id: Guid) {
 
     let mut fs = FileSystem::default();
     let mut opened_inodes: BTreeMap<i64, HalOpenedInode> = BTreeMap::new();
-    let mut inode_idx_counter: i64 = 0;
     let mut mount_points = MountPointArray::new();
 
-    let gpt_reader = GptReader::new(
-        get_storage_devices_by_guid!()
-            .lock()
-            .await
-            .get(&drive_id)
-            .expect("Failed to mount root")
-            .0,
-    );
-
-    let (_header, entries) = gpt_reader.read_gpt().await.expect("Failed to read GPT");
-    let entry = {
-        let mut res = None;
-        for ent in entries.iter() {
-            if ent.unique_guid() == entry_id {
-                res = Some(ent);
+    // the initrd is the root until the disk is up, then it moves out of the way to /initrd
+    let initrd = load_initrd();
+    let has_initrd = initrd.is_some();
+    if let Some(initrd) = initrd {
+        let initrd = FileSystem {
+            fs_impl: HalFs::Tmpfs(initrd),
+            mounted_at: Path::new_appended("/"),
+            ..Default::default()
+        };
+        mount_points.insert(Path::new_appended("/"), initrd);
+    }
+
+    // without a root partition on the command line the initrd is all there is
+    if !has_initrd || drive_id != Guid::default() {
+        let gpt_reader = GptReader::new(
+            get_storage_devices_by_guid!()
+                .lock()
+                .await
+                .get(&drive_id)
+                .expect("Failed to mount root")
+                .0,
+        );
+
+        let (_header, entries) = gpt_reader.read_gpt().await.expect("Failed to read GPT");
+        let entry = {
+            let mut res = None;
+            for ent in entries.iter() {
+                if ent.unique_guid() == entry_id {
+                    res = Some(ent);
+                }
             }
-        }
-        res.expect("Failed to mount root: cannot find GPT entry")
-    };
-    log!("Root directory entry: {:?}", entry);
+            res.expect("Failed to mount root: cannot find GPT entry")
+        };
+        log!("Root directory entry: {:?}", entry);
 
-    fs.drive_id = drive_id;
-    fs.entry = *entry;
-    fs.mounted_at = Path::new_appended("/");
+        fs.drive_id = drive_id;
+        fs.entry = *entry;
+        fs.mounted_at = Path::new_appended("/");
 
-    // only ext2 is supported
-    fs.fs_impl = crate::hal::fs::HalFs::Ext2(Ext2Fs::new(drive_id, fs.entry.clone()).await);
+        // only ext2 is supported
+        fs.fs_impl = HalFs::Ext2(Ext2Fs::new(drive_id, fs.entry.clone()).await);
 
-    mount_points.insert(Path::new_appended("/"), fs);
+        mount_points.move_mount(&Path::new_appended("/"), Path::new_appended("/initrd"));
+        mount_points.insert(Path::new_appended("/"), fs);
+    }
+
+    let tmp = FileSystem {
+        fs_impl: HalFs::Tmpfs(Tmpfs::new()),
+        mounted_at: Path::new_appended("/tmp"),
+        ..Default::default()
+    };
+    mount_points.insert(Path::new_appended("/tmp"), tmp);
+
+    let proc = FileSystem {
+        fs_impl: HalFs::Procfs(Procfs::new()),
+        mounted_at: Path::new_appended("/proc"),
+        ..Default::default()
+    };
+    mount_points.insert(Path::new_appended("/proc"), proc);
+
+    let dev = FileSystem {
+        fs_impl: HalFs::Devfs(Devfs::scan().await),
+        mounted_at: Path::new_appended("/dev"),
+        ..Default::default()
+    };
+    mount_points.insert(Path::new_appended("/dev"), dev);
 
     while let Some(operation) = rx.recv().await {
         match operation.operation_type {
             VfsOperationType::Open { path, flags, cell } => {
                 let path = path.normalize();
 
-                let (_, id) = mount_points.path_to_id_map.iter().fold(
-                    (usize::MAX, None),
-                    |(mut acc, mut res), (p, id)| {
-                        if path.as_str().starts_with(p.as_str()) && p.as_str().len() < acc {
-                            acc = p.as_str().len();
-                            res = Some(id);
-                        }
-
-                        (acc, res)
-                    },
-                );
-
-                match id {
+                match mount_points.find_mount_point(&path) {
                     Some(id) => {
-                        let id = *id;
                         let fs = match mount_points.get_mount_point_by_id(id) {
                             Some(fs) => fs,
                             None => {
@@ -227,25 +323,34 @@ pub async fn spawn_vfs_task(drive_id: Guid, entry_id: Guid) {
                         };
 
                         let path = Path::new_appended(
-                            path.as_str().trim_start_matches(fs.mounted_at.as_str()),
+                            path.as_str()
+                                .strip_prefix(fs.mounted_at.as_str())
+                                .unwrap_or(path.as_str()),
                         );
 
-                        match fs.fs_impl {
-                            crate::hal::fs::HalFs::Ext2(ref mut ext2) => {
-                                match ext2.open_file(path, flags).await {
-                                    Ok(inode) => {
-                                        let inode = HalOpenedInode::from_inode(inode, id);
-                                        opened_inodes.insert(inode_idx_counter, inode);
-                                        fs.opened_inodes.insert(inode_idx_counter);
-                                        cell.set(Ok(inode_idx_counter));
-                                        inode_idx_counter += 1;
-                                    }
-                                    Err(e) => {
-                                        cell.set(Err(Into::<ErrNo>::into(e)));
-                                    }
-                                }
+                        let directory_only =
+                            flags.flags & OpenFlagsValue::OpenDirectoryOnly as i32 != 0;
+
+                        let res = match fs.fs_impl.open_file(path, flags).await {
+                            Ok(inode) if directory_only => match fs.fs_impl.is_directory(&inode) {
+                                Ok(true) => Ok(inode),
+                                Ok(false) => Err(HalFsIOErr::NotADirectory),
+                                Err(e) => Err(e),
+                            },
+                            res => res,
+                        };
+
+                        match res {
+                            Ok(inode) => {
+                                let fd = FD_COUNTER.fetch_add(1, Ordering::AcqRel);
+                                let inode = HalOpenedInode::from_inode(inode, id);
+                                opened_inodes.insert(fd, inode);
+                                fs.opened_inodes.insert(fd);
+                                cell.set(Ok(fd));
+                            }
+                            Err(e) => {
+                                cell.set(Err(Into::<ErrNo>::into(e)));
                             }
-                            crate::hal::fs::HalFs::Unidentified => panic!("Bad fs"),
                         }
                     }
                     None => {
@@ -254,13 +359,96 @@ pub async fn spawn_vfs_task(drive_id: Guid, entry_id: Guid) {
                 }
             }
 
+            VfsOperationType::Mkdir { path, perms, cell } => {
+                let path = path.normalize();
+
+                let Some(fs) = mount_points
+                    .find_mount_point(&path)
+                    .and_then(|id| mount_points.get_mount_point_by_id(id))
+                else {
+                    cell.set(Err(ErrNo::NoSuchFileOrDirectory));
+                    continue;
+                };
+
+                let path = Path::new_appended(
+                    path.as_str()
+                        .strip_prefix(fs.mounted_at.as_str())
+                        .unwrap_or(path.as_str()),
+                );
+
+                cell.set(
+                    fs.fs_impl
+                        .mkdir(path, perms)
+                        .await
+                        .map(|_| 0)
+                        .map_err(Into::into),
+                );
+            }
+
+            VfsOperationType::Rmdir { path, cell } => {
+                let path = path.normalize();
+
+                let Some(fs) = mount_points
+                    .find_mount_point(&path)
+                    .and_then(|id| mount_points.get_mount_point_by_id(id))
+                else {
+                    cell.set(Err(ErrNo::NoSuchFileOrDirectory));
+                    continue;
+                };
+
+                let path = Path::new_appended(
+                    path.as_str()
+                        .strip_prefix(fs.mounted_at.as_str())
+                        .unwrap_or(path.as_str()),
+                );
+
+                cell.set(fs.fs_impl.rmdir(path).await.map(|_| 0).map_err(Into::into));
+            }
+
+            VfsOperationType::Unlink { path, cell } => {
+                let path = path.normalize();
+
+                let Some(fs) = mount_points
+                    .find_mount_point(&path)
+                    .and_then(|id| mount_points.get_mount_point_by_id(id))
+                else {
+                    cell.set(Err(ErrNo::NoSuchFileOrDirectory));
+                    continue;
+                };
+
+                let path = Path::new_appended(
+                    path.as_str()
+                        .strip_prefix(fs.mounted_at.as_str())
+                        .unwrap_or(path.as_str()),
+                );
+
+                cell.set(fs.fs_impl.unlink(path).await.map(|_| 0).map_err(Into::into));
+            }
+
+            VfsOperationType::ReadDir {
+                inode_id,
+                mut buffer,
+                cell,
+            } => {
+                find_inode_and_process!(opened_inodes, inode_id, cell, mount_points, |inode, fs| => {
+                    cell.set(
+                        inode
+                            .file
+                            .read_dir(fs, &mut buffer)
+                            .await
+                            .map(|len| len as i64)
+                            .map_err(Into::<ErrNo>::into),
+                    );
+                });
+            }
+
             VfsOperationType::Read {
                 inode_id,
                 mut buffer,
                 cell,
             } => {
-                find_inode_and_process!(opened_inodes, inode_id, cell, mount_points, |inode, ino, ext2| => {
-                    match ext2.read(ino, &mut buffer, &mut inode.ctx).await {
+                find_inode_and_process!(opened_inodes, inode_id, cell, mount_points, |inode, fs| => {
+                    match inode.file.read(fs, &mut buffer).await {
                         Ok(bytes_read) => {
                             cell.set(Ok(bytes_read as i64));
                         }
@@ -276,8 +464,8 @@ pub async fn spawn_vfs_task(drive_id: Guid, entry_id: Guid) {
                 buffer,
                 cell,
             } => {
-                find_inode_and_process!(opened_inodes, inode_id, cell, mount_points, |inode, ino, ext2| => {
-                    match ext2.write(ino, &buffer, &mut inode.ctx).await {
+                find_inode_and_process!(opened_inodes, inode_id, cell, mount_points, |inode, fs| => {
+                    match inode.file.write(fs, &buffer).await {
                         Ok(bytes_written) => {
                             cell.set(Ok(bytes_written as i64));
                         }
@@ -295,29 +483,53 @@ pub async fn spawn_vfs_task(drive_id: Guid, entry_id: Guid) {
                 offset,
                 cell,
             } => {
-                find_inode_and_process!(opened_inodes, inode_id, cell, mount_points, |inode, _ino, _ext2| => {
-                    match whence {
-                        Whence::SeekSet => {
-                            inode.ctx.head = offset as usize;
-                            cell.set(Ok(inode.ctx.head as i64));
-                        }
-                        Whence::SeekCur => {
-                            if offset < 0 {
-                                inode.ctx.head -= (offset * -1) as usize;
-                            } else {
-                                inode.ctx.head += offset as usize;
-                            }
-                            cell.set(Ok(inode.ctx.head as i64));
-                        }
-                        Whence::SeekEnd => {}
-                        Whence::SeekData => {}
-                        Whence::SeekHole => {}
-                    }
+                find_inode_and_process!(opened_inodes, inode_id, cell, mount_points, |inode, fs| => {
+                    let pos = match whence {
+                        Whence::SeekSet if offset < 0 => Err(HalFsIOErr::InvalidArgument),
+                        Whence::SeekSet => Ok(SeekFrom::Start(offset as u64)),
+                        Whence::SeekCur => Ok(SeekFrom::Current(offset)),
+                        Whence::SeekEnd => Ok(SeekFrom::End(offset)),
+                        // holes aren't tracked
+                        Whence::SeekData | Whence::SeekHole => Err(HalFsIOErr::Unsupported),
+                    };
+
+                    let res = match pos {
+                        Ok(pos) => inode.file.seek(fs, pos).await,
+                        Err(e) => Err(e),
+                    };
+
+                    cell.set(res.map(|head| head as i64).map_err(Into::<ErrNo>::into));
                 });
             }
 
-            VfsOperationType::Close { .. } => {
-                todo!();
+            VfsOperationType::Sync { cell } => {
+                let mut res = Ok(0);
+                for fs in mount_points.mount_points.values_mut() {
+                    if let Err(e) = fs.fs_impl.sync().await {
+                        log!("Failed to sync {}: {:?}", fs.mounted_at.as_str(), e);
+                        res = res.and(Err(e.into()));
+                    }
+                }
+
+                cell.set(res);
+            }
+
+            VfsOperationType::Close { inode_id, cell } => {
+                let Some(inode) = opened_inodes.get_mut(&inode_id) else {
+                    cell.set(Err(ErrNo::BadFd));
+                    continue;
+                };
+
+                inode.count -= 1;
+                if inode.count == 0 {
+                    let mount_point_id = inode.mount_point_id;
+                    opened_inodes.remove(&inode_id);
+                    if let Some(fs) = mount_points.get_mount_point_by_id(mount_point_id) {
+                        fs.opened_inodes.remove(&inode_id);
+                    }
+                }
+
+                cell.set(Ok(0));
             }
         }
     }
@@ -339,7 +551,37 @@ pub async fn vfs_open(path: Path, flags: OpenFlags) -> Result<i64, ErrNo> {
     tx.get().await
 }
 
-pub async fn vfs_read(fd: i64, buf: Buffer) -> Result<i64, ErrNo> {
+fn get_pipe(fd: i64) -> Option<PipeEnd> {
+    PIPES.lock().get(&fd).cloned()
+}
+
+/// returns the (read, write) fds of a new pipe
+pub fn vfs_pipe() -> (i64, i64) {
+    let (reader, writer) = pipe(PIPE_CAPACITY);
+    let read_fd = FD_COUNTER.fetch_add(1, Ordering::AcqRel);
+    let write_fd = FD_COUNTER.fetch_add(1, Ordering::AcqRel);
+
+    let mut pipes = PIPES.lock();
+    pipes.insert(read_fd, PipeEnd::Read(Arc::new(reader)));
+    pipes.insert(write_fd, PipeEnd::Write(Arc::new(writer)));
+
+    (read_fd, write_fd)
+}
+
+/// there's no input for user programs yet, stdin is always at its end
+pub async fn vfs_read(fd: i64, mut buf: Buffer) -> Result<i64, ErrNo> {
+    match fd {
+        STDIN_FD => return Ok(0),
+        STDOUT_FD | STDERR_FD => return Err(ErrNo::BadFd),
+        _ => {}
+    }
+
+    match get_pipe(fd) {
+        Some(PipeEnd::Read(reader)) => return Ok(reader.read(&mut buf).await as i64),
+        Some(PipeEnd::Write(_)) => return Err(ErrNo::BadFd),
+        None => {}
+    }
+
     let sender = VFS_SENDER.get().expect("Failed to get VFS sender");
 
     let (tx, rx) = spsc_cells::<Result<i64, ErrNo>>();
@@ -355,7 +597,23 @@ pub async fn vfs_read(fd: i64, buf: Buffer) -> Result<i64, ErrNo> {
     tx.get().await
 }
 
+/// stdout and stderr both end up on the terminal
 pub async fn vfs_write(fd: i64, buf: Buffer) -> Result<i64, ErrNo> {
+    match fd {
+        STDIN_FD => return Err(ErrNo::BadFd),
+        STDOUT_FD | STDERR_FD => {
+            iprint!("{}", String::from_utf8_lossy(&buf));
+            return Ok(buf.len() as i64);
+        }
+        _ => {}
+    }
+
+    match get_pipe(fd) {
+        Some(PipeEnd::Write(writer)) => return writer.write(&buf).await.map(|len| len as i64),
+        Some(PipeEnd::Read(_)) => return Err(ErrNo::BadFd),
+        None => {}
+    }
+
     let sender = VFS_SENDER.get().expect("Failed to get VFS sender");
 
     let (tx, rx) = spsc_cells::<Result<i64, ErrNo>>();
@@ -372,6 +630,10 @@ pub async fn vfs_write(fd: i64, buf: Buffer) -> Result<i64, ErrNo> {
 }
 
 pub async fn vfs_lseek(fd: i64, whence: Whence, offset: i64) -> Result<i64, ErrNo> {
+    if is_std_stream(fd) || get_pipe(fd).is_some() {
+        return Err(ErrNo::IllegalSeek);
+    }
+
     let sender = VFS_SENDER.get().expect("Failed to get VFS sender");
 
     let (tx, rx) = spsc_cells::<Result<i64, ErrNo>>();
@@ -387,3 +649,292 @@ pub async fn vfs_lseek(fd: i64, whence: Whence, offset: i64) -> Result<i64, ErrN
 
     tx.get().await
 }
+
+/// closing the last fd of a pipe end wakes whoever waits on the other end
+pub async fn vfs_close(fd: i64) -> Result<i64, ErrNo> {
+    if is_std_stream(fd) || PIPES.lock().remove(&fd).is_some() {
+        return Ok(0);
+    }
+
+    let sender = VFS_SENDER.get().expect("Failed to get VFS sender");
+
+    let (tx, rx) = spsc_cells::<Result<i64, ErrNo>>();
+
+    sender.send(VfsOperation {
+        operation_type: VfsOperationType::Close {
+            inode_id: fd,
+            cell: rx,
+        },
+    });
+
+    tx.get().await
+}
+
+/// syncs every mounted filesystem, the first error is returned once all of them were tried
+pub async fn vfs_sync() -> Result<i64, ErrNo> {
+    let sender = VFS_SENDER.get().expect("Failed to get VFS sender");
+
+    let (tx, rx) = spsc_cells::<Result<i64, ErrNo>>();
+
+    sender.send(VfsOperation {
+        operation_type: VfsOperationType::Sync { cell: rx },
+    });
+
+    tx.get().await
+}
+
+/// called before the machine goes down, the filesystems are synced and then every drive's cache is
+/// flushed in case something wrote to a drive directly
+pub async fn sync_all() {
+    if VFS_SENDER.get().is_some() {
+        if let Err(e) = vfs_sync().await {
+            log!("Failed to sync the filesystems: {:?}", e);
+        }
+    }
+
+    if let Err(e) = storage::flush_all().await {
+        log!("Failed to flush the drives: {}", e);
+    }
+}
+
+pub async fn vfs_mkdir(path: Path, perms: i32) -> Result<i64, ErrNo> {
+    let sender = VFS_SENDER.get().expect("Failed to get VFS sender");
+
+    let (tx, rx) = spsc_cells::<Result<i64, ErrNo>>();
+
+    sender.send(VfsOperation {
+        operation_type: VfsOperationType::Mkdir {
+            path,
+            perms,
+            cell: rx,
+        },
+    });
+
+    tx.get().await
+}
+
+pub async fn vfs_rmdir(path: Path) -> Result<i64, ErrNo> {
+    let sender = VFS_SENDER.get().expect("Failed to get VFS sender");
+
+    let (tx, rx) = spsc_cells::<Result<i64, ErrNo>>();
+
+    sender.send(VfsOperation {
+        operation_type: VfsOperationType::Rmdir { path, cell: rx },
+    });
+
+    tx.get().await
+}
+
+pub async fn vfs_unlink(path: Path) -> Result<i64, ErrNo> {
+    let sender = VFS_SENDER.get().expect("Failed to get VFS sender");
+
+    let (tx, rx) = spsc_cells::<Result<i64, ErrNo>>();
+
+    sender.send(VfsOperation {
+        operation_type: VfsOperationType::Unlink { path, cell: rx },
+    });
+
+    tx.get().await
+}
+
+/// fills buf with the next DirEnt64 records of the directory opened as fd, returns how many bytes
+/// were written and 0 at the end of the directory
+pub async fn vfs_read_dir(fd: i64, buf: Buffer) -> Result<i64, ErrNo> {
+    if is_std_stream(fd) || get_pipe(fd).is_some() {
+        return Err(ErrNo::NotADirectory);
+    }
+
+    let sender = VFS_SENDER.get().expect("Failed to get VFS sender");
+
+    let (tx, rx) = spsc_cells::<Result<i64, ErrNo>>();
+
+    sender.send(VfsOperation {
+        operation_type: VfsOperationType::ReadDir {
+            inode_id: fd,
+            buffer: buf,
+            cell: rx,
+        },
+    });
+
+    tx.get().await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{end_test, test_name};
+
+    use super::*;
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn vfs_mount_lookup() {
+        test_name!("vfs mount lookup");
+
+        let mut mounts = MountPointArray::new();
+        mounts.insert(Path::new_appended("/"), FileSystem::default());
+        mounts.insert(Path::new_appended("/tmp"), FileSystem::default());
+
+        assert_eq!(
+            mounts.find_mount_point(&Path::new_appended("/tmp/a")),
+            Some(1)
+        );
+        assert_eq!(
+            mounts.find_mount_point(&Path::new_appended("/tmp")),
+            Some(1)
+        );
+        assert_eq!(
+            mounts.find_mount_point(&Path::new_appended("/tmpfile")),
+            Some(0)
+        );
+        assert_eq!(
+            mounts.find_mount_point(&Path::new_appended("/bin/sh")),
+            Some(0)
+        );
+
+        // moving a mount keeps its id
+        assert!(mounts.move_mount(&Path::new_appended("/tmp"), Path::new_appended("/scratch")));
+        assert_eq!(
+            mounts.find_mount_point(&Path::new_appended("/scratch/a")),
+            Some(1)
+        );
+        assert_eq!(
+            mounts.find_mount_point(&Path::new_appended("/tmp/a")),
+            Some(0)
+        );
+        assert_eq!(
+            mounts
+                .get_mount_point_by_id(1)
+                .expect("No mount")
+                .mounted_at
+                .as_str(),
+            "/scratch"
+        );
+        assert!(!mounts.move_mount(&Path::new_appended("/tmp"), Path::new_appended("/x")));
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn vfs_tmpfs_directories() {
+        use crate::{
+            drivers::fs::ext2::{EXT2_FT_DIR, EXT2_FT_REG_FILE},
+            terminal::test::block_on,
+        };
+        use alloc::{boxed::Box, vec};
+
+        test_name!("directories on tmpfs are made, listed and removed through the vfs");
+
+        let path = |path| Path::new_appended(path);
+
+        block_on(vfs_mkdir(path("/tmp/vfs_dir"), 0o755)).expect("Failed to mkdir");
+        block_on(vfs_mkdir(path("/tmp/vfs_dir/b"), 0o700)).expect("Failed to mkdir");
+        let create = OpenFlags {
+            flags: OpenFlagsValue::CreateIfNotExist as i32,
+            perms: Some(0o644),
+            ..Default::default()
+        };
+        let fd = block_on(vfs_open(path("/tmp/vfs_dir/a"), create)).expect("Failed to create");
+        block_on(vfs_close(fd)).expect("Failed to close");
+
+        let directory_only = OpenFlags {
+            flags: OpenFlagsValue::OpenDirectoryOnly as i32,
+            ..Default::default()
+        };
+        let dir =
+            block_on(vfs_open(path("/tmp/vfs_dir"), directory_only)).expect("Failed to open dir");
+
+        // "a" then "b", 24 bytes each
+        let buf: Buffer = vec![0u8; 256].into_boxed_slice().into();
+        assert_eq!(block_on(vfs_read_dir(dir, buf.clone())), Ok(48));
+        assert_eq!((buf[18], buf[19]), (EXT2_FT_REG_FILE, b'a'));
+        assert_eq!((buf[24 + 18], buf[24 + 19]), (EXT2_FT_DIR, b'b'));
+        // the fd remembers where the listing stopped
+        assert_eq!(block_on(vfs_read_dir(dir, buf.clone())), Ok(0));
+
+        // a buffer that can't take a single entry
+        assert_eq!(block_on(vfs_lseek(dir, Whence::SeekSet, 0)), Ok(0));
+        let small: Buffer = vec![0u8; 16].into_boxed_slice().into();
+        assert_eq!(
+            block_on(vfs_read_dir(dir, small.clone())),
+            Err(ErrNo::InvalidArgument)
+        );
+        let _: Box<[u8]> = small.into();
+
+        assert_eq!(
+            block_on(vfs_rmdir(path("/tmp/vfs_dir"))),
+            Err(ErrNo::DirectoryNotEmpty)
+        );
+        assert_eq!(
+            block_on(vfs_unlink(path("/tmp/vfs_dir/b"))),
+            Err(ErrNo::IsADirectory)
+        );
+        assert_eq!(block_on(vfs_unlink(path("/tmp/vfs_dir/a"))), Ok(0));
+        assert_eq!(block_on(vfs_rmdir(path("/tmp/vfs_dir/b"))), Ok(0));
+
+        // the listing sees the removals
+        assert_eq!(block_on(vfs_lseek(dir, Whence::SeekSet, 0)), Ok(0));
+        assert_eq!(block_on(vfs_read_dir(dir, buf.clone())), Ok(0));
+        block_on(vfs_close(dir)).expect("Failed to close");
+
+        assert_eq!(block_on(vfs_rmdir(path("/tmp/vfs_dir"))), Ok(0));
+        assert_eq!(
+            block_on(vfs_open(path("/tmp/vfs_dir"), OpenFlags::default())),
+            Err(ErrNo::NoSuchFileOrDirectory)
+        );
+        // procfs has no directories to remove
+        assert_eq!(
+            block_on(vfs_unlink(path("/proc/meminfo"))),
+            Err(ErrNo::from(HalFsIOErr::Unsupported))
+        );
+
+        let _: Box<[u8]> = buf.into();
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn vfs_pipe_fds() {
+        use crate::terminal::test::block_on;
+        use alloc::{boxed::Box, vec};
+
+        test_name!("bytes written to a pipe fd come out of its read fd");
+
+        let (read_fd, write_fd) = vfs_pipe();
+
+        let data: Buffer = Box::<[u8]>::from(*b"ls | cat").into();
+        assert_eq!(block_on(vfs_write(write_fd, data.clone())), Ok(8));
+        let _: Box<[u8]> = data.into();
+
+        // the wrong end of the pipe
+        let buf: Buffer = vec![0u8; 16].into_boxed_slice().into();
+        assert_eq!(block_on(vfs_read(write_fd, buf.clone())), Err(ErrNo::BadFd));
+        assert_eq!(
+            block_on(vfs_lseek(read_fd, Whence::SeekSet, 0)),
+            Err(ErrNo::IllegalSeek)
+        );
+
+        assert_eq!(block_on(vfs_read(read_fd, buf.clone())), Ok(8));
+        assert_eq!(&buf[..8], b"ls | cat");
+
+        // eof once the write end is gone and the pipe is drained
+        block_on(vfs_write(write_fd, buf.clone())).expect("Write failed");
+        assert_eq!(block_on(vfs_close(write_fd)), Ok(0));
+        assert_eq!(block_on(vfs_read(read_fd, buf.clone())), Ok(16));
+        assert_eq!(block_on(vfs_read(read_fd, buf.clone())), Ok(0));
+
+        // writing with no reader left
+        let (read_fd, write_fd) = vfs_pipe();
+        block_on(vfs_close(read_fd)).expect("Close failed");
+        assert_eq!(
+            block_on(vfs_write(write_fd, buf.clone())),
+            Err(ErrNo::BrokenPipe)
+        );
+        block_on(vfs_close(write_fd)).expect("Close failed");
+
+        let _: Box<[u8]> = buf.into();
+
+        end_test!();
+    }
+}
@@ -37,6 +37,8 @@ pub mod hal;
 pub mod terminal;
 #[cfg(target_arch = "x86_64")]
 pub mod time;
+#[cfg(target_arch = "x86_64")]
+pub mod utils;
 
 pub const STACK_SIZE: u64 = 0x100000;
 
@@ -0,0 +1,434 @@
+use alloc::{boxed::Box, string::String, vec::Vec};
+use core::fmt::{self, Write};
+
+use pc_keyboard::{DecodedKey, KeyCode};
+
+const BACKSPACE: char = '\u{8}';
+const DELETE: char = '\u{7f}';
+const BELL: char = '\u{7}';
+pub const DEFAULT_HISTORY_LEN: usize = 64;
+
+/// given the word under the cursor, returns every string it could be completed to
+pub type Completer = Box<dyn FnMut(&str) -> Vec<String> + Send>;
+
+/// completions for a path, names are the entries of the directory the word points into with
+/// whether each is a directory, the shell reads them from the filesystem
+pub fn filename_candidates<'a>(
+    word: &str,
+    names: impl IntoIterator<Item = (&'a str, bool)>,
+) -> Vec<String> {
+    let (directory, partial) = match word.rfind('/') {
+        Some(idx) => word.split_at(idx + 1),
+        None => ("", word),
+    };
+
+    names
+        .into_iter()
+        .filter(|(name, _)| *name != "." && *name != ".." && name.starts_with(partial))
+        .map(|(name, is_dir)| {
+            let mut candidate = String::from(directory) + name;
+            if is_dir {
+                candidate.push('/');
+            }
+            candidate
+        })
+        .collect()
+}
+
+/// an editable input line with a cursor and a history, fed decoded keys one at a time
+///
+/// the line is redrawn in place with `\r` and backspaces after every edit, it assumes the prompt
+/// and the line fit on one row of the terminal
+pub struct LineEditor {
+    prompt: String,
+    line: Vec<char>,
+    /// index into line, the next character is inserted before it
+    cursor: usize,
+    history: Vec<String>,
+    max_history: usize,
+    /// which history entry is shown, None while editing a new line
+    history_idx: Option<usize>,
+    /// the new line being typed, put back once the history is scrolled past its end
+    stash: Vec<char>,
+    /// how many characters the last redraw left on the row
+    drawn_len: usize,
+    completer: Option<Completer>,
+}
+
+impl LineEditor {
+    pub fn new(prompt: &str) -> Self {
+        Self {
+            prompt: String::from(prompt),
+            line: Vec::new(),
+            cursor: 0,
+            history: Vec::new(),
+            max_history: DEFAULT_HISTORY_LEN,
+            history_idx: None,
+            stash: Vec::new(),
+            drawn_len: 0,
+            completer: None,
+        }
+    }
+
+    /// what tab completes with, the shell hands in one backed by the filesystem
+    pub fn set_completer(&mut self, completer: Completer) {
+        self.completer = Some(completer);
+    }
+
+    pub fn line(&self) -> String {
+        self.line.iter().collect()
+    }
+
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    /// oldest first
+    pub fn history(&self) -> &[String] {
+        &self.history
+    }
+
+    /// handles one key and redraws the line to out, returns the line once enter is pressed
+    pub fn handle_key(&mut self, key: DecodedKey, out: &mut impl Write) -> Option<String> {
+        match key {
+            DecodedKey::Unicode('\n' | '\r') => return Some(self.submit(out)),
+            DecodedKey::Unicode('\t') => {
+                let _ = self.complete(out);
+                return None;
+            }
+            DecodedKey::Unicode(BACKSPACE) | DecodedKey::RawKey(KeyCode::Backspace) => {
+                if self.cursor > 0 {
+                    self.cursor -= 1;
+                    self.line.remove(self.cursor);
+                }
+            }
+            DecodedKey::Unicode(DELETE) | DecodedKey::RawKey(KeyCode::Delete) => {
+                if self.cursor < self.line.len() {
+                    self.line.remove(self.cursor);
+                }
+            }
+            DecodedKey::Unicode(c) if !c.is_control() => {
+                self.line.insert(self.cursor, c);
+                self.cursor += 1;
+            }
+            DecodedKey::RawKey(KeyCode::ArrowLeft) => self.cursor = self.cursor.saturating_sub(1),
+            DecodedKey::RawKey(KeyCode::ArrowRight) => {
+                self.cursor = (self.cursor + 1).min(self.line.len())
+            }
+            DecodedKey::RawKey(KeyCode::Home) => self.cursor = 0,
+            DecodedKey::RawKey(KeyCode::End) => self.cursor = self.line.len(),
+            DecodedKey::RawKey(KeyCode::ArrowUp) => self.history_prev(),
+            DecodedKey::RawKey(KeyCode::ArrowDown) => self.history_next(),
+            _ => return None,
+        }
+
+        let _ = self.redraw(out);
+        None
+    }
+
+    fn history_prev(&mut self) {
+        let idx = match self.history_idx {
+            Some(0) => return,
+            Some(idx) => idx - 1,
+            None if self.history.is_empty() => return,
+            None => {
+                self.stash = core::mem::take(&mut self.line);
+                self.history.len() - 1
+            }
+        };
+
+        self.history_idx = Some(idx);
+        self.line = self.history[idx].chars().collect();
+        self.cursor = self.line.len();
+    }
+
+    fn history_next(&mut self) {
+        let Some(idx) = self.history_idx else {
+            return;
+        };
+
+        if idx + 1 < self.history.len() {
+            self.history_idx = Some(idx + 1);
+            self.line = self.history[idx + 1].chars().collect();
+        } else {
+            self.history_idx = None;
+            self.line = core::mem::take(&mut self.stash);
+        }
+        self.cursor = self.line.len();
+    }
+
+    /// the start of the whitespace separated word ending at the cursor
+    fn word_start(&self) -> usize {
+        self.line[..self.cursor]
+            .iter()
+            .rposition(|c| c.is_whitespace())
+            .map_or(0, |idx| idx + 1)
+    }
+
+    /// a single match is completed fully, several are completed up to what they share and
+    /// listed if that adds nothing, no match rings the bell
+    fn complete(&mut self, out: &mut impl Write) -> fmt::Result {
+        let start = self.word_start();
+        let word: String = self.line[start..self.cursor].iter().collect();
+
+        let Some(completer) = self.completer.as_mut() else {
+            return Ok(());
+        };
+        let mut candidates = completer(&word);
+        candidates.retain(|candidate| candidate.starts_with(word.as_str()));
+        candidates.sort();
+        candidates.dedup();
+
+        let completion = match candidates.as_slice() {
+            [] => return out.write_char(BELL),
+            // directories are left open so the next tab can go into them
+            [only] if only.ends_with('/') => only.clone(),
+            [only] => only.clone() + " ",
+            [first, rest @ ..] => {
+                let shared = rest
+                    .iter()
+                    .map(|candidate| {
+                        first
+                            .chars()
+                            .zip(candidate.chars())
+                            .take_while(|(a, b)| a == b)
+                            .count()
+                    })
+                    .min()
+                    .unwrap_or(0);
+                let prefix: String = first.chars().take(shared).collect();
+
+                if shared == word.chars().count() {
+                    out.write_char('\n')?;
+                    for (idx, candidate) in candidates.iter().enumerate() {
+                        if idx > 0 {
+                            out.write_str("  ")?;
+                        }
+                        out.write_str(candidate)?;
+                    }
+                    out.write_char('\n')?;
+                    // the listing moved everything down a row
+                    self.drawn_len = 0;
+                    return self.redraw(out);
+                }
+
+                prefix
+            }
+        };
+
+        let inserted: Vec<char> = completion.chars().skip(word.chars().count()).collect();
+        let count = inserted.len();
+        self.line.splice(self.cursor..self.cursor, inserted);
+        self.cursor += count;
+
+        self.redraw(out)
+    }
+
+    fn submit(&mut self, out: &mut impl Write) -> String {
+        let line = self.line();
+
+        // blank lines and repeats of the last one aren't worth keeping
+        if !line.trim().is_empty() && self.history.last() != Some(&line) {
+            if self.history.len() == self.max_history {
+                self.history.remove(0);
+            }
+            self.history.push(line.clone());
+        }
+
+        self.line.clear();
+        self.stash.clear();
+        self.cursor = 0;
+        self.history_idx = None;
+        self.drawn_len = 0;
+        let _ = out.write_char('\n');
+
+        line
+    }
+
+    /// prints the prompt and whatever is on the line so far, for a fresh line
+    pub fn redraw(&mut self, out: &mut impl Write) -> fmt::Result {
+        out.write_char('\r')?;
+        out.write_str(&self.prompt)?;
+        for c in self.line.iter() {
+            out.write_char(*c)?;
+        }
+
+        // blank out what's left of a longer line drawn before
+        let stale = self.drawn_len.saturating_sub(self.line.len());
+        for _ in 0..stale {
+            out.write_char(' ')?;
+        }
+
+        for _ in 0..stale + self.line.len() - self.cursor {
+            out.write_char(BACKSPACE)?;
+        }
+
+        self.drawn_len = self.line.len();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::String;
+    use pc_keyboard::{DecodedKey, KeyCode};
+
+    use alloc::{boxed::Box, vec::Vec};
+
+    use super::{LineEditor, filename_candidates};
+    use crate::{end_test, test_name};
+
+    fn type_str(editor: &mut LineEditor, out: &mut String, s: &str) {
+        for c in s.chars() {
+            assert!(editor.handle_key(DecodedKey::Unicode(c), out).is_none());
+        }
+    }
+
+    fn press(editor: &mut LineEditor, out: &mut String, key: KeyCode) {
+        assert!(editor.handle_key(DecodedKey::RawKey(key), out).is_none());
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn line_editor_editing() {
+        test_name!("line editor editing");
+
+        let mut editor = LineEditor::new("$ ");
+        let mut out = String::new();
+
+        type_str(&mut editor, &mut out, "lss");
+        assert!(
+            editor
+                .handle_key(DecodedKey::Unicode('\u{8}'), &mut out)
+                .is_none()
+        );
+        assert_eq!(editor.line(), "ls");
+
+        // insert in the middle
+        press(&mut editor, &mut out, KeyCode::ArrowLeft);
+        type_str(&mut editor, &mut out, "x");
+        assert_eq!(editor.line(), "lxs");
+        assert_eq!(editor.cursor(), 2);
+
+        press(&mut editor, &mut out, KeyCode::Home);
+        press(&mut editor, &mut out, KeyCode::Delete);
+        press(&mut editor, &mut out, KeyCode::ArrowLeft);
+        assert_eq!(editor.cursor(), 0);
+        press(&mut editor, &mut out, KeyCode::End);
+        type_str(&mut editor, &mut out, " /");
+        assert_eq!(editor.line(), "xs /");
+
+        // the last redraw puts the cursor back at the end, nothing to erase
+        out.clear();
+        type_str(&mut editor, &mut out, "a");
+        assert_eq!(out, "\r$ xs /a");
+
+        // a shorter line blanks the old tail and steps back over it
+        press(&mut editor, &mut out, KeyCode::Home);
+        out.clear();
+        press(&mut editor, &mut out, KeyCode::Delete);
+        assert_eq!(out, "\r$ s /a \u{8}\u{8}\u{8}\u{8}\u{8}");
+
+        let line = editor.handle_key(DecodedKey::Unicode('\n'), &mut out);
+        assert_eq!(line.as_deref(), Some("s /a"));
+        assert_eq!(editor.line(), "");
+        assert!(out.ends_with('\n'));
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn line_editor_history() {
+        test_name!("line editor history");
+
+        let mut editor = LineEditor::new("> ");
+        let mut out = String::new();
+
+        for line in ["first", "second", "second", ""] {
+            type_str(&mut editor, &mut out, line);
+            editor.handle_key(DecodedKey::Unicode('\n'), &mut out);
+        }
+        assert_eq!(editor.history(), ["first", "second"]);
+
+        type_str(&mut editor, &mut out, "draft");
+        press(&mut editor, &mut out, KeyCode::ArrowUp);
+        assert_eq!(editor.line(), "second");
+        press(&mut editor, &mut out, KeyCode::ArrowUp);
+        press(&mut editor, &mut out, KeyCode::ArrowUp);
+        assert_eq!(editor.line(), "first");
+        assert_eq!(editor.cursor(), 5);
+
+        press(&mut editor, &mut out, KeyCode::ArrowDown);
+        assert_eq!(editor.line(), "second");
+        // past the newest entry the draft comes back
+        press(&mut editor, &mut out, KeyCode::ArrowDown);
+        assert_eq!(editor.line(), "draft");
+        press(&mut editor, &mut out, KeyCode::ArrowDown);
+        assert_eq!(editor.line(), "draft");
+
+        end_test!();
+    }
+
+    fn mock_editor() -> LineEditor {
+        let mut editor = LineEditor::new("$ ");
+        editor.set_completer(Box::new(|word: &str| {
+            let entries = [
+                (".", true),
+                ("..", true),
+                ("motd", false),
+                ("hostname", false),
+                ("hosts", false),
+                ("init.d", true),
+            ];
+            filename_candidates(word, entries)
+        }));
+        editor
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn line_editor_completion() {
+        test_name!("line editor completion");
+
+        let mut editor = mock_editor();
+        let mut out = String::new();
+
+        // a single match is finished off with a space, a directory with a slash
+        type_str(&mut editor, &mut out, "cat /etc/mo");
+        type_str(&mut editor, &mut out, "\t");
+        assert_eq!(editor.line(), "cat /etc/motd ");
+        type_str(&mut editor, &mut out, "in\t");
+        assert_eq!(editor.line(), "cat /etc/motd init.d/");
+
+        // several matches complete up to what they share, then get listed
+        let mut editor = mock_editor();
+        type_str(&mut editor, &mut out, "h\t");
+        assert_eq!(editor.line(), "host");
+        out.clear();
+        type_str(&mut editor, &mut out, "\t");
+        assert_eq!(editor.line(), "host");
+        assert!(out.starts_with("\nhostname  hosts\n\r$ host"));
+
+        // completing in the middle of the line leaves the rest alone
+        let mut editor = mock_editor();
+        type_str(&mut editor, &mut out, "m x");
+        for key in [KeyCode::ArrowLeft, KeyCode::ArrowLeft] {
+            press(&mut editor, &mut out, key);
+        }
+        type_str(&mut editor, &mut out, "\t");
+        assert_eq!(editor.line(), "motd  x");
+        assert_eq!(editor.cursor(), 5);
+
+        // nothing matches
+        out.clear();
+        type_str(&mut editor, &mut out, "zz\t");
+        assert!(out.ends_with('\u{7}'));
+        assert_eq!(editor.line(), "motd zz x");
+
+        let names: Vec<String> = filename_candidates("..", [(".", true), ("..", true)]);
+        assert!(names.is_empty());
+
+        end_test!();
+    }
+}
@@ -0,0 +1,326 @@
+use core::{
+    arch::x86_64::__cpuid,
+    fmt::{self, Write},
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+};
+
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::terminal::port_dbg::SERIAL_WRITER;
+
+pub const LOG_RING_SIZE: usize = 16 * 1024;
+/// longer log lines get cut off
+pub const LOG_LINE_SIZE: usize = 256;
+
+/// formats into a fixed slice, whatever doesn't fit is cut off instead of allocating
+pub struct SliceWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> SliceWriter<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, len: 0 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == self.buf.len()
+    }
+
+    pub fn into_bytes(self) -> &'a mut [u8] {
+        let Self { buf, len } = self;
+        &mut buf[..len]
+    }
+}
+
+impl Write for SliceWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let to_copy = s.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + to_copy].copy_from_slice(&s.as_bytes()[..to_copy]);
+        self.len += to_copy;
+
+        if to_copy < s.len() {
+            return Err(fmt::Error);
+        }
+
+        Ok(())
+    }
+}
+
+/// a byte ring of newline terminated messages, when it's full the oldest messages are thrown
+/// away and counted so a flood never makes the producer wait
+pub struct LogRing<const N: usize> {
+    buf: [u8; N],
+    start: usize,
+    len: usize,
+    dropped: u64,
+}
+
+impl<const N: usize> Default for LogRing<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> LogRing<N> {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            start: 0,
+            len: 0,
+            dropped: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// messages thrown away since the last marker was read
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    pub fn add_dropped(&mut self, count: u64) {
+        self.dropped += count;
+    }
+
+    fn byte(&self, idx: usize) -> u8 {
+        self.buf[(self.start + idx) % N]
+    }
+
+    fn consume(&mut self, count: usize) {
+        self.start = (self.start + count) % N;
+        self.len -= count;
+    }
+
+    /// evicts everything up to and including the first newline
+    fn drop_oldest(&mut self) {
+        let mut count = 0;
+        while count < self.len {
+            count += 1;
+            if self.byte(count - 1) == b'\n' {
+                break;
+            }
+        }
+
+        self.consume(count);
+        self.dropped += 1;
+    }
+
+    pub fn push(&mut self, msg: &[u8]) {
+        if msg.len() > N {
+            self.dropped += 1;
+            return;
+        }
+
+        while N - self.len < msg.len() {
+            self.drop_oldest();
+        }
+
+        for byte in msg {
+            self.buf[(self.start + self.len) % N] = *byte;
+            self.len += 1;
+        }
+    }
+
+    /// copies the oldest bytes out, if anything was dropped the marker comes first and takes a
+    /// read of its own
+    pub fn read(&mut self, out: &mut [u8]) -> usize {
+        if self.dropped > 0 {
+            let mut writer = SliceWriter::new(out);
+            if writeln!(writer, "[{} messages dropped]", self.dropped).is_ok() {
+                self.dropped = 0;
+                return writer.len();
+            }
+        }
+
+        let count = out.len().min(self.len);
+        for (idx, byte) in out[..count].iter_mut().enumerate() {
+            *byte = self.byte(idx);
+        }
+        self.consume(count);
+
+        count
+    }
+}
+
+pub static LOG_RING: Mutex<LogRing<LOG_RING_SIZE>> = Mutex::new(LogRing::new());
+/// the core holding [`LOG_RING`], [`NO_OWNER`] while nobody does
+static LOG_RING_OWNER: AtomicU32 = AtomicU32::new(NO_OWNER);
+const NO_OWNER: u32 = u32::MAX;
+/// messages lost because the core logging them already held the ring, added to the ring's count
+/// later
+static REENTRANT_DROPS: AtomicU64 = AtomicU64::new(0);
+
+/// the initial apic id, logging starts before the per cpu data is there
+fn current_core() -> u32 {
+    __cpuid(1).ebx >> 24
+}
+
+/// runs `f` on the ring with interrupts off. Another core only holds it for a push or a copy so
+/// this waits for it, None if this core holds it already and got here from inside its own call
+fn with_ring<T>(f: impl FnOnce(&mut LogRing<LOG_RING_SIZE>) -> T) -> Option<T> {
+    without_interrupts(|| {
+        let core = current_core();
+        let mut ring = loop {
+            if let Some(ring) = LOG_RING.try_lock() {
+                break ring;
+            }
+
+            if LOG_RING_OWNER.load(Ordering::Acquire) == core {
+                return None;
+            }
+            core::hint::spin_loop();
+        };
+
+        LOG_RING_OWNER.store(core, Ordering::Release);
+        let res = f(&mut ring);
+        LOG_RING_OWNER.store(NO_OWNER, Ordering::Release);
+
+        Some(res)
+    })
+}
+
+/// queues the message and drains the ring to the serial port if nobody else is. A message is
+/// only lost when the ring is full or the core was already logging, e.g. a fault in the middle
+/// of it
+#[doc(hidden)]
+pub fn _log(args: fmt::Arguments) {
+    let mut line = [0u8; LOG_LINE_SIZE];
+    let mut writer = SliceWriter::new(&mut line);
+    // a cut off line still gets its newline so the ring can tell the messages apart
+    if writer.write_fmt(args).is_err() && writer.is_full() {
+        writer.buf[LOG_LINE_SIZE - 1] = b'\n';
+    }
+    let line = writer.into_bytes();
+
+    let pushed = with_ring(|ring| {
+        ring.add_dropped(REENTRANT_DROPS.swap(0, Ordering::Relaxed));
+        ring.push(line);
+    });
+    if pushed.is_none() {
+        REENTRANT_DROPS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    without_interrupts(flush);
+}
+
+/// writes out whatever is queued, the core already holding the serial port does it for
+/// everyone else so this returns straight away if it's busy
+pub fn flush() {
+    loop {
+        let Some(mut serial) = SERIAL_WRITER.try_lock() else {
+            return;
+        };
+
+        let mut chunk = [0u8; LOG_LINE_SIZE];
+        loop {
+            // the ring is only held for the copy so producers keep going while the port is slow
+            let count = with_ring(|ring| ring.read(&mut chunk)).unwrap_or(0);
+
+            if count == 0 {
+                break;
+            }
+
+            serial.write_bytes(&chunk[..count]);
+        }
+
+        drop(serial);
+
+        // a message queued after the last read but before the port was let go saw it busy and
+        // left the writing to us
+        let pending = with_ring(|ring| !ring.is_empty() || ring.dropped() > 0).unwrap_or(false);
+        if !pending {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::fmt::Write;
+
+    use super::{LogRing, SliceWriter, with_ring};
+    use crate::{end_test, test_name};
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn log_ring_flood() {
+        test_name!("log ring flood");
+
+        // room for exactly 5 of the 11 byte messages
+        let mut ring = LogRing::<60>::new();
+        for i in 0..20 {
+            let mut line = [0u8; 16];
+            let mut writer = SliceWriter::new(&mut line);
+            writeln!(writer, "message {:02}", i).expect("Line too long");
+            ring.push(writer.into_bytes());
+        }
+
+        assert_eq!(ring.dropped(), 15);
+        assert_eq!(ring.len(), 55);
+
+        let mut out = [0u8; 128];
+        let len = ring.read(&mut out);
+        assert_eq!(&out[..len], b"[15 messages dropped]\n");
+        assert_eq!(ring.dropped(), 0);
+
+        let len = ring.read(&mut out);
+        assert_eq!(
+            &out[..len],
+            b"message 15\nmessage 16\nmessage 17\nmessage 18\nmessage 19\n"
+        );
+        assert!(ring.is_empty());
+        assert_eq!(ring.read(&mut out), 0);
+
+        // a message bigger than the whole ring is dropped as well
+        ring.push(&[b'x'; 61]);
+        assert_eq!(ring.dropped(), 1);
+        assert!(ring.is_empty());
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn log_ring_reentry() {
+        test_name!("log ring only gives up on its own core");
+
+        // the core already holding the ring can't wait for itself
+        let inner = with_ring(|_| with_ring(|ring| ring.len()));
+        assert_eq!(inner, Some(None));
+
+        // and the owner is gone once it let go
+        assert!(with_ring(|_| ()).is_some());
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn slice_writer_truncates() {
+        test_name!("slice writer truncates");
+
+        let mut buf = [0u8; 4];
+        let mut writer = SliceWriter::new(&mut buf);
+        assert!(write!(writer, "abcdef").is_err());
+        assert!(writer.is_full());
+        assert_eq!(writer.into_bytes(), b"abcd");
+
+        end_test!();
+    }
+}
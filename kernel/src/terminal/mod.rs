@@ -5,6 +5,9 @@ use limine::request::FramebufferRequest;
 use spin::Mutex;
 
 pub mod font;
+pub mod line_editor;
+#[cfg(target_arch = "x86_64")]
+pub mod log_ring;
 #[cfg(target_arch = "x86_64")]
 pub mod port_dbg;
 pub mod test;
@@ -257,6 +260,11 @@ impl DebugWriter {
         for byte in format.bytes() {
             match byte {
                 b'\n' => self.debug_terminal_newline(),
+                b'\r' => self.current_col = 0,
+                // steps back without erasing, like a real terminal
+                0x08 => self.current_col = self.current_col.saturating_sub(1),
+                // there's no speaker to ring
+                0x07 => {}
                 0x00..=0x7f => self.debug_terminal_putbyte(byte),
                 _ => self.debug_terminal_putbyte(0xFE),
             }
@@ -30,9 +30,9 @@ fn is_transmit_empty() -> bool {
 
 pub struct SerialWriter {}
 
-impl fmt::Write for SerialWriter {
-    fn write_str(&mut self, s: &str) -> fmt::Result {
-        for c in s.as_bytes() {
+impl SerialWriter {
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        for c in bytes {
             while !is_transmit_empty() {
                 core::hint::spin_loop();
             }
@@ -42,6 +42,12 @@ impl fmt::Write for SerialWriter {
                 data_port.write(*c);
             }
         }
+    }
+}
+
+impl fmt::Write for SerialWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_bytes(s.as_bytes());
 
         Ok(())
     }
@@ -64,7 +70,7 @@ pub fn _get_core() -> u32 {
         return 0;
     }
 
-    get_local_apic().read_id() >> 24 & 0xFF
+    get_local_apic().apic_id()
 }
 
 #[doc(hidden)]
@@ -76,5 +82,5 @@ macro_rules! serial_iprint {
 
 #[macro_export]
 macro_rules! log {
-    ($($arg:tt)*) => ($crate::serial_iprint!("Core {}: {} - line {}, {}\n", $crate::terminal::port_dbg::_get_core(), file!(), line!(),  format_args!($($arg)*)));
+    ($($arg:tt)*) => ($crate::terminal::log_ring::_log(format_args!("Core {}: {} - line {}, {}\n", $crate::terminal::port_dbg::_get_core(), file!(), line!(),  format_args!($($arg)*))));
 }
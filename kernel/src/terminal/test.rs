@@ -43,3 +43,18 @@ pub fn run_tests(tests: &[&dyn Fn()]) {
         test();
     }
 }
+
+/// drives a future to completion on the current core, tests don't have an executor to spawn on
+#[cfg(test)]
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = core::pin::pin!(future);
+    let mut ctx = core::task::Context::from_waker(core::task::Waker::noop());
+
+    loop {
+        if let core::task::Poll::Ready(res) = future.as_mut().poll(&mut ctx) {
+            return res;
+        }
+
+        core::hint::spin_loop();
+    }
+}
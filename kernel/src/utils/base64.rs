@@ -0,0 +1,135 @@
+use alloc::{string::String, vec::Vec};
+use thiserror::Error;
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+const PAD: char = '=';
+
+#[derive(Debug, Error, PartialEq, Eq, Clone, Copy)]
+pub enum DecodeErr {
+    #[error("Invalid base64 character: {0}")]
+    InvalidCharacter(char),
+    #[error("The input length can't come from base64")]
+    BadLength,
+    #[error("The padding doesn't match the input length")]
+    BadPadding,
+}
+
+/// standard alphabet, always padded to a multiple of 4
+pub fn encode(bytes: &[u8]) -> String {
+    let mut res = String::with_capacity(bytes.len().div_ceil(3) * 4);
+
+    for chunk in bytes.chunks(3) {
+        let group = (chunk[0] as u32) << 16
+            | (*chunk.get(1).unwrap_or(&0) as u32) << 8
+            | *chunk.get(2).unwrap_or(&0) as u32;
+
+        // n bytes fill n + 1 characters, the rest of the group is padding
+        for i in 0..4 {
+            if i <= chunk.len() {
+                res.push(ALPHABET[(group >> (18 - i * 6)) as usize & 0x3F] as char);
+            } else {
+                res.push(PAD);
+            }
+        }
+    }
+
+    res
+}
+
+fn decode_char(c: u8) -> Result<u32, DecodeErr> {
+    let value = match c {
+        b'A'..=b'Z' => c - b'A',
+        b'a'..=b'z' => c - b'a' + 26,
+        b'0'..=b'9' => c - b'0' + 52,
+        b'+' => 62,
+        b'/' => 63,
+        _ => return Err(DecodeErr::InvalidCharacter(c as char)),
+    };
+
+    Ok(value as u32)
+}
+
+/// takes the standard alphabet with or without the trailing padding
+pub fn decode(input: &str) -> Result<Vec<u8>, DecodeErr> {
+    let data = input.trim_end_matches(PAD);
+    let pad_len = input.len() - data.len();
+
+    if pad_len > 0 && (pad_len > 2 || !input.len().is_multiple_of(4)) {
+        return Err(DecodeErr::BadPadding);
+    }
+
+    // a lone character only carries 6 bits, not enough for a byte
+    if data.len() % 4 == 1 {
+        return Err(DecodeErr::BadLength);
+    }
+
+    let mut res = Vec::with_capacity(data.len() * 3 / 4);
+
+    for chunk in data.as_bytes().chunks(4) {
+        let mut group = 0;
+        for (i, c) in chunk.iter().enumerate() {
+            group |= decode_char(*c)? << (18 - i * 6);
+        }
+
+        // n characters hold n - 1 whole bytes
+        for i in 0..chunk.len() - 1 {
+            res.push((group >> (16 - i * 8)) as u8);
+        }
+    }
+
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DecodeErr, decode, encode};
+    use crate::{end_test, test_name};
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn base64_round_trips() {
+        test_name!("base64 round trips");
+
+        let cases: [(&[u8], &str); 7] = [
+            (b"", ""),
+            (b"f", "Zg=="),
+            (b"fo", "Zm8="),
+            (b"foo", "Zm9v"),
+            (b"foob", "Zm9vYg=="),
+            (b"fooba", "Zm9vYmE="),
+            (b"foobar", "Zm9vYmFy"),
+        ];
+
+        for (bytes, encoded) in cases {
+            assert_eq!(encode(bytes), encoded);
+            assert_eq!(decode(encoded).expect("Failed to decode"), bytes);
+            // the padding is optional when decoding
+            assert_eq!(
+                decode(encoded.trim_end_matches('=')).expect("Failed to decode"),
+                bytes
+            );
+        }
+
+        let all: [u8; 256] = core::array::from_fn(|i| i as u8);
+        let encoded = encode(&all);
+        assert!(encoded.starts_with("AAECAwQF"));
+        assert!(encoded.ends_with("/P3+/w=="));
+        assert_eq!(decode(&encoded).expect("Failed to decode"), all);
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn base64_rejects_bad_input() {
+        test_name!("base64 rejects bad input");
+
+        assert_eq!(decode("Zm9v!A=="), Err(DecodeErr::InvalidCharacter('!')));
+        assert_eq!(decode("Zm=v"), Err(DecodeErr::InvalidCharacter('=')));
+        assert_eq!(decode("Zm9vY"), Err(DecodeErr::BadLength));
+        assert_eq!(decode("Zg="), Err(DecodeErr::BadPadding));
+        assert_eq!(decode("Zm9v===="), Err(DecodeErr::BadPadding));
+
+        end_test!();
+    }
+}
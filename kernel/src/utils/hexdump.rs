@@ -0,0 +1,85 @@
+use core::fmt::{self, Write};
+
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::terminal::port_dbg::SERIAL_WRITER;
+
+const BYTES_PER_LINE: usize = 16;
+
+/// writes the bytes in the `hexdump -C` layout, the offset, 16 bytes of hex split in two groups
+/// of 8, then the printable ones between bars with a dot for everything else
+///
+/// 00000000  48 65 6c 6c 6f 2c 20 77  6f 72 6c 64 21 0a 00 ff  |Hello, world!...|
+pub fn hexdump_to(writer: &mut impl Write, bytes: &[u8]) -> fmt::Result {
+    for (line_idx, line) in bytes.chunks(BYTES_PER_LINE).enumerate() {
+        write!(writer, "{:08x} ", line_idx * BYTES_PER_LINE)?;
+
+        for idx in 0..BYTES_PER_LINE {
+            if idx % 8 == 0 {
+                writer.write_char(' ')?;
+            }
+
+            // a short last line is padded so the ascii column stays aligned
+            match line.get(idx) {
+                Some(byte) => write!(writer, "{:02x} ", byte)?,
+                None => writer.write_str("   ")?,
+            }
+        }
+
+        writer.write_str(" |")?;
+        for byte in line {
+            let c = if byte.is_ascii_graphic() || *byte == b' ' {
+                *byte as char
+            } else {
+                '.'
+            };
+            writer.write_char(c)?;
+        }
+        writer.write_str("|\n")?;
+    }
+
+    Ok(())
+}
+
+/// dumps to serial, like storage tracing it stays off the terminal so it's safe anywhere
+pub fn hexdump(bytes: &[u8]) {
+    without_interrupts(|| {
+        let mut serial = SERIAL_WRITER.lock();
+        let _ = hexdump_to(&mut *serial, bytes);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::String;
+
+    use super::hexdump_to;
+    use crate::{end_test, test_name};
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn hexdump_format() {
+        test_name!("hexdump format");
+
+        let mut out = String::new();
+        hexdump_to(&mut out, b"Hello, world!\n\x00\xff").expect("Failed to format");
+        assert_eq!(
+            out,
+            "00000000  48 65 6c 6c 6f 2c 20 77  6f 72 6c 64 21 0a 00 ff  |Hello, world!...|\n"
+        );
+
+        let mut out = String::new();
+        hexdump_to(&mut out, b"0123456789abcdefXYZ").expect("Failed to format");
+        assert_eq!(
+            out,
+            "00000000  30 31 32 33 34 35 36 37  38 39 61 62 63 64 65 66  |0123456789abcdef|\n\
+             00000010  58 59 5a                                          |XYZ|\n"
+        );
+
+        let mut out = String::new();
+        hexdump_to(&mut out, &[]).expect("Failed to format");
+        assert!(out.is_empty());
+
+        end_test!();
+    }
+}
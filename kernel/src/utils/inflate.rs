@@ -0,0 +1,183 @@
+use alloc::vec::Vec;
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq, Eq, Clone, Copy)]
+pub enum InflateErr {
+    #[error("The compressed data ends in the middle of a sequence")]
+    Truncated,
+    #[error("A match refers to {0} bytes back, before the start of the output")]
+    BadOffset(usize),
+    #[error("The data decompresses to more than the expected {0} bytes")]
+    OutputTooLarge(usize),
+    #[error("Expected {expected} bytes, the data decompresses to {actual}")]
+    SizeMismatch { expected: usize, actual: usize },
+}
+
+/// the 4 bit lengths in a token are extended by bytes that keep adding until one isn't 255
+fn read_length(input: &[u8], cursor: &mut usize, nibble: u8) -> Result<usize, InflateErr> {
+    let mut len = nibble as usize;
+    if nibble != 0xF {
+        return Ok(len);
+    }
+
+    loop {
+        let byte = *input.get(*cursor).ok_or(InflateErr::Truncated)?;
+        *cursor += 1;
+        len += byte as usize;
+        if byte != 0xFF {
+            return Ok(len);
+        }
+    }
+}
+
+/// decompresses a raw LZ4 block, without the frame around it, the block doesn't record its own
+/// size so the caller has to know how big the data was before compression
+///
+/// every sequence is a token, the literals and then a match copying from earlier output, the
+/// last sequence stops after its literals
+pub fn decompress_lz4_block(input: &[u8], decompressed_len: usize) -> Result<Vec<u8>, InflateErr> {
+    let mut out = Vec::with_capacity(decompressed_len);
+    let mut cursor = 0;
+
+    loop {
+        let token = *input.get(cursor).ok_or(InflateErr::Truncated)?;
+        cursor += 1;
+
+        let literal_len = read_length(input, &mut cursor, token >> 4)?;
+        let literals = input
+            .get(cursor..cursor + literal_len)
+            .ok_or(InflateErr::Truncated)?;
+        if out.len() + literal_len > decompressed_len {
+            return Err(InflateErr::OutputTooLarge(decompressed_len));
+        }
+        out.extend_from_slice(literals);
+        cursor += literal_len;
+
+        if cursor == input.len() {
+            break;
+        }
+
+        let offset = input
+            .get(cursor..cursor + 2)
+            .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]) as usize)
+            .ok_or(InflateErr::Truncated)?;
+        cursor += 2;
+        if offset == 0 || offset > out.len() {
+            return Err(InflateErr::BadOffset(offset));
+        }
+
+        let match_len = read_length(input, &mut cursor, token & 0xF)? + 4;
+        if out.len() + match_len > decompressed_len {
+            return Err(InflateErr::OutputTooLarge(decompressed_len));
+        }
+
+        // the match can overlap what it's writing, a short offset repeats the same bytes
+        let start = out.len() - offset;
+        for idx in start..start + match_len {
+            out.push(out[idx]);
+        }
+    }
+
+    if out.len() != decompressed_len {
+        return Err(InflateErr::SizeMismatch {
+            expected: decompressed_len,
+            actual: out.len(),
+        });
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::{InflateErr, decompress_lz4_block};
+    use crate::{end_test, test_name};
+
+    /// both produced by the lz4 tool at -9 with the frame stripped off
+    const REPEATS: [u8; 32] = [
+        0x68, 0x64, 0x76, 0x69, 0x64, 0x61, 0x20, 0x06, 0x00, 0x7f, 0x6f, 0x78, 0x69, 0x64, 0x65,
+        0x21, 0x20, 0x19, 0x00, 0x38, 0x3d, 0x61, 0x62, 0x63, 0x03, 0x00, 0x50, 0x63, 0x61, 0x62,
+        0x63, 0x0a,
+    ];
+    const LONG_RUNS: [u8; 42] = [
+        0xff, 0x05, 0x40, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49, 0x4a, 0x4b, 0x4c,
+        0x4d, 0x4e, 0x4f, 0x50, 0x51, 0x52, 0x53, 0x14, 0x00, 0x29, 0x1f, 0x21, 0x01, 0x00, 0xff,
+        0x19, 0xa0, 0x65, 0x6e, 0x64, 0x20, 0x6f, 0x66, 0x20, 0x69, 0x74, 0x0a,
+    ];
+
+    fn long_runs_expected() -> Vec<u8> {
+        let mut expected = Vec::new();
+        for _ in 0..4 {
+            expected.extend(0x40..0x54u8);
+        }
+        expected.extend([b'!'; 300]);
+        expected.extend(b"end of it\n");
+        expected
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn lz4_block_known_data() {
+        test_name!("lz4 block decompression");
+
+        let mut expected = Vec::new();
+        for _ in 0..4 {
+            expected.extend(b"dvida dvida dvida oxide! ");
+        }
+        expected.extend(b"abcabcabcabcabcabcabcabc\n");
+        assert_eq!(
+            decompress_lz4_block(&REPEATS, expected.len()).expect("Failed to decompress"),
+            expected
+        );
+
+        // 20 literals and a 300 byte match both need the extra length bytes
+        let expected = long_runs_expected();
+        assert_eq!(
+            decompress_lz4_block(&LONG_RUNS, expected.len()).expect("Failed to decompress"),
+            expected
+        );
+
+        // a block holding nothing is a single empty token
+        assert_eq!(decompress_lz4_block(&[0x00], 0), Ok(Vec::new()));
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn lz4_block_rejects_bad_input() {
+        test_name!("lz4 block rejects bad input");
+
+        let len = long_runs_expected().len();
+        assert_eq!(decompress_lz4_block(&[], 0), Err(InflateErr::Truncated));
+        // inside the literals, the offset and the match length
+        for cut in [10, 23, 29] {
+            assert_eq!(
+                decompress_lz4_block(&LONG_RUNS[..cut], len),
+                Err(InflateErr::Truncated)
+            );
+        }
+
+        assert_eq!(
+            decompress_lz4_block(&LONG_RUNS, len - 1),
+            Err(InflateErr::OutputTooLarge(len - 1))
+        );
+        assert_eq!(
+            decompress_lz4_block(&LONG_RUNS, len + 1),
+            Err(InflateErr::SizeMismatch {
+                expected: len + 1,
+                actual: len
+            })
+        );
+
+        // a match before any output exists
+        assert_eq!(
+            decompress_lz4_block(&[0x00, 0x01, 0x00, 0x00], 16),
+            Err(InflateErr::BadOffset(1))
+        );
+
+        end_test!();
+    }
+}
@@ -0,0 +1,190 @@
+use alloc::collections::btree_map::BTreeMap;
+
+/// a capacity bounded map that evicts the least recently used entry when full
+///
+/// recency is a counter bumped on every get and put, the order map goes from that counter back to
+/// the key so the oldest entry is always its first one
+#[derive(Debug, Clone)]
+pub struct LruCache<K: Ord + Clone, V> {
+    entries: BTreeMap<K, (V, u64)>,
+    order: BTreeMap<u64, K>,
+    capacity: usize,
+    tick: u64,
+}
+
+impl<K: Ord + Clone, V> LruCache<K, V> {
+    /// a capacity of 0 is bumped to 1
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: BTreeMap::new(),
+            order: BTreeMap::new(),
+            capacity: capacity.max(1),
+            tick: 0,
+        }
+    }
+
+    fn touch(&mut self, key: &K) -> Option<&mut V> {
+        let (value, last_used) = self.entries.get_mut(key)?;
+
+        self.order.remove(last_used);
+        self.tick += 1;
+        *last_used = self.tick;
+        self.order.insert(self.tick, key.clone());
+
+        Some(value)
+    }
+
+    /// marks the entry as the most recently used one
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        self.touch(key).map(|value| &*value)
+    }
+
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        self.touch(key)
+    }
+
+    /// looks the entry up without changing its recency
+    pub fn peek(&self, key: &K) -> Option<&V> {
+        self.entries.get(key).map(|(value, _)| value)
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    /// returns the entry that had to make room, replacing the value of an existing key evicts
+    /// nothing
+    pub fn put(&mut self, key: K, value: V) -> Option<(K, V)> {
+        if let Some(old) = self.touch(&key) {
+            *old = value;
+            return None;
+        }
+
+        let evicted = if self.entries.len() >= self.capacity {
+            self.pop_lru()
+        } else {
+            None
+        };
+
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, (value, self.tick));
+
+        evicted
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let (value, last_used) = self.entries.remove(key)?;
+        self.order.remove(&last_used);
+
+        Some(value)
+    }
+
+    /// removes the least recently used entry
+    pub fn pop_lru(&mut self) -> Option<(K, V)> {
+        let (_, key) = self.order.pop_first()?;
+        let (value, _) = self.entries.remove(&key)?;
+
+        Some((key, value))
+    }
+
+    pub fn peek_mut(&mut self, key: &K) -> Option<&mut V> {
+        self.entries.get_mut(key).map(|(value, _)| value)
+    }
+
+    /// visits every entry in key order without changing its recency
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.entries.iter().map(|(key, (value, _))| (key, value))
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LruCache;
+    use crate::{end_test, test_name};
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn lru_get_put() {
+        test_name!("lru get and put");
+
+        let mut cache = LruCache::new(4);
+        assert!(cache.is_empty());
+        assert_eq!(cache.put(1, "one"), None);
+        assert_eq!(cache.put(2, "two"), None);
+
+        assert_eq!(cache.get(&1), Some(&"one"));
+        assert_eq!(cache.get(&3), None);
+
+        // replacing a value doesn't evict anything
+        assert_eq!(cache.put(1, "uno"), None);
+        assert_eq!(cache.get(&1), Some(&"uno"));
+        assert_eq!(cache.len(), 2);
+
+        assert_eq!(cache.remove(&2), Some("two"));
+        assert!(!cache.contains_key(&2));
+        assert_eq!(cache.len(), 1);
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn lru_eviction() {
+        test_name!("lru eviction");
+
+        let mut cache = LruCache::new(2);
+        cache.put(1, 10);
+        cache.put(2, 20);
+
+        assert_eq!(cache.put(3, 30), Some((1, 10)));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.peek(&1), None);
+        assert_eq!(cache.peek(&2), Some(&20));
+        assert_eq!(cache.peek(&3), Some(&30));
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn lru_get_promotes() {
+        test_name!("lru get promotes");
+
+        let mut cache = LruCache::new(3);
+        cache.put('a', 1);
+        cache.put('b', 2);
+        cache.put('c', 3);
+
+        // a is the oldest until it gets read
+        assert_eq!(cache.get(&'a'), Some(&1));
+        assert_eq!(cache.put('d', 4), Some(('b', 2)));
+
+        // peeking leaves c as the oldest
+        assert_eq!(cache.peek(&'c'), Some(&3));
+        assert_eq!(cache.put('e', 5), Some(('c', 3)));
+
+        *cache.get_mut(&'a').expect("a was evicted") += 10;
+        assert_eq!(cache.put('f', 6), Some(('d', 4)));
+        assert_eq!(cache.peek(&'a'), Some(&11));
+
+        end_test!();
+    }
+}
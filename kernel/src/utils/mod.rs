@@ -0,0 +1,7 @@
+pub mod base64;
+pub mod hexdump;
+pub mod inflate;
+pub mod lru;
+pub mod tar;
+
+pub use hexdump::{hexdump, hexdump_to};
@@ -0,0 +1,290 @@
+use alloc::string::String;
+use thiserror::Error;
+
+pub const TAR_BLOCK_SIZE: usize = 512;
+
+const NAME: core::ops::Range<usize> = 0..100;
+const MODE: core::ops::Range<usize> = 100..108;
+const SIZE: core::ops::Range<usize> = 124..136;
+const TYPE_FLAG: usize = 156;
+const LINK_NAME: core::ops::Range<usize> = 157..257;
+const MAGIC: core::ops::Range<usize> = 257..262;
+const PREFIX: core::ops::Range<usize> = 345..500;
+
+/// gnu tar stores names that don't fit in the header in an entry of their own right before
+const GNU_LONG_NAME: u8 = b'L';
+const GNU_LONG_LINK_NAME: u8 = b'K';
+
+#[derive(Debug, Error, PartialEq, Eq, Clone, Copy)]
+pub enum TarErr {
+    #[error("The archive ends in the middle of an entry")]
+    Truncated,
+    #[error("A numeric field isn't octal")]
+    BadNumber,
+    #[error("A name isn't valid utf-8")]
+    BadName,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TarEntryKind {
+    File,
+    Directory,
+    Symlink,
+    HardLink,
+    /// devices, fifos and anything else, with the type flag as written
+    Other(u8),
+}
+
+#[derive(Debug)]
+pub struct TarEntry<'a> {
+    /// as written in the archive, usually relative like `etc/motd` or `./etc/motd`
+    pub name: String,
+    /// the target of links, empty for everything else
+    pub link_name: String,
+    pub kind: TarEntryKind,
+    pub mode: u32,
+    pub size: usize,
+    pub data: &'a [u8],
+}
+
+/// walks the entries of a ustar archive, old v7 headers without the magic and gnu long names
+/// work as well
+pub struct TarReader<'a> {
+    archive: &'a [u8],
+    offset: usize,
+}
+
+/// the numeric fields are octal text, padded with spaces or nulls
+fn parse_octal(field: &[u8]) -> Result<usize, TarErr> {
+    let mut res: usize = 0;
+
+    for byte in field
+        .iter()
+        .skip_while(|b| **b == b' ')
+        .take_while(|b| **b != 0 && **b != b' ')
+    {
+        if !(b'0'..=b'7').contains(byte) {
+            return Err(TarErr::BadNumber);
+        }
+
+        res = res
+            .checked_mul(8)
+            .and_then(|res| res.checked_add((byte - b'0') as usize))
+            .ok_or(TarErr::BadNumber)?;
+    }
+
+    Ok(res)
+}
+
+fn parse_str(field: &[u8]) -> Result<&str, TarErr> {
+    let len = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    core::str::from_utf8(&field[..len]).map_err(|_| TarErr::BadName)
+}
+
+impl<'a> TarReader<'a> {
+    pub fn new(archive: &'a [u8]) -> Self {
+        Self { archive, offset: 0 }
+    }
+
+    /// the header at the current offset and the data after it, None at the end of the archive
+    fn next_block(&mut self) -> Result<Option<(&'a [u8], &'a [u8])>, TarErr> {
+        let Some(header) = self.archive.get(self.offset..self.offset + TAR_BLOCK_SIZE) else {
+            // some tools leave out the zero blocks at the end
+            return if self.offset >= self.archive.len() {
+                Ok(None)
+            } else {
+                Err(TarErr::Truncated)
+            };
+        };
+
+        if header.iter().all(|b| *b == 0) {
+            return Ok(None);
+        }
+
+        let size = parse_octal(&header[SIZE])?;
+        let data_start = self.offset + TAR_BLOCK_SIZE;
+        let data = self
+            .archive
+            .get(data_start..data_start + size)
+            .ok_or(TarErr::Truncated)?;
+
+        // the data is padded out to whole blocks
+        self.offset = data_start + size.next_multiple_of(TAR_BLOCK_SIZE);
+
+        Ok(Some((header, data)))
+    }
+
+    fn parse_entry(&mut self) -> Result<Option<TarEntry<'a>>, TarErr> {
+        let mut long_name = None;
+        let mut long_link_name = None;
+
+        let (header, data) = loop {
+            let Some((header, data)) = self.next_block()? else {
+                return Ok(None);
+            };
+
+            match header[TYPE_FLAG] {
+                GNU_LONG_NAME => long_name = Some(parse_str(data)?),
+                GNU_LONG_LINK_NAME => long_link_name = Some(parse_str(data)?),
+                _ => break (header, data),
+            }
+        };
+
+        let name = match long_name {
+            Some(name) => String::from(name),
+            None => {
+                let mut name = String::new();
+                if &header[MAGIC] == b"ustar" {
+                    let prefix = parse_str(&header[PREFIX])?;
+                    if !prefix.is_empty() {
+                        name.push_str(prefix);
+                        name.push('/');
+                    }
+                }
+                name.push_str(parse_str(&header[NAME])?);
+                name
+            }
+        };
+        let link_name = String::from(match long_link_name {
+            Some(link_name) => link_name,
+            None => parse_str(&header[LINK_NAME])?,
+        });
+
+        let kind = match header[TYPE_FLAG] {
+            // v7 archives mark directories only with the trailing slash
+            b'0' | 0 if name.ends_with('/') => TarEntryKind::Directory,
+            b'0' | 0 => TarEntryKind::File,
+            b'1' => TarEntryKind::HardLink,
+            b'2' => TarEntryKind::Symlink,
+            b'5' => TarEntryKind::Directory,
+            flag => TarEntryKind::Other(flag),
+        };
+
+        Ok(Some(TarEntry {
+            name,
+            link_name,
+            kind,
+            mode: parse_octal(&header[MODE])? as u32,
+            size: data.len(),
+            data,
+        }))
+    }
+}
+
+impl<'a> Iterator for TarReader<'a> {
+    type Item = Result<TarEntry<'a>, TarErr>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.parse_entry() {
+            Ok(entry) => entry.map(Ok),
+            Err(e) => {
+                // nothing after a broken header can be trusted
+                self.offset = self.archive.len();
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use alloc::{string::ToString, vec::Vec};
+
+    use super::*;
+    use crate::{end_test, test_name};
+
+    /// builds a ustar header by hand, only the fields the reader looks at are filled in
+    pub fn push_entry(archive: &mut Vec<u8>, name: &str, type_flag: u8, mode: u16, data: &[u8]) {
+        let mut header = [0u8; TAR_BLOCK_SIZE];
+        let name_len = name.len().min(NAME.len());
+        header[..name_len].copy_from_slice(&name.as_bytes()[..name_len]);
+
+        let mode = alloc::format!("{:07o}\0", mode);
+        header[MODE].copy_from_slice(mode.as_bytes());
+        let size = alloc::format!("{:011o}\0", data.len());
+        header[SIZE].copy_from_slice(size.as_bytes());
+        header[TYPE_FLAG] = type_flag;
+        header[MAGIC].copy_from_slice(b"ustar");
+
+        archive.extend_from_slice(&header);
+        archive.extend_from_slice(data);
+        archive.resize(archive.len().next_multiple_of(TAR_BLOCK_SIZE), 0);
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn tar_entries() {
+        test_name!("tar entries");
+
+        let mut archive = Vec::new();
+        push_entry(&mut archive, "etc/", b'5', 0o755, &[]);
+        push_entry(
+            &mut archive,
+            "etc/motd",
+            b'0',
+            0o644,
+            b"hello from the initrd\n",
+        );
+        push_entry(&mut archive, "bin/sh", b'2', 0o777, &[]);
+        archive.extend_from_slice(&[0u8; TAR_BLOCK_SIZE * 2]);
+
+        let entries: Vec<TarEntry> = TarReader::new(&archive)
+            .collect::<Result<_, _>>()
+            .expect("Failed to parse");
+        assert_eq!(entries.len(), 3);
+
+        assert_eq!(entries[0].name, "etc/");
+        assert_eq!(entries[0].kind, TarEntryKind::Directory);
+        assert_eq!(entries[0].mode, 0o755);
+        assert_eq!(entries[1].name, "etc/motd");
+        assert_eq!(entries[1].kind, TarEntryKind::File);
+        assert_eq!(entries[1].mode, 0o644);
+        assert_eq!(entries[1].size, 22);
+        assert_eq!(entries[1].data, b"hello from the initrd\n");
+        assert_eq!(entries[2].kind, TarEntryKind::Symlink);
+
+        // the data runs past the end of the archive
+        let truncated = &archive[..TAR_BLOCK_SIZE * 2 + 10];
+        let mut it = TarReader::new(truncated);
+        assert!(it.next().expect("No entry").is_ok());
+        assert!(matches!(it.next(), Some(Err(TarErr::Truncated))));
+        assert!(it.next().is_none());
+
+        assert_eq!(parse_octal(b"0009\0"), Err(TarErr::BadNumber));
+        assert_eq!(parse_octal(b"  17 \0"), Ok(15));
+
+        end_test!();
+    }
+
+    #[test_case]
+    #[allow(unreachable_code)]
+    fn tar_gnu_long_names() {
+        test_name!("tar gnu long names");
+
+        let long_name = "a/".repeat(80) + "file";
+
+        let mut archive = Vec::new();
+        push_entry(
+            &mut archive,
+            "././@LongLink",
+            GNU_LONG_NAME,
+            0,
+            (long_name.clone() + "\0").as_bytes(),
+        );
+        push_entry(&mut archive, &long_name, b'0', 0o600, b"deep");
+        push_entry(&mut archive, "short", b'0', 0o600, b"");
+
+        let mut it = TarReader::new(&archive);
+        let entry = it.next().expect("No entry").expect("Failed to parse");
+        assert_eq!(entry.name, long_name);
+        assert_eq!(entry.data, b"deep");
+
+        // the long name only applies to the one entry after it
+        let entry = it.next().expect("No entry").expect("Failed to parse");
+        assert_eq!(entry.name, "short".to_string());
+        assert_eq!(entry.size, 0);
+        assert!(it.next().is_none());
+
+        end_test!();
+    }
+}